@@ -22,6 +22,10 @@ fn main() {
     let git_version = get_git_version();
     let git_commit_short = get_git_commit_short();
 
+    // 目标平台与静态链接信息
+    let build_target = env::var("TARGET").unwrap_or_else(|_| "unknown".to_string());
+    let static_link = check_static_link();
+
     // 生成 build.rs 供 include!
     let content = format!(
         r#"// This file is auto-generated by build.rs
@@ -29,8 +33,10 @@ pub const BUILD_DATE: &str = "{}";
 pub const BUILD_TIME: &str = "{}";
 pub const GIT_VERSION: &str = "{}";
 pub const GIT_COMMIT_SHORT: &str = "{}";
+pub const BUILD_TARGET: &str = "{}";
+pub const STATIC_LINK: bool = {};
 "#,
-        build_date, build_time, git_version, git_commit_short
+        build_date, build_time, git_version, git_commit_short, build_target, static_link
     );
 
     fs::write(&dest_path, &content).unwrap();
@@ -39,6 +45,25 @@ pub const GIT_COMMIT_SHORT: &str = "{}";
     println!("cargo:rerun-if-changed=.git/index");
 }
 
+/// 检查是否为静态链接构建
+///
+/// musl 目标默认启用 crt-static，生成完全静态的二进制 (推荐用于 OpenWrt/Alpine):
+///   cargo build --profile minimal --no-default-features --target mipsel-unknown-linux-musl
+/// 如果工具链关闭了 crt-static，给出提示而不是静默生成动态链接的二进制
+fn check_static_link() -> bool {
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    let crt_static = target_features.split(',').any(|f| f == "crt-static");
+
+    if target_env == "musl" && !crt_static {
+        println!(
+            "cargo:warning=musl target without crt-static, the binary will be dynamically linked; \
+             set RUSTFLAGS=\"-C target-feature=+crt-static\" for a static build"
+        );
+    }
+    crt_static
+}
+
 /// 获取 git 版本信息（tag 或 commit hash）
 fn get_git_version() -> String {
     // 首先尝试获取最新 tag
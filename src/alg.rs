@@ -0,0 +1,216 @@
+//! UDP 应用层网关 (--udp-alg tftp)
+//!
+//! UDP 会话默认使用连接到远程的 socket，只接收来自远程地址和端口的回包。TFTP 等协议的服务端
+//! 从新的临时端口应答，回包会被内核丢弃。远程端口配置了网关的会话 socket 先不连接，发往远程的数据包
+//! 用 sendto；收到来自其他地址的回包时交给网关判断，接受后会话 socket 改为连接到该地址，之后的流量
+//! 都与新端口往来。判断之前的其他来源的数据包丢弃。
+//!
+//! 网关按远程端口挂载，`UdpAlgMap` 作为中间件为远程端口匹配的会话创建过滤器，库用户可以实现 `UdpAlg`
+//! 挂载自己的协议
+
+use crate::middleware::{Middleware, UdpFilter};
+use crate::types::Address;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+#[cfg(windows)]
+use crate::winsock::{self as libc, RawFd};
+
+/// TFTP 服务端的知名端口
+pub const TFTP_PORT: u16 = 69;
+
+/// UDP 应用层网关
+pub trait UdpAlg: Send + Sync + fmt::Debug {
+    /// 名称，用于日志
+    fn name(&self) -> &str;
+
+    /// 会话发往 `remote`，收到来自 `from` 的 `payload` 时，会话是否改为与 `from` 通信
+    fn follow(&self, remote: &Address, from: &Address, payload: &[u8]) -> bool;
+}
+
+/// TFTP (RFC 1350)：服务端从新端口 (TID) 回复 DATA、ACK、ERROR 或 OACK (RFC 2347)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tftp;
+
+impl UdpAlg for Tftp {
+    fn name(&self) -> &str {
+        "tftp"
+    }
+
+    fn follow(&self, remote: &Address, from: &Address, payload: &[u8]) -> bool {
+        let opcode = match payload {
+            [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+            _ => return false,
+        };
+        from.to_sockaddr().ip() == remote.to_sockaddr().ip()
+            && from.port() != remote.port()
+            && (3..=6).contains(&opcode)
+    }
+}
+
+/// 按名称创建内置网关，返回其默认端口
+pub fn builtin(name: &str) -> Option<(u16, Arc<dyn UdpAlg>)> {
+    match name {
+        "tftp" => Some((TFTP_PORT, Arc::new(Tftp))),
+        _ => None,
+    }
+}
+
+/// 按远程端口挂载的网关
+#[derive(Debug, Clone, Default)]
+pub struct UdpAlgMap {
+    algs: HashMap<u16, Arc<dyn UdpAlg>>,
+}
+
+impl UdpAlgMap {
+    /// 在远程端口上挂载网关，返回该端口原来的网关
+    pub fn insert(&mut self, port: u16, alg: Arc<dyn UdpAlg>) -> Option<Arc<dyn UdpAlg>> {
+        self.algs.insert(port, alg)
+    }
+
+    /// 远程端口上的网关
+    pub fn get(&self, port: u16) -> Option<&Arc<dyn UdpAlg>> {
+        self.algs.get(&port)
+    }
+
+    /// 是否没有挂载任何网关
+    pub fn is_empty(&self) -> bool {
+        self.algs.is_empty()
+    }
+}
+
+impl Middleware for UdpAlgMap {
+    fn name(&self) -> &str {
+        "udp-alg"
+    }
+
+    fn udp_filter(&self, remote: &Address) -> Option<Box<dyn UdpFilter>> {
+        let alg = self.get(remote.port())?;
+        Some(Box::new(AlgFilter(Arc::clone(alg))))
+    }
+}
+
+/// 会话上的网关：会话 socket 先不连接，由网关判断是否跟随
+#[derive(Debug)]
+struct AlgFilter(Arc<dyn UdpAlg>);
+
+impl UdpFilter for AlgFilter {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn unconnected(&self) -> bool {
+        true
+    }
+
+    fn follow(&mut self, remote: &Address, from: &Address, payload: &[u8]) -> bool {
+        self.0.follow(remote, from, payload)
+    }
+}
+
+impl FromStr for UdpAlgMap {
+    type Err = String;
+
+    /// 解析 `name[=port],...`，例如 `tftp` 或 `tftp=6969`，省略端口时使用协议的知名端口
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = UdpAlgMap::default();
+        for item in s.split(',') {
+            let (name, port) = match item.split_once('=') {
+                Some((name, port)) => (name.trim(), Some(port.trim())),
+                None => (item.trim(), None),
+            };
+            let (default_port, alg) = builtin(name)
+                .ok_or_else(|| format!("unknown udp alg '{}', expected tftp", name))?;
+            let port = match port {
+                Some(port) => port
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|&v| v > 0)
+                    .ok_or_else(|| format!("invalid port '{}' for udp alg {}", port, name))?,
+                None => default_port,
+            };
+            if map.insert(port, alg).is_some() {
+                return Err(format!("duplicate port {} in udp alg '{}'", port, s));
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl fmt::Display for UdpAlgMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ports: Vec<_> = self.algs.iter().collect();
+        ports.sort_by_key(|(port, _)| **port);
+        for (i, (port, alg)) in ports.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", alg.name(), port)?;
+        }
+        Ok(())
+    }
+}
+
+/// 会话 socket 改为连接到 `peer`，之后只收发与 `peer` 的数据包
+pub fn rebind(fd: RawFd, peer: &Address) -> io::Result<()> {
+    let sockaddr = peer.to_sockaddr_storage();
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const _ as *const libc::sockaddr,
+            peer.get_len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tftp_follow() {
+        let remote: Address = "10.0.0.1:69".parse().unwrap();
+        let data = [0, 3, 0, 1, b'x'];
+        assert!(Tftp.follow(&remote, &"10.0.0.1:40000".parse().unwrap(), &data));
+        // 来自其他主机、原端口或不是应答的操作码时不跟随
+        assert!(!Tftp.follow(&remote, &"10.0.0.2:40000".parse().unwrap(), &data));
+        assert!(!Tftp.follow(&remote, &remote, &data));
+        let rrq = b"\x00\x01file\x00octet\x00";
+        assert!(!Tftp.follow(&remote, &"10.0.0.1:40000".parse().unwrap(), rrq));
+        assert!(!Tftp.follow(&remote, &"10.0.0.1:40000".parse().unwrap(), &[0]));
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map: UdpAlgMap = "tftp".parse().unwrap();
+        assert_eq!(map.get(TFTP_PORT).map(|alg| alg.name()), Some("tftp"));
+        let map: UdpAlgMap = "tftp, tftp=6969".parse().unwrap();
+        assert!(map.get(6969).is_some());
+        assert_eq!(map.to_string(), "tftp=69,tftp=6969");
+
+        assert!("ftp".parse::<UdpAlgMap>().is_err());
+        assert!("tftp=0".parse::<UdpAlgMap>().is_err());
+        assert!("tftp,tftp=69".parse::<UdpAlgMap>().is_err());
+
+        // 只为远程端口匹配的会话创建过滤器
+        let mut filter = map.udp_filter(&"10.0.0.1:6969".parse().unwrap()).unwrap();
+        assert!(filter.unconnected());
+        assert!(filter.follow(
+            &"10.0.0.1:6969".parse().unwrap(),
+            &"10.0.0.1:40000".parse().unwrap(),
+            &[0, 3, 0, 1]
+        ));
+        assert!(map.udp_filter(&"10.0.0.1:53".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,227 @@
+//! socket 缓冲区自动调整
+//!
+//! `--sock-buf-autotune` 时每个 TCP 连接从 `--sock-buf` 大小开始，按观测到的吞吐量和积压情况
+//! 调整两端 socket 的 SO_SNDBUF/SO_RCVBUF：一个观测周期内出现积压且转发量超过当前缓冲区时加倍，
+//! 没有积压且转发量不到当前缓冲区时减半。超出初始大小的部分从全局预算中扣除，预算用完后不再增长
+
+use crate::config::{AUTOTUNE_INTERVAL_MS, AUTOTUNE_MAX_BUF};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 每个连接的缓冲区数：两个 socket 各有发送和接收缓冲区
+const BUFFERS_PER_CONN: usize = 4;
+
+/// 全局缓冲区预算
+#[derive(Debug)]
+pub struct BufAutotune {
+    /// 初始 (最小) 缓冲区大小
+    base: usize,
+    /// 单个缓冲区上限
+    max: usize,
+    /// 所有连接超出初始大小部分的总上限
+    budget: usize,
+    used: AtomicUsize,
+}
+
+impl BufAutotune {
+    /// 从 `base` 开始调整，单个缓冲区最大 `AUTOTUNE_MAX_BUF` (不小于 `base`)
+    pub fn new(base: usize, budget: usize) -> Arc<Self> {
+        Arc::new(Self {
+            base,
+            max: AUTOTUNE_MAX_BUF.max(base),
+            budget,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    /// 已分配给各连接的额外字节数
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// 预算上限
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// 新连接的调整状态
+    pub fn track(self: &Arc<Self>, now: Instant) -> BufTune {
+        BufTune {
+            autotune: Arc::clone(self),
+            size: self.base,
+            window_start: now,
+            bytes: 0,
+            backlogged: false,
+        }
+    }
+
+    /// 申请最多 `want` 字节，返回实际得到的字节数
+    fn reserve(&self, want: usize) -> usize {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let grant = want.min(self.budget.saturating_sub(used));
+            if grant == 0 {
+                return 0;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used + grant,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return grant,
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// 单个连接的缓冲区调整状态，drop 时归还占用的预算
+#[derive(Debug)]
+pub struct BufTune {
+    autotune: Arc<BufAutotune>,
+    /// 当前缓冲区大小
+    size: usize,
+    window_start: Instant,
+    /// 本周期转发的字节数 (两个方向)
+    bytes: u64,
+    /// 本周期是否有发不完而积压的数据
+    backlogged: bool,
+}
+
+impl BufTune {
+    /// 当前缓冲区大小
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// 是否大于初始大小
+    pub fn grown(&self) -> bool {
+        self.size > self.autotune.base
+    }
+
+    /// 记录转发的字节数
+    pub fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /// 记录发送积压
+    pub fn record_backlog(&mut self) {
+        self.backlogged = true;
+    }
+
+    /// 观测周期结束时计算新的缓冲区大小，需要调整时返回新大小
+    pub fn evaluate(&mut self, now: Instant) -> Option<usize> {
+        if now.duration_since(self.window_start) < Duration::from_millis(AUTOTUNE_INTERVAL_MS) {
+            return None;
+        }
+        let (bytes, backlogged) = (self.bytes, self.backlogged);
+        self.window_start = now;
+        self.bytes = 0;
+        self.backlogged = false;
+
+        let base = self.autotune.base;
+        let target = if backlogged && bytes >= self.size as u64 {
+            (self.size * 2).min(self.autotune.max)
+        } else if !backlogged && bytes < self.size as u64 {
+            (self.size / 2).max(base)
+        } else {
+            self.size
+        };
+
+        let target = if target > self.size {
+            let want = (target - self.size) * BUFFERS_PER_CONN;
+            let grant = self.autotune.reserve(want);
+            let extra = grant / BUFFERS_PER_CONN;
+            self.autotune.release(grant - extra * BUFFERS_PER_CONN);
+            self.size + extra
+        } else {
+            self.autotune
+                .release((self.size - target) * BUFFERS_PER_CONN);
+            target
+        };
+        if target == self.size {
+            return None;
+        }
+        self.size = target;
+        Some(target)
+    }
+}
+
+/// 复制出的状态不占用预算，从初始大小重新开始
+impl Clone for BufTune {
+    fn clone(&self) -> Self {
+        self.autotune.track(self.window_start)
+    }
+}
+
+impl Drop for BufTune {
+    fn drop(&mut self) {
+        self.autotune
+            .release((self.size - self.autotune.base) * BUFFERS_PER_CONN);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grow_and_shrink() {
+        let autotune = BufAutotune::new(1024, 1 << 20);
+        let start = Instant::now();
+        let mut tune = autotune.track(start);
+        let tick = Duration::from_millis(AUTOTUNE_INTERVAL_MS);
+
+        // 周期未结束不调整
+        tune.record(4096);
+        tune.record_backlog();
+        assert_eq!(tune.evaluate(start), None);
+
+        // 积压且转发量超过缓冲区：加倍
+        assert_eq!(tune.evaluate(start + tick), Some(2048));
+        assert_eq!(autotune.used(), 1024 * BUFFERS_PER_CONN);
+
+        // 积压但转发量小 (对端慢)：不变
+        tune.record(100);
+        tune.record_backlog();
+        assert_eq!(tune.evaluate(start + tick * 2), None);
+
+        // 空闲：减半，不低于初始大小
+        assert_eq!(tune.evaluate(start + tick * 3), Some(1024));
+        assert_eq!(tune.evaluate(start + tick * 4), None);
+        assert_eq!(autotune.used(), 0);
+    }
+
+    #[test]
+    fn test_budget() {
+        let autotune = BufAutotune::new(1024, 1024 * BUFFERS_PER_CONN + 512 * BUFFERS_PER_CONN);
+        let start = Instant::now();
+        let tick = Duration::from_millis(AUTOTUNE_INTERVAL_MS);
+        let mut a = autotune.track(start);
+        let mut b = autotune.track(start);
+
+        a.record(1 << 20);
+        a.record_backlog();
+        assert_eq!(a.evaluate(start + tick), Some(2048));
+        // 预算只剩 512 字节 × 4
+        b.record(1 << 20);
+        b.record_backlog();
+        assert_eq!(b.evaluate(start + tick), Some(1536));
+        assert_eq!(autotune.used(), autotune.budget());
+
+        a.record(1 << 20);
+        a.record_backlog();
+        assert_eq!(a.evaluate(start + tick * 2), None);
+
+        drop(b);
+        assert_eq!(autotune.used(), 1024 * BUFFERS_PER_CONN);
+        drop(a);
+        assert_eq!(autotune.used(), 0);
+    }
+}
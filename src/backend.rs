@@ -0,0 +1,613 @@
+//! 后端地址池
+//!
+//! 指定多个远程地址时，新的 TCP 连接和 UDP 会话按负载均衡策略 (默认轮询) 分配到各个后端，
+//! 健康检查标记为不健康的后端会被跳过
+
+use crate::config::{CircuitBreaker, FwdType};
+use crate::stats::{BackendStats, TrafficStats};
+use crate::sync::Recover;
+use crate::types::{Address, Nat64Prefix};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 后端权重上限
+pub const MAX_BACKEND_WEIGHT: u32 = 1000;
+
+/// 负载均衡策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LbPolicy {
+    /// 轮询
+    #[default]
+    RoundRobin,
+    /// 按权重平滑轮询 (权重通过 `地址@权重` 指定)
+    Weighted,
+    /// 选择当前连接/会话数最少的后端
+    LeastConn,
+}
+
+impl FromStr for LbPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(LbPolicy::RoundRobin),
+            "weighted" => Ok(LbPolicy::Weighted),
+            "least-conn" => Ok(LbPolicy::LeastConn),
+            _ => Err(format!(
+                "invalid lb policy '{}', must be round-robin, weighted or least-conn",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LbPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LbPolicy::RoundRobin => write!(f, "round-robin"),
+            LbPolicy::Weighted => write!(f, "weighted"),
+            LbPolicy::LeastConn => write!(f, "least-conn"),
+        }
+    }
+}
+
+/// 解析 `地址` 或 `地址@权重` 形式的远程地址，未指定权重时为 1
+pub fn parse_weighted_remote(s: &str) -> Result<(Address, u32), String> {
+    let (addr, weight) = split_weight(s)?;
+    let addr = Address::from_str(addr).map_err(|e| e.to_string())?;
+    Ok((addr, weight))
+}
+
+/// 同 `parse_weighted_remote`，另外支持 `域名:端口`：同时解析出 IPv6 和 IPv4 地址时，
+/// 返回首个 IPv6 地址和作为 Happy Eyeballs 备用地址的首个 IPv4 地址
+pub fn resolve_weighted_remote(s: &str) -> Result<(Address, Option<Address>, u32), String> {
+    let (addr, weight) = split_weight(s)?;
+    if let Ok(addr) = Address::from_str(addr) {
+        return Ok((addr, None, weight));
+    }
+    let resolved: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve '{}': {}", addr, e))?
+        .collect();
+    let (primary, fallback) = pick_happy_eyeballs(&resolved)
+        .ok_or_else(|| format!("'{}' resolved to no address", addr))?;
+    Ok((primary, fallback, weight))
+}
+
+/// 从解析结果中选出首选地址和备用地址 (RFC 8305：优先 IPv6，IPv4 作为备用)
+fn pick_happy_eyeballs(resolved: &[SocketAddr]) -> Option<(Address, Option<Address>)> {
+    let v6 = resolved.iter().find(|addr| addr.is_ipv6());
+    let v4 = resolved.iter().find(|addr| addr.is_ipv4());
+    match (v6, v4) {
+        (Some(v6), Some(v4)) => Some((
+            Address::from_sockaddr(*v6),
+            Some(Address::from_sockaddr(*v4)),
+        )),
+        (Some(addr), None) | (None, Some(addr)) => Some((Address::from_sockaddr(*addr), None)),
+        (None, None) => None,
+    }
+}
+
+/// 拆分 `地址@权重` 中的权重
+fn split_weight(s: &str) -> Result<(&str, u32), String> {
+    match s.rsplit_once('@') {
+        Some((addr, weight)) => {
+            let weight = weight
+                .parse::<u32>()
+                .ok()
+                .filter(|w| (1..=MAX_BACKEND_WEIGHT).contains(w))
+                .ok_or_else(|| {
+                    format!(
+                        "invalid weight '{}', must be 1-{}",
+                        weight, MAX_BACKEND_WEIGHT
+                    )
+                })?;
+            Ok((addr, weight))
+        }
+        None => Ok((s, 1)),
+    }
+}
+
+/// 根据转发类型和 NAT64 前缀转换实际连接的远程地址
+pub fn translate_addr(addr: &Address, fwd_type: FwdType, prefix: &Nat64Prefix) -> Address {
+    match fwd_type {
+        FwdType::FwdType4to6 => addr.to_nat64(prefix).unwrap_or_else(|| addr.clone()),
+        FwdType::FwdType6to4 => addr.from_nat64(prefix).unwrap_or_else(|| addr.clone()),
+        _ => addr.clone(),
+    }
+}
+
+/// 单个后端
+#[derive(Debug)]
+pub struct Backend {
+    /// 后端地址
+    pub addr: Address,
+    /// 后端统计
+    pub stats: Arc<BackendStats>,
+    /// 权重 (仅 weighted 策略使用)
+    pub weight: u32,
+    /// Happy Eyeballs 备用地址 (域名同时解析出 IPv6 和 IPv4 地址时为 IPv4 地址)
+    pub fallback: Option<Address>,
+    /// 最近一次健康检查是否通过 (未启用健康检查时始终为 true)
+    healthy: AtomicBool,
+    /// 是否有正在进行的健康检查
+    pub(crate) probing: AtomicBool,
+    /// 连续连接失败次数
+    connect_failures: AtomicU32,
+    /// 熔断结束时间 (毫秒)，0 表示熔断器关闭
+    breaker_until: AtomicU64,
+}
+
+impl Backend {
+    /// 不属于任何地址池的后端 (SOCKS5 服务端的 CONNECT 目标)，统计记录在 `stats` 中
+    pub fn direct(addr: Address, stats: Arc<BackendStats>) -> Self {
+        Self {
+            addr,
+            stats,
+            weight: 1,
+            fallback: None,
+            healthy: AtomicBool::new(true),
+            probing: AtomicBool::new(false),
+            connect_failures: AtomicU32::new(0),
+            breaker_until: AtomicU64::new(0),
+        }
+    }
+
+    /// 是否健康
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// 更新健康状态，返回状态是否发生变化
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.healthy.swap(healthy, Ordering::Relaxed) != healthy
+    }
+
+    /// 健康且未熔断
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.breaker_open(crate::log::get_monotonic_time())
+    }
+
+    /// 熔断器在 `now` 时是否打开
+    pub fn breaker_open(&self, now: u64) -> bool {
+        let until = self.breaker_until.load(Ordering::Relaxed);
+        until != 0 && now < until
+    }
+
+    /// 是否放行新连接：熔断器关闭时放行；熔断时间已过时放行一个试探连接，
+    /// 结果出来之前其余连接仍被拒绝
+    pub fn breaker_allows(&self, breaker: &CircuitBreaker, now: u64) -> bool {
+        let until = self.breaker_until.load(Ordering::Relaxed);
+        if until == 0 {
+            return true;
+        }
+        if now < until {
+            self.stats.breaker_rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let next = now + breaker.cooldown.as_millis() as u64;
+        self.breaker_until
+            .compare_exchange(until, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// 记录一次连接失败，返回熔断器是否因此打开 (试探连接失败时继续熔断，返回 false)
+    pub fn record_connect_failure(&self, breaker: &CircuitBreaker, now: u64) -> bool {
+        let failures = self.connect_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < breaker.threshold {
+            return false;
+        }
+        let until = now + breaker.cooldown.as_millis() as u64;
+        let opened = self.breaker_until.swap(until, Ordering::Relaxed) == 0;
+        if opened {
+            self.stats.breaker_trips.fetch_add(1, Ordering::Relaxed);
+        }
+        opened
+    }
+
+    /// 记录一次连接成功，返回熔断器是否因此关闭
+    pub fn record_connect_success(&self) -> bool {
+        self.connect_failures.store(0, Ordering::Relaxed);
+        self.breaker_until.swap(0, Ordering::Relaxed) != 0
+    }
+
+    /// 当前连接数 (TCP 连接 + UDP 会话)
+    pub fn active(&self) -> u64 {
+        let snapshot = self.stats.snapshot();
+        snapshot.tcp_connections + snapshot.udp_sessions
+    }
+}
+
+/// 后端地址池
+#[derive(Debug, Default)]
+pub struct BackendPool {
+    backends: Vec<Arc<Backend>>,
+    policy: LbPolicy,
+    /// 按客户端地址固定后端 (忽略负载均衡策略)
+    sticky: bool,
+    next: AtomicUsize,
+    /// 平滑加权轮询的当前权重 (与 backends 一一对应)
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl BackendPool {
+    /// 创建轮询的后端地址池，后端统计记录在 `stats` 中
+    pub fn new(addrs: &[Address], stats: &TrafficStats) -> Self {
+        Self::with_policy(addrs, &[], LbPolicy::RoundRobin, stats)
+    }
+
+    /// 按指定策略创建后端地址池，`weights` 与 `addrs` 一一对应，缺省的权重为 1
+    pub fn with_policy(
+        addrs: &[Address],
+        weights: &[u32],
+        policy: LbPolicy,
+        stats: &TrafficStats,
+    ) -> Self {
+        let backends: Vec<Arc<Backend>> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                Arc::new(Backend {
+                    addr: addr.clone(),
+                    stats: stats.backend(&addr.to_string()),
+                    weight: weights.get(i).copied().unwrap_or(1),
+                    fallback: None,
+                    healthy: AtomicBool::new(true),
+                    probing: AtomicBool::new(false),
+                    connect_failures: AtomicU32::new(0),
+                    breaker_until: AtomicU64::new(0),
+                })
+            })
+            .collect();
+        Self {
+            current_weights: Mutex::new(vec![0; backends.len()]),
+            backends,
+            policy,
+            sticky: false,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// 设置各后端的 Happy Eyeballs 备用地址，`fallbacks` 与创建时的 `addrs` 一一对应
+    pub fn with_fallbacks(mut self, fallbacks: &[Option<Address>]) -> Self {
+        for (backend, fallback) in self.backends.iter_mut().zip(fallbacks) {
+            if let Some(backend) = Arc::get_mut(backend) {
+                backend.fallback = fallback.clone();
+            }
+        }
+        self
+    }
+
+    /// 创建共享同一组后端 (含健康状态)、独立轮询位置的地址池
+    pub fn fork(&self) -> Self {
+        Self {
+            backends: self.backends.clone(),
+            policy: self.policy,
+            sticky: false,
+            next: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; self.backends.len()]),
+        }
+    }
+
+    /// 负载均衡策略
+    pub fn policy(&self) -> LbPolicy {
+        self.policy
+    }
+
+    /// 设置是否按客户端地址固定后端
+    pub fn set_sticky(&mut self, sticky: bool) {
+        self.sticky = sticky;
+    }
+
+    /// 为客户端选择后端：启用固定后端时按地址哈希选择，否则按负载均衡策略
+    pub fn pick_for(&self, client: &Address) -> Option<Arc<Backend>> {
+        if !self.sticky {
+            return self.pick();
+        }
+        // 最高随机权重 (rendezvous) 哈希：后端上下线时只有分配到该后端的客户端会改变
+        let score = |backend: &Backend| {
+            let mut hasher = DefaultHasher::new();
+            client.hash(&mut hasher);
+            backend.addr.hash(&mut hasher);
+            hasher.finish()
+        };
+        self.backends
+            .iter()
+            .filter(|backend| backend.is_available())
+            .max_by_key(|backend| score(backend))
+            .or_else(|| self.backends.iter().max_by_key(|backend| score(backend)))
+            .cloned()
+    }
+
+    /// 按负载均衡策略选择下一个健康且未熔断的后端
+    ///
+    /// 所有后端都不可用时仍按轮询顺序返回，由连接结果 (或熔断器) 决定成败
+    pub fn pick(&self) -> Option<Arc<Backend>> {
+        let len = self.backends.len();
+        if len == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let healthy = (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|&i| self.backends[i].is_available());
+        let picked = match self.policy {
+            LbPolicy::RoundRobin => healthy.take(1).next(),
+            // 从轮询位置开始比较，连接数相同时依次分配
+            LbPolicy::LeastConn => healthy.min_by_key(|&i| self.backends[i].active()),
+            LbPolicy::Weighted => self.pick_weighted(healthy),
+        };
+        Some(Arc::clone(&self.backends[picked.unwrap_or(start)]))
+    }
+
+    /// 平滑加权轮询 (与 nginx 相同)：每次各候选后端的当前权重加上自身权重，
+    /// 选出当前权重最大的后端并减去候选权重总和
+    fn pick_weighted(&self, candidates: impl Iterator<Item = usize>) -> Option<usize> {
+        let mut current = self.current_weights.lock().recover();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for i in candidates {
+            let weight = i64::from(self.backends[i].weight);
+            current[i] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+        if let Some(b) = best {
+            current[b] -= total;
+        }
+        best
+    }
+
+    /// 后端数量
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// 是否没有后端
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    /// 遍历所有后端
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Backend>> {
+        self.backends.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_translate_addr() {
+        let v4 = Address::from_str("192.0.2.33:443").unwrap();
+        let mapped = translate_addr(&v4, FwdType::FwdType4to6, &Nat64Prefix::default());
+        assert_eq!(mapped.to_string(), "[::ffff:192.0.2.33]:443");
+        let nat64 = translate_addr(&v4, FwdType::FwdType4to6, &Nat64Prefix::WELL_KNOWN);
+        assert_eq!(nat64.to_string(), "[64:ff9b::c000:221]:443");
+        assert_eq!(
+            translate_addr(&nat64, FwdType::FwdType6to4, &Nat64Prefix::WELL_KNOWN),
+            v4
+        );
+        // 不在前缀内的 IPv6 地址原样使用
+        assert_eq!(
+            translate_addr(&mapped, FwdType::FwdType6to4, &Nat64Prefix::WELL_KNOWN),
+            mapped
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002", "127.0.0.1:1003"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let pool = BackendPool::new(&addrs, &stats);
+        assert_eq!(pool.len(), 3);
+
+        let picked: Vec<String> = (0..4)
+            .map(|_| pool.pick().expect("backend").addr.to_string())
+            .collect();
+        assert_eq!(
+            picked,
+            vec![
+                "127.0.0.1:1001",
+                "127.0.0.1:1002",
+                "127.0.0.1:1003",
+                "127.0.0.1:1001"
+            ]
+        );
+
+        assert!(BackendPool::default().pick().is_none());
+    }
+
+    #[test]
+    fn test_skip_unhealthy() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let pool = BackendPool::new(&addrs, &stats);
+        let forked = pool.fork();
+
+        let first = pool.iter().next().expect("backend");
+        assert!(first.set_healthy(false));
+        assert!(!first.set_healthy(false));
+        for _ in 0..3 {
+            assert_eq!(
+                forked.pick().expect("backend").addr.to_string(),
+                "127.0.0.1:1002"
+            );
+        }
+
+        // 全部不健康时仍然轮询
+        pool.iter().for_each(|backend| {
+            backend.set_healthy(false);
+        });
+        assert!(pool.pick().is_some());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let pool = BackendPool::with_policy(&addrs, &[3, 1], LbPolicy::Weighted, &stats);
+
+        let picked: Vec<u16> = (0..8)
+            .map(|_| pool.pick().expect("backend").addr.port())
+            .collect();
+        assert_eq!(picked.iter().filter(|&&p| p == 1001).count(), 6);
+        // 平滑加权：权重小的后端不会被连续跳过太久
+        assert!(picked[..4].contains(&1002));
+
+        assert_eq!(parse_weighted_remote("[::1]:443@5").expect("weighted").1, 5);
+        assert_eq!(parse_weighted_remote("127.0.0.1:443").expect("plain").1, 1);
+        assert!(parse_weighted_remote("127.0.0.1:443@0").is_err());
+        assert!(parse_weighted_remote("127.0.0.1:443@x").is_err());
+        assert_eq!("least-conn".parse(), Ok(LbPolicy::LeastConn));
+        assert!("random".parse::<LbPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002", "127.0.0.1:1003"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let pool = BackendPool::with_policy(&addrs, &[], LbPolicy::LeastConn, &stats);
+
+        // 连接数相同时依次分配
+        for _ in 0..3 {
+            pool.pick().expect("backend").stats.inc_tcp_connections();
+        }
+        let backends: Vec<&Arc<Backend>> = pool.iter().collect();
+        assert!(backends.iter().all(|b| b.active() == 1));
+
+        backends[0].stats.dec_tcp_connections();
+        backends[1].stats.inc_udp_sessions();
+        assert_eq!(pool.pick().expect("backend").addr.port(), 1001);
+
+        // 不健康的后端即使连接最少也被跳过
+        backends[0].set_healthy(false);
+        assert_eq!(pool.pick().expect("backend").addr.port(), 1003);
+    }
+
+    #[test]
+    fn test_sticky() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002", "127.0.0.1:1003"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let mut pool = BackendPool::new(&addrs, &stats);
+        pool.set_sticky(true);
+
+        let clients: Vec<Address> = (0..20)
+            .map(|i| Address::from_str(&format!("10.0.0.{}:5000", i)).expect("address"))
+            .collect();
+        let first: Vec<u16> = clients
+            .iter()
+            .map(|c| pool.pick_for(c).expect("backend").addr.port())
+            .collect();
+        let again: Vec<u16> = clients
+            .iter()
+            .map(|c| pool.pick_for(c).expect("backend").addr.port())
+            .collect();
+        assert_eq!(first, again);
+        assert!(first.iter().any(|&p| p != first[0]));
+
+        // 后端下线只影响分配到它的客户端
+        pool.iter().next().expect("backend").set_healthy(false);
+        for (client, &port) in clients.iter().zip(&first) {
+            let now = pool.pick_for(client).expect("backend").addr.port();
+            if port == 1001 {
+                assert_ne!(now, 1001);
+            } else {
+                assert_eq!(now, port);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resolve_weighted_remote() {
+        let (addr, fallback, weight) = resolve_weighted_remote("[::1]:443@3").expect("remote");
+        assert_eq!(addr, Address::from_str("[::1]:443").expect("address"));
+        assert_eq!((fallback, weight), (None, 3));
+        assert!(resolve_weighted_remote("no-such-host.invalid:443").is_err());
+
+        // 优先 IPv6，首个 IPv4 地址作为备用
+        let resolved: Vec<SocketAddr> = ["10.0.0.1:80", "[2001:db8::1]:80", "10.0.0.2:80"]
+            .iter()
+            .map(|s| s.parse().expect("socket address"))
+            .collect();
+        let (primary, fallback) = pick_happy_eyeballs(&resolved).expect("address");
+        assert_eq!(primary.to_string(), "[2001:db8::1]:80");
+        assert_eq!(fallback.expect("fallback").to_string(), "10.0.0.1:80");
+        let (primary, fallback) = pick_happy_eyeballs(&resolved[..1]).expect("address");
+        assert_eq!(
+            (primary.to_string(), fallback),
+            ("10.0.0.1:80".to_string(), None)
+        );
+        assert!(pick_happy_eyeballs(&[]).is_none());
+
+        let stats = TrafficStats::default();
+        let pool = BackendPool::new(&[primary], &stats)
+            .with_fallbacks(&[Some(Address::from_str("10.0.0.2:80").expect("address"))]);
+        assert!(pool.iter().next().expect("backend").fallback.is_some());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let stats = TrafficStats::default();
+        let addrs: Vec<Address> = ["127.0.0.1:1001", "127.0.0.1:1002"]
+            .iter()
+            .map(|s| Address::from_str(s).expect("address"))
+            .collect();
+        let pool = BackendPool::new(&addrs, &stats);
+        let backend = pool.iter().next().expect("backend");
+        let breaker = CircuitBreaker {
+            threshold: 3,
+            cooldown: std::time::Duration::from_secs(10),
+        };
+
+        assert!(!backend.record_connect_failure(&breaker, 1000));
+        assert!(!backend.record_connect_failure(&breaker, 1000));
+        assert!(backend.record_connect_failure(&breaker, 1000));
+        assert!(backend.breaker_open(5000));
+        assert!(!backend.breaker_allows(&breaker, 5000));
+        assert_eq!(backend.stats.breaker_rejected.load(Ordering::Relaxed), 1);
+
+        // 熔断期间轮询跳过该后端
+        let now = crate::log::get_monotonic_time();
+        backend.record_connect_failure(&breaker, now);
+        for _ in 0..4 {
+            assert_eq!(pool.pick().expect("backend").addr.port(), 1002);
+        }
+
+        // 熔断结束后只放行一个试探连接，试探失败继续熔断
+        let later = now + 10_000;
+        assert!(backend.breaker_allows(&breaker, later));
+        assert!(!backend.breaker_allows(&breaker, later));
+        assert!(!backend.record_connect_failure(&breaker, later));
+        assert!(backend.breaker_open(later + 9_999));
+
+        assert!(backend.record_connect_success());
+        assert!(!backend.breaker_open(later));
+        assert!(!backend.record_connect_success());
+        assert_eq!(backend.stats.breaker_trips.load(Ordering::Relaxed), 1);
+    }
+}
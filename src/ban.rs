@@ -0,0 +1,217 @@
+//! 自动封禁 (--auto-ban)
+//!
+//! 记录每个客户端 IP 最近被拒绝的时间，`window` 内达到 `threshold` 次后封禁 `duration`，
+//! 期间 TCP 监听直接关闭它的新连接、UDP 监听丢弃它建立新会话的数据包 (已有的连接和会话不受影响)。
+//! 到期由事件循环的定时器解除。拒绝和封禁都输出固定格式的日志行，便于 fail2ban 等工具匹配；
+//! UDP 的拒绝按数据包发生，由 `RejectThrottle` 按客户端 IP 节流
+
+use crate::config::AutoBan;
+use crate::log::{anonymize_addr, Logger};
+use crate::sync::{Mutex, Recover};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// 检查封禁到期的间隔
+pub const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 同时跟踪的客户端 IP 上限，超出后新的 IP 不再计数 (已封禁的不受影响)
+pub const MAX_TRACKED: usize = 65536;
+
+/// 同一客户端 IP 节流的拒绝最多每隔这么久报告一次
+pub const REJECT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 日志中显示的 IP，启用 `--log-anonymize-ips` 时截断
+pub fn shown_ip(ip: IpAddr) -> IpAddr {
+    if Logger::global().is_anonymize_ips_enabled() {
+        anonymize_addr(SocketAddr::new(ip, 0)).ip()
+    } else {
+        ip
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    /// 窗口内被拒绝的时间 (毫秒)
+    failures: VecDeque<u64>,
+    /// 封禁结束时间 (毫秒)，0 表示未封禁
+    banned_until: u64,
+}
+
+/// 客户端 IP 封禁表，只在事件循环线程中访问
+#[derive(Debug)]
+pub struct BanList {
+    config: AutoBan,
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl BanList {
+    pub fn new(config: AutoBan) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> AutoBan {
+        self.config
+    }
+
+    /// `ip` 在 `now` (毫秒) 时是否处于封禁中
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.entries
+            .lock()
+            .recover()
+            .get(&ip)
+            .is_some_and(|entry| entry.banned_until > now)
+    }
+
+    /// 记录一次拒绝，达到阈值刚被封禁时返回 true
+    pub fn record_failure(&self, ip: IpAddr, now: u64) -> bool {
+        let mut entries = self.entries.lock().recover();
+        if !entries.contains_key(&ip) && entries.len() >= MAX_TRACKED {
+            return false;
+        }
+        let entry = entries.entry(ip).or_default();
+        if entry.banned_until > now {
+            return false;
+        }
+        let window = self.config.window.as_millis() as u64;
+        while entry
+            .failures
+            .front()
+            .is_some_and(|&t| now.saturating_sub(t) >= window)
+        {
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+        if entry.failures.len() < self.config.threshold as usize {
+            return false;
+        }
+        entry.failures.clear();
+        entry.banned_until = now + self.config.duration.as_millis() as u64;
+        true
+    }
+
+    /// 解除到期的封禁并清理窗口外的记录，返回被解除封禁的 IP
+    pub fn expire(&self, now: u64) -> Vec<IpAddr> {
+        let window = self.config.window.as_millis() as u64;
+        let mut unbanned = Vec::new();
+        self.entries.lock().recover().retain(|ip, entry| {
+            if entry.banned_until != 0 && entry.banned_until <= now {
+                entry.banned_until = 0;
+                unbanned.push(*ip);
+            }
+            entry.failures.retain(|&t| now.saturating_sub(t) < window);
+            entry.banned_until != 0 || !entry.failures.is_empty()
+        });
+        unbanned
+    }
+
+    /// 当前封禁中的 IP 数
+    pub fn banned(&self, now: u64) -> usize {
+        self.entries
+            .lock()
+            .recover()
+            .values()
+            .filter(|entry| entry.banned_until > now)
+            .count()
+    }
+}
+
+/// 按客户端 IP 节流拒绝报告，只在事件循环线程中访问
+#[derive(Debug, Default)]
+pub struct RejectThrottle {
+    /// IP -> (上次报告时间 (毫秒), 此后被抑制的次数)
+    last: Mutex<HashMap<IpAddr, (u64, u64)>>,
+}
+
+impl RejectThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 距离该 IP 上次报告不到 `REJECT_REPORT_INTERVAL` 时计数并返回 None，
+    /// 否则返回此前被抑制的次数
+    pub fn check(&self, ip: IpAddr, now: u64) -> Option<u64> {
+        let interval = REJECT_REPORT_INTERVAL.as_millis() as u64;
+        let mut last = self.last.lock().recover();
+        if let Some((reported, suppressed)) = last.get_mut(&ip) {
+            if now.saturating_sub(*reported) < interval {
+                *suppressed += 1;
+                return None;
+            }
+            let count = std::mem::take(suppressed);
+            *reported = now;
+            return Some(count);
+        }
+        if last.len() >= MAX_TRACKED {
+            last.retain(|_, (reported, _)| now.saturating_sub(*reported) < interval);
+            if last.len() >= MAX_TRACKED {
+                return Some(0);
+            }
+        }
+        last.insert(ip, (now, 0));
+        Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "5,60,600".parse::<AutoBan>(),
+            Ok(AutoBan {
+                threshold: 5,
+                window: Duration::from_secs(60),
+                duration: Duration::from_secs(600),
+            })
+        );
+        assert!("5,60".parse::<AutoBan>().is_err());
+        assert!("5,0,600".parse::<AutoBan>().is_err());
+    }
+
+    #[test]
+    fn test_ban_and_expire() {
+        let bans = BanList::new("3,10,60".parse().unwrap());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        // 窗口外的拒绝不计入
+        assert!(!bans.record_failure(ip, 1_000));
+        assert!(!bans.record_failure(ip, 2_000));
+        assert!(!bans.record_failure(ip, 12_000));
+        assert!(!bans.record_failure(ip, 13_000));
+        assert!(!bans.is_banned(ip, 13_000));
+        assert!(bans.record_failure(ip, 14_000));
+        assert!(bans.is_banned(ip, 14_000));
+        assert!(!bans.is_banned(other, 14_000));
+        // 封禁期间的拒绝不延长封禁
+        assert!(!bans.record_failure(ip, 20_000));
+        assert_eq!(bans.banned(20_000), 1);
+
+        assert!(!bans.record_failure(other, 20_000));
+        assert!(bans.expire(73_999).is_empty());
+        assert_eq!(bans.expire(74_000), vec![ip]);
+        assert!(!bans.is_banned(ip, 74_000));
+        assert_eq!(bans.banned(74_000), 0);
+        // 没有封禁、窗口内也没有拒绝的记录被清理
+        assert!(bans.entries.lock().recover().is_empty());
+    }
+
+    #[test]
+    fn test_reject_throttle() {
+        let throttle = RejectThrottle::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(throttle.check(ip, 1_000), Some(0));
+        assert_eq!(throttle.check(ip, 1_100), None);
+        assert_eq!(throttle.check(ip, 1_999), None);
+        assert_eq!(throttle.check(other, 1_999), Some(0));
+        assert_eq!(throttle.check(ip, 2_000), Some(2));
+        assert_eq!(throttle.check(ip, 3_500), Some(0));
+    }
+}
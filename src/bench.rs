@@ -0,0 +1,345 @@
+//! 内置压测客户端 (--bench)
+//!
+//! 向回显目标 (通常是经过被测映射的 --echo-server) 发起若干条并发 TCP 流和固定速率的 UDP 包，
+//! 统计吞吐量、包速率和往返延迟分位数，用于性能回归对比。
+//! TCP 每条流按请求-应答方式发送 `size` 字节并等待完整回显；UDP 包内携带序号和发送时间
+
+use crate::stats::format_bytes;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 建立 TCP 连接的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 单次读写的超时时间，超时的流记为错误并停止
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 发送结束后继续等待 UDP 回包的时间
+const UDP_GRACE: Duration = Duration::from_secs(1);
+
+/// UDP 包头：序号 (8 字节) + 相对开始时间的发送时刻 (8 字节，纳秒)
+const UDP_HEADER_LEN: usize = 16;
+
+/// 压测参数
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// 回显目标地址
+    pub target: SocketAddr,
+    /// 并发 TCP 流数，0 表示不测 TCP
+    pub tcp_streams: usize,
+    /// UDP 发包速率 (包/秒)，0 表示不测 UDP
+    pub udp_rate: u64,
+    /// 每次请求/每个 UDP 包的字节数
+    pub size: usize,
+    /// 持续时间
+    pub duration: Duration,
+}
+
+/// 延迟分位数 (微秒)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    /// 由延迟样本 (微秒) 计算分位数
+    pub fn from_samples(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Self {
+            samples: samples.len(),
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.samples == 0 {
+            return write!(f, "no samples");
+        }
+        write!(
+            f,
+            "p50 {} us, p90 {} us, p99 {} us, max {} us",
+            self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+/// TCP 压测结果
+#[derive(Debug, Clone, Default)]
+pub struct TcpReport {
+    pub streams: usize,
+    /// 完成回显的字节数 (单向)
+    pub bytes: u64,
+    /// 完成的请求数
+    pub requests: u64,
+    /// 连接失败或中途出错的流数
+    pub failed_streams: usize,
+    pub latency: LatencySummary,
+}
+
+/// UDP 压测结果
+#[derive(Debug, Clone, Default)]
+pub struct UdpReport {
+    pub sent: u64,
+    pub received: u64,
+    pub bytes: u64,
+    pub latency: LatencySummary,
+}
+
+impl UdpReport {
+    /// 丢包率 (百分比)
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.sent.saturating_sub(self.received) as f64 * 100.0 / self.sent as f64
+    }
+}
+
+/// 压测结果
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub elapsed: Duration,
+    pub tcp: Option<TcpReport>,
+    pub udp: Option<UdpReport>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let per_sec = |n: u64| (n as f64 / secs) as u64;
+        writeln!(f, "duration: {:.2}s", secs)?;
+        if let Some(tcp) = &self.tcp {
+            writeln!(
+                f,
+                "tcp: {} streams ({} failed), {} requests, {}/s, {} req/s",
+                tcp.streams,
+                tcp.failed_streams,
+                tcp.requests,
+                format_bytes(per_sec(tcp.bytes)),
+                per_sec(tcp.requests)
+            )?;
+            writeln!(f, "tcp latency: {}", tcp.latency)?;
+        }
+        if let Some(udp) = &self.udp {
+            writeln!(
+                f,
+                "udp: sent {} ({} pps), received {} ({} pps), loss {:.2}%, {}/s",
+                udp.sent,
+                per_sec(udp.sent),
+                udp.received,
+                per_sec(udp.received),
+                udp.loss_percent(),
+                format_bytes(per_sec(udp.bytes))
+            )?;
+            writeln!(f, "udp latency: {}", udp.latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// 运行压测，阻塞到结束
+pub fn run(config: &BenchConfig) -> io::Result<BenchReport> {
+    if config.tcp_streams == 0 && config.udp_rate == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bench needs TCP streams or a UDP rate",
+        ));
+    }
+    if config.size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bench size must be greater than 0",
+        ));
+    }
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let tcp_workers: Vec<_> = (0..config.tcp_streams)
+        .map(|_| {
+            let (target, size) = (config.target, config.size);
+            thread::spawn(move || tcp_stream(target, size, deadline))
+        })
+        .collect();
+    let udp = (config.udp_rate > 0)
+        .then(|| udp_flow(config.target, config.size, config.udp_rate, start, deadline))
+        .transpose()?;
+
+    let tcp = (config.tcp_streams > 0).then(|| {
+        let mut report = TcpReport {
+            streams: config.tcp_streams,
+            ..TcpReport::default()
+        };
+        let mut samples = Vec::new();
+        for worker in tcp_workers {
+            let stream = worker.join().unwrap_or_default();
+            report.bytes += stream.bytes;
+            report.requests += stream.samples.len() as u64;
+            report.failed_streams += usize::from(stream.failed);
+            samples.extend(stream.samples);
+        }
+        report.latency = LatencySummary::from_samples(samples);
+        report
+    });
+    Ok(BenchReport {
+        elapsed: start.elapsed().min(config.duration),
+        tcp,
+        udp,
+    })
+}
+
+#[derive(Debug, Default)]
+struct StreamResult {
+    bytes: u64,
+    samples: Vec<u64>,
+    failed: bool,
+}
+
+/// 单条 TCP 流：发送 `size` 字节，读完回显后记录往返时间
+fn tcp_stream(target: SocketAddr, size: usize, deadline: Instant) -> StreamResult {
+    let mut result = StreamResult::default();
+    let run = |result: &mut StreamResult| -> io::Result<()> {
+        let mut stream = TcpStream::connect_timeout(&target, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let request: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut response = vec![0u8; size];
+        while Instant::now() < deadline {
+            let sent = Instant::now();
+            stream.write_all(&request)?;
+            stream.read_exact(&mut response)?;
+            result.samples.push(sent.elapsed().as_micros() as u64);
+            result.bytes += size as u64;
+        }
+        Ok(())
+    };
+    result.failed = run(&mut result).is_err();
+    result
+}
+
+/// UDP：按固定速率发包，另一线程接收回显并按包内时间戳计算延迟
+fn udp_flow(
+    target: SocketAddr,
+    size: usize,
+    rate: u64,
+    start: Instant,
+    deadline: Instant,
+) -> io::Result<UdpReport> {
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let receiver = socket.try_clone()?;
+    let receiver = thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        let (mut received, mut bytes, mut samples) = (0u64, 0u64, Vec::new());
+        while Instant::now() < deadline + UDP_GRACE {
+            match receiver.recv(&mut buf) {
+                Ok(n) if n >= UDP_HEADER_LEN => {
+                    let sent_ns = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+                    let now_ns = start.elapsed().as_nanos() as u64;
+                    samples.push(now_ns.saturating_sub(sent_ns) / 1000);
+                    received += 1;
+                    bytes += n as u64;
+                }
+                _ => {}
+            }
+        }
+        (received, bytes, samples)
+    });
+
+    let mut packet = vec![0u8; size.max(UDP_HEADER_LEN)];
+    let interval = Duration::from_secs(1).as_nanos() as u64 / rate;
+    let mut sent = 0u64;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        // 补发到当前时刻应发的包数，再睡到下一个包的发送时刻
+        let due = (now - start).as_nanos() as u64 / interval.max(1) + 1;
+        while sent < due {
+            packet[..8].copy_from_slice(&sent.to_be_bytes());
+            packet[8..16].copy_from_slice(&(start.elapsed().as_nanos() as u64).to_be_bytes());
+            // 发送缓冲区满或 ICMP 错误时丢弃该包，计入丢包
+            let _ = socket.send(&packet);
+            sent += 1;
+        }
+        let next = start + Duration::from_nanos(sent * interval);
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+
+    let (received, bytes, samples) = receiver.join().unwrap_or_default();
+    Ok(UdpReport {
+        sent,
+        received,
+        bytes,
+        latency: LatencySummary::from_samples(samples),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::{EchoMode, EchoServer};
+
+    #[test]
+    fn test_latency_summary() {
+        let summary = LatencySummary::from_samples((1..=100).rev().collect());
+        assert_eq!(summary.samples, 100);
+        assert_eq!(summary.p50, 50);
+        assert_eq!(summary.p90, 90);
+        assert_eq!(summary.p99, 99);
+        assert_eq!(summary.max, 100);
+        assert_eq!(LatencySummary::from_samples(Vec::new()).samples, 0);
+    }
+
+    #[test]
+    fn test_bench_against_echo() {
+        let mut server =
+            EchoServer::bind("127.0.0.1:0".parse().unwrap(), true, true, EchoMode::Echo)
+                .expect("bind echo server");
+        let target = server.local_addr().expect("local addr");
+        let handle = server.handle();
+        let runner = thread::spawn(move || server.run().expect("run echo server"));
+
+        let report = run(&BenchConfig {
+            target,
+            tcp_streams: 2,
+            udp_rate: 200,
+            size: 512,
+            duration: Duration::from_millis(300),
+        })
+        .expect("run bench");
+        let tcp = report.tcp.as_ref().expect("tcp report");
+        assert_eq!(tcp.failed_streams, 0);
+        assert!(tcp.requests > 0);
+        assert_eq!(tcp.bytes, tcp.requests * 512);
+        let udp = report.udp.as_ref().expect("udp report");
+        assert!(udp.sent >= 50);
+        assert!(udp.received > 0);
+        assert!(report.to_string().contains("udp latency"));
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+}
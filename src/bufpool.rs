@@ -0,0 +1,289 @@
+//! 缓冲区池
+//!
+//! 回收 TCP 连接和 UDP 收包使用的固定大小缓冲区，避免连接频繁建立/关闭时反复申请大块内存。
+//! 归还的缓冲区不清零，使用方只读取自己写入的部分。
+//! 内核可能仍在引用的缓冲区 (MSG_ZEROCOPY) 用 `PooledBuf::hold` 暂存，到期后才回到池中。
+//! 设置了内存预算 (`--max-memory`) 时，新分配的缓冲区计入预算，释放时归还
+
+use crate::memory::MemoryBudget;
+use crate::sync::{Mutex, Recover};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 池中保留的空闲缓冲区总字节数上限，超出后归还的缓冲区直接释放
+pub const MAX_IDLE_BYTES: usize = 32 * 1024 * 1024;
+
+/// 固定大小缓冲区池
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+    /// 暂不复用的缓冲区及到期时间，按到期时间排列
+    held: Mutex<VecDeque<(Instant, Vec<u8>)>>,
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+impl BufferPool {
+    /// 创建缓冲区大小为 `size` 的池，空闲缓冲区数量按 `MAX_IDLE_BYTES` 限制 (至少 1 个)
+    pub fn new(size: usize) -> Arc<Self> {
+        Self::with_max_idle(size, Self::idle_limit(size))
+    }
+
+    /// 按 `MAX_IDLE_BYTES` 计算的空闲缓冲区数量上限 (至少 1 个)
+    pub fn idle_limit(size: usize) -> usize {
+        (MAX_IDLE_BYTES / size.max(1)).max(1)
+    }
+
+    /// 创建最多保留 `max_idle` 个空闲缓冲区的池
+    pub fn with_max_idle(size: usize, max_idle: usize) -> Arc<Self> {
+        Self::with_budget(size, max_idle, None)
+    }
+
+    /// 创建分配计入内存预算的池，超出预算时归还的缓冲区直接释放
+    pub fn with_budget(
+        size: usize,
+        max_idle: usize,
+        budget: Option<Arc<MemoryBudget>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+            held: Mutex::new(VecDeque::new()),
+            budget,
+        })
+    }
+
+    /// 缓冲区大小
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// 当前空闲缓冲区数量
+    pub fn idle_len(&self) -> usize {
+        self.idle.lock().recover().len()
+    }
+
+    /// 暂存中的缓冲区数量
+    pub fn held_len(&self) -> usize {
+        self.held.lock().recover().len()
+    }
+
+    /// 取出一个缓冲区，池为空时先回收到期的暂存缓冲区，仍没有时新分配
+    pub fn get(self: &Arc<Self>) -> PooledBuf {
+        let idle = self.idle.lock().recover().pop();
+        let buf = idle
+            .or_else(|| {
+                self.reclaim();
+                self.idle.lock().recover().pop()
+            })
+            .unwrap_or_else(|| {
+                if let Some(budget) = &self.budget {
+                    budget.charge(self.size);
+                }
+                vec![0u8; self.size]
+            });
+        PooledBuf {
+            buf,
+            pool: Arc::clone(self),
+        }
+    }
+
+    /// 归还缓冲区
+    fn put(&self, buf: Vec<u8>) {
+        if buf.len() != self.size {
+            return;
+        }
+        let exceeded = self.budget.as_ref().is_some_and(|b| b.is_exceeded());
+        let mut idle = self.idle.lock().recover();
+        if idle.len() < self.max_idle && !exceeded {
+            idle.push(buf);
+        } else if let Some(budget) = &self.budget {
+            budget.release(self.size);
+        }
+    }
+
+    /// 释放所有空闲缓冲区，内存超出预算时调用
+    pub fn trim(&self) {
+        self.reclaim();
+        let idle = std::mem::take(&mut *self.idle.lock().recover());
+        if let Some(budget) = &self.budget {
+            budget.release(idle.len() * self.size);
+        }
+    }
+
+    /// 暂存缓冲区，`until` 之前不会被复用
+    fn hold(&self, buf: Vec<u8>, until: Instant) {
+        self.reclaim();
+        let mut held = self.held.lock().recover();
+        let at = held.partition_point(|(t, _)| *t <= until);
+        held.insert(at, (until, buf));
+    }
+
+    /// 把到期的暂存缓冲区归还池中
+    fn reclaim(&self) {
+        let now = Instant::now();
+        let expired: Vec<Vec<u8>> = {
+            let mut held = self.held.lock().recover();
+            let n = held.partition_point(|(t, _)| *t <= now);
+            held.drain(..n).map(|(_, buf)| buf).collect()
+        };
+        for buf in expired {
+            self.put(buf);
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            let count = self.idle.get_mut().recover().len() + self.held.get_mut().recover().len();
+            budget.release(count * self.size);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("size", &self.size)
+            .field("max_idle", &self.max_idle)
+            .field("idle", &self.idle_len())
+            .field("held", &self.held_len())
+            .finish()
+    }
+}
+
+/// 从池中取出的缓冲区，drop 时归还
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl PooledBuf {
+    /// 放弃缓冲区，但在 `hold` 时间内不让池复用 (内核可能仍在读取其中的数据)
+    pub fn hold(mut self, hold: Duration) {
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.hold(buf, Instant::now() + hold);
+    }
+}
+
+impl Clone for PooledBuf {
+    fn clone(&self) -> Self {
+        let mut buf = self.pool.get();
+        buf.copy_from_slice(&self.buf);
+        buf
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf")
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_reuse() {
+        let pool = BufferPool::with_max_idle(1024, 2);
+        let mut a = pool.get();
+        assert_eq!(a.len(), 1024);
+        a[0] = 7;
+        let ptr = a.as_ptr();
+        drop(a);
+        assert_eq!(pool.idle_len(), 1);
+
+        let b = pool.get();
+        assert_eq!(b.as_ptr(), ptr);
+        assert_eq!(pool.idle_len(), 0);
+        let c = b.clone();
+        assert_eq!(c[0], 7);
+
+        let d = pool.get();
+        drop((b, c, d));
+        assert_eq!(pool.idle_len(), 2);
+
+        assert_eq!(BufferPool::new(usize::MAX).max_idle, 1);
+        assert_eq!(
+            BufferPool::new(16 * 1024).max_idle,
+            MAX_IDLE_BYTES / (16 * 1024)
+        );
+    }
+
+    #[test]
+    fn test_hold() {
+        let pool = BufferPool::with_max_idle(1024, 4);
+        let a = pool.get();
+        let ptr = a.as_ptr();
+        a.hold(Duration::from_secs(60));
+        assert_eq!(pool.held_len(), 1);
+        assert_eq!(pool.idle_len(), 0);
+
+        // 暂存未到期，不会被复用
+        let b = pool.get();
+        assert_ne!(b.as_ptr(), ptr);
+        assert_eq!(pool.held_len(), 1);
+
+        // 到期后在池为空时回收
+        b.hold(Duration::ZERO);
+        assert_eq!(pool.held_len(), 2);
+        let c = pool.get();
+        assert_eq!(pool.held_len(), 1);
+        drop(c);
+        assert_eq!(pool.idle_len(), 1);
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = MemoryBudget::new(2048);
+        let pool = BufferPool::with_budget(1024, 4, Some(Arc::clone(&budget)));
+        let a = pool.get();
+        let b = pool.get();
+        assert_eq!(budget.used(), 2048);
+
+        // 复用空闲缓冲区不重复计入
+        drop(a);
+        let a = pool.get();
+        assert_eq!(budget.used(), 2048);
+
+        // 超出预算时归还的缓冲区直接释放
+        let c = pool.get();
+        assert!(budget.is_exceeded());
+        drop(c);
+        assert_eq!(pool.idle_len(), 0);
+        assert_eq!(budget.used(), 2048);
+
+        drop((a, b));
+        assert_eq!(pool.idle_len(), 2);
+        drop(pool);
+        assert_eq!(budget.used(), 0);
+    }
+}
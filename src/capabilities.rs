@@ -0,0 +1,238 @@
+//! 运行时能力检测模块
+//!
+//! 启动时探测内核实际支持的加速路径 (splice、io_uring、GSO、TPROXY、SO_REUSEPORT_CBPF)，
+//! 结果保存在全局 `Capabilities` 中供处理器查询
+
+use std::sync::OnceLock;
+
+/// UDP_SEGMENT (GSO) 选项，部分 libc 版本未导出
+#[cfg(target_os = "linux")]
+const UDP_SEGMENT: libc::c_int = 103;
+
+/// SO_ATTACH_REUSEPORT_CBPF 选项，部分 libc 版本未导出
+#[cfg(target_os = "linux")]
+const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+
+/// 运行时能力
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// splice() 零拷贝转发
+    pub splice: bool,
+    /// io_uring 异步 I/O
+    pub io_uring: bool,
+    /// UDP GSO (UDP_SEGMENT)
+    pub gso: bool,
+    /// 透明代理 (IP_TRANSPARENT，需要 CAP_NET_ADMIN)
+    pub tproxy: bool,
+    /// SO_REUSEPORT 的 cBPF 分流程序
+    pub reuseport_cbpf: bool,
+}
+
+impl Capabilities {
+    /// 获取全局能力 (首次调用时探测)
+    pub fn global() -> &'static Self {
+        static INSTANCE: OnceLock<Capabilities> = OnceLock::new();
+        INSTANCE.get_or_init(Capabilities::detect)
+    }
+
+    /// 探测当前系统的能力
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Self {
+        Self {
+            splice: probe_splice(),
+            io_uring: probe_io_uring(),
+            gso: probe_gso(),
+            tproxy: probe_tproxy(),
+            reuseport_cbpf: probe_reuseport_cbpf(),
+        }
+    }
+
+    /// 探测当前系统的能力 (非 Linux 平台均不支持)
+    #[cfg(not(target_os = "linux"))]
+    pub fn detect() -> Self {
+        Self::default()
+    }
+
+    /// 以 (名称, 是否可用) 列表形式返回
+    pub fn entries(&self) -> [(&'static str, bool); 5] {
+        [
+            ("splice", self.splice),
+            ("io_uring", self.io_uring),
+            ("gso", self.gso),
+            ("tproxy", self.tproxy),
+            ("reuseport_cbpf", self.reuseport_cbpf),
+        ]
+    }
+
+    /// 格式化为 JSON 对象
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .entries()
+            .iter()
+            .map(|(name, enabled)| format!("\"{}\":{}", name, enabled))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// 格式化为日志字符串
+    pub fn summary(&self) -> String {
+        let fields: Vec<String> = self
+            .entries()
+            .iter()
+            .map(|(name, enabled)| format!("{}={}", name, if *enabled { "yes" } else { "no" }))
+            .collect();
+        fields.join(" ")
+    }
+}
+
+/// 创建用于探测的临时 socket，返回 -1 表示失败
+#[cfg(target_os = "linux")]
+fn probe_socket(domain: libc::c_int, ty: libc::c_int) -> libc::c_int {
+    unsafe { libc::socket(domain, ty | libc::SOCK_CLOEXEC, 0) }
+}
+
+/// 设置 int 类型的 socket 选项
+#[cfg(target_os = "linux")]
+fn probe_setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    val: libc::c_int,
+) -> bool {
+    unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    }
+}
+
+/// splice: 对无效 fd 调用，ENOSYS 表示内核不支持，其他错误 (EBADF) 表示支持
+#[cfg(target_os = "linux")]
+fn probe_splice() -> bool {
+    let ret = unsafe { libc::splice(-1, std::ptr::null_mut(), -1, std::ptr::null_mut(), 1, 0) };
+    ret >= 0 || crate::get_sock_errno() != libc::ENOSYS
+}
+
+/// io_uring: 尝试创建 1 个条目的 ring (可能被 seccomp 或 sysctl 禁用)
+#[cfg(target_os = "linux")]
+fn probe_io_uring() -> bool {
+    // struct io_uring_params 共 120 字节，全部置零即可
+    let mut params = [0u8; 120];
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1u32, params.as_mut_ptr()) };
+    if fd < 0 {
+        return false;
+    }
+    unsafe {
+        libc::close(fd as libc::c_int);
+    }
+    true
+}
+
+/// GSO: 在 UDP socket 上设置 UDP_SEGMENT
+#[cfg(target_os = "linux")]
+fn probe_gso() -> bool {
+    let fd = probe_socket(libc::AF_INET, libc::SOCK_DGRAM);
+    if fd < 0 {
+        return false;
+    }
+    let ok = probe_setsockopt(fd, libc::SOL_UDP, UDP_SEGMENT, 1400);
+    unsafe {
+        libc::close(fd);
+    }
+    ok
+}
+
+/// TPROXY: 设置 IP_TRANSPARENT (需要 CAP_NET_ADMIN)
+#[cfg(target_os = "linux")]
+fn probe_tproxy() -> bool {
+    let fd = probe_socket(libc::AF_INET, libc::SOCK_STREAM);
+    if fd < 0 {
+        return false;
+    }
+    let ok = probe_setsockopt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1);
+    unsafe {
+        libc::close(fd);
+    }
+    ok
+}
+
+/// SO_REUSEPORT_CBPF: 挂载一个只返回 0 的 cBPF 程序
+#[cfg(target_os = "linux")]
+fn probe_reuseport_cbpf() -> bool {
+    let fd = probe_socket(libc::AF_INET, libc::SOCK_DGRAM);
+    if fd < 0 {
+        return false;
+    }
+    let mut ok = probe_setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1);
+    if ok {
+        // BPF_RET | BPF_K, 0
+        let mut filter = [libc::sock_filter {
+            code: 0x06,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        }];
+        let prog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        ok = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                SO_ATTACH_REUSEPORT_CBPF,
+                &prog as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            ) == 0
+        };
+    }
+    unsafe {
+        libc::close(fd);
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let caps = Capabilities {
+            splice: true,
+            gso: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            caps.to_json(),
+            "{\"splice\":true,\"io_uring\":false,\"gso\":true,\"tproxy\":false,\"reuseport_cbpf\":false}"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let caps = Capabilities {
+            io_uring: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            caps.summary(),
+            "splice=no io_uring=yes gso=no tproxy=no reuseport_cbpf=no"
+        );
+    }
+
+    #[test]
+    fn test_detect_is_cached() {
+        assert_eq!(Capabilities::global(), Capabilities::global());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_splice_detected_on_linux() {
+        assert!(Capabilities::detect().splice);
+    }
+}
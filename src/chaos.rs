@@ -0,0 +1,268 @@
+//! 故障注入 (--chaos)
+//!
+//! 在转发路径上模拟差的网络：每个方向的数据额外延迟 `delay` ± `jitter`，UDP 数据包按 `loss` 概率丢弃。
+//! UDP 数据包按各自的到期时间发出，抖动大于包间隔时会乱序；TCP 只延迟读取，不丢数据
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// 延迟队列中最多积压的 UDP 数据包数，超出的包直接丢弃
+pub const MAX_DELAYED_DATAGRAMS: usize = 4096;
+
+/// 故障注入参数
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Chaos {
+    /// 固定延迟
+    pub delay: Duration,
+    /// 延迟的随机浮动范围 (±)
+    pub jitter: Duration,
+    /// UDP 丢包概率 (百分比)
+    pub loss: f64,
+}
+
+impl Chaos {
+    /// 本次转发的延迟，在 `delay - jitter` 到 `delay + jitter` 之间均匀分布 (不小于 0)
+    pub fn sample_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let span = self.jitter.as_micros() as u64 * 2 + 1;
+        let offset = crate::get_fake_random_number_64() % span;
+        let micros = self.delay.as_micros() as u64 + offset;
+        Duration::from_micros(micros.saturating_sub(self.jitter.as_micros() as u64))
+    }
+
+    /// 是否丢弃这个数据包
+    pub fn should_drop(&self) -> bool {
+        // 以百万分之一为粒度抽样
+        let sample = (crate::get_fake_random_number_64() % 1_000_000) as f64;
+        self.loss > 0.0 && sample < self.loss * 10_000.0
+    }
+
+    /// 是否需要延迟转发
+    pub fn delays(&self) -> bool {
+        !self.delay.is_zero() || !self.jitter.is_zero()
+    }
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "delay={:?}, jitter={:?}, loss={}%",
+            self.delay, self.jitter, self.loss
+        )
+    }
+}
+
+/// 解析时长，支持 us/ms/s 后缀，不带后缀时为毫秒
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "ms"),
+    };
+    let value: u64 = num.parse().ok()?;
+    match unit {
+        "us" => Some(Duration::from_micros(value)),
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        _ => None,
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    /// 解析 `delay=50ms,jitter=10ms,loss=1%`，各项均可省略
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos::default();
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let invalid = || {
+                format!(
+                    "invalid chaos option '{}', expected e.g. delay=50ms,jitter=10ms,loss=1%",
+                    field
+                )
+            };
+            let (key, value) = field.split_once('=').ok_or_else(invalid)?;
+            match key.trim() {
+                "delay" => chaos.delay = parse_duration(value.trim()).ok_or_else(invalid)?,
+                "jitter" => chaos.jitter = parse_duration(value.trim()).ok_or_else(invalid)?,
+                "loss" => {
+                    let value = value.trim();
+                    chaos.loss = value
+                        .strip_suffix('%')
+                        .unwrap_or(value)
+                        .parse()
+                        .ok()
+                        .filter(|loss| (0.0..=100.0).contains(loss))
+                        .ok_or_else(invalid)?;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if chaos == Chaos::default() {
+            return Err(format!("chaos '{}' injects nothing", s));
+        }
+        Ok(chaos)
+    }
+}
+
+struct Delayed<T> {
+    due: Instant,
+    /// 到期时间相同时按入队顺序发出
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Delayed<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl<T> Eq for Delayed<T> {}
+
+impl<T> PartialOrd for Delayed<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Delayed<T> {
+    // BinaryHeap 是大顶堆，反转后最早到期的在堆顶
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+/// 按到期时间出队的延迟队列
+pub struct DelayQueue<T> {
+    heap: BinaryHeap<Delayed<T>>,
+    next_seq: u64,
+}
+
+impl<T> fmt::Debug for DelayQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DelayQueue")
+            .field("len", &self.heap.len())
+            .finish()
+    }
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// 入队，队列已满时返回 false
+    pub fn push(&mut self, due: Instant, item: T) -> bool {
+        if self.heap.len() >= MAX_DELAYED_DATAGRAMS {
+            return false;
+        }
+        self.heap.push(Delayed {
+            due,
+            seq: self.next_seq,
+            item,
+        });
+        self.next_seq += 1;
+        true
+    }
+
+    /// 取出一个在 `now` 之前到期的条目
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self.heap.peek()?.due > now {
+            return None;
+        }
+        self.heap.pop().map(|delayed| delayed.item)
+    }
+
+    /// 最早的到期时间
+    pub fn next_due(&self) -> Option<Instant> {
+        self.heap.peek().map(|delayed| delayed.due)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let chaos: Chaos = "delay=50ms,jitter=10ms,loss=1%".parse().unwrap();
+        assert_eq!(chaos.delay, Duration::from_millis(50));
+        assert_eq!(chaos.jitter, Duration::from_millis(10));
+        assert_eq!(chaos.loss, 1.0);
+        let chaos: Chaos = "loss=0.5".parse().unwrap();
+        assert_eq!(chaos.delay, Duration::ZERO);
+        assert_eq!(chaos.loss, 0.5);
+        assert!(!chaos.delays());
+        assert_eq!(
+            "delay=2s".parse::<Chaos>().unwrap().delay,
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            "jitter=300us".parse::<Chaos>().unwrap().jitter,
+            Duration::from_micros(300)
+        );
+        assert!("delay=5m".parse::<Chaos>().is_err());
+        assert!("loss=101%".parse::<Chaos>().is_err());
+        assert!("speed=1".parse::<Chaos>().is_err());
+        assert!("delay=0".parse::<Chaos>().is_err());
+    }
+
+    #[test]
+    fn test_sample() {
+        let chaos = Chaos {
+            delay: Duration::from_millis(5),
+            jitter: Duration::from_millis(10),
+            loss: 0.0,
+        };
+        for _ in 0..1000 {
+            assert!(chaos.sample_delay() <= Duration::from_millis(15));
+        }
+        assert!(!chaos.should_drop());
+        let always = Chaos {
+            loss: 100.0,
+            ..chaos
+        };
+        assert!(always.should_drop());
+    }
+
+    #[test]
+    fn test_delay_queue() {
+        let mut queue = DelayQueue::new();
+        let now = Instant::now();
+        queue.push(now + Duration::from_millis(20), "late");
+        queue.push(now + Duration::from_millis(10), "early");
+        queue.push(now + Duration::from_millis(10), "early2");
+        assert_eq!(queue.next_due(), Some(now + Duration::from_millis(10)));
+        assert_eq!(queue.pop_due(now), None);
+        let later = now + Duration::from_millis(15);
+        assert_eq!(queue.pop_due(later), Some("early"));
+        assert_eq!(queue.pop_due(later), Some("early2"));
+        assert_eq!(queue.pop_due(later), None);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop_due(now + Duration::from_millis(20)), Some("late"));
+        assert!(queue.is_empty());
+    }
+}
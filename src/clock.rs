@@ -0,0 +1,120 @@
+//! 时钟抽象
+//!
+//! 定时器、超时清理、LRU 时间戳、限速和故障注入的恢复时间都通过 `now()` 读取 (`get_monotonic_time` 也基于它)。
+//! 默认使用系统单调时钟；测试可在当前线程安装 `MockClock` 并手动推进，超时和定时器不依赖真实等待，结果可复现
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 时间来源
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// 当前时刻
+    fn now(&self) -> Instant;
+}
+
+/// 系统单调时钟
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 手动推进的时钟，创建后停在创建时刻，只在 `advance` 时前进
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed_ns: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        // 先确定 get_monotonic_time 的起点，模拟时间不会早于它
+        crate::log::get_monotonic_time();
+        Arc::new(Self {
+            start: Instant::now(),
+            elapsed_ns: AtomicU64::new(0),
+        })
+    }
+
+    /// 时间前进 `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// 创建以来推进的总时间
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns.load(Ordering::Relaxed))
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+/// 当前线程的时钟读数，没有安装时钟时为系统时间
+pub fn now() -> Instant {
+    CURRENT.with(|current| match *current.borrow() {
+        Some(ref clock) => clock.now(),
+        None => Instant::now(),
+    })
+}
+
+/// 在当前线程安装时钟，返回的守卫释放时恢复原来的时钟
+///
+/// 事件循环在 `run` 开始时安装 `Config::clock`，循环内的所有时间读数都来自它
+pub fn install(clock: Arc<dyn Clock>) -> ClockGuard {
+    let previous = CURRENT.with(|current| current.replace(Some(clock)));
+    ClockGuard { previous }
+}
+
+/// 安装时钟的守卫
+#[must_use = "the clock is uninstalled when the guard is dropped"]
+pub struct ClockGuard {
+    previous: Option<Arc<dyn Clock>>,
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let guard = install(clock.clone());
+        let (t0, ms0) = (now(), crate::log::get_monotonic_time());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(now(), t0);
+        assert_eq!(crate::log::get_monotonic_time(), ms0);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(now() - t0, Duration::from_secs(90));
+        assert_eq!(crate::log::get_monotonic_time() - ms0, 90_000);
+
+        // 其他线程不受影响
+        let other = std::thread::spawn(now).join().expect("join");
+        assert!(other < t0 + Duration::from_secs(90));
+
+        drop(guard);
+        assert!(now() < t0 + Duration::from_secs(90));
+    }
+}
@@ -0,0 +1,306 @@
+//! 中继之间的 LZ4 压缩 (--compress-out / --decompress-in)
+//!
+//! 压缩端在每个方向开头发送 8 字节魔数，之后按帧发送：2 字节原始长度、2 字节存储长度和数据。
+//! 存储长度小于原始长度时数据为 LZ4 块，否则为原始数据，不可压缩的数据只多出帧头。
+//!
+//! 解压端按开头是否为魔数判断对端是否压缩，不是时原样转发，因此 `--decompress-in` 也接受普通客户端；
+//! 回包只在客户端发来的流带魔数时压缩。只处理 TCP，LZ4 编解码需要 `lz4` 特性
+
+use std::io;
+
+/// 压缩流开头的魔数
+pub const MAGIC: &[u8; 8] = b"TPMLZ4\x00\x01";
+
+/// 帧头：原始长度和存储长度
+const FRAME_HEADER: usize = 4;
+
+/// 每帧最多压缩的原始数据长度
+pub const MAX_FRAME: usize = 4096;
+
+/// 一个方向的压缩端
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    /// 是否压缩，跟随对端时在第一次有数据时决定
+    enabled: Option<bool>,
+    magic_sent: bool,
+}
+
+impl Compressor {
+    /// 总是压缩 (`--compress-out`)
+    pub fn new() -> Self {
+        Self {
+            enabled: Some(true),
+            magic_sent: false,
+        }
+    }
+
+    /// 对端压缩时才压缩 (`--decompress-in` 的回包方向)
+    pub fn follow() -> Self {
+        Self {
+            enabled: None,
+            magic_sent: false,
+        }
+    }
+
+    /// 压缩 `input` 追加到 `out`，`peer_compresses` 为反方向是否收到了压缩流
+    pub fn compress(&mut self, input: &[u8], out: &mut Vec<u8>, peer_compresses: bool) {
+        if input.is_empty() {
+            return;
+        }
+        if !*self.enabled.get_or_insert(peer_compresses) {
+            out.extend_from_slice(input);
+            return;
+        }
+        if !self.magic_sent {
+            out.extend_from_slice(MAGIC);
+            self.magic_sent = true;
+        }
+        for chunk in input.chunks(MAX_FRAME) {
+            let start = out.len();
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.extend_from_slice(&[0; 2]);
+            if !compress_block(chunk, out) {
+                out.extend_from_slice(chunk);
+            }
+            let stored = (out.len() - start - FRAME_HEADER) as u16;
+            out[start + 2..start + FRAME_HEADER].copy_from_slice(&stored.to_be_bytes());
+        }
+    }
+
+    /// 输出不超过 `room` 字节时最多能输入的字节数
+    pub fn max_input(&self, room: usize) -> usize {
+        if self.enabled == Some(false) {
+            return room;
+        }
+        let room = room.saturating_sub(if self.magic_sent { 0 } else { MAGIC.len() });
+        let frames = room.div_ceil(MAX_FRAME + FRAME_HEADER);
+        room.saturating_sub(frames * FRAME_HEADER)
+    }
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 一个方向的解压端，积压不完整的魔数和帧
+#[derive(Debug, Clone, Default)]
+pub struct Decompressor {
+    /// 对端是否压缩，收到足够判断魔数的数据之前为 None
+    compressed: Option<bool>,
+    pending: Vec<u8>,
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 对端是否发来了压缩流
+    pub fn compressed(&self) -> Option<bool> {
+        self.compressed
+    }
+
+    /// 解压 `input` 和积压中的完整帧追加到 `out`，输出不超过 `cap` 字节，放不下的帧留在积压中
+    pub fn decompress(&mut self, input: &[u8], out: &mut Vec<u8>, cap: usize) -> io::Result<()> {
+        let mut input = input;
+        if self.compressed.is_none() {
+            self.pending.extend_from_slice(input);
+            input = &[];
+            let seen = self.pending.len().min(MAGIC.len());
+            if self.pending[..seen] != MAGIC[..seen] {
+                self.compressed = Some(false);
+                out.append(&mut self.pending);
+                return Ok(());
+            }
+            if seen < MAGIC.len() {
+                return Ok(());
+            }
+            self.pending.drain(..MAGIC.len());
+            self.compressed = Some(true);
+        }
+        if self.compressed == Some(false) {
+            out.extend_from_slice(input);
+            return Ok(());
+        }
+
+        self.pending.extend_from_slice(input);
+        let mut offset = 0;
+        while let Some((raw, stored)) = frame_header(&self.pending[offset..])? {
+            let frame = &self.pending[offset + FRAME_HEADER..];
+            if frame.len() < stored || out.len() + raw > cap {
+                break;
+            }
+            if stored == raw {
+                out.extend_from_slice(&frame[..stored]);
+            } else {
+                decompress_block(&frame[..stored], raw, out)?;
+            }
+            offset += FRAME_HEADER + stored;
+        }
+        self.pending.drain(..offset);
+        Ok(())
+    }
+
+    /// 积压中有完整的帧 (或非法帧头) 等待输出，无需读取新数据即可继续处理
+    pub fn ready(&self) -> bool {
+        if self.compressed != Some(true) {
+            return false;
+        }
+        match frame_header(&self.pending) {
+            Ok(Some((_, stored))) => self.pending.len() >= FRAME_HEADER + stored,
+            Ok(None) => false,
+            Err(_) => true,
+        }
+    }
+
+    /// 输出不超过 `room` 字节时最多能输入的字节数
+    ///
+    /// 解压后的长度由 `decompress` 的 `cap` 限制，这里只限制积压的压缩数据
+    pub fn max_input(&self, room: usize) -> usize {
+        room.saturating_sub(self.pending.len())
+    }
+}
+
+/// 解析帧头，数据不足时返回 None
+fn frame_header(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    if buf.len() < FRAME_HEADER {
+        return Ok(None);
+    }
+    let raw = usize::from(u16::from_be_bytes([buf[0], buf[1]]));
+    let stored = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+    if raw == 0 || raw > MAX_FRAME || stored == 0 || stored > raw {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad compressed frame length",
+        ));
+    }
+    Ok(Some((raw, stored)))
+}
+
+/// 压缩后比原始数据短时追加到 `out` 并返回 true
+#[cfg(feature = "lz4")]
+fn compress_block(chunk: &[u8], out: &mut Vec<u8>) -> bool {
+    let start = out.len();
+    out.resize(
+        start + lz4_flex::block::get_maximum_output_size(chunk.len()),
+        0,
+    );
+    match lz4_flex::block::compress_into(chunk, &mut out[start..]) {
+        Ok(len) if len < chunk.len() => {
+            out.truncate(start + len);
+            true
+        }
+        _ => {
+            out.truncate(start);
+            false
+        }
+    }
+}
+
+/// 没有 LZ4 时按原始数据发送，对端照常接收
+#[cfg(not(feature = "lz4"))]
+fn compress_block(_chunk: &[u8], _out: &mut Vec<u8>) -> bool {
+    false
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_block(block: &[u8], raw: usize, out: &mut Vec<u8>) -> io::Result<()> {
+    let start = out.len();
+    out.resize(start + raw, 0);
+    match lz4_flex::block::decompress_into(block, &mut out[start..]) {
+        Ok(len) if len == raw => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad lz4 block")),
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_block(_block: &[u8], _raw: usize, _out: &mut Vec<u8>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "lz4 block received but the lz4 feature is disabled",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(300);
+        let noise = crate::selftest::pattern(3 * MAX_FRAME + 5, 1);
+
+        let mut wire = Vec::new();
+        compressor.compress(&text, &mut wire, false);
+        compressor.compress(&noise, &mut wire, false);
+        assert!(wire.starts_with(MAGIC));
+        if cfg!(feature = "lz4") {
+            assert!(wire.len() < text.len());
+        }
+        let room = 10000;
+        assert!(compressor.max_input(room) + 3 * FRAME_HEADER <= room);
+
+        // 按任意边界分段到达，输出按 cap 分批
+        let mut plain = Vec::new();
+        for piece in wire.chunks(7) {
+            decompressor
+                .decompress(piece, &mut plain, usize::MAX)
+                .unwrap();
+        }
+        assert_eq!(decompressor.compressed(), Some(true));
+        assert_eq!(plain.len(), text.len() + noise.len());
+        assert!(plain.starts_with(&text) && plain.ends_with(&noise));
+
+        let mut decompressor = Decompressor::new();
+        let mut out = Vec::new();
+        decompressor.decompress(&wire, &mut out, MAX_FRAME).unwrap();
+        assert!(out.len() <= MAX_FRAME);
+        assert!(decompressor.ready());
+        while decompressor.ready() {
+            let mut more = Vec::new();
+            decompressor.decompress(&[], &mut more, MAX_FRAME).unwrap();
+            assert!(!more.is_empty() && more.len() <= MAX_FRAME);
+            out.extend_from_slice(&more);
+        }
+        assert_eq!(out, plain);
+
+        // 非法帧头
+        let mut decompressor = Decompressor::new();
+        let mut bad = MAGIC.to_vec();
+        bad.extend_from_slice(&[0, 4, 0, 5, 1, 2, 3, 4, 5]);
+        assert!(decompressor.decompress(&bad, &mut out, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        // 没有魔数的流原样转发，前缀相同的数据先积压
+        let mut decompressor = Decompressor::new();
+        let mut out = Vec::new();
+        decompressor
+            .decompress(b"TPM", &mut out, usize::MAX)
+            .unwrap();
+        assert!(out.is_empty() && decompressor.compressed().is_none());
+        decompressor
+            .decompress(b"X and more", &mut out, usize::MAX)
+            .unwrap();
+        assert_eq!(out, b"TPMX and more");
+        assert_eq!(decompressor.compressed(), Some(false));
+        assert!(!decompressor.ready());
+
+        let mut follow = Compressor::follow();
+        let mut wire = Vec::new();
+        follow.compress(b"", &mut wire, false);
+        follow.compress(b"reply", &mut wire, false);
+        follow.compress(b" again", &mut wire, true);
+        assert_eq!(wire, b"reply again");
+
+        let mut follow = Compressor::follow();
+        let mut wire = Vec::new();
+        follow.compress(b"reply", &mut wire, true);
+        assert!(wire.starts_with(MAGIC));
+    }
+}
@@ -2,25 +2,68 @@
 //!
 //! 命令行参数解析
 
-use crate::log::LogLevel;
-use crate::types::Address;
+use crate::alg::UdpAlgMap;
+use crate::backend::LbPolicy;
+use crate::chaos::Chaos;
+use crate::clock::Clock;
+use crate::log::{LogErrorPolicy, LogLevel};
+use crate::middleware::Middleware;
+use crate::obfs::Psk;
+use crate::sni::SniRoutes;
+use crate::sniff::ExpectProtocol;
+use crate::socks5::Socks5Upstream;
+use crate::types::{Address, Nat64Prefix};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 /// 监听 socket 缓冲区大小 (与 C++ 版本保持一致: 2MB)
 pub const LISTEN_FD_BUF_SIZE: usize = 2 * 1024 * 1024;
 
+/// 默认 socket 缓冲区大小 (与命令行 --sock-buf 默认值一致: 1024KB)
+pub const DEFAULT_SOCKET_BUF_SIZE: usize = 1024 * 1024;
+
 /// 定时器间隔 (与 C++ 版本保持一致: 400ms)
 pub const TIMER_INTERVAL_MS: u64 = 400;
 
 /// 连接清除间隔 (与 C++ 版本保持一致: 1000ms)
 pub const CONN_CLEAR_INTERVAL_MS: u64 = 1000;
 
+/// 默认统计输出间隔 (与 C++ 版本保持一致: 10s)
+pub const DEFAULT_STATS_INTERVAL_SECS: u64 = 10;
+
+/// poll 最大等待时间 (无定时器到期时的上限: 1000ms)
+pub const MAX_POLL_TIMEOUT_MS: u64 = 1000;
+
 /// UDP 数据包最大长度 (与 C++ 版本保持一致: 65536)
 pub const MAX_DATA_LEN_UDP: usize = 65536;
 
 /// TCP 数据包最大长度 (与 C++ 版本保持一致: 4096*4 = 16384)
 pub const MAX_DATA_LEN_TCP: usize = 4096 * 4;
 
+/// MSG_ZEROCOPY 每个发送方向最多同时等待完成通知的缓冲区数，超出后改用普通发送
+pub const ZEROCOPY_MAX_INFLIGHT: usize = 4;
+
+/// 连接关闭时仍未收到完成通知的 MSG_ZEROCOPY 缓冲区，经过该时间后才回到缓冲区池
+pub const ZEROCOPY_HOLD_MS: u64 = 30 * 1000;
+
+/// 缓冲区自动调整的观测周期
+pub const AUTOTUNE_INTERVAL_MS: u64 = 250;
+
+/// 缓冲区自动调整时单个 socket 缓冲区的上限
+pub const AUTOTUNE_MAX_BUF: usize = 16 * 1024 * 1024;
+
+/// 每个连接最多尝试加入 sockmap 的次数 (加入时接收队列中有数据会撤销)
+pub const SOCKMAP_MAX_TRIES: u8 = 4;
+
+/// 由 sockmap 转发的连接读到 EOF 后，检查内核是否转发完剩余数据的间隔
+pub const SOCKMAP_DRAIN_MS: u64 = 10;
+
+/// 默认 TCP 监听队列长度 (实际上限还受 net.core.somaxconn 限制)
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 512;
+
 /// 默认最大连接数 (与 C++ 版本保持一致: 20000)
 pub const DEFAULT_MAX_CONNECTIONS: usize = 20000;
 
@@ -47,33 +90,447 @@ pub enum FwdType {
     FwdType6to4,
 }
 
+/// TCP keepalive 参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// 连接空闲多久后开始发送探测包
+    pub idle: Duration,
+    /// 探测包间隔
+    pub interval: Duration,
+    /// 连续多少个探测包无响应后断开连接
+    pub count: u32,
+}
+
+impl FromStr for TcpKeepalive {
+    type Err = String;
+
+    /// 解析 `idle,interval,count` (秒, 秒, 次数)，例如 `60,10,6`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid tcp keepalive '{}', expected <idle>,<interval>,<count>, e.g. 60,10,6",
+                s
+            )
+        };
+        let fields = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().ok().filter(|&v| v > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        match fields[..] {
+            [idle, interval, count] => Ok(TcpKeepalive {
+                idle: Duration::from_secs(u64::from(idle)),
+                interval: Duration::from_secs(u64::from(interval)),
+                count,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// UDP 会话保活参数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpKeepalive {
+    /// 客户端方向空闲多久后向远程发送保活数据包，之后按同样间隔重复
+    pub interval: Duration,
+    /// 保活数据包内容，为空时发送零长度数据包
+    pub payload: Vec<u8>,
+}
+
+impl FromStr for UdpKeepalive {
+    type Err = String;
+
+    /// 解析 `interval[,hex]` (秒, 十六进制内容)，例如 `25` 或 `25,00`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid udp keepalive '{}', expected <interval>[,<hex payload>], e.g. 25 or 25,00",
+                s
+            )
+        };
+        let (interval, hex) = s.split_once(',').unwrap_or((s, ""));
+        let interval = interval
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&v| v > 0)
+            .ok_or_else(invalid)?;
+        let hex = hex.trim();
+        if hex.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let payload = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(UdpKeepalive {
+            interval: Duration::from_secs(interval),
+            payload,
+        })
+    }
+}
+
+/// SO_LINGER 设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linger {
+    /// 关闭 SO_LINGER：close 立即返回，内核在后台发送剩余数据和 FIN
+    Off,
+    /// close 最多等待指定秒数发送剩余数据，为 0 时丢弃缓冲区直接发送 RST
+    Secs(u32),
+}
+
+impl FromStr for Linger {
+    type Err = String;
+
+    /// 解析 `off` 或秒数，例如 `0`、`5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "off" => Ok(Linger::Off),
+            v => v.parse::<u32>().map(Linger::Secs).map_err(|_| {
+                format!(
+                    "invalid linger '{}', expected off or a number of seconds",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Linger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Linger::Off => write!(f, "off"),
+            Linger::Secs(secs) => write!(f, "{}s", secs),
+        }
+    }
+}
+
+/// socket 就绪通知方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PollMode {
+    /// 边沿触发：每次通知后把 socket 读到 WouldBlock 为止，唤醒次数最少
+    #[default]
+    Edge,
+    /// 水平触发：每次通知只处理一次收发 (一个连接或数据包)，处理完重新注册 socket，
+    /// 还有数据时 poll 再次通知。单个繁忙连接不会连续占用事件循环，代价是更多的唤醒和系统调用
+    Level,
+}
+
+impl PollMode {
+    /// 一次事件最多处理的连接或数据包数：边沿触发为 `edge`，水平触发为 1
+    pub fn batch(self, edge: usize) -> usize {
+        match self {
+            PollMode::Edge => edge,
+            PollMode::Level => 1,
+        }
+    }
+}
+
+impl FromStr for PollMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edge" => Ok(PollMode::Edge),
+            "level" => Ok(PollMode::Level),
+            _ => Err(format!("invalid poll mode '{}', must be edge or level", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for PollMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PollMode::Edge => write!(f, "edge"),
+            PollMode::Level => write!(f, "level"),
+        }
+    }
+}
+
+/// 后端熔断参数：连续 `threshold` 次连接失败后，`cooldown` 内到该后端的新连接直接关闭
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// 打开熔断器的连续连接失败次数
+    pub threshold: u32,
+    /// 熔断持续时间，之后放行一个连接试探后端
+    pub cooldown: Duration,
+}
+
+impl FromStr for CircuitBreaker {
+    type Err = String;
+
+    /// 解析 `failures,cooldown` (次数, 秒)，例如 `5,30`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid circuit breaker '{}', expected <failures>,<cooldown>, e.g. 5,30",
+                s
+            )
+        };
+        let fields = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().ok().filter(|&v| v > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        match fields[..] {
+            [threshold, cooldown] => Ok(CircuitBreaker {
+                threshold,
+                cooldown: Duration::from_secs(u64::from(cooldown)),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// 自动封禁参数：同一客户端 IP 在 `window` 内被拒绝 `threshold` 次后，`duration` 内拒绝它的新连接
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoBan {
+    /// 触发封禁的拒绝次数
+    pub threshold: u32,
+    /// 统计拒绝次数的时间窗口
+    pub window: Duration,
+    /// 封禁时长
+    pub duration: Duration,
+}
+
+impl FromStr for AutoBan {
+    type Err = String;
+
+    /// 解析 `failures,window,ban` (次数, 秒, 秒)，例如 `5,60,600`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid auto ban '{}', expected <failures>,<window>,<ban>, e.g. 5,60,600",
+                s
+            )
+        };
+        let fields = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().ok().filter(|&v| v > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        match fields[..] {
+            [threshold, window, duration] => Ok(AutoBan {
+                threshold,
+                window: Duration::from_secs(u64::from(window)),
+                duration: Duration::from_secs(u64::from(duration)),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// 外连 socket 的源端口范围 (含两端)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// 起始端口
+    pub start: u16,
+    /// 结束端口
+    pub end: u16,
+}
+
+impl PortRange {
+    /// 范围内的端口数
+    pub fn len(&self) -> u32 {
+        u32::from(self.end) - u32::from(self.start) + 1
+    }
+
+    /// 范围是否为空 (构造时保证 start <= end，始终为 false)
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// 解析 `start-end`，例如 `40000-50000`，单个端口也可以只写一个数字
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid port range '{}', expected <start>-<end> within 1-65535, e.g. 40000-50000",
+                s
+            )
+        };
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let port = |v: &str| v.trim().parse::<u16>().ok().filter(|&v| v > 0);
+        match (port(start), port(end)) {
+            (Some(start), Some(end)) if start <= end => Ok(PortRange { start, end }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// 按目标端口覆盖的 UDP 会话超时
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UdpTimeoutMap {
+    timeouts: HashMap<u16, Duration>,
+}
+
+impl UdpTimeoutMap {
+    /// 设置目标端口的超时，返回该端口原来的超时
+    pub fn insert(&mut self, port: u16, timeout: Duration) -> Option<Duration> {
+        self.timeouts.insert(port, timeout)
+    }
+
+    /// 目标端口的超时，没有覆盖时返回 None
+    pub fn get(&self, port: u16) -> Option<Duration> {
+        self.timeouts.get(&port).copied()
+    }
+
+    /// 是否没有任何覆盖
+    pub fn is_empty(&self) -> bool {
+        self.timeouts.is_empty()
+    }
+}
+
+impl FromStr for UdpTimeoutMap {
+    type Err = String;
+
+    /// 解析 `port=secs,...`，例如 `53=5,27015=300`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid udp timeout map '{}', expected <port>=<secs>[,<port>=<secs>...], e.g. 53=5,27015=300",
+                s
+            )
+        };
+        let mut map = UdpTimeoutMap::default();
+        for item in s.split(',') {
+            let (port, secs) = item.split_once('=').ok_or_else(invalid)?;
+            let port = port
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|&v| v > 0)
+                .ok_or_else(invalid)?;
+            let secs = secs
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|&v| v > 0)
+                .ok_or_else(invalid)?;
+            if map.insert(port, Duration::from_secs(secs)).is_some() {
+                return Err(format!(
+                    "duplicate port {} in udp timeout map '{}'",
+                    port, s
+                ));
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl std::fmt::Display for UdpTimeoutMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ports: Vec<_> = self.timeouts.iter().collect();
+        ports.sort();
+        for (i, (port, timeout)) in ports.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", port, timeout.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// 转发流量的 DSCP/TOS 和 SO_MARK 标记，供 tc/QoS 分类和策略路由匹配
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketMark {
+    /// IP_TOS/IPV6_TCLASS 字节，DSCP 占高 6 位
+    pub tos: Option<u8>,
+    /// SO_MARK (仅 Linux，需要 CAP_NET_ADMIN)
+    pub fwmark: Option<u32>,
+}
+
+impl SocketMark {
+    /// 是否没有设置任何标记
+    pub fn is_empty(&self) -> bool {
+        self.tos.is_none() && self.fwmark.is_none()
+    }
+}
+
 /// 配置结构体
 #[derive(Debug, Clone)]
 pub struct Config {
     /// 监听地址
     pub listen_addr: Address,
-    /// 远程地址
-    pub remote_addr: Address,
+    /// 双栈：监听地址为 0.0.0.0 或 [::] 时分别创建 IPv4 和 IPv6 (IPV6_V6ONLY) 监听 socket
+    pub dual_stack: bool,
+    /// IPv6 监听 socket 的 IPV6_V6ONLY，None 时使用系统默认 (net.ipv6.bindv6only)
+    pub v6only: Option<bool>,
+    /// 不监听，把 fd 0 上继承的已连接 socket (inetd 模式) 转发到远程地址，连接结束后退出
+    pub inherit_stdin: bool,
+    /// 监听地址为组播地址时加入组的接口 (`-l 组地址:端口@接口`)，None 时由内核按路由选择
+    pub multicast_interface: Option<String>,
+    /// 远程的回包发往组播组，而不是发回客户端
+    pub multicast_reply: bool,
+    /// 广播中继：监听和会话 socket 设置 SO_BROADCAST，会话 socket 不连接，远程可以是广播地址
+    pub udp_broadcast: bool,
+    /// 远程地址 (多个时按负载均衡策略分配)
+    pub remote_addrs: Vec<Address>,
+    /// 远程地址权重，与 remote_addrs 一一对应
+    pub remote_weights: Vec<u32>,
+    /// Happy Eyeballs 备用地址，与 remote_addrs 一一对应 (远程地址为同时解析出 IPv6 和 IPv4 的域名时为 IPv4 地址)
+    pub remote_fallbacks: Vec<Option<Address>>,
+    /// 负载均衡策略
+    pub lb_policy: LbPolicy,
+    /// UDP 按客户端地址固定后端
+    pub udp_sticky: bool,
+    /// 跟踪 QUIC 连接 ID，客户端地址变化 (如 Wi-Fi 切换到蜂窝网络) 后沿用原会话
+    pub udp_quic: bool,
     /// 启用 TCP
     pub enable_tcp: bool,
     /// 启用 UDP
     pub enable_udp: bool,
     /// Socket 缓冲区大小
     pub socket_buf_size: usize,
+    /// 按吞吐量和积压自动调整 TCP 连接的 socket 缓冲区，值为超出初始大小部分的全局预算 (字节)，
+    /// None 时固定为 socket_buf_size
+    pub tcp_buf_autotune: Option<usize>,
     /// 监听 socket 缓冲区大小
     pub listen_fd_buf_size: usize,
     /// 日志级别
     pub log_level: LogLevel,
     /// 显示位置信息
     pub log_position: bool,
+    /// 日志和连接记录中隐去客户端地址
+    pub log_anonymize_ips: bool,
     /// 禁用颜色
     pub disable_color: bool,
     /// 最大连接数
     pub max_connections: usize,
+    /// 缓冲区和连接状态的总内存预算 (字节)，新连接会超出时拒绝，已超出时关闭最久未活动的连接
+    pub max_memory: Option<usize>,
+    /// TCP 监听队列长度
+    pub listen_backlog: u32,
+    /// 监听 socket 设置 SO_REUSEPORT (仅 Linux，默认启用)
+    pub reuseport: bool,
+    /// socket 就绪通知方式
+    pub poll_mode: PollMode,
     /// TCP 超时
     pub tcp_timeout: Duration,
     /// UDP 超时 (与 C++ 版本的 conn_timeout_udp=180s 对齐)
     pub udp_timeout: Duration,
+    /// 按目标端口覆盖的 UDP 超时，未列出的端口使用 `udp_timeout`
+    pub udp_timeout_map: UdpTimeoutMap,
+    /// 按远程端口挂载的 UDP 应用层网关 (远程从新端口回包的协议，如 TFTP)
+    pub udp_algs: UdpAlgMap,
+    /// 客户端 -> 远程方向没有数据的超时 (TCP 和 UDP)，None 时只按总超时清理
+    pub idle_timeout_c2s: Option<Duration>,
+    /// 远程 -> 客户端方向没有数据的超时 (TCP 和 UDP)，None 时只按总超时清理
+    pub idle_timeout_s2c: Option<Duration>,
     /// 连接清除比例 (每 conn_clear_ratio 个连接清除 1 个)
     pub conn_clear_ratio: u32,
     /// 连接清除最小数量
@@ -84,12 +541,137 @@ pub struct Config {
     pub timer_interval: u64,
     /// 转发类型
     pub fwd_type: FwdType,
+    /// 4to6/6to4 翻译使用的 NAT64 前缀，默认为 IPv4 映射地址前缀 `::ffff:0:0/96`
+    pub nat64_prefix: Nat64Prefix,
     /// 绑定的网络接口名称
     pub bind_interface: Option<String>,
+    /// 上游 SOCKS5 代理，TCP 和 UDP 外连都经由代理
+    pub upstream: Option<Socks5Upstream>,
+    /// TLS SNI 路由表，设置时 TCP 连接按 ClientHello 中的主机名选择后端
+    pub sni_routes: Option<SniRoutes>,
+    /// TCP 客户端应使用的协议，开头数据不符合的连接不转发
+    pub expect_protocol: ExpectProtocol,
+    /// 作为 SOCKS5 服务端：TCP 连接的后端为客户端 CONNECT 的目标，UDP 监听 socket 作为 UDP ASSOCIATE 中继
+    pub socks5_listen: bool,
+    /// SOCKS5 服务端要求的用户名和密码，None 时不认证
+    pub socks5_auth: Option<(String, String)>,
+    /// 透明代理：监听 socket 设置 IP_TRANSPARENT，外连使用客户端源 IP (仅 Linux)
+    pub transparent: bool,
+    /// 外连 socket 在 connect 前绑定的源地址，每个地址族最多一个
+    pub bind_source: Vec<IpAddr>,
+    /// 外连 socket 的源端口范围，None 时由内核分配
+    pub source_ports: Option<PortRange>,
+    /// TCP keepalive 参数，为 None 时不启用
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// UDP 会话保活参数，为 None 时不发送保活数据包
+    pub udp_keepalive: Option<UdpKeepalive>,
+    /// 会话 socket 启用 IP_RECVERR，收到 ICMP 不可达等错误时立即关闭会话 (仅 Linux)
+    pub udp_recverr: bool,
+    /// TCP_NODELAY (默认启用)
+    pub tcp_nodelay: bool,
+    /// TCP_QUICKACK，每次读取后重新设置 (仅 Linux)
+    pub tcp_quickack: bool,
+    /// TCP 拥塞控制算法 (仅 Linux)
+    pub tcp_congestion: Option<String>,
+    /// TCP_USER_TIMEOUT：已发送数据超过该时间未被确认即断开连接 (仅 Linux)
+    pub tcp_user_timeout: Option<Duration>,
+    /// 单次发送达到该字节数时使用 MSG_ZEROCOPY，None 时不启用 (仅 Linux)
+    pub tcp_zerocopy: Option<usize>,
+    /// 连接建立后交给 eBPF sockmap 在内核中转发 (仅 Linux)
+    pub tcp_sockmap: bool,
+    /// SO_LINGER，None 时保持系统默认
+    pub tcp_linger: Option<Linger>,
+    /// 超时清理的 TCP 连接以 RST 关闭 (SO_LINGER 0)
+    pub abort_on_timeout: bool,
+    /// 连接后端失败 (拒绝或超时) 后的重试次数，按指数退避等待，0 表示不重试
+    pub connect_retries: u32,
+    /// 后端熔断，None 时不启用
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// 多次被拒绝的客户端 IP 临时封禁
+    pub auto_ban: Option<AutoBan>,
+    /// 外连 socket 的 DSCP/TOS 和 SO_MARK 标记
+    pub socket_mark: SocketMark,
+    /// 监听 socket 也设置标记 (TCP 接受的连接继承监听 socket 的标记)
+    pub mark_inbound: bool,
     /// 日志文件路径
     pub log_file: Option<String>,
-    /// 启用 UDP 分片转发
+    /// 日志文件写入失败时的处理策略
+    pub log_on_error: LogErrorPolicy,
+    /// 启用 UDP 分片转发：外连 socket 的 DF 跟随客户端数据包是否分片到达 (仅 Linux)
     pub enable_udp_fragment: bool,
+    /// 客户端 -> 远程 UDP 数据包的 IP 包长度上限，超出且完整到达的数据包丢弃
+    pub udp_mtu: Option<usize>,
+    /// 转发 UDP 数据包时沿用收到的 TOS/ECN 和 TTL，TTL 减一 (仅 Linux)
+    pub udp_preserve_tos_ttl: bool,
+    /// 全局限速 (字节/秒)
+    pub rate_limit: Option<u64>,
+    /// 单连接限速 (字节/秒)
+    pub rate_limit_per_conn: Option<u64>,
+    /// 故障注入 (延迟、抖动、UDP 丢包)，None 时不启用
+    pub chaos: Option<Chaos>,
+    /// 客户端 -> 后端方向数据的镜像目标
+    pub mirror: Option<Address>,
+    /// 解密客户端发来的 TCP 流 (上一个中继的 `encrypt_out`)
+    pub decrypt_in: Option<Psk>,
+    /// 加密发往后端的 TCP 流 (下一个中继用 `decrypt_in` 解密)
+    pub encrypt_out: Option<Psk>,
+    /// 客户端发来 LZ4 压缩流时解压，并压缩回包 (上一个中继的 `compress_out`)
+    pub decompress_in: bool,
+    /// LZ4 压缩发往后端的 TCP 流 (下一个中继用 `decompress_in` 解压)
+    pub compress_out: bool,
+    /// FTP 应用层网关：改写控制连接中的数据通道地址并打开临时监听
+    pub ftp_alg: bool,
+    /// 库用户挂载的中间件，排在 `ftp_alg` 和 `udp_algs` 之前
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    /// 事件循环使用的时钟，None 时为系统时钟 (测试中用 `MockClock` 控制超时和定时器)
+    pub clock: Option<Arc<dyn Clock>>,
+    /// 租户名，用于统计汇总和日志标识
+    pub tenant: Option<String>,
+    /// 同一租户所有映射的 TCP 连接和 UDP 会话总数上限
+    pub tenant_max_connections: Option<usize>,
+    /// 同一租户所有映射共享的带宽 (字节/秒)
+    pub tenant_rate_limit: Option<u64>,
+    /// 租户 ACL：非空时只接受这些网段 (CIDR) 的客户端
+    pub tenant_allow: Vec<String>,
+    /// 租户 ACL：拒绝这些网段的客户端，先于 `tenant_allow` 检查
+    pub tenant_deny: Vec<String>,
+    /// 停止时等待已有连接关闭的最长时间，为 0 时立即关闭
+    pub drain_timeout: Duration,
+    /// 后端健康检查间隔，为 0 时不检查
+    pub health_check_interval: Duration,
+    /// 请求路由器 (PCP/NAT-PMP/UPnP IGD) 把相同的外部端口映射到监听端口，并定期续期
+    pub upnp: bool,
+    /// STUN 服务端 `host[:port]`，从 UDP 监听 socket 发现公网地址
+    pub stun_server: Option<String>,
+    /// 连接/拒绝/后端状态事件以 JSON POST 到这个 `http://` 地址
+    pub webhook: Option<String>,
+    /// 连接建立时执行的命令
+    pub on_connect_exec: Option<String>,
+    /// 连接关闭时执行的命令
+    pub on_close_exec: Option<String>,
+    /// 每秒最多执行的命令数
+    pub exec_rate: u32,
+    /// GeoLite2 Country/City 数据库路径，用于标注和过滤客户端国家
+    pub geoip_db: Option<String>,
+    /// GeoLite2 ASN 数据库路径，用于在日志中标注 ASN
+    pub geoip_asn_db: Option<String>,
+    /// 只接受这些国家 (两位代码) 的客户端，为空时不限制
+    pub geo_allow: Vec<String>,
+    /// 拒绝这些国家的客户端
+    pub geo_deny: Vec<String>,
+    /// 统计输出间隔，为 0 时不输出
+    pub stats_interval: Duration,
+    /// 累计统计状态文件，启动时加载、退出时保存
+    pub stats_file: Option<String>,
+    /// 启动时清零累计统计，不加载状态文件
+    pub reset_stats: bool,
+    /// 平滑升级控制 socket 路径：启动时从该路径上运行的旧进程接管监听 socket，之后在此等待下一次升级
+    pub upgrade_socket: Option<String>,
+    /// 在终端实时刷新连接表，控制台不再输出日志
+    pub top: bool,
+    /// 处理 SIGTERM/SIGINT (停止)、SIGUSR1 (输出连接表)、SIGUSR2 (切换 debug 日志)，
+    /// 会修改进程的信号处理，只应由独立运行的程序开启
+    pub handle_signals: bool,
 }
 
 impl Config {
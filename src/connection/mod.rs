@@ -2,56 +2,193 @@
 //!
 //! TCP 连接和 UDP 会话的数据结构定义
 
+use crate::autotune::BufTune;
+use crate::backend::Backend;
+use crate::bufpool::PooledBuf;
+#[cfg(target_os = "linux")]
+use crate::config::ZEROCOPY_HOLD_MS;
+use crate::event::observer::{CloseReason, ConnectionSummary, Protocol};
 use crate::fd_manager::Fd64;
+use crate::middleware::{TcpFilter, UdpFilter};
+use crate::mirror::MirrorStream;
+use crate::obfs::ObfsStreams;
+use crate::ratelimit::TokenBucket;
+#[cfg(target_os = "linux")]
+use crate::sockmap::SockmapPair;
+use crate::socks5::{Socks5Association, Socks5Handshake};
+use crate::stats::Direction;
 use crate::types::Address;
+#[cfg(target_os = "linux")]
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// 分配新的连接/会话 ID (TCP 和 UDP 共用，进程内单调递增，从 1 开始)
+pub fn next_conn_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Happy Eyeballs 备用地址的连接状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    /// 首选地址仍在领先时间内，尚未连接备用地址
+    Pending(Address),
+    /// 正在连接备用地址
+    Connecting(Fd64),
+}
 
 /// TCP 端点
+///
+/// 待发送数据最多分两段：第一段发送不完时又读到的新数据作为第二段 (`tail`)，
+/// 两段一起用 writev 发送，不必先等第一段发完再读取
 #[derive(Debug, Clone)]
 pub struct TcpEndpoint {
     /// 文件描述符
     pub fd64: Fd64,
-    /// 数据缓冲区 (fallback 用)
-    pub data: Vec<u8>,
+    /// 待发送数据的缓冲区，只在发送不完整时持有，发完后归还缓冲区池
+    pub data: Option<PooledBuf>,
     /// 缓冲区起始位置
     pub begin: usize,
-    /// 有效数据长度
+    /// 待发送数据总长度 (两段之和)
     pub data_len: usize,
+    /// 第二段待发送数据的缓冲区和区间
+    pub tail: Option<(PooledBuf, Range<usize>)>,
+    /// MSG_ZEROCOPY 发送状态
+    #[cfg(target_os = "linux")]
+    pub zerocopy: ZeroCopy,
 }
 
 impl TcpEndpoint {
-    /// 创建新的 TCP 端点
-    pub fn new(fd64: Fd64, buf_size: usize) -> Self {
+    /// 创建新的 TCP 端点 (不分配缓冲区)
+    pub fn new(fd64: Fd64) -> Self {
         Self {
             fd64,
-            data: vec![0u8; buf_size],
+            data: None,
             begin: 0,
             data_len: 0,
+            tail: None,
+            #[cfg(target_os = "linux")]
+            zerocopy: ZeroCopy::default(),
         }
     }
 
     /// 清空缓冲区
     pub fn clear(&mut self) {
+        self.data = None;
         self.begin = 0;
         self.data_len = 0;
+        self.tail = None;
     }
 
-    /// 获取可用空间
-    pub fn available_space(&self) -> usize {
-        self.data.len() - (self.begin + self.data_len)
+    /// 第二段数据长度
+    fn tail_len(&self) -> usize {
+        self.tail.as_ref().map_or(0, |(_, range)| range.len())
     }
 
-    /// 获取读取切片
+    /// 获取第一段数据的读取切片
     pub fn read_slice(&self) -> &[u8] {
-        &self.data[self.begin..self.begin + self.data_len]
+        match self.data {
+            Some(ref data) => &data[self.begin..self.begin + self.data_len - self.tail_len()],
+            None => &[],
+        }
     }
 
-    /// 获取写入位置
-    pub fn write_pos(&mut self) -> &mut [u8] {
-        let start = self.begin + self.data_len;
-        &mut self.data[start..]
+    /// 获取第二段数据的读取切片
+    pub fn tail_slice(&self) -> &[u8] {
+        match self.tail {
+            Some((ref data, ref range)) => &data[range.clone()],
+            None => &[],
+        }
+    }
+
+    /// 是否已有两段待发送数据 (此时不再追加)
+    pub fn is_full(&self) -> bool {
+        self.tail.is_some()
+    }
+
+    /// 接管 `buf[begin..end]` 作为待发送数据
+    pub fn stash(&mut self, buf: PooledBuf, begin: usize, end: usize) {
+        self.data = Some(buf);
+        self.begin = begin;
+        self.data_len = end - begin;
+        self.tail = None;
+    }
+
+    /// 把 `buf[begin..end]` 追加到待发送数据之后，没有待发送数据时等同于 `stash`
+    pub fn append(&mut self, buf: PooledBuf, begin: usize, end: usize) {
+        if self.data_len == 0 {
+            self.stash(buf, begin, end);
+            return;
+        }
+        debug_assert!(self.tail.is_none(), "endpoint already holds two segments");
+        self.data_len += end - begin;
+        self.tail = Some((buf, begin..end));
+    }
+
+    /// 标记已发送 `n` 字节，第一段发完时换到第二段，全部发完时归还缓冲区
+    pub fn consume(&mut self, n: usize) {
+        let head_len = self.data_len - self.tail_len();
+        if n >= head_len {
+            if let Some((buf, range)) = self.tail.take() {
+                let skip = n - head_len;
+                self.data = Some(buf);
+                self.begin = range.start + skip;
+                self.data_len = range.len() - skip;
+                if self.data_len == 0 {
+                    self.clear();
+                }
+                return;
+            }
+        }
+        self.begin += n;
+        self.data_len -= n;
+        if self.data_len == 0 {
+            self.clear();
+        }
+    }
+}
+
+/// MSG_ZEROCOPY 发送状态 (仅 Linux)
+///
+/// 内核按 socket 为每次成功的零拷贝发送分配递增序号，完成通知给出已完成的序号区间。
+/// 通知到达前缓冲区不能复用，连接关闭时仍未完成的缓冲区交给缓冲区池暂存 `ZEROCOPY_HOLD_MS`
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Default)]
+pub struct ZeroCopy {
+    /// socket 是否开启了 SO_ZEROCOPY，None 表示尚未检查
+    pub enabled: Option<bool>,
+    /// 下一次零拷贝发送的序号
+    pub next_seq: u32,
+    /// 等待完成通知的缓冲区及其序号
+    pub inflight: VecDeque<(u32, PooledBuf)>,
+}
+
+#[cfg(target_os = "linux")]
+impl ZeroCopy {
+    /// 记录一次成功的零拷贝发送，持有缓冲区直到完成
+    pub fn push(&mut self, buf: PooledBuf) {
+        self.inflight.push_back((self.next_seq, buf));
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    /// 处理序号 `lo..=hi` 的完成通知，归还对应的缓冲区
+    pub fn complete(&mut self, lo: u32, hi: u32) {
+        let span = hi.wrapping_sub(lo);
+        self.inflight.retain(|(seq, _)| seq.wrapping_sub(lo) > span);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ZeroCopy {
+    fn drop(&mut self) {
+        let hold = Duration::from_millis(ZEROCOPY_HOLD_MS);
+        for (_, buf) in self.inflight.drain(..) {
+            buf.hold(hold);
+        }
     }
 }
 
@@ -98,31 +235,78 @@ impl SplicePipe {
 }
 
 /// TCP 连接对
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct TcpConnection {
+    /// 连接 ID，日志中以 `#id` 标识
+    pub id: u64,
     /// 本地端
     pub local: TcpEndpoint,
     /// 远程端
     pub remote: TcpEndpoint,
     /// 源地址字符串
     pub addr_s: String,
+    /// 客户端地址，用于拒绝日志和自动封禁
+    pub peer: Option<SocketAddr>,
     /// 创建时间戳
     pub create_time: u64,
+    /// 接受连接的时刻，用于计算连接建立和首字节延迟
+    pub accept_time: Instant,
     /// 最后活跃时间
     pub last_active_time: Arc<AtomicU64>,
+    /// 最后一次转发客户端 -> 远程数据的时间 (毫秒)
+    pub last_up_time: u64,
+    /// 最后一次转发远程 -> 客户端数据的时间 (毫秒)
+    pub last_down_time: u64,
     /// 远程端是否仍在连接中（非阻塞连接尚未完成）
     pub remote_connecting: bool,
+    /// 与首选地址竞速的备用地址连接，连接完成后为 None
+    pub fallback: Option<Fallback>,
+    /// 已重试连接后端的次数
+    pub connect_attempts: u32,
+    /// 单连接限速令牌桶
+    pub rate_bucket: Option<TokenBucket>,
+    /// socket 缓冲区自动调整状态
+    pub buf_tune: Option<BufTune>,
+    /// --chaos 下 local、remote 端的数据可以读取的时间，读空后清除，下次可读时重新抽样延迟
+    pub chaos_due: [Option<Instant>; 2],
+    /// --mirror 的镜像流，镜像连接失败或积压过多时为 None
+    pub mirror: Option<MirrorStream>,
+    /// --encrypt-out/--decrypt-in 两个方向的加解密状态
+    pub obfs: Option<Box<ObfsStreams>>,
+    /// 中间件为该连接创建的过滤器，按顺序处理读到的数据；辅助映射的连接没有过滤器
+    pub filters: Vec<Box<dyn TcpFilter>>,
+    /// 客户端 -> 远程 已转发字节数
+    pub bytes_up: u64,
+    /// 远程 -> 客户端 已转发字节数
+    pub bytes_down: u64,
+    /// 客户端 -> 远程 成功发送次数
+    pub packets_up: u64,
+    /// 远程 -> 客户端 成功发送次数
+    pub packets_down: u64,
+    /// 分配到的后端
+    pub backend: Option<Arc<Backend>>,
+    /// 经上游 SOCKS5 代理连接时的握手状态，握手完成后为 None
+    pub socks: Option<Socks5Handshake>,
+    /// 作为 SOCKS5 服务端 (`--socks5-listen`) 时尚未向客户端应答 CONNECT 的结果
+    pub socks_reply: bool,
     /// local -> remote 方向的 splice pipe
     #[cfg(target_os = "linux")]
     pub pipe_l2r: Option<SplicePipe>,
     /// remote -> local 方向的 splice pipe
     #[cfg(target_os = "linux")]
     pub pipe_r2l: Option<SplicePipe>,
+    /// 已交给内核 sockmap 转发时的连接对
+    #[cfg(target_os = "linux")]
+    pub sockmap: Option<Arc<SockmapPair>>,
+    /// 已尝试加入 sockmap 的次数
+    #[cfg(target_os = "linux")]
+    pub sockmap_tries: u8,
 }
 
 impl TcpConnection {
     /// 创建新的 TCP 连接
     pub fn new(
+        id: u64,
         local_fd: Fd64,
         remote_fd: Fd64,
         addr_s: String,
@@ -130,40 +314,115 @@ impl TcpConnection {
         buf_size: usize,
         remote_connecting: bool,
     ) -> Self {
-        // 创建 splice pipes (Linux only)
+        // 创建 splice pipes (Linux only，且内核支持 splice 时)
         #[cfg(target_os = "linux")]
-        let (pipe_l2r, pipe_r2l) = {
+        let (pipe_l2r, pipe_r2l) = if crate::capabilities::Capabilities::global().splice {
             let pipe_size = buf_size.max(65536); // 至少 64KB
             (SplicePipe::new(pipe_size), SplicePipe::new(pipe_size))
+        } else {
+            (None, None)
         };
+        #[cfg(not(target_os = "linux"))]
+        let _ = buf_size;
 
         Self {
-            local: TcpEndpoint::new(local_fd, buf_size),
-            remote: TcpEndpoint::new(remote_fd, buf_size),
+            id,
+            local: TcpEndpoint::new(local_fd),
+            remote: TcpEndpoint::new(remote_fd),
             addr_s,
+            peer: None,
             create_time,
+            accept_time: Instant::now(),
             last_active_time: Arc::new(AtomicU64::new(create_time)),
+            last_up_time: create_time,
+            last_down_time: create_time,
             remote_connecting,
+            fallback: None,
+            connect_attempts: 0,
+            rate_bucket: None,
+            buf_tune: None,
+            chaos_due: [None; 2],
+            mirror: None,
+            obfs: None,
+            filters: Vec::new(),
+            bytes_up: 0,
+            bytes_down: 0,
+            packets_up: 0,
+            packets_down: 0,
+            backend: None,
+            socks: None,
+            socks_reply: false,
             #[cfg(target_os = "linux")]
             pipe_l2r,
             #[cfg(target_os = "linux")]
             pipe_r2l,
+            #[cfg(target_os = "linux")]
+            sockmap: None,
+            #[cfg(target_os = "linux")]
+            sockmap_tries: 0,
         }
     }
 
-    /// 更新活跃时间
-    pub fn update_active(&self) {
-        let now = crate::log::get_current_time();
+    /// 转发数据后更新活跃时间和对应方向的最后活跃时间
+    pub fn update_active(&mut self, direction: Direction) {
+        let now = crate::log::get_monotonic_time();
         self.last_active_time.store(now, Ordering::Relaxed);
+        match direction {
+            Direction::ClientToServer => self.last_up_time = now,
+            Direction::ServerToClient => self.last_down_time = now,
+        }
     }
 
     /// 获取空闲时间（毫秒）
     pub fn idle_duration(&self) -> Duration {
-        let now = crate::log::get_current_time();
+        let now = crate::log::get_monotonic_time();
         let last = self.last_active_time.load(Ordering::Relaxed);
         Duration::from_millis(now - last)
     }
 
+    /// 镜像连接的 socket
+    pub fn mirror_fd64(&self) -> Option<Fd64> {
+        self.mirror.as_ref().map(|mirror| mirror.fd64)
+    }
+
+    /// 正在连接的备用地址 socket
+    pub fn fallback_fd64(&self) -> Option<Fd64> {
+        match self.fallback {
+            Some(Fallback::Connecting(fd64)) => Some(fd64),
+            _ => None,
+        }
+    }
+
+    /// 连接已建立的时间
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(crate::log::get_monotonic_time().saturating_sub(self.create_time))
+    }
+
+    /// 缓冲区 (含 splice pipe) 中尚未发出的字节数
+    pub fn buffered(&self) -> usize {
+        #[allow(unused_mut)]
+        let mut buffered = self.local.data_len + self.remote.data_len;
+        #[cfg(target_os = "linux")]
+        for pipe in [&self.pipe_l2r, &self.pipe_r2l].into_iter().flatten() {
+            buffered += pipe.pending;
+        }
+        buffered
+    }
+
+    /// 生成关闭时的汇总信息
+    pub fn summary(&self, reason: CloseReason) -> ConnectionSummary<'_> {
+        ConnectionSummary {
+            protocol: Protocol::Tcp,
+            peer: &self.addr_s,
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            packets_up: self.packets_up,
+            packets_down: self.packets_down,
+            duration_ms: crate::log::get_monotonic_time().saturating_sub(self.create_time),
+            reason,
+        }
+    }
+
     /// 关闭 splice pipes
     #[cfg(target_os = "linux")]
     pub fn close_pipes(&self) {
@@ -177,8 +436,10 @@ impl TcpConnection {
 }
 
 /// UDP 会话
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct UdpSession {
+    /// 会话 ID，日志中以 `#id` 标识
+    pub id: u64,
     /// 客户端地址
     pub address: Address,
     /// 远程 FD
@@ -191,6 +452,36 @@ pub struct UdpSession {
     pub create_time: u64,
     /// 最后活跃时间
     pub last_active_time: Arc<AtomicU64>,
+    /// 最后一次转发客户端 -> 远程数据的时间 (毫秒)
+    pub last_up_time: u64,
+    /// 最后一次转发远程 -> 客户端数据的时间 (毫秒)
+    pub last_down_time: u64,
+    /// 最后一次向远程发送保活数据包的时间 (毫秒)，未发送过时为 0
+    pub keepalive_time: u64,
+    /// 外连 socket 是否为 IPv6 socket (`--mtu` 据此计算 IP 头长度)
+    pub remote_ipv6: bool,
+    /// 外连 socket 当前是否带 DF 发送 (`-d`)，未设置过时为 None
+    pub df: Option<bool>,
+    /// 单会话限速令牌桶
+    pub rate_bucket: Option<TokenBucket>,
+    /// 客户端 -> 远程 已转发字节数
+    pub bytes_up: u64,
+    /// 远程 -> 客户端 已转发字节数
+    pub bytes_down: u64,
+    /// 客户端 -> 远程 已转发数据报数
+    pub packets_up: u64,
+    /// 远程 -> 客户端 已转发数据报数
+    pub packets_down: u64,
+    /// 分配到的后端
+    pub backend: Option<Arc<Backend>>,
+    /// 经上游 SOCKS5 代理中继时的关联状态
+    pub socks: Option<Arc<Socks5Association>>,
+    /// 已登记到会话管理器的 QUIC 连接 ID
+    pub quic_cids: Vec<Vec<u8>>,
+    /// 中间件为该会话创建的过滤器
+    pub filters: Vec<Box<dyn UdpFilter>>,
+    /// 会话 socket 未连接，等待过滤器跟随远程的新端口，跟随 (重新连接) 后为 false
+    pub following: bool,
 }
 
 impl UdpSession {
@@ -203,27 +494,66 @@ impl UdpSession {
         create_time: u64,
     ) -> Self {
         Self {
+            id: next_conn_id(),
             address,
             fd64,
             local_listen_fd,
             addr_s,
             create_time,
             last_active_time: Arc::new(AtomicU64::new(create_time)),
+            last_up_time: create_time,
+            last_down_time: create_time,
+            keepalive_time: 0,
+            remote_ipv6: false,
+            df: None,
+            rate_bucket: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            packets_up: 0,
+            packets_down: 0,
+            backend: None,
+            socks: None,
+            quic_cids: Vec::new(),
+            filters: Vec::new(),
+            following: false,
         }
     }
 
-    /// 更新活跃时间
-    pub fn update_active(&self) {
-        let now = crate::log::get_current_time();
+    /// 转发数据后更新活跃时间和对应方向的最后活跃时间
+    pub fn update_active(&mut self, direction: Direction) {
+        let now = crate::log::get_monotonic_time();
         self.last_active_time.store(now, Ordering::Relaxed);
+        match direction {
+            Direction::ClientToServer => self.last_up_time = now,
+            Direction::ServerToClient => self.last_down_time = now,
+        }
     }
 
     /// 获取空闲时间（毫秒）
     pub fn idle_duration(&self) -> Duration {
-        let now = crate::log::get_current_time();
+        let now = crate::log::get_monotonic_time();
         let last = self.last_active_time.load(Ordering::Relaxed);
         Duration::from_millis(now - last)
     }
+
+    /// 会话已建立的时间
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(crate::log::get_monotonic_time().saturating_sub(self.create_time))
+    }
+
+    /// 生成关闭时的汇总信息
+    pub fn summary(&self, reason: CloseReason) -> ConnectionSummary<'_> {
+        ConnectionSummary {
+            protocol: Protocol::Udp,
+            peer: &self.addr_s,
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            packets_up: self.packets_up,
+            packets_down: self.packets_down,
+            duration_ms: crate::log::get_monotonic_time().saturating_sub(self.create_time),
+            reason,
+        }
+    }
 }
 
 /// FD 信息枚举
@@ -234,3 +564,63 @@ pub enum FdInfo {
     /// UDP 会话
     Udp(Arc<UdpSession>),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bufpool::BufferPool;
+
+    #[test]
+    fn test_endpoint_segments() {
+        let pool = BufferPool::new(16);
+        let mut head = pool.get();
+        head[..8].copy_from_slice(b"abcdefgh");
+        let mut tail = pool.get();
+        tail[..4].copy_from_slice(b"1234");
+
+        let mut ep = TcpEndpoint::new(Fd64(1));
+        ep.append(head, 2, 8);
+        assert_eq!(ep.read_slice(), b"cdefgh");
+        assert!(!ep.is_full());
+        ep.append(tail, 1, 4);
+        assert!(ep.is_full());
+        assert_eq!(ep.data_len, 9);
+        assert_eq!(ep.tail_slice(), b"234");
+
+        ep.consume(4);
+        assert_eq!(ep.read_slice(), b"gh");
+        assert_eq!(ep.tail_slice(), b"234");
+
+        // 跨过第一段，剩余数据换到第二段
+        ep.consume(3);
+        assert!(!ep.is_full());
+        assert_eq!(ep.read_slice(), b"34");
+        assert_eq!(ep.tail_slice(), b"");
+
+        ep.consume(2);
+        assert_eq!(ep.data_len, 0);
+        assert!(ep.data.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_zerocopy_complete() {
+        let pool = BufferPool::new(16);
+        let mut zc = ZeroCopy::default();
+        zc.next_seq = u32::MAX - 1;
+        for _ in 0..4 {
+            zc.push(pool.get());
+        }
+        assert_eq!(zc.next_seq, 2);
+
+        // 序号回绕
+        zc.complete(u32::MAX, 0);
+        let left: Vec<u32> = zc.inflight.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(left, vec![u32::MAX - 1, 1]);
+
+        // 关闭时未完成的缓冲区暂存在池中
+        drop(zc);
+        assert_eq!(pool.held_len(), 2);
+        assert_eq!(pool.idle_len(), 2);
+    }
+}
@@ -0,0 +1,358 @@
+//! 内置回显/黑洞服务器 (--echo-server)
+//!
+//! 在同一地址上监听 TCP 和 UDP，把收到的数据原样发回 (或在 sink 模式下丢弃)，
+//! 作为转发目标验证转发、分片和超时，无需 nc/socat 等外部工具
+
+use crate::{debug, info, warn};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const WAKER_TOKEN: Token = Token(0);
+const TCP_LISTENER_TOKEN: Token = Token(1);
+const UDP_TOKEN: Token = Token(2);
+const FIRST_CONN_TOKEN: usize = 3;
+
+/// 读缓冲区大小，足够容纳最大的 UDP 数据报
+const ECHO_BUF_SIZE: usize = 65536;
+
+/// 收到数据后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoMode {
+    /// 原样发回
+    Echo,
+    /// 读取后丢弃
+    Sink,
+}
+
+impl std::fmt::Display for EchoMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EchoMode::Echo => write!(f, "echo"),
+            EchoMode::Sink => write!(f, "sink"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EchoConn {
+    stream: TcpStream,
+    peer: SocketAddr,
+    /// 尚未写出的回显数据，非空时暂停读取
+    pending: Vec<u8>,
+    received: u64,
+}
+
+/// 停止回显服务器的句柄，可跨线程使用
+#[derive(Debug, Clone)]
+pub struct EchoHandle {
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl EchoHandle {
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.waker.wake();
+    }
+}
+
+/// 回显服务器
+#[derive(Debug)]
+pub struct EchoServer {
+    poll: Poll,
+    tcp: Option<TcpListener>,
+    udp: Option<UdpSocket>,
+    mode: EchoMode,
+    conns: HashMap<Token, EchoConn>,
+    next_token: usize,
+    buf: Vec<u8>,
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl EchoServer {
+    /// 绑定监听地址；端口为 0 且同时启用 TCP 和 UDP 时，UDP 使用 TCP 分配到的端口
+    pub fn bind(addr: SocketAddr, tcp: bool, udp: bool, mode: EchoMode) -> io::Result<Self> {
+        if !tcp && !udp {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "echo server needs TCP or UDP",
+            ));
+        }
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let mut tcp = tcp.then(|| TcpListener::bind(addr)).transpose()?;
+        let udp_addr = match &tcp {
+            Some(listener) => listener.local_addr()?,
+            None => addr,
+        };
+        let mut udp = udp.then(|| UdpSocket::bind(udp_addr)).transpose()?;
+        if let Some(listener) = &mut tcp {
+            poll.registry()
+                .register(listener, TCP_LISTENER_TOKEN, Interest::READABLE)?;
+        }
+        if let Some(socket) = &mut udp {
+            poll.registry()
+                .register(socket, UDP_TOKEN, Interest::READABLE)?;
+        }
+        Ok(Self {
+            poll,
+            tcp,
+            udp,
+            mode,
+            conns: HashMap::new(),
+            next_token: FIRST_CONN_TOKEN,
+            buf: vec![0u8; ECHO_BUF_SIZE],
+            running: Arc::new(AtomicBool::new(true)),
+            waker,
+        })
+    }
+
+    /// 实际监听的地址
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match (&self.tcp, &self.udp) {
+            (Some(listener), _) => listener.local_addr(),
+            (None, Some(socket)) => socket.local_addr(),
+            (None, None) => unreachable!("echo server without sockets"),
+        }
+    }
+
+    pub fn handle(&self) -> EchoHandle {
+        EchoHandle {
+            running: Arc::clone(&self.running),
+            waker: Arc::clone(&self.waker),
+        }
+    }
+
+    /// 运行直到 `EchoHandle::stop`
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(256);
+        while self.running.load(Ordering::Relaxed) {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER_TOKEN => {}
+                    TCP_LISTENER_TOKEN => self.accept(),
+                    UDP_TOKEN => self.on_datagrams(),
+                    token => self.on_conn(token, event.is_readable(), event.is_writable()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        let Some(listener) = &self.tcp else {
+            return;
+        };
+        loop {
+            let (mut stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("[echo] accept failed: {}", e);
+                    return;
+                }
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)
+            {
+                warn!("[echo] register {} failed: {}", peer, e);
+                continue;
+            }
+            info!("[echo] tcp connection from {}", peer);
+            self.conns.insert(
+                token,
+                EchoConn {
+                    stream,
+                    peer,
+                    pending: Vec::new(),
+                    received: 0,
+                },
+            );
+        }
+    }
+
+    fn on_conn(&mut self, token: Token, readable: bool, writable: bool) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let open = match (writable, readable) {
+            (true, _) if !conn.pending.is_empty() => {
+                Self::flush(conn).and_then(|()| Self::read(conn, self.mode, &mut self.buf))
+            }
+            (_, true) => Self::read(conn, self.mode, &mut self.buf),
+            _ => Ok(true),
+        };
+        let open = match open {
+            Ok(open) => open,
+            Err(e) => {
+                debug!("[echo] {} error: {}", conn.peer, e);
+                false
+            }
+        };
+        let interest = if conn.pending.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::WRITABLE
+        };
+        if open
+            && self
+                .poll
+                .registry()
+                .reregister(&mut conn.stream, token, interest)
+                .is_ok()
+        {
+            return;
+        }
+        if let Some(mut conn) = self.conns.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+            info!(
+                "[echo] tcp connection from {} closed, {} bytes received",
+                conn.peer, conn.received
+            );
+        }
+    }
+
+    /// 读到 WouldBlock 或写回受阻为止，返回连接是否仍然打开
+    fn read(conn: &mut EchoConn, mode: EchoMode, buf: &mut [u8]) -> io::Result<bool> {
+        while conn.pending.is_empty() {
+            let n = match conn.stream.read(buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            conn.received += n as u64;
+            if mode == EchoMode::Echo {
+                conn.pending.extend_from_slice(&buf[..n]);
+                Self::flush(conn)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// 尽量写出待回显的数据
+    fn flush(conn: &mut EchoConn) -> io::Result<()> {
+        let mut written = 0;
+        while written < conn.pending.len() {
+            match conn.stream.write(&conn.pending[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        conn.pending.drain(..written);
+        Ok(())
+    }
+
+    fn on_datagrams(&mut self) {
+        let Some(socket) = &self.udp else {
+            return;
+        };
+        loop {
+            let (n, peer) = match socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // ICMP 错误等，不影响后续数据报
+                    debug!("[echo] udp recv failed: {}", e);
+                    continue;
+                }
+            };
+            debug!("[echo] udp {} bytes from {}", n, peer);
+            if self.mode == EchoMode::Echo {
+                if let Err(e) = socket.send_to(&self.buf[..n], peer) {
+                    debug!("[echo] udp send to {} failed: {}", peer, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_echo_tcp_and_udp() {
+        let mut server =
+            EchoServer::bind("127.0.0.1:0".parse().unwrap(), true, true, EchoMode::Echo)
+                .expect("bind echo server");
+        let addr = server.local_addr().expect("local addr");
+        let handle = server.handle();
+        let runner = std::thread::spawn(move || server.run().expect("run echo server"));
+
+        // 超过套接字缓冲区的数据，回显需要处理写阻塞
+        let data: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+        let mut stream = std::net::TcpStream::connect(addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        let mut reader = stream.try_clone().expect("clone stream");
+        let expected = data.clone();
+        let reader = std::thread::spawn(move || {
+            let mut echoed = vec![0u8; expected.len()];
+            reader.read_exact(&mut echoed).expect("read echo");
+            assert!(echoed == expected);
+        });
+        stream.write_all(&data).expect("write");
+        reader.join().expect("join reader");
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        let datagram = vec![7u8; 9000];
+        client.send_to(&datagram, addr).expect("send");
+        let mut buf = [0u8; ECHO_BUF_SIZE];
+        let (n, from) = client.recv_from(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], datagram.as_slice());
+        assert_eq!(from, addr);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_sink() {
+        let mut server =
+            EchoServer::bind("127.0.0.1:0".parse().unwrap(), true, false, EchoMode::Sink)
+                .expect("bind echo server");
+        let addr = server.local_addr().expect("local addr");
+        let handle = server.handle();
+        let runner = std::thread::spawn(move || server.run().expect("run echo server"));
+
+        let mut stream = std::net::TcpStream::connect(addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .expect("set timeout");
+        stream.write_all(b"discard me").expect("write");
+        let mut buf = [0u8; 16];
+        assert!(stream.read(&mut buf).is_err());
+
+        handle.stop();
+        runner.join().expect("join runner");
+        assert!(EchoServer::bind(addr, false, false, EchoMode::Echo).is_err());
+    }
+}
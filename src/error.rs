@@ -0,0 +1,132 @@
+//! 错误类型
+//!
+//! 库接口 (`PortMapper`、`PortMapperBuilder`) 返回的错误，按出错环节区分，嵌入方可以按原因匹配。
+//! 底层的 socket 辅助函数仍返回 `std::io::Error`，由调用方加上环节和上下文
+
+use std::io;
+use thiserror::Error;
+
+/// 库接口的错误
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// 地址缺失或无法解析 (`kind` 为 listen、remote 等)
+    #[error("invalid {kind} address '{addr}': {reason}")]
+    Address {
+        kind: &'static str,
+        addr: String,
+        reason: String,
+    },
+    /// 参数缺失、无效或相互冲突
+    #[error("{0}")]
+    Config(String),
+    /// 当前平台不支持的功能
+    #[error("{0}")]
+    Unsupported(String),
+    /// 创建或设置 socket 失败
+    #[error("{context}: {source}")]
+    Socket {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// 绑定监听地址失败
+    #[error("failed to bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    /// 开始监听失败
+    #[error("failed to listen on {addr}: {source}")]
+    Listen {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    /// 连接失败
+    #[error("failed to connect to {addr}: {source}")]
+    Connect {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    /// 创建事件循环、注册 socket 或事件循环运行出错
+    #[error("event loop failed: {0}")]
+    EventLoop(#[source] io::Error),
+    /// 其他 I/O 错误 (读取配置文件、接管连接等)
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// 库接口的 Result
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// 参数缺失、无效或相互冲突
+    pub(crate) fn config(msg: impl Into<String>) -> Self {
+        Error::Config(msg.into())
+    }
+
+    /// 创建或设置 socket 失败
+    pub(crate) fn socket(context: impl Into<String>, source: io::Error) -> Self {
+        Error::Socket {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// 其他 I/O 错误
+    pub(crate) fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// 对应的 `io::ErrorKind`，便于按 I/O 错误处理
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Address { .. } | Error::Config(_) => io::ErrorKind::InvalidInput,
+            Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            Error::Socket { source, .. }
+            | Error::Bind { source, .. }
+            | Error::Listen { source, .. }
+            | Error::Connect { source, .. }
+            | Error::EventLoop(source)
+            | Error::Io { source, .. } => source.kind(),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_and_display() {
+        let e = Error::Bind {
+            addr: "127.0.0.1:80".to_string(),
+            source: io::Error::from(io::ErrorKind::AddrInUse),
+        };
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(e.to_string().starts_with("failed to bind 127.0.0.1:80: "));
+        assert!(std::error::Error::source(&e).is_some());
+
+        let e = Error::Config("remote address is required".to_string());
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let io_err = io::Error::from(e);
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(io_err.to_string(), "remote address is required");
+    }
+}
@@ -0,0 +1,196 @@
+//! 连接排空模块
+//!
+//! 停止时不再接受新连接，等待已有连接自然关闭，并定期报告剩余连接和预计完成时间
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 排空状态日志输出间隔
+pub const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 排空报告中列出的流量最大连接数
+pub const DRAIN_TOP_TALKERS: usize = 3;
+
+/// 排空报告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrainReport {
+    /// 剩余 TCP 连接数
+    pub tcp_remaining: usize,
+    /// 剩余 UDP 会话数
+    pub udp_remaining: usize,
+    /// 已排空时间
+    pub elapsed: Duration,
+    /// 距离强制关闭的剩余时间，没有期限 (升级交接后等到连接全部关闭) 时为 None
+    pub time_left: Option<Duration>,
+    /// 按当前关闭速度估算的完成时间，尚无连接关闭时为 None
+    pub eta: Option<Duration>,
+    /// 流量最大的剩余连接 (客户端地址, 总字节数)
+    pub top_talkers: Vec<(String, u64)>,
+}
+
+impl DrainReport {
+    /// 剩余连接总数
+    pub fn remaining(&self) -> usize {
+        self.tcp_remaining + self.udp_remaining
+    }
+}
+
+impl fmt::Display for DrainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "remaining: TCP={}, UDP={}, elapsed: {}s, eta: ",
+            self.tcp_remaining,
+            self.udp_remaining,
+            self.elapsed.as_secs()
+        )?;
+        match self.eta {
+            Some(eta) => write!(f, "{}s", eta.as_secs())?,
+            None => write!(f, "unknown")?,
+        }
+        match self.time_left {
+            Some(time_left) => write!(f, ", force close in {}s", time_left.as_secs())?,
+            None => write!(f, ", no force close")?,
+        }
+        if !self.top_talkers.is_empty() {
+            let talkers: Vec<String> = self
+                .top_talkers
+                .iter()
+                .map(|(addr, bytes)| format!("{}({})", addr, crate::stats::format_bytes(*bytes)))
+                .collect();
+            write!(f, ", top: {}", talkers.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// 排空进度跟踪
+#[derive(Debug)]
+pub struct Drain {
+    started: Instant,
+    deadline: Option<Instant>,
+    initial: usize,
+    last_log: Instant,
+}
+
+impl Drain {
+    /// 开始排空，`initial` 为开始时的连接总数，`timeout` 为 None 时等到连接全部关闭
+    pub fn start(now: Instant, timeout: Option<Duration>, initial: usize) -> Self {
+        Self {
+            started: now,
+            deadline: timeout.map(|timeout| now + timeout),
+            initial,
+            last_log: now,
+        }
+    }
+
+    /// 是否已到强制关闭时间
+    pub fn expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// 是否应输出排空日志 (到期时更新输出时间)
+    pub fn log_due(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.last_log) < DRAIN_LOG_INTERVAL {
+            return false;
+        }
+        self.last_log = now;
+        true
+    }
+
+    /// 按开始以来的平均关闭速度估算剩余连接全部关闭所需时间
+    pub fn eta(&self, now: Instant, remaining: usize) -> Option<Duration> {
+        let closed = self.initial.saturating_sub(remaining);
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if closed == 0 {
+            return None;
+        }
+        let elapsed = now.duration_since(self.started);
+        Some(elapsed.mul_f64(remaining as f64 / closed as f64))
+    }
+
+    /// 生成排空报告，`talkers` 为剩余连接的 (客户端地址, 总字节数)
+    pub fn report(
+        &self,
+        now: Instant,
+        tcp_remaining: usize,
+        udp_remaining: usize,
+        mut talkers: Vec<(String, u64)>,
+    ) -> DrainReport {
+        talkers.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        talkers.truncate(DRAIN_TOP_TALKERS);
+        DrainReport {
+            tcp_remaining,
+            udp_remaining,
+            elapsed: now.duration_since(self.started),
+            time_left: self
+                .deadline
+                .map(|deadline| deadline.saturating_duration_since(now)),
+            eta: self.eta(now, tcp_remaining + udp_remaining),
+            top_talkers: talkers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let start = Instant::now();
+        let drain = Drain::start(start, Some(Duration::from_secs(60)), 10);
+
+        // 尚无连接关闭，无法估算
+        assert_eq!(drain.eta(start + Duration::from_secs(2), 10), None);
+        // 4 秒关闭 8 个，剩余 2 个约需 1 秒
+        assert_eq!(
+            drain.eta(start + Duration::from_secs(4), 2),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(drain.eta(start, 0), Some(Duration::ZERO));
+
+        assert!(!drain.expired(start + Duration::from_secs(59)));
+        assert!(drain.expired(start + Duration::from_secs(60)));
+
+        let unbounded = Drain::start(start, None, 10);
+        assert!(!unbounded.expired(start + Duration::from_secs(86400)));
+        assert!(unbounded
+            .report(start + Duration::from_secs(1), 1, 0, Vec::new())
+            .to_string()
+            .ends_with("no force close"));
+    }
+
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let mut drain = Drain::start(start, Some(Duration::from_secs(30)), 4);
+        assert!(!drain.log_due(start + Duration::from_secs(1)));
+        assert!(drain.log_due(start + DRAIN_LOG_INTERVAL));
+        assert!(!drain.log_due(start + DRAIN_LOG_INTERVAL));
+
+        let talkers = vec![
+            ("a".to_string(), 10),
+            ("b".to_string(), 300),
+            ("c".to_string(), 20),
+            ("d".to_string(), 200),
+        ];
+        let report = drain.report(start + Duration::from_secs(10), 1, 1, talkers);
+        assert_eq!(report.remaining(), 2);
+        assert_eq!(report.time_left, Some(Duration::from_secs(20)));
+        assert_eq!(report.eta, Some(Duration::from_secs(10)));
+        assert_eq!(
+            report.top_talkers,
+            vec![
+                ("b".to_string(), 300),
+                ("d".to_string(), 200),
+                ("c".to_string(), 20)
+            ]
+        );
+        assert!(report
+            .to_string()
+            .starts_with("remaining: TCP=1, UDP=1, elapsed: 10s, eta: 10s, force close in 20s"));
+    }
+}
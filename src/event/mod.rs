@@ -2,70 +2,79 @@
 //!
 //! 基于 mio 的事件驱动框架
 
-use crate::config::Config;
+use crate::backend::Backend;
+use crate::ban::{self, BanList, RejectThrottle};
+use crate::config::{Config, Linger, PollMode, MAX_POLL_TIMEOUT_MS};
+use crate::connection::TcpConnection;
 use crate::debug;
+use crate::event::drain::{Drain, DrainReport};
+use crate::event::observer::{CloseReason, ConnectionObserver, Observers, Protocol, RejectReason};
 use crate::event::signals::SignalHandler;
 use crate::event::tcp::TcpHandler;
 use crate::event::timer::Timer;
 use crate::event::udp::UdpHandler;
 use crate::fd_manager::{Fd64, FdManager};
-use crate::log::get_current_time;
+use crate::geo::GeoFilter;
+use crate::log::get_monotonic_time;
 use crate::log_bare;
 use crate::manager::{TcpConnectionManager, UdpSessionManager};
-use crate::stats::TrafficStats;
+use crate::memory::MemoryBudget;
+use crate::ratelimit::RateLimiter;
+use crate::slab::Slab;
+use crate::stats::{format_bytes, TrafficStats};
+use crate::sync::{LoopShared, Mutex, Recover, RwLock};
+use crate::tenant::Tenant;
+use crate::top::{self, Top};
+#[cfg(unix)]
+use crate::upgrade::{self, SocketKind};
 
+use crate::error;
 use crate::info;
 use crate::trace;
+use crate::warn;
+#[cfg(windows)]
+use crate::winsock::RawFd;
+#[cfg(unix)]
+use mio::net::UnixListener;
 use mio::net::{TcpListener, UdpSocket};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+pub mod drain;
+pub mod observer;
 pub mod signals;
 pub mod tcp;
 pub mod timer;
 pub mod udp;
 
 /// Token 管理器
+///
+/// Token 由 slab 分配，释放后复用槽位；代数标记保证旧 Token 的迟到事件不会被当作新 socket 的事件
 #[derive(Debug)]
 struct TokenManager {
+    tokens: Slab<Fd64>,
     fd64_to_token: HashMap<Fd64, Token>,
-    token_to_fd64: HashMap<Token, Fd64>,
-    counter: AtomicUsize,
-}
-
-/// 格式化字节数（与 lib.rs 中的 stats 模块保持一致）
-fn format_bytes(bytes: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = 1024 * KB;
-    const GB: u64 = 1024 * MB;
-
-    if bytes >= GB {
-        format!("{:.2} GB", bytes as f64 / GB as f64)
-    } else if bytes >= MB {
-        format!("{:.2} MB", bytes as f64 / MB as f64)
-    } else if bytes >= KB {
-        format!("{:.2} KB", bytes as f64 / KB as f64)
-    } else {
-        format!("{} B", bytes)
-    }
 }
 
 impl TokenManager {
     fn new() -> Self {
         Self {
+            tokens: Slab::new(),
             fd64_to_token: HashMap::new(),
-            token_to_fd64: HashMap::new(),
-            counter: AtomicUsize::new(1),
         }
     }
 
     fn generate_token(&mut self, fd64: Fd64) -> Token {
-        let token = Token(self.counter.fetch_add(1, Ordering::Relaxed));
+        let token = Token(self.tokens.insert(fd64));
         self.fd64_to_token.insert(fd64, token);
-        self.token_to_fd64.insert(token, fd64);
         token
     }
 
@@ -74,22 +83,79 @@ impl TokenManager {
     }
 
     fn get_fd64(&self, token: Token) -> Option<Fd64> {
-        self.token_to_fd64.get(&token).copied()
+        self.tokens.get(token.0).copied()
     }
 
     fn remove(&mut self, fd64: &Fd64) -> Option<Token> {
         self.fd64_to_token.remove(fd64).inspect(|token| {
-            self.token_to_fd64.remove(token);
+            self.tokens.remove(token.0);
         })
     }
 }
 
+/// 唤醒 poll 使用的 Token (slab 分配的 Token 不会是 0，不会冲突)
+const WAKER_TOKEN: Token = Token(0);
+
+/// 停止句柄
+///
+/// 可以跨线程调用 `stop()`，立即唤醒阻塞中的 poll 并有序退出事件循环
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    running: Arc<AtomicBool>,
+    /// 不等待排空，立即停止
+    aborted: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl StopHandle {
+    fn new(waker: Arc<Waker>) -> Self {
+        Self {
+            running: Arc::new(AtomicBool::new(true)),
+            aborted: Arc::new(AtomicBool::new(false)),
+            waker,
+        }
+    }
+
+    /// 请求停止事件循环
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.wake();
+    }
+
+    /// 请求立即停止事件循环，不等待连接排空 (排空期间再次收到终止信号)
+    pub(crate) fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+        self.stop();
+    }
+
+    /// 唤醒阻塞中的 poll
+    pub(crate) fn wake(&self) {
+        if let Err(e) = self.waker.wake() {
+            warn!("[event] failed to wake event loop: {}", e);
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        !self.running.load(Ordering::Relaxed)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+}
+
 /// 监听 socket 信息
 struct ListenSocket {
     tcp_listener: Option<TcpListener>,
     udp_socket: Option<UdpSocket>,
     tcp_listen_token: Token,
     udp_listen_token: Token,
+    /// 上次接受连接时达到批量上限，backlog 中可能还有连接
+    tcp_backlog: bool,
+    /// fd 耗尽后暂停接受连接，由定时器恢复
+    tcp_paused: bool,
+    /// 上次读取 UDP 数据包时达到批量上限，内核缓冲区中可能还有数据包
+    udp_backlog: bool,
 }
 
 /// 事件循环
@@ -103,9 +169,50 @@ pub struct EventLoop {
     tcp_handler: Arc<RwLock<TcpHandler>>,
     udp_handler: Arc<RwLock<UdpHandler>>,
     timer: Timer,
-    signal_handler: SignalHandler,
-    running: Arc<AtomicBool>,
-    listen_socket: RwLock<Option<ListenSocket>>,
+    /// 处理 SIGTERM/SIGINT/SIGUSR1/SIGUSR2 (`Config::handle_signals`)
+    signal_handler: Option<SignalHandler>,
+    /// 停止标志和用于从其他线程唤醒 poll 的 Waker
+    stop: StopHandle,
+    /// 监听 socket (双栈时 IPv4 和 IPv6 各一组)
+    listen_sockets: RwLock<Vec<ListenSocket>>,
+    /// 因限速暂停读取、已安排恢复定时器的 TCP socket
+    tcp_resume: Mutex<HashSet<Fd64>>,
+    /// 恢复定时器已到期的 TCP socket，由定时器回调填充
+    tcp_resume_due: Arc<Mutex<Vec<Fd64>>>,
+    /// 连接事件观察者
+    pub(crate) observers: Arc<Observers>,
+    /// 流量统计 (设置租户时为租户统计，否则为全局统计)
+    pub(crate) stats: &'static TrafficStats,
+    /// 租户的连接数上限、带宽和 ACL (--tenant-*)
+    tenant: Option<Arc<Tenant>>,
+    /// 是否正在排空 (不再接受新连接)
+    draining: AtomicBool,
+    /// 最近一次排空报告
+    drain_report: Arc<std::sync::Mutex<Option<DrainReport>>>,
+    /// 是否接管了继承的客户端连接 (inetd 模式，连接结束后退出)
+    inherited: AtomicBool,
+    /// 平滑升级控制 socket 及其 token
+    #[cfg(unix)]
+    upgrade_listener: Mutex<Option<(UnixListener, Token)>>,
+    /// 正在后台线程中进行的交接，线程结束时发送结果并唤醒 poll
+    #[cfg(unix)]
+    upgrade_pending: Mutex<Option<std::sync::mpsc::Receiver<std::io::Result<bool>>>>,
+    /// 监听 socket 是否已交给新进程
+    handed_over: AtomicBool,
+    /// 各 socket 注册的 token 和关注事件，Windows 上和水平触发模式下处理完事件后据此重新注册
+    interests: Mutex<HashMap<Fd64, (Token, Interest)>>,
+    /// 暂停接受连接的时间已到，由定时器设置
+    accept_resume: Arc<AtomicBool>,
+    /// 到了超时清理的时间，由定时器设置
+    sweep_due: Arc<AtomicBool>,
+    /// 内存预算，超出时关闭最久未活动的连接
+    memory: Option<Arc<MemoryBudget>>,
+    /// 自动封禁的客户端 IP (--auto-ban)
+    ban_list: Option<Arc<BanList>>,
+    /// UDP 拒绝和限速按客户端 IP 节流，避免一个发包的客户端刷满日志
+    reject_throttle: RejectThrottle,
+    /// GeoIP 标注和过滤 (--geoip-db)
+    geo: Option<Arc<GeoFilter>>,
 }
 
 impl EventLoop {
@@ -118,23 +225,212 @@ impl EventLoop {
         // 初始化 UdpHandler 并设置分片转发选项
         let mut udp_handler = UdpHandler::new();
         udp_handler.set_enable_fragment(config.enable_udp_fragment);
+        udp_handler.set_mtu(config.udp_mtu);
+
+        // 全局限速器由 TCP/UDP 处理器共享
+        let mut tcp_handler = TcpHandler::new();
+        let rate_limiter =
+            RateLimiter::new(config.rate_limit, config.rate_limit_per_conn, None).map(Arc::new);
+        tcp_handler.set_rate_limiter(rate_limiter.clone());
+        udp_handler.set_rate_limiter(rate_limiter);
+
+        let poll = Poll::new()?;
+        let stop = StopHandle::new(Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?));
+        let signal_handler = match config.handle_signals {
+            true => Some(SignalHandler::new(stop.clone())?),
+            false => None,
+        };
 
         Ok(Self {
-            poll: Poll::new()?,
+            poll,
             token_manager: Arc::new(RwLock::new(TokenManager::new())),
             fd_manager,
             tcp_manager,
             udp_manager,
             config: Arc::clone(&config),
-            tcp_handler: Arc::new(RwLock::new(TcpHandler::new())),
+            tcp_handler: Arc::new(RwLock::new(tcp_handler)),
             udp_handler: Arc::new(RwLock::new(udp_handler)),
             timer: Timer::new(),
-            signal_handler: SignalHandler::new()?,
-            running: Arc::new(AtomicBool::new(false)),
-            listen_socket: RwLock::new(None),
+            signal_handler,
+            stop,
+            listen_sockets: RwLock::new(Vec::new()),
+            tcp_resume: Mutex::new(HashSet::new()),
+            tcp_resume_due: Arc::new(Mutex::new(Vec::new())),
+            observers: Arc::new(Observers::default()),
+            stats: TrafficStats::scope(config.tenant.as_deref()),
+            tenant: None,
+            draining: AtomicBool::new(false),
+            drain_report: Arc::new(std::sync::Mutex::new(None)),
+            ban_list: config
+                .auto_ban
+                .map(|auto_ban| Arc::new(BanList::new(auto_ban))),
+            reject_throttle: RejectThrottle::new(),
+            geo: None,
+            inherited: AtomicBool::new(false),
+            #[cfg(unix)]
+            upgrade_listener: Mutex::new(None),
+            #[cfg(unix)]
+            upgrade_pending: Mutex::new(None),
+            handed_over: AtomicBool::new(false),
+            interests: Mutex::new(HashMap::new()),
+            accept_resume: Arc::new(AtomicBool::new(false)),
+            sweep_due: Arc::new(AtomicBool::new(false)),
+            memory: None,
         })
     }
 
+    /// 设置租户限制，租户带宽与全局限速一起交给 TCP/UDP 处理器
+    pub fn set_tenant(&mut self, tenant: Option<Arc<Tenant>>) {
+        let rate_limiter = RateLimiter::new(
+            self.config.rate_limit,
+            self.config.rate_limit_per_conn,
+            tenant.as_ref().and_then(|tenant| tenant.bucket()),
+        )
+        .map(Arc::new);
+        self.tcp_handler
+            .write()
+            .recover()
+            .set_rate_limiter(rate_limiter.clone());
+        self.udp_handler
+            .write()
+            .recover()
+            .set_rate_limiter(rate_limiter);
+        self.tenant = tenant;
+    }
+
+    /// 按租户 ACL 和连接数上限检查新的客户端，`pending` 为本实例尚未计入统计的连接数
+    pub(crate) fn tenant_check(&self, addr: SocketAddr, pending: usize) -> Option<RejectReason> {
+        let tenant = self.tenant.as_ref()?;
+        if self.config.listen_addr.is_ip() && !tenant.allows(addr.ip()) {
+            return Some(RejectReason::Acl);
+        }
+        if tenant.is_full(pending) {
+            return Some(RejectReason::MaxConnections);
+        }
+        None
+    }
+
+    /// 设置内存预算，TCP/UDP 处理器的缓冲区分配计入其中
+    ///
+    /// 连接和会话的状态由管理器计入，需在创建管理器时分别设置
+    pub fn set_memory_budget(&mut self, memory: Option<Arc<MemoryBudget>>) {
+        self.tcp_handler
+            .write()
+            .recover()
+            .set_memory_budget(memory.clone());
+        self.udp_handler
+            .write()
+            .recover()
+            .set_memory_budget(memory.clone());
+        self.memory = memory;
+    }
+
+    /// 设置 GeoIP 标注和过滤
+    pub fn set_geo_filter(&mut self, geo: Option<Arc<GeoFilter>>) {
+        self.geo = geo;
+    }
+
+    /// GeoIP 过滤器，未设置 --geoip-db 或不是 IP 监听时为 None
+    pub(crate) fn geo(&self) -> Option<&GeoFilter> {
+        self.geo
+            .as_deref()
+            .filter(|_| self.config.listen_addr.is_ip())
+    }
+
+    /// 新连接日志中的国家/ASN 标注，例如 ` [CN AS4134]`，未启用 GeoIP 时为空
+    pub(crate) fn geo_tag(&self, addr: SocketAddr) -> String {
+        self.geo()
+            .map(|geo| format!(" [{}]", geo.lookup(addr.ip())))
+            .unwrap_or_default()
+    }
+
+    /// 注册连接事件观察者
+    pub fn add_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.observers.add(observer);
+    }
+
+    /// 自动封禁表，未启用 --auto-ban 时为 None
+    pub(crate) fn ban_list(&self) -> Option<Arc<BanList>> {
+        self.ban_list.clone()
+    }
+
+    /// 客户端 IP 是否被自动封禁
+    pub(crate) fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.ban_list
+            .as_ref()
+            .is_some_and(|bans| bans.is_banned(addr.ip(), get_monotonic_time()))
+    }
+
+    /// 拒绝新连接或会话：输出固定格式的拒绝日志 (便于 fail2ban 匹配)，客户端引起的拒绝计入自动封禁，
+    /// 并通知观察者。`addr` 为客户端地址，Unix socket 监听时不输出 IP、也不计入封禁。
+    /// UDP 的拒绝和限速每个数据包/每次读取都会发生，同一 IP 每 `REJECT_REPORT_INTERVAL` 最多报告一次
+    pub(crate) fn reject(
+        &self,
+        protocol: Protocol,
+        addr: SocketAddr,
+        client_addr: &str,
+        reason: RejectReason,
+    ) {
+        let suppressed = if protocol == Protocol::Udp || reason == RejectReason::RateLimit {
+            match self.reject_throttle.check(addr.ip(), get_monotonic_time()) {
+                Some(suppressed) => suppressed,
+                None => return,
+            }
+        } else {
+            0
+        };
+        let more = if suppressed > 0 {
+            format!(" ({} more since last report)", suppressed)
+        } else {
+            String::new()
+        };
+        if self.config.listen_addr.is_ip() {
+            let ip = ban::shown_ip(addr.ip());
+            warn!(
+                "[reject] {} {} port {}: {}{}",
+                protocol,
+                ip,
+                addr.port(),
+                reason,
+                more
+            );
+            if let Some(bans) = self.ban_list.as_ref().filter(|_| reason.is_client_fault()) {
+                if bans.record_failure(addr.ip(), get_monotonic_time()) {
+                    let auto_ban = bans.config();
+                    warn!(
+                        "[ban] {} banned for {}s after {} rejects in {}s",
+                        ip,
+                        auto_ban.duration.as_secs(),
+                        auto_ban.threshold,
+                        auto_ban.window.as_secs()
+                    );
+                }
+            }
+        } else {
+            warn!("[reject] {} {}: {}{}", protocol, client_addr, reason, more);
+        }
+        self.observers
+            .notify(|o| o.on_reject(protocol, client_addr, reason));
+    }
+
+    /// 注册周期性定时任务，在事件循环线程中执行
+    pub(crate) fn register_timer<F>(&self, interval: Duration, callback: F)
+    where
+        F: Fn() + LoopShared + 'static,
+    {
+        self.timer.register(interval, callback);
+    }
+
+    /// 是否正在排空
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// 排空报告 (排空期间定期更新)
+    pub(crate) fn drain_report(&self) -> Arc<std::sync::Mutex<Option<DrainReport>>> {
+        Arc::clone(&self.drain_report)
+    }
+
     pub fn tcp_handler(&self) -> Arc<RwLock<TcpHandler>> {
         Arc::clone(&self.tcp_handler)
     }
@@ -143,12 +439,112 @@ impl EventLoop {
         Arc::clone(&self.udp_handler)
     }
 
+    /// 注册 FdManager 持有的 socket
+    pub(crate) fn register_source(
+        &self,
+        fd64: Fd64,
+        token: Token,
+        interest: Interest,
+    ) -> Result<(), std::io::Error> {
+        let result = self
+            .fd_manager
+            .with_source(fd64, |source| {
+                self.poll.registry().register(source, token, interest)
+            })
+            .unwrap_or_else(|| Err(std::io::ErrorKind::NotFound.into()));
+        if result.is_ok() && self.rearms() {
+            self.interests
+                .lock()
+                .recover()
+                .insert(fd64, (token, interest));
+        }
+        result
+    }
+
+    /// 修改 FdManager 持有的 socket 的关注事件
+    pub(crate) fn reregister_source(
+        &self,
+        fd64: Fd64,
+        token: Token,
+        interest: Interest,
+    ) -> Result<(), std::io::Error> {
+        let result = self
+            .fd_manager
+            .with_source(fd64, |source| {
+                self.poll.registry().reregister(source, token, interest)
+            })
+            .unwrap_or_else(|| Err(std::io::ErrorKind::NotFound.into()));
+        if result.is_ok() && self.rearms() {
+            self.interests
+                .lock()
+                .recover()
+                .insert(fd64, (token, interest));
+        }
+        result
+    }
+
+    /// 注销 FdManager 持有的 socket
+    pub(crate) fn deregister_source(&self, fd64: Fd64) {
+        if self.rearms() {
+            self.interests.lock().recover().remove(&fd64);
+        }
+        self.fd_manager
+            .with_source(fd64, |source| self.poll.registry().deregister(source).ok());
+    }
+
+    /// 处理完事件后是否需要重新注册 socket
+    ///
+    /// 水平触发模式下每次事件只处理一次收发，重新注册 (EPOLL_CTL_MOD) 时内核重新检查就绪状态，
+    /// 还有未读数据的 socket 在下一轮 poll 中再次通知
+    fn rearms(&self) -> bool {
+        cfg!(windows) || self.config.poll_mode == PollMode::Level
+    }
+
+    /// 重新启用 socket 的关注事件 (连接两端都处理)
+    ///
+    /// Windows 上 mio 投递事件后即清除该 socket 的关注事件，只有经 mio 读写遇到 WouldBlock 时才重新启用，
+    /// 而转发直接在原始 socket 上收发，因此每处理完一个事件都要重新注册。
+    /// 发往对端的数据未发完或正在限速的一端不关注 READABLE，否则未读的数据会让 poll 立即返回；
+    /// 这两种情况恢复读取时都会再处理一次事件或调用本函数。水平触发模式下其他平台也这样重新注册
+    fn rearm(&self, fd64: Fd64) {
+        let sockets = match self.tcp_manager.get_connection_by_any_fd(&fd64) {
+            Some(conn) => {
+                let conn = conn.read().recover();
+                vec![
+                    (conn.local.fd64, conn.remote.data_len > 0),
+                    (conn.remote.fd64, conn.local.data_len > 0),
+                ]
+            }
+            None => vec![(fd64, false)],
+        };
+        let resuming = self.tcp_resume.lock().recover().clone();
+        for (fd64, backlogged) in sockets {
+            let registered = self.interests.lock().recover().get(&fd64).copied();
+            let Some((token, mut interest)) = registered else {
+                continue;
+            };
+            if backlogged || resuming.contains(&fd64) {
+                match interest.remove(Interest::READABLE) {
+                    Some(rest) => interest = rest,
+                    None => continue,
+                }
+            }
+            self.fd_manager.with_source(fd64, |source| {
+                self.poll
+                    .registry()
+                    .reregister(source, token, interest)
+                    .ok()
+            });
+        }
+    }
+
+    /// 注册一组监听 socket，双栈时每个地址族调用一次
     pub fn register_listen_socket(
         &mut self,
         mut tcp_listener: Option<TcpListener>,
         mut udp_socket: Option<UdpSocket>,
     ) -> Result<(), std::io::Error> {
-        let mut token_manager = self.token_manager.write().expect("RwLock poisoned");
+        let mut token_manager = self.token_manager.write().recover();
 
         let tcp_listen_token = token_manager.generate_token(Fd64(0));
         let udp_listen_token = token_manager.generate_token(Fd64(0));
@@ -165,55 +561,410 @@ impl EventLoop {
                 .register(socket, udp_listen_token, Interest::READABLE)?;
         }
 
-        *self.listen_socket.write().expect("RwLock poisoned") = Some(ListenSocket {
+        self.listen_sockets.write().recover().push(ListenSocket {
             tcp_listener,
             udp_socket,
             tcp_listen_token,
             udp_listen_token,
+            tcp_backlog: false,
+            tcp_paused: false,
+            udp_backlog: false,
         });
 
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), std::io::Error> {
-        self.running.store(true, Ordering::Relaxed);
+    /// 注册平滑升级控制 socket，新进程连接后交出监听 socket
+    #[cfg(unix)]
+    pub fn register_upgrade_listener(
+        &self,
+        listener: std::os::unix::net::UnixListener,
+    ) -> Result<(), std::io::Error> {
+        let mut listener = UnixListener::from_std(listener);
+        let token = self.token_manager.write().recover().generate_token(Fd64(0));
+        self.poll
+            .registry()
+            .register(&mut listener, token, Interest::READABLE)?;
+        *self.upgrade_listener.lock().recover() = Some((listener, token));
+        Ok(())
+    }
+
+    /// 监听 socket 是否已交给新进程 (此时退出前不应删除 socket 文件)
+    pub fn handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
 
-        self.signal_handler.register()?;
+    /// 处理控制 socket 上的升级请求：在后台线程中交出监听 socket 并等待新进程确认
+    ///
+    /// 新进程在启动完成后才确认，期间本进程继续正常转发，确认后由 `finish_upgrade` 停止读取并开始排空
+    #[cfg(unix)]
+    fn on_upgrade(&self, listen_sockets: &[ListenSocket]) {
+        let stream = {
+            let guard = self.upgrade_listener.lock().recover();
+            let Some((ref listener, _)) = *guard else {
+                return;
+            };
+            match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("[upgrade] accept on control socket failed: {}", e);
+                    return;
+                }
+            }
+        };
+        let mut pending = self.upgrade_pending.lock().recover();
+        if pending.is_some() {
+            // 关闭连接，新进程收不到监听 socket 后启动失败
+            warn!("[upgrade] another handover is in progress, rejecting upgrade request");
+            return;
+        }
+        let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(stream.into_raw_fd()) };
+        // 发送复制的 fd，交接期间事件循环退出并关闭监听 socket 时不影响后台线程
+        let mut sockets = Vec::new();
+        for listen in listen_sockets {
+            let tcp = listen
+                .tcp_listener
+                .as_ref()
+                .map(|l| (SocketKind::Tcp, l.as_raw_fd()));
+            let udp = listen
+                .udp_socket
+                .as_ref()
+                .map(|s| (SocketKind::Udp, s.as_raw_fd()));
+            for (kind, fd) in tcp.into_iter().chain(udp) {
+                match unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+                    Ok(fd) => sockets.push((kind, fd)),
+                    Err(e) => {
+                        warn!("[upgrade] handover failed: {}, keep serving", e);
+                        return;
+                    }
+                }
+            }
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        let stop = self.stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name("upgrade".to_string())
+            .spawn(move || {
+                let fds: Vec<_> = sockets.iter().map(|(k, fd)| (*k, fd.as_raw_fd())).collect();
+                let _ = tx.send(upgrade::serve(stream, &fds));
+                stop.wake();
+            });
+        match spawned {
+            Ok(_) => *pending = Some(rx),
+            Err(e) => warn!("[upgrade] handover failed: {}, keep serving", e),
+        }
+    }
+
+    /// 取出后台交接的结果：新进程确认后停止读取监听 socket 并开始排空
+    #[cfg(unix)]
+    fn finish_upgrade(&self, listen_sockets: &mut [ListenSocket]) {
+        let result = {
+            let mut pending = self.upgrade_pending.lock().recover();
+            let Some(ref rx) = *pending else {
+                return;
+            };
+            let result = match rx.try_recv() {
+                Ok(result) => result,
+                Err(std::sync::mpsc::TryRecvError::Empty) => return,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    Err(std::io::Error::other("handover thread exited"))
+                }
+            };
+            *pending = None;
+            result
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("[upgrade] new instance did not take over, keep serving");
+                return;
+            }
+            Err(e) => {
+                warn!("[upgrade] handover failed: {}, keep serving", e);
+                return;
+            }
+        }
+
+        info!("[upgrade] listening sockets handed over to the new instance, draining");
+        self.handed_over.store(true, Ordering::Relaxed);
+        // UDP 监听 socket 保持打开，已有会话的回包仍从这里发出，新数据包由新进程读取
+        for listen in listen_sockets.iter_mut() {
+            if let Some(ref mut socket) = listen.udp_socket {
+                let _ = self.poll.registry().deregister(socket);
+            }
+            listen.tcp_backlog = false;
+            listen.udp_backlog = false;
+        }
+        if let Some((mut listener, _)) = self.upgrade_listener.lock().recover().take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        self.stop.running.store(false, Ordering::Relaxed);
+    }
+
+    /// 接管继承的已连接客户端 socket (inetd 模式)，按新接受的连接处理，该连接结束后事件循环退出
+    pub fn adopt_connection(&self, fd: RawFd) -> Result<(), std::io::Error> {
+        self.inherited.store(true, Ordering::Relaxed);
+        self.tcp_handler.read().recover().on_inherited(self, fd)
+    }
+
+    /// 继承的连接是否已经结束
+    fn inherited_done(&self) -> bool {
+        self.inherited.load(Ordering::Relaxed)
+            && self.tcp_manager.is_empty()
+            && self.tcp_handler.read().recover().pending_len() == 0
+    }
+
+    /// 是否有监听 socket 上一轮未接受完连接或未读完 UDP 数据包
+    fn has_listen_backlog(&self) -> bool {
+        self.listen_sockets
+            .read()
+            .recover()
+            .iter()
+            .any(|listen| (listen.tcp_backlog && !listen.tcp_paused) || listen.udp_backlog)
+    }
+
+    /// 在监听 socket 上接受一批连接，记录是否还有待接受的连接
+    ///
+    /// fd 耗尽时暂停该监听 socket，`ACCEPT_PAUSE` 后由定时器恢复，避免 accept 反复失败占满 CPU
+    fn accept_batch(&self, listen: &mut ListenSocket) {
+        let Some(ref mut listener) = listen.tcp_listener else {
+            listen.tcp_backlog = false;
+            return;
+        };
+        let handler = self.tcp_handler.read().recover();
+        let accepted = self.isolate(None, || handler.on_accept(self, listener));
+        listen.tcp_backlog = match accepted.unwrap_or(Ok(false)) {
+            Ok(more) => more,
+            Err(ref e) if tcp::is_fd_exhausted(e) => {
+                listen.tcp_paused = true;
+                let resume = Arc::clone(&self.accept_resume);
+                self.timer.register_once(tcp::ACCEPT_PAUSE, move || {
+                    resume.store(true, Ordering::Relaxed);
+                });
+                // 恢复后先尝试接受暂停期间积压的连接
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    pub fn run(&mut self) -> Result<(), std::io::Error> {
+        // 循环内的定时器、超时和时间戳都读取配置的时钟
+        let _clock = self.config.clock.clone().map(crate::clock::install);
 
         // 定期统计输出（与 C++ 版本风格一致）
-        let stats_interval = Duration::from_secs(10);
+        let stats_interval = self.config.stats_interval;
         let tcp_manager = Arc::clone(&self.tcp_manager);
         let udp_manager = Arc::clone(&self.udp_manager);
-        self.timer.register(stats_interval, move || {
+        let last_activity = Mutex::new(None);
+        let stats = self.stats;
+        let sample_bytes = move || {
+            [
+                stats.tcp_bytes_received.get(),
+                stats.tcp_bytes_sent.get(),
+                stats.udp_bytes_received.get(),
+                stats.udp_bytes_sent.get(),
+            ]
+        };
+        let last_sample = Mutex::new((Instant::now(), sample_bytes()));
+        let label = match self.config.tenant {
+            Some(ref tenant) => format!("[stats][{}]", tenant),
+            None => "[stats]".to_string(),
+        };
+        let stun = self.udp_handler.read().recover().stun();
+        let ban_list = self.ban_list();
+        let print_stats = move || {
+            // 速率按上一个统计周期的增量计算
+            let bytes = sample_bytes();
+            let rates = {
+                let mut last = last_sample.lock().recover();
+                let now = Instant::now();
+                let secs = now.duration_since(last.0).as_secs_f64().max(0.001);
+                let rates = std::array::from_fn::<_, 4, _>(|i| {
+                    format_bytes((bytes[i].wrapping_sub(last.1[i]) as f64 / secs) as u64)
+                });
+                *last = (now, bytes);
+                rates
+            };
+
+            // 自上次输出以来没有任何连接活动，跳过统计
+            let activity = (tcp_manager.activity(), udp_manager.activity());
+            {
+                let mut last = last_activity.lock().recover();
+                if *last == Some(activity) {
+                    return;
+                }
+                *last = Some(activity);
+            }
+
             let tcp_count = tcp_manager.len();
             let udp_count = udp_manager.len();
-            let stats = TrafficStats::global();
-            let tcp_rx = stats.tcp_bytes_received.load(Ordering::Relaxed);
-            let tcp_tx = stats.tcp_bytes_sent.load(Ordering::Relaxed);
-            let udp_rx = stats.udp_bytes_received.load(Ordering::Relaxed);
-            let udp_tx = stats.udp_bytes_sent.load(Ordering::Relaxed);
 
             // 格式化输出（与 C++ 版本风格一致）
             log_bare!(
-                "[stats] TCP: {}/{}, UDP: {}/{}, conn: TCP={}, UDP={}\n",
-                format_bytes(tcp_rx),
-                format_bytes(tcp_tx),
-                format_bytes(udp_rx),
-                format_bytes(udp_tx),
+                "{} TCP: {}/{} ({}/s, {}/s), UDP: {}/{} ({}/s, {}/s), conn: TCP={}, UDP={}, peak: TCP={}, UDP={}\n",
+                label,
+                format_bytes(bytes[0]),
+                format_bytes(bytes[1]),
+                rates[0],
+                rates[1],
+                format_bytes(bytes[2]),
+                format_bytes(bytes[3]),
+                rates[2],
+                rates[3],
                 tcp_count,
-                udp_count
+                udp_count,
+                tcp_manager.take_peak(),
+                udp_manager.take_peak()
             );
-        });
+
+            if let Some(endpoint) = stun.as_ref().and_then(|stun| stun.endpoint()) {
+                log_bare!("{} public endpoint: {}\n", label, endpoint);
+            }
+
+            if let Some(ref bans) = ban_list {
+                let banned = bans.banned(get_monotonic_time());
+                if banned > 0 {
+                    log_bare!("{} auto ban: {} clients banned\n", label, banned);
+                }
+            }
+
+            let connect_latency = stats.connect_latency.snapshot();
+            if connect_latency.count > 0 {
+                log_bare!(
+                    "{} latency p50/p90/p99: connect {}, first byte {}\n",
+                    label,
+                    connect_latency,
+                    stats.first_byte_latency.snapshot()
+                );
+            }
+
+            // 多个后端时输出各后端的分配情况
+            let backends = stats.backends();
+            if backends.len() > 1 {
+                for (addr, backend) in &backends {
+                    log_bare!(
+                        "{} backend {}: {}/{}, conn: TCP={}, UDP={}, total={}, latency: connect {}, first byte {}\n",
+                        label,
+                        addr,
+                        format_bytes(backend.bytes_up),
+                        format_bytes(backend.bytes_down),
+                        backend.tcp_connections,
+                        backend.udp_sessions,
+                        backend.assigned,
+                        backend.connect_latency,
+                        backend.first_byte_latency
+                    );
+                }
+            }
+            for (addr, backend) in backends.iter().filter(|(_, b)| b.breaker_trips > 0) {
+                log_bare!(
+                    "{} backend {}: circuit breaker opened {} times, rejected {} connections\n",
+                    label,
+                    addr,
+                    backend.breaker_trips,
+                    backend.breaker_rejected
+                );
+            }
+        };
+        if !stats_interval.is_zero() {
+            self.timer.register(stats_interval, print_stats);
+        }
+
+        // --top: 定期重绘连接表
+        if self.config.top {
+            let tcp_manager = Arc::clone(&self.tcp_manager);
+            let udp_manager = Arc::clone(&self.udp_manager);
+            let top = Mutex::new(Top::default());
+            self.timer.register(top::TOP_INTERVAL, move || {
+                let rows = top::collect(&tcp_manager, &udp_manager);
+                let frame = top
+                    .lock()
+                    .recover()
+                    .render(rows, Instant::now(), top::terminal_rows());
+                // 光标移到左上角并清屏后输出
+                let mut stdout = std::io::stdout().lock();
+                let _ = write!(stdout, "\x1b[H\x1b[2J{}", frame);
+                let _ = stdout.flush();
+            });
+        }
+
+        // 非活跃连接清理（与 C++ 版本 timer_interval 保持一致），关闭 socket 需要访问事件循环，在循环中执行
+        let sweep_due = Arc::clone(&self.sweep_due);
+        self.timer.register(
+            Duration::from_millis(self.config.timer_interval),
+            move || sweep_due.store(true, Ordering::Relaxed),
+        );
 
         let mut events = Events::with_capacity(1024);
-        let mut last_clear_time = 0u64;
+        let max_poll_timeout = Duration::from_millis(MAX_POLL_TIMEOUT_MS);
+
+        let mut drain: Option<Drain> = None;
+        // 上一轮未读完响应的 UDP 会话
+        let mut session_backlog: Vec<Fd64> = Vec::new();
+        loop {
+            // 检查是否收到终止信号（SIGTERM/SIGINT）或 stop() 请求
+            if self.stop.is_aborted() {
+                info!("[event] forced stop, closing all connections");
+                break;
+            }
+            if self.stop.is_stopped() && !self.drain_tick(&mut drain) {
+                break;
+            }
+            if self.inherited_done() {
+                info!("[event] inherited connection closed, exit");
+                break;
+            }
 
-        // 检查是否收到终止信号（SIGTERM/SIGINT）
-        while self.signal_handler.is_running() {
             self.timer.run();
+            if self.sweep_due.swap(false, Ordering::Relaxed) {
+                self.sweep_inactive();
+            }
+            if self.memory.as_ref().is_some_and(|m| m.is_exceeded()) {
+                self.enforce_memory();
+            }
+            if self.accept_resume.swap(false, Ordering::Relaxed) {
+                for listen in self.listen_sockets.write().recover().iter_mut() {
+                    listen.tcp_paused = false;
+                }
+            }
+
+            if self
+                .signal_handler
+                .as_ref()
+                .is_some_and(SignalHandler::take_dump_request)
+            {
+                self.on_dump_request(drain.as_ref());
+            }
+
+            self.run_tcp_resumes();
+            if self.config.chaos.is_some() {
+                self.udp_handler.read().recover().release_delayed(self);
+            }
+            self.isolate(None, || {
+                let handler = self.tcp_handler.read().recover();
+                handler.expire_peeks(self);
+                handler.expire_aux(self);
+                handler.start_fallbacks(self);
+                handler.start_retries(self);
+            });
+
+            // poll 等待时间由最近的定时器决定，避免定时任务被延迟；
+            // 还有未接受的连接或未读完的 UDP 数据包时不等待 (边沿触发不会再次通知)
+            let timeout = if !session_backlog.is_empty() || self.has_listen_backlog() {
+                Duration::ZERO
+            } else {
+                self.timer.poll_timeout(max_poll_timeout).min(
+                    self.udp_handler
+                        .read()
+                        .recover()
+                        .next_delayed(max_poll_timeout),
+                )
+            };
 
             // 处理 EINTR 等被信号中断的情况
-            let poll_result = self.poll.poll(&mut events, Some(Duration::from_millis(10)));
+            let poll_result = self.poll.poll(&mut events, Some(timeout));
             // 统计事件数量并打印所有事件
             let event_count = events.iter().count();
             if event_count > 0 {
@@ -237,40 +988,92 @@ impl EventLoop {
                 Err(e) => return Err(e),
             }
 
-            let mut listen_socket_guard = self.listen_socket.write().expect("RwLock poisoned");
-            let mut listen_socket = listen_socket_guard.as_mut();
+            let mut listen_sockets = self.listen_sockets.write().recover();
+            #[cfg(unix)]
+            let upgrade_token = self
+                .upgrade_listener
+                .lock()
+                .recover()
+                .as_ref()
+                .map(|(_, token)| *token);
+            #[cfg(unix)]
+            self.finish_upgrade(&mut listen_sockets);
+
+            // 先继续处理上一轮未处理完的监听 socket，再处理新事件
+            for listen in listen_sockets
+                .iter_mut()
+                .filter(|l| l.tcp_backlog && !l.tcp_paused)
+            {
+                self.accept_batch(listen);
+            }
+            for listen in listen_sockets.iter_mut().filter(|l| l.udp_backlog) {
+                listen.udp_backlog = match listen.udp_socket {
+                    Some(ref socket) => {
+                        let handler = self.udp_handler.read().recover();
+                        self.isolate(None, || handler.on_datagram(self, socket))
+                            .and_then(Result::ok)
+                            .unwrap_or(false)
+                    }
+                    None => false,
+                };
+            }
+            for fd64 in std::mem::take(&mut session_backlog) {
+                if self.udp_manager.get_session_by_fd64(&fd64).is_none() {
+                    continue;
+                }
+                let handler = self.udp_handler.read().recover();
+                if self
+                    .isolate(Some(fd64), || handler.on_response(self, fd64))
+                    .and_then(Result::ok)
+                    .unwrap_or(false)
+                {
+                    session_backlog.push(fd64);
+                }
+            }
 
             for event in &events {
                 let token = event.token();
 
+                // 唤醒事件只用于打断 poll，循环条件会检查停止标志
+                if token == WAKER_TOKEN {
+                    continue;
+                }
+
                 // 调试：打印所有事件（上面已经打印过，这里不再重复）
                 // debug!("[event] token={:?}, readable={}, writable={}",
                 //        token, event.is_readable(), event.is_writable());
 
-                if let Some(ref mut listen) = listen_socket {
+                #[cfg(unix)]
+                if Some(token) == upgrade_token {
+                    self.on_upgrade(&listen_sockets);
+                    continue;
+                }
+
+                if let Some(listen) = listen_sockets.iter_mut().find(|listen| {
+                    token == listen.tcp_listen_token || token == listen.udp_listen_token
+                }) {
                     if token == listen.tcp_listen_token {
-                        if let Some(ref mut listener) = listen.tcp_listener {
-                            if event.is_readable() {
-                                debug!("[event] TCP listener event, accepting connection");
-                                let handler = self.tcp_handler.read().expect("RwLock poisoned");
-                                let _ = handler.on_accept(self, token, listener);
-                            }
+                        if listen.tcp_paused {
+                            // 暂停期间到达的连接在恢复后接受
+                            listen.tcp_backlog = true;
+                        } else if event.is_readable() {
+                            debug!("[event] TCP listener event, accepting connection");
+                            self.accept_batch(listen);
                         }
-                        continue;
-                    }
-                    if token == listen.udp_listen_token {
-                        if let Some(ref socket) = listen.udp_socket {
-                            if event.is_readable() {
-                                let handler = self.udp_handler.read().expect("RwLock poisoned");
-                                let _ = handler.on_datagram(self, token, socket);
-                            }
+                    } else if let Some(ref socket) = listen.udp_socket {
+                        if event.is_readable() {
+                            let handler = self.udp_handler.read().recover();
+                            listen.udp_backlog = self
+                                .isolate(None, || handler.on_datagram(self, socket))
+                                .and_then(Result::ok)
+                                .unwrap_or(false);
                         }
-                        continue;
                     }
+                    continue;
                 }
 
                 let fd64 = {
-                    let token_manager = self.token_manager.read().expect("RwLock poisoned");
+                    let token_manager = self.token_manager.read().recover();
                     let result = token_manager.get_fd64(token);
                     debug!("[event] token={:?}, fd64={:?}", token, result);
                     result
@@ -283,90 +1086,458 @@ impl EventLoop {
                         continue;
                     }
 
-                    if event.is_readable() {
-                        // 使用 O(1) 查找判断是否是 UDP 会话
-                        let is_udp = self.udp_manager.get_session_by_fd64(&fd64).is_some();
-                        trace!("[event] token={:?} readable, is_udp={}", token, is_udp);
-
-                        if is_udp {
-                            let handler = self.udp_handler.read().expect("RwLock poisoned");
-                            let _ = handler.on_response(self, token, fd64);
-                        } else {
-                            debug!(
-                                "[event] calling tcp_handler.on_read for token={:?}, fd64={:?}",
-                                token, fd64
-                            );
-                            let handler = self.tcp_handler.read().expect("RwLock poisoned");
-                            let result = handler.on_read(self, token, fd64);
-                            debug!("[event] tcp_handler.on_read returned {:?}", result);
+                    // panic 只关闭当前连接，不影响事件循环
+                    self.isolate(Some(fd64), || {
+                        // MSG_ZEROCOPY 完成通知和 UDP 会话的 ICMP 错误在错误队列中，以 EPOLLERR 报告
+                        #[cfg(target_os = "linux")]
+                        if event.is_error() {
+                            if self.udp_manager.get_session_by_fd64(&fd64).is_some() {
+                                let handler = self.udp_handler.read().recover();
+                                // 远程不可达时会话已关闭，不再处理读写
+                                if handler.on_error_queue(self, fd64) {
+                                    return;
+                                }
+                            } else {
+                                let handler = self.tcp_handler.read().recover();
+                                handler.on_error_queue(self, fd64);
+                            }
                         }
-                    }
 
-                    if event.is_writable() {
-                        // 使用 O(1) 查找判断是否是 UDP 会话
-                        let is_udp = self.udp_manager.get_session_by_fd64(&fd64).is_some();
+                        if event.is_readable() {
+                            // 使用 O(1) 查找判断是否是 UDP 会话
+                            let is_udp = self.udp_manager.get_session_by_fd64(&fd64).is_some();
+                            trace!("[event] token={:?} readable, is_udp={}", token, is_udp);
 
-                        if !is_udp {
-                            let handler = self.tcp_handler.read().expect("RwLock poisoned");
-                            let _ = handler.on_write(self, token, fd64);
+                            if is_udp {
+                                let handler = self.udp_handler.read().recover();
+                                if handler.on_response(self, fd64).unwrap_or(false)
+                                    && !session_backlog.contains(&fd64)
+                                {
+                                    session_backlog.push(fd64);
+                                }
+                            } else {
+                                debug!(
+                                    "[event] calling tcp_handler.on_read for token={:?}, fd64={:?}",
+                                    token, fd64
+                                );
+                                let handler = self.tcp_handler.read().recover();
+                                let result = handler.on_read(self, token, fd64);
+                                debug!("[event] tcp_handler.on_read returned {:?}", result);
+                            }
                         }
+
+                        if event.is_writable() {
+                            // 使用 O(1) 查找判断是否是 UDP 会话
+                            let is_udp = self.udp_manager.get_session_by_fd64(&fd64).is_some();
+
+                            if !is_udp {
+                                let handler = self.tcp_handler.read().recover();
+                                let _ = handler.on_write(self, token, fd64);
+                            }
+                        }
+                    });
+
+                    if self.rearms() {
+                        self.rearm(fd64);
                     }
                 }
             }
-
-            let now = get_current_time();
-            let timer_interval = self.config.timer_interval;
-            if now - last_clear_time > timer_interval {
-                // 与 C++ 版本 timer_interval 保持一致
-                last_clear_time = now;
-                self.tcp_manager.clear_inactive();
-                self.udp_manager.clear_inactive();
-            }
         }
 
         self.shutdown();
         Ok(())
     }
 
-    pub fn stop(&self) {
-        self.running.store(false, Ordering::Relaxed);
+    /// 停止请求后的排空处理，返回 false 表示应退出事件循环
+    fn drain_tick(&self, drain: &mut Option<Drain>) -> bool {
+        let now = crate::clock::now();
+        // 还在握手/嗅探中的连接也要等待，否则刚接受的连接会被直接关闭
+        let tcp_remaining =
+            self.tcp_manager.len() + self.tcp_handler.read().recover().pending_len();
+        let udp_remaining = self.udp_manager.len();
+        let remaining = tcp_remaining + udp_remaining;
+
+        let drain = match drain {
+            Some(drain) => drain,
+            None => {
+                // 监听 socket 已交给新进程时总是排空，未设置 --drain-timeout 则等到连接全部关闭
+                // (空闲连接仍由超时清理)，升级不丢弃任何连接
+                let timeout = match self.config.drain_timeout {
+                    timeout if !timeout.is_zero() => Some(timeout),
+                    _ if self.handed_over() => None,
+                    _ => return false,
+                };
+                if remaining == 0 {
+                    return false;
+                }
+                self.stop_accepting();
+                match timeout {
+                    Some(timeout) => info!(
+                        "[drain] draining {} TCP connections and {} UDP sessions, force close in {}s",
+                        tcp_remaining,
+                        udp_remaining,
+                        timeout.as_secs()
+                    ),
+                    None => info!(
+                        "[drain] draining {} TCP connections and {} UDP sessions until they close",
+                        tcp_remaining, udp_remaining
+                    ),
+                }
+                drain.insert(Drain::start(now, timeout, remaining))
+            }
+        };
+
+        if remaining == 0 {
+            info!("[drain] all connections closed");
+            return false;
+        }
+        if drain.expired(now) {
+            warn!(
+                "[drain] timeout, force closing {} TCP connections and {} UDP sessions",
+                tcp_remaining, udp_remaining
+            );
+            return false;
+        }
+        if drain.log_due(now) || self.drain_report.lock().recover().is_none() {
+            self.report_drain(drain, now, tcp_remaining, udp_remaining);
+        }
+        true
     }
 
-    pub fn shutdown(&mut self) {
-        info!("[event] shutting down...");
+    /// 输出并保存排空报告，供 `PortMapperHandle::drain_report()` 读取
+    fn report_drain(
+        &self,
+        drain: &Drain,
+        now: Instant,
+        tcp_remaining: usize,
+        udp_remaining: usize,
+    ) {
+        let report = drain.report(now, tcp_remaining, udp_remaining, self.talkers());
+        info!("[drain] {}", report);
+        *self.drain_report.lock().recover() = Some(report);
+    }
 
-        {
-            let connections = self
-                .tcp_manager
-                .connections
-                .write()
-                .expect("RwLock poisoned");
-            for (fd64, conn) in connections.iter() {
-                let conn_guard = conn.read().expect("RwLock poisoned");
-                if let Some(raw_fd) = self.fd_manager.to_fd(*fd64) {
-                    unsafe {
-                        libc::close(raw_fd);
-                    }
-                }
-                if let Some(raw_fd) = self.fd_manager.to_fd(conn_guard.remote.fd64) {
-                    unsafe {
-                        libc::close(raw_fd);
+    /// SIGUSR1：输出连接表，排空期间再输出一份当前的排空报告 (相当于 show drain)
+    fn on_dump_request(&self, drain: Option<&Drain>) {
+        self.dump_connections();
+        if let Some(drain) = drain {
+            let tcp_remaining =
+                self.tcp_manager.len() + self.tcp_handler.read().recover().pending_len();
+            self.report_drain(
+                drain,
+                crate::clock::now(),
+                tcp_remaining,
+                self.udp_manager.len(),
+            );
+        }
+    }
+
+    /// 关闭 TCP 监听并拒绝新的 UDP 会话
+    fn stop_accepting(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        let mut listen_sockets = self.listen_sockets.write().recover();
+        for listen in listen_sockets.iter_mut() {
+            if let Some(ref mut listener) = listen.tcp_listener {
+                let _ = self.poll.registry().deregister(listener);
+            }
+            // 释放 listener 即关闭监听 socket；UDP 监听 socket 仍需为已有会话收发数据
+            listen.tcp_listener = None;
+        }
+    }
+
+    /// 关闭超时清理掉的连接和会话：注销并关闭 socket、释放 token，更新统计并通知观察者
+    fn sweep_inactive(&self) {
+        // 内核转发的连接用户态看不到数据，先同步字节数和活跃时间
+        #[cfg(target_os = "linux")]
+        self.isolate(None, || {
+            self.tcp_handler.read().recover().sync_sockmap(self)
+        });
+        self.isolate(None, || {
+            self.tcp_handler.read().recover().autotune_idle(self)
+        });
+        for (conn, reason) in self.tcp_manager.clear_inactive() {
+            let conn = conn.read().recover();
+            // --abort-on-timeout: 以 RST 关闭两端，立即释放后端资源
+            if self.config.abort_on_timeout {
+                for fd64 in [conn.local.fd64, conn.remote.fd64] {
+                    if let Some(fd) = self.fd_manager.to_fd(fd64) {
+                        let _ = tcp::set_linger(fd, Linger::Secs(0));
                     }
                 }
             }
+            self.release_tcp(&conn, reason);
+        }
+        for (session, reason) in self.udp_manager.clear_inactive() {
+            let session = session.read().recover();
+            self.release_fd(session.fd64);
+            self.observers
+                .notify(|o| o.on_close(&session.summary(reason)));
+        }
+        self.isolate(None, || {
+            self.udp_handler.read().recover().send_keepalives(self)
+        });
+        self.isolate(None, || {
+            let listen_sockets = self.listen_sockets.read().recover();
+            self.udp_handler
+                .read()
+                .recover()
+                .send_stun(listen_sockets.iter().filter_map(|l| l.udp_socket.as_ref()))
+        });
+    }
+
+    /// 内存超出预算：先释放空闲缓冲区，仍超出时从最久未活动的连接或会话开始关闭，直到回到预算内
+    fn enforce_memory(&self) {
+        let Some(ref memory) = self.memory else {
+            return;
+        };
+        self.tcp_handler.read().recover().trim_buffers();
+        self.udp_handler.read().recover().trim_buffers();
+        while memory.is_exceeded() {
+            let tcp = self.tcp_manager.oldest();
+            let udp = self.udp_manager.oldest();
+            let evict_tcp = match (&tcp, &udp) {
+                (Some((_, tcp_ts)), Some((_, udp_ts))) => tcp_ts <= udp_ts,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if evict_tcp {
+                let Some((fd64, _)) = tcp else { break };
+                let Some(conn) = self.tcp_manager.evict(&fd64) else {
+                    continue;
+                };
+                let conn = conn.read().recover();
+                warn!(
+                    "[tcp] memory budget exceeded ({}/{} bytes), closing oldest connection {}, #{}",
+                    memory.used(),
+                    memory.limit(),
+                    conn.addr_s,
+                    conn.id
+                );
+                self.release_tcp(&conn, CloseReason::Memory);
+            } else {
+                let Some((address, _)) = udp else { break };
+                let Some(session) = self.udp_manager.evict(&address) else {
+                    continue;
+                };
+                let session = session.read().recover();
+                warn!(
+                    "[udp] memory budget exceeded ({}/{} bytes), closing oldest session {}, #{}",
+                    memory.used(),
+                    memory.limit(),
+                    session.addr_s,
+                    session.id
+                );
+                self.release_fd(session.fd64);
+                self.observers
+                    .notify(|o| o.on_close(&session.summary(CloseReason::Memory)));
+            }
+            memory.record_evicted();
+        }
+    }
+
+    /// 从管理器移除并关闭 UDP 会话，关闭外连 socket 并通知观察者
+    #[cfg(target_os = "linux")]
+    pub(crate) fn close_udp(&self, address: &crate::types::Address, reason: CloseReason) {
+        let Some(session) = self.udp_manager.evict(address) else {
+            return;
+        };
+        let session = session.read().recover();
+        self.release_fd(session.fd64);
+        self.observers
+            .notify(|o| o.on_close(&session.summary(reason)));
+    }
+
+    /// 关闭已从管理器移除的 TCP 连接：释放两端、备用地址和镜像的 socket，更新统计并通知观察者
+    fn release_tcp(&self, conn: &TcpConnection, reason: CloseReason) {
+        self.release_fd(conn.local.fd64);
+        self.release_fd(conn.remote.fd64);
+        if let Some(fallback) = conn.fallback_fd64() {
+            self.release_fd(fallback);
+        }
+        if let Some(mirror) = conn.mirror_fd64() {
+            self.release_fd(mirror);
         }
+        #[cfg(target_os = "linux")]
+        conn.close_pipes();
+        self.stats.dec_tcp_connections();
+        if let Some(ref backend) = conn.backend {
+            backend.stats.dec_tcp_connections();
+        }
+        self.observers.notify(|o| o.on_close(&conn.summary(reason)));
+    }
+
+    /// 执行一次事件处理，截获其中的 panic
+    ///
+    /// 发生 panic 时记录日志并关闭 `fd64` 所属的连接或会话，返回 `None`，事件循环继续运行
+    fn isolate<R>(&self, fd64: Option<Fd64>, f: impl FnOnce() -> R) -> Option<R> {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(result) => return Some(result),
+            Err(payload) => payload,
+        };
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        error!("[event] panic while handling fd64={:?}: {}", fd64, msg);
+        if let Some(fd64) = fd64 {
+            self.close_panicked(fd64);
+        }
+        None
+    }
+
+    /// 关闭处理事件时 panic 的连接或会话
+    fn close_panicked(&self, fd64: Fd64) {
+        if let Some(conn) = self.tcp_manager.get_connection_by_any_fd(&fd64) {
+            let conn = conn.read().recover();
+            self.tcp_manager.erase(&conn.local.fd64);
+            self.release_tcp(&conn, CloseReason::Panic);
+        } else if let Some(session) = self.udp_manager.get_session_by_fd64(&fd64) {
+            let session = session.read().recover();
+            self.udp_manager.erase(&session.address);
+            self.release_fd(session.fd64);
+            self.observers
+                .notify(|o| o.on_close(&session.summary(CloseReason::Panic)));
+        } else {
+            // 尚未加入管理器的 socket
+            self.release_fd(fd64);
+        }
+    }
 
+    /// 注销并关闭 fd64 对应的 socket，释放其 token
+    fn release_fd(&self, fd64: Fd64) {
+        self.deregister_source(fd64);
+        self.fd_manager.close(fd64);
+        self.token_manager.write().recover().remove(&fd64);
+    }
+
+    /// 输出所有 TCP 连接和 UDP 会话 (客户端、后端、存在时间、空闲时间、缓冲字节数、流量)
+    pub fn dump_connections(&self) {
+        let connections = self.tcp_manager.connections.read().recover();
+        let sessions = self.udp_manager.sessions.read().recover();
+        info!(
+            "[dump] TCP connections: {}, UDP sessions: {}",
+            connections.len(),
+            sessions.len()
+        );
+        let backend_of = |backend: &Option<Arc<Backend>>| {
+            backend
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |b| b.addr.to_string())
+        };
+        for conn in connections.values() {
+            let conn = conn.read().recover();
+            info!(
+                "[dump] tcp #{} {} -> {}, age: {}s, idle: {}s, buffered: {}, up: {} ({} packets), down: {} ({} packets){}",
+                conn.id,
+                conn.addr_s,
+                backend_of(&conn.backend),
+                conn.age().as_secs(),
+                conn.idle_duration().as_secs(),
+                format_bytes(conn.buffered() as u64),
+                format_bytes(conn.bytes_up),
+                conn.packets_up,
+                format_bytes(conn.bytes_down),
+                conn.packets_down,
+                if conn.remote_connecting {
+                    ", connecting"
+                } else {
+                    ""
+                }
+            );
+        }
+        for session in sessions.values() {
+            let session = session.read().recover();
+            info!(
+                "[dump] udp #{} {} -> {}, age: {}s, idle: {}s, up: {} ({} packets), down: {} ({} packets)",
+                session.id,
+                session.addr_s,
+                backend_of(&session.backend),
+                session.age().as_secs(),
+                session.idle_duration().as_secs(),
+                format_bytes(session.bytes_up),
+                session.packets_up,
+                format_bytes(session.bytes_down),
+                session.packets_down
+            );
+        }
+    }
+
+    /// 所有剩余连接的 (客户端地址, 总字节数)
+    fn talkers(&self) -> Vec<(String, u64)> {
+        let mut talkers = Vec::new();
+        for conn in self.tcp_manager.connections.read().recover().values() {
+            let conn = conn.read().recover();
+            talkers.push((conn.addr_s.clone(), conn.bytes_up + conn.bytes_down));
+        }
+        for session in self.udp_manager.sessions.read().recover().values() {
+            let session = session.read().recover();
+            talkers.push((
+                session.addr_s.clone(),
+                session.bytes_up + session.bytes_down,
+            ));
+        }
+        talkers
+    }
+
+    /// 安排在 `delay` 之后恢复读取被限速暂停的 TCP 连接
+    ///
+    /// 通过定时器调度，poll 等待时间随之缩短；已安排恢复的 socket 不重复安排
+    pub(crate) fn schedule_tcp_resume(&self, fd64: Fd64, delay: Duration) {
+        if !self.tcp_resume.lock().recover().insert(fd64) {
+            return;
+        }
+        let due = Arc::clone(&self.tcp_resume_due);
+        self.timer.register_once(delay, move || {
+            due.lock().recover().push(fd64);
+        });
+    }
+
+    /// 恢复定时器已到期的限速连接
+    fn run_tcp_resumes(&self) {
+        let due = std::mem::take(&mut *self.tcp_resume_due.lock().recover());
+        if due.is_empty() {
+            return;
+        }
         {
-            let sessions = self.udp_manager.sessions.read().expect("RwLock poisoned");
-            for (_, session) in sessions.iter() {
-                let session_guard = session.read().expect("RwLock poisoned");
-                if let Some(raw_fd) = self.fd_manager.to_fd(session_guard.fd64) {
-                    unsafe {
-                        libc::close(raw_fd);
-                    }
+            let mut resume = self.tcp_resume.lock().recover();
+            for fd64 in &due {
+                resume.remove(fd64);
+            }
+        }
+
+        for fd64 in due {
+            if !self.fd_manager.exist(fd64) {
+                continue;
+            }
+            let token = self.token_manager.read().recover().get_token(&fd64);
+            if let Some(token) = token {
+                trace!("[event] resuming rate limited fd64={:?}", fd64);
+                let handler = self.tcp_handler.read().recover();
+                self.isolate(Some(fd64), || handler.on_read(self, token, fd64));
+                if self.rearms() {
+                    self.rearm(fd64);
                 }
             }
         }
+    }
+
+    /// 停止事件循环，并唤醒阻塞中的 poll
+    pub fn stop(&self) {
+        self.stop_handle().stop();
+    }
+
+    /// 获取停止句柄，可传给其他线程用于停止事件循环
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    pub fn shutdown(&mut self) {
+        info!("[event] shutting down...");
+
+        // 连接和会话的 socket 由 FdManager 持有，随之关闭
+        self.fd_manager.close_all();
 
         info!("[event] shutdown complete");
     }
@@ -0,0 +1,276 @@
+//! 连接事件观察者
+//!
+//! 嵌入程序或插件可以在 `EventLoop` 上注册观察者，接收连接建立/关闭、拒绝新连接和后端状态变化等事件
+
+use crate::stats::format_bytes;
+use crate::sync::Recover;
+use crate::types::Address;
+use std::sync::{Arc, RwLock};
+
+/// 连接协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// TCP 连接
+    Tcp,
+    /// UDP 会话
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        })
+    }
+}
+
+/// 连接关闭原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 对端关闭连接
+    Eof,
+    /// 读写出错
+    Error,
+    /// 连接远程地址失败
+    ConnectFailed,
+    /// 超时被清理
+    Timeout,
+    /// 客户端 -> 远程方向超过 `--idle-timeout-c2s` 没有数据
+    ClientIdle,
+    /// 远程 -> 客户端方向超过 `--idle-timeout-s2c` 没有数据
+    RemoteIdle,
+    /// 处理事件时 panic
+    Panic,
+    /// 超出 `--max-memory` 内存预算被关闭
+    Memory,
+    /// 远程以 ICMP 报告不可达 (`--udp-recverr`)
+    Unreachable,
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CloseReason::Eof => "eof",
+            CloseReason::Error => "error",
+            CloseReason::ConnectFailed => "connect failed",
+            CloseReason::Timeout => "timeout",
+            CloseReason::ClientIdle => "client idle",
+            CloseReason::RemoteIdle => "remote idle",
+            CloseReason::Panic => "panic",
+            CloseReason::Memory => "memory",
+            CloseReason::Unreachable => "unreachable",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 拒绝新连接/会话的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// 达到 `--max-connections` 或租户的 `--tenant-max-connections`
+    MaxConnections,
+    /// 超出 `--max-memory` 内存预算
+    Memory,
+    /// SOCKS5 握手或认证失败
+    Handshake,
+    /// 开头数据不符合 `--expect-protocol`
+    Protocol,
+    /// 客户端所在国家不符合 `--geo-allow`/`--geo-deny`
+    Geo,
+    /// 客户端超出单连接限速 (`--rate-limit-per-conn`)
+    RateLimit,
+    /// 客户端地址不符合租户的 `--tenant-allow`/`--tenant-deny`
+    Acl,
+}
+
+impl RejectReason {
+    /// 是否由客户端自身行为引起，只有这些拒绝计入 `--auto-ban`；
+    /// 连接数和内存达到上限说明服务端已满，正常重试的客户端不应被封禁
+    pub fn is_client_fault(self) -> bool {
+        match self {
+            RejectReason::MaxConnections | RejectReason::Memory => false,
+            RejectReason::Handshake
+            | RejectReason::Protocol
+            | RejectReason::Geo
+            | RejectReason::RateLimit
+            | RejectReason::Acl => true,
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            RejectReason::MaxConnections => "max connections",
+            RejectReason::Memory => "memory",
+            RejectReason::Handshake => "handshake",
+            RejectReason::Protocol => "protocol mismatch",
+            RejectReason::Geo => "geo policy",
+            RejectReason::RateLimit => "rate limit",
+            RejectReason::Acl => "tenant acl",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 已关闭连接的汇总信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSummary<'a> {
+    /// 协议
+    pub protocol: Protocol,
+    /// 客户端地址
+    pub peer: &'a str,
+    /// 客户端 -> 远程 字节数
+    pub bytes_up: u64,
+    /// 远程 -> 客户端 字节数
+    pub bytes_down: u64,
+    /// 客户端 -> 远程 包数
+    pub packets_up: u64,
+    /// 远程 -> 客户端 包数
+    pub packets_down: u64,
+    /// 连接持续时间 (毫秒)
+    pub duration_ms: u64,
+    /// 关闭原因
+    pub reason: CloseReason,
+}
+
+impl ConnectionSummary<'_> {
+    /// 日志中使用的流量描述
+    pub fn traffic(&self) -> String {
+        format!(
+            "up: {} ({} packets), down: {} ({} packets)",
+            format_bytes(self.bytes_up),
+            self.packets_up,
+            format_bytes(self.bytes_down),
+            self.packets_down
+        )
+    }
+}
+
+/// 连接事件观察者
+///
+/// 除后端状态回调外，所有方法都在事件循环线程中同步调用，实现应尽快返回
+pub trait ConnectionObserver: Send + Sync {
+    /// 接受新的 TCP 连接
+    fn on_accept(&self, _peer: &str) {}
+
+    /// 到远程地址的 TCP 连接建立完成
+    fn on_connect_established(&self, _peer: &str, _remote: &Address) {}
+
+    /// 创建新的 UDP 会话
+    fn on_udp_session(&self, _peer: &str, _remote: &Address) {}
+
+    /// TCP 连接或 UDP 会话关闭
+    fn on_close(&self, _summary: &ConnectionSummary<'_>) {}
+
+    /// 拒绝新的 TCP 连接或 UDP 会话
+    fn on_reject(&self, _protocol: Protocol, _peer: &str, _reason: RejectReason) {}
+
+    /// 后端健康检查失败或熔断器打开；健康检查的结果在探测线程中回调
+    fn on_backend_down(&self, _backend: &Address, _reason: &str) {}
+
+    /// 后端恢复 (健康检查再次通过或熔断器关闭)
+    fn on_backend_up(&self, _backend: &Address) {}
+}
+
+/// 观察者列表
+#[derive(Default)]
+pub struct Observers {
+    list: RwLock<Vec<Arc<dyn ConnectionObserver>>>,
+}
+
+impl std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observers")
+            .field("len", &self.list.read().recover().len())
+            .finish()
+    }
+}
+
+impl Observers {
+    /// 注册观察者
+    pub fn add(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.list.write().recover().push(observer);
+    }
+
+    /// 是否没有观察者
+    pub fn is_empty(&self) -> bool {
+        self.list.read().recover().is_empty()
+    }
+
+    /// 依次通知所有观察者
+    pub fn notify<F: Fn(&dyn ConnectionObserver)>(&self, f: F) {
+        for observer in self.list.read().recover().iter() {
+            f(observer.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl ConnectionObserver for Recorder {
+        fn on_accept(&self, peer: &str) {
+            self.events
+                .lock()
+                .recover()
+                .push(format!("accept {}", peer));
+        }
+
+        fn on_close(&self, summary: &ConnectionSummary<'_>) {
+            self.events.lock().recover().push(format!(
+                "close {} {}/{} {:?}",
+                summary.peer, summary.bytes_up, summary.bytes_down, summary.reason
+            ));
+            assert_eq!(
+                summary.traffic(),
+                "up: 10 B (1 packets), down: 20 B (2 packets)"
+            );
+        }
+    }
+
+    #[test]
+    fn test_notify() {
+        let observers = Observers::default();
+        assert!(observers.is_empty());
+
+        let recorder = Arc::new(Recorder::default());
+        observers.add(recorder.clone());
+        assert!(!observers.is_empty());
+
+        observers.notify(|o| o.on_accept("127.0.0.1:1000"));
+        // 未实现的回调使用默认空实现
+        let remote = Address::from_str("127.0.0.1:2000").expect("address");
+        observers.notify(|o| o.on_udp_session("127.0.0.1:1000", &remote));
+        observers.notify(|o| {
+            o.on_close(&ConnectionSummary {
+                protocol: Protocol::Tcp,
+                peer: "127.0.0.1:1000",
+                bytes_up: 10,
+                bytes_down: 20,
+                packets_up: 1,
+                packets_down: 2,
+                duration_ms: 5,
+                reason: CloseReason::Eof,
+            })
+        });
+
+        let events = recorder.events.lock().recover();
+        assert_eq!(
+            *events,
+            vec![
+                "accept 127.0.0.1:1000".to_string(),
+                "close 127.0.0.1:1000 10/20 Eof".to_string()
+            ]
+        );
+    }
+}
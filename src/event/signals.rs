@@ -1,92 +1,276 @@
 //! 信号处理模块
 //!
-//! 处理 SIGPIPE、SIGTERM、SIGINT 等信号
-//! 使用原始 libc 调用，避免 signal_hook 库的兼容性问题
+//! 处理 SIGPIPE、SIGTERM、SIGINT、SIGUSR1、SIGUSR2 等信号
+//! 使用原始 libc 调用，避免 signal_hook 库的兼容性问题。
+//! Windows 只有 SIGINT (Ctrl-C) 和 SIGTERM，由 signal_hook 注册处理函数
+//!
+//! 信号处理影响整个进程，只在配置了 `handle_signals` 时 (命令行程序) 启用，
+//! 嵌入的 `PortMapper` 默认不处理信号，由宿主程序通过 `PortMapperHandle::stop()` 停止
 
+use super::StopHandle;
+#[cfg(unix)]
 use crate::info;
-use libc::{SIGINT, SIGPIPE, SIGTERM, SIG_DFL};
+#[cfg(unix)]
+use crate::log::Logger;
+#[cfg(unix)]
+use libc::{SIGINT, SIGPIPE, SIGTERM, SIGUSR1, SIGUSR2, SIG_DFL};
 use std::io::Error;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-/// 信号处理器
-#[derive(Debug, Clone)]
+/// 信号处理器，drop 时停止处理
+#[derive(Debug)]
 pub struct SignalHandler {
-    /// 运行标志
-    running: Arc<AtomicBool>,
+    /// 收到 SIGUSR1，等待事件循环输出连接表
+    dump_requested: Arc<AtomicBool>,
+    /// 信号线程及其退出标志
+    #[cfg(unix)]
+    thread: Option<(std::thread::JoinHandle<()>, Arc<AtomicBool>)>,
+    /// 注册的处理函数
+    #[cfg(windows)]
+    ids: Vec<signal_hook::SigId>,
 }
 
-impl SignalHandler {
-    /// 创建新的信号处理器
-    pub fn new() -> Result<Self, Error> {
-        let running = Arc::new(AtomicBool::new(true));
+/// 收到信号后的处理：通过停止句柄停止事件循环，或设置标志后唤醒 poll，使其立即处理
+struct Dispatcher {
+    stop: StopHandle,
+    dump_requested: Arc<AtomicBool>,
+    /// 已收到过 SIGTERM/SIGINT，正在排空
+    terminating: AtomicBool,
+}
 
-        // Spawn signal handling thread
-        {
-            let running = Arc::clone(&running);
-            std::thread::spawn(move || {
-                // 只处理 SIGTERM 和 SIGINT（与 C++ 版本保持一致）
-                info!("[signal] signal handler started");
-
-                // 设置信号处理函数
-                unsafe {
-                    libc::signal(SIGPIPE, SIG_DFL);
-                }
+impl Dispatcher {
+    /// 第一次收到 SIGTERM/SIGINT 时有序停止，排空期间再次收到时立即停止
+    fn terminate(&self) {
+        if self.terminating.swap(true, Ordering::Relaxed) {
+            self.stop.abort();
+        } else {
+            self.stop.stop();
+        }
+    }
 
-                // 使用简单的信号等待机制
-                let mut sigset: libc::sigset_t = unsafe { std::mem::zeroed() };
-                unsafe {
-                    libc::sigemptyset(&mut sigset);
-                    libc::sigaddset(&mut sigset, SIGTERM);
-                    libc::sigaddset(&mut sigset, SIGINT);
-                    libc::pthread_sigmask(libc::SIG_BLOCK, &sigset, std::ptr::null_mut());
+    /// SIGTERM 和 SIGINT 与 C++ 版本保持一致，SIGUSR1 用于输出连接表，SIGUSR2 切换 debug 日志
+    #[cfg(unix)]
+    fn dispatch(&self, sig: libc::c_int) {
+        match sig {
+            SIGPIPE => {
+                // 忽略 SIGPIPE
+                info!("[signal] got sigpipe, ignored");
+            }
+            SIGTERM | SIGINT => {
+                let sig_name = if sig == SIGTERM { "sigterm" } else { "sigint" };
+                if self.terminating.load(Ordering::Relaxed) {
+                    info!("[signal] got {} again, force exit", sig_name);
+                } else {
+                    info!("[signal] got {}, exit", sig_name);
                 }
+                self.terminate();
+            }
+            SIGUSR1 => {
+                info!("[signal] got sigusr1, dumping connection table");
+                self.dump_requested.store(true, Ordering::Relaxed);
+                self.stop.wake();
+            }
+            SIGUSR2 => {
+                let level = Logger::global().toggle_debug();
+                info!("[signal] got sigusr2, log level is now {}", level);
+            }
+            _ => {
+                info!("[signal] got unknown signal: {}", sig);
+            }
+        }
+    }
+}
+
+impl SignalHandler {
+    /// 创建新的信号处理器，收到终止信号时通过 `stop` 停止事件循环
+    ///
+    /// 在调用线程中屏蔽 SIGTERM/SIGINT/SIGUSR1/SIGUSR2，需在创建其他线程之前调用
+    #[cfg(unix)]
+    pub fn new(stop: StopHandle) -> Result<Self, Error> {
+        let dump_requested = Arc::new(AtomicBool::new(false));
+
+        // 在调用线程中屏蔽 SIGTERM/SIGINT/SIGUSR1/SIGUSR2，之后创建的线程 (包括信号线程) 继承该屏蔽字，
+        // 信号只能由 sigwait 接收，不会以默认动作直接终止进程
+        let mut sigset: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut sigset);
+            libc::sigaddset(&mut sigset, SIGTERM);
+            libc::sigaddset(&mut sigset, SIGINT);
+            libc::sigaddset(&mut sigset, SIGUSR1);
+            libc::sigaddset(&mut sigset, SIGUSR2);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &sigset, std::ptr::null_mut());
+        }
 
-                loop {
-                    let mut sig: libc::c_int = 0;
-                    let ret = unsafe { libc::sigwait(&sigset, &mut sig) };
+        // Spawn signal handling thread
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let dispatcher = Dispatcher {
+                stop,
+                dump_requested: Arc::clone(&dump_requested),
+                terminating: AtomicBool::new(false),
+            };
+            let closed = Arc::clone(&closed);
+            std::thread::Builder::new()
+                .name("signal".to_string())
+                .spawn(move || {
+                    info!("[signal] signal handler started");
 
-                    if ret != 0 {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        continue;
+                    // 设置信号处理函数
+                    unsafe {
+                        libc::signal(SIGPIPE, SIG_DFL);
                     }
 
-                    match sig {
-                        SIGPIPE => {
-                            // 忽略 SIGPIPE
-                            info!("[signal] got sigpipe, ignored");
-                        }
-                        SIGTERM | SIGINT => {
-                            let sig_name = if sig == SIGTERM { "sigterm" } else { "sigint" };
-                            info!("[signal] got {}, exit", sig_name);
-                            running.store(false, Ordering::Relaxed);
-                            break;
+                    loop {
+                        let mut sig: libc::c_int = 0;
+                        let ret = unsafe { libc::sigwait(&sigset, &mut sig) };
+
+                        // drop 时向本线程发送信号唤醒 sigwait
+                        if closed.load(Ordering::Relaxed) {
+                            return;
                         }
-                        _ => {
-                            info!("[signal] got unknown signal: {}", sig);
+                        if ret != 0 {
+                            std::thread::sleep(std::time::Duration::from_millis(100));
+                            continue;
                         }
+                        dispatcher.dispatch(sig);
                     }
-                }
+                })?
+        };
+
+        Ok(Self {
+            dump_requested,
+            thread: Some((thread, closed)),
+        })
+    }
 
-                info!("[signal] signal handler thread exiting");
-            });
+    /// 创建新的信号处理器，收到终止信号时通过 `stop` 停止事件循环
+    ///
+    /// 处理函数只修改标志并唤醒事件循环 (控制台事件在系统创建的线程中处理)
+    #[cfg(windows)]
+    pub fn new(stop: StopHandle) -> Result<Self, Error> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        let dispatcher = Arc::new(Dispatcher {
+            stop,
+            dump_requested: Arc::new(AtomicBool::new(false)),
+            terminating: AtomicBool::new(false),
+        });
+        let mut ids = Vec::new();
+        for sig in [SIGINT, SIGTERM] {
+            let dispatcher = Arc::clone(&dispatcher);
+            let id =
+                unsafe { signal_hook::low_level::register(sig, move || dispatcher.terminate()) };
+            match id {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    for id in ids {
+                        signal_hook::low_level::unregister(id);
+                    }
+                    return Err(e);
+                }
+            }
         }
 
-        Ok(Self { running })
+        Ok(Self {
+            dump_requested: Arc::clone(&dispatcher.dump_requested),
+            ids,
+        })
+    }
+
+    /// 取出待处理的连接表输出请求
+    pub fn take_dump_request(&self) -> bool {
+        self.dump_requested.swap(false, Ordering::Relaxed)
     }
+}
+
+impl Drop for SignalHandler {
+    /// 停止信号线程 (Windows 上注销处理函数)；调用线程的信号屏蔽字保持不变
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some((thread, closed)) = self.thread.take() {
+            use std::os::unix::thread::JoinHandleExt;
+
+            closed.store(true, Ordering::Relaxed);
+            // 发给信号线程自身的信号只会由它的 sigwait 接收
+            if unsafe { libc::pthread_kill(thread.as_pthread_t(), SIGUSR2) } == 0 {
+                let _ = thread.join();
+            }
+        }
+        #[cfg(windows)]
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use mio::{Events, Poll, Token, Waker};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_dispatch_wakes_poll() {
+        let mut poll = Poll::new().expect("poll");
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).expect("waker"));
+        let stop = StopHandle::new(waker);
+        let dispatcher = Dispatcher {
+            stop: stop.clone(),
+            dump_requested: Arc::new(AtomicBool::new(false)),
+            terminating: AtomicBool::new(false),
+        };
+        let mut events = Events::with_capacity(4);
+        // 标志修改后 poll 立即返回，不必等到超时
+        let mut woken = |sig| {
+            dispatcher.dispatch(sig);
+            let start = Instant::now();
+            poll.poll(&mut events, Some(Duration::from_secs(5)))
+                .expect("poll");
+            events.iter().any(|e| e.token() == Token(0)) && start.elapsed() < Duration::from_secs(1)
+        };
 
-    /// 注册信号处理
-    pub fn register(&self) -> Result<(), Error> {
-        Ok(())
+        assert!(woken(SIGUSR1));
+        assert!(dispatcher.dump_requested.load(Ordering::Relaxed));
+        assert!(woken(SIGTERM));
+        assert!(stop.is_stopped() && !stop.is_aborted());
+        // 排空期间再次收到终止信号时立即停止，不退出进程
+        assert!(woken(SIGINT));
+        assert!(stop.is_aborted());
     }
 
-    /// 检查是否仍在运行
-    pub fn is_running(&self) -> bool {
-        self.running.load(Ordering::Relaxed)
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_drop_joins_thread() {
+        crate::sandbox::run_isolated("event::signals::tests::test_drop_joins_thread", || {
+            let poll = Poll::new().expect("poll");
+            let waker = Arc::new(Waker::new(poll.registry(), Token(0)).expect("waker"));
+            let stop = StopHandle::new(waker);
+            let handler = SignalHandler::new(stop.clone()).expect("signal handler");
+            drop(handler);
+            assert!(!stop.is_stopped());
+        });
     }
 
-    /// 停止运行
-    pub fn stop(&self) {
-        self.running.store(false, Ordering::Relaxed);
+    #[test]
+    fn test_new_blocks_signals_in_caller() {
+        // 在单独的线程中创建，不影响测试线程的信号屏蔽字
+        std::thread::spawn(|| {
+            let poll = Poll::new().expect("poll");
+            let waker = Arc::new(Waker::new(poll.registry(), Token(0)).expect("waker"));
+            let _handler = SignalHandler::new(StopHandle::new(waker)).expect("signal handler");
+            let mut blocked: libc::sigset_t = unsafe { std::mem::zeroed() };
+            unsafe {
+                libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut blocked);
+            }
+            for sig in [SIGTERM, SIGINT] {
+                assert_eq!(unsafe { libc::sigismember(&blocked, sig) }, 1);
+            }
+        })
+        .join()
+        .expect("join");
     }
 }
@@ -1,102 +1,472 @@
 //! TCP 处理器模块 - 使用简单 recv/send 转发 (高性能可靠方案)
 
-use crate::config::FwdType;
+use crate::autotune::BufAutotune;
+use crate::backend::{translate_addr, Backend, BackendPool};
+use crate::bufpool::BufferPool;
+#[cfg(target_os = "linux")]
+use crate::bufpool::PooledBuf;
+use crate::chaos::Chaos;
+use crate::config::{
+    CircuitBreaker, FwdType, Linger, PollMode, PortRange, SocketMark, TcpKeepalive,
+    MAX_DATA_LEN_TCP,
+};
+#[cfg(target_os = "linux")]
+use crate::config::{SOCKMAP_DRAIN_MS, SOCKMAP_MAX_TRIES, ZEROCOPY_MAX_INFLIGHT};
+use crate::connection::{next_conn_id, Fallback, TcpConnection, TcpEndpoint};
+use crate::event::observer::{CloseReason, Protocol, RejectReason};
 use crate::event::EventLoop;
-use crate::fd_manager::Fd64;
+use crate::fd_manager::{Fd64, Source};
 use crate::manager::TcpConnectionManager;
-use crate::stats::TrafficStats;
-use crate::types::Address;
+use crate::memory::{MemoryBudget, TCP_CONN_MEMORY};
+use crate::middleware::{
+    AuxMapping, Middleware, StreamInfo, TcpContext, AUX_TIMEOUT, MAX_AUX_MAPPINGS,
+};
+use crate::mirror::MirrorStream;
+use crate::obfs::{ObfsStreams, Psk};
+use crate::ratelimit::RateLimiter;
+use crate::sim::{SocketIo, SysIo};
+use crate::sni::{
+    parse_client_hello, SniResult, SniRouter, MAX_CLIENT_HELLO_LEN, SNI_PEEK_TIMEOUT,
+};
+use crate::sniff::{ExpectProtocol, Sniff};
+#[cfg(target_os = "linux")]
+use crate::sockmap::Sockmap;
+use crate::socks5::{self, Accept, Socks5Accept, Socks5Server, Socks5Upstream, Step};
+use crate::stats::{BackendStats, Direction};
+use crate::sync::{Mutex, Recover, RwLock};
+use crate::types::{Address, Nat64Prefix};
+#[cfg(windows)]
+use crate::winsock::{self as libc, AsRawFd, FromRawFd, RawFd};
 use crate::{debug, info, warn};
 use mio::net::{TcpListener, TcpStream};
 use mio::{Interest, Token};
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::io;
-use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 监听 socket 每次可读事件最多接受的连接数
+pub const TCP_ACCEPT_BATCH: usize = 32;
+
+/// fd 耗尽时暂停接受连接的时间
+pub const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
+
+/// Happy Eyeballs 首选地址 (IPv6) 的领先时间，超时未连接成功时开始连接备用地址 (RFC 8305 建议 250ms)
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// 连接后端失败后第一次重试前的等待时间，之后每次翻倍
+pub const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// 重试等待时间上限
+pub const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// 第 `attempt` 次 (从 1 开始) 重试前的等待时间
+pub fn retry_backoff(attempt: u32) -> Duration {
+    CONNECT_RETRY_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(CONNECT_RETRY_MAX_DELAY)
+}
+
+/// 已连接 socket 的对端地址
+fn peer_addr(fd: RawFd) -> Option<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getpeername(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret != 0 {
+        return None;
+    }
+    Address::from_raw_sockaddr(&storage as *const _ as *const libc::sockaddr, len)
+        .ok()
+        .filter(Address::is_ip)
+        .map(|addr| addr.to_sockaddr())
+}
+
+/// 错误是否为进程或系统的 fd 数量达到上限
+pub fn is_fd_exhausted(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// 打开预留 fd (空设备)
+fn open_reserve_fd() -> Option<std::fs::File> {
+    #[cfg(unix)]
+    const NULL_DEVICE: &str = "/dev/null";
+    #[cfg(windows)]
+    const NULL_DEVICE: &str = "NUL";
+    std::fs::File::open(NULL_DEVICE).ok()
+}
+
+/// 辅助映射的临时监听，接受一个来自 `peer` 的连接后转发到 `target`
+#[derive(Debug)]
+struct AuxListener {
+    /// 请求映射的连接 ID
+    conn_id: u64,
+    /// 允许连接的对端 IP
+    peer: IpAddr,
+    target: Address,
+    /// 请求映射的连接的后端统计，辅助连接的流量计入其中
+    stats: Arc<BackendStats>,
+    deadline: Instant,
+}
+
+/// 过滤器处理连接数据时的上下文
+struct ConnContext<'a> {
+    handler: &'a TcpHandler,
+    event_loop: &'a EventLoop,
+    conn: &'a TcpConnection,
+}
+
+impl TcpContext for ConnContext<'_> {
+    fn stream(&self) -> Option<StreamInfo> {
+        let addrs = |fd64| {
+            self.event_loop
+                .fd_manager
+                .with_source(fd64, |source| match source {
+                    Source::Tcp(stream) => {
+                        Some((stream.local_addr().ok()?, stream.peer_addr().ok()?))
+                    }
+                    _ => None,
+                })
+                .flatten()
+        };
+        let (client_local, client) = addrs(self.conn.local.fd64)?;
+        let (remote_local, remote) = addrs(self.conn.remote.fd64)?;
+        Some(StreamInfo {
+            client,
+            client_local,
+            remote,
+            remote_local,
+        })
+    }
+
+    fn open_tcp(&mut self, mapping: AuxMapping) -> Option<SocketAddr> {
+        self.handler.open_aux(self.event_loop, self.conn, mapping)
+    }
+}
+
+/// 等待开头数据 (SNI 路由的 ClientHello、协议嗅探或 SOCKS5 握手) 的客户端连接 (尚未连接后端)
+#[derive(Debug)]
+struct PeekPending {
+    id: u64,
+    addr: SocketAddr,
+    client_addr: String,
+    /// 超时时间，UDP ASSOCIATE 控制连接为 None (一直保持到客户端关闭)
+    deadline: Option<Instant>,
+    /// SOCKS5 服务端握手状态
+    socks: Option<Socks5Accept>,
+}
+
+/// 限速时一次至少读取的字节数，令牌不足时暂停读取，避免每轮循环积累的零星令牌引发大量小读取
+pub const RATE_LIMIT_MIN_READ: usize = 4096;
+
+/// 尚未建立转发的客户端 socket
+enum ClientSocket {
+    /// 刚接受的连接，尚未注册
+    New(TcpStream),
+    /// 已交给 FdManager 并注册的连接 (等待开头数据)
+    Registered(Fd64),
+    /// 辅助映射的临时监听刚接受的连接，不经过中间件
+    Aux(TcpStream),
+    /// 完成 SOCKS5 握手的连接和 CONNECT 请求之后已读出的数据
+    Socks(Fd64, Vec<u8>),
+}
 
 /// TCP 处理器
 #[derive(Debug)]
 pub struct TcpHandler {
-    remote_addr: Address,
+    backends: Arc<BackendPool>,
     socket_buf_size: usize,
+    /// 连接读写缓冲区池
+    buffers: Arc<BufferPool>,
+    /// 内存预算，缓冲区分配计入其中
+    memory: Option<Arc<MemoryBudget>>,
     fwd_type: FwdType,
+    /// 4to6/6to4 翻译使用的 NAT64 前缀
+    nat64_prefix: Nat64Prefix,
     bind_interface: Option<String>,
+    transparent: bool,
+    /// 外连 socket 绑定的源地址 (每个地址族最多一个)
+    bind_source: Vec<IpAddr>,
+    /// 外连 socket 的源端口范围
+    source_ports: Option<PortRange>,
+    keepalive: Option<TcpKeepalive>,
+    nodelay: bool,
+    quickack: bool,
+    congestion: Option<CString>,
+    user_timeout: Option<Duration>,
+    /// 单次发送达到该字节数时使用 MSG_ZEROCOPY
+    zerocopy: Option<usize>,
+    /// socket 缓冲区自动调整的全局预算
+    autotune: Option<Arc<BufAutotune>>,
+    /// 缓冲区已增长的连接 (local fd64)，空闲后由定时器缩小
+    tuned: Mutex<Vec<Fd64>>,
+    /// 连接建立后交给内核转发的 sockmap
+    #[cfg(target_os = "linux")]
+    sockmap: Option<Arc<Sockmap>>,
+    /// 已加入 sockmap 的连接 (local fd64)，定期同步内核转发的字节数
+    #[cfg(target_os = "linux")]
+    accelerated: Mutex<Vec<Fd64>>,
+    linger: Option<Linger>,
+    upstream: Option<Arc<Socks5Upstream>>,
+    sni_router: Option<Arc<SniRouter>>,
+    /// 客户端连接应使用的协议，不符合的连接在连接后端前关闭
+    expect: ExpectProtocol,
+    /// 作为 SOCKS5 服务端时的握手和 UDP 关联状态
+    socks_server: Option<Arc<Socks5Server>>,
+    peek_pending: Mutex<HashMap<Fd64, PeekPending>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// 预留 fd：fd 耗尽时释放它来接受并关闭一个待处理连接
+    reserve_fd: Mutex<Option<std::fs::File>>,
+    /// 预留 fd 的模板，释放后复制它重新占住预留 fd，不必再打开文件 (沙箱不允许打开)
+    reserve_template: Option<std::fs::File>,
+    /// 领先时间已到、需要开始连接备用地址的连接 (local fd64)，由定时器添加
+    fallback_due: Arc<Mutex<Vec<Fd64>>>,
+    /// 连接后端失败后的重试次数
+    connect_retries: u32,
+    /// 退避时间已到、需要重新连接后端的连接 (local fd64)，由定时器添加
+    retry_due: Arc<Mutex<Vec<Fd64>>>,
+    /// 后端熔断参数
+    breaker: Option<CircuitBreaker>,
+    /// 外连 socket 的 DSCP/TOS 和 SO_MARK
+    mark: SocketMark,
+    /// 转发数据的收发方式 (测试中替换为模拟网络)
+    io: Arc<dyn SocketIo>,
+    /// 客户端 -> 后端方向数据的镜像目标
+    mirror: Option<Address>,
+    /// 解密客户端发来的流 (--decrypt-in)
+    decrypt_in: Option<Psk>,
+    /// 加密发往后端的流 (--encrypt-out)
+    encrypt_out: Option<Psk>,
+    /// 客户端发来压缩流时解压 (--decompress-in)
+    decompress_in: bool,
+    /// 压缩发往后端的流 (--compress-out)
+    compress_out: bool,
+    /// 为新连接创建过滤器的中间件
+    middlewares: Vec<Arc<dyn Middleware>>,
+    /// FTP 数据通道的临时监听 (监听 socket 的 fd64)
+    aux: Mutex<HashMap<Fd64, AuxListener>>,
 }
 
 impl TcpHandler {
     pub fn new() -> Self {
+        let reserve_template = open_reserve_fd();
         Self {
-            remote_addr: Address::from_ipv4(std::net::Ipv4Addr::UNSPECIFIED, 0),
+            backends: Arc::new(BackendPool::default()),
             socket_buf_size: 16 * 1024,
+            buffers: BufferPool::new(16 * 1024),
+            memory: None,
             fwd_type: FwdType::Normal,
+            nat64_prefix: Nat64Prefix::default(),
             bind_interface: None,
+            transparent: false,
+            bind_source: Vec::new(),
+            source_ports: None,
+            keepalive: None,
+            nodelay: true,
+            quickack: false,
+            congestion: None,
+            user_timeout: None,
+            zerocopy: None,
+            autotune: None,
+            tuned: Mutex::new(Vec::new()),
+            #[cfg(target_os = "linux")]
+            sockmap: None,
+            #[cfg(target_os = "linux")]
+            accelerated: Mutex::new(Vec::new()),
+            linger: None,
+            upstream: None,
+            sni_router: None,
+            expect: ExpectProtocol::None,
+            socks_server: None,
+            peek_pending: Mutex::new(HashMap::new()),
+            rate_limiter: None,
+            reserve_fd: Mutex::new(reserve_template.as_ref().and_then(|f| f.try_clone().ok())),
+            reserve_template,
+            fallback_due: Arc::new(Mutex::new(Vec::new())),
+            connect_retries: 0,
+            retry_due: Arc::new(Mutex::new(Vec::new())),
+            breaker: None,
+            mark: SocketMark::default(),
+            io: Arc::new(SysIo),
+            mirror: None,
+            decrypt_in: None,
+            encrypt_out: None,
+            decompress_in: false,
+            compress_out: false,
+            middlewares: Vec::new(),
+            aux: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn set_remote_addr(&mut self, addr: Address) {
-        self.remote_addr = addr;
+    pub fn set_backends(&mut self, backends: Arc<BackendPool>) {
+        self.backends = backends;
     }
 
     pub fn set_buf_size(&mut self, size: usize) {
         self.socket_buf_size = size;
+        self.buffers =
+            BufferPool::with_budget(size, BufferPool::idle_limit(size), self.memory.clone());
+    }
+
+    pub fn set_memory_budget(&mut self, memory: Option<Arc<MemoryBudget>>) {
+        self.memory = memory;
+        self.set_buf_size(self.socket_buf_size);
+    }
+
+    /// 释放缓冲区池中的空闲缓冲区
+    pub(crate) fn trim_buffers(&self) {
+        self.buffers.trim();
     }
 
     pub fn set_fwd_type(&mut self, fwd_type: FwdType) {
         self.fwd_type = fwd_type;
     }
 
+    pub fn set_nat64_prefix(&mut self, prefix: Nat64Prefix) {
+        self.nat64_prefix = prefix;
+    }
+
     pub fn set_bind_interface(&mut self, interface: Option<String>) {
         self.bind_interface = interface;
     }
 
-    fn set_bind_to_device(&self, fd: libc::c_int) -> Result<(), std::io::Error> {
-        #[cfg(target_os = "linux")]
-        if let Some(ref interface) = self.bind_interface {
-            if !interface.is_empty() {
-                let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
-                let len = std::cmp::min(interface.len(), libc::IFNAMSIZ - 1);
-                unsafe {
-                    std::ptr::copy_nonoverlapping(
-                        interface.as_ptr() as *const libc::c_char,
-                        ifreq.ifr_name.as_mut_ptr() as *mut libc::c_char,
-                        len,
-                    );
-                }
-                let ret = unsafe {
-                    libc::setsockopt(
-                        fd,
-                        libc::SOL_SOCKET,
-                        libc::SO_BINDTODEVICE,
-                        &ifreq as *const _ as *const libc::c_void,
-                        std::mem::size_of::<libc::ifreq>() as libc::socklen_t,
-                    )
-                };
-                if ret < 0 {
-                    return Err(std::io::Error::last_os_error());
-                }
+    pub fn set_transparent(&mut self, enable: bool) {
+        self.transparent = enable;
+    }
+
+    pub fn set_bind_source(&mut self, sources: Vec<IpAddr>) {
+        self.bind_source = sources;
+    }
+
+    pub fn set_source_ports(&mut self, ports: Option<PortRange>) {
+        self.source_ports = ports;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    pub fn set_quickack(&mut self, quickack: bool) {
+        self.quickack = quickack;
+    }
+
+    pub fn set_congestion(&mut self, congestion: Option<CString>) {
+        self.congestion = congestion;
+    }
+
+    pub fn set_user_timeout(&mut self, user_timeout: Option<Duration>) {
+        self.user_timeout = user_timeout;
+    }
+
+    pub fn set_zerocopy(&mut self, threshold: Option<usize>) {
+        self.zerocopy = threshold;
+    }
+
+    pub fn set_buf_autotune(&mut self, autotune: Option<Arc<BufAutotune>>) {
+        self.autotune = autotune;
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn set_sockmap(&mut self, sockmap: Option<Arc<Sockmap>>) {
+        self.sockmap = sockmap;
+    }
+
+    pub fn set_linger(&mut self, linger: Option<Linger>) {
+        self.linger = linger;
+    }
+
+    pub fn set_connect_retries(&mut self, retries: u32) {
+        self.connect_retries = retries;
+    }
+
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>) {
+        self.breaker = breaker;
+    }
+
+    pub fn set_socket_mark(&mut self, mark: SocketMark) {
+        self.mark = mark;
+    }
+
+    pub fn set_upstream(&mut self, upstream: Option<Arc<Socks5Upstream>>) {
+        self.upstream = upstream;
+    }
+
+    pub fn set_sni_router(&mut self, router: Option<Arc<SniRouter>>) {
+        self.sni_router = router;
+    }
+
+    pub fn set_expect_protocol(&mut self, expect: ExpectProtocol) {
+        self.expect = expect;
+    }
+
+    pub fn set_socks_server(&mut self, server: Option<Arc<Socks5Server>>) {
+        self.socks_server = server;
+    }
+
+    /// 新连接是否需要先窥探开头数据 (或完成 SOCKS5 握手) 再连接后端
+    fn peeks(&self) -> bool {
+        self.sni_router.is_some()
+            || self.expect != ExpectProtocol::None
+            || self.socks_server.is_some()
+    }
+
+    pub fn set_socket_io(&mut self, io: Arc<dyn SocketIo>) {
+        self.io = io;
+    }
+
+    pub fn set_rate_limiter(&mut self, limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = limiter;
+    }
+
+    pub fn set_mirror(&mut self, mirror: Option<Address>) {
+        self.mirror = mirror;
+    }
+
+    pub fn set_obfs(&mut self, decrypt_in: Option<Psk>, encrypt_out: Option<Psk>) {
+        self.decrypt_in = decrypt_in;
+        self.encrypt_out = encrypt_out;
+    }
+
+    pub fn set_compress(&mut self, decompress_in: bool, compress_out: bool) {
+        self.decompress_in = decompress_in;
+        self.compress_out = compress_out;
+    }
+
+    /// 设置为新连接创建过滤器的中间件
+    pub fn set_middlewares(&mut self, middlewares: Vec<Arc<dyn Middleware>>) {
+        self.middlewares = middlewares;
+    }
+
+    fn set_bind_to_device(&self, fd: RawFd) -> Result<(), std::io::Error> {
+        match self.bind_interface {
+            Some(ref interface) if !interface.is_empty() => {
+                crate::set_bind_to_device(fd, interface)
             }
+            _ => Ok(()),
         }
-        Ok(())
     }
 
-    fn get_remote_addr_for_connect(&self) -> Address {
-        match self.fwd_type {
-            FwdType::FwdType4to6 => self
-                .remote_addr
-                .to_ipv4_mapped_ipv6()
-                .unwrap_or_else(|| self.remote_addr.clone()),
-            FwdType::FwdType6to4 => self
-                .remote_addr
-                .from_ipv4_mapped_ipv6()
-                .unwrap_or_else(|| self.remote_addr.clone()),
-            _ => self.remote_addr.clone(),
-        }
+    fn get_remote_addr_for_connect(&self, remote_addr: &Address) -> Address {
+        translate_addr(remote_addr, self.fwd_type, &self.nat64_prefix)
     }
 
-    fn get_remote_addr_family(&self) -> libc::c_int {
+    fn get_remote_addr_family(&self, remote_addr: &Address) -> libc::c_int {
+        if !remote_addr.is_ip() {
+            return remote_addr.get_addr_family();
+        }
         match self.fwd_type {
             FwdType::FwdType4to6 => libc::AF_INET6,
             FwdType::FwdType6to4 => libc::AF_INET,
             _ => {
-                if self.remote_addr.get_type() == 4 {
+                if remote_addr.get_type() == 4 {
                     libc::AF_INET
                 } else {
                     libc::AF_INET6
@@ -105,10 +475,11 @@ impl TcpHandler {
         }
     }
 
+    /// 设置非阻塞和缓冲区大小，`family` 不是 AF_INET/AF_INET6 时跳过 TCP 选项
     #[inline]
-    fn configure_socket(&self, fd: RawFd) -> Result<(), std::io::Error> {
+    fn configure_socket(&self, fd: RawFd, family: libc::c_int) -> Result<(), std::io::Error> {
+        crate::set_nonblocking(fd)?;
         unsafe {
-            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
             let bufsize = self.socket_buf_size as libc::c_int;
             let buflen = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
             libc::setsockopt(
@@ -125,83 +496,391 @@ impl TcpHandler {
                 &bufsize as *const _ as *const libc::c_void,
                 buflen,
             );
-            let nodelay: libc::c_int = 1;
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_NODELAY,
-                &nodelay as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
+        }
+        if family != libc::AF_INET && family != libc::AF_INET6 {
+            return Ok(());
+        }
+        let _ = setsockopt_int(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            libc::c_int::from(self.nodelay),
+        );
+        #[cfg(target_os = "linux")]
+        if self.quickack {
+            let _ = setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_QUICKACK, 1);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ref congestion) = self.congestion {
+            if let Err(e) = set_congestion(fd, congestion) {
+                debug!("[tcp] set congestion control on fd {} failed: {}", fd, e);
+            }
+        }
+        if let Some(ref keepalive) = self.keepalive {
+            if let Err(e) = set_keepalive(fd, keepalive) {
+                debug!("[tcp] set keepalive on fd {} failed: {}", fd, e);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(timeout) = self.user_timeout {
+            let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            if let Err(e) = setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, ms) {
+                debug!("[tcp] set TCP_USER_TIMEOUT on fd {} failed: {}", fd, e);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if self.zerocopy.is_some() {
+            if let Err(e) = setsockopt_int(fd, libc::SOL_SOCKET, SO_ZEROCOPY, 1) {
+                debug!("[tcp] set SO_ZEROCOPY on fd {} failed: {}", fd, e);
+            }
+        }
+        if let Some(linger) = self.linger {
+            if let Err(e) = set_linger(fd, linger) {
+                debug!("[tcp] set SO_LINGER on fd {} failed: {}", fd, e);
+            }
         }
         Ok(())
     }
 
+    /// 标记外连 socket，需要在 connect 之前设置以便策略路由生效
+    fn mark_socket(&self, fd: RawFd) {
+        if self.mark.is_empty() {
+            return;
+        }
+        if let Err(e) = crate::set_socket_mark(fd, &self.mark) {
+            debug!("[tcp] set socket mark on fd {} failed: {}", fd, e);
+        }
+    }
+
+    /// 绑定 `--bind-source` 中与 `family` 相同地址族的源地址和 `--source-ports` 中的端口，都没有时不绑定
+    fn bind_source(&self, fd: RawFd, family: libc::c_int) -> io::Result<()> {
+        let ip = crate::pick_source(&self.bind_source, family);
+        if ip.is_none() && self.source_ports.is_none() {
+            return Ok(());
+        }
+        crate::bind_source(fd, family, ip, self.source_ports)
+    }
+
+    /// 接受新的客户端连接
+    ///
+    /// 每次最多接受 `TCP_ACCEPT_BATCH` 个连接 (水平触发模式下为 1 个)，与其他事件交替处理。
+    /// 没有更多待接受的连接时返回 false；达到上限时返回 true，调用方应稍后继续接受
     pub fn on_accept(
         &self,
         event_loop: &EventLoop,
-        _token: Token,
         listener: &mut TcpListener,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<bool, std::io::Error> {
+        for _ in 0..event_loop.config.poll_mode.batch(TCP_ACCEPT_BATCH) {
+            if !self.accept_one(event_loop, listener)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 接受并处理一个客户端连接，没有待接受的连接时返回 false
+    fn accept_one(
+        &self,
+        event_loop: &EventLoop,
+        listener: &mut TcpListener,
+    ) -> Result<bool, std::io::Error> {
         let tcp_manager = &event_loop.tcp_manager;
-        let poll = &event_loop.poll;
-        let token_manager = &event_loop.token_manager;
 
-        let (mut stream, addr) = match listener.accept() {
+        let listen_addr = &event_loop.config.listen_addr;
+        let (stream, addr, client_addr) = match Self::accept(listen_addr, listener) {
             Ok(result) => result,
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-            Err(e) => return Err(e),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => {
+                if is_fd_exhausted(&e) {
+                    self.shed_connection(listener);
+                }
+                return Err(e);
+            }
         };
 
-        let client_addr = format!("{}", addr);
+        let id = next_conn_id();
+        if event_loop.is_banned(addr) {
+            debug!("[tcp] #{} {} is banned, closing", id, client_addr);
+            return Ok(true);
+        }
+        if let Some(geo) = event_loop.geo() {
+            let info = geo.lookup(addr.ip());
+            if !geo.allows(&info) {
+                debug!(
+                    "[tcp] #{} {} [{}] denied by geo policy, closing",
+                    id, client_addr, info
+                );
+                event_loop.reject(Protocol::Tcp, addr, &client_addr, RejectReason::Geo);
+                return Ok(true);
+            }
+        }
+        if let Some(reason) = event_loop.tenant_check(addr, self.pending_len()) {
+            debug!(
+                "[tcp] #{} {} rejected by tenant limits ({}), closing",
+                id, client_addr, reason
+            );
+            event_loop.reject(Protocol::Tcp, addr, &client_addr, reason);
+            return Ok(true);
+        }
+        if tcp_manager.len() + self.pending_len() >= event_loop.config.max_connections {
+            debug!(
+                "[tcp] #{} max connections reached, closing {}",
+                id, client_addr
+            );
+            event_loop.reject(
+                Protocol::Tcp,
+                addr,
+                &client_addr,
+                RejectReason::MaxConnections,
+            );
+            return Ok(true);
+        }
+        if let Some(ref memory) = self.memory {
+            if !memory.has_room(TCP_CONN_MEMORY) {
+                memory.record_refused();
+                debug!(
+                    "[tcp] #{} memory budget exhausted ({}/{} bytes), closing {}",
+                    id,
+                    memory.used(),
+                    memory.limit(),
+                    client_addr
+                );
+                event_loop.reject(Protocol::Tcp, addr, &client_addr, RejectReason::Memory);
+                return Ok(true);
+            }
+        }
+        // 单个连接建立失败不影响继续接受后面的连接
+        if let Err(e) = self.on_client(
+            event_loop,
+            id,
+            stream,
+            addr,
+            client_addr.clone(),
+            listen_addr.get_addr_family(),
+        ) {
+            warn!(
+                "[tcp] #{} failed to set up connection from {}: {}",
+                id, client_addr, e
+            );
+        }
+        Ok(true)
+    }
 
-        if tcp_manager.len() >= event_loop.config.max_connections {
-            warn!("[tcp] max connections reached, closing {}", client_addr);
-            return Ok(());
+    /// 接管继承的已连接客户端 socket (inetd 模式)
+    ///
+    /// 对端不是 IP 地址 (如 socketpair) 时客户端显示为 `stdin`
+    pub(crate) fn on_inherited(
+        &self,
+        event_loop: &EventLoop,
+        fd: RawFd,
+    ) -> Result<(), std::io::Error> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getpeername(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
         }
+        let peer = Address::from_raw_sockaddr(&storage as *const _ as *const _, len).ok();
+        let (addr, client_addr) = match peer {
+            Some(ref peer) if peer.is_ip() => (
+                peer.to_sockaddr(),
+                crate::log::client_addr(peer.to_sockaddr()),
+            ),
+            Some(ref peer) => (peer.to_sockaddr(), peer.to_string()),
+            None => (
+                SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0)),
+                "stdin".to_string(),
+            ),
+        };
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        self.on_client(
+            event_loop,
+            next_conn_id(),
+            stream,
+            addr,
+            client_addr,
+            libc::c_int::from(storage.ss_family),
+        )
+    }
+
+    /// 等待开头数据的客户端连接数 (尚未计入连接管理器)
+    pub(crate) fn pending_len(&self) -> usize {
+        self.peek_pending.lock().recover().len()
+    }
 
+    /// 为新客户端连接选择后端 (或等待 ClientHello)，`family` 为客户端 socket 的地址族
+    fn on_client(
+        &self,
+        event_loop: &EventLoop,
+        id: u64,
+        stream: TcpStream,
+        addr: SocketAddr,
+        client_addr: String,
+        family: libc::c_int,
+    ) -> Result<(), std::io::Error> {
         let fd = stream.as_raw_fd();
-        self.configure_socket(fd)?;
+        self.configure_socket(fd, family)?;
 
-        let remote_addr_for_connect = self.get_remote_addr_for_connect();
-        let remote_fd = unsafe {
-            let fd = libc::socket(self.get_remote_addr_family(), libc::SOCK_STREAM, 0);
-            if fd < 0 {
-                warn!("[tcp] create remote socket failed");
-                drop(stream);
+        // SNI 路由、协议嗅探和 SOCKS5 服务端：先等待开头数据，选出后端后再连接
+        if self.peeks() {
+            return self.defer_for_peek(event_loop, id, stream, addr, client_addr);
+        }
+
+        let backend = match self.backends.pick() {
+            Some(backend) => backend,
+            None => {
+                warn!("[tcp] #{} no remote address, closing {}", id, client_addr);
                 return Ok(());
             }
-            let _ = self.set_bind_to_device(fd);
-            self.configure_socket(fd).ok();
-            fd
         };
+        self.connect_backend(
+            event_loop,
+            id,
+            ClientSocket::New(stream),
+            addr,
+            client_addr,
+            backend,
+        )
+    }
 
-        let sockaddr = remote_addr_for_connect.to_sockaddr_storage();
-        let ret = unsafe {
-            libc::connect(
-                remote_fd,
-                &sockaddr as *const _ as *const libc::sockaddr,
-                remote_addr_for_connect.get_len() as libc::socklen_t,
-            )
-        };
-        let remote_connecting =
-            ret != 0 && unsafe { *libc::__errno_location() } == libc::EINPROGRESS;
+    /// fd 耗尽时释放预留 fd，接受并立即关闭一个待处理连接，再重新占住预留 fd
+    ///
+    /// 否则待处理连接一直留在 backlog 中，客户端只能等到超时
+    fn shed_connection(&self, listener: &TcpListener) {
+        let mut reserve = self.reserve_fd.lock().recover();
+        drop(reserve.take());
+        // 客户端地址无法解析 (Unix 域 socket) 时 accept 返回错误，已接受的 socket 同样会被关闭
+        drop(listener.accept());
+        *reserve = self
+            .reserve_template
+            .as_ref()
+            .and_then(|f| f.try_clone().ok());
+        warn!(
+            "[tcp] too many open files, dropped a pending connection and paused accepting for {}ms",
+            ACCEPT_PAUSE.as_millis()
+        );
+    }
 
-        let now = crate::log::get_current_time();
-        let fd_manager = &event_loop.fd_manager;
-        let local_fd64 = fd_manager.create(fd, now);
-        let remote_fd64 = fd_manager.create(remote_fd, now);
+    /// 接受一个客户端连接，返回 socket、客户端地址和日志中显示的客户端名称
+    ///
+    /// Unix 域 socket 和 vsock 的客户端没有 IP 地址，mio 无法解析，改为直接调用 accept4，
+    /// 客户端地址返回 `0.0.0.0:0` 作为占位；Unix 域 socket 客户端显示为监听路径
+    fn accept(
+        listen_addr: &Address,
+        listener: &TcpListener,
+    ) -> Result<(TcpStream, SocketAddr, String), std::io::Error> {
+        if listen_addr.is_ip() {
+            let (stream, addr) = listener.accept()?;
+            return Ok((stream, addr, crate::log::client_addr(addr)));
+        }
+        #[cfg(unix)]
+        {
+            let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            let fd = unsafe {
+                libc::accept4(
+                    listener.as_raw_fd(),
+                    &mut storage as *mut _ as *mut libc::sockaddr,
+                    &mut len,
+                    libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stream = unsafe { TcpStream::from_raw_fd(fd) };
+            let client_addr = Address::from_raw_sockaddr(&storage as *const _ as *const _, len)
+                .unwrap_or_else(|_| listen_addr.clone());
+            Ok((stream, listen_addr.to_sockaddr(), client_addr.to_string()))
+        }
+        // Windows 上只有 IP 地址
+        #[cfg(windows)]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot accept on {}", listen_addr),
+        ))
+    }
 
-        let mut tm = token_manager.write().expect("poisoned");
-        let local_token = tm.generate_token(local_fd64);
-        poll.registry()
-            .register(&mut stream, local_token, Interest::READABLE)?;
-        let _ = stream.into_raw_fd();
+    /// 连接后端并创建连接
+    fn connect_backend(
+        &self,
+        event_loop: &EventLoop,
+        id: u64,
+        client: ClientSocket,
+        addr: SocketAddr,
+        client_addr: String,
+        backend: Arc<Backend>,
+    ) -> Result<(), std::io::Error> {
+        let tcp_manager = &event_loop.tcp_manager;
+        let token_manager = &event_loop.token_manager;
+        let fd_manager = &event_loop.fd_manager;
+        // SOCKS5 客户端：连接建立后再应答 CONNECT，请求之后已读出的数据随后发给目标
+        let (client, early) = match client {
+            ClientSocket::Socks(fd64, early) => (ClientSocket::Registered(fd64), Some(early)),
+            client => (client, None),
+        };
+        let fd = match client {
+            ClientSocket::New(ref stream) | ClientSocket::Aux(ref stream) => stream.as_raw_fd(),
+            ClientSocket::Registered(fd64) | ClientSocket::Socks(fd64, _) => {
+                match fd_manager.to_fd(fd64) {
+                    Some(fd) => fd,
+                    None => return Ok(()),
+                }
+            }
+        };
+        if let Some(ref breaker) = self.breaker {
+            if !backend.breaker_allows(breaker, crate::log::get_monotonic_time()) {
+                debug!(
+                    "[tcp] #{} circuit breaker for {} is open, closing {}",
+                    id, backend.addr, client_addr
+                );
+                if early.is_some() {
+                    send_segments(fd, &[&socks5::reply(socks5::REP_CONNECTION_REFUSED, None)]);
+                }
+                Self::abort_local(event_loop, client);
+                return Ok(());
+            }
+        }
+        let remote_addr_for_connect = self.get_remote_addr_for_connect(&backend.addr);
+        let Some((remote_fd, connect_err, fallback)) =
+            self.open_remote(id, &backend, addr, &client_addr)
+        else {
+            if early.is_some() {
+                send_segments(fd, &[&socks5::reply(socks5::REP_GENERAL_FAILURE, None)]);
+            }
+            Self::abort_local(event_loop, client);
+            return Ok(());
+        };
+        let in_progress = connect_err == libc::EINPROGRESS;
+        // 立即失败且允许重试时按连接中处理，创建连接后安排重试
+        let failed = connect_err != 0 && !in_progress;
+        // 经上游代理时即使立即连接成功也要先完成握手
+        let remote_connecting = in_progress
+            || (connect_err == 0 && self.upstream.is_some())
+            || (failed && self.connect_retries > 0);
+
+        let now = crate::log::get_monotonic_time();
+        let deferred = !matches!(client, ClientSocket::New(_) | ClientSocket::Aux(_));
+        let filtered = !matches!(client, ClientSocket::Aux(_));
+        let remote_stream = unsafe { TcpStream::from_raw_fd(remote_fd) };
+        let remote_fd64 = fd_manager.insert(Source::Tcp(remote_stream), now);
+
+        let mut tm = token_manager.write().recover();
+        let local_fd64 = match client {
+            ClientSocket::Registered(fd64) | ClientSocket::Socks(fd64, _) => fd64,
+            ClientSocket::New(stream) | ClientSocket::Aux(stream) => {
+                let fd64 = fd_manager.insert(Source::Tcp(stream), now);
+                let local_token = tm.generate_token(fd64);
+                event_loop.register_source(fd64, local_token, Interest::READABLE)?;
+                fd64
+            }
+        };
 
         let remote_token = tm.generate_token(remote_fd64);
-        let mut remote_stream = unsafe { TcpStream::from_raw_fd(remote_fd) };
-        poll.registry().register(
-            &mut remote_stream,
+        event_loop.register_source(
+            remote_fd64,
             remote_token,
             if remote_connecting {
                 Interest::READABLE | Interest::WRITABLE
@@ -209,9 +888,10 @@ impl TcpHandler {
                 Interest::READABLE
             },
         )?;
-        let _ = remote_stream.into_raw_fd();
+        drop(tm);
 
-        tcp_manager.new_connection(
+        let conn = tcp_manager.new_connection(
+            id,
             local_fd64,
             remote_fd64,
             client_addr.clone(),
@@ -219,31 +899,1123 @@ impl TcpHandler {
             self.socket_buf_size,
             remote_connecting,
         );
-        TrafficStats::global().inc_tcp_connections();
+        let gave_up = {
+            let mut conn = conn.write().recover();
+            conn.peer = Some(addr);
+            if let Some(ref limiter) = self.rate_limiter {
+                conn.rate_bucket = limiter.new_conn_bucket();
+            }
+            backend.stats.inc_tcp_connections();
+            conn.backend = Some(Arc::clone(&backend));
+            conn.obfs = ObfsStreams::new(
+                self.decrypt_in.as_ref(),
+                self.encrypt_out.as_ref(),
+                self.decompress_in,
+                self.compress_out,
+            )
+            .map(Box::new);
+            if filtered {
+                conn.filters = self
+                    .middlewares
+                    .iter()
+                    .filter_map(|middleware| middleware.tcp_filter(&backend.addr))
+                    .collect();
+            }
+            conn.socks = self
+                .upstream
+                .as_ref()
+                .map(|upstream| upstream.connect(remote_addr_for_connect.clone()));
+            if let Some(ref early) = early {
+                conn.socks_reply = true;
+                self.stash_early(event_loop, &mut conn, early);
+            }
+            if connect_err == 0 && !remote_connecting {
+                Self::record_connect_latency(event_loop, &conn);
+                self.record_connect_success(event_loop, &conn);
+                Self::reply_socks(event_loop, &mut conn);
+            }
+            if failed {
+                self.record_connect_failure(event_loop, &conn);
+            }
+            if let Some(addr) = fallback.filter(|_| remote_connecting && !failed) {
+                self.arm_fallback(event_loop, &mut conn, addr);
+            }
+            if let Some(ref addr) = self.mirror {
+                self.open_mirror(event_loop, &mut conn, addr);
+            }
+            // 后端已熔断时不再重试
+            failed && remote_connecting && !self.schedule_retry(event_loop, &mut conn, connect_err)
+        };
+        event_loop.stats.inc_tcp_connections();
+        event_loop.observers.notify(|o| o.on_accept(&client_addr));
+        if gave_up {
+            let conn = conn.read().recover();
+            Self::close_conn(
+                event_loop,
+                &conn,
+                remote_fd64,
+                local_fd64,
+                CloseReason::ConnectFailed,
+            );
+            return Ok(());
+        }
+        if connect_err == 0 && !remote_connecting {
+            event_loop
+                .observers
+                .notify(|o| o.on_connect_established(&client_addr, &remote_addr_for_connect));
+        }
 
         info!(
-            "[tcp] new connection from {}, fd1={}, fd2={}, tcp connections={}",
+            "[tcp] #{} new connection from {}{} to {}, fd1={}, fd2={}, tcp connections={}",
+            id,
             client_addr,
+            event_loop.geo_tag(addr),
+            backend.addr,
             fd,
             remote_fd,
             tcp_manager.len()
         );
+
+        // ClientHello 已在客户端 socket 中，后端立即连接成功时不会再收到可读事件
+        if deferred && !remote_connecting {
+            let tok = token_manager.read().recover().get_token(&local_fd64);
+            if let Some(tok) = tok {
+                return self.on_read(event_loop, tok, local_fd64);
+            }
+        }
         Ok(())
     }
 
-    pub fn on_read(
-        &self,
-        event_loop: &EventLoop,
-        _token: Token,
-        fd64: Fd64,
-    ) -> Result<(), std::io::Error> {
-        debug!("[tcp] on_read ENTRY fd64={:?}", fd64);
-        let fd_manager = &event_loop.fd_manager;
-        let tcp_manager = &event_loop.tcp_manager;
+    /// SOCKS5 客户端在 CONNECT 请求之后发来的数据，放入发往后端的缓冲区
+    fn stash_early(&self, event_loop: &EventLoop, conn: &mut TcpConnection, early: &[u8]) {
+        if early.is_empty() {
+            return;
+        }
+        event_loop.stats.add_tcp_received(early.len());
+        self.mirror_data(event_loop, conn, early);
+        let mut buf = self.buffers.get();
+        buf[..early.len()].copy_from_slice(early);
+        // 服务端模式下不解密、不解压，加密和压缩不会失败
+        let len = match conn.obfs {
+            Some(ref mut obfs) => obfs.apply(true, &mut buf, early.len()).unwrap_or(0),
+            None => early.len(),
+        };
+        if len > 0 {
+            conn.remote.stash(buf, 0, len);
+        }
+    }
 
-        if !fd_manager.exist(fd64) {
-            debug!("[tcp] on_read: fd64 does not exist");
-            return Ok(());
+    /// 作为 SOCKS5 服务端时，后端连接建立后应答客户端的 CONNECT 请求
+    fn reply_socks(event_loop: &EventLoop, conn: &mut TcpConnection) {
+        if !std::mem::take(&mut conn.socks_reply) {
+            return;
+        }
+        if let Some(fd) = event_loop.fd_manager.to_fd(conn.local.fd64) {
+            send_segments(fd, &[&socks5::reply(socks5::REP_SUCCEEDED, None)]);
+        }
+    }
+
+    /// 创建远程 socket 并发起非阻塞连接 (经上游代理时连接代理)，透明代理时以客户端地址 `addr` 为源地址
+    ///
+    /// 返回 fd、connect 的错误码 (立即成功时为 0) 和稍后与之竞速的 Happy Eyeballs 备用地址；
+    /// 首选地址立即失败 (如没有 IPv6 路由) 时直接改连备用地址。创建或绑定 socket 失败时返回 None
+    fn open_remote(
+        &self,
+        id: u64,
+        backend: &Backend,
+        addr: SocketAddr,
+        client_addr: &str,
+    ) -> Option<(RawFd, libc::c_int, Option<Address>)> {
+        // 经上游代理时先连接代理，握手中再请求连接后端
+        let (connect_addr, remote_family) = match self.upstream {
+            Some(ref upstream) => (upstream.proxy.clone(), upstream.proxy.get_addr_family()),
+            None => (
+                self.get_remote_addr_for_connect(&backend.addr),
+                self.get_remote_addr_family(&backend.addr),
+            ),
+        };
+        let remote_fd = match crate::new_socket(remote_family, libc::SOCK_STREAM, 0) {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("[tcp] #{} create remote socket failed: {}", id, e);
+                return None;
+            }
+        };
+        let _ = self.set_bind_to_device(remote_fd);
+        self.configure_socket(remote_fd, remote_family).ok();
+        self.mark_socket(remote_fd);
+        if self.transparent {
+            if let Err(e) = crate::bind_transparent(remote_fd, remote_family, addr) {
+                warn!(
+                    "[tcp] #{} bind remote socket to client address {} failed: {}, closing",
+                    id, client_addr, e
+                );
+                unsafe { libc::close(remote_fd) };
+                return None;
+            }
+        } else if let Err(e) = self.bind_source(remote_fd, remote_family) {
+            warn!(
+                "[tcp] #{} bind remote socket to source address/port failed: {}, closing",
+                id, e
+            );
+            unsafe { libc::close(remote_fd) };
+            return None;
+        }
+
+        let sockaddr = connect_addr.to_sockaddr_storage();
+        let ret = unsafe {
+            libc::connect(
+                remote_fd,
+                &sockaddr as *const _ as *const libc::sockaddr,
+                connect_addr.get_len() as libc::socklen_t,
+            )
+        };
+        let connect_err = if ret == 0 { 0 } else { crate::get_sock_errno() };
+        // Happy Eyeballs 只用于直连 (经代理时由代理解析，透明代理的源地址族固定)
+        let mut fallback = backend
+            .fallback
+            .clone()
+            .filter(|_| self.upstream.is_none() && !self.transparent);
+        if connect_err != 0 && connect_err != libc::EINPROGRESS {
+            if let Some(addr) = fallback.take() {
+                match self.connect_nonblocking(&addr) {
+                    Ok(fd) => {
+                        debug!(
+                            "[tcp] #{} connect to {} failed immediately, trying {}",
+                            id, backend.addr, addr
+                        );
+                        unsafe { libc::close(remote_fd) };
+                        return Some((fd, libc::EINPROGRESS, None));
+                    }
+                    Err(e) => debug!("[tcp] #{} connect to {} failed: {}", id, addr, e),
+                }
+            }
+        }
+        Some((remote_fd, connect_err, fallback))
+    }
+
+    /// 首选地址连接中时记下备用地址，`HAPPY_EYEBALLS_DELAY` 后仍未连接成功再开始竞速
+    fn arm_fallback(&self, event_loop: &EventLoop, conn: &mut TcpConnection, addr: Address) {
+        conn.fallback = Some(Fallback::Pending(addr));
+        let due = Arc::clone(&self.fallback_due);
+        let local_fd64 = conn.local.fd64;
+        event_loop
+            .timer
+            .register_once(HAPPY_EYEBALLS_DELAY, move || {
+                due.lock().recover().push(local_fd64);
+            });
+    }
+
+    /// 记录一次后端连接失败，连续失败达到阈值时打开熔断器
+    fn record_connect_failure(&self, event_loop: &EventLoop, conn: &TcpConnection) {
+        let (Some(ref breaker), Some(ref backend)) = (self.breaker, &conn.backend) else {
+            return;
+        };
+        if backend.record_connect_failure(breaker, crate::log::get_monotonic_time()) {
+            warn!(
+                "[tcp] circuit breaker for {} opened after {} consecutive connect failures, rejecting new connections for {}s",
+                backend.addr,
+                breaker.threshold,
+                breaker.cooldown.as_secs()
+            );
+            event_loop
+                .observers
+                .notify(|o| o.on_backend_down(&backend.addr, "circuit breaker opened"));
+        }
+    }
+
+    /// 记录一次后端连接成功，关闭已打开的熔断器
+    fn record_connect_success(&self, event_loop: &EventLoop, conn: &TcpConnection) {
+        let (Some(_), Some(ref backend)) = (self.breaker, &conn.backend) else {
+            return;
+        };
+        if backend.record_connect_success() {
+            info!("[tcp] circuit breaker for {} closed", backend.addr);
+            event_loop
+                .observers
+                .notify(|o| o.on_backend_up(&backend.addr));
+        }
+    }
+
+    /// 连接后端失败时关闭远程 socket 并按退避时间安排重试，重试次数已用完或后端已熔断时返回 false
+    fn schedule_retry(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        err: libc::c_int,
+    ) -> bool {
+        let now = crate::log::get_monotonic_time();
+        if conn.connect_attempts >= self.connect_retries
+            || conn
+                .backend
+                .as_ref()
+                .is_some_and(|backend| backend.breaker_open(now))
+        {
+            return false;
+        }
+        conn.connect_attempts += 1;
+        let delay = retry_backoff(conn.connect_attempts);
+        event_loop.release_fd(conn.remote.fd64);
+        if let Some(fallback) = conn.fallback_fd64() {
+            event_loop.release_fd(fallback);
+        }
+        conn.fallback = None;
+        if let Some(ref backend) = conn.backend {
+            warn!(
+                "[tcp] #{} connect to {} failed: {}, retry {}/{} in {}ms",
+                conn.id,
+                backend.addr,
+                io::Error::from_raw_os_error(err),
+                conn.connect_attempts,
+                self.connect_retries,
+                delay.as_millis()
+            );
+        }
+        let due = Arc::clone(&self.retry_due);
+        let local_fd64 = conn.local.fd64;
+        event_loop.timer.register_once(delay, move || {
+            due.lock().recover().push(local_fd64);
+        });
+        true
+    }
+
+    /// 退避时间已到的连接重新连接后端
+    pub(crate) fn start_retries(&self, event_loop: &EventLoop) {
+        let due = std::mem::take(&mut *self.retry_due.lock().recover());
+        for local_fd64 in due {
+            let Some(conn_arc) = event_loop.tcp_manager.get_connection(&local_fd64) else {
+                continue;
+            };
+            let mut conn = conn_arc.write().recover();
+            let Some(backend) = conn.backend.clone() else {
+                continue;
+            };
+            let client = event_loop
+                .fd_manager
+                .to_fd(local_fd64)
+                .and_then(peer_addr)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+            let opened = self.open_remote(conn.id, &backend, client, &conn.addr_s);
+            let connect_err = match opened {
+                Some((fd, err, fallback)) if err == 0 || err == libc::EINPROGRESS => {
+                    match self.attach_remote(event_loop, &mut conn, fd) {
+                        Ok(()) => {
+                            conn.socks = self.upstream.as_ref().map(|upstream| {
+                                upstream.connect(self.get_remote_addr_for_connect(&backend.addr))
+                            });
+                            if let Some(addr) = fallback {
+                                self.arm_fallback(event_loop, &mut conn, addr);
+                            }
+                            continue;
+                        }
+                        Err(e) => e.raw_os_error().unwrap_or(libc::EIO),
+                    }
+                }
+                Some((fd, err, _)) => {
+                    unsafe { libc::close(fd) };
+                    err
+                }
+                None => libc::EIO,
+            };
+            self.record_connect_failure(event_loop, &conn);
+            if !self.schedule_retry(event_loop, &mut conn, connect_err) {
+                let (remote_fd64, local_fd64) = (conn.remote.fd64, conn.local.fd64);
+                Self::close_conn(
+                    event_loop,
+                    &conn,
+                    remote_fd64,
+                    local_fd64,
+                    CloseReason::ConnectFailed,
+                );
+            }
+        }
+    }
+
+    /// 把重新连接的 socket 设为连接的远程端，等待连接完成
+    fn attach_remote(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        fd: RawFd,
+    ) -> io::Result<()> {
+        let now = crate::log::get_monotonic_time();
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        let fd64 = event_loop.fd_manager.insert(Source::Tcp(stream), now);
+        let token = event_loop
+            .token_manager
+            .write()
+            .recover()
+            .generate_token(fd64);
+        conn.remote.fd64 = fd64;
+        event_loop.register_source(fd64, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    /// 创建连接 `addr` (备用地址或镜像目标) 的 socket 并发起非阻塞连接
+    fn connect_nonblocking(&self, addr: &Address) -> io::Result<RawFd> {
+        let connect_addr = self.get_remote_addr_for_connect(addr);
+        let family = self.get_remote_addr_family(addr);
+        let fd = crate::new_socket(family, libc::SOCK_STREAM, 0)?;
+        let _ = self.set_bind_to_device(fd);
+        self.configure_socket(fd, family).ok();
+        self.mark_socket(fd);
+        if let Err(e) = self.bind_source(fd, family) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let sockaddr = connect_addr.to_sockaddr_storage();
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                &sockaddr as *const _ as *const libc::sockaddr,
+                connect_addr.get_len() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        }
+        Ok(fd)
+    }
+
+    /// 开始连接备用地址，与仍在连接的首选地址竞速，失败时放弃备用地址
+    ///
+    /// 备用 socket 只关注可写事件，连接结果由 `handle_connect_finish` 处理
+    fn race_fallback(&self, event_loop: &EventLoop, conn: &mut TcpConnection, addr: &Address) {
+        conn.fallback = None;
+        let fd = match self.connect_nonblocking(addr) {
+            Ok(fd) => fd,
+            Err(e) => {
+                debug!(
+                    "[tcp] #{} connect to fallback {} failed: {}",
+                    conn.id, addr, e
+                );
+                return;
+            }
+        };
+        let now = crate::log::get_monotonic_time();
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        let fd64 = event_loop.fd_manager.insert(Source::Tcp(stream), now);
+        let token = event_loop
+            .token_manager
+            .write()
+            .recover()
+            .generate_token(fd64);
+        if let Err(e) = event_loop.register_source(fd64, token, Interest::WRITABLE) {
+            debug!("[tcp] #{} register fallback socket failed: {}", conn.id, e);
+            event_loop.release_fd(fd64);
+            return;
+        }
+        debug!("[tcp] #{} racing fallback {}, fd={}", conn.id, addr, fd);
+        conn.fallback = Some(Fallback::Connecting(fd64));
+    }
+
+    /// 为连接打开到镜像目标的连接，失败时该连接不做镜像
+    fn open_mirror(&self, event_loop: &EventLoop, conn: &mut TcpConnection, addr: &Address) {
+        let fd = match self.connect_nonblocking(addr) {
+            Ok(fd) => fd,
+            Err(e) => {
+                debug!(
+                    "[tcp] #{} connect to mirror {} failed: {}",
+                    conn.id, addr, e
+                );
+                return;
+            }
+        };
+        let now = crate::log::get_monotonic_time();
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        let fd64 = event_loop.fd_manager.insert(Source::Tcp(stream), now);
+        let token = event_loop
+            .token_manager
+            .write()
+            .recover()
+            .generate_token(fd64);
+        if let Err(e) =
+            event_loop.register_source(fd64, token, Interest::READABLE | Interest::WRITABLE)
+        {
+            debug!("[tcp] #{} register mirror socket failed: {}", conn.id, e);
+            event_loop.release_fd(fd64);
+            return;
+        }
+        conn.mirror = Some(MirrorStream::new(fd64));
+    }
+
+    /// 停止镜像该连接，关闭镜像连接
+    fn drop_mirror(event_loop: &EventLoop, conn: &mut TcpConnection, why: &str) {
+        if let Some(mirror) = conn.mirror.take() {
+            warn!(
+                "[tcp] #{} stop mirroring ({}), {} bytes mirrored",
+                conn.id, why, mirror.sent
+            );
+            event_loop.release_fd(mirror.fd64);
+        }
+    }
+
+    /// 把客户端发来的数据复制到镜像流，镜像连接已建立时立即尝试发送
+    fn mirror_data(&self, event_loop: &EventLoop, conn: &mut TcpConnection, data: &[u8]) {
+        let Some(ref mut mirror) = conn.mirror else {
+            return;
+        };
+        if !mirror.push(data) {
+            Self::drop_mirror(event_loop, conn, "backlog limit reached");
+            return;
+        }
+        if !mirror.connected {
+            return;
+        }
+        let Some(fd) = event_loop.fd_manager.to_fd(mirror.fd64) else {
+            return;
+        };
+        let fd64 = mirror.fd64;
+        match mirror.flush(self.io.as_ref(), fd) {
+            Ok(()) if mirror.pending() > 0 => Self::set_write_interest(event_loop, fd64, true),
+            Ok(()) => {}
+            Err(e) => Self::drop_mirror(event_loop, conn, &e.to_string()),
+        }
+    }
+
+    /// 镜像连接的事件：回应读取后丢弃，可写时发送积压的数据
+    fn on_mirror_event(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        fd64: Fd64,
+        writable: bool,
+    ) {
+        let Some(fd) = event_loop.fd_manager.to_fd(fd64) else {
+            return;
+        };
+        if !writable {
+            let mut buf = self.buffers.get();
+            loop {
+                match self.io.recv(fd, &mut buf) {
+                    Ok(0) => return Self::drop_mirror(event_loop, conn, "closed by peer"),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => return Self::drop_mirror(event_loop, conn, &e.to_string()),
+                }
+            }
+        }
+        let Some(ref mut mirror) = conn.mirror else {
+            return;
+        };
+        mirror.connected = true;
+        match mirror.flush(self.io.as_ref(), fd) {
+            Ok(()) => {
+                let backlog = mirror.pending() > 0;
+                Self::set_write_interest(event_loop, fd64, backlog);
+            }
+            Err(e) => Self::drop_mirror(event_loop, conn, &e.to_string()),
+        }
+    }
+
+    /// 首选地址领先时间已到仍在连接的连接开始连接备用地址
+    pub(crate) fn start_fallbacks(&self, event_loop: &EventLoop) {
+        let due = std::mem::take(&mut *self.fallback_due.lock().recover());
+        for local_fd64 in due {
+            let Some(conn_arc) = event_loop.tcp_manager.get_connection(&local_fd64) else {
+                continue;
+            };
+            let mut conn = conn_arc.write().recover();
+            if !conn.remote_connecting {
+                continue;
+            }
+            if let Some(Fallback::Pending(addr)) = conn.fallback.clone() {
+                self.race_fallback(event_loop, &mut conn, &addr);
+            }
+        }
+    }
+
+    /// 处理 Happy Eyeballs 中一次连接尝试的结果：先连接成功的 socket 成为远程端，另一个关闭；
+    /// 一个地址失败而另一个仍有机会时丢弃失败的 socket。
+    /// 返回是否继续按 `fd64` 完成连接 (成功，或两个地址都已失败)
+    fn settle_race(
+        &self,
+        event_loop: &EventLoop,
+        conn_arc: &RwLock<TcpConnection>,
+        fd64: Fd64,
+        err: libc::c_int,
+    ) -> bool {
+        let mut conn = conn_arc.write().recover();
+        let Some(fallback) = conn.fallback.take() else {
+            return true;
+        };
+        if err == 0 {
+            if let Fallback::Connecting(other) = fallback {
+                if other == fd64 {
+                    // 备用地址胜出
+                    let primary = std::mem::replace(&mut conn.remote.fd64, fd64);
+                    event_loop.release_fd(primary);
+                    if let Some(ref backend) = conn.backend {
+                        info!(
+                            "[tcp] #{} connected to fallback {} before {}",
+                            conn.id,
+                            backend.fallback.as_ref().unwrap_or(&backend.addr),
+                            backend.addr
+                        );
+                    }
+                } else {
+                    event_loop.release_fd(other);
+                }
+            }
+            return true;
+        }
+        match fallback {
+            // 备用地址失败，继续等待首选地址
+            Fallback::Connecting(other) if other == fd64 => {
+                event_loop.release_fd(fd64);
+                false
+            }
+            // 首选地址失败，改用正在连接的备用地址
+            Fallback::Connecting(other) => {
+                event_loop.release_fd(std::mem::replace(&mut conn.remote.fd64, other));
+                false
+            }
+            // 首选地址在领先时间内失败，立即连接备用地址
+            Fallback::Pending(addr) => {
+                self.race_fallback(event_loop, &mut conn, &addr);
+                match conn.fallback.take() {
+                    Some(Fallback::Connecting(other)) => {
+                        event_loop.release_fd(std::mem::replace(&mut conn.remote.fd64, other));
+                        false
+                    }
+                    _ => true,
+                }
+            }
+        }
+    }
+
+    /// 注册客户端 socket，等待开头数据到达后再选择后端
+    fn defer_for_peek(
+        &self,
+        event_loop: &EventLoop,
+        id: u64,
+        stream: TcpStream,
+        addr: SocketAddr,
+        client_addr: String,
+    ) -> Result<(), std::io::Error> {
+        let now = crate::log::get_monotonic_time();
+        let local_fd64 = event_loop.fd_manager.insert(Source::Tcp(stream), now);
+        {
+            let mut tm = event_loop.token_manager.write().recover();
+            let local_token = tm.generate_token(local_fd64);
+            if let Err(e) = event_loop.register_source(local_fd64, local_token, Interest::READABLE)
+            {
+                tm.remove(&local_fd64);
+                event_loop.fd_manager.close(local_fd64);
+                return Err(e);
+            }
+        }
+        debug!("[tcp] #{} waiting for first bytes from {}", id, client_addr);
+        self.peek_pending.lock().recover().insert(
+            local_fd64,
+            PeekPending {
+                id,
+                addr,
+                client_addr,
+                deadline: Some(crate::clock::now() + SNI_PEEK_TIMEOUT),
+                socks: self.socks_server.as_ref().map(|server| server.accept()),
+            },
+        );
+        Ok(())
+    }
+
+    /// 关闭尚未建立转发的客户端 socket，已注册的同时注销
+    fn abort_local(event_loop: &EventLoop, client: ClientSocket) {
+        if let ClientSocket::Registered(fd64) | ClientSocket::Socks(fd64, _) = client {
+            event_loop.deregister_source(fd64);
+            event_loop.token_manager.write().recover().remove(&fd64);
+            event_loop.fd_manager.close(fd64);
+        }
+    }
+
+    /// 窥探等待中的客户端数据：不符合 `--expect-protocol` 的连接直接关闭，
+    /// 否则 ClientHello 完整 (或超时) 后按 SNI 选择后端并连接
+    ///
+    /// 数据只用 MSG_PEEK 读取，连接建立后由正常的转发流程发给后端
+    fn route_peeked(
+        &self,
+        event_loop: &EventLoop,
+        fd64: Fd64,
+        expired: bool,
+    ) -> Result<(), std::io::Error> {
+        if self.socks_server.is_some() {
+            return self.accept_socks(event_loop, fd64, expired);
+        }
+        let fd = match event_loop.fd_manager.to_fd(fd64) {
+            Some(f) => f,
+            None => {
+                self.peek_pending.lock().recover().remove(&fd64);
+                return Ok(());
+            }
+        };
+        let mut buf = vec![0u8; MAX_CLIENT_HELLO_LEN];
+        let len = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_PEEK,
+            )
+        };
+        if len == 0 || (len < 0 && io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock) {
+            // 客户端在发送数据前关闭连接或出错
+            if let Some(pending) = self.peek_pending.lock().recover().remove(&fd64) {
+                debug!(
+                    "[tcp] #{} {} closed before sending data",
+                    pending.id, pending.client_addr
+                );
+            }
+            Self::abort_local(event_loop, ClientSocket::Registered(fd64));
+            return Ok(());
+        }
+        let data = &buf[..len.max(0) as usize];
+        match self.expect.sniff(data) {
+            Sniff::Match => {}
+            Sniff::Incomplete if !expired => return Ok(()),
+            _ => {
+                let pending = self.peek_pending.lock().recover().remove(&fd64);
+                if let Some(pending) = pending {
+                    debug!(
+                        "[tcp] #{} {} does not speak {}, closing",
+                        pending.id, pending.client_addr, self.expect
+                    );
+                    event_loop.reject(
+                        Protocol::Tcp,
+                        pending.addr,
+                        &pending.client_addr,
+                        RejectReason::Protocol,
+                    );
+                }
+                Self::abort_local(event_loop, ClientSocket::Registered(fd64));
+                return Ok(());
+            }
+        }
+        let host = match self.sni_router.as_ref().map(|_| parse_client_hello(data)) {
+            Some(SniResult::Incomplete) if !expired && data.len() < buf.len() => return Ok(()),
+            Some(SniResult::Found(host)) => Some(host),
+            _ => None,
+        };
+        let pending = match self.peek_pending.lock().recover().remove(&fd64) {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let routed = host.as_deref().and_then(|host| {
+            self.sni_router
+                .as_ref()
+                .and_then(|router| router.route(host))
+        });
+        let backend = match routed.unwrap_or(&self.backends).pick() {
+            Some(backend) => backend,
+            None => {
+                warn!(
+                    "[tcp] #{} no remote address, closing {}",
+                    pending.id, pending.client_addr
+                );
+                Self::abort_local(event_loop, ClientSocket::Registered(fd64));
+                return Ok(());
+            }
+        };
+        if self.sni_router.is_some() {
+            debug!(
+                "[tcp] #{} SNI {} from {} routed to {}",
+                pending.id,
+                host.as_deref().unwrap_or("-"),
+                pending.client_addr,
+                backend.addr
+            );
+        }
+        self.connect_backend(
+            event_loop,
+            pending.id,
+            ClientSocket::Registered(fd64),
+            pending.addr,
+            pending.client_addr,
+            backend,
+        )
+    }
+
+    /// 推进 SOCKS5 服务端握手 (`--socks5-listen`)：CONNECT 的目标作为后端连接，
+    /// UDP ASSOCIATE 应答中继地址后保留控制连接，控制连接关闭时取消关联
+    ///
+    /// 与窥探不同，握手消息直接读出，CONNECT 请求之后已读出的数据在连接建立后发给目标
+    fn accept_socks(
+        &self,
+        event_loop: &EventLoop,
+        fd64: Fd64,
+        expired: bool,
+    ) -> Result<(), std::io::Error> {
+        let Some(ref server) = self.socks_server else {
+            return Ok(());
+        };
+        let Some(fd) = event_loop.fd_manager.to_fd(fd64) else {
+            self.close_socks(event_loop, fd64, &[], "socket closed");
+            return Ok(());
+        };
+        if expired {
+            self.close_socks(event_loop, fd64, &[], "handshake timed out");
+            return Ok(());
+        }
+        let mut buf = [0u8; 512];
+        loop {
+            let len =
+                unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock {
+                return Ok(());
+            }
+            if len <= 0 {
+                self.close_socks(event_loop, fd64, &[], "closed by client");
+                return Ok(());
+            }
+            // 客户端可能不等应答就发送后续消息，每次应答后继续处理已收到的数据
+            let mut data = &buf[..len as usize];
+            loop {
+                let accept = {
+                    let mut pending = self.peek_pending.lock().recover();
+                    match pending.get_mut(&fd64).and_then(|p| p.socks.as_mut()) {
+                        Some(socks) => socks.on_data(std::mem::take(&mut data)),
+                        None => return Ok(()),
+                    }
+                };
+                match accept {
+                    Accept::Wait => break,
+                    // 握手应答很短，客户端等待应答时发送缓冲区不会满
+                    Accept::Reply(reply) => {
+                        send_segments(fd, &[&reply]);
+                    }
+                    Accept::Reject(reply, reason) => {
+                        let client = self
+                            .peek_pending
+                            .lock()
+                            .recover()
+                            .get(&fd64)
+                            .map(|pending| (pending.addr, pending.client_addr.clone()));
+                        if let Some((addr, client)) = client {
+                            event_loop.reject(
+                                Protocol::Tcp,
+                                addr,
+                                &client,
+                                RejectReason::Handshake,
+                            );
+                        }
+                        self.close_socks(event_loop, fd64, &reply, reason);
+                        return Ok(());
+                    }
+                    Accept::Associate => {
+                        if !event_loop.config.enable_udp {
+                            let reply = socks5::reply(socks5::REP_COMMAND_NOT_SUPPORTED, None);
+                            self.close_socks(event_loop, fd64, &reply, "UDP is not enabled");
+                            return Ok(());
+                        }
+                        let relay = Self::udp_relay_addr(event_loop, fd64);
+                        let mut pending = self.peek_pending.lock().recover();
+                        let Some(pending) = pending.get_mut(&fd64) else {
+                            return Ok(());
+                        };
+                        pending.deadline = None;
+                        server.associate(pending.addr.ip());
+                        debug!(
+                            "[tcp] #{} socks5 client {} associated, UDP relay {}",
+                            pending.id, pending.client_addr, relay
+                        );
+                        send_segments(fd, &[&socks5::reply(socks5::REP_SUCCEEDED, Some(relay))]);
+                    }
+                    Accept::Connect(target, early) => {
+                        let Some(pending) = self.peek_pending.lock().recover().remove(&fd64) else {
+                            return Ok(());
+                        };
+                        debug!(
+                            "[tcp] #{} socks5 client {} connecting to {}",
+                            pending.id, pending.client_addr, target
+                        );
+                        return self.connect_backend(
+                            event_loop,
+                            pending.id,
+                            ClientSocket::Socks(fd64, early),
+                            pending.addr,
+                            pending.client_addr,
+                            server.backend(target),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// 关闭握手中的 SOCKS5 客户端或 UDP ASSOCIATE 控制连接，`reply` 非空时先尽量发出
+    fn close_socks(&self, event_loop: &EventLoop, fd64: Fd64, reply: &[u8], reason: &str) {
+        if let Some(pending) = self.peek_pending.lock().recover().remove(&fd64) {
+            debug!(
+                "[tcp] #{} socks5 client {} closed: {}",
+                pending.id, pending.client_addr, reason
+            );
+            // 控制连接关闭后不再接受该客户端发往中继的数据包
+            if pending.deadline.is_none() {
+                if let Some(ref server) = self.socks_server {
+                    server.release(pending.addr.ip());
+                }
+            }
+        }
+        if !reply.is_empty() {
+            if let Some(fd) = event_loop.fd_manager.to_fd(fd64) {
+                send_segments(fd, &[reply]);
+            }
+        }
+        Self::abort_local(event_loop, ClientSocket::Registered(fd64));
+    }
+
+    /// UDP ASSOCIATE 应答的中继地址：UDP 监听地址，监听通配地址时使用客户端所连接的本机地址
+    fn udp_relay_addr(event_loop: &EventLoop, fd64: Fd64) -> SocketAddr {
+        let listen = event_loop.config.listen_addr.to_sockaddr();
+        if !listen.ip().is_unspecified() {
+            return listen;
+        }
+        let local = event_loop
+            .fd_manager
+            .with_source(fd64, |source| match source {
+                Source::Tcp(stream) => stream.local_addr().ok(),
+                _ => None,
+            })
+            .flatten();
+        match local {
+            Some(local) => SocketAddr::new(local.ip(), listen.port()),
+            None => listen,
+        }
+    }
+
+    /// 等待开头数据超时的客户端按未匹配处理 (要求协议时关闭)，由事件循环每轮调用
+    pub(crate) fn expire_peeks(&self, event_loop: &EventLoop) {
+        let expired: Vec<Fd64> = {
+            let pending = self.peek_pending.lock().recover();
+            if pending.is_empty() {
+                return;
+            }
+            let now = crate::clock::now();
+            pending
+                .iter()
+                .filter(|(_, p)| p.deadline.is_some_and(|deadline| deadline <= now))
+                .map(|(fd64, _)| *fd64)
+                .collect()
+        };
+        for fd64 in expired {
+            let _ = self.route_peeked(event_loop, fd64, true);
+        }
+    }
+
+    /// 依次交给连接的过滤器处理收到的数据，返回处理后的长度
+    fn filter(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        to_remote: bool,
+        buf: &mut [u8],
+        len: usize,
+    ) -> usize {
+        let mut filters = std::mem::take(&mut conn.filters);
+        let mut data = buf[..len].to_vec();
+        for filter in filters.iter_mut() {
+            let mut out = Vec::with_capacity(data.len());
+            let mut ctx = ConnContext {
+                handler: self,
+                event_loop,
+                conn,
+            };
+            filter.process(to_remote, &data, &mut out, &mut ctx);
+            data = out;
+        }
+        conn.filters = filters;
+        // 读取时已按各过滤器的 max_input 留出余量
+        buf[..data.len()].copy_from_slice(&data);
+        data.len()
+    }
+
+    /// 打开辅助映射的临时监听，返回监听地址
+    fn open_aux(
+        &self,
+        event_loop: &EventLoop,
+        conn: &TcpConnection,
+        mapping: AuxMapping,
+    ) -> Option<SocketAddr> {
+        let fd_manager = &event_loop.fd_manager;
+        let opened = self
+            .aux
+            .lock()
+            .recover()
+            .values()
+            .filter(|aux| aux.conn_id == conn.id)
+            .count();
+        if opened >= MAX_AUX_MAPPINGS {
+            warn!(
+                "[tcp] #{} too many pending auxiliary mappings, not forwarding to {}",
+                conn.id, mapping.target
+            );
+            return None;
+        }
+        let listener = match std::net::TcpListener::bind((mapping.listen, 0))
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+        {
+            Ok(listener) => TcpListener::from_std(listener),
+            Err(e) => {
+                warn!(
+                    "[tcp] #{} cannot listen on {} for auxiliary mapping: {}",
+                    conn.id, mapping.listen, e
+                );
+                return None;
+            }
+        };
+        let listen_addr = listener.local_addr().ok()?;
+        let now = crate::log::get_monotonic_time();
+        let fd64 = fd_manager.insert(Source::Listener(listener), now);
+        let token = event_loop
+            .token_manager
+            .write()
+            .recover()
+            .generate_token(fd64);
+        if let Err(e) = event_loop.register_source(fd64, token, Interest::READABLE) {
+            warn!(
+                "[tcp] #{} cannot register auxiliary listener: {}",
+                conn.id, e
+            );
+            Self::close_aux(event_loop, fd64);
+            return None;
+        }
+        let stats = match conn.backend {
+            Some(ref backend) => Arc::clone(&backend.stats),
+            None => Arc::new(BackendStats::default()),
+        };
+        info!(
+            "[tcp] #{} auxiliary mapping to {} listening on {}",
+            conn.id, mapping.target, listen_addr
+        );
+        self.aux.lock().recover().insert(
+            fd64,
+            AuxListener {
+                conn_id: conn.id,
+                // 双栈监听时对端为 IPv4 映射地址
+                peer: mapping.peer.to_canonical(),
+                target: Address::from_sockaddr(mapping.target),
+                stats,
+                deadline: crate::clock::now() + AUX_TIMEOUT,
+            },
+        );
+        Some(listen_addr)
+    }
+
+    /// 辅助映射的临时监听接受连接：预期对端的连接转发到目标后关闭监听，其他来源的连接直接关闭
+    fn accept_aux(
+        &self,
+        event_loop: &EventLoop,
+        fd64: Fd64,
+        aux: AuxListener,
+    ) -> Result<(), std::io::Error> {
+        let accepted = event_loop
+            .fd_manager
+            .with_source(fd64, |source| match source {
+                Source::Listener(listener) => Some(listener.accept()),
+                _ => None,
+            })
+            .flatten();
+        let (stream, addr) = match accepted {
+            Some(Ok(accepted)) => accepted,
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.aux.lock().recover().insert(fd64, aux);
+                return Ok(());
+            }
+            Some(Err(e)) => {
+                Self::close_aux(event_loop, fd64);
+                return Err(e);
+            }
+            None => return Ok(()),
+        };
+        let client_addr = crate::log::client_addr(addr);
+        if addr.ip().to_canonical() != aux.peer {
+            warn!(
+                "[tcp] #{} auxiliary connection from unexpected {}, closing",
+                aux.conn_id, client_addr
+            );
+            self.aux.lock().recover().insert(fd64, aux);
+            return Ok(());
+        }
+        Self::close_aux(event_loop, fd64);
+        let family = if addr.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        self.configure_socket(stream.as_raw_fd(), family)?;
+        info!(
+            "[tcp] #{} auxiliary connection from {} to {}",
+            aux.conn_id, client_addr, aux.target
+        );
+        self.connect_backend(
+            event_loop,
+            next_conn_id(),
+            ClientSocket::Aux(stream),
+            addr,
+            client_addr,
+            Arc::new(Backend::direct(aux.target, aux.stats)),
+        )
+    }
+
+    /// 关闭辅助映射的临时监听
+    fn close_aux(event_loop: &EventLoop, fd64: Fd64) {
+        event_loop.deregister_source(fd64);
+        event_loop.token_manager.write().recover().remove(&fd64);
+        event_loop.fd_manager.close(fd64);
+    }
+
+    /// 关闭超时没有连接的辅助映射临时监听，由事件循环每轮调用
+    pub(crate) fn expire_aux(&self, event_loop: &EventLoop) {
+        let expired: Vec<(Fd64, AuxListener)> = {
+            let mut listeners = self.aux.lock().recover();
+            if listeners.is_empty() {
+                return;
+            }
+            let now = crate::clock::now();
+            let fds: Vec<Fd64> = listeners
+                .iter()
+                .filter(|(_, aux)| aux.deadline <= now)
+                .map(|(fd64, _)| *fd64)
+                .collect();
+            fds.into_iter()
+                .filter_map(|fd64| listeners.remove(&fd64).map(|aux| (fd64, aux)))
+                .collect()
+        };
+        for (fd64, aux) in expired {
+            debug!(
+                "[tcp] #{} auxiliary mapping to {} timed out",
+                aux.conn_id, aux.target
+            );
+            Self::close_aux(event_loop, fd64);
+        }
+    }
+
+    pub fn on_read(
+        &self,
+        event_loop: &EventLoop,
+        _token: Token,
+        fd64: Fd64,
+    ) -> Result<(), std::io::Error> {
+        debug!("[tcp] on_read ENTRY fd64={:?}", fd64);
+        let fd_manager = &event_loop.fd_manager;
+        let tcp_manager = &event_loop.tcp_manager;
+
+        if !fd_manager.exist(fd64) {
+            debug!("[tcp] on_read: fd64 does not exist");
+            return Ok(());
+        }
+
+        if self.peeks() && self.peek_pending.lock().recover().contains_key(&fd64) {
+            return self.route_peeked(event_loop, fd64, false);
+        }
+        if !self.middlewares.is_empty() {
+            let aux = self.aux.lock().recover().remove(&fd64);
+            if let Some(aux) = aux {
+                return self.accept_aux(event_loop, fd64, aux);
+            }
         }
 
         let conn_arc = match tcp_manager.get_connection_by_any_fd(&fd64) {
@@ -255,10 +2027,10 @@ impl TcpHandler {
         };
 
         debug!("[tcp] on_read: got connection arc");
-        let conn = conn_arc.read().expect("poisoned");
+        let conn = conn_arc.read().recover();
         debug!(
-            "[tcp] on_read: got read lock, remote_connecting={}",
-            conn.remote_connecting
+            "[tcp] #{} on_read: got read lock, remote_connecting={}",
+            conn.id, conn.remote_connecting
         );
 
         if fd64 == conn.remote.fd64 && conn.remote_connecting {
@@ -266,6 +2038,15 @@ impl TcpHandler {
             debug!("[tcp] on_read: calling handle_connect_finish");
             return self.handle_connect_finish(event_loop, fd64, fd_manager, tcp_manager);
         }
+        // 备用地址 socket 在连接完成前不转发数据
+        if conn.fallback_fd64() == Some(fd64) {
+            return Ok(());
+        }
+        if conn.mirror_fd64() == Some(fd64) {
+            drop(conn);
+            self.on_mirror_event(event_loop, &mut conn_arc.write().recover(), fd64, false);
+            return Ok(());
+        }
 
         let (my_fd64, other_fd64, is_local) = if fd64 == conn.local.fd64 {
             (conn.local.fd64, conn.remote.fd64, true)
@@ -282,351 +2063,708 @@ impl TcpHandler {
             None => return Ok(()),
         };
 
-        let addr_s = conn.addr_s.clone();
+        let id = conn.id;
         let remote_still_connecting = conn.remote_connecting;
 
         drop(conn);
 
         // 即使远程连接尚未完成，也应该尝试读取数据
         // 只是不能将数据发送到尚未建立连接的远程 socket
-        let mut conn = conn_arc.write().expect("poisoned");
-        let poll = &event_loop.poll;
-        let token_manager = &event_loop.token_manager;
+        let mut conn = conn_arc.write().recover();
 
         debug!(
-            "[tcp] on_read: is_local={}, remote_connecting={}",
-            is_local, remote_still_connecting
+            "[tcp] #{} on_read: is_local={}, remote_connecting={}",
+            id, is_local, remote_still_connecting
         );
 
-        if is_local {
-            // local -> remote
-            // 循环读取并发送数据，直到没有更多数据
-            debug!("[tcp] local: pending data_len={}", conn.remote.data_len);
-            loop {
-                // 1. 发送 pending 数据
-                if conn.remote.data_len > 0 && !remote_still_connecting {
-                    debug!(
-                        "[tcp] local: sending {} pending bytes",
-                        conn.remote.data_len
-                    );
-                    let sent = unsafe {
-                        libc::send(
-                            other_fd,
-                            conn.remote.data.as_ptr().add(conn.remote.begin) as *const libc::c_void,
-                            conn.remote.data_len,
-                            0,
-                        )
+        // is_local 时 local -> remote，否则 remote -> local
+        if !self.relay(
+            event_loop,
+            &mut conn,
+            (my_fd64, my_fd),
+            (other_fd64, other_fd),
+            is_local,
+            remote_still_connecting,
+        ) {
+            return Ok(());
+        }
+
+        let pending = if is_local {
+            conn.remote.data_len
+        } else {
+            conn.local.data_len
+        };
+        debug!("[tcp] #{} on_read: exiting relay, pending={}", id, pending);
+
+        // 如果有待发送数据，在对端上注册 WRITE 事件
+        if pending > 0 && !remote_still_connecting {
+            Self::set_write_interest(event_loop, other_fd64, true);
+        }
+
+        if !remote_still_connecting {
+            let (local_fd, remote_fd) = if is_local {
+                (my_fd, other_fd)
+            } else {
+                (other_fd, my_fd)
+            };
+            if self.autotune_conn(&mut conn, local_fd, remote_fd) {
+                self.tuned.lock().recover().push(conn.local.fd64);
+            }
+            #[cfg(target_os = "linux")]
+            self.try_sockmap(&mut conn, local_fd, remote_fd);
+        }
+
+        tcp_manager.update_lru(&fd64);
+        Ok(())
+    }
+
+    /// 循环从 my 端读取并发送到 other 端，直到读不到数据或发送不完
+    ///
+    /// 已有待发送数据时先读取新数据，和待发送数据一起用 writev 发送，一次系统调用发出多段。
+    /// 发不完的部分留在发送方向的端点上 (最多两段)。水平触发模式下只收发一次，剩余数据由重新注册后
+    /// 的下一次事件处理。连接被关闭或等待内核转发完剩余数据时返回 false
+    fn relay(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        (my_fd64, my_fd): (Fd64, RawFd),
+        (other_fd64, other_fd): (Fd64, RawFd),
+        to_remote: bool,
+        connecting: bool,
+    ) -> bool {
+        let side = if to_remote { "local" } else { "remote" };
+        let level = event_loop.config.poll_mode == PollMode::Level;
+        // 已由内核转发的连接先同步字节数，关闭时的统计才完整
+        #[cfg(target_os = "linux")]
+        Self::sync_sockmap_conn(event_loop, conn);
+        loop {
+            let out = Self::outbound(conn, to_remote);
+            let pending = out.data_len;
+            let was_full = out.is_full();
+            // 连接尚未建立时已缓冲的数据不能发送，也不再继续读取
+            if connecting && pending > 0 {
+                break;
+            }
+
+            // 1. 接收新数据 (受限速约束)，已有两段待发送数据时只发送不读取
+            let mut fresh = None;
+            let mut close_reason = None;
+            if !was_full {
+                if let Some(limit) = self.recv_allowance(event_loop, conn, my_fd64) {
+                    let mut buf = self.buffers.get();
+                    // 加解密后的数据不能超出缓冲区
+                    let limit = conn.obfs.as_ref().map_or(limit, |obfs| {
+                        limit.min(obfs.max_input(to_remote, buf.len()))
+                    });
+                    // 过滤器改写后的数据可能变长，同样要留出余量
+                    let limit = conn
+                        .filters
+                        .iter()
+                        .rev()
+                        .fold(limit, |limit, filter| filter.max_input(limit));
+                    // 解压端积压了完整的帧时先输出积压的数据，读取新数据留到下一轮
+                    let backlog = conn
+                        .obfs
+                        .as_ref()
+                        .is_some_and(|obfs| obfs.backlogged(to_remote));
+                    let recv_len = if backlog {
+                        0
+                    } else {
+                        self.do_recv(my_fd, &mut buf[..limit])
                     };
-                    debug!("[tcp] local: sent {}", sent);
-                    if sent > 0 {
-                        TrafficStats::global().add_tcp_sent(sent as usize);
-                        conn.remote.data_len -= sent as usize;
-                        conn.remote.begin += sent as usize;
-                    } else if sent < 0 {
-                        let e = std::io::Error::last_os_error();
-                        debug!("[tcp] local: send error {:?}", e.kind());
-                        if e.kind() != io::ErrorKind::WouldBlock {
-                            Self::close_conn(
-                                poll,
-                                token_manager,
-                                fd_manager,
-                                my_fd64,
-                                other_fd64,
-                                my_fd,
-                                other_fd,
-                                &addr_s,
-                                tcp_manager,
-                            );
-                            tcp_manager.erase(&fd64);
-                            return Ok(());
+                    debug!("[tcp] #{} {}: do_recv returned {}", conn.id, side, recv_len);
+                    if recv_len == 0 && !backlog {
+                        conn.chaos_due[usize::from(!to_remote)] = None;
+                    }
+                    if recv_len < 0 {
+                        close_reason = Some(Self::recv_close_reason(recv_len));
+                    } else if recv_len > 0 || backlog {
+                        let recv_len = recv_len as usize;
+                        if recv_len > 0 {
+                            event_loop.stats.add_tcp_received(recv_len);
+                            self.consume_rate(conn, recv_len);
+                            if to_remote {
+                                self.mirror_data(event_loop, conn, &buf[..recv_len]);
+                            }
+                        }
+                        let recv_len = match conn.obfs {
+                            Some(ref mut obfs) => match obfs.apply(to_remote, &mut buf, recv_len) {
+                                Ok(len) => len,
+                                Err(e) => {
+                                    warn!(
+                                        "[tcp] #{} {}: cannot decode stream from {}: {}",
+                                        conn.id, side, conn.addr_s, e
+                                    );
+                                    Self::close_conn(
+                                        event_loop,
+                                        conn,
+                                        my_fd64,
+                                        other_fd64,
+                                        CloseReason::Error,
+                                    );
+                                    return false;
+                                }
+                            },
+                            None => recv_len,
+                        };
+                        let recv_len = if conn.filters.is_empty() {
+                            recv_len
+                        } else {
+                            self.filter(event_loop, conn, to_remote, &mut buf, recv_len)
+                        };
+                        // 只收到了盐、魔数、半帧或过滤器留住的不完整数据，继续读取
+                        if recv_len == 0 {
+                            continue;
                         }
-                        // WouldBlock，停止发送
-                        break;
+                        fresh = Some((buf, recv_len));
                     }
                 }
+            }
+
+            if let Some(reason) = close_reason {
+                // 还有待发送数据时先尽量发出，发不完留到可写后再次读到 EOF 时关闭
+                if pending == 0 {
+                    // 内核还没把重定向的数据交给对端时稍后再读到 EOF，不刷新活跃时间
+                    #[cfg(target_os = "linux")]
+                    if conn
+                        .sockmap
+                        .as_ref()
+                        .is_some_and(|pair| !pair.drained(to_remote))
+                    {
+                        event_loop
+                            .schedule_tcp_resume(my_fd64, Duration::from_millis(SOCKMAP_DRAIN_MS));
+                        return false;
+                    }
+                    info!("[tcp] #{} connection {} closed (EOF)", conn.id, conn.addr_s);
+                    Self::close_conn(event_loop, conn, my_fd64, other_fd64, reason);
+                    return false;
+                }
+            }
 
-                // 2. 从 local 接收数据
-                let recv_len = Self::do_recv(my_fd, &mut conn.remote.data);
-                debug!("[tcp] local: do_recv returned {}", recv_len);
+            if connecting {
+                // 连接尚未建立，缓冲数据等待连接完成
+                if let Some((buf, recv_len)) = fresh {
+                    debug!(
+                        "[tcp] #{} {}: buffering {} bytes (connecting)",
+                        conn.id, side, recv_len
+                    );
+                    Self::outbound(conn, to_remote).stash(buf, 0, recv_len);
+                }
+                break;
+            }
+
+            let fresh_len = fresh.as_ref().map_or(0, |(_, len)| *len);
+            if pending == 0 && fresh_len == 0 {
+                break;
+            }
+
+            // 2. 待发送数据和新数据一起发送
+            let sent = {
+                let out = Self::outbound(conn, to_remote);
+                #[cfg(target_os = "linux")]
+                let zerocopy = self.send_zerocopy(other_fd, out, &mut fresh);
+                #[cfg(not(target_os = "linux"))]
+                let zerocopy = None;
+                match zerocopy {
+                    Some(sent) if sent < 0 => Err(io::Error::last_os_error()),
+                    Some(sent) => Ok(sent as usize),
+                    None => {
+                        let fresh_slice = fresh.as_ref().map_or(&[][..], |(buf, len)| &buf[..*len]);
+                        self.io
+                            .send(other_fd, &[out.read_slice(), out.tail_slice(), fresh_slice])
+                    }
+                }
+            };
+            debug!(
+                "[tcp] #{} {}: sent {:?} of {} bytes",
+                conn.id,
+                side,
+                sent,
+                pending + fresh_len
+            );
+            let sent = match sent {
+                Ok(sent) => sent,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                Err(e) => {
+                    debug!("[tcp] #{} {}: send error {:?}", conn.id, side, e.kind());
+                    Self::close_conn(event_loop, conn, my_fd64, other_fd64, CloseReason::Error);
+                    return false;
+                }
+            };
+            if sent > 0 {
+                Self::record_sent(event_loop, conn, to_remote, sent);
+            }
+
+            // 3. 发不完的部分留待对端可写时发送
+            let out = Self::outbound(conn, to_remote);
+            out.consume(sent.min(pending));
+            if let Some((buf, recv_len)) = fresh {
+                let begin = sent.saturating_sub(pending);
+                if begin < recv_len {
+                    out.append(buf, begin, recv_len);
+                }
+            }
+
+            if let Some(reason) = close_reason {
+                if out.data_len == 0 {
+                    info!("[tcp] #{} connection {} closed (EOF)", conn.id, conn.addr_s);
+                    Self::close_conn(event_loop, conn, my_fd64, other_fd64, reason);
+                    return false;
+                }
+                break;
+            }
+            if out.data_len > 0 {
+                if let Some(ref mut tune) = conn.buf_tune {
+                    tune.record_backlog();
+                }
+                break;
+            }
+            // 没读到新数据 (WouldBlock 或限速) 时停止；只是腾出了缓冲区则继续读取。
+            // 解压积压的数据没有可读事件通知，水平触发时也要处理完
+            let backlog = conn
+                .obfs
+                .as_ref()
+                .is_some_and(|obfs| obfs.backlogged(to_remote));
+            if (level && !backlog) || (fresh_len == 0 && !was_full) {
+                break;
+            }
+        }
+        true
+    }
+
+    /// 用 MSG_ZEROCOPY 发送新数据，不适用时返回 None 由调用方普通发送
+    ///
+    /// 只在没有待发送数据且新数据达到阈值时使用。发出的缓冲区由端点持有到完成通知到达，
+    /// 部分发送时剩余数据复制到新缓冲区的相同位置，调用方照常处理
+    #[cfg(target_os = "linux")]
+    fn send_zerocopy(
+        &self,
+        fd: RawFd,
+        out: &mut TcpEndpoint,
+        fresh: &mut Option<(PooledBuf, usize)>,
+    ) -> Option<isize> {
+        let threshold = self.zerocopy?;
+        let len = fresh.as_ref().map(|(_, len)| *len)?;
+        if out.data_len > 0
+            || len < threshold
+            || out.zerocopy.inflight.len() >= ZEROCOPY_MAX_INFLIGHT
+        {
+            return None;
+        }
+        // 未开启 SO_ZEROCOPY 时内核忽略 MSG_ZEROCOPY，也不分配序号，不能按零拷贝跟踪
+        let enabled = *out.zerocopy.enabled.get_or_insert_with(|| {
+            getsockopt_int(fd, libc::SOL_SOCKET, SO_ZEROCOPY).is_ok_and(|v| v != 0)
+        });
+        if !enabled {
+            return None;
+        }
+
+        let (buf, len) = fresh.take()?;
+        let sent = unsafe {
+            libc::send(
+                fd,
+                buf.as_ptr() as *const libc::c_void,
+                len,
+                libc::MSG_ZEROCOPY,
+            )
+        };
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+            *fresh = Some((buf, len));
+            // 超出 optmem 限制时改用普通发送，其他错误交给调用方
+            if e.raw_os_error() == Some(libc::ENOBUFS) {
+                return None;
+            }
+            return Some(sent);
+        }
+        let sent_len = sent as usize;
+        if sent_len < len {
+            let mut rest = self.buffers.get();
+            rest[sent_len..len].copy_from_slice(&buf[sent_len..len]);
+            *fresh = Some((rest, len));
+        }
+        out.zerocopy.push(buf);
+        Some(sent)
+    }
+
+    /// 读取错误队列中的 MSG_ZEROCOPY 完成通知，归还已完成的缓冲区
+    ///
+    /// 内核报告数据是复制发送的 (例如环回接口) 时，该端点之后改用普通发送
+    #[cfg(target_os = "linux")]
+    pub(crate) fn on_error_queue(&self, event_loop: &EventLoop, fd64: Fd64) {
+        if self.zerocopy.is_none() {
+            return;
+        }
+        let fd = match event_loop.fd_manager.to_fd(fd64) {
+            Some(fd) => fd,
+            None => return,
+        };
+        let conn_arc = match event_loop.tcp_manager.get_connection_by_any_fd(&fd64) {
+            Some(c) => c,
+            None => return,
+        };
+        let mut conn = conn_arc.write().recover();
+        let id = conn.id;
+        let ep = if fd64 == conn.local.fd64 {
+            &mut conn.local
+        } else if fd64 == conn.remote.fd64 {
+            &mut conn.remote
+        } else {
+            return;
+        };
+
+        while let Some(notice) = recv_zerocopy_notice(fd) {
+            let (lo, hi, copied) = match notice {
+                Ok(notice) => notice,
+                Err(e) => {
+                    debug!("[tcp] #{} zerocopy notification error: {}", id, e);
+                    continue;
+                }
+            };
+            ep.zerocopy.complete(lo, hi);
+            if copied && ep.zerocopy.enabled == Some(true) {
+                debug!("[tcp] #{} zerocopy fell back to copying, disabled", id);
+                ep.zerocopy.enabled = Some(false);
+            }
+        }
+    }
+
+    /// 两个方向都没有积压数据时把连接交给 sockmap，由内核直接转发
+    ///
+    /// 加入时接收队列中已有数据会撤销，留给用户态转发后再试，最多 `SOCKMAP_MAX_TRIES` 次
+    #[cfg(target_os = "linux")]
+    fn try_sockmap(&self, conn: &mut TcpConnection, local_fd: RawFd, remote_fd: RawFd) {
+        let sockmap = match self.sockmap {
+            Some(ref sockmap) => sockmap,
+            None => return,
+        };
+        if conn.sockmap.is_some()
+            || conn.sockmap_tries >= SOCKMAP_MAX_TRIES
+            || conn.socks.is_some()
+            || conn.local.data_len > 0
+            || conn.remote.data_len > 0
+        {
+            return;
+        }
+        conn.sockmap_tries += 1;
+        match sockmap.attach(local_fd, remote_fd) {
+            Ok(pair) => {
+                debug!("[tcp] #{} {} forwarded by sockmap", conn.id, conn.addr_s);
+                conn.sockmap = Some(Arc::new(pair));
+                self.accelerated.lock().recover().push(conn.local.fd64);
+            }
+            Err(e) => debug!(
+                "[tcp] #{} sockmap attach failed (try {}): {}",
+                conn.id, conn.sockmap_tries, e
+            ),
+        }
+    }
 
-                if recv_len < 0 {
-                    let e = std::io::Error::last_os_error();
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        // 没有更多数据，停止
-                        break;
-                    }
-                    // EOF 或错误
-                    info!("[tcp] connection {} closed (EOF)", addr_s);
-                    Self::close_conn(
-                        poll,
-                        token_manager,
-                        fd_manager,
-                        my_fd64,
-                        other_fd64,
-                        my_fd,
-                        other_fd,
-                        &addr_s,
-                        tcp_manager,
-                    );
-                    tcp_manager.erase(&fd64);
-                    return Ok(());
-                }
+    /// 把内核转发的字节数计入统计，并刷新活跃时间
+    #[cfg(target_os = "linux")]
+    fn sync_sockmap_conn(event_loop: &EventLoop, conn: &mut TcpConnection) -> bool {
+        let (up, down) = match conn.sockmap {
+            Some(ref pair) => pair.take_bytes(),
+            None => return false,
+        };
+        for (to_remote, bytes) in [(true, up), (false, down)] {
+            if bytes > 0 {
+                event_loop.stats.add_tcp_received(bytes as usize);
+                Self::record_sent(event_loop, conn, to_remote, bytes as usize);
+            }
+        }
+        up > 0 || down > 0
+    }
 
-                if recv_len == 0 {
-                    // WouldBlock，停止
-                    break;
-                }
+    /// 同步所有已加入 sockmap 的连接，由事件循环在清理超时连接前调用
+    #[cfg(target_os = "linux")]
+    pub(crate) fn sync_sockmap(&self, event_loop: &EventLoop) {
+        if self.sockmap.is_none() {
+            return;
+        }
+        let mut accelerated = self.accelerated.lock().recover();
+        accelerated.retain(|fd64| {
+            let conn_arc = match event_loop.tcp_manager.get_connection_by_any_fd(fd64) {
+                Some(c) => c,
+                None => return false,
+            };
+            let mut conn = conn_arc.write().recover();
+            if conn.sockmap.is_none() {
+                return false;
+            }
+            if Self::sync_sockmap_conn(event_loop, &mut conn) {
+                event_loop.tcp_manager.update_lru(fd64);
+            }
+            true
+        });
+    }
 
-                // 3. 发送到 remote
-                if remote_still_connecting {
-                    // 连接尚未建立，缓冲数据
-                    debug!("[tcp] local: buffering {} bytes (connecting)", recv_len);
-                    conn.remote.data_len = recv_len as usize;
-                    conn.remote.begin = 0;
-                    // 不能发送，等待连接建立
-                    break;
-                } else {
-                    let sent = unsafe {
-                        libc::send(
-                            other_fd,
-                            conn.remote.data.as_ptr() as *const libc::c_void,
-                            recv_len as usize,
-                            0,
-                        )
-                    };
-                    debug!("[tcp] local: sent to remote {}", sent);
-                    if sent > 0 {
-                        TrafficStats::global().add_tcp_sent(sent as usize);
-                        conn.remote.data_len = 0;
-                        conn.remote.begin = 0;
-                    } else if sent < 0 {
-                        let e = std::io::Error::last_os_error();
-                        if e.kind() != io::ErrorKind::WouldBlock {
-                            Self::close_conn(
-                                poll,
-                                token_manager,
-                                fd_manager,
-                                my_fd64,
-                                other_fd64,
-                                my_fd,
-                                other_fd,
-                                &addr_s,
-                                tcp_manager,
-                            );
-                            tcp_manager.erase(&fd64);
-                            return Ok(());
-                        }
-                        // WouldBlock，部分发送
-                        conn.remote.data_len -= sent as usize;
-                        conn.remote.begin += sent as usize;
-                        break;
-                    } else {
-                        conn.remote.data_len = 0;
-                        conn.remote.begin = 0;
-                    }
-                }
+    /// 观测周期结束时按吞吐量和积压调整连接两端的 socket 缓冲区
+    ///
+    /// 缓冲区从初始大小开始增长时返回 true，调用方把连接加入定时检查列表
+    fn autotune_conn(&self, conn: &mut TcpConnection, local_fd: RawFd, remote_fd: RawFd) -> bool {
+        let autotune = match self.autotune {
+            Some(ref autotune) => autotune,
+            None => return false,
+        };
+        let now = crate::clock::now();
+        let tune = conn.buf_tune.get_or_insert_with(|| autotune.track(now));
+        let was_grown = tune.grown();
+        let size = match tune.evaluate(now) {
+            Some(size) => size,
+            None => return false,
+        };
+        let grown = tune.grown();
+        for fd in [local_fd, remote_fd] {
+            if let Err(e) = crate::set_buf_size(fd, size) {
+                debug!(
+                    "[tcp] #{} set buffer size on fd {} failed: {}",
+                    conn.id, fd, e
+                );
             }
+        }
+        debug!(
+            "[tcp] #{} {} socket buffers resized to {} (autotune used {} of {})",
+            conn.id,
+            conn.addr_s,
+            size,
+            autotune.used(),
+            autotune.budget()
+        );
+        grown && !was_grown
+    }
 
-            debug!(
-                "[tcp] local: exiting loop, pending={}",
-                conn.remote.data_len
+    /// 检查缓冲区已增长的连接，空闲的连接缩小缓冲区并归还预算，由事件循环定期调用
+    pub(crate) fn autotune_idle(&self, event_loop: &EventLoop) {
+        if self.autotune.is_none() {
+            return;
+        }
+        let mut tuned = self.tuned.lock().recover();
+        tuned.retain(|fd64| {
+            let conn_arc = match event_loop.tcp_manager.get_connection_by_any_fd(fd64) {
+                Some(c) => c,
+                None => return false,
+            };
+            let mut conn = conn_arc.write().recover();
+            let fds = (
+                event_loop.fd_manager.to_fd(conn.local.fd64),
+                event_loop.fd_manager.to_fd(conn.remote.fd64),
             );
-
-            // 如果有待发送数据，注册 WRITE 事件
-            if conn.remote.data_len > 0 && !remote_still_connecting {
-                if let Some(tok) = token_manager.read().expect("poisoned").get_token(&fd64) {
-                    let mut s = unsafe { TcpStream::from_raw_fd(my_fd) };
-                    poll.registry()
-                        .reregister(&mut s, tok, Interest::READABLE | Interest::WRITABLE)
-                        .ok();
-                    let _ = s.into_raw_fd();
-                }
+            if let (Some(local_fd), Some(remote_fd)) = fds {
+                self.autotune_conn(&mut conn, local_fd, remote_fd);
             }
-        } else {
-            // remote -> local
-            // 循环读取并发送数据
-            loop {
-                // 1. 发送 pending 数据到 local
-                if conn.remote.data_len > 0 {
-                    let sent = unsafe {
-                        libc::send(
-                            other_fd,
-                            conn.remote.data.as_ptr().add(conn.remote.begin) as *const libc::c_void,
-                            conn.remote.data_len,
-                            0,
-                        )
-                    };
-                    if sent > 0 {
-                        TrafficStats::global().add_tcp_sent(sent as usize);
-                        conn.remote.data_len -= sent as usize;
-                        conn.remote.begin += sent as usize;
-                    } else if sent < 0 {
-                        let e = std::io::Error::last_os_error();
-                        if e.kind() != io::ErrorKind::WouldBlock {
-                            Self::close_conn(
-                                poll,
-                                token_manager,
-                                fd_manager,
-                                my_fd64,
-                                other_fd64,
-                                my_fd,
-                                other_fd,
-                                &addr_s,
-                                tcp_manager,
-                            );
-                            tcp_manager.erase(&fd64);
-                            return Ok(());
-                        }
-                        break;
-                    }
-                }
+            conn.buf_tune.as_ref().is_some_and(|tune| tune.grown())
+        });
+    }
 
-                // 2. 从 remote 接收数据
-                let recv_len = Self::do_recv(my_fd, &mut conn.remote.data);
+    /// 发往对端的待发送数据所在端点
+    #[inline]
+    fn outbound(conn: &mut TcpConnection, to_remote: bool) -> &mut TcpEndpoint {
+        if to_remote {
+            &mut conn.remote
+        } else {
+            &mut conn.local
+        }
+    }
 
-                if recv_len < 0 {
-                    let e = std::io::Error::last_os_error();
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        break;
-                    }
-                    info!("[tcp] connection {} closed (EOF)", addr_s);
-                    Self::close_conn(
-                        poll,
-                        token_manager,
-                        fd_manager,
-                        my_fd64,
-                        other_fd64,
-                        my_fd,
-                        other_fd,
-                        &addr_s,
-                        tcp_manager,
-                    );
-                    tcp_manager.erase(&fd64);
-                    return Ok(());
-                }
+    /// 计算本次最多可接收的字节数
+    ///
+    /// 未启用限速时返回整个缓冲区大小；令牌不足时安排定时恢复并返回 None
+    fn recv_allowance(
+        &self,
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        fd64: Fd64,
+    ) -> Option<usize> {
+        // --chaos：每次开始读取前等待抽样的延迟，到期后读到没有数据为止
+        if let Some(chaos) = event_loop.config.chaos.filter(Chaos::delays) {
+            let side = usize::from(fd64 != conn.local.fd64);
+            let now = crate::clock::now();
+            let due = *conn.chaos_due[side].get_or_insert_with(|| now + chaos.sample_delay());
+            if due > now {
+                event_loop.schedule_tcp_resume(fd64, due - now);
+                return None;
+            }
+        }
 
-                if recv_len == 0 {
-                    break;
-                }
+        let want = self.buffers.size();
+        let limiter = match self.rate_limiter {
+            Some(ref limiter) => limiter,
+            None => return Some(want),
+        };
 
-                // 3. 发送到 local
-                let sent = unsafe {
-                    libc::send(
-                        other_fd,
-                        conn.remote.data.as_ptr() as *const libc::c_void,
-                        recv_len as usize,
-                        0,
-                    )
-                };
-                if sent > 0 {
-                    TrafficStats::global().add_tcp_sent(sent as usize);
-                    conn.remote.data_len = 0;
-                    conn.remote.begin = 0;
-                } else if sent < 0 {
-                    let e = std::io::Error::last_os_error();
-                    if e.kind() != io::ErrorKind::WouldBlock {
-                        Self::close_conn(
-                            poll,
-                            token_manager,
-                            fd_manager,
-                            my_fd64,
-                            other_fd64,
-                            my_fd,
-                            other_fd,
-                            &addr_s,
-                            tcp_manager,
-                        );
-                        tcp_manager.erase(&fd64);
-                        return Ok(());
-                    }
-                    conn.remote.data_len -= sent as usize;
-                    conn.remote.begin += sent as usize;
-                    break;
-                } else {
-                    conn.remote.data_len = 0;
-                    conn.remote.begin = 0;
-                }
-            }
+        let allowance = limiter.allowance(conn.rate_bucket.as_mut(), want);
+        let min_read = want.min(RATE_LIMIT_MIN_READ);
+        if allowance >= min_read {
+            return Some(allowance);
+        }
 
-            // 如果有待发送数据，注册 WRITE 事件
-            if conn.remote.data_len > 0 {
-                if let Some(tok) = token_manager.read().expect("poisoned").get_token(&fd64) {
-                    let mut s = unsafe { TcpStream::from_raw_fd(my_fd) };
-                    poll.registry()
-                        .reregister(&mut s, tok, Interest::READABLE | Interest::WRITABLE)
-                        .ok();
-                    let _ = s.into_raw_fd();
-                }
+        // 等待令牌积累到一个完整缓冲区 (或 MAX_DATA_LEN_TCP) 后再恢复读取
+        let delay = limiter.wait_time(conn.rate_bucket.as_mut(), want.min(MAX_DATA_LEN_TCP));
+        debug!(
+            "[tcp] #{} rate limited {}, pausing fd64={:?} for {:?}",
+            conn.id, conn.addr_s, fd64, delay
+        );
+        event_loop.schedule_tcp_resume(fd64, delay);
+        // 客户端自身超出单连接速率时报告拒绝 (全局限速说明服务端繁忙，不算客户端的问题)
+        if fd64 == conn.local.fd64 && limiter.conn_limited(conn.rate_bucket.as_mut(), min_read) {
+            if let Some(peer) = conn.peer {
+                event_loop.reject(Protocol::Tcp, peer, &conn.addr_s, RejectReason::RateLimit);
             }
         }
+        None
+    }
 
-        tcp_manager.update_lru(&fd64);
-        Ok(())
+    /// 扣除已接收字节对应的令牌
+    #[inline]
+    fn consume_rate(&self, conn: &mut TcpConnection, bytes: usize) {
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.consume(conn.rate_bucket.as_mut(), bytes);
+        }
     }
 
     #[inline]
-    fn do_recv(fd: RawFd, data: &mut [u8]) -> isize {
+    fn do_recv(&self, fd: RawFd, data: &mut [u8]) -> isize {
         // 直接尝试读取数据
-        let real_recv =
-            unsafe { libc::recv(fd, data.as_mut_ptr() as *mut libc::c_void, data.len(), 0) };
+        let real_recv = match self.io.recv(fd, data) {
+            Ok(0) => return -2, // EOF - 对端关闭连接
+            Ok(len) => len as isize,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return 0, // 没有数据
+            Err(_) => return -1,                                         // 其他错误
+        };
+
+        // TCP_QUICKACK 不是持久选项，内核可能随时退回延迟确认，每次读取后重新设置
+        #[cfg(target_os = "linux")]
+        if self.quickack {
+            let _ = setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_QUICKACK, 1);
+        }
+
+        real_recv
+    }
 
-        if real_recv < 0 {
-            let e = std::io::Error::last_os_error();
-            if e.kind() == io::ErrorKind::WouldBlock {
-                return 0; // 没有数据
+    /// 记录已发送字节数 (实例统计和单连接计数)
+    fn record_sent(
+        event_loop: &EventLoop,
+        conn: &mut TcpConnection,
+        to_remote: bool,
+        bytes: usize,
+    ) {
+        let direction = if to_remote {
+            Direction::ClientToServer
+        } else {
+            Direction::ServerToClient
+        };
+        event_loop.stats.add_tcp_sent(direction, bytes);
+        if !to_remote && conn.packets_down == 0 {
+            let latency = conn.accept_time.elapsed();
+            event_loop.stats.record_first_byte_latency(latency);
+            if let Some(ref backend) = conn.backend {
+                backend.stats.first_byte_latency.record(latency);
+            }
+        }
+        conn.update_active(direction);
+        if let Some(ref mut tune) = conn.buf_tune {
+            tune.record(bytes);
+        }
+        if to_remote {
+            conn.bytes_up += bytes as u64;
+            conn.packets_up += 1;
+        } else {
+            conn.bytes_down += bytes as u64;
+            conn.packets_down += 1;
+        }
+        if let Some(ref backend) = conn.backend {
+            if to_remote {
+                backend.stats.add_bytes_up(bytes);
+            } else {
+                backend.stats.add_bytes_down(bytes);
             }
-            // 其他错误
-            return -1;
         }
+    }
+
+    /// 开启或关闭 fd 上的 WRITE 事件 (READABLE 始终保留)
+    fn set_write_interest(event_loop: &EventLoop, fd64: Fd64, writable: bool) {
+        let interest = if writable {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        if let Some(tok) = event_loop.token_manager.read().recover().get_token(&fd64) {
+            event_loop.reregister_source(fd64, tok, interest).ok();
+        }
+    }
 
-        if real_recv == 0 {
-            return -2; // EOF - 对端关闭连接
+    /// 记录从接受连接到远程连接建立的延迟
+    fn record_connect_latency(event_loop: &EventLoop, conn: &TcpConnection) {
+        let latency = conn.accept_time.elapsed();
+        event_loop.stats.record_connect_latency(latency);
+        if let Some(ref backend) = conn.backend {
+            backend.stats.connect_latency.record(latency);
         }
+    }
 
-        real_recv
+    /// recv 返回值对应的关闭原因 (-2 为对端关闭，其余为错误)
+    fn recv_close_reason(recv_len: isize) -> CloseReason {
+        if recv_len == -2 {
+            CloseReason::Eof
+        } else {
+            CloseReason::Error
+        }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn close_conn(
-        poll: &mio::Poll,
-        token_manager: &std::sync::Arc<std::sync::RwLock<super::TokenManager>>,
-        fd_manager: &crate::fd_manager::FdManager,
+        event_loop: &EventLoop,
+        conn: &TcpConnection,
         fd64: Fd64,
         other_fd64: Fd64,
-        my_fd: RawFd,
-        other_fd: RawFd,
-        addr_s: &str,
-        tcp_manager: &TcpConnectionManager,
+        reason: CloseReason,
     ) {
-        if let Some(f) = fd_manager.close(fd64) {
-            unsafe {
-                libc::close(f);
+        let fd_manager = &event_loop.fd_manager;
+        // SOCKS5 客户端还在等待 CONNECT 的结果
+        if conn.socks_reply {
+            let code = if reason == CloseReason::ConnectFailed {
+                socks5::REP_CONNECTION_REFUSED
+            } else {
+                socks5::REP_GENERAL_FAILURE
+            };
+            if let Some(fd) = fd_manager.to_fd(conn.local.fd64) {
+                send_segments(fd, &[&socks5::reply(code, None)]);
             }
         }
-        if let Some(f) = fd_manager.close(other_fd64) {
-            unsafe {
-                libc::close(f);
-            }
+        event_loop.deregister_source(fd64);
+        event_loop.deregister_source(other_fd64);
+        fd_manager.close(fd64);
+        fd_manager.close(other_fd64);
+        if let Some(fallback) = conn.fallback_fd64() {
+            event_loop.release_fd(fallback);
         }
+        if let Some(mirror) = conn.mirror_fd64() {
+            event_loop.release_fd(mirror);
+        }
+        #[cfg(target_os = "linux")]
+        conn.close_pipes();
 
-        let mut s1 = unsafe { TcpStream::from_raw_fd(my_fd) };
-        poll.registry().deregister(&mut s1).ok();
-        let _ = s1.into_raw_fd();
-
-        let mut s2 = unsafe { TcpStream::from_raw_fd(other_fd) };
-        poll.registry().deregister(&mut s2).ok();
-        let _ = s2.into_raw_fd();
-
+        let summary = conn.summary(reason);
         info!(
-            "[tcp] closed connection {} cleared, tcp connections={}",
-            addr_s,
-            tcp_manager.len()
+            "[tcp] #{} closed connection {} cleared, {}, tcp connections={}",
+            conn.id,
+            conn.addr_s,
+            summary.traffic(),
+            event_loop.tcp_manager.len()
         );
-        TrafficStats::global().dec_tcp_connections();
+        event_loop.stats.dec_tcp_connections();
+        if let Some(ref backend) = conn.backend {
+            backend.stats.dec_tcp_connections();
+        }
+        event_loop.observers.notify(|o| o.on_close(&summary));
 
-        let mut tm = token_manager.write().expect("poisoned");
+        let mut tm = event_loop.token_manager.write().recover();
         tm.remove(&fd64);
         tm.remove(&other_fd64);
+        drop(tm);
+
+        // 连接以 local fd64 为键，从 remote 端关闭时也要按 local fd64 删除
+        event_loop.tcp_manager.erase(&conn.local.fd64);
     }
 
     fn handle_connect_finish(
@@ -649,6 +2787,7 @@ impl TcpHandler {
 
         let mut err: libc::c_int = 0;
         let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
         unsafe {
             libc::getsockopt(
                 fd,
@@ -672,20 +2811,54 @@ impl TcpHandler {
             }
         };
 
+        if !self.settle_race(event_loop, &conn_arc, fd64, err) {
+            return Ok(());
+        }
+
+        if err == 0 {
+            match self.advance_socks(event_loop, &conn_arc, fd) {
+                Ok(true) => {}
+                Ok(false) => {
+                    // 握手进行中，等待代理应答
+                    Self::set_write_interest(event_loop, fd64, false);
+                    return Ok(());
+                }
+                Err(e) => {
+                    let conn = conn_arc.read().recover();
+                    warn!(
+                        "[tcp] #{} upstream proxy handshake for {} failed: {}",
+                        conn.id, conn.addr_s, e
+                    );
+                    err = e.raw_os_error().unwrap_or(libc::ECONNREFUSED);
+                }
+            }
+        }
+
         if err == 0 {
             {
-                let mut conn = conn_arc.write().expect("poisoned");
+                let mut conn = conn_arc.write().recover();
                 conn.remote_connecting = false;
                 debug!(
-                    "[tcp] handle_connect_finish: connection established, remote_connecting=false"
+                    "[tcp] #{} handle_connect_finish: connection established, remote_connecting=false",
+                    conn.id
                 );
+                Self::record_connect_latency(event_loop, &conn);
+                self.record_connect_success(event_loop, &conn);
+                Self::reply_socks(event_loop, &mut conn);
+                if let Some(ref backend) = conn.backend {
+                    let remote = self.get_remote_addr_for_connect(&backend.addr);
+                    event_loop
+                        .observers
+                        .notify(|o| o.on_connect_established(&conn.addr_s, &remote));
+                }
 
                 // 如果有缓冲的数据，立即尝试发送
                 if conn.local.data_len > 0 {
                     debug!(
-                        "[tcp] handle_connect_finish: {} buffered bytes ready to send",
-                        conn.local.data_len
+                        "[tcp] #{} handle_connect_finish: {} buffered bytes ready to send",
+                        conn.id, conn.local.data_len
                     );
+                    Self::set_write_interest(event_loop, conn.local.fd64, true);
                 }
             }
 
@@ -696,27 +2869,21 @@ impl TcpHandler {
                 Some(c) => c,
                 None => return Ok(()),
             };
-            let conn = conn_arc.read().expect("poisoned");
+            let conn = conn_arc.read().recover();
             let local_fd64 = conn.local.fd64;
-            let local_token = token_manager
-                .read()
-                .expect("poisoned")
-                .get_token(&local_fd64);
+            let local_token = token_manager.read().recover().get_token(&local_fd64);
             drop(conn);
 
             // reregister remote socket
-            if let Some(tok) = token_manager.read().expect("poisoned").get_token(&fd64) {
-                let mut s = unsafe { TcpStream::from_raw_fd(fd) };
+            let tok = token_manager.read().recover().get_token(&fd64);
+            if let Some(tok) = tok {
                 debug!(
                     "[tcp] handle_connect_finish: reregistering remote fd64={:?} with READABLE",
                     fd64
                 );
                 event_loop
-                    .poll
-                    .registry()
-                    .reregister(&mut s, tok, Interest::READABLE)
+                    .reregister_source(fd64, tok, Interest::READABLE)
                     .ok();
-                let _ = s.into_raw_fd();
             }
 
             // 优先调用 local socket 的 on_read 来发送缓冲的数据
@@ -732,42 +2899,106 @@ impl TcpHandler {
                 "[tcp] handle_connect_finish: calling on_read for remote fd64={:?}",
                 fd64
             );
-            return self.on_read(
-                event_loop,
-                token_manager
-                    .read()
-                    .expect("poisoned")
-                    .get_token(&fd64)
-                    .unwrap(),
-                fd64,
-            );
+            let tok = token_manager.read().recover().get_token(&fd64).unwrap();
+            return self.on_read(event_loop, tok, fd64);
         }
 
+        let mut conn = conn_arc.write().recover();
         debug!(
-            "[tcp] handle_connect_finish: connection failed, err={}",
-            err
+            "[tcp] #{} handle_connect_finish: connection failed, err={}",
+            conn.id, err
         );
-        let conn = conn_arc.read().expect("poisoned");
-        let addr_s = conn.addr_s.clone();
+        self.record_connect_failure(event_loop, &conn);
+        if self.schedule_retry(event_loop, &mut conn, err) {
+            return Ok(());
+        }
         let other_fd64 = conn.local.fd64;
-        let other_fd = fd_manager.to_fd(other_fd64).unwrap_or(-1);
-        drop(conn);
 
         Self::close_conn(
-            &event_loop.poll,
-            &event_loop.token_manager,
-            fd_manager,
+            event_loop,
+            &conn,
             fd64,
             other_fd64,
-            fd,
-            other_fd,
-            &addr_s,
-            tcp_manager,
+            CloseReason::ConnectFailed,
         );
-        tcp_manager.erase(&fd64);
         Ok(())
     }
 
+    /// 推进上游 SOCKS5 握手，返回握手是否已完成 (未使用代理时直接返回 true)
+    ///
+    /// 与应答一起到达的后端数据放入发往客户端的缓冲区
+    fn advance_socks(
+        &self,
+        event_loop: &EventLoop,
+        conn_arc: &RwLock<TcpConnection>,
+        fd: RawFd,
+    ) -> io::Result<bool> {
+        let mut conn = conn_arc.write().recover();
+        let handshake = match conn.socks {
+            Some(ref mut handshake) => handshake,
+            None => return Ok(true),
+        };
+        let mut step = if handshake.is_started() {
+            Step::Wait
+        } else {
+            Step::Send(handshake.start())
+        };
+        let mut buf = [0u8; 512];
+        loop {
+            match step {
+                Step::Send(data) => {
+                    let sent = unsafe {
+                        libc::send(fd, data.as_ptr() as *const libc::c_void, data.len(), 0)
+                    };
+                    if sent < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // 握手消息很短，刚建立的连接发送缓冲区不会满
+                    if sent as usize != data.len() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "short write to proxy",
+                        ));
+                    }
+                }
+                Step::Done(_) => break,
+                Step::Wait => {}
+            }
+            let len =
+                unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(false);
+                }
+                return Err(e);
+            }
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proxy closed the connection during handshake",
+                ));
+            }
+            step = handshake.on_data(&buf[..len as usize])?;
+        }
+
+        let remaining = handshake.take_remaining();
+        conn.socks = None;
+        if !remaining.is_empty() {
+            event_loop.stats.add_tcp_received(remaining.len());
+            let mut buf = self.buffers.get();
+            buf[..remaining.len()].copy_from_slice(&remaining);
+            let len = match conn.obfs {
+                Some(ref mut obfs) => obfs.apply(false, &mut buf, remaining.len())?,
+                None => remaining.len(),
+            };
+            if len > 0 {
+                conn.local.stash(buf, 0, len);
+            }
+        }
+        Ok(true)
+    }
+
     pub fn on_write(
         &self,
         event_loop: &EventLoop,
@@ -787,14 +3018,21 @@ impl TcpHandler {
         };
 
         {
-            let conn = conn_arc.read().expect("poisoned");
-            if fd64 == conn.remote.fd64 && conn.remote_connecting {
+            let conn = conn_arc.read().recover();
+            if (fd64 == conn.remote.fd64 && conn.remote_connecting)
+                || conn.fallback_fd64() == Some(fd64)
+            {
                 drop(conn);
                 return self.handle_connect_finish(event_loop, fd64, fd_manager, tcp_manager);
             }
+            if conn.mirror_fd64() == Some(fd64) {
+                drop(conn);
+                self.on_mirror_event(event_loop, &mut conn_arc.write().recover(), fd64, true);
+                return Ok(());
+            }
         }
 
-        let conn = conn_arc.read().expect("poisoned");
+        let conn = conn_arc.read().recover();
 
         let (my_fd64, other_fd64, is_local) = if fd64 == conn.local.fd64 {
             (conn.local.fd64, conn.remote.fd64, true)
@@ -806,12 +3044,10 @@ impl TcpHandler {
             Some(f) => f,
             None => return Ok(()),
         };
-        let other_fd = match fd_manager.to_fd(other_fd64) {
-            Some(f) => f,
-            None => return Ok(()),
-        };
+        if !fd_manager.exist(other_fd64) {
+            return Ok(());
+        }
 
-        let addr_s = conn.addr_s.clone();
         let pending_data_len = if is_local {
             conn.local.data_len
         } else {
@@ -821,63 +3057,39 @@ impl TcpHandler {
         drop(conn);
 
         if pending_data_len > 0 {
-            let mut conn = conn_arc.write().expect("poisoned");
-            let (data_len, data_begin, data_ptr, fd_to_send) = if is_local {
-                (
-                    conn.local.data_len,
-                    conn.local.begin,
-                    conn.local.data.as_ptr(),
-                    my_fd,
-                )
-            } else {
-                (
-                    conn.remote.data_len,
-                    conn.remote.begin,
-                    conn.remote.data.as_ptr(),
-                    my_fd,
-                )
-            };
-
-            if data_len > 0 {
-                let sent = unsafe {
-                    libc::send(
-                        fd_to_send,
-                        data_ptr.add(data_begin) as *const libc::c_void,
-                        data_len,
-                        0,
-                    )
-                };
-                if sent > 0 {
-                    TrafficStats::global().add_tcp_sent(sent as usize);
-                    if is_local {
-                        conn.local.data_len -= sent as usize;
-                        conn.local.begin += sent as usize;
-                    } else {
-                        conn.remote.data_len -= sent as usize;
-                        conn.remote.begin += sent as usize;
+            let mut conn = conn_arc.write().recover();
+            let pending = if is_local { &conn.local } else { &conn.remote };
+
+            if pending.data_len > 0 {
+                match self
+                    .io
+                    .send(my_fd, &[pending.read_slice(), pending.tail_slice()])
+                {
+                    Ok(0) => {}
+                    Ok(sent) => {
+                        Self::record_sent(event_loop, &mut conn, !is_local, sent);
+                        if is_local {
+                            conn.local.consume(sent);
+                        } else {
+                            conn.remote.consume(sent);
+                        }
                     }
-                } else if sent < 0 {
-                    let e = std::io::Error::last_os_error();
-                    if e.kind() != io::ErrorKind::WouldBlock {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => {
                         Self::close_conn(
-                            &event_loop.poll,
-                            &event_loop.token_manager,
-                            fd_manager,
+                            event_loop,
+                            &conn,
                             my_fd64,
                             other_fd64,
-                            my_fd,
-                            other_fd,
-                            &addr_s,
-                            tcp_manager,
+                            CloseReason::Error,
                         );
-                        tcp_manager.erase(&fd64);
                         return Ok(());
                     }
                 }
             }
         }
 
-        let conn = conn_arc.read().expect("poisoned");
+        let conn = conn_arc.read().recover();
         let pending = if is_local {
             conn.local.data_len
         } else {
@@ -886,15 +3098,19 @@ impl TcpHandler {
         drop(conn);
 
         if pending == 0 {
-            let poll = &event_loop.poll;
-            let token_manager = &event_loop.token_manager;
-            if let Some(tok) = token_manager.read().expect("poisoned").get_token(&fd64) {
-                let mut s = unsafe { TcpStream::from_raw_fd(my_fd) };
-                poll.registry()
-                    .reregister(&mut s, tok, Interest::READABLE)
-                    .ok();
-                let _ = s.into_raw_fd();
+            Self::set_write_interest(event_loop, my_fd64, false);
+            tcp_manager.update_lru(&fd64);
+            // 缓冲区已清空，继续读取对端 (边缘触发下不会再收到可读事件)
+            // 先释放 token_manager 读锁，on_read 关闭连接时需要写锁
+            let tok = event_loop
+                .token_manager
+                .read()
+                .recover()
+                .get_token(&other_fd64);
+            if let Some(tok) = tok {
+                return self.on_read(event_loop, tok, other_fd64);
             }
+            return Ok(());
         }
 
         tcp_manager.update_lru(&fd64);
@@ -907,3 +3123,417 @@ impl Default for TcpHandler {
         Self::new()
     }
 }
+
+/// SO_ZEROCOPY (include/uapi/asm-generic/socket.h)，libc 未导出
+#[cfg(target_os = "linux")]
+const SO_ZEROCOPY: libc::c_int = 60;
+/// 完成通知的 ee_origin
+#[cfg(target_os = "linux")]
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+/// 完成通知的 ee_code：内核没有零拷贝，而是复制了数据
+#[cfg(target_os = "linux")]
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+/// 读取一条 MSG_ZEROCOPY 完成通知，返回 `(lo, hi, copied)`；错误队列为空时返回 None
+///
+/// 错误队列中的其他错误 (例如 ICMP) 以 `Err` 返回
+#[cfg(target_os = "linux")]
+fn recv_zerocopy_notice(fd: RawFd) -> Option<io::Result<(u32, u32, bool)>> {
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE) };
+    if ret < 0 {
+        return None;
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        let is_recverr = (hdr.cmsg_level == libc::SOL_IP && hdr.cmsg_type == libc::IP_RECVERR)
+            || (hdr.cmsg_level == libc::SOL_IPV6 && hdr.cmsg_type == libc::IPV6_RECVERR);
+        if is_recverr {
+            let err = unsafe {
+                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+            };
+            if err.ee_origin != SO_EE_ORIGIN_ZEROCOPY {
+                return Some(Err(io::Error::from_raw_os_error(err.ee_errno as i32)));
+            }
+            let copied = err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0;
+            return Some(Ok((err.ee_info, err.ee_data, copied)));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Some(Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "error queue message without IP_RECVERR",
+    )))
+}
+
+/// 发送多段数据，多于一段时用 writev 合并为一次系统调用，返回值同 `send`
+pub(crate) fn send_segments(fd: RawFd, segments: &[&[u8]]) -> isize {
+    let iov: Vec<libc::iovec> = segments
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| libc::iovec {
+            iov_base: s.as_ptr() as *mut libc::c_void,
+            iov_len: s.len(),
+        })
+        .collect();
+    match iov.len() {
+        0 => 0,
+        1 => unsafe { libc::send(fd, iov[0].iov_base, iov[0].iov_len, 0) },
+        n => unsafe { libc::writev(fd, iov.as_ptr(), n as libc::c_int) },
+    }
+}
+
+/// 设置整型 socket 选项
+fn setsockopt_int(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 读取整型 socket 选项
+#[cfg(target_os = "linux")]
+fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// 设置拥塞控制算法 (TCP_CONGESTION)
+#[cfg(target_os = "linux")]
+pub fn set_congestion(fd: RawFd, algo: &std::ffi::CStr) -> io::Result<()> {
+    let name = algo.to_bytes();
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 检查拥塞控制算法是否可用 (在临时 socket 上尝试设置)
+#[cfg(target_os = "linux")]
+pub fn check_congestion(algo: &std::ffi::CStr) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = set_congestion(fd, algo);
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// 启用 SO_KEEPALIVE 并设置探测参数
+fn set_keepalive(fd: RawFd, keepalive: &TcpKeepalive) -> io::Result<()> {
+    #[cfg(target_vendor = "apple")]
+    const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(target_vendor = "apple"))]
+    const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+    let secs = |d: std::time::Duration| d.as_secs().min(libc::c_int::MAX as u64) as libc::c_int;
+    setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    setsockopt_int(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE, secs(keepalive.idle))?;
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        secs(keepalive.interval),
+    )?;
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPCNT,
+        keepalive.count.min(libc::c_int::MAX as u32) as libc::c_int,
+    )
+}
+
+/// 设置 SO_LINGER
+pub(crate) fn set_linger(fd: RawFd, linger: Linger) -> io::Result<()> {
+    // Windows 上 l_linger 为 u_short
+    #[cfg(unix)]
+    const MAX_LINGER: u32 = libc::c_int::MAX as u32;
+    #[cfg(windows)]
+    const MAX_LINGER: u32 = u16::MAX as u32;
+
+    let value = match linger {
+        Linger::Off => libc::linger {
+            l_onoff: 0,
+            l_linger: 0,
+        },
+        Linger::Secs(secs) => libc::linger {
+            l_onoff: 1,
+            l_linger: secs.min(MAX_LINGER) as _,
+        },
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    }
+
+    #[cfg(any(target_os = "linux", windows))]
+    #[test]
+    fn test_set_keepalive() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let fd = socket.as_raw_fd();
+        let keepalive: TcpKeepalive = "60,10,6".parse().expect("keepalive");
+        assert_eq!(keepalive.idle, Duration::from_secs(60));
+        set_keepalive(fd, &keepalive).expect("set keepalive");
+
+        assert_eq!(getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(
+            getsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+            60
+        );
+        assert_eq!(
+            getsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+            10
+        );
+        assert_eq!(getsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 6);
+
+        assert!("60,10".parse::<TcpKeepalive>().is_err());
+        assert!("60,0,6".parse::<TcpKeepalive>().is_err());
+        assert!("a,b,c".parse::<TcpKeepalive>().is_err());
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_shed_connection_sandboxed() {
+        use std::io::Read;
+
+        // 每次丢弃连接后都要重新占住预留 fd，沙箱中不能再打开文件
+        crate::sandbox::run_isolated("event::tcp::tests::test_shed_connection_sandboxed", || {
+            let handler = TcpHandler::new();
+            let listener = TcpListener::bind("127.0.0.1:0".parse().expect("addr")).expect("bind");
+            let addr = listener.local_addr().expect("addr");
+            crate::sandbox::install(Default::default()).expect("install sandbox");
+            for _ in 0..2 {
+                let mut client = std::net::TcpStream::connect(addr).expect("connect");
+                client
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .expect("set timeout");
+                handler.shed_connection(&listener);
+                assert!(handler.reserve_fd.lock().recover().is_some());
+                assert_eq!(client.read(&mut [0u8; 1]).expect("read"), 0);
+            }
+        });
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(1), CONNECT_RETRY_DELAY);
+        assert_eq!(retry_backoff(2), CONNECT_RETRY_DELAY * 2);
+        assert_eq!(retry_backoff(4), CONNECT_RETRY_DELAY * 8);
+        assert_eq!(retry_backoff(10), CONNECT_RETRY_MAX_DELAY);
+        assert_eq!(retry_backoff(u32::MAX), CONNECT_RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_set_linger() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let fd = socket.as_raw_fd();
+        let get = || {
+            let mut value: libc::linger = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::linger>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_LINGER,
+                    &mut value as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            (value.l_onoff != 0, value.l_linger)
+        };
+
+        set_linger(fd, "0".parse().expect("linger")).expect("set linger");
+        assert_eq!(get(), (true, 0));
+        set_linger(fd, Linger::Secs(5)).expect("set linger");
+        assert_eq!(get(), (true, 5));
+        set_linger(fd, "off".parse().expect("linger")).expect("set linger");
+        assert!(!get().0);
+        assert!("on".parse::<Linger>().is_err());
+    }
+
+    #[test]
+    fn test_send_segments() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let client =
+            std::net::TcpStream::connect(listener.local_addr().expect("addr")).expect("connect");
+        let (mut server, _) = listener.accept().expect("accept");
+
+        let fd = client.as_raw_fd();
+        assert_eq!(send_segments(fd, &[b"", b""]), 0);
+        assert_eq!(send_segments(fd, &[b"he", b"", b"llo"]), 5);
+        assert_eq!(send_segments(fd, &[b"!"]), 1);
+
+        let mut buf = [0u8; 6];
+        server.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf, b"hello!");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_check_congestion() {
+        // reno 始终内置在内核中
+        assert!(check_congestion(c"reno").is_ok());
+        assert!(check_congestion(c"no-such-algo").is_err());
+    }
+
+    #[test]
+    fn test_backpressure_on_sim_net() {
+        use crate::clock::{self, MockClock};
+        use crate::fd_manager::FdManager;
+        use crate::manager::UdpSessionManager;
+        use crate::mapper::PortMapper;
+        use crate::sim::SimNet;
+
+        let clock = MockClock::new();
+        let _guard = clock::install(clock.clone());
+        let config = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:1")
+            .tcp(true)
+            .tenant("sim-backpressure")
+            .config()
+            .expect("config");
+        let tcp_manager = Arc::new(TcpConnectionManager::new(
+            Duration::from_secs(60),
+            30,
+            1,
+            false,
+        ));
+        let udp_manager = Arc::new(UdpSessionManager::new(
+            Duration::from_secs(60),
+            30,
+            1,
+            false,
+        ));
+        let event_loop = EventLoop::new(
+            Arc::new(config),
+            FdManager::new(),
+            Arc::clone(&tcp_manager),
+            udp_manager,
+        )
+        .expect("event loop");
+        let net = SimNet::new();
+        event_loop
+            .tcp_handler()
+            .write()
+            .recover()
+            .set_socket_io(net.clone());
+
+        // 后端只能缓存 4KB，客户端一次发来 64KB
+        let (client, server) = (net.socket(usize::MAX), net.socket(4096));
+        let now = crate::log::get_monotonic_time();
+        let local = event_loop.fd_manager.create(client, now);
+        let remote = event_loop.fd_manager.create(server, now);
+        tcp_manager.new_connection(1, local, remote, "sim".to_string(), now, 16384, false);
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        net.deliver(client, &data);
+
+        let handler = event_loop.tcp_handler();
+        let handler = handler.read().recover();
+        handler.on_read(&event_loop, Token(0), local).expect("read");
+        // 发不完的数据留在连接上，不再继续读取客户端
+        assert_eq!(net.queued(server), 4096);
+        let conn = tcp_manager.get_connection(&local).expect("connection");
+        assert_eq!(conn.read().recover().remote.data_len, 16384 - 4096);
+
+        let mut received = net.take(server, usize::MAX);
+        while received.len() < data.len() {
+            handler
+                .on_write(&event_loop, Token(0), remote)
+                .expect("write");
+            handler.on_read(&event_loop, Token(0), local).expect("read");
+            received.extend(net.take(server, 1000));
+        }
+        assert!(received == data);
+        assert_eq!(conn.read().recover().bytes_up, data.len() as u64);
+
+        // 空闲超过超时时间后由清理任务关闭，不需要真实等待
+        clock.advance(Duration::from_secs(30));
+        event_loop.sweep_inactive();
+        assert_eq!(tcp_manager.len(), 1);
+        clock.advance(Duration::from_secs(31));
+        event_loop.sweep_inactive();
+        assert!(tcp_manager.is_empty());
+        assert!(!event_loop.fd_manager.exist(local));
+        assert!(!event_loop.fd_manager.exist(remote));
+    }
+}
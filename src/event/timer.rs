@@ -2,14 +2,19 @@
 //!
 //! 提供定时任务功能
 
+use crate::sync::{LoopShared, Mutex, Recover};
 use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 /// 定时器回调类型
+#[cfg(not(feature = "single-thread"))]
 pub type TimerCallback = Box<dyn Fn() + Send + Sync>;
+/// 定时器回调类型 (单线程模式下回调可以持有事件循环内部的数据)
+#[cfg(feature = "single-thread")]
+pub type TimerCallback = Box<dyn Fn()>;
 
 /// 定时器
 pub struct Timer {
@@ -22,6 +27,8 @@ struct TimerEntry {
     callback: Option<TimerCallback>,
     /// 间隔
     interval: Duration,
+    /// 只执行一次
+    once: bool,
     /// 是否已标记删除
     deleted: Arc<AtomicBool>,
 }
@@ -37,15 +44,28 @@ impl Timer {
     /// 注册定时任务
     pub fn register<F>(&self, interval: Duration, callback: F)
     where
-        F: Fn() + Send + Sync + 'static,
+        F: Fn() + LoopShared + 'static,
     {
-        let mut entries = self.entries.lock().expect("Mutex poisoned");
-        let now = Instant::now();
+        self.insert(interval, false, Box::new(callback));
+    }
+
+    /// 注册只执行一次的任务，`delay` 后执行
+    pub fn register_once<F>(&self, delay: Duration, callback: F)
+    where
+        F: Fn() + LoopShared + 'static,
+    {
+        self.insert(delay, true, Box::new(callback));
+    }
+
+    fn insert(&self, interval: Duration, once: bool, callback: TimerCallback) {
+        let mut entries = self.entries.lock().recover();
+        let now = crate::clock::now();
         let next_time = now + interval;
 
         let entry = TimerEntry {
-            callback: Some(Box::new(callback)),
+            callback: Some(callback),
             interval,
+            once,
             deleted: Arc::new(AtomicBool::new(false)),
         };
 
@@ -54,57 +74,54 @@ impl Timer {
 
     /// 运行定时器 - 执行所有到期的回调
     pub fn run(&self) {
-        let now = Instant::now();
+        let now = crate::clock::now();
         let mut to_remove: Vec<Instant> = Vec::new();
-        let mut to_reschedule: Vec<(Duration, TimerCallback, Arc<AtomicBool>)> = Vec::new();
+        let mut to_reschedule: Vec<(Duration, bool, TimerCallback, Arc<AtomicBool>)> = Vec::new();
 
         // 收集到期的回调
         {
-            let mut entries = self.entries.lock().expect("Mutex poisoned");
+            let mut entries = self.entries.lock().recover();
 
             for (time, vec) in entries.iter_mut() {
-                if *time <= now {
-                    for entry in vec.iter_mut() {
-                        if !entry.deleted.load(Ordering::Relaxed) {
-                            // 标记为删除
-                            entry.deleted.store(true, Ordering::Relaxed);
-                            // 取出回调用于执行，然后重新调度
-                            if let Some(callback) = entry.callback.take() {
-                                to_reschedule.push((
-                                    entry.interval,
-                                    callback,
-                                    Arc::clone(&entry.deleted),
-                                ));
-                            }
-                        }
+                if *time > now {
+                    break;
+                }
+                for entry in vec.iter_mut() {
+                    if entry.deleted.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    // 取出回调用于执行，然后重新调度
+                    if let Some(callback) = entry.callback.take() {
+                        to_reschedule.push((
+                            entry.interval,
+                            entry.once,
+                            callback,
+                            Arc::clone(&entry.deleted),
+                        ));
                     }
-                    to_remove.push(*time);
                 }
+                to_remove.push(*time);
             }
 
-            // 清理已删除的条目
+            // 清理已到期的条目
             for time in &to_remove {
-                entries
-                    .entry(*time)
-                    .and_modify(|vec| vec.retain(|e| !e.deleted.load(Ordering::Relaxed)));
+                entries.remove(time);
             }
-
-            // 清理空条目
-            entries.retain(|_, vec| !vec.is_empty());
         }
 
         // 执行回调并重新调度
-        for (interval, callback, deleted) in to_reschedule {
+        for (interval, once, callback, deleted) in to_reschedule {
             // 执行回调
             callback();
 
-            // 重新调度 - 只有未标记删除时才重新调度
-            if !deleted.load(Ordering::Relaxed) {
-                let mut entries = self.entries.lock().expect("Mutex poisoned");
-                let new_time = Instant::now() + interval;
+            // 重新调度 - 只有周期任务且未标记删除时才重新调度
+            if !once && !deleted.load(Ordering::Relaxed) {
+                let mut entries = self.entries.lock().recover();
+                let new_time = crate::clock::now() + interval;
                 let new_entry = TimerEntry {
                     callback: Some(callback),
                     interval,
+                    once,
                     deleted,
                 };
                 entries.entry(new_time).or_default().push(new_entry);
@@ -114,9 +131,9 @@ impl Timer {
 
     /// 获取下一个定时器到期时间
     pub fn next_timeout(&self) -> Option<Duration> {
-        let entries = self.entries.lock().expect("Mutex poisoned");
+        let entries = self.entries.lock().recover();
         entries.keys().next().map(|time| {
-            let now = Instant::now();
+            let now = crate::clock::now();
             if *time > now {
                 time.duration_since(now)
             } else {
@@ -124,6 +141,13 @@ impl Timer {
             }
         })
     }
+
+    /// 计算 poll 等待时间
+    ///
+    /// 取最近一个定时器的到期时间，并以 `max` 为上限；没有定时器时直接返回 `max`
+    pub fn poll_timeout(&self, max: Duration) -> Duration {
+        self.next_timeout().map_or(max, |timeout| timeout.min(max))
+    }
 }
 
 impl Default for Timer {
@@ -131,3 +155,113 @@ impl Default for Timer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_timeout_without_entries() {
+        let timer = Timer::new();
+        assert_eq!(timer.next_timeout(), None);
+        assert_eq!(
+            timer.poll_timeout(Duration::from_secs(1)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_poll_timeout_follows_deadline() {
+        let timer = Timer::new();
+        timer.register(Duration::from_millis(50), || {});
+        let timeout = timer.poll_timeout(Duration::from_secs(1));
+        assert!(timeout <= Duration::from_millis(50));
+
+        // 上限生效
+        let timeout = timer.poll_timeout(Duration::from_millis(5));
+        assert!(timeout <= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_overdue_timer_returns_zero() {
+        let timer = Timer::new();
+        timer.register(Duration::ZERO, || {});
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(timer.poll_timeout(Duration::from_secs(1)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_periodic_reschedule() {
+        use std::sync::atomic::AtomicUsize;
+
+        let timer = Timer::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+        timer.register(Duration::ZERO, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // 周期任务执行后应重新调度，而不是只执行一次
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(1));
+            timer.run();
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert!(timer.next_timeout().is_some());
+    }
+
+    #[test]
+    fn test_register_once() {
+        use std::sync::atomic::AtomicUsize;
+
+        let timer = Timer::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+        timer.register_once(Duration::ZERO, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(1));
+            timer.run();
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert_eq!(timer.next_timeout(), None);
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        use crate::clock::{self, MockClock};
+        use std::sync::atomic::AtomicUsize;
+
+        let clock = MockClock::new();
+        let _guard = clock::install(clock.clone());
+        let timer = Timer::new();
+        let (periodic, once) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let counter = Arc::clone(&periodic);
+        timer.register(Duration::from_secs(10), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let counter = Arc::clone(&once);
+        timer.register_once(Duration::from_secs(5), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // 时间只随时钟推进，不受真实等待影响
+        std::thread::sleep(Duration::from_millis(5));
+        timer.run();
+        assert_eq!(timer.next_timeout(), Some(Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(5));
+        timer.run();
+        assert_eq!(once.load(Ordering::Relaxed), 1);
+        assert_eq!(periodic.load(Ordering::Relaxed), 0);
+        assert_eq!(timer.next_timeout(), Some(Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(25));
+        timer.run();
+        // 错过的周期不补执行，从执行时刻重新计时
+        assert_eq!(periodic.load(Ordering::Relaxed), 1);
+        assert_eq!(once.load(Ordering::Relaxed), 1);
+        assert_eq!(timer.next_timeout(), Some(Duration::from_secs(10)));
+    }
+}
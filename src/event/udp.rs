@@ -2,38 +2,115 @@
 //!
 //! 处理 UDP 数据包的所有事件
 
+use crate::debug;
 use crate::info;
+use crate::sync::{Mutex, Recover, RwLock};
 use crate::trace;
 use crate::warn;
 
-use crate::config::FwdType;
+use crate::alg;
+use crate::backend::{translate_addr, BackendPool};
+use crate::bufpool::BufferPool;
+use crate::chaos::DelayQueue;
+use crate::config::{FwdType, PortRange, SocketMark, UdpKeepalive};
+use crate::connection::UdpSession;
+#[cfg(target_os = "linux")]
+use crate::event::observer::{CloseReason, Protocol, RejectReason};
 use crate::event::EventLoop;
-use crate::fd_manager::Fd64;
-use crate::stats::TrafficStats;
-use crate::types::Address;
+use crate::fd_manager::{Fd64, Source};
+use crate::fragment;
+use crate::ipheader::{self, IpHeader};
+use crate::manager::UdpSessionManager;
+use crate::memory::{MemoryBudget, UDP_SESSION_MEMORY};
+use crate::middleware::Middleware;
+use crate::mirror::UdpMirror;
+use crate::quic;
+use crate::ratelimit::RateLimiter;
+use crate::sockets::UdpSocketBuilder;
+use crate::socks5::{self, Socks5Association, Socks5Server, Socks5Upstream};
+use crate::stats::Direction;
+use crate::stun::StunClient;
+use crate::types::{Address, Nat64Prefix};
 use mio::net::UdpSocket;
-use mio::Token;
+use std::borrow::Cow;
 use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(unix)]
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 #[cfg(windows)]
-use std::os::windows::io::{AsRawFd, FromRawFd, IntoRawFd};
+use crate::winsock::{self as libc, AsRawFd, FromRawFd, RawFd};
+
+/// 收包缓冲区大小 (比 UDP 最大载荷多一字节，用于识别超大包)
+const DATAGRAM_BUF_SIZE: usize = 65536 + 1;
+
+/// 监听 socket 每次可读事件最多处理的数据包数
+pub const UDP_RECV_BATCH: usize = 64;
+
+/// ICMP 超时 (TTL 耗尽) 的类型
+#[cfg(target_os = "linux")]
+const ICMP_TIME_EXCEEDED: u8 = 11;
+#[cfg(target_os = "linux")]
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
 
 /// UDP 处理器
 #[derive(Debug)]
 pub struct UdpHandler {
-    /// 远程地址
-    remote_addr: Address,
+    /// 后端地址池
+    backends: Arc<BackendPool>,
     /// Socket 缓冲区大小
     socket_buf_size: usize,
     /// 转发类型
     fwd_type: FwdType,
-    /// 启用 UDP 分片转发 (启用 IP_MTU_DISCOVER)
+    /// 4to6/6to4 翻译使用的 NAT64 前缀
+    nat64_prefix: Nat64Prefix,
+    /// 启用 UDP 分片转发：外连 socket 的 DF 跟随客户端数据包是否分片到达
     enable_fragment: bool,
+    /// 客户端 -> 远程数据包的 IP 包长度上限
+    mtu: Option<usize>,
     /// 绑定的网络接口名称
     bind_interface: Option<String>,
+    /// 透明代理：以客户端 IP 作为外连源地址
+    transparent: bool,
+    /// 外连 socket 绑定的源地址 (每个地址族最多一个)
+    bind_source: Vec<IpAddr>,
+    /// 外连 socket 的源端口范围
+    source_ports: Option<PortRange>,
+    /// 上游 SOCKS5 代理 (经 UDP ASSOCIATE 中继)
+    upstream: Option<Arc<Socks5Upstream>>,
+    /// 作为 SOCKS5 服务端时监听 socket 是 UDP ASSOCIATE 的中继，数据包带 SOCKS5 UDP 头
+    socks_server: Option<Arc<Socks5Server>>,
+    /// 跟踪 QUIC 连接 ID，客户端地址变化时沿用原会话
+    quic: bool,
+    /// 限速器 (超出速率的数据包直接丢弃)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// 收包缓冲区池
+    buffers: Arc<BufferPool>,
+    /// 内存预算，新会话超出时丢弃数据包
+    memory: Option<Arc<MemoryBudget>>,
+    /// 外连 socket 的 DSCP/TOS 和 SO_MARK
+    mark: SocketMark,
+    /// --chaos 延迟队列
+    delayed: Mutex<DelayQueue<DelayedDatagram>>,
+    /// 客户端数据包副本的发送 socket
+    mirror: Option<UdpMirror>,
+    /// 会话保活参数
+    keepalive: Option<UdpKeepalive>,
+    /// 会话 socket 启用 IP_RECVERR，远程不可达时立即关闭会话 (仅 Linux)
+    recverr: bool,
+    /// 两个方向都沿用收到的数据包的 TOS/ECN 和 TTL (仅 Linux)
+    preserve_tos_ttl: bool,
+    /// 回包发往的组播组 (--multicast-reply)，None 时发回客户端
+    multicast_reply: Option<Address>,
+    /// 广播中继：会话 socket 不连接并设置 SO_BROADCAST，远程可以是广播地址
+    broadcast: bool,
+    /// 为新会话创建过滤器的中间件
+    middlewares: Vec<Arc<dyn Middleware>>,
+    /// 从监听 socket 发现公网地址的 STUN 客户端
+    stun: Option<Arc<StunClient>>,
 }
 
 impl UdpHandler {
@@ -41,17 +118,38 @@ impl UdpHandler {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            remote_addr: Address::from_ipv4(std::net::Ipv4Addr::UNSPECIFIED, 0),
+            backends: Arc::new(BackendPool::default()),
             socket_buf_size: 16 * 1024,
             fwd_type: FwdType::Normal,
+            nat64_prefix: Nat64Prefix::default(),
             enable_fragment: false,
+            mtu: None,
             bind_interface: None,
+            transparent: false,
+            bind_source: Vec::new(),
+            source_ports: None,
+            upstream: None,
+            socks_server: None,
+            quic: false,
+            rate_limiter: None,
+            buffers: BufferPool::with_max_idle(DATAGRAM_BUF_SIZE, 4),
+            memory: None,
+            mark: SocketMark::default(),
+            delayed: Mutex::new(DelayQueue::new()),
+            mirror: None,
+            keepalive: None,
+            recverr: false,
+            preserve_tos_ttl: false,
+            multicast_reply: None,
+            broadcast: false,
+            middlewares: Vec::new(),
+            stun: None,
         }
     }
 
-    /// 设置远程地址
-    pub fn set_remote_addr(&mut self, addr: Address) {
-        self.remote_addr = addr;
+    /// 设置后端地址池
+    pub fn set_backends(&mut self, backends: Arc<BackendPool>) {
+        self.backends = backends;
     }
 
     /// 设置缓冲区大小
@@ -64,170 +162,403 @@ impl UdpHandler {
         self.fwd_type = fwd_type;
     }
 
+    pub fn set_nat64_prefix(&mut self, prefix: Nat64Prefix) {
+        self.nat64_prefix = prefix;
+    }
+
     /// 启用/禁用 UDP 分片转发
     pub fn set_enable_fragment(&mut self, enable: bool) {
         self.enable_fragment = enable;
     }
 
+    /// 设置客户端 -> 远程数据包的 IP 包长度上限
+    pub fn set_mtu(&mut self, mtu: Option<usize>) {
+        self.mtu = mtu;
+    }
+
     /// 设置绑定的网络接口
     pub fn set_bind_interface(&mut self, interface: Option<String>) {
         self.bind_interface = interface;
     }
 
-    /// 设置 socket 到指定网络接口 (SO_BINDTODEVICE)
-    #[allow(dead_code)]
-    fn set_bind_to_device(&self, fd: libc::c_int) -> Result<(), std::io::Error> {
-        if let Some(ref interface) = self.bind_interface {
-            if interface.is_empty() {
-                return Ok(());
-            }
-            #[cfg(target_os = "linux")]
-            {
-                let ifreq = {
-                    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
-                    let interface_bytes = interface.as_bytes();
-                    let ifr_name_len = std::mem::size_of::<libc::c_char>() * libc::IFNAMSIZ;
-                    let len = std::cmp::min(interface_bytes.len(), ifr_name_len - 1);
-                    unsafe {
-                        // ifreq.ifr_name 是 *mut i8，需要正确转换
-                        let dest_ptr = ifreq.ifr_name.as_mut_ptr() as *mut libc::c_char;
-                        std::ptr::copy_nonoverlapping(
-                            interface_bytes.as_ptr() as *const libc::c_char,
-                            dest_ptr,
-                            len,
-                        );
-                    }
-                    ifreq
-                };
+    /// 启用/禁用透明代理
+    pub fn set_transparent(&mut self, enable: bool) {
+        self.transparent = enable;
+    }
 
-                let ret = unsafe {
-                    libc::setsockopt(
-                        fd,
-                        libc::SOL_SOCKET,
-                        libc::SO_BINDTODEVICE,
-                        &ifreq as *const _ as *const libc::c_void,
-                        std::mem::size_of::<libc::ifreq>() as libc::socklen_t,
-                    )
-                };
+    /// 设置外连 socket 的源地址
+    pub fn set_bind_source(&mut self, sources: Vec<IpAddr>) {
+        self.bind_source = sources;
+    }
 
-                if ret < 0 {
-                    return Err(std::io::Error::last_os_error());
-                }
-            }
-            #[cfg(not(target_os = "linux"))]
-            {
-                // 非 Linux 平台不支持 SO_BINDTODEVICE
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "SO_BINDTODEVICE is not supported on this platform",
-                ));
-            }
+    /// 设置外连 socket 的源端口范围
+    pub fn set_source_ports(&mut self, ports: Option<PortRange>) {
+        self.source_ports = ports;
+    }
+
+    /// 设置上游 SOCKS5 代理
+    pub fn set_upstream(&mut self, upstream: Option<Arc<Socks5Upstream>>) {
+        self.upstream = upstream;
+    }
+
+    pub fn set_socks_server(&mut self, server: Option<Arc<Socks5Server>>) {
+        self.socks_server = server;
+    }
+
+    /// 启用/禁用 QUIC 连接迁移跟踪
+    pub fn set_quic(&mut self, enable: bool) {
+        self.quic = enable;
+    }
+
+    /// 设置限速器
+    pub fn set_rate_limiter(&mut self, limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// 设置外连 socket 的标记
+    pub fn set_socket_mark(&mut self, mark: SocketMark) {
+        self.mark = mark;
+    }
+
+    /// 设置镜像目标，客户端数据包的副本发往该处
+    pub fn set_mirror(&mut self, mirror: Option<UdpMirror>) {
+        self.mirror = mirror;
+    }
+
+    /// 设置会话保活参数
+    pub fn set_keepalive(&mut self, keepalive: Option<UdpKeepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// 设置是否在会话 socket 上启用 IP_RECVERR
+    pub fn set_recverr(&mut self, enable: bool) {
+        self.recverr = enable;
+    }
+
+    /// 设置是否沿用收到的数据包的 TOS/ECN 和 TTL
+    pub fn set_preserve_tos_ttl(&mut self, enable: bool) {
+        self.preserve_tos_ttl = enable;
+    }
+
+    /// 设置回包发往的组播组
+    pub fn set_multicast_reply(&mut self, group: Option<Address>) {
+        self.multicast_reply = group;
+    }
+
+    /// 设置是否启用广播中继
+    pub fn set_broadcast(&mut self, enable: bool) {
+        self.broadcast = enable;
+    }
+
+    /// 设置为新会话创建过滤器的中间件
+    pub fn set_middlewares(&mut self, middlewares: Vec<Arc<dyn Middleware>>) {
+        self.middlewares = middlewares;
+    }
+
+    /// 设置 STUN 客户端
+    pub fn set_stun(&mut self, stun: Option<Arc<StunClient>>) {
+        self.stun = stun;
+    }
+
+    /// STUN 客户端
+    pub fn stun(&self) -> Option<Arc<StunClient>> {
+        self.stun.clone()
+    }
+
+    /// 到期时从与 STUN 服务端同一地址族的监听 socket 发送绑定请求
+    pub(crate) fn send_stun<'a>(&self, mut listen_sockets: impl Iterator<Item = &'a UdpSocket>) {
+        let Some(ref stun) = self.stun else {
+            return;
+        };
+        let server = stun.server();
+        let Some(socket) = listen_sockets.find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv6() == server.is_ipv6())
+        }) else {
+            return;
+        };
+        let Some(request) = stun.poll_request(crate::log::get_monotonic_time()) else {
+            return;
+        };
+        match socket.send_to(&request, server) {
+            Ok(_) => trace!("[stun] binding request sent to {}", server),
+            Err(e) => debug!("[stun] binding request to {} failed: {}", server, e),
         }
-        Ok(())
     }
 
-    /// 设置分片转发的 socket 选项
+    /// 设置内存预算，收包缓冲区也计入其中
+    pub fn set_memory_budget(&mut self, memory: Option<Arc<MemoryBudget>>) {
+        self.buffers = BufferPool::with_budget(DATAGRAM_BUF_SIZE, 4, memory.clone());
+        self.memory = memory;
+    }
+
+    /// 释放缓冲区池中的空闲缓冲区
+    pub(crate) fn trim_buffers(&self) {
+        self.buffers.trim();
+    }
+
+    /// 检查限速，允许通过时扣除令牌
+    ///
+    /// UDP 无法像 TCP 那样暂停读取（监听 socket 为所有客户端共享），超速的数据包直接丢弃
+    fn rate_limit_pass(&self, session: &Arc<RwLock<UdpSession>>, len: usize) -> bool {
+        let limiter = match self.rate_limiter {
+            Some(ref limiter) => limiter,
+            None => return true,
+        };
+        let mut guard = session.write().recover();
+        if !limiter.check(guard.rate_bucket.as_mut(), len) {
+            trace!(
+                "[udp] rate limited {}, dropping {} bytes",
+                guard.addr_s,
+                len
+            );
+            return false;
+        }
+        limiter.consume(guard.rate_bucket.as_mut(), len);
+        true
+    }
+
+    /// 设置 socket 到指定网络接口 (Linux 为 SO_BINDTODEVICE，macOS 为 IP_BOUND_IF)
     #[allow(dead_code)]
-    fn setup_fragment_socket_options(&self, fd: libc::c_int) -> Result<(), std::io::Error> {
-        if !self.enable_fragment {
-            return Ok(());
+    fn set_bind_to_device(&self, fd: RawFd) -> Result<(), std::io::Error> {
+        match self.bind_interface {
+            Some(ref interface) if !interface.is_empty() => {
+                crate::set_bind_to_device(fd, interface)
+            }
+            _ => Ok(()),
         }
+    }
 
-        // 启用路径 MTU 发现 (IP_MTU_DISCOVER)
-        // IP_PMTUDISC_DO: 总是进行路径 MTU 发现
+    /// 从监听 socket 读取一个数据包，启用 `-d` 或 `--udp-preserve-tos-ttl` 时同时读取 IP 头字段
+    fn recv_client(
+        &self,
+        listen_socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, std::net::SocketAddr, IpHeader)> {
         #[cfg(target_os = "linux")]
-        {
-            let val: libc::c_int = libc::IP_PMTUDISC_DO;
-            unsafe {
-                if libc::setsockopt(
-                    fd,
-                    libc::IPPROTO_IP,
-                    libc::IP_MTU_DISCOVER,
-                    &val as *const _ as *const libc::c_void,
-                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-                ) != 0
-                {
-                    return Err(std::io::Error::last_os_error());
-                }
-            }
+        if self.enable_fragment || self.preserve_tos_ttl {
+            return ipheader::recv_from(listen_socket.as_raw_fd(), buf);
         }
+        listen_socket
+            .recv_from(buf)
+            .map(|(len, addr)| (len, addr, IpHeader::default()))
+    }
 
-        // IPv6 的路径 MTU 发现
+    /// 从会话 socket 读取一个数据包，返回 `(长度, 来源地址, IP 头字段)`
+    ///
+    /// SOCKS5 服务端的会话 socket 未连接，需要数据包的来源地址；来源地址无法解析时为 None
+    fn recv_remote(
+        &self,
+        fd: RawFd,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, Option<Address>, IpHeader)> {
         #[cfg(target_os = "linux")]
-        {
-            let val: libc::c_int = libc::IP_PMTUDISC_DO;
-            unsafe {
-                if libc::setsockopt(
-                    fd,
-                    libc::IPPROTO_IPV6,
-                    libc::IPV6_MTU_DISCOVER,
-                    &val as *const _ as *const libc::c_void,
-                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-                ) != 0
-                {
-                    // IPv6 可能不可用，忽略错误
-                }
-            }
+        if self.preserve_tos_ttl {
+            return ipheader::recv_from(fd, buf)
+                .map(|(len, from, header)| (len, Some(Address::from_sockaddr(from)), header));
+        }
+        let mut from: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut from_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let recv_len = unsafe {
+            libc::recvfrom(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                &mut from as *mut _ as *mut libc::sockaddr,
+                &mut from_len,
+            )
+        };
+        if recv_len < 0 {
+            return Err(io::Error::last_os_error());
         }
+        let from =
+            Address::from_raw_sockaddr(&from as *const _ as *const libc::sockaddr, from_len).ok();
+        Ok((recv_len as usize, from, IpHeader::default()))
+    }
 
-        Ok(())
+    /// 按 `--udp-preserve-tos-ttl` 计算转发时设置的 IP 头字段，TTL 耗尽时返回 None 表示丢弃
+    ///
+    /// 外连方向保留 `--tos` 配置的 DSCP，只沿用 ECN 位
+    fn forward_header(&self, header: &IpHeader, direction: Direction) -> Option<IpHeader> {
+        let dscp = match direction {
+            Direction::ClientToServer => self.mark.tos,
+            Direction::ServerToClient => None,
+        };
+        header.forward(dscp)
     }
 
-    /// 根据转发类型获取远程地址
-    fn get_remote_addr_for_connect(&self) -> Address {
-        match self.fwd_type {
-            FwdType::FwdType4to6 => {
-                if let Some(ipv6_addr) = self.remote_addr.to_ipv4_mapped_ipv6() {
-                    ipv6_addr
-                } else {
-                    self.remote_addr.clone()
+    /// 按 `--mtu` 和 `-d` 处理客户端数据包的分片策略，返回是否发送
+    ///
+    /// 超过 MTU 的数据包完整到达时丢弃，分片到达时照常发送；启用 `-d` 时外连 socket 的 DF
+    /// 跟随数据包是否分片到达，只在变化时设置
+    fn apply_fragment_policy(
+        &self,
+        session_arc: &Arc<RwLock<UdpSession>>,
+        remote_fd: RawFd,
+        len: usize,
+        fragmented: bool,
+    ) -> bool {
+        if !self.enable_fragment && self.mtu.is_none() {
+            return true;
+        }
+        let mut session = session_arc.write().recover();
+        if let Some(mtu) = self.mtu {
+            if !fragment::fits(len, session.remote_ipv6, mtu) {
+                if !fragmented {
+                    info!(
+                        "[udp] #{} datagram of {} bytes from {} exceeds mtu {}, dropped",
+                        session.id, len, session.addr_s, mtu
+                    );
+                    return false;
                 }
+                debug!(
+                    "[udp] #{} fragmented datagram of {} bytes from {} exceeds mtu {}, sent fragmented",
+                    session.id, len, session.addr_s, mtu
+                );
             }
-            FwdType::FwdType6to4 => {
-                if let Some(ipv4_addr) = self.remote_addr.from_ipv4_mapped_ipv6() {
-                    ipv4_addr
-                } else {
-                    self.remote_addr.clone()
-                }
+        }
+        #[cfg(target_os = "linux")]
+        if self.enable_fragment && session.df != Some(!fragmented) {
+            match fragment::set_df(remote_fd, !fragmented) {
+                Ok(()) => session.df = Some(!fragmented),
+                Err(e) => debug!("[udp] #{} set DF failed: {}", session.id, e),
             }
-            _ => self.remote_addr.clone(),
         }
+        #[cfg(not(target_os = "linux"))]
+        let _ = remote_fd;
+        true
     }
 
-    /// 获取远程地址类型（用于创建 socket）
-    #[allow(dead_code)]
-    fn get_remote_addr_family(&self) -> libc::c_int {
-        match self.fwd_type {
-            FwdType::FwdType4to6 => libc::AF_INET6,
-            FwdType::FwdType6to4 => libc::AF_INET,
-            _ => self.remote_addr.get_type() as libc::c_int,
+    /// 根据转发类型获取远程地址
+    fn get_remote_addr_for_connect(&self, remote_addr: &Address) -> Address {
+        translate_addr(remote_addr, self.fwd_type, &self.nat64_prefix)
+    }
+
+    /// 未连接的会话 socket 发往的远程地址：IPv4-mapped IPv6 地址与 `UdpSocketBuilder` 一样换成 IPv4
+    fn session_remote(&self, remote_addr: &Address) -> Address {
+        let remote = self.get_remote_addr_for_connect(remote_addr);
+        remote.from_ipv4_mapped_ipv6().unwrap_or(remote)
+    }
+
+    /// 会话 socket 未连接期间，判断来自 `from` 的回包是否转发
+    ///
+    /// 来自远程原端口的照常转发；有过滤器跟随来自新地址的回包时会话 socket 改为连接到该地址，其余丢弃
+    fn follow_remote(
+        &self,
+        session_arc: &Arc<RwLock<UdpSession>>,
+        fd: RawFd,
+        from: Option<&Address>,
+        packet: &[u8],
+    ) -> bool {
+        let mut session = session_arc.write().recover();
+        let Some(ref backend) = session.backend else {
+            return false;
+        };
+        let remote = self.session_remote(&backend.addr);
+        let Some(from) = from else {
+            return false;
+        };
+        if *from == remote {
+            return true;
+        }
+        let Some(i) = session
+            .filters
+            .iter_mut()
+            .position(|filter| filter.follow(&remote, from, packet))
+        else {
+            trace!(
+                "[udp] #{} unexpected packet from {}, dropped",
+                session.id,
+                from
+            );
+            return false;
+        };
+        let name = session.filters[i].name();
+        if let Err(e) = alg::rebind(fd, from) {
+            warn!(
+                "[udp] #{} {}: failed to follow {} -> {}: {}",
+                session.id, name, remote, from, e
+            );
+            return false;
         }
+        info!(
+            "[udp] #{} {}: following remote {} -> {}",
+            session.id, name, remote, from
+        );
+        session.following = false;
+        true
     }
 
-    /// 处理 UDP 数据包
+    /// 交给会话的过滤器检查或改写数据报，被丢弃时返回 None
+    fn filter_datagram<'a>(
+        &self,
+        session_arc: &Arc<RwLock<UdpSession>>,
+        to_remote: bool,
+        data: &'a [u8],
+    ) -> Option<Cow<'a, [u8]>> {
+        if self.middlewares.is_empty() {
+            return Some(Cow::Borrowed(data));
+        }
+        let mut session = session_arc.write().recover();
+        if session.filters.is_empty() {
+            return Some(Cow::Borrowed(data));
+        }
+        let id = session.id;
+        let mut data = data.to_vec();
+        for filter in session.filters.iter_mut() {
+            if !filter.datagram(to_remote, &mut data) {
+                trace!("[udp] #{} {}: datagram dropped", id, filter.name());
+                return None;
+            }
+        }
+        Some(Cow::Owned(data))
+    }
+
+    /// 处理监听 socket 上的 UDP 数据包
+    ///
+    /// 每次最多读取 `UDP_RECV_BATCH` 个数据包 (水平触发模式下为 1 个)，避免一个繁忙的监听 socket
+    /// 独占事件循环。读完所有数据包时返回 false；达到上限时返回 true，调用方应稍后继续读取
     pub fn on_datagram(
         &self,
         event_loop: &EventLoop,
-        _token: Token,
         listen_socket: &UdpSocket,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<bool, std::io::Error> {
+        for _ in 0..event_loop.config.poll_mode.batch(UDP_RECV_BATCH) {
+            if !self.recv_datagram(event_loop, listen_socket)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 读取并转发一个数据包，没有数据包可读时返回 false
+    fn recv_datagram(
+        &self,
+        event_loop: &EventLoop,
+        listen_socket: &UdpSocket,
+    ) -> Result<bool, std::io::Error> {
         let fd_manager = &event_loop.fd_manager;
         let udp_manager = &event_loop.udp_manager;
 
-        let mut buf = vec![0u8; 65535];
-        let (recv_len, src_addr) = match listen_socket.recv_from(&mut buf) {
+        let mut buf = self.buffers.get();
+        let (recv_len, src_addr, header) = match self.recv_client(listen_socket, &mut buf[..65535])
+        {
             Ok(result) => result,
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
             Err(e) => return Err(e),
         };
 
+        event_loop.stats.add_udp_received(recv_len);
+
         // 创建源地址 (支持 IPv4 和 IPv6)
         let src_address = Address::from_sockaddr(src_addr);
-        let src_addr_s = src_address.to_string();
+        let src_addr_s = crate::log::client_addr(src_addr);
 
         if recv_len > 65535 - 1 {
             warn!("[udp] huge packet from {}, dropped", src_addr_s);
-            return Ok(());
+            return Ok(true);
         }
 
         // 与 C++ 版本保持一致: data[data_len] = 0; (便于调试)
@@ -239,36 +570,200 @@ impl UdpHandler {
             buf.push(0);
         }
 
-        let session_arc = if let Some(existing) = udp_manager.get_session(&src_address) {
+        // STUN 服务端对绑定请求的应答不转发
+        if self
+            .stun
+            .as_ref()
+            .is_some_and(|stun| stun.on_datagram(src_addr, &buf[..recv_len]))
+        {
+            return Ok(true);
+        }
+
+        // SOCKS5 服务端只中继有控制连接的客户端发来的、带 SOCKS5 UDP 头的数据包
+        let socks_target = match self.socks_server {
+            Some(ref server) => match socks5::decode_udp_target(&buf[..recv_len])
+                .filter(|_| server.is_associated(src_addr.ip()))
+            {
+                Some(target) => Some(target),
+                None => {
+                    trace!(
+                        "[udp] not a SOCKS5 relay packet from {}, dropped",
+                        src_addr_s
+                    );
+                    return Ok(true);
+                }
+            },
+            None => None,
+        };
+        let data_start = socks_target.map_or(0, |(_, start)| start);
+
+        let existing = udp_manager.get_session(&src_address).or_else(|| {
+            self.migrate_quic(udp_manager, &buf[..recv_len], &src_address, &src_addr_s)
+        });
+        let session_arc = if let Some(existing) = existing {
             trace!("[udp] found existing session for {}", src_addr_s);
             existing
         } else {
+            if event_loop.is_draining() {
+                trace!(
+                    "[udp] draining, dropping packet from new peer {}",
+                    src_addr_s
+                );
+                return Ok(true);
+            }
+            if event_loop.is_banned(src_addr) {
+                trace!("[udp] {} is banned, dropping packet", src_addr_s);
+                return Ok(true);
+            }
+            if let Some(geo) = event_loop.geo() {
+                let info = geo.lookup(src_addr.ip());
+                if !geo.allows(&info) {
+                    debug!(
+                        "[udp] {} [{}] denied by geo policy, dropping packet",
+                        src_addr_s, info
+                    );
+                    event_loop.reject(Protocol::Udp, src_addr, &src_addr_s, RejectReason::Geo);
+                    return Ok(true);
+                }
+            }
+            if let Some(reason) = event_loop.tenant_check(src_addr, 0) {
+                debug!(
+                    "[udp] {} rejected by tenant limits ({}), dropping packet",
+                    src_addr_s, reason
+                );
+                event_loop.reject(Protocol::Udp, src_addr, &src_addr_s, reason);
+                return Ok(true);
+            }
             if udp_manager.len() >= event_loop.config.max_connections {
-                info!(
+                debug!(
                     "[udp] max connections reached, dropping packet from {}",
                     src_addr_s
                 );
-                return Ok(());
+                event_loop.reject(
+                    Protocol::Udp,
+                    src_addr,
+                    &src_addr_s,
+                    RejectReason::MaxConnections,
+                );
+                return Ok(true);
+            }
+            if let Some(ref memory) = self.memory {
+                if !memory.has_room(UDP_SESSION_MEMORY) {
+                    memory.record_refused();
+                    debug!(
+                        "[udp] memory budget exhausted ({}/{} bytes), dropping packet from {}",
+                        memory.used(),
+                        memory.limit(),
+                        src_addr_s
+                    );
+                    event_loop.reject(Protocol::Udp, src_addr, &src_addr_s, RejectReason::Memory);
+                    return Ok(true);
+                }
             }
 
-            // 与 Go 版本保持一致：使用 Address::new_connected_udp_fd 创建已连接的 UDP socket
-            // 这样可以正确处理 IPv4/IPv6 地址转换
-            let remote_addr_for_connect = self.get_remote_addr_for_connect();
-            let udp_fd = match remote_addr_for_connect.new_connected_udp_fd(self.socket_buf_size) {
-                Ok(fd) => fd,
+            // 与 Go 版本保持一致：每个会话使用已连接的 UDP socket，
+            // IPv4-mapped IPv6 后端由 UdpSocketBuilder 改用 IPv4 socket
+            let picked = match (socks_target, &self.socks_server) {
+                (Some((target, _)), Some(server)) => Some(server.backend(target)),
+                _ => self.backends.pick_for(&src_address),
+            };
+            let backend = match picked {
+                Some(backend) => backend,
+                None => {
+                    warn!(
+                        "[udp] no remote address, dropping packet from {}",
+                        src_addr_s
+                    );
+                    return Ok(true);
+                }
+            };
+            let remote_addr_for_connect = self.get_remote_addr_for_connect(&backend.addr);
+            let filters: Vec<_> = self
+                .middlewares
+                .iter()
+                .filter_map(|middleware| middleware.udp_filter(&remote_addr_for_connect))
+                .collect();
+            let following = filters.iter().any(|filter| filter.unconnected());
+            // SOCKS5 服务端的会话不连接，按数据包中的目标 sendto
+            let connected = if self.socks_server.is_some() {
+                socks5::new_relay_udp_fd(
+                    &backend.addr,
+                    self.socket_buf_size,
+                    &self.bind_source,
+                    self.source_ports,
+                )
+                .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+            } else if let Some(ref upstream) = self.upstream {
+                socks5::new_relay_udp_fd(
+                    &upstream.proxy,
+                    self.socket_buf_size,
+                    &self.bind_source,
+                    self.source_ports,
+                )
+                .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+            } else if self.broadcast {
+                // 广播中继的会话不连接：远程为广播地址时回包来自实际应答的主机
+                socks5::new_relay_udp_fd(
+                    &remote_addr_for_connect,
+                    self.socket_buf_size,
+                    &self.bind_source,
+                    self.source_ports,
+                )
+                .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+                .and_then(|socket| crate::set_broadcast(socket.as_raw_fd()).map(|()| socket))
+            } else {
+                let builder = UdpSocketBuilder::new()
+                    .buf_size(self.socket_buf_size)
+                    .source(&self.bind_source, self.source_ports)
+                    .transparent_source(self.transparent.then_some(src_addr));
+                // 过滤器要跟随远程新端口的会话先不连接，等待远程从新端口回包
+                if following {
+                    builder.unconnected(&remote_addr_for_connect)
+                } else {
+                    builder.connect(&remote_addr_for_connect)
+                }
+                .map_err(io::Error::from)
+            };
+            let remote_socket = match connected {
+                Ok(socket) => socket,
                 Err(e) => {
                     info!(
                         "[udp] create connected udp socket failed for {} -> {}: {}",
                         src_addr_s, remote_addr_for_connect, e
                     );
-                    return Ok(());
+                    return Ok(true);
                 }
             };
 
-            let now = crate::log::get_current_time();
+            // 已连接的 socket 修改 SO_MARK 时内核会重新查路由
+            if !self.mark.is_empty() {
+                if let Err(e) = crate::set_socket_mark(remote_socket.as_raw_fd(), &self.mark) {
+                    debug!("[udp] set socket mark for {} failed: {}", src_addr_s, e);
+                }
+            }
 
-            // 添加 remote socket 的 fd 到 fd_manager
-            let remote_fd64 = fd_manager.create(udp_fd, now);
+            // SOCKS5 服务端和广播中继的会话 socket 不连接，单个目标不可达不关闭会话
+            if self.recverr && self.socks_server.is_none() && !self.broadcast {
+                #[cfg(target_os = "linux")]
+                if let Err(e) = set_recverr(remote_socket.as_raw_fd()) {
+                    debug!("[udp] set IP_RECVERR for {} failed: {}", src_addr_s, e);
+                }
+            }
+
+            #[cfg(target_os = "linux")]
+            if self.preserve_tos_ttl {
+                if let Err(e) = ipheader::enable_recv_tos_ttl(remote_socket.as_raw_fd()) {
+                    debug!("[udp] set IP_RECVTOS for {} failed: {}", src_addr_s, e);
+                }
+            }
+
+            let now = crate::log::get_monotonic_time();
+
+            // remote socket 交给 fd_manager 持有
+            let udp_fd = remote_socket.as_raw_fd();
+            let remote_ipv6 =
+                (self.mtu.is_some() || self.preserve_tos_ttl) && fragment::is_ipv6_socket(udp_fd);
+            let remote_fd64 = fd_manager.insert(Source::Udp(remote_socket), now);
 
             // 添加 listen socket 的 fd 到 fd_manager（如果尚未添加）
             let listen_raw_fd = listen_socket.as_raw_fd();
@@ -280,30 +775,17 @@ impl UdpHandler {
                 listen_fd64
             );
 
-            let poll = &event_loop.poll;
             let token_manager = &event_loop.token_manager;
-            let mut token_manager_guard = token_manager.write().expect("token_manager poisoned");
+            let mut token_manager_guard = token_manager.write().recover();
             let tok = token_manager_guard.generate_token(remote_fd64);
 
-            // 创建 UdpSocket 用于注册（不获取所有权）
-            #[cfg(unix)]
-            let mut remote_socket = unsafe { UdpSocket::from_raw_fd(udp_fd) };
-            #[cfg(windows)]
-            let mut remote_socket =
-                unsafe { UdpSocket::from_raw_socket(udp_fd as std::os::windows::io::RawSocket) };
-            if let Err(e) =
-                poll.registry()
-                    .register(&mut remote_socket, tok, mio::Interest::READABLE)
-            {
+            if let Err(e) = event_loop.register_source(remote_fd64, tok, mio::Interest::READABLE) {
                 warn!("[udp] failed to register remote socket: {}", e);
-                unsafe { libc::close(udp_fd) };
-                return Ok(());
+                token_manager_guard.remove(&remote_fd64);
+                fd_manager.close(remote_fd64);
+                return Ok(true);
             }
             trace!("[udp] registered remote socket with token {:?}", tok);
-            #[cfg(unix)]
-            let _ = remote_socket.into_raw_fd(); // 防止 drop 时关闭
-            #[cfg(windows)]
-            let _ = remote_socket.into_raw_socket(); // 防止 drop 时关闭
 
             // 使用 get_or_create 返回的 listen_fd64
             let session = udp_manager.new_session(
@@ -314,13 +796,45 @@ impl UdpHandler {
                 now,
             );
 
+            let id = {
+                let mut session = session.write().recover();
+                if let Some(ref limiter) = self.rate_limiter {
+                    session.rate_bucket = limiter.new_conn_bucket();
+                }
+                backend.stats.inc_udp_sessions();
+                session.backend = Some(Arc::clone(&backend));
+                session.remote_ipv6 = remote_ipv6;
+                session.filters = filters;
+                session.following = following;
+                if let Some(ref upstream) = self.upstream {
+                    let association =
+                        Arc::new(Socks5Association::new(remote_addr_for_connect.clone()));
+                    session.socks = Some(Arc::clone(&association));
+                    if let Err(e) = socks5::spawn_associate(
+                        Arc::clone(upstream),
+                        association,
+                        udp_fd,
+                        src_addr_s.clone(),
+                    ) {
+                        warn!("[udp] failed to start upstream associate: {}", e);
+                    }
+                }
+                session.id
+            };
+
             // 更新统计
-            TrafficStats::global().inc_udp_sessions();
+            event_loop.stats.inc_udp_sessions();
+            event_loop
+                .observers
+                .notify(|o| o.on_udp_session(&src_addr_s, &remote_addr_for_connect));
 
             // 与 C++ 版本保持一致：打印 udp fd 和 sessions
             info!(
-                "[udp] new connection from {}, udp fd={}, udp connections={}",
+                "[udp] #{} new connection from {}{} to {}, udp fd={}, udp connections={}",
+                id,
                 src_addr_s,
+                event_loop.geo_tag(src_addr),
+                backend.addr,
                 udp_fd,
                 udp_manager.len()
             );
@@ -328,147 +842,687 @@ impl UdpHandler {
             session
         };
 
+        if !self.rate_limit_pass(&session_arc, recv_len - data_start) {
+            // 客户端自身超出单会话速率时报告拒绝 (同一 IP 节流)，全局限速不算客户端的问题
+            let conn_limited = self.rate_limiter.as_ref().is_some_and(|limiter| {
+                let mut session = session_arc.write().recover();
+                limiter.conn_limited(session.rate_bucket.as_mut(), recv_len - data_start)
+            });
+            if conn_limited {
+                event_loop.reject(
+                    Protocol::Udp,
+                    src_addr,
+                    &src_addr_s,
+                    RejectReason::RateLimit,
+                );
+            }
+            return Ok(true);
+        }
+        if let Some(ref mirror) = self.mirror {
+            mirror.send(&buf[data_start..recv_len]);
+        }
+
+        // 客户端 Initial 包的目标连接 ID，握手完成前地址变化时据此找回会话
+        if self.quic {
+            if let Some(header) = quic::parse_long_header(&buf[..recv_len]) {
+                udp_manager.add_quic_cid(&src_address, header.dcid);
+            }
+        }
+
+        let Some(data) = self.filter_datagram(&session_arc, true, &buf[..recv_len]) else {
+            return Ok(true);
+        };
+
         // 获取会话信息并发送
-        let session_fd64 = {
-            let guard = session_arc.read().expect("session poisoned");
-            guard.fd64
+        let (session_fd64, socks) = {
+            let guard = session_arc.read().recover();
+            (guard.fd64, guard.socks.clone())
         };
 
         // 直接使用 raw fd 发送，避免 UdpSocket drop 时关闭 fd
         let remote_fd = match fd_manager.to_fd(session_fd64) {
             Some(fd) => fd,
-            None => return Ok(()),
+            None => return Ok(true),
         };
         // 与 C++ 版本保持一致：使用 recv_len 而非 buf.len()
-        let send_len =
-            unsafe { libc::send(remote_fd, buf.as_ptr() as *const libc::c_void, recv_len, 0) };
+        let packet;
+        let payload = match socks {
+            Some(ref association) => {
+                let encoded = socks5::encode_udp(&association.target, &data);
+                match association.queue(encoded) {
+                    Some(encoded) => packet = encoded,
+                    None => {
+                        trace!("[udp] upstream relay for {} not ready yet", src_addr_s);
+                        return Ok(true);
+                    }
+                }
+                &packet[..]
+            }
+            None => &data[..],
+        };
+        if !self.apply_fragment_policy(&session_arc, remote_fd, payload.len(), header.fragmented) {
+            return Ok(true);
+        }
+        let Some(header) = self.forward_header(&header, Direction::ClientToServer) else {
+            trace!("[udp] ttl of datagram from {} expired, dropped", src_addr_s);
+            return Ok(true);
+        };
+        let stats_len = data.len() - data_start;
+        if self.chaos_hold(
+            event_loop,
+            session_fd64,
+            Direction::ClientToServer,
+            payload,
+            stats_len,
+            header,
+        ) {
+            return Ok(true);
+        }
+        self.send_to_remote(
+            event_loop,
+            &session_arc,
+            remote_fd,
+            &src_address,
+            payload,
+            stats_len,
+            &header,
+        );
 
-        // 更新发送统计
-        TrafficStats::global().add_udp_sent(send_len as usize);
+        Ok(true)
+    }
 
-        if send_len < 0 {
-            let err = std::io::Error::last_os_error();
-            warn!("[udp] send failed to remote: {}", err);
-        } else {
-            udp_manager.update_lru(&src_address);
+    /// 来自新地址的数据包带有已登记的 QUIC 连接 ID 时，将原会话迁移到新地址
+    fn migrate_quic(
+        &self,
+        udp_manager: &UdpSessionManager,
+        packet: &[u8],
+        src_address: &Address,
+        src_addr_s: &str,
+    ) -> Option<Arc<RwLock<UdpSession>>> {
+        if !self.quic {
+            return None;
         }
-
-        Ok(())
+        let from = udp_manager.find_quic(packet)?;
+        let from_s = udp_manager
+            .get_session(&from)?
+            .read()
+            .recover()
+            .addr_s
+            .clone();
+        let session = udp_manager.migrate(&from, src_address.clone(), src_addr_s.to_string())?;
+        info!(
+            "[udp] QUIC connection migrated from {} to {}",
+            from_s, src_addr_s
+        );
+        Some(session)
     }
 
     /// 处理远程响应
-    pub fn on_response(
-        &self,
-        event_loop: &EventLoop,
-        _token: Token,
-        fd64: Fd64,
-    ) -> Result<(), std::io::Error> {
-        const MAX_DATA_LEN_UDP: usize = 65536;
+    ///
+    /// 与 `on_datagram` 相同，每次最多读取 `UDP_RECV_BATCH` 个数据包，达到上限时返回 true
+    pub fn on_response(&self, event_loop: &EventLoop, fd64: Fd64) -> Result<bool, std::io::Error> {
+        for _ in 0..event_loop.config.poll_mode.batch(UDP_RECV_BATCH) {
+            if !self.recv_response(event_loop, fd64)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 
+    /// 读取并转发一个远程响应，没有数据包可读或会话已关闭时返回 false
+    fn recv_response(&self, event_loop: &EventLoop, fd64: Fd64) -> Result<bool, std::io::Error> {
         let fd_manager = &event_loop.fd_manager;
         let udp_manager = &event_loop.udp_manager;
 
         if !fd_manager.exist(fd64) {
             trace!("[udp] on_response: fd64 {:?} does not exist", fd64);
-            return Ok(());
+            return Ok(false);
         }
 
         let fd = match fd_manager.to_fd(fd64) {
             Some(f) => f,
-            None => return Ok(()),
+            None => return Ok(false),
         };
 
         trace!("[udp] on_response: reading from fd {}", fd);
-        let mut buf = vec![0u8; MAX_DATA_LEN_UDP + 1];
-        let recv_len =
-            unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
-
-        // 更新接收统计
-        TrafficStats::global().add_udp_received(recv_len as usize);
-
-        if recv_len < 0 {
-            let err = std::io::Error::last_os_error();
-            warn!("[udp] recv from remote failed: {}", err);
-            return Ok(());
-        }
+        let mut buf = self.buffers.get();
+        let (recv_len, from, header) = match self.recv_remote(fd, &mut buf) {
+            Ok(result) => result,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => {
+                #[cfg(target_os = "linux")]
+                if self.on_error_queue(event_loop, fd64) {
+                    return Ok(false);
+                }
+                // ICMP 等错误只影响一次读取，继续读取之后的数据包
+                warn!("[udp] recv from remote failed: {}", err);
+                return Ok(true);
+            }
+        };
 
         if recv_len == 0 {
             trace!("[udp] on_response: recv_len = 0, no data");
-            return Ok(());
+            return Ok(true);
         }
 
         trace!("[udp] on_response: received {} bytes from remote", recv_len);
+        event_loop.stats.add_udp_received(recv_len);
 
         // 检查是否超大包（类似C++版本的处理）
-        if recv_len == (MAX_DATA_LEN_UDP + 1) as isize {
+        if recv_len == DATAGRAM_BUF_SIZE {
             // 获取会话地址用于日志
             if let Some(session_arc) = udp_manager.get_session_by_fd64(&fd64) {
-                let guard = session_arc.read().expect("session poisoned");
-                warn!("[udp] huge packet from {}, dropped", guard.address);
+                let guard = session_arc.read().recover();
+                warn!("[udp] huge packet from {}, dropped", guard.addr_s);
             }
-            return Ok(());
+            return Ok(true);
         }
 
-        buf.truncate(recv_len as usize);
+        let packet = &buf[..recv_len];
 
         // 使用 O(1) 查找获取会话
         let session_arc = match udp_manager.get_session_by_fd64(&fd64) {
             Some(s) => s,
             None => {
                 warn!("[udp] on_response: no session found for fd64 {:?}", fd64);
-                return Ok(());
+                return Ok(false);
+            }
+        };
+
+        let following = session_arc.read().recover().following;
+        if following && !self.follow_remote(&session_arc, fd, from.as_ref(), packet) {
+            return Ok(true);
+        }
+
+        if !self.rate_limit_pass(&session_arc, recv_len) {
+            return Ok(true);
+        }
+
+        // 经 SOCKS5 中继时去掉中继头
+        let socks = session_arc.read().recover().socks.clone();
+        let payload_start = match socks {
+            Some(ref association) => {
+                if !association.is_connected() {
+                    return Ok(true);
+                }
+                match socks5::decode_udp(packet) {
+                    Some(start) => start,
+                    None => {
+                        trace!("[udp] malformed packet from upstream relay, dropped");
+                        return Ok(true);
+                    }
+                }
             }
+            None => 0,
+        };
+        let Some(payload) = self.filter_datagram(&session_arc, false, &packet[payload_start..])
+        else {
+            return Ok(true);
         };
+        let payload = &payload[..];
 
         let (listen_fd, dest_addr, session_addr) = {
-            let guard = session_arc.read().expect("session poisoned");
+            let guard = session_arc.read().recover();
             let lfd = guard.local_listen_fd;
             let addr = guard.address.clone();
             let addr_clone = guard.address.clone();
             (lfd, addr, addr_clone)
         };
+        let Some(header) = self.forward_header(&header, Direction::ServerToClient) else {
+            trace!("[udp] ttl of datagram for {} expired, dropped", dest_addr);
+            return Ok(true);
+        };
+
+        // 服务端选择的源连接 ID 即客户端之后短包头中的目标连接 ID
+        if self.quic {
+            if let Some(header) = quic::parse_long_header(payload) {
+                udp_manager.add_quic_cid(&session_addr, header.scid);
+            }
+        }
 
-        let listen_raw_fd = match fd_manager.to_fd(listen_fd) {
+        // 作为 SOCKS5 服务端时加上 SOCKS5 UDP 头，标明回包来自哪个目标
+        let wrapped;
+        let (payload, stats_len) = match self.socks_server {
+            Some(_) => {
+                let Some(source) = from else {
+                    return Ok(true);
+                };
+                wrapped = socks5::encode_udp(&source, payload);
+                (&wrapped[..], payload.len())
+            }
+            None => (payload, payload.len()),
+        };
+
+        if self.chaos_hold(
+            event_loop,
+            fd64,
+            Direction::ServerToClient,
+            payload,
+            stats_len,
+            header,
+        ) {
+            return Ok(true);
+        }
+        self.send_to_client(
+            event_loop,
+            &session_arc,
+            listen_fd,
+            &dest_addr,
+            payload,
+            stats_len,
+            &header,
+        );
+
+        Ok(true)
+    }
+
+    /// 经会话的外连 socket 发给远程并更新统计
+    #[allow(clippy::too_many_arguments)]
+    fn send_to_remote(
+        &self,
+        event_loop: &EventLoop,
+        session_arc: &Arc<RwLock<UdpSession>>,
+        remote_fd: RawFd,
+        src_address: &Address,
+        payload: &[u8],
+        stats_len: usize,
+        header: &IpHeader,
+    ) {
+        let (remote_ipv6, backend, following) = {
+            let session = session_arc.read().recover();
+            (
+                session.remote_ipv6,
+                session.backend.clone(),
+                session.following,
+            )
+        };
+        let send_len = if self.socks_server.is_some() {
+            // 去掉 SOCKS5 UDP 头后发往头中的目标
+            let Some((target, start)) = socks5::decode_udp_target(payload) else {
+                return;
+            };
+            send_marked(
+                remote_fd,
+                &payload[start..],
+                Some(&Address::from_sockaddr(target)),
+                header,
+                remote_ipv6,
+            )
+        } else if self.broadcast || following {
+            let Some(backend) = backend else {
+                return;
+            };
+            let remote = if following {
+                self.session_remote(&backend.addr)
+            } else {
+                self.get_remote_addr_for_connect(&backend.addr)
+            };
+            send_marked(remote_fd, payload, Some(&remote), header, remote_ipv6)
+        } else {
+            send_marked(remote_fd, payload, None, header, remote_ipv6)
+        };
+        if send_len < 0 {
+            let err = std::io::Error::last_os_error();
+            warn!("[udp] send failed to remote: {}", err);
+        } else {
+            // 统计中不计入 SOCKS5 中继头
+            let send_len = send_len.min(stats_len as isize);
+            event_loop
+                .stats
+                .add_udp_sent(Direction::ClientToServer, send_len as usize);
+            let mut session = session_arc.write().recover();
+            session.update_active(Direction::ClientToServer);
+            session.bytes_up += send_len as u64;
+            session.packets_up += 1;
+            if let Some(ref backend) = session.backend {
+                backend.stats.add_bytes_up(send_len as usize);
+            }
+            drop(session);
+            event_loop.udp_manager.update_lru(src_address);
+        }
+    }
+
+    /// 向客户端方向空闲超过保活间隔的会话的远程发送保活数据包，保持沿途防火墙和 NAT 的映射
+    ///
+    /// 保活数据包不计入统计，也不刷新会话的活跃时间，客户端一直不回来时会话仍按超时清理
+    pub fn send_keepalives(&self, event_loop: &EventLoop) {
+        let Some(ref keepalive) = self.keepalive else {
+            return;
+        };
+        let now = crate::log::get_monotonic_time();
+        let interval = keepalive.interval.as_millis() as u64;
+        let sessions = event_loop.udp_manager.sessions.read().recover();
+        for session_arc in sessions.values() {
+            let mut session = session_arc.write().recover();
+            if now < session.last_up_time.max(session.keepalive_time) + interval {
+                continue;
+            }
+            session.keepalive_time = now;
+            let Some(remote_fd) = event_loop.fd_manager.to_fd(session.fd64) else {
+                continue;
+            };
+            let send_len = if let Some(ref association) = session.socks {
+                // 上游中继未就绪时没有可保活的映射
+                if !association.is_connected() {
+                    continue;
+                }
+                let packet = socks5::encode_udp(&association.target, &keepalive.payload);
+                send_datagram(remote_fd, &packet, None)
+            } else if self.socks_server.is_some() || self.broadcast || session.following {
+                // SOCKS5 服务端、广播中继和等待跟随新端口的会话 socket 未连接，发往会话的首个目标 (远程)
+                let Some(ref backend) = session.backend else {
+                    continue;
+                };
+                let remote = if session.following {
+                    self.session_remote(&backend.addr)
+                } else {
+                    self.get_remote_addr_for_connect(&backend.addr)
+                };
+                send_datagram(remote_fd, &keepalive.payload, Some(&remote))
+            } else {
+                send_datagram(remote_fd, &keepalive.payload, None)
+            };
+            if send_len < 0 {
+                debug!(
+                    "[udp] keepalive for {} failed: {}",
+                    session.addr_s,
+                    io::Error::last_os_error()
+                );
+            } else {
+                trace!("[udp] keepalive sent for {}", session.addr_s);
+            }
+        }
+    }
+
+    /// 读取会话 socket 错误队列中的错误，其中有 ICMP 错误 (端口不可达、需要分片等) 时关闭会话
+    ///
+    /// TTL 超时不关闭会话：沿用客户端 TTL 时 traceroute 的探测包会在沿途超时。
+    /// 返回会话是否已关闭；未启用 `--udp-recverr` 时不读取
+    #[cfg(target_os = "linux")]
+    pub(crate) fn on_error_queue(&self, event_loop: &EventLoop, fd64: Fd64) -> bool {
+        if !self.recverr {
+            return false;
+        }
+        let Some(fd) = event_loop.fd_manager.to_fd(fd64) else {
+            return false;
+        };
+        let mut icmp = None;
+        while let Some((origin, icmp_type, errno)) = recv_queued_error(fd) {
+            let time_exceeded = match origin {
+                libc::SO_EE_ORIGIN_ICMP => icmp_type == ICMP_TIME_EXCEEDED,
+                libc::SO_EE_ORIGIN_ICMP6 => icmp_type == ICMPV6_TIME_EXCEEDED,
+                _ => continue,
+            };
+            if !time_exceeded {
+                icmp.get_or_insert(io::Error::from_raw_os_error(errno));
+            }
+        }
+        let Some(err) = icmp else {
+            return false;
+        };
+        let Some(session_arc) = event_loop.udp_manager.get_session_by_fd64(&fd64) else {
+            return false;
+        };
+        let address = {
+            let session = session_arc.read().recover();
+            info!(
+                "[udp] #{} remote of {} unreachable: {}, closing session",
+                session.id, session.addr_s, err
+            );
+            session.address.clone()
+        };
+        event_loop.close_udp(&address, CloseReason::Unreachable);
+        true
+    }
+
+    /// 经监听 socket 发回客户端并更新统计
+    #[allow(clippy::too_many_arguments)]
+    fn send_to_client(
+        &self,
+        event_loop: &EventLoop,
+        session_arc: &Arc<RwLock<UdpSession>>,
+        listen_fd: Fd64,
+        dest_addr: &Address,
+        payload: &[u8],
+        stats_len: usize,
+        header: &IpHeader,
+    ) {
+        let listen_raw_fd = match event_loop.fd_manager.to_fd(listen_fd) {
             Some(fd) => fd,
             None => {
                 warn!("[udp] on_response: listen_fd not found");
-                return Ok(());
+                return;
             }
         };
 
         trace!(
             "[udp] on_response: sending {} bytes to client {} via listen_fd {}",
-            recv_len,
-            session_addr,
+            payload.len(),
+            dest_addr,
             listen_raw_fd
         );
 
-        let dest_sockaddr = dest_addr.to_sockaddr_storage();
-        let sockaddr_len = dest_addr.get_len() as libc::socklen_t;
-
-        let send_len = unsafe {
-            libc::sendto(
-                listen_raw_fd,
-                buf.as_ptr() as *const libc::c_void,
-                recv_len as usize,
-                0,
-                &dest_sockaddr as *const _ as *const libc::sockaddr,
-                sockaddr_len,
-            )
-        };
-
-        // 更新发送到客户端的统计
-        TrafficStats::global().add_udp_sent(send_len as usize);
+        // --multicast-reply 时发往组播组，统计和 LRU 仍按客户端的会话计算
+        let dest = self.multicast_reply.as_ref().unwrap_or(dest_addr);
+        let send_len = send_marked(listen_raw_fd, payload, Some(dest), header, false);
 
         if send_len < 0 {
             let err = std::io::Error::last_os_error();
             warn!("[udp] sendto to client failed: {}", err);
         } else {
-            udp_manager.update_lru(&session_addr);
+            // 统计中不计入 SOCKS5 UDP 头
+            let send_len = send_len.min(stats_len as isize);
+            event_loop
+                .stats
+                .add_udp_sent(Direction::ServerToClient, send_len as usize);
+            let mut session = session_arc.write().recover();
+            session.update_active(Direction::ServerToClient);
+            session.bytes_down += send_len as u64;
+            session.packets_down += 1;
+            if let Some(ref backend) = session.backend {
+                backend.stats.add_bytes_down(send_len as usize);
+            }
+            drop(session);
+            event_loop.udp_manager.update_lru(dest_addr);
         }
+    }
 
+    /// --chaos：按概率丢弃数据包，或放入延迟队列等到期后发出。返回 true 时数据包已被接管
+    fn chaos_hold(
+        &self,
+        event_loop: &EventLoop,
+        session_fd64: Fd64,
+        direction: Direction,
+        payload: &[u8],
+        stats_len: usize,
+        header: IpHeader,
+    ) -> bool {
+        let Some(chaos) = event_loop.config.chaos else {
+            return false;
+        };
+        if chaos.should_drop() {
+            trace!(
+                "[udp] chaos: dropped {} bytes ({:?})",
+                payload.len(),
+                direction
+            );
+            return true;
+        }
+        if !chaos.delays() {
+            return false;
+        }
+        let datagram = DelayedDatagram {
+            session_fd64,
+            direction,
+            payload: payload.to_vec(),
+            stats_len,
+            header,
+        };
+        let due = crate::clock::now() + chaos.sample_delay();
+        if !self.delayed.lock().recover().push(due, datagram) {
+            trace!(
+                "[udp] chaos: delay queue full, dropped {} bytes",
+                payload.len()
+            );
+        }
+        true
+    }
+
+    /// 发出延迟队列中已到期的数据包，会话已关闭的丢弃
+    pub fn release_delayed(&self, event_loop: &EventLoop) {
+        let now = crate::clock::now();
+        loop {
+            let Some(datagram) = self.delayed.lock().recover().pop_due(now) else {
+                return;
+            };
+            let fd64 = datagram.session_fd64;
+            let Some(session_arc) = event_loop.udp_manager.get_session_by_fd64(&fd64) else {
+                continue;
+            };
+            let (listen_fd, address) = {
+                let session = session_arc.read().recover();
+                (session.local_listen_fd, session.address.clone())
+            };
+            match datagram.direction {
+                Direction::ClientToServer => {
+                    if let Some(remote_fd) = event_loop.fd_manager.to_fd(fd64) {
+                        self.send_to_remote(
+                            event_loop,
+                            &session_arc,
+                            remote_fd,
+                            &address,
+                            &datagram.payload,
+                            datagram.stats_len,
+                            &datagram.header,
+                        );
+                    }
+                }
+                Direction::ServerToClient => self.send_to_client(
+                    event_loop,
+                    &session_arc,
+                    listen_fd,
+                    &address,
+                    &datagram.payload,
+                    datagram.stats_len,
+                    &datagram.header,
+                ),
+            }
+        }
+    }
+
+    /// 距离延迟队列中下一个数据包到期的时间，以 `max` 为上限
+    pub fn next_delayed(&self, max: Duration) -> Duration {
+        match self.delayed.lock().recover().next_due() {
+            Some(due) => due.saturating_duration_since(crate::clock::now()).min(max),
+            None => max,
+        }
+    }
+}
+
+/// 启用 IP_RECVERR/IPV6_RECVERR，按 socket 的地址族只有一个会成功
+#[cfg(target_os = "linux")]
+fn set_recverr(fd: RawFd) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let set = |level, name| unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    };
+    if set(libc::SOL_IP, libc::IP_RECVERR) | set(libc::SOL_IPV6, libc::IPV6_RECVERR) {
         Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 读取错误队列中的一条错误，返回 `(ee_origin, ee_type, ee_errno)`；错误队列为空时返回 None
+#[cfg(target_os = "linux")]
+fn recv_queued_error(fd: RawFd) -> Option<(u8, u8, i32)> {
+    // 错误队列中的消息还带有触发错误的原始数据包，只需要控制消息
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE) };
+    if ret < 0 {
+        return None;
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        let is_recverr = (hdr.cmsg_level == libc::SOL_IP && hdr.cmsg_type == libc::IP_RECVERR)
+            || (hdr.cmsg_level == libc::SOL_IPV6 && hdr.cmsg_type == libc::IPV6_RECVERR);
+        if is_recverr {
+            let err = unsafe {
+                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+            };
+            return Some((err.ee_origin, err.ee_type, err.ee_errno as i32));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
     }
+    Some((libc::SO_EE_ORIGIN_NONE, 0, 0))
+}
+
+/// 用 raw fd 发送一个数据包，`dest` 为 None 时发往 socket 已连接的地址
+fn send_datagram(fd: RawFd, payload: &[u8], dest: Option<&Address>) -> isize {
+    match dest {
+        Some(dest) => {
+            let addr = dest.to_sockaddr_storage();
+            unsafe {
+                libc::sendto(
+                    fd,
+                    payload.as_ptr() as *const libc::c_void,
+                    payload.len(),
+                    0,
+                    &addr as *const _ as *const libc::sockaddr,
+                    dest.get_len() as libc::socklen_t,
+                )
+            }
+        }
+        None => unsafe {
+            libc::send(
+                fd,
+                payload.as_ptr() as *const libc::c_void,
+                payload.len(),
+                0,
+            )
+        },
+    }
+}
+
+/// 发送一个数据包，`header` 带 TOS 或 TTL 时逐包设置 (仅 Linux)，否则同 `send_datagram`
+fn send_marked(
+    fd: RawFd,
+    payload: &[u8],
+    dest: Option<&Address>,
+    header: &IpHeader,
+    ipv6: bool,
+) -> isize {
+    #[cfg(target_os = "linux")]
+    if header.has_marks() {
+        return ipheader::send_to(fd, payload, dest, header, ipv6);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (header, ipv6);
+    send_datagram(fd, payload, dest)
+}
+
+/// --chaos 延迟转发的数据包
+#[derive(Debug)]
+struct DelayedDatagram {
+    /// 会话的外连 socket
+    session_fd64: Fd64,
+    direction: Direction,
+    payload: Vec<u8>,
+    /// 计入统计的字节数 (不含 SOCKS5 UDP 头)
+    stats_len: usize,
+    /// 发送时设置的 TOS 和 TTL
+    header: IpHeader,
 }
 
 impl Default for UdpHandler {
@@ -1,14 +1,20 @@
 //! 文件描述符管理器
 //!
-//! 管理 RawFd 和 Fd64 之间的映射关系
+//! 管理 RawFd 和 Fd64 之间的映射关系，并持有注册到事件循环的 socket。
+//! Fd64 由 slab 分配并复用槽位
 
+use crate::slab::Slab;
+use crate::sync::{Recover, RwLock};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio::{Interest, Registry, Token};
 use std::collections::HashMap;
+use std::io;
 #[cfg(unix)]
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(windows)]
-use std::os::windows::io::RawSocket as RawFd;
+use std::os::windows::io::{AsRawSocket, RawSocket as RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 /// 抽象的文件描述符类型（u64 包装）
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -42,153 +48,224 @@ impl FdInfo {
     /// 更新活跃时间
     pub fn update_active(&self) {
         self.last_active_time
-            .store(crate::log::get_current_time(), Ordering::Relaxed);
+            .store(crate::log::get_monotonic_time(), Ordering::Relaxed);
     }
 }
 
+/// 注册到事件循环的 socket
+#[derive(Debug)]
+pub enum Source {
+    /// TCP 连接 (客户端或后端)
+    Tcp(TcpStream),
+    /// UDP 会话的已连接 socket
+    Udp(UdpSocket),
+    /// FTP 数据通道的临时监听 socket
+    Listener(TcpListener),
+}
+
+impl Source {
+    /// 底层文件描述符
+    pub fn raw_fd(&self) -> RawFd {
+        #[cfg(unix)]
+        match self {
+            Source::Tcp(stream) => stream.as_raw_fd(),
+            Source::Udp(socket) => socket.as_raw_fd(),
+            Source::Listener(listener) => listener.as_raw_fd(),
+        }
+        #[cfg(windows)]
+        match self {
+            Source::Tcp(stream) => stream.as_raw_socket(),
+            Source::Udp(socket) => socket.as_raw_socket(),
+            Source::Listener(listener) => listener.as_raw_socket(),
+        }
+    }
+}
+
+impl mio::event::Source for Source {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Source::Tcp(stream) => stream.register(registry, token, interests),
+            Source::Udp(socket) => socket.register(registry, token, interests),
+            Source::Listener(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Source::Tcp(stream) => stream.reregister(registry, token, interests),
+            Source::Udp(socket) => socket.reregister(registry, token, interests),
+            Source::Listener(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Source::Tcp(stream) => stream.deregister(registry),
+            Source::Udp(socket) => socket.deregister(registry),
+            Source::Listener(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+/// Fd64 对应的条目
+#[derive(Debug)]
+struct FdEntry {
+    raw_fd: RawFd,
+    info: FdInfo,
+    /// 持有的 socket (监听 socket 等未交给管理器的 fd 没有此项)
+    source: Option<Source>,
+}
+
 /// 文件描述符管理器
 ///
-/// 管理 RawFd 和 Fd64 之间的双向映射，以及 FD 附加信息
+/// Fd64 由 slab 分配，关闭后槽位被复用，代数标记保证旧的 Fd64 不会指向新的 socket
 #[derive(Debug)]
 pub struct FdManager {
+    /// Fd64 -> RawFd、FdInfo 和持有的 socket
+    entries: RwLock<Slab<FdEntry>>,
     /// RawFd -> Fd64 映射
     fd_to_fd64: RwLock<HashMap<RawFd, Fd64>>,
-    /// Fd64 -> RawFd 映射
-    fd64_to_fd: RwLock<HashMap<Fd64, RawFd>>,
-    /// Fd64 -> FdInfo 映射
-    fd_info: RwLock<HashMap<Fd64, FdInfo>>,
-    /// Fd64 计数器
-    counter: AtomicU64,
 }
 
 impl FdManager {
     /// 创建新的 FD 管理器
     pub fn new() -> Arc<Self> {
         Arc::new(Self {
+            entries: RwLock::new(Slab::new()),
             fd_to_fd64: RwLock::new(HashMap::new()),
-            fd64_to_fd: RwLock::new(HashMap::new()),
-            fd_info: RwLock::new(HashMap::new()),
-            counter: AtomicU64::new(1),
         })
     }
 
     /// 预分配容量
     pub fn reserve(&self, capacity: usize) {
-        self.fd_to_fd64
-            .write()
-            .expect("RwLock poisoned")
-            .reserve(capacity);
-        self.fd64_to_fd
-            .write()
-            .expect("RwLock poisoned")
-            .reserve(capacity);
-        self.fd_info
-            .write()
-            .expect("RwLock poisoned")
-            .reserve(capacity);
+        self.entries.write().recover().reserve(capacity);
+        self.fd_to_fd64.write().recover().reserve(capacity);
     }
 
     /// 从 RawFd 创建 Fd64
     pub fn create(&self, raw_fd: RawFd, create_time: u64) -> Fd64 {
-        let fd64 = Fd64(self.counter.fetch_add(1, Ordering::Relaxed));
+        self.create_entry(raw_fd, create_time, None)
+    }
 
-        let mut fd_to_fd64 = self.fd_to_fd64.write().expect("RwLock poisoned");
-        let mut fd64_to_fd = self.fd64_to_fd.write().expect("RwLock poisoned");
-        let mut fd_info = self.fd_info.write().expect("RwLock poisoned");
+    fn create_entry(&self, raw_fd: RawFd, create_time: u64, source: Option<Source>) -> Fd64 {
+        let mut entries = self.entries.write().recover();
+        let mut fd_to_fd64 = self.fd_to_fd64.write().recover();
 
+        let fd64 = Fd64(entries.insert(FdEntry {
+            raw_fd,
+            info: FdInfo::new(create_time),
+            source,
+        }) as u64);
         fd_to_fd64.insert(raw_fd, fd64);
-        fd64_to_fd.insert(fd64, raw_fd);
-        fd_info.insert(fd64, FdInfo::new(create_time));
-
         fd64
     }
 
+    /// 接管 socket 并创建 Fd64，socket 在 `close` 时关闭
+    pub fn insert(&self, source: Source, create_time: u64) -> Fd64 {
+        self.create_entry(source.raw_fd(), create_time, Some(source))
+    }
+
+    /// 对持有的 socket 执行操作 (注册、修改关注事件等)，没有该 socket 时返回 None
+    pub fn with_source<R>(&self, fd64: Fd64, f: impl FnOnce(&mut Source) -> R) -> Option<R> {
+        let mut entries = self.entries.write().recover();
+        entries
+            .get_mut(fd64.0 as usize)
+            .and_then(|entry| entry.source.as_mut())
+            .map(f)
+    }
+
     /// 获取现有的 Fd64 或创建新的
     /// 如果 raw_fd 已存在映射，返回现有的 Fd64；否则创建新的
     pub fn get_or_create(&self, raw_fd: RawFd, create_time: u64) -> Fd64 {
         // 首先检查是否已存在
-        {
-            let fd_to_fd64 = self.fd_to_fd64.read().expect("RwLock poisoned");
-            if let Some(fd64) = fd_to_fd64.get(&raw_fd) {
-                return *fd64;
-            }
+        if let Some(fd64) = self.fd_to_fd64.read().recover().get(&raw_fd) {
+            return *fd64;
         }
 
-        // 不存在，创建新的
-        let fd64 = Fd64(self.counter.fetch_add(1, Ordering::Relaxed));
-
-        let mut fd_to_fd64 = self.fd_to_fd64.write().expect("RwLock poisoned");
-        let mut fd64_to_fd = self.fd64_to_fd.write().expect("RwLock poisoned");
-        let mut fd_info = self.fd_info.write().expect("RwLock poisoned");
+        let mut entries = self.entries.write().recover();
+        let mut fd_to_fd64 = self.fd_to_fd64.write().recover();
 
         // 双重检查，避免并发创建
         if let Some(existing) = fd_to_fd64.get(&raw_fd) {
             return *existing;
         }
 
+        let fd64 = Fd64(entries.insert(FdEntry {
+            raw_fd,
+            info: FdInfo::new(create_time),
+            source: None,
+        }) as u64);
         fd_to_fd64.insert(raw_fd, fd64);
-        fd64_to_fd.insert(fd64, raw_fd);
-        fd_info.insert(fd64, FdInfo::new(create_time));
-
         fd64
     }
 
     /// 将 Fd64 转换为 RawFd
     pub fn to_fd(&self, fd64: Fd64) -> Option<RawFd> {
-        self.fd64_to_fd
+        self.entries
             .read()
-            .expect("RwLock poisoned")
-            .get(&fd64)
-            .copied()
+            .recover()
+            .get(fd64.0 as usize)
+            .map(|entry| entry.raw_fd)
     }
 
     /// 检查 Fd64 是否存在
     pub fn exist(&self, fd64: Fd64) -> bool {
-        self.fd64_to_fd
-            .read()
-            .expect("RwLock poisoned")
-            .contains_key(&fd64)
+        self.entries.read().recover().contains(fd64.0 as usize)
     }
 
     /// 获取 FD 信息
     pub fn get_info(&self, fd64: &Fd64) -> Option<FdInfo> {
-        self.fd_info
+        self.entries
             .read()
-            .expect("RwLock poisoned")
-            .get(fd64)
-            .cloned()
+            .recover()
+            .get(fd64.0 as usize)
+            .map(|entry| entry.info.clone())
     }
 
     /// 检查 FD 信息是否存在
     pub fn exist_info(&self, fd64: &Fd64) -> bool {
-        self.fd_info
-            .read()
-            .expect("RwLock poisoned")
-            .contains_key(fd64)
+        self.exist(*fd64)
     }
 
-    /// 关闭并清理 Fd64
+    /// 清理 Fd64，持有的 socket 随之关闭
     pub fn close(&self, fd64: Fd64) -> Option<RawFd> {
-        let raw_fd = {
-            let mut fd64_to_fd = self.fd64_to_fd.write().expect("RwLock poisoned");
-            fd64_to_fd.remove(&fd64)
-        };
-
-        if let Some(_raw_fd) = raw_fd {
-            let mut fd_to_fd64 = self.fd_to_fd64.write().expect("RwLock poisoned");
-            fd_to_fd64.retain(|_, v| *v != fd64);
-
-            let mut fd_info = self.fd_info.write().expect("RwLock poisoned");
-            fd_info.remove(&fd64);
+        let entry = self.entries.write().recover().remove(fd64.0 as usize)?;
+        {
+            let mut fd_to_fd64 = self.fd_to_fd64.write().recover();
+            // RawFd 可能已被新的 Fd64 使用 (socket 在别处关闭后内核复用了 fd)
+            if fd_to_fd64.get(&entry.raw_fd) == Some(&fd64) {
+                fd_to_fd64.remove(&entry.raw_fd);
+            }
         }
+        Some(entry.raw_fd)
+    }
 
-        raw_fd
+    /// 关闭所有持有的 socket (退出时调用)
+    pub fn close_all(&self) {
+        let sources: Vec<Source> = self
+            .entries
+            .write()
+            .recover()
+            .values_mut()
+            .filter_map(|entry| entry.source.take())
+            .collect();
+        drop(sources);
     }
 
     /// 更新活跃时间
     pub fn update_active(&self, fd64: &Fd64) {
-        if let Some(info) = self.fd_info.read().expect("RwLock poisoned").get(fd64) {
-            info.update_active();
+        if let Some(entry) = self.entries.read().recover().get(fd64.0 as usize) {
+            entry.info.update_active();
         }
     }
 }
@@ -249,6 +326,39 @@ mod tests {
         assert!(!manager.exist_info(&Fd64(99999)));
     }
 
+    #[test]
+    fn test_owned_source() {
+        let manager: Arc<FdManager> = FdManager::new();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let addr = socket.local_addr().expect("addr");
+        let fd64 = manager.insert(Source::Udp(UdpSocket::from_std(socket)), 1000);
+
+        let local = manager.with_source(fd64, |source| match source {
+            Source::Udp(socket) => socket.local_addr().ok(),
+            _ => None,
+        });
+        assert_eq!(local, Some(Some(addr)));
+        assert!(manager.close(fd64).is_some());
+        assert!(manager.with_source(fd64, |_| ()).is_none());
+        // socket 已关闭，端口可以重新绑定
+        std::net::UdpSocket::bind(addr).expect("rebind");
+    }
+
+    #[test]
+    fn test_slot_reuse() {
+        let manager: Arc<FdManager> = FdManager::new();
+        let old = manager.create(42, 1000);
+        manager.close(old);
+
+        // 复用槽位和 RawFd，旧的 Fd64 不会指向新的 fd
+        let new = manager.create(42, 2000);
+        assert_ne!(new, old);
+        assert!(!manager.exist(old));
+        assert_eq!(manager.to_fd(new), Some(42));
+        assert_eq!(manager.close(old), None);
+        assert_eq!(manager.get_or_create(42, 3000), new);
+    }
+
     #[test]
     fn test_close_nonexistent() {
         let manager: Arc<FdManager> = FdManager::new();
@@ -0,0 +1,148 @@
+//! UDP 分片状态和 DF 位 (-d / --mtu)
+//!
+//! Linux 不向 UDP socket 报告收到的数据包是否设置了 DF 位，能拿到的是 IP_RECVFRAGSIZE：
+//! 数据包是分片到达 (重组) 时控制消息中带有最大分片长度。启用 `-d` 时据此把数据包分为两类：
+//! 分片到达的发送时允许分片，完整到达的视为设置了 DF，外连 socket 设置 DF 且不在本地分片。
+//!
+//! `--mtu` 按 IP 包长度 (负载 + UDP 头 + IP 头) 限制客户端发来的数据包：超出时完整到达的丢弃并记录日志，
+//! 分片到达的照常发送，由内核分片。读取控制消息见 `ipheader::recv_from`
+
+use std::io;
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+#[cfg(windows)]
+use crate::winsock::{self as libc, RawFd};
+
+/// UDP 头长度
+const UDP_HEADER: usize = 8;
+/// IPv4 头长度 (不含选项)
+const IPV4_HEADER: usize = 20;
+/// IPv6 头长度 (不含扩展头)
+const IPV6_HEADER: usize = 40;
+
+/// IPv4 要求所有链路支持的最小 MTU
+pub const MIN_MTU: usize = 68;
+
+/// 负载长度为 `len` 的 UDP 数据包的 IP 包长度
+pub fn packet_len(len: usize, ipv6: bool) -> usize {
+    len + UDP_HEADER + if ipv6 { IPV6_HEADER } else { IPV4_HEADER }
+}
+
+/// 在 MTU 为 `mtu` 时能否不分片发送负载长度为 `len` 的数据包
+pub fn fits(len: usize, ipv6: bool, mtu: usize) -> bool {
+    packet_len(len, ipv6) <= mtu
+}
+
+/// 监听 socket 启用 IP_RECVFRAGSIZE/IPV6_RECVFRAGSIZE，按 socket 的地址族只有一个会成功
+#[cfg(target_os = "linux")]
+pub fn enable_recv_fragsize(fd: RawFd) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let set = |level, name| unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    };
+    if set(libc::SOL_IP, libc::IP_RECVFRAGSIZE) | set(libc::SOL_IPV6, libc::IPV6_RECVFRAGSIZE) {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 设置外连 socket 是否带 DF 发送：带 DF 时超过路径 MTU 的数据包发送失败 (EMSGSIZE)，
+/// 否则由内核分片发送。按 socket 的地址族只有一个选项会成功
+#[cfg(target_os = "linux")]
+pub fn set_df(fd: RawFd, df: bool) -> io::Result<()> {
+    let (v4, v6) = if df {
+        (libc::IP_PMTUDISC_DO, libc::IPV6_PMTUDISC_DO)
+    } else {
+        (libc::IP_PMTUDISC_DONT, libc::IPV6_PMTUDISC_DONT)
+    };
+    let set = |level, name, val: libc::c_int| unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    };
+    if set(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, v4)
+        | set(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, v6)
+    {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 外连 socket 是否为 IPv6 socket
+pub fn is_ipv6_socket(fd: RawFd) -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    ret == 0 && i32::from(addr.ss_family) == libc::AF_INET6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits() {
+        assert_eq!(packet_len(1472, false), 1500);
+        assert_eq!(packet_len(1452, true), 1500);
+        assert!(fits(1472, false, 1500));
+        assert!(!fits(1473, false, 1500));
+        assert!(!fits(1472, true, 1500));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_recv_and_df() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        enable_recv_fragsize(server.as_raw_fd()).expect("IP_RECVFRAGSIZE");
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        client
+            .connect(server.local_addr().expect("addr"))
+            .expect("connect");
+        assert!(!is_ipv6_socket(client.as_raw_fd()));
+
+        let pmtudisc = |fd| {
+            let mut val: libc::c_int = -1;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    &mut val as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            val
+        };
+        set_df(client.as_raw_fd(), true).expect("set df");
+        assert_eq!(pmtudisc(client.as_raw_fd()), libc::IP_PMTUDISC_DO);
+        set_df(client.as_raw_fd(), false).expect("clear df");
+        assert_eq!(pmtudisc(client.as_raw_fd()), libc::IP_PMTUDISC_DONT);
+
+        // 环回接口 MTU 为 64K，不会分片，数据包都是完整到达
+        client.send(b"whole").expect("send");
+        let mut buf = vec![0u8; 65536];
+        let (len, from, header) =
+            crate::ipheader::recv_from(server.as_raw_fd(), &mut buf).expect("recv");
+        assert_eq!((&buf[..len], header.fragmented), (&b"whole"[..], false));
+        assert_eq!(from, client.local_addr().expect("addr"));
+    }
+}
@@ -0,0 +1,407 @@
+//! FTP 应用层网关 (--ftp-alg)
+//!
+//! FTP 的数据通道另开 TCP 连接：主动模式下客户端用 PORT/EPRT 告诉服务端自己监听的地址，被动模式下
+//! 服务端在 PASV/EPSV 的应答 (227/229) 中给出监听地址，经过映射后这些地址对另一端通常不可达。
+//! 网关逐行解析转发的控制连接，为每个数据通道在映射上打开一个临时监听 socket，把命令或应答中的地址
+//! 改写为临时监听地址；临时监听只接受预期对端 IP 的一个连接，转发到原端口后关闭。
+//!
+//! 临时监听由中间件的辅助映射 (`middleware::AuxMapping`) 打开。
+//!
+//! 数据通道的目标 IP 总是取控制连接对端的 IP，忽略命令和应答中的 IP，避免 FTP bounce 和服务端位于
+//! NAT 之后时给出的私有地址。只解析明文控制连接 (不支持 AUTH TLS)
+
+use crate::middleware::{
+    AuxMapping, Middleware, StreamInfo, TcpContext, TcpFilter, MAX_AUX_MAPPINGS,
+};
+use crate::types::Address;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// 解析的最大行长度，超出的行原样转发
+pub const MAX_LINE: usize = 512;
+
+/// 改写一行最多增加的长度 (PORT 改为 IPv6 的 EPRT 时最长)
+const MAX_GROWTH: usize = 64;
+
+/// FTP 网关中间件，为每个 TCP 连接创建 `FtpControl`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ftp;
+
+impl Middleware for Ftp {
+    fn name(&self) -> &str {
+        "ftp"
+    }
+
+    fn tcp_filter(&self, _remote: &Address) -> Option<Box<dyn TcpFilter>> {
+        Some(Box::new(FtpControl::new()))
+    }
+}
+
+/// 控制连接中请求建立的数据通道，只带端口，IP 取控制连接对端的 IP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataRequest {
+    /// 客户端的 PORT/EPRT：客户端在该端口监听，由服务端连接
+    Active(u16),
+    /// 服务端的 PASV/EPSV 应答：服务端在该端口监听，由客户端连接
+    Passive(u16),
+}
+
+/// 控制连接两个方向的行缓冲
+#[derive(Debug, Clone, Default)]
+pub struct FtpControl {
+    /// 不完整的行，下标 0 为客户端 -> 服务端
+    pending: [Vec<u8>; 2],
+    /// 正在原样转发超长行的剩余部分
+    overlong: [bool; 2],
+}
+
+impl FtpControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 处理后不超过 `room` 字节时一次最多输入的字节数
+    pub fn max_input(room: usize) -> usize {
+        room.saturating_sub(MAX_LINE + MAX_AUX_MAPPINGS * MAX_GROWTH)
+    }
+
+    /// 处理 `to_remote` 方向收到的数据，完整的行改写后追加到 `out`，不完整的行留到下次
+    ///
+    /// `open` 为数据通道打开临时监听，返回改写进命令或应答的地址，返回 None 时该行原样转发
+    pub fn rewrite(
+        &mut self,
+        to_remote: bool,
+        input: &[u8],
+        out: &mut Vec<u8>,
+        open: &mut dyn FnMut(DataRequest) -> Option<SocketAddr>,
+    ) {
+        let dir = usize::from(!to_remote);
+        let mut rest = input;
+        while !rest.is_empty() {
+            let newline = rest.iter().position(|&b| b == b'\n');
+            if self.overlong[dir] {
+                let end = newline.map_or(rest.len(), |i| i + 1);
+                out.extend_from_slice(&rest[..end]);
+                self.overlong[dir] = newline.is_none();
+                rest = &rest[end..];
+                continue;
+            }
+            let Some(i) = newline else {
+                let pending = &mut self.pending[dir];
+                pending.extend_from_slice(rest);
+                if pending.len() > MAX_LINE {
+                    out.append(pending);
+                    self.overlong[dir] = true;
+                }
+                return;
+            };
+            let mut line = std::mem::take(&mut self.pending[dir]);
+            line.extend_from_slice(&rest[..=i]);
+            rest = &rest[i + 1..];
+            match rewrite_line(to_remote, &line, open) {
+                Some(rewritten) => out.extend_from_slice(rewritten.as_bytes()),
+                None => out.extend_from_slice(&line),
+            }
+        }
+    }
+}
+
+impl TcpFilter for FtpControl {
+    fn max_input(&self, room: usize) -> usize {
+        Self::max_input(room)
+    }
+
+    fn process(
+        &mut self,
+        to_remote: bool,
+        input: &[u8],
+        out: &mut Vec<u8>,
+        ctx: &mut dyn TcpContext,
+    ) {
+        self.rewrite(to_remote, input, out, &mut |request| {
+            let stream = ctx.stream()?;
+            ctx.open_tcp(data_mapping(&stream, request))
+        });
+    }
+}
+
+/// 数据通道的辅助映射
+///
+/// 主动模式在连接服务端所用的本机地址上监听，只接受服务端 IP，转发到客户端 IP 上的端口；被动模式反之
+pub fn data_mapping(stream: &StreamInfo, request: DataRequest) -> AuxMapping {
+    match request {
+        DataRequest::Active(port) => AuxMapping {
+            listen: stream.remote_local.ip(),
+            peer: stream.remote.ip(),
+            target: SocketAddr::new(stream.client.ip(), port),
+        },
+        DataRequest::Passive(port) => AuxMapping {
+            listen: stream.client_local.ip(),
+            peer: stream.client.ip(),
+            target: SocketAddr::new(stream.remote.ip(), port),
+        },
+    }
+}
+
+/// 改写一行中的数据通道地址，不需要改写时返回 None
+fn rewrite_line(
+    to_remote: bool,
+    line: &[u8],
+    open: &mut dyn FnMut(DataRequest) -> Option<SocketAddr>,
+) -> Option<String> {
+    let text = std::str::from_utf8(line).ok()?;
+    let body = text.trim_end_matches(['\r', '\n']);
+    let eol = &text[body.len()..];
+    if to_remote {
+        let (verb, args) = body.split_once(' ').unwrap_or((body, ""));
+        let port = if verb.eq_ignore_ascii_case("PORT") {
+            parse_port(args)?.port()
+        } else if verb.eq_ignore_ascii_case("EPRT") {
+            parse_eprt(args)?.port()
+        } else {
+            return None;
+        };
+        let addr = canonical(open(DataRequest::Active(port))?);
+        let command = match addr.ip() {
+            IpAddr::V4(ip) if verb.eq_ignore_ascii_case("PORT") => {
+                format!("{} {}", verb, format_port(ip, addr.port()))
+            }
+            _ => format!("EPRT {}", format_eprt(&addr)),
+        };
+        Some(command + eol)
+    } else if body.starts_with("227 ") {
+        let (span, requested) = find_pasv(body)?;
+        let addr = canonical(open(DataRequest::Passive(requested.port()))?);
+        // 227 只能表示 IPv4 地址，IPv6 客户端应使用 EPSV
+        let IpAddr::V4(ip) = addr.ip() else {
+            return None;
+        };
+        Some(format!(
+            "{}{}{}{}",
+            &body[..span.start],
+            format_port(ip, addr.port()),
+            &body[span.end..],
+            eol
+        ))
+    } else if body.starts_with("229 ") {
+        let (span, port) = find_epsv(body)?;
+        let addr = open(DataRequest::Passive(port))?;
+        Some(format!(
+            "{}{}{}{}",
+            &body[..span.start],
+            addr.port(),
+            &body[span.end..],
+            eol
+        ))
+    } else {
+        None
+    }
+}
+
+/// IPv4-mapped IPv6 地址换成 IPv4 地址
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// 解析 PORT 参数 `h1,h2,h3,h4,p1,p2`
+pub fn parse_port(args: &str) -> Option<SocketAddr> {
+    let fields = args
+        .trim()
+        .split(',')
+        .map(|v| v.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let [a, b, c, d, p1, p2] = fields[..] else {
+        return None;
+    };
+    Some(SocketAddr::from((
+        [a, b, c, d],
+        u16::from_be_bytes([p1, p2]),
+    )))
+}
+
+/// 格式化 PORT 参数
+pub fn format_port(ip: Ipv4Addr, port: u16) -> String {
+    let [a, b, c, d] = ip.octets();
+    let [p1, p2] = port.to_be_bytes();
+    format!("{},{},{},{},{},{}", a, b, c, d, p1, p2)
+}
+
+/// 解析 EPRT 参数 `|1|132.235.1.2|6275|` (RFC 2428)，分隔符为第一个字符
+pub fn parse_eprt(args: &str) -> Option<SocketAddr> {
+    let args = args.trim();
+    let delim = args.chars().next()?;
+    let mut fields = args.split(delim);
+    let (Some(""), Some(proto), Some(ip), Some(port), Some(""), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+    let ip: IpAddr = ip.parse().ok()?;
+    match (proto, ip) {
+        ("1", IpAddr::V4(_)) | ("2", IpAddr::V6(_)) => {}
+        _ => return None,
+    }
+    Some(SocketAddr::new(ip, port.parse().ok()?))
+}
+
+/// 格式化 EPRT 参数
+pub fn format_eprt(addr: &SocketAddr) -> String {
+    let proto = if addr.is_ipv4() { 1 } else { 2 };
+    format!("|{}|{}|{}|", proto, addr.ip(), addr.port())
+}
+
+/// 在 227 应答中找到 `h1,h2,h3,h4,p1,p2`，返回其位置和地址
+pub fn find_pasv(body: &str) -> Option<(std::ops::Range<usize>, SocketAddr)> {
+    let start = 4 + body[4..].find(|c: char| c.is_ascii_digit())?;
+    let len = body[start..]
+        .find(|c: char| !c.is_ascii_digit() && c != ',')
+        .unwrap_or(body.len() - start);
+    let span = start..start + len;
+    Some((span.clone(), parse_port(&body[span])?))
+}
+
+/// 在 229 应答 `(|||port|)` 中找到端口，返回其位置和端口
+pub fn find_epsv(body: &str) -> Option<(std::ops::Range<usize>, u16)> {
+    let open = body.find('(')?;
+    let inner = &body[open + 1..];
+    let delim = inner.chars().next()?;
+    if !delim.is_ascii_punctuation() || !inner.starts_with(&delim.to_string().repeat(3)) {
+        return None;
+    }
+    let start = open + 1 + 3 * delim.len_utf8();
+    let len = body[start..].find(delim)?;
+    let span = start..start + len;
+    Some((span.clone(), body[span].parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        control: &mut FtpControl,
+        to_remote: bool,
+        input: &[u8],
+        advertised: SocketAddr,
+    ) -> (Vec<u8>, Vec<DataRequest>) {
+        let mut out = Vec::new();
+        let mut requests = Vec::new();
+        control.rewrite(to_remote, input, &mut out, &mut |request| {
+            requests.push(request);
+            Some(advertised)
+        });
+        (out, requests)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_port("10,0,0,1,4,1"),
+            Some("10.0.0.1:1025".parse().unwrap())
+        );
+        assert_eq!(parse_port("10,0,0,1,4"), None);
+        assert_eq!(parse_port("10,0,0,1,4,256"), None);
+        assert_eq!(
+            format_port(Ipv4Addr::new(10, 0, 0, 1), 1025),
+            "10,0,0,1,4,1"
+        );
+        assert_eq!(
+            parse_eprt("|2|::1|6275|"),
+            Some("[::1]:6275".parse().unwrap())
+        );
+        assert_eq!(parse_eprt("|1|::1|6275|"), None);
+        assert_eq!(format_eprt(&"[::1]:6275".parse().unwrap()), "|2|::1|6275|");
+        assert_eq!(
+            find_epsv("229 Entering Extended Passive Mode (|||6446|)"),
+            Some((39..43, 6446))
+        );
+        assert_eq!(find_epsv("229 Entering Extended Passive Mode (6446)"), None);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut control = FtpControl::new();
+        let v4: SocketAddr = "192.168.1.1:40000".parse().unwrap();
+
+        // 命令分段到达，不完整的行等待下次
+        let (out, requests) = run(&mut control, true, b"USER a\r\nPORT 10,0,0,", v4);
+        assert_eq!(out, b"USER a\r\n");
+        assert!(requests.is_empty());
+        let (out, requests) = run(&mut control, true, b"2,4,1\r\nLIST\r\n", v4);
+        assert_eq!(out, b"PORT 192,168,1,1,156,64\r\nLIST\r\n");
+        assert_eq!(requests, [DataRequest::Active(1025)]);
+
+        // 应答方向的缓冲与命令方向独立
+        let (out, requests) = run(
+            &mut control,
+            false,
+            b"227 Entering Passive Mode (10,0,0,2,7,208).\r\n",
+            "[::ffff:192.168.1.1]:40000".parse().unwrap(),
+        );
+        assert_eq!(out, b"227 Entering Passive Mode (192,168,1,1,156,64).\r\n");
+        assert_eq!(requests, [DataRequest::Passive(2000)]);
+        let (out, _) = run(
+            &mut control,
+            false,
+            b"229 Entering Extended Passive Mode (|||6446|)\n",
+            v4,
+        );
+        assert_eq!(out, b"229 Entering Extended Passive Mode (|||40000|)\n");
+
+        // 映射在 IPv6 上监听时 PORT 改为 EPRT
+        let (out, _) = run(
+            &mut control,
+            true,
+            b"PORT 10,0,0,2,4,1\r\n",
+            "[2001:db8::1]:40000".parse().unwrap(),
+        );
+        assert_eq!(out, b"EPRT |2|2001:db8::1|40000|\r\n");
+
+        // 打开监听失败时原样转发
+        let mut out = Vec::new();
+        control.rewrite(true, b"EPRT |1|10.0.0.2|1025|\r\n", &mut out, &mut |_| None);
+        assert_eq!(out, b"EPRT |1|10.0.0.2|1025|\r\n");
+    }
+
+    #[test]
+    fn test_data_mapping() {
+        let stream = StreamInfo {
+            client: "198.51.100.7:50000".parse().unwrap(),
+            client_local: "192.0.2.1:21".parse().unwrap(),
+            remote: "10.0.0.2:21".parse().unwrap(),
+            remote_local: "10.0.0.1:40000".parse().unwrap(),
+        };
+        assert_eq!(
+            data_mapping(&stream, DataRequest::Active(1025)),
+            AuxMapping {
+                listen: "10.0.0.1".parse().unwrap(),
+                peer: "10.0.0.2".parse().unwrap(),
+                target: "198.51.100.7:1025".parse().unwrap(),
+            }
+        );
+        assert_eq!(
+            data_mapping(&stream, DataRequest::Passive(2000)),
+            AuxMapping {
+                listen: "192.0.2.1".parse().unwrap(),
+                peer: "198.51.100.7".parse().unwrap(),
+                target: "10.0.0.2:2000".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_overlong_line() {
+        let mut control = FtpControl::new();
+        let addr: SocketAddr = "192.168.1.1:40000".parse().unwrap();
+        let long = vec![b'x'; MAX_LINE + 1];
+        let (out, _) = run(&mut control, false, &long, addr);
+        assert_eq!(out, long);
+        let (out, _) = run(&mut control, false, b"xx\r\n227 (10,0,0,2,7,208)\r\n", addr);
+        assert_eq!(out, b"xx\r\n227 (192,168,1,1,156,64)\r\n");
+        assert!(FtpControl::max_input(64 * 1024) > 60 * 1024);
+    }
+}
@@ -0,0 +1,210 @@
+//! GeoIP 过滤与日志标注 (--geoip-db / --geo-allow / --geo-deny)
+//!
+//! 用 MaxMind GeoLite2 Country/City 数据库 (可选 ASN 数据库) 查询客户端的国家和 ASN，
+//! 在新连接日志中标注，并在接受连接/新建 UDP 会话时按国家代码过滤。数据库中查不到的地址
+//! (内网、回环) 的国家代码为 `ZZ`。查询需要 `geoip` feature
+
+use std::fmt;
+use std::net::IpAddr;
+
+/// 查不到国家时使用的代码 (ISO 3166 用户自定义代码)
+pub const UNKNOWN_COUNTRY: &str = "ZZ";
+
+/// 一个客户端地址的查询结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// 两位大写国家代码
+    pub country: Option<String>,
+    /// 自治系统号
+    pub asn: Option<u32>,
+}
+
+impl GeoInfo {
+    /// 国家代码，查不到时为 `ZZ`
+    pub fn country(&self) -> &str {
+        self.country.as_deref().unwrap_or(UNKNOWN_COUNTRY)
+    }
+}
+
+impl fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.country())?;
+        if let Some(asn) = self.asn {
+            write!(f, " AS{}", asn)?;
+        }
+        Ok(())
+    }
+}
+
+/// 按国家代码放行或拒绝：先看拒绝列表，允许列表非空时只放行其中的国家
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoPolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GeoPolicy {
+    /// 检查并规范化国家代码 (两位字母，不区分大小写)
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        let normalize = |codes: &[String]| {
+            codes
+                .iter()
+                .map(|code| {
+                    let code = code.trim();
+                    if code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()) {
+                        Ok(code.to_ascii_uppercase())
+                    } else {
+                        Err(format!(
+                            "invalid country code '{}', expected two letters such as CN",
+                            code
+                        ))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            allow: normalize(allow)?,
+            deny: normalize(deny)?,
+        })
+    }
+
+    /// 是否设置了过滤规则
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, country: &str) -> bool {
+        if self.deny.iter().any(|code| code == country) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|code| code == country)
+    }
+}
+
+#[cfg(feature = "geoip")]
+type Reader = maxminddb::Reader<Vec<u8>>;
+
+/// 已加载的 GeoIP 数据库和过滤规则
+pub struct GeoFilter {
+    #[cfg(feature = "geoip")]
+    country_db: Reader,
+    #[cfg(feature = "geoip")]
+    asn_db: Option<Reader>,
+    policy: GeoPolicy,
+}
+
+impl fmt::Debug for GeoFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoFilter")
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GeoFilter {
+    /// 读取国家数据库 (GeoLite2-Country 或 GeoLite2-City) 和可选的 ASN 数据库
+    #[cfg(feature = "geoip")]
+    pub fn open(country_db: &str, asn_db: Option<&str>, policy: GeoPolicy) -> Result<Self, String> {
+        let open = |path: &str| {
+            Reader::open_readfile(path)
+                .map_err(|e| format!("failed to open GeoIP database {}: {}", path, e))
+        };
+        Ok(Self {
+            country_db: open(country_db)?,
+            asn_db: asn_db.map(open).transpose()?,
+            policy,
+        })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(
+        _country_db: &str,
+        _asn_db: Option<&str>,
+        _policy: GeoPolicy,
+    ) -> Result<Self, String> {
+        Err("geoip-db requires the geoip feature".to_string())
+    }
+
+    /// 查询客户端地址，查不到的字段为 None
+    #[cfg(feature = "geoip")]
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        use maxminddb::geoip2;
+
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let country = self
+            .country_db
+            .lookup::<geoip2::Country>(ip)
+            .ok()
+            .and_then(|record| record.country.or(record.registered_country))
+            .and_then(|country| country.iso_code)
+            .map(str::to_string);
+        let asn = self
+            .asn_db
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Asn>(ip).ok())
+            .and_then(|record| record.autonomous_system_number);
+        GeoInfo { country, asn }
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn lookup(&self, _ip: IpAddr) -> GeoInfo {
+        GeoInfo::default()
+    }
+
+    /// 按过滤规则检查查询结果
+    pub fn allows(&self, info: &GeoInfo) -> bool {
+        self.policy.allows(info.country())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let codes = |s: &str| s.split(',').map(str::to_string).collect::<Vec<_>>();
+        let allow = GeoPolicy::new(&codes("cn, US"), &[]).unwrap();
+        assert!(allow.allows("CN"));
+        assert!(allow.allows("US"));
+        assert!(!allow.allows("DE"));
+        assert!(!allow.allows(UNKNOWN_COUNTRY));
+
+        let deny = GeoPolicy::new(&[], &codes("RU,ZZ")).unwrap();
+        assert!(deny.allows("DE"));
+        assert!(!deny.allows("RU"));
+        assert!(!deny.allows(UNKNOWN_COUNTRY));
+
+        let both = GeoPolicy::new(&codes("US,DE"), &codes("DE")).unwrap();
+        assert!(both.allows("US"));
+        assert!(!both.allows("DE"));
+
+        assert!(GeoPolicy::new(&[], &[]).unwrap().is_empty());
+        assert!(GeoPolicy::new(&codes("USA"), &[]).is_err());
+        assert!(GeoPolicy::new(&[], &codes("1A")).is_err());
+    }
+
+    #[test]
+    fn test_info_display() {
+        assert_eq!(GeoInfo::default().to_string(), "ZZ");
+        let info = GeoInfo {
+            country: Some("CN".to_string()),
+            asn: Some(4134),
+        };
+        assert_eq!(info.to_string(), "CN AS4134");
+    }
+
+    #[test]
+    fn test_open_missing() {
+        // 没有 geoip feature 时同样报错
+        assert!(GeoFilter::open(
+            "/nonexistent/GeoLite2-Country.mmdb",
+            None,
+            GeoPolicy::default()
+        )
+        .is_err());
+    }
+}
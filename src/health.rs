@@ -0,0 +1,211 @@
+//! 后端健康检查
+//!
+//! 由事件循环的定时器驱动，每轮为每个后端启动一个短暂的探测线程 (TCP 连接或 UDP 探测包)，
+//! 探测失败的后端被标记为不健康并在轮询时跳过，直到再次探测成功
+
+use crate::backend::{translate_addr, Backend};
+use crate::config::FwdType;
+use crate::event::observer::Observers;
+use crate::types::{Address, Nat64Prefix};
+use crate::{info, warn};
+use std::io;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 单次探测的超时时间
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 探测方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// 建立 TCP 连接
+    Tcp,
+    /// 发送空 UDP 包，收到 ICMP 端口不可达视为失败
+    Udp,
+}
+
+/// 健康检查器
+#[derive(Debug)]
+pub struct HealthChecker {
+    backends: Vec<Arc<Backend>>,
+    kind: ProbeKind,
+    fwd_type: FwdType,
+    nat64_prefix: Nat64Prefix,
+    timeout: Duration,
+    /// 后端状态变化时通知的观察者
+    observers: Arc<Observers>,
+}
+
+impl HealthChecker {
+    /// 创建健康检查器
+    pub fn new(
+        backends: Vec<Arc<Backend>>,
+        kind: ProbeKind,
+        fwd_type: FwdType,
+        nat64_prefix: Nat64Prefix,
+    ) -> Self {
+        Self {
+            backends,
+            kind,
+            fwd_type,
+            nat64_prefix,
+            timeout: HEALTH_CHECK_TIMEOUT,
+            observers: Arc::new(Observers::default()),
+        }
+    }
+
+    /// 后端状态变化时通知这些观察者 (在探测线程中回调)
+    pub fn with_observers(mut self, observers: Arc<Observers>) -> Self {
+        self.observers = observers;
+        self
+    }
+
+    /// 启动一轮探测 (上一轮探测尚未结束的后端跳过)
+    pub fn run(&self) {
+        for backend in &self.backends {
+            if backend.probing.swap(true, Ordering::Relaxed) {
+                continue;
+            }
+            let backend = Arc::clone(backend);
+            let addr = translate_addr(&backend.addr, self.fwd_type, &self.nat64_prefix);
+            let (kind, timeout) = (self.kind, self.timeout);
+            let observers = Arc::clone(&self.observers);
+            let spawned = std::thread::Builder::new()
+                .name("health-check".to_string())
+                .spawn({
+                    let backend = Arc::clone(&backend);
+                    move || {
+                        let result = probe_addr(kind, &addr, timeout);
+                        update_health(&backend, result, &observers);
+                        backend.probing.store(false, Ordering::Relaxed);
+                    }
+                });
+            if let Err(e) = spawned {
+                warn!("[health] failed to spawn probe thread: {}", e);
+                backend.probing.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// 根据探测结果更新后端状态，状态变化时输出日志并通知观察者
+fn update_health(backend: &Backend, result: io::Result<()>, observers: &Observers) {
+    match result {
+        Ok(()) => {
+            if backend.set_healthy(true) {
+                info!("[health] backend {} is up", backend.addr);
+                observers.notify(|o| o.on_backend_up(&backend.addr));
+            }
+        }
+        Err(e) => {
+            if backend.set_healthy(false) {
+                warn!("[health] backend {} is down: {}", backend.addr, e);
+                let reason = format!("health check failed: {}", e);
+                observers.notify(|o| o.on_backend_down(&backend.addr, &reason));
+            }
+        }
+    }
+}
+
+/// 探测一次后端地址，Unix 域 socket、vsock 和命名管道后端只支持建立连接
+fn probe_addr(kind: ProbeKind, addr: &Address, timeout: Duration) -> io::Result<()> {
+    #[cfg(windows)]
+    if let Some(name) = addr.named_pipe() {
+        return crate::npipe::probe(name);
+    }
+    #[cfg(unix)]
+    if !addr.is_ip() {
+        return probe_stream(addr);
+    }
+    probe(kind, addr.to_sockaddr(), timeout)
+}
+
+/// 用阻塞 socket 连接 Unix 域 socket 或 vsock 后端
+#[cfg(unix)]
+fn probe_stream(addr: &Address) -> io::Result<()> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    let fd = unsafe { libc::socket(addr.get_addr_family(), libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let storage = addr.to_sockaddr_storage();
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            addr.get_len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 探测一次后端
+pub fn probe(kind: ProbeKind, addr: SocketAddr, timeout: Duration) -> io::Result<()> {
+    match kind {
+        ProbeKind::Tcp => TcpStream::connect_timeout(&addr, timeout).map(|_| ()),
+        ProbeKind::Udp => {
+            let bind = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind)?;
+            socket.connect(addr)?;
+            socket.set_read_timeout(Some(timeout))?;
+            socket.send(&[])?;
+            let mut buf = [0u8; 1];
+            match socket.recv(&mut buf) {
+                Ok(_) => Ok(()),
+                // 没有响应也没有 ICMP 错误，认为端口可达
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// 获取一个当前未被监听的本地端口
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        listener.local_addr().expect("local addr")
+    }
+
+    #[test]
+    fn test_probe_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        assert!(probe(ProbeKind::Tcp, addr, Duration::from_secs(1)).is_ok());
+        assert!(probe(ProbeKind::Tcp, closed_port(), Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_probe_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let addr = socket.local_addr().expect("local addr");
+        assert!(probe(ProbeKind::Udp, addr, Duration::from_millis(100)).is_ok());
+
+        let closed = UdpSocket::bind("127.0.0.1:0")
+            .expect("bind")
+            .local_addr()
+            .expect("local addr");
+        assert!(probe(ProbeKind::Udp, closed, Duration::from_millis(500)).is_err());
+    }
+}
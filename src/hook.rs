@@ -0,0 +1,320 @@
+//! 连接事件执行命令 (--on-connect-exec / --on-close-exec)
+//!
+//! 连接建立和关闭时通过 shell 执行用户命令，用 `TPM_*` 环境变量描述客户端和映射，
+//! 供脚本封禁地址 (iptables) 或发送通知。命令由后台线程启动和回收，不阻塞事件循环；
+//! 每秒最多启动 `--exec-rate` 个，超出的事件丢弃
+
+use crate::event::observer::{ConnectionObserver, ConnectionSummary, Protocol};
+use crate::log::get_monotonic_time;
+use crate::sync::Recover;
+use crate::types::Address;
+use crate::{debug, warn};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 默认每秒最多启动的命令数
+pub const DEFAULT_EXEC_RATE: u32 = 10;
+
+/// 等待启动的命令数上限
+const QUEUE_LEN: usize = 256;
+
+/// 回收已退出子进程的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 待执行的命令和环境变量
+#[derive(Debug)]
+struct Job {
+    command: String,
+    env: Vec<(&'static str, String)>,
+}
+
+/// 按秒计数的启动次数限制
+#[derive(Debug)]
+struct RateWindow {
+    limit: u32,
+    second: u64,
+    count: u32,
+}
+
+impl RateWindow {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            second: 0,
+            count: 0,
+        }
+    }
+
+    /// `now_ms` 所在的这一秒内是否还能再启动一个
+    fn allow(&mut self, now_ms: u64) -> bool {
+        let second = now_ms / 1000;
+        if second != self.second {
+            self.second = second;
+            self.count = 0;
+        }
+        if self.count >= self.limit {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+/// 执行命令的观察者
+#[derive(Debug)]
+pub struct ExecHook {
+    on_connect: Option<String>,
+    on_close: Option<String>,
+    /// 映射的监听地址 (`TPM_LISTEN`)
+    listen: String,
+    tenant: Option<String>,
+    tx: SyncSender<Job>,
+    window: Mutex<RateWindow>,
+    dropped: AtomicU64,
+}
+
+impl ExecHook {
+    /// 启动执行命令的后台线程，`rate` 为每秒最多启动的命令数
+    pub fn start(
+        on_connect: Option<String>,
+        on_close: Option<String>,
+        rate: u32,
+        listen: String,
+        tenant: Option<String>,
+    ) -> Result<Self, String> {
+        if rate == 0 {
+            return Err("exec-rate must be at least 1".to_string());
+        }
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("exec-hook".to_string())
+            .spawn(move || run_jobs(rx))
+            .map_err(|e| format!("failed to spawn exec hook thread: {}", e))?;
+        Ok(Self {
+            on_connect,
+            on_close,
+            listen,
+            tenant,
+            tx,
+            window: Mutex::new(RateWindow::new(rate)),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// 通用的环境变量
+    fn env(&self, event: &str, protocol: Protocol, peer: &str) -> Vec<(&'static str, String)> {
+        let (ip, port) = split_addr(peer);
+        let mut env = vec![
+            ("TPM_EVENT", event.to_string()),
+            ("TPM_PROTOCOL", protocol.to_string()),
+            ("TPM_CLIENT", peer.to_string()),
+            ("TPM_CLIENT_IP", ip.to_string()),
+            ("TPM_CLIENT_PORT", port.to_string()),
+            ("TPM_LISTEN", self.listen.clone()),
+        ];
+        if let Some(ref tenant) = self.tenant {
+            env.push(("TPM_TENANT", tenant.clone()));
+        }
+        env
+    }
+
+    fn exec(&self, command: &str, env: Vec<(&'static str, String)>) {
+        if !self.window.lock().recover().allow(get_monotonic_time()) {
+            self.drop_job("rate limit reached");
+            return;
+        }
+        let job = Job {
+            command: command.to_string(),
+            env,
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(job) {
+            self.drop_job("queue full");
+        }
+    }
+
+    fn drop_job(&self, reason: &str) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            warn!("[exec] {}, {} commands skipped so far", reason, dropped);
+        }
+    }
+
+    fn on_connect(&self, protocol: Protocol, peer: &str, remote: &Address) {
+        let Some(ref command) = self.on_connect else {
+            return;
+        };
+        let mut env = self.env("connect", protocol, peer);
+        env.push(("TPM_REMOTE", remote.to_string()));
+        self.exec(command, env);
+    }
+}
+
+impl ConnectionObserver for ExecHook {
+    fn on_connect_established(&self, peer: &str, remote: &Address) {
+        self.on_connect(Protocol::Tcp, peer, remote);
+    }
+
+    fn on_udp_session(&self, peer: &str, remote: &Address) {
+        self.on_connect(Protocol::Udp, peer, remote);
+    }
+
+    fn on_close(&self, summary: &ConnectionSummary<'_>) {
+        let Some(ref command) = self.on_close else {
+            return;
+        };
+        let mut env = self.env("close", summary.protocol, summary.peer);
+        env.extend([
+            ("TPM_REASON", summary.reason.to_string()),
+            ("TPM_BYTES_UP", summary.bytes_up.to_string()),
+            ("TPM_BYTES_DOWN", summary.bytes_down.to_string()),
+            ("TPM_DURATION_MS", summary.duration_ms.to_string()),
+        ]);
+        self.exec(command, env);
+    }
+}
+
+/// 把 `ip:port` / `[ipv6]:port` 拆成地址和端口，没有端口时端口为空
+fn split_addr(addr: &str) -> (&str, &str) {
+    match addr.rsplit_once(':') {
+        Some((ip, port)) if port.chars().all(|c| c.is_ascii_digit()) && !ip.is_empty() => {
+            let ip = ip
+                .strip_prefix('[')
+                .and_then(|ip| ip.strip_suffix(']'))
+                .unwrap_or(ip);
+            (ip, port)
+        }
+        _ => (addr, ""),
+    }
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    }
+    #[cfg(not(windows))]
+    {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
+
+/// 后台线程：启动命令并回收退出的子进程，ExecHook 释放后等待剩余子进程退出
+fn run_jobs(rx: Receiver<Job>) {
+    let mut children: Vec<Child> = Vec::new();
+    loop {
+        let job = rx.recv_timeout(REAP_INTERVAL);
+        children.retain_mut(|child| match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    debug!("[exec] command {} exited with {}", child.id(), status);
+                }
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        });
+        let job = match job {
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let spawned = shell(&job.command)
+            .envs(job.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        match spawned {
+            Ok(child) => children.push(child),
+            Err(e) => warn!("[exec] failed to run '{}': {}", job.command, e),
+        }
+    }
+    for mut child in children {
+        let _ = child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::observer::CloseReason;
+    use std::str::FromStr;
+    use std::time::Instant;
+
+    #[test]
+    fn test_rate_window() {
+        let mut window = RateWindow::new(2);
+        assert!(window.allow(5_000));
+        assert!(window.allow(5_999));
+        assert!(!window.allow(5_999));
+        assert!(window.allow(6_000));
+    }
+
+    #[test]
+    fn test_split_addr() {
+        assert_eq!(split_addr("1.2.3.4:80"), ("1.2.3.4", "80"));
+        assert_eq!(split_addr("[2001:db8::1]:443"), ("2001:db8::1", "443"));
+        assert_eq!(split_addr("/run/app.sock"), ("/run/app.sock", ""));
+        assert_eq!(split_addr("1.2.3.x:*"), ("1.2.3.x:*", ""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_env() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let out = dir.path().join("events");
+        let command = format!(
+            "echo \"$TPM_EVENT $TPM_PROTOCOL $TPM_CLIENT_IP $TPM_CLIENT_PORT $TPM_LISTEN $TPM_REMOTE$TPM_REASON $TPM_BYTES_UP $TPM_TENANT\" >> {}",
+            out.display()
+        );
+        let hook = ExecHook::start(
+            Some(command.clone()),
+            Some(command),
+            1,
+            "0.0.0.0:1234".to_string(),
+            Some("t1".to_string()),
+        )
+        .expect("start");
+        let remote = Address::from_str("10.0.0.1:80").unwrap();
+        hook.on_connect_established("192.0.2.7:5000", &remote);
+        // 同一秒内的第二个命令被限速丢弃 (除非恰好跨过秒边界)
+        let second = get_monotonic_time() / 1000;
+        hook.on_udp_session("192.0.2.8:5000", &remote);
+        let limited = get_monotonic_time() / 1000 == second;
+        std::thread::sleep(Duration::from_millis(1100));
+        hook.on_close(&ConnectionSummary {
+            protocol: Protocol::Tcp,
+            peer: "[2001:db8::7]:5001",
+            bytes_up: 10,
+            bytes_down: 20,
+            packets_up: 1,
+            packets_down: 2,
+            duration_ms: 5,
+            reason: CloseReason::Eof,
+        });
+        drop(hook);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let expected_lines = if limited { 2 } else { 3 };
+        let mut lines = Vec::new();
+        while Instant::now() < deadline {
+            let text = std::fs::read_to_string(&out).unwrap_or_default();
+            lines = text.lines().map(str::to_string).collect();
+            if lines.len() >= expected_lines {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(lines.len(), expected_lines, "{:?}", lines);
+        assert!(
+            lines.contains(&"connect tcp 192.0.2.7 5000 0.0.0.0:1234 10.0.0.1:80  t1".to_string())
+        );
+        assert!(lines.contains(&"close tcp 2001:db8::7 5001 0.0.0.0:1234 eof 10 t1".to_string()));
+    }
+}
@@ -0,0 +1,273 @@
+//! 转发 UDP 数据包时沿用的 IP 头字段 (-d / --udp-preserve-tos-ttl)
+//!
+//! 收包时通过 recvmsg 的控制消息读取数据包是否分片到达 (IP_RECVFRAGSIZE)、TOS/Traffic Class
+//! (IP_RECVTOS/IPV6_RECVTCLASS) 和 TTL/Hop Limit (IP_RECVTTL/IPV6_RECVHOPLIMIT)，发包时用
+//! sendmsg 的控制消息逐包设置 TOS 和 TTL，使经过转发的流量在 AQM (ECN) 和 traceroute 看来与直连一致。
+//!
+//! 本机按一跳计算：转发时 TTL 减一，减到 0 的数据包丢弃 (不回 ICMP 超时)。
+//! 配置了 `--tos` 时外连方向保留配置的 DSCP，只沿用 ECN 位
+
+#[cfg(target_os = "linux")]
+use crate::types::Address;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::net::SocketAddr;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+
+/// TOS 字节中的 ECN 位
+pub const ECN_MASK: u8 = 0x03;
+
+/// 从收到的数据包读取的 IP 头字段，未启用对应选项时为默认值
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IpHeader {
+    /// 数据包是否分片到达 (启用 IP_RECVFRAGSIZE 时)
+    pub fragmented: bool,
+    /// TOS/Traffic Class 字节
+    pub tos: Option<u8>,
+    /// TTL/Hop Limit
+    pub ttl: Option<u8>,
+}
+
+impl IpHeader {
+    /// 转发时使用的字段：TTL 减一，减到 0 时返回 None 表示丢弃；
+    /// `dscp` 为配置的 TOS 字节时保留其 DSCP，只沿用收到的 ECN 位
+    pub fn forward(&self, dscp: Option<u8>) -> Option<Self> {
+        let ttl = match self.ttl {
+            Some(ttl) if ttl <= 1 => return None,
+            ttl => ttl.map(|ttl| ttl - 1),
+        };
+        let tos = match (self.tos, dscp) {
+            (Some(tos), Some(dscp)) => Some((dscp & !ECN_MASK) | (tos & ECN_MASK)),
+            (tos, _) => tos,
+        };
+        Some(Self {
+            fragmented: self.fragmented,
+            tos,
+            ttl,
+        })
+    }
+
+    /// 是否需要逐包设置 TOS 或 TTL
+    pub fn has_marks(&self) -> bool {
+        self.tos.is_some() || self.ttl.is_some()
+    }
+}
+
+/// 启用 IP_RECVTOS/IP_RECVTTL 和 IPV6_RECVTCLASS/IPV6_RECVHOPLIMIT
+///
+/// 双栈 IPv6 socket 收到的 IPv4 数据包也用 IPv4 选项报告，因此四个选项都尝试设置，至少一组成功即可
+#[cfg(target_os = "linux")]
+pub fn enable_recv_tos_ttl(fd: RawFd) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let set = |level, name| unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    };
+    let v4 = set(libc::SOL_IP, libc::IP_RECVTOS) & set(libc::SOL_IP, libc::IP_RECVTTL);
+    let v6 =
+        set(libc::SOL_IPV6, libc::IPV6_RECVTCLASS) & set(libc::SOL_IPV6, libc::IPV6_RECVHOPLIMIT);
+    if v4 || v6 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 读取一个数据包，返回 `(长度, 来源地址, IP 头字段)`
+///
+/// 只能读到 socket 已启用的控制消息，其他字段保持默认值
+#[cfg(target_os = "linux")]
+pub fn recv_from(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpHeader)> {
+    let mut from: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut from as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut header = IpHeader::default();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        // IP_TOS 为单字节，其余为 int
+        let int = || unsafe { std::ptr::read_unaligned(data as *const libc::c_int) };
+        match (hdr.cmsg_level, hdr.cmsg_type) {
+            (libc::SOL_IP, libc::IP_RECVFRAGSIZE) | (libc::SOL_IPV6, libc::IPV6_RECVFRAGSIZE) => {
+                header.fragmented = true
+            }
+            (libc::SOL_IP, libc::IP_TOS) => header.tos = Some(unsafe { *data }),
+            (libc::SOL_IPV6, libc::IPV6_TCLASS) => header.tos = Some(int() as u8),
+            (libc::SOL_IP, libc::IP_TTL) | (libc::SOL_IPV6, libc::IPV6_HOPLIMIT) => {
+                header.ttl = Some(int() as u8)
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    let from =
+        Address::from_raw_sockaddr(&from as *const _ as *const libc::sockaddr, msg.msg_namelen)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok((ret as usize, from.to_sockaddr(), header))
+}
+
+/// 发送一个数据包并逐包设置 `header` 中的 TOS 和 TTL，`dest` 为 None 时发往 socket 已连接的地址
+///
+/// 控制消息的协议层按目标地址选择 (IPv4 和 IPv4 映射地址用 IPv4 选项)，已连接时按 `ipv6` 选择。
+/// 返回值同 `sendmsg`
+#[cfg(target_os = "linux")]
+pub fn send_to(
+    fd: RawFd,
+    payload: &[u8],
+    dest: Option<&Address>,
+    header: &IpHeader,
+    ipv6: bool,
+) -> isize {
+    let ipv6 = match dest.map(Address::to_sockaddr) {
+        Some(SocketAddr::V6(addr)) => addr.ip().to_ipv4_mapped().is_none(),
+        Some(SocketAddr::V4(_)) => false,
+        None => ipv6,
+    };
+    let (level, tos_type, ttl_type) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_TCLASS, libc::IPV6_HOPLIMIT)
+    } else {
+        (libc::SOL_IP, libc::IP_TOS, libc::IP_TTL)
+    };
+    let cmsgs: Vec<(libc::c_int, libc::c_int)> = [(tos_type, header.tos), (ttl_type, header.ttl)]
+        .into_iter()
+        .filter_map(|(ty, val)| Some((ty, libc::c_int::from(val?))))
+        .collect();
+
+    let addr = dest.map(Address::to_sockaddr_storage);
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    if let (Some(dest), Some(addr)) = (dest, addr.as_ref()) {
+        msg.msg_name = addr as *const _ as *mut libc::c_void;
+        msg.msg_namelen = dest.get_len() as libc::socklen_t;
+    }
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !cmsgs.is_empty() {
+        let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) };
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (space as usize * cmsgs.len()) as _;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        for (ty, val) in cmsgs {
+            unsafe {
+                (*cmsg).cmsg_level = level;
+                (*cmsg).cmsg_type = ty;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+                std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, val);
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+    unsafe { libc::sendmsg(fd, &msg, 0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward() {
+        let header = IpHeader {
+            fragmented: true,
+            tos: Some(0xb9),
+            ttl: Some(64),
+        };
+        let forwarded = header.forward(None).expect("forwarded");
+        assert_eq!(forwarded.tos, Some(0xb9));
+        assert_eq!(forwarded.ttl, Some(63));
+        assert!(forwarded.fragmented);
+
+        // 配置的 DSCP 优先，ECN 位沿用收到的
+        assert_eq!(
+            header.forward(Some(0x20)).expect("forwarded").tos,
+            Some(0x21)
+        );
+
+        // TTL 减到 0 时丢弃
+        let expiring = IpHeader {
+            ttl: Some(1),
+            ..header
+        };
+        assert_eq!(expiring.forward(None), None);
+
+        // 未读取的字段不设置
+        let plain = IpHeader::default();
+        assert_eq!(plain.forward(Some(0x20)), Some(plain));
+        assert!(!plain.has_marks());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_send_and_recv() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        enable_recv_tos_ttl(server.as_raw_fd()).expect("IP_RECVTOS");
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let server_addr = Address::from_sockaddr(server.local_addr().expect("addr"));
+
+        let header = IpHeader {
+            fragmented: false,
+            tos: Some(0x2a),
+            ttl: Some(7),
+        };
+        let sent = send_to(
+            client.as_raw_fd(),
+            b"marked",
+            Some(&server_addr),
+            &header,
+            false,
+        );
+        assert_eq!(sent, 6);
+
+        let mut buf = vec![0u8; 2048];
+        let (len, from, received) = recv_from(server.as_raw_fd(), &mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"marked");
+        assert_eq!(from, client.local_addr().expect("addr"));
+        assert_eq!(received, header);
+
+        // 不带控制消息时为系统默认值
+        client
+            .connect(server.local_addr().expect("addr"))
+            .expect("connect");
+        let sent = send_to(
+            client.as_raw_fd(),
+            b"plain",
+            None,
+            &IpHeader::default(),
+            false,
+        );
+        assert_eq!(sent, 5);
+        let (_, _, received) = recv_from(server.as_raw_fd(), &mut buf).expect("recv");
+        assert_eq!(received.tos, Some(0));
+        assert!(received.ttl.is_some_and(|ttl| ttl > 7));
+    }
+}
@@ -2,24 +2,79 @@
 //!
 //! 轻量级高性能端口映射/转发工具
 
+// single-thread 特性下事件循环内部的锁不是 Sync，共享它们的 Arc 只在事件循环线程中使用
+#![cfg_attr(feature = "single-thread", allow(clippy::arc_with_non_send_sync))]
+
+pub mod alg;
+pub mod autotune;
+pub mod backend;
+pub mod ban;
+pub mod bench;
+pub mod bufpool;
+pub mod capabilities;
+pub mod chaos;
+pub mod clock;
+pub mod compress;
 pub mod config;
 pub mod connection;
+pub mod echo;
 #[macro_use]
 pub mod event;
+pub mod error;
 pub mod fd_manager;
+pub mod fragment;
+pub mod ftp;
+pub mod geo;
+pub mod health;
+pub mod hook;
+pub mod ipheader;
 pub mod log;
 pub mod lru;
 pub mod manager;
+pub mod mapper;
+pub mod memory;
+pub mod middleware;
+pub mod mirror;
+pub mod multicast;
+#[cfg(windows)]
+pub mod npipe;
+pub mod obfs;
+pub mod punch;
+pub mod quic;
+pub mod ratelimit;
+pub mod sandbox;
+pub mod selftest;
+pub mod sim;
+pub mod slab;
+pub mod sni;
+pub mod sniff;
+pub mod sockets;
+#[cfg(target_os = "linux")]
+pub mod sockmap;
+pub mod socks5;
 pub mod stats;
+pub mod stun;
+pub mod sync;
+pub mod systemd;
+pub mod tenant;
+pub mod top;
 pub mod types;
+pub mod upgrade;
+pub mod upnp;
+pub mod webhook;
+#[cfg(windows)]
+mod winsock;
 
 // Include the build module generated by build.rs
 include!(concat!(env!("OUT_DIR"), "/build.rs"));
 
+pub use error::Error;
 pub use fd_manager::{Fd64, FdManager};
 pub use log::{
-    get_current_time, is_about_to_exit, set_about_to_exit, LogLevel, Logger, MY_DEBUG_MODE,
+    get_current_time, get_monotonic_time, is_about_to_exit, set_about_to_exit, LogErrorPolicy,
+    LogLevel, Logger, MY_DEBUG_MODE,
 };
+pub use mapper::{PortMapper, PortMapperBuilder, PortMapperHandle};
 
 // 跨平台 RawFd 类型别名（定义在开头供其他函数使用）
 #[cfg(unix)]
@@ -28,6 +83,9 @@ pub(crate) type PlatformRawFd = std::os::unix::io::RawFd;
 #[cfg(windows)]
 pub(crate) type PlatformRawFd = std::os::windows::io::RawSocket;
 
+#[cfg(windows)]
+use crate::winsock as libc;
+
 /// 获取当前时间戳（微秒）
 ///
 /// 类似 C++ 版本的 get_current_time_us()，带时间修正
@@ -132,14 +190,63 @@ pub fn hton64(val: u64) -> u64 {
 
 /// 获取 socket 错误描述
 pub fn get_sock_error() -> String {
-    std::io::Error::last_os_error().to_string()
+    std::io::Error::from_raw_os_error(get_sock_errno()).to_string()
 }
 
 /// 获取 socket 错误码
+#[cfg(unix)]
 pub fn get_sock_errno() -> i32 {
     std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
 }
 
+/// 获取 socket 错误码 (WSAGetLastError)
+#[cfg(windows)]
+pub fn get_sock_errno() -> i32 {
+    unsafe { winapi::um::winsock2::WSAGetLastError() }
+}
+
+/// 创建 socket，Linux 上设置 SOCK_CLOEXEC
+#[cfg(unix)]
+pub fn new_socket(
+    family: libc::c_int,
+    ty: libc::c_int,
+    protocol: libc::c_int,
+) -> std::io::Result<PlatformRawFd> {
+    #[cfg(target_os = "linux")]
+    let ty = ty | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(family, ty, protocol) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// 创建 socket (Windows)，与标准库一样使用 overlapped 模式并禁止子进程继承句柄
+#[cfg(windows)]
+pub fn new_socket(
+    family: libc::c_int,
+    ty: libc::c_int,
+    protocol: libc::c_int,
+) -> std::io::Result<PlatformRawFd> {
+    use winapi::um::winsock2::{
+        WSASocketW, INVALID_SOCKET, WSA_FLAG_NO_HANDLE_INHERIT, WSA_FLAG_OVERLAPPED,
+    };
+    let socket = unsafe {
+        WSASocketW(
+            family,
+            ty,
+            protocol,
+            std::ptr::null_mut(),
+            0,
+            WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT,
+        )
+    };
+    if socket == INVALID_SOCKET {
+        return Err(std::io::Error::from_raw_os_error(get_sock_errno()));
+    }
+    Ok(socket as PlatformRawFd)
+}
+
 /// 设置非阻塞 socket
 #[cfg(unix)]
 pub fn set_nonblocking(fd: std::os::unix::io::RawFd) -> std::io::Result<()> {
@@ -154,20 +261,293 @@ pub fn set_nonblocking(fd: std::os::unix::io::RawFd) -> std::io::Result<()> {
     Ok(())
 }
 
-/// 设置非阻塞 socket (Windows)
+/// 设置非阻塞 socket (Windows 上通过 ioctlsocket FIONBIO)
 #[cfg(windows)]
 pub fn set_nonblocking(fd: std::os::windows::io::RawSocket) -> std::io::Result<()> {
-    use std::os::windows::io::AsRawSocket;
+    let mut nonblocking: u32 = 1;
+    if unsafe { libc::ioctlsocket(fd, libc::FIONBIO, &mut nonblocking) } != 0 {
+        return Err(std::io::Error::from_raw_os_error(get_sock_errno()));
+    }
+    Ok(())
+}
+
+/// 设置 IP_TRANSPARENT/IPV6_TRANSPARENT，允许绑定和接受非本机地址 (需要 CAP_NET_ADMIN)
+#[cfg(target_os = "linux")]
+pub fn set_transparent(fd: std::os::unix::io::RawFd, family: libc::c_int) -> std::io::Result<()> {
+    let (level, name) = if family == libc::AF_INET6 {
+        (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 设置 IP_TRANSPARENT (非 Linux 平台不支持)
+#[cfg(not(target_os = "linux"))]
+pub fn set_transparent(_fd: PlatformRawFd, _family: libc::c_int) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IP_TRANSPARENT is only supported on Linux",
+    ))
+}
+
+/// 透明代理：把外连 socket 绑定到客户端 IP (端口由内核分配)
+///
+/// 客户端是 IPv4-mapped IPv6 地址而 socket 为 IPv4 时自动转换，其他地址族不一致的情况返回错误
+pub fn bind_transparent(
+    fd: PlatformRawFd,
+    family: libc::c_int,
+    client: std::net::SocketAddr,
+) -> std::io::Result<()> {
+    set_transparent(fd, family)?;
+    bind_source(fd, family, Some(client.ip()), None)
+}
+
+/// 把外连 socket 绑定到源地址 `ip` (None 时为通配地址) 和 `ports` 中的端口 (None 时由内核分配)
+///
+/// `ip` 是 IPv4-mapped IPv6 地址而 socket 为 IPv4 时自动转换，其他地址族不一致的情况返回错误。
+/// 端口从范围内的随机位置开始依次尝试，被占用 (EADDRINUSE) 时换下一个
+pub fn bind_source(
+    fd: PlatformRawFd,
+    family: libc::c_int,
+    ip: Option<std::net::IpAddr>,
+    ports: Option<config::PortRange>,
+) -> std::io::Result<()> {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    let ip = match (ip, family) {
+        (None, libc::AF_INET6) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        (None, _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (Some(IpAddr::V6(v6)), libc::AF_INET) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        (Some(ip), _) => ip,
+    };
+    let ip_family = if ip.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    if ip_family != family {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("source {} and remote use different address families", ip),
+        ));
+    }
+
+    let bind = |port: u16| {
+        let source = types::Address::from_sockaddr(SocketAddr::new(ip, port));
+        let sockaddr = source.to_sockaddr_storage();
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &sockaddr as *const _ as *const libc::sockaddr,
+                source.get_len() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    let Some(ports) = ports else {
+        return bind(0);
+    };
+    let first = random_between(0, ports.len() - 1);
+    for i in 0..ports.len() {
+        let port = u32::from(ports.start) + (first + i) % ports.len();
+        match bind(port as u16) {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        format!("no free source port in {} on {}", ports, ip),
+    ))
+}
+
+/// 从 `--bind-source` 的地址中选出与 socket 地址族 `family` 相同的源地址
+pub fn pick_source(sources: &[std::net::IpAddr], family: libc::c_int) -> Option<std::net::IpAddr> {
+    sources
+        .iter()
+        .find(|ip| (family == libc::AF_INET6) == ip.is_ipv6())
+        .copied()
+}
+
+/// socket 的地址族 (由 getsockname 获取)
+#[cfg(unix)]
+fn socket_family(fd: std::os::unix::io::RawFd) -> std::io::Result<libc::c_int> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) } < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(libc::c_int::from(storage.ss_family))
+}
+
+/// 把 socket 绑定到网络接口 `interface` (SO_BINDTODEVICE)，之后只经该接口收发
+#[cfg(target_os = "linux")]
+pub fn set_bind_to_device(fd: std::os::unix::io::RawFd, interface: &str) -> std::io::Result<()> {
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+    let len = std::cmp::min(interface.len(), libc::IFNAMSIZ - 1);
     unsafe {
-        let mut nonblocking: u32 = 1;
-        let result = libc::ioctlsocket(
-            fd.as_raw_socket() as std::os::windows::io::RawSocket,
-            libc::FIONBIO,
-            &nonblocking as *const _ as *mut libc::c_void,
+        std::ptr::copy_nonoverlapping(
+            interface.as_ptr() as *const libc::c_char,
+            ifreq.ifr_name.as_mut_ptr(),
+            len,
         );
-        if result != 0 {
+    }
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            &ifreq as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::ifreq>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 把 socket 绑定到网络接口 `interface` (按接口索引设置 IP_BOUND_IF/IPV6_BOUND_IF)
+///
+/// 需要在 bind/connect 之前设置，非 IP socket 直接返回
+#[cfg(target_vendor = "apple")]
+pub fn set_bind_to_device(fd: std::os::unix::io::RawFd, interface: &str) -> std::io::Result<()> {
+    let name = std::ffi::CString::new(interface)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("interface {} not found", interface),
+        ));
+    }
+    let (level, name) = match socket_family(fd)? {
+        libc::AF_INET => (libc::IPPROTO_IP, libc::IP_BOUND_IF),
+        libc::AF_INET6 => (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF),
+        _ => return Ok(()),
+    };
+    let index = index as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &index as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 绑定网络接口 (其他平台不支持)
+#[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+pub fn set_bind_to_device(_fd: PlatformRawFd, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux and macOS",
+    ))
+}
+
+/// 按 `SocketMark` 设置 IP_TOS/IPV6_TCLASS 和 SO_MARK，非 IP socket 直接返回
+///
+/// IPv6 socket 同时设置 IP_TOS，发往 IPv4-mapped 地址的数据包使用它
+#[cfg(unix)]
+pub fn set_socket_mark(
+    fd: std::os::unix::io::RawFd,
+    mark: &config::SocketMark,
+) -> std::io::Result<()> {
+    let setsockopt = |level: libc::c_int, name: libc::c_int, value: libc::c_int| {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
             return Err(std::io::Error::last_os_error());
         }
+        Ok(())
+    };
+
+    let family = socket_family(fd)?;
+    if family != libc::AF_INET && family != libc::AF_INET6 {
+        return Ok(());
+    }
+
+    if let Some(tos) = mark.tos {
+        let tos = libc::c_int::from(tos);
+        if family == libc::AF_INET6 {
+            setsockopt(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+            let _ = setsockopt(libc::IPPROTO_IP, libc::IP_TOS, tos);
+        } else {
+            setsockopt(libc::IPPROTO_IP, libc::IP_TOS, tos)?;
+        }
+    }
+    if let Some(fwmark) = mark.fwmark {
+        #[cfg(target_os = "linux")]
+        setsockopt(libc::SOL_SOCKET, libc::SO_MARK, fwmark as libc::c_int)?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = fwmark;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "SO_MARK is only supported on Linux",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 设置 DSCP/TOS 和 SO_MARK (Windows 不支持)
+#[cfg(windows)]
+pub fn set_socket_mark(_fd: PlatformRawFd, _mark: &config::SocketMark) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "socket marking is not supported on Windows",
+    ))
+}
+
+/// 设置 SO_BROADCAST，允许 UDP socket 发往广播地址
+pub fn set_broadcast(fd: PlatformRawFd) -> std::io::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BROADCAST,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
     }
     Ok(())
 }
@@ -203,7 +583,6 @@ pub fn set_buf_size(fd: PlatformRawFd, size: usize) -> std::io::Result<()> {
 /// my_ntoa - 将 IPv4 地址 u32 转换为点分十进制字符串
 ///
 /// 对应 C++ 版本: `char * my_ntoa(u32_t ip)`
-#[cfg(unix)]
 pub fn my_ntoa(ip: u32) -> String {
     let octet1 = (ip >> 24) & 0xFF;
     let octet2 = (ip >> 16) & 0xFF;
@@ -212,36 +591,6 @@ pub fn my_ntoa(ip: u32) -> String {
     format!("{}.{}.{}.{}", octet1, octet2, octet3, octet4)
 }
 
-/// my_ntoa - Windows 版本
-#[cfg(windows)]
-pub fn my_ntoa(ip: u32) -> String {
-    use std::ptr;
-    let mut addr: libc::in_addr = unsafe { std::mem::zeroed() };
-    unsafe {
-        addr.s_addr = ip.to_be();
-        let mut buf: [i8; 16] = [0; 16];
-        if libc::inet_ntop(
-            libc::AF_INET,
-            &addr as *const _ as *const libc::c_void,
-            buf.as_mut_ptr() as *mut libc::c_char,
-            16,
-        ) != ptr::null_mut()
-        {
-            unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }
-                .to_string_lossy()
-                .into_owned()
-        } else {
-            format!(
-                "{}.{}.{}.{}",
-                (ip >> 24) & 0xFF,
-                (ip >> 16) & 0xFF,
-                (ip >> 8) & 0xFF,
-                ip & 0xFF
-            )
-        }
-    }
-}
-
 /// larger_than_u16 - 检查 a 是否大于 b（考虑 u16 溢出）
 ///
 /// 对应 C++ 版本: `bool larger_than_u16(uint16_t a,uint16_t b)`
@@ -331,7 +680,9 @@ pub fn format_log_with_position(
     output.push_str(&format!("[{}]", timestamp));
     output.push_str(color);
     output.push_str(&level_str);
-    output.push_str(RESET);
+    if !color.is_empty() {
+        output.push_str(RESET);
+    }
 
     if logger.is_position_enabled() {
         // 使用调用者的位置信息 (类似 C++ 的 __FILE__:__LINE__:__func__)
@@ -351,16 +702,19 @@ pub fn format_log_with_position(
         set_about_to_exit();
     }
 
-    println!("{}", output);
+    if logger.is_console_enabled() {
+        println!("{}", output);
+    }
 
     // 同时写入日志文件（无颜色）
-    if !output.contains("\x1b[") {
-        logger.write_to_file(&output);
-    } else {
+    #[cfg(feature = "color")]
+    if output.contains("\x1b[") {
         // 去除 ANSI 颜色码后写入文件
         let plain_output = strip_ansi_escapes::strip_str(&output);
         logger.write_to_file(&plain_output);
+        return;
     }
+    logger.write_to_file(&output);
 }
 
 /// MY_DEBUG 模式下的简化日志输出 (与 C++ 版本保持一致)
@@ -383,7 +737,9 @@ pub fn format_log_with_position(
 #[doc(hidden)]
 #[cfg(not(feature = "my_debug"))]
 pub fn log_bare_impl(args: std::fmt::Arguments<'_>) {
-    print!("{}", args);
+    if Logger::global().is_console_enabled() {
+        print!("{}", args);
+    }
 }
 
 /// MY_DEBUG 模式下的 bare logging
@@ -817,3 +1173,130 @@ mod random_tests {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod socket_tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    fn getsockopt_int(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = -1;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            );
+        }
+        value
+    }
+
+    #[test]
+    fn test_set_socket_mark() {
+        let mark = config::SocketMark {
+            tos: Some(0xb8),
+            fwmark: None,
+        };
+        let v4 = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind v4");
+        set_socket_mark(v4.as_raw_fd(), &mark).expect("mark v4");
+        assert_eq!(
+            getsockopt_int(v4.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS),
+            0xb8
+        );
+
+        if let Ok(v6) = std::net::UdpSocket::bind("[::1]:0") {
+            set_socket_mark(v6.as_raw_fd(), &mark).expect("mark v6");
+            assert_eq!(
+                getsockopt_int(v6.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+                0xb8
+            );
+        }
+
+        // 非 IP socket 不设置
+        let (unix, _) = std::os::unix::net::UnixStream::pair().expect("socketpair");
+        set_socket_mark(unix.as_raw_fd(), &mark).expect("mark unix");
+    }
+
+    #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+    #[test]
+    fn test_set_bind_to_device() {
+        #[cfg(target_os = "linux")]
+        let loopback = "lo";
+        #[cfg(target_vendor = "apple")]
+        let loopback = "lo0";
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        set_bind_to_device(socket.as_raw_fd(), loopback).expect("bind to loopback");
+        assert!(set_bind_to_device(socket.as_raw_fd(), "no-such-if0").is_err());
+    }
+
+    #[test]
+    fn test_bind_source() {
+        let v4: std::net::IpAddr = "127.0.0.1".parse().expect("ip");
+        let v6: std::net::IpAddr = "::1".parse().expect("ip");
+        assert_eq!(pick_source(&[v6, v4], libc::AF_INET), Some(v4));
+        assert_eq!(pick_source(&[v6, v4], libc::AF_INET6), Some(v6));
+        assert_eq!(pick_source(&[v4], libc::AF_INET6), None);
+
+        let connected = types::Address::from_ipv4(std::net::Ipv4Addr::LOCALHOST, 9)
+            .new_connected_udp_fd(64 * 1024, &[v6, v4], None)
+            .expect("connected socket");
+        let socket = unsafe {
+            <std::net::UdpSocket as std::os::unix::io::FromRawFd>::from_raw_fd(connected)
+        };
+        assert_eq!(socket.local_addr().expect("local").ip(), v4);
+
+        // 地址族不一致时报错，IPv4-mapped 地址可用于 IPv4 socket
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        assert!(bind_source(fd, libc::AF_INET, Some(v6), None).is_err());
+        let mapped: std::net::IpAddr = "::ffff:127.0.0.1".parse().expect("ip");
+        bind_source(fd, libc::AF_INET, Some(mapped), None).expect("bind mapped");
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn test_bind_source_ports() {
+        use config::PortRange;
+
+        assert_eq!(
+            "40000-50000".parse::<PortRange>(),
+            Ok(PortRange {
+                start: 40000,
+                end: 50000
+            })
+        );
+        assert_eq!("8000".parse::<PortRange>().map(|r| r.len()), Ok(1));
+        assert!("50000-40000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("1-70000".parse::<PortRange>().is_err());
+
+        // 占用范围内的一个端口，另一个端口总能绑定成功
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let port = taken.local_addr().expect("addr").port();
+        let localhost = Some(std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST));
+        let only_taken = PortRange {
+            start: port,
+            end: port,
+        };
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        let err = bind_source(fd, libc::AF_INET, localhost, Some(only_taken)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        unsafe { libc::close(fd) };
+
+        let (start, end) = if port > 1024 {
+            (port - 1, port)
+        } else {
+            (port, port + 1)
+        };
+        let connected = types::Address::from_ipv4(std::net::Ipv4Addr::LOCALHOST, 9)
+            .new_connected_udp_fd(64 * 1024, &[], Some(PortRange { start, end }))
+            .expect("connected socket");
+        let socket = unsafe {
+            <std::net::UdpSocket as std::os::unix::io::FromRawFd>::from_raw_fd(connected)
+        };
+        let bound = socket.local_addr().expect("local").port();
+        assert!(bound != port && (start..=end).contains(&bound));
+    }
+}
@@ -3,10 +3,48 @@
 //! 提供彩色日志输出和级别控制
 //! 支持 MY_DEBUG 调试模式（与 C++ 版本保持一致）
 
+use crate::sync::Recover;
 use std::fmt;
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 日志文件写入失败后重新打开的间隔
+pub const LOG_REOPEN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 截断 IP 地址：IPv4 保留 /24，IPv6 保留 /48，端口不变
+pub fn anonymize_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => {
+                let [a, b, c, _] = v4.octets();
+                IpAddr::V6(std::net::Ipv4Addr::new(a, b, c, 0).to_ipv6_mapped())
+            }
+            None => {
+                let s = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+            }
+        },
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+/// 生成用于日志、观察者事件和排空报告的客户端地址字符串
+///
+/// 启用 `--log-anonymize-ips` 时返回截断后的地址，转发仍使用完整地址
+pub fn client_addr(addr: SocketAddr) -> String {
+    if Logger::global().is_anonymize_ips_enabled() {
+        anonymize_addr(addr).to_string()
+    } else {
+        addr.to_string()
+    }
+}
 
 /// 全局退出状态标记（与 C++ 版本保持一致）
 ///
@@ -95,6 +133,64 @@ impl fmt::Display for LogLevel {
     }
 }
 
+/// 日志文件写入失败时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogErrorPolicy {
+    /// 静默丢弃 (仅计数)
+    Drop = 0,
+    /// 在 stderr 上报告错误 (默认)
+    Stderr = 1,
+    /// 报告错误后退出进程
+    Exit = 2,
+}
+
+impl From<u8> for LogErrorPolicy {
+    fn from(val: u8) -> Self {
+        match val {
+            0 => LogErrorPolicy::Drop,
+            2 => LogErrorPolicy::Exit,
+            _ => LogErrorPolicy::Stderr,
+        }
+    }
+}
+
+impl std::str::FromStr for LogErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(LogErrorPolicy::Drop),
+            "stderr" => Ok(LogErrorPolicy::Stderr),
+            "exit" => Ok(LogErrorPolicy::Exit),
+            _ => Err(format!(
+                "invalid log error policy: {}, must be drop/stderr/exit",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogErrorPolicy::Drop => write!(f, "drop"),
+            LogErrorPolicy::Stderr => write!(f, "stderr"),
+            LogErrorPolicy::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+/// 日志文件状态
+#[derive(Debug)]
+struct LogFileState {
+    /// 已打开的文件 (写入失败后置为 None)
+    file: Option<std::fs::File>,
+    /// 文件路径 (用于重新打开)
+    path: Option<String>,
+    /// 下次尝试重新打开的时间
+    retry_at: Option<Instant>,
+}
+
 impl Default for Logger {
     fn default() -> Self {
         Self::new()
@@ -111,7 +207,17 @@ pub struct Logger {
     /// 是否显示位置信息
     enable_position: AtomicBool,
     /// 日志文件 (Mutex 保护)
-    log_file: Mutex<Option<std::fs::File>>,
+    log_file: Mutex<LogFileState>,
+    /// 日志文件写入失败时的处理策略
+    error_policy: AtomicU8,
+    /// 未能写入日志文件的消息数
+    write_errors: AtomicU64,
+    /// 日志和连接记录中是否隐去客户端地址
+    anonymize_ips: AtomicBool,
+    /// 切换到 debug 前的日志级别，再次切换时恢复
+    saved_level: AtomicU8,
+    /// 是否输出到控制台 (--top 时关闭，日志只写入日志文件)
+    console: AtomicBool,
 }
 
 impl Logger {
@@ -121,13 +227,24 @@ impl Logger {
             log_level: AtomicU8::new(LogLevel::Info as u8),
             enable_color: AtomicBool::new(true),
             enable_position: AtomicBool::new(true),
-            log_file: Mutex::new(None),
+            log_file: Mutex::new(LogFileState {
+                file: None,
+                path: None,
+                retry_at: None,
+            }),
+            error_policy: AtomicU8::new(LogErrorPolicy::Stderr as u8),
+            write_errors: AtomicU64::new(0),
+            anonymize_ips: AtomicBool::new(false),
+            saved_level: AtomicU8::new(LogLevel::Info as u8),
+            console: AtomicBool::new(true),
         }
     }
 
     /// 检查是否启用颜色
+    ///
+    /// 未启用 `color` feature 时始终返回 false
     pub fn is_color_enabled(&self) -> bool {
-        self.enable_color.load(Ordering::Relaxed)
+        cfg!(feature = "color") && self.enable_color.load(Ordering::Relaxed)
     }
 
     /// 设置日志级别
@@ -140,6 +257,21 @@ impl Logger {
         LogLevel::from(self.log_level.load(Ordering::Relaxed))
     }
 
+    /// 在 debug 和原日志级别之间切换，返回切换后的级别
+    ///
+    /// 当前级别低于 debug 时记住当前级别并切换到 debug，否则恢复之前记住的级别 (默认 info)
+    pub fn toggle_debug(&self) -> LogLevel {
+        let current = self.get_level();
+        let level = if current >= LogLevel::Debug {
+            LogLevel::from(self.saved_level.load(Ordering::Relaxed))
+        } else {
+            self.saved_level.store(current as u8, Ordering::Relaxed);
+            LogLevel::Debug
+        };
+        self.set_level(level);
+        level
+    }
+
     /// 启用/禁用颜色
     pub fn set_color(&self, enable: bool) {
         self.enable_color.store(enable, Ordering::Relaxed);
@@ -155,27 +287,142 @@ impl Logger {
         self.enable_position.load(Ordering::Relaxed)
     }
 
+    /// 启用/禁用客户端地址匿名化
+    pub fn set_anonymize_ips(&self, enable: bool) {
+        self.anonymize_ips.store(enable, Ordering::Relaxed);
+    }
+
+    /// 检查是否启用客户端地址匿名化
+    pub fn is_anonymize_ips_enabled(&self) -> bool {
+        self.anonymize_ips.load(Ordering::Relaxed)
+    }
+
+    /// 启用/禁用控制台输出
+    pub fn set_console(&self, enable: bool) {
+        self.console.store(enable, Ordering::Relaxed);
+    }
+
+    /// 检查是否输出到控制台
+    pub fn is_console_enabled(&self) -> bool {
+        self.console.load(Ordering::Relaxed)
+    }
+
     /// 检查级别是否启用
     pub fn is_enabled(&self, level: LogLevel) -> bool {
         level as u8 <= self.log_level.load(Ordering::Relaxed)
     }
 
+    /// 设置日志文件写入失败时的处理策略
+    pub fn set_error_policy(&self, policy: LogErrorPolicy) {
+        self.error_policy.store(policy as u8, Ordering::Relaxed);
+    }
+
+    /// 获取日志文件写入失败时的处理策略
+    pub fn get_error_policy(&self) -> LogErrorPolicy {
+        LogErrorPolicy::from(self.error_policy.load(Ordering::Relaxed))
+    }
+
+    /// 未能写入日志文件的消息数
+    pub fn write_error_count(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
     /// 打开日志文件
+    ///
+    /// 打开失败时仍会记录路径，之后按 `LOG_REOPEN_INTERVAL` 重试
     pub fn open_log_file(&self, path: &str) -> Result<(), std::io::Error> {
+        let mut guard = self.log_file.lock().recover();
+        guard.path = Some(path.to_string());
+        guard.file = None;
+        guard.retry_at = Some(Instant::now() + LOG_REOPEN_INTERVAL);
+
         let mut file = std::fs::File::create(path)?;
         // 写入 BOM 以支持中文
         file.write_all(b"\xef\xbb\xbf")?;
-        let mut guard = self.log_file.lock().expect("Mutex poisoned");
-        *guard = Some(file);
+        guard.file = Some(file);
+        guard.retry_at = None;
         Ok(())
     }
 
     /// 写入日志到文件
+    ///
+    /// 写入失败时按策略处理，并在 `LOG_REOPEN_INTERVAL` 后尝试重新打开
     pub fn write_to_file(&self, msg: &str) {
-        if let Ok(mut guard) = self.log_file.lock() {
-            if let Some(ref mut file) = *guard {
-                let _ = file.write_all(msg.as_bytes());
-                let _ = file.write_all(b"\n");
+        let Ok(mut guard) = self.log_file.lock() else {
+            return;
+        };
+        if guard.path.is_none() {
+            return;
+        }
+        if guard.file.is_none() && !self.try_reopen(&mut guard) {
+            self.write_errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let result = match guard.file {
+            Some(ref mut file) => file
+                .write_all(msg.as_bytes())
+                .and_then(|_| file.write_all(b"\n")),
+            None => return,
+        };
+        if let Err(e) = result {
+            guard.file = None;
+            guard.retry_at = Some(Instant::now() + LOG_REOPEN_INTERVAL);
+            self.write_errors.fetch_add(1, Ordering::Relaxed);
+            self.report_write_error(guard.path.as_deref().unwrap_or(""), &e);
+        }
+    }
+
+    /// 到达重试时间后以追加模式重新打开日志文件
+    fn try_reopen(&self, state: &mut LogFileState) -> bool {
+        let Some(ref path) = state.path else {
+            return false;
+        };
+        let now = Instant::now();
+        if state.retry_at.is_some_and(|at| now < at) {
+            return false;
+        }
+
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(mut file) => {
+                let errors = self.write_error_count();
+                if self.get_error_policy() != LogErrorPolicy::Drop {
+                    eprintln!(
+                        "[log] log file '{}' reopened, {} messages lost",
+                        path, errors
+                    );
+                }
+                let _ = writeln!(file, "[log] log file reopened, {} messages lost", errors);
+                state.file = Some(file);
+                state.retry_at = None;
+                true
+            }
+            Err(_) => {
+                state.retry_at = Some(now + LOG_REOPEN_INTERVAL);
+                false
+            }
+        }
+    }
+
+    /// 按策略报告日志文件写入失败
+    fn report_write_error(&self, path: &str, err: &std::io::Error) {
+        match self.get_error_policy() {
+            LogErrorPolicy::Drop => {}
+            LogErrorPolicy::Stderr => {
+                eprintln!(
+                    "[log] failed to write log file '{}': {}, retrying every {}s",
+                    path,
+                    err,
+                    LOG_REOPEN_INTERVAL.as_secs()
+                );
+            }
+            LogErrorPolicy::Exit => {
+                eprintln!("[log] failed to write log file '{}': {}, exit", path, err);
+                std::process::exit(1);
             }
         }
     }
@@ -194,7 +441,8 @@ impl Logger {
 
 /// 获取当前时间戳（毫秒）- 与 C++ 版本保持一致
 ///
-/// 使用时间修正逻辑，确保时间戳单调递增，处理系统时间回跳
+/// 墙上时间，使用时间修正逻辑确保时间戳单调递增，处理系统时间回跳，
+/// 但系统时间向前跳变时仍会跟着跳。超时计算使用 `get_monotonic_time`
 pub fn get_current_time() -> u64 {
     use std::sync::atomic::{AtomicU64, Ordering};
     use std::time::SystemTime;
@@ -225,10 +473,32 @@ pub fn get_current_time() -> u64 {
     (raw_value + value_fix) / 1000
 }
 
+/// 获取单调时钟时间（毫秒）
+///
+/// 第一次调用时取当时的墙上时间作为起点，之后按 `Instant` 前进，不受 NTP 步进或手动修改系统时间的影响
+/// (起点非零，0 仍可作为“未设置”的标记)。
+/// 连接/会话时间戳、LRU 和所有超时计算都使用它，`get_current_time` 只用于需要墙上时间的场合。
+/// 时间读数来自当前线程的时钟 (见 `crate::clock`)
+pub fn get_monotonic_time() -> u64 {
+    static EPOCH: std::sync::OnceLock<(Instant, u64)> = std::sync::OnceLock::new();
+    let (start, base) = EPOCH.get_or_init(|| (Instant::now(), get_current_time()));
+    base + crate::clock::now()
+        .saturating_duration_since(*start)
+        .as_millis() as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_anonymize_addr() {
+        let anon = |s: &str| anonymize_addr(s.parse().expect("addr")).to_string();
+        assert_eq!(anon("192.168.1.77:5000"), "192.168.1.0:5000");
+        assert_eq!(anon("[2001:db8:1:2:3:4:5:6]:443"), "[2001:db8:1::]:443");
+        assert_eq!(anon("[::ffff:10.1.2.3]:80"), "[::ffff:10.1.2.0]:80");
+    }
+
     #[test]
     fn test_log_level_ordering() {
         // 与 C++ 版本保持一致: NEVER=0, FATAL=1, ERROR=2, WARN=3, INFO=4, DEBUG=5, TRACE=6
@@ -238,6 +508,64 @@ mod tests {
         assert!(LogLevel::Warn < LogLevel::Info); // WARN(3) < INFO(4)
     }
 
+    #[test]
+    fn test_toggle_debug() {
+        let logger = Logger::new();
+        logger.set_level(LogLevel::Warn);
+        assert_eq!(logger.toggle_debug(), LogLevel::Debug);
+        assert_eq!(logger.toggle_debug(), LogLevel::Warn);
+
+        // 启动时即为 trace，切换后回到默认的 info
+        logger.set_level(LogLevel::Trace);
+        assert_eq!(logger.toggle_debug(), LogLevel::Warn);
+        let logger = Logger::new();
+        logger.set_level(LogLevel::Trace);
+        assert_eq!(logger.toggle_debug(), LogLevel::Info);
+    }
+
+    #[test]
+    fn test_log_error_policy_parse() {
+        assert_eq!("drop".parse(), Ok(LogErrorPolicy::Drop));
+        assert_eq!("STDERR".parse(), Ok(LogErrorPolicy::Stderr));
+        assert_eq!("exit".parse(), Ok(LogErrorPolicy::Exit));
+        assert!("abort".parse::<LogErrorPolicy>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_log_file_write_error_and_reopen() {
+        let path = std::env::temp_dir().join(format!("tpm-log-test-{}.log", std::process::id()));
+        let path_str = path.to_string_lossy().into_owned();
+        let logger = Logger::new();
+        logger.set_error_policy(LogErrorPolicy::Drop);
+
+        // /dev/full 写入总是返回 ENOSPC，模拟磁盘已满
+        {
+            let mut state = logger.log_file.lock().recover();
+            state.file = std::fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/full")
+                .ok();
+            state.path = Some(path_str.clone());
+        }
+        logger.write_to_file("lost");
+        assert_eq!(logger.write_error_count(), 1);
+
+        // 未到重试时间，继续计数
+        logger.write_to_file("lost");
+        assert_eq!(logger.write_error_count(), 2);
+
+        // 到达重试时间后重新打开
+        logger.log_file.lock().recover().retry_at = Some(Instant::now());
+        logger.write_to_file("hello");
+        assert_eq!(logger.write_error_count(), 2);
+
+        let content = std::fs::read_to_string(&path).expect("read log file");
+        let _ = std::fs::remove_file(&path);
+        assert!(content.contains("2 messages lost"));
+        assert!(content.ends_with("hello\n"));
+    }
+
     #[test]
     fn test_get_current_time() {
         let t1 = get_current_time();
@@ -245,4 +573,12 @@ mod tests {
         let t2 = get_current_time();
         assert!(t2 >= t1);
     }
+
+    #[test]
+    fn test_get_monotonic_time() {
+        let t1 = get_monotonic_time();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let t2 = get_monotonic_time();
+        assert!(t2 >= t1 + 5);
+    }
 }
@@ -54,7 +54,7 @@ where
         self.access_times.insert(key.clone(), access_time);
         self.time_list.push(key.clone());
         self.min_heap.push((access_time, key.clone()));
-        self.min_heap.sort_by(|a, b| a.0.cmp(&b.0));
+        self.min_heap.sort_by_key(|a| a.0);
     }
 
     /// 更新已有条目的访问时间
@@ -68,7 +68,7 @@ where
                     break;
                 }
             }
-            self.min_heap.sort_by(|a, b| a.0.cmp(&b.0));
+            self.min_heap.sort_by_key(|a| a.0);
             self.access_times.insert(key.clone(), new_time);
             true
         } else {
@@ -120,12 +120,12 @@ where
 
     /// 清理超时条目
     pub fn cleanup_timeout(&mut self, timeout: Duration) -> Vec<K> {
-        let now = crate::log::get_current_time();
+        let now = crate::log::get_monotonic_time();
         let timeout_ms = timeout.as_millis() as u64;
 
         let mut removed = Vec::new();
         self.min_heap.retain(|(time, key)| {
-            let is_timeout = now - *time > timeout_ms;
+            let is_timeout = now.saturating_sub(*time) > timeout_ms;
             if is_timeout {
                 self.values.remove(key);
                 self.access_times.remove(key);
@@ -181,7 +181,7 @@ mod tests {
         let mut lru: LruCollector<&str, &str> = LruCollector::new();
         lru.new_key("key1", "value1", 1000); // Old timestamp
         std::thread::sleep(Duration::from_millis(10));
-        lru.new_key("key2", "value2", crate::log::get_current_time());
+        lru.new_key("key2", "value2", crate::log::get_monotonic_time());
 
         let removed = lru.cleanup_timeout(Duration::from_millis(5));
         assert!(removed.contains(&"key1"));
@@ -2,32 +2,48 @@
 //!
 //! Rust 重写版本
 
-use tinyportmapper::{get_sock_error, info, log_bare, myexit};
+use tinyportmapper::{bench, info, log_bare, myexit, sandbox, systemd, warn, PortMapper};
 
-use mio::net::{TcpListener, UdpSocket};
 use std::env;
-#[cfg(unix)]
-use std::os::unix::io::FromRawFd;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tinyportmapper::config::{Config, FwdType, LISTEN_FD_BUF_SIZE, TIMER_INTERVAL_MS};
-use tinyportmapper::event::EventLoop;
-use tinyportmapper::fd_manager::FdManager;
-use tinyportmapper::log::LogLevel;
-use tinyportmapper::manager::{TcpConnectionManager, UdpSessionManager};
-use tinyportmapper::types::Address;
+use tinyportmapper::alg::UdpAlgMap;
+use tinyportmapper::backend::{resolve_weighted_remote, LbPolicy};
+use tinyportmapper::bench::BenchConfig;
+use tinyportmapper::chaos::Chaos;
+use tinyportmapper::config::{
+    AutoBan, CircuitBreaker, Config, FwdType, Linger, PollMode, PortRange, SocketMark,
+    TcpKeepalive, UdpKeepalive, UdpTimeoutMap, LISTEN_FD_BUF_SIZE, TIMER_INTERVAL_MS,
+};
+use tinyportmapper::echo::{EchoMode, EchoServer};
+use tinyportmapper::hook::DEFAULT_EXEC_RATE;
+use tinyportmapper::log::{LogErrorPolicy, LogLevel};
+use tinyportmapper::memory::parse_size;
+use tinyportmapper::multicast;
+use tinyportmapper::obfs::{Cipher, Psk};
+use tinyportmapper::punch::{PunchConfig, PunchPeer, PunchRole, RendezvousServer};
+use tinyportmapper::ratelimit::parse_rate;
+use tinyportmapper::sni::SniRoutes;
+use tinyportmapper::sniff::ExpectProtocol;
+use tinyportmapper::socks5::Socks5Upstream;
+use tinyportmapper::stats::format_bytes;
+use tinyportmapper::types::{Address, Nat64Prefix};
 
 use clap::Parser;
 
 /// Windows WSA 初始化
 #[cfg(windows)]
 fn init_ws() {
-    use std::os::windows::io::AsRawSocket;
-    use winapi::um::winsock2::{WSACleanup, WSAStartup, MAKEWORD, WSADATA};
+    use winapi::um::winsock2::{WSACleanup, WSAStartup, WSADATA};
+
+    // MAKEWORD(2, 2)
+    const WINSOCK_2_2: u16 = 0x0202;
 
     let mut wsa_data: WSADATA = unsafe { std::mem::zeroed() };
-    let w_version_requested = MAKEWORD(2, 2);
+    let w_version_requested = WINSOCK_2_2;
 
     let result = unsafe { WSAStartup(w_version_requested, &mut wsa_data) };
     if result != 0 {
@@ -36,7 +52,7 @@ fn init_ws() {
     }
 
     // 确认 WinSock DLL 支持 2.2
-    if wsa_data.wVersion.lo() != 2 || wsa_data.wVersion.hi() != 2 {
+    if wsa_data.wVersion != WINSOCK_2_2 {
         eprintln!("Could not find a usable version of Winsock.dll");
         unsafe {
             WSACleanup();
@@ -44,31 +60,8 @@ fn init_ws() {
         myexit(1);
     }
 
+    // socket 句柄不占用 C 运行库的文件描述符，无需像 C++ 版本那样调用 _setmaxstdio
     println!("The Winsock 2.2 dll was found okay");
-
-    // 设置最大文件描述符数量
-    let values = [
-        0,
-        100,
-        200,
-        300,
-        500,
-        800,
-        1000,
-        2000,
-        3000,
-        4000,
-        usize::MAX,
-    ];
-    let mut succ = 0;
-    for i in 1..values.len() {
-        if unsafe { libc::_setmaxstdio(values[i] as libc::c_int) } == -1 {
-            break;
-        } else {
-            succ = i;
-        }
-    }
-    println!(", _setmaxstdio() was set to {}", values[succ]);
 }
 
 #[cfg(not(windows))]
@@ -79,8 +72,9 @@ fn init_ws() {
 fn print_help() {
     use tinyportmapper::build::{BUILD_DATE, BUILD_TIME, GIT_VERSION};
     use tinyportmapper::config::{
-        DEFAULT_CONN_CLEAR_MIN, DEFAULT_CONN_CLEAR_RATIO, DEFAULT_MAX_CONNECTIONS,
-        DEFAULT_TCP_TIMEOUT_MS, DEFAULT_UDP_TIMEOUT_MS,
+        DEFAULT_CONN_CLEAR_MIN, DEFAULT_CONN_CLEAR_RATIO, DEFAULT_LISTEN_BACKLOG,
+        DEFAULT_MAX_CONNECTIONS, DEFAULT_STATS_INTERVAL_SECS, DEFAULT_TCP_TIMEOUT_MS,
+        DEFAULT_UDP_TIMEOUT_MS,
     };
 
     println!();
@@ -96,13 +90,28 @@ fn print_help() {
     println!(
         "    ./this_program  -l <listen_ip>:<listen_port> -r <remote_ip>:<remote_port>  [options]"
     );
+    println!("    ./this_program  --echo-server <ip>:<port>  [-t] [-u] [--sink]");
+    println!("    ./this_program  --bench <ip>:<port>  [-t] [-u] [--bench-* options]");
+    println!("    ./this_program  --rendezvous-server <ip>:<port>");
+    println!("    ./this_program  --punch <host>:<port> --punch-id <name>  -l <ip>:<port> | -r <ip>:<port>");
     println!();
     println!("main options:");
     println!("    -t                                    enable TCP forwarding/mapping");
     println!("    -u                                    enable UDP forwarding/mapping");
+    println!("    -r can be repeated or comma-separated, new connections/sessions are distributed per --lb-policy");
+    println!("    --dual-stack                          with -l 0.0.0.0 or [::], listen on separate IPv4 and IPv6 sockets");
+    println!("    --v6only                              IPv6 listen sockets accept only IPv6 clients (IPV6_V6ONLY=1)");
+    println!("    --no-v6only                           [::] also accepts IPv4 clients as ::ffff: mapped addresses (IPV6_V6ONLY=0), default: OS setting");
+    println!("    --inherit-stdin                       forward the connected socket on fd 0 (inetd) instead of listening, -l may be omitted");
+    println!("    -l <group>:<port>[@iface]             with a multicast group, join it (on iface) and forward received datagrams to -r (UDP only)");
+    println!("    --multicast-reply                     with a multicast -l, send remote responses to the group instead of the client");
+    println!("    --broadcast                           relay LAN broadcasts (listen on 0.0.0.0 with -e <iface>); -r may be a broadcast address, replies from any host go back to the client (IPv4, UDP)");
+    println!("    -l/-r also accept unix:<path> and vsock://<cid|any>:<port> to bridge them with TCP (TCP only)");
+    println!("    on Windows, -l/-r also accept npipe:\\\\.\\pipe\\<name> to bridge named pipes with TCP (TCP only)");
     println!();
     println!("other options:");
     println!("    --sock-buf            <number>        buf size for socket, >=10 and <=10240, unit: kbyte, default: 1024");
+    println!("    --sock-buf-autotune   <MB>            grow/shrink TCP socket buffers from --sock-buf by throughput and backlog, total growth capped at <MB>");
     println!(
         "    --log-level           <number>        0: never    1: fatal   2: error   3: warn "
     );
@@ -113,21 +122,47 @@ fn print_help() {
         "                                          or: fatal, error, warn, info, debug, trace"
     );
     println!("    --log-position                        enable file name, function name, line number in log");
+    println!("    --log-anonymize-ips                   truncate client addresses (IPv4 /24, IPv6 /48) in logs and connection records");
     println!("    --disable-color                       disable log color");
     println!("    --enable-color                        enable log color, log color is enabled by default on most platforms");
     println!("    --log-file            <path>          write log to file");
+    println!("    --log-on-error        <policy>        on log file write failure: drop, stderr (default), exit");
+    println!("                                          the log file is reopened every 10s after a failure");
     println!(
         "    -4                                    enable 4to6 translation mode (IPv4 to IPv6)"
     );
     println!(
         "    -6                                    enable 6to4 translation mode (IPv6 to IPv4)"
     );
-    println!("    -e <interface>                        bind to specified interface");
-    println!("    -d                                    enable UDP fragment forwarding");
+    println!("    --nat64-prefix        <prefix/len>    NAT64 prefix for -4/-6, e.g. 64:ff9b::/96 (RFC 6052 lengths; default ::ffff:0:0/96)");
+    println!(
+        "    -e <interface>                        bind to specified interface (Linux and macOS)"
+    );
+    println!("    --tos                  <value>        IP_TOS/IPV6_TCLASS on remote sockets, decimal or 0x hex, e.g. 0xb8 for DSCP EF");
+    println!("    --fwmark               <value>        SO_MARK on remote sockets for policy routing and tc, decimal or 0x hex (Linux only, needs CAP_NET_ADMIN)");
+    println!("    --mark-inbound                        also apply --tos/--fwmark to listen sockets (traffic sent to clients)");
+    println!("    --upstream             <url>          connect to remotes through a SOCKS5 proxy: socks5://host:port[:user:pass]");
+    println!("    --sni-routes           <path>         route TCP connections by TLS SNI, file lines: <host|*.domain> <remote>...");
+    println!("    --expect-protocol      <proto>        close TCP connections whose first bytes are not tls or http, default: none");
+    println!("    --socks5-listen        <addr>         run as a SOCKS5 server on addr instead of -l/-r: CONNECT to IP targets, UDP ASSOCIATE with -u");
+    println!("    --socks5-auth          <user:pass>    require username/password authentication from SOCKS5 clients");
+    println!("    --transparent                         transparent proxy: IP_TRANSPARENT listener, connect to remotes from the client IP (Linux only, needs CAP_NET_ADMIN)");
+    println!("    --bind-source          <ip>           bind outbound sockets to this local address before connecting; repeat for one IPv4 and one IPv6");
+    println!("    --source-ports         <start-end>    bind outbound sockets to a free source port in this range, e.g. 40000-50000");
+    println!("    -d                                    enable UDP fragment forwarding: datagrams that arrived fragmented may be fragmented again, others are sent with DF (Linux only)");
+    println!("    --mtu                  <number>       drop client datagrams whose IP packet exceeds this size unless they arrived fragmented (-d)");
+    println!("    --udp-preserve-tos-ttl                forward UDP datagrams with the TOS/ECN bits and TTL (minus one) they arrived with (Linux only)");
     println!(
         "    --max-connections      <number>       max connections, default: {}",
         DEFAULT_MAX_CONNECTIONS
     );
+    println!("    --max-memory           <size>         cap buffer and connection memory (e.g. 512M, 2G): refuse new connections, close the least recently active ones when over");
+    println!(
+        "    --backlog              <number>       TCP listen backlog (capped by net.core.somaxconn), default: {}",
+        DEFAULT_LISTEN_BACKLOG
+    );
+    println!("    --no-reuseport                        do not set SO_REUSEPORT on listen sockets, so no other process can share the port (Linux)");
+    println!("    --poll-mode            <edge|level>   edge: drain each ready socket; level: one recv per wakeup, re-armed while ready, default: edge");
     println!(
         "    --tcp-timeout          <number>       TCP connection timeout in seconds, default: {}",
         DEFAULT_TCP_TIMEOUT_MS / 1000
@@ -136,6 +171,26 @@ fn print_help() {
         "    --udp-timeout          <number>       UDP session timeout in seconds, default: {}",
         DEFAULT_UDP_TIMEOUT_MS / 1000
     );
+    println!("    --udp-timeout-map      <port=secs,..> UDP session timeout by destination port, e.g. 53=5,27015=300");
+    println!("    --udp-alg              <name[=port],..> application-layer gateway by remote port, follows replies from a new server port: tftp (default port 69)");
+    println!("    --idle-timeout-c2s     <number>       close a connection/session after this many seconds without client->remote data, default: 0 (disabled)");
+    println!("    --idle-timeout-s2c     <number>       close a connection/session after this many seconds without remote->client data, default: 0 (disabled)");
+    println!("    --tcp-nodelay          <true|false>   TCP_NODELAY on both sides of each connection, default: true");
+    println!(
+        "    --tcp-quickack                        enable TCP_QUICKACK on both sides (Linux only)"
+    );
+    println!("    --congestion           <algo>         TCP congestion control on both sides, e.g. bbr, cubic (Linux only)");
+    println!("    --tcp-keepalive        <idle,intvl,cnt> enable TCP keepalive on both sides, e.g. 60,10,6 (seconds, seconds, probes)");
+    println!("    --udp-keepalive        <secs[,hex]>   send a keepalive datagram (default empty) to the remote of sessions idle this long on the client side, e.g. 25");
+    println!("    --udp-recverr                         close a UDP session as soon as the remote answers with an ICMP error such as port unreachable (Linux only)");
+    println!("    --tcp-user-timeout     <ms>           drop a connection when sent data stays unacknowledged this long, on both sides (Linux only)");
+    println!("    --zerocopy             <bytes>        send TCP data with MSG_ZEROCOPY when at least this many bytes go out at once, e.g. 32768 (Linux 4.14+)");
+    println!("    --sockmap                             relay established TCP connections in the kernel with eBPF sockmap (Linux 5.7+, needs CAP_BPF and CAP_NET_ADMIN)");
+    println!("    --linger               <secs|off>     SO_LINGER on both sides; 0 closes connections with RST instead of FIN");
+    println!("    --abort-on-timeout                    close TCP connections reaped by idle timeouts with RST instead of FIN");
+    println!("    --connect-retries      <number>       retry a refused or timed out remote connect this many times with exponential backoff, default: 0");
+    println!("    --circuit-breaker      <fails,secs>   after this many consecutive connect failures, close new connections to that remote for secs, e.g. 5,30");
+    println!("    --auto-ban             <n,secs,ban>   ban a client IP for ban seconds after n rejected connections within secs, e.g. 5,60,600");
     println!(
         "    --conn-clear-ratio     <number>       connection clear ratio, default: {}",
         DEFAULT_CONN_CLEAR_RATIO
@@ -145,11 +200,94 @@ fn print_help() {
         DEFAULT_CONN_CLEAR_MIN
     );
     println!("    --disable-conn-clear                   disable automatic connection clearing");
-    println!("    --run-test                            run unit tests");
+    println!("    --rate-limit           <rate>         global bandwidth limit in bytes/s, K/M/G suffix allowed, e.g. 10M");
+    println!("    --rate-limit-per-conn  <rate>         per connection/session bandwidth limit in bytes/s, e.g. 512K");
+    println!("    --chaos                <spec>         emulate a bad network for testing, e.g. delay=50ms,jitter=10ms,loss=1%");
+    println!("                                          delay/jitter apply to each direction, loss drops UDP datagrams only");
+    println!("    --mirror               <ip:port>      copy client-to-remote traffic to this address (TCP as a separate stream, UDP as copies)");
+    println!("    --encrypt-out          <psk>          encrypt TCP streams to the remote, which must be a relay running --decrypt-in with the same key");
+    println!("    --decrypt-in           <psk>          decrypt TCP streams from clients, which must be a relay running --encrypt-out with the same key");
+    println!("    --cipher               <name>         cipher for --encrypt-out/--decrypt-in: xor (obfuscation only, default) or chacha20-poly1305 (aead feature)");
+    println!("    --compress-out                        LZ4-compress TCP streams to the remote, which must be a relay running --decompress-in (lz4 feature)");
+    println!("    --decompress-in                       decompress TCP streams from --compress-out relays and compress their replies; plain clients pass through (lz4 feature)");
+    println!("    --ftp-alg                             rewrite PORT/EPRT and PASV/EPSV on forwarded FTP control connections and open temporary data-channel listeners");
+    println!("    --tenant               <name>         tenant name used to label stats output");
+    println!("    --tenant-max-connections <number>     max TCP connections plus UDP sessions across all mappings of the tenant in this process");
+    println!("    --tenant-rate-limit    <rate>         bandwidth shared by all mappings of the tenant in this process, e.g. 10M");
+    println!("    --tenant-allow         <cidr,...>     only accept clients from these networks, e.g. 10.0.0.0/8,2001:db8::/32");
+    println!("    --tenant-deny          <cidr,...>     reject clients from these networks, checked before --tenant-allow");
+    println!("    --drain-timeout        <number>       on stop, stop accepting and wait up to this many seconds for connections to close, default: 0");
+    println!("    --lb-policy            <policy>       how new connections pick a remote: round-robin (default), weighted, least-conn");
+    println!("                                          weighted takes weights from -r <ip>:<port>@<weight>");
+    println!("    --udp-sticky                          send all datagrams from the same client address to the same remote");
+    println!("    --udp-quic                            track QUIC connection IDs so clients keep their session after an address change");
+    println!("    --health-check-interval <number>      probe each remote every this many seconds and skip unhealthy ones, default: 0 (disabled)");
+    println!("    --upnp                                ask the router (PCP, NAT-PMP or UPnP IGD) to map the same external port to the listen port and renew it periodically");
+    println!("    --stun                 <host[:port]>  discover the public address of the UDP listener through this STUN server (default port 3478)");
+    println!("    --webhook              <url>          POST connect/close/reject/backend-down events as JSON to this http:// URL from a background thread");
+    println!("    --on-connect-exec      <cmd>          run cmd with sh -c when a TCP connection is established or a UDP session is created, TPM_* env vars describe it");
+    println!("    --on-close-exec        <cmd>          run cmd with sh -c when a connection/session closes, adds TPM_REASON, TPM_BYTES_UP/DOWN, TPM_DURATION_MS");
+    println!(
+        "    --exec-rate            <number>       run at most this many --on-*-exec commands per second, default: {}",
+        DEFAULT_EXEC_RATE
+    );
+    println!("    --geoip-db             <path>         MaxMind GeoLite2 Country/City database, tag new connections with the client country (geoip feature)");
+    println!("    --geoip-asn-db         <path>         MaxMind GeoLite2 ASN database, also tag new connections with the client ASN");
+    println!("    --geo-allow            <CC,...>       only accept clients from these countries, ZZ matches addresses not in the database");
+    println!("    --geo-deny             <CC,...>       reject clients from these countries, checked before --geo-allow");
+    println!(
+        "    --stats-interval       <number>       print traffic stats every this many seconds, 0 to disable, default: {}",
+        DEFAULT_STATS_INTERVAL_SECS
+    );
+    println!("    --stats-file           <path>         load cumulative stats from this file on start and save them on exit");
+    println!("    --reset-stats                         start with zeroed cumulative stats instead of loading --stats-file");
+    println!("    --upgrade              <path>         take over listening sockets from the instance running on this control socket, then wait for the next upgrade on it");
+    println!("    --top                                 show a live table of connections and rates instead of console logs (logs still go to --log-file)");
+    println!("    --echo-server          <ip>:<port>    run a TCP/UDP echo server for testing instead of forwarding, -t/-u limit it to one protocol");
+    println!("    --sink                                with --echo-server, discard received data instead of echoing it");
+    println!("    --sandbox                             restrict syscalls with a seccomp filter after startup (Linux only)");
+    println!("    --bench                <ip>:<port>    benchmark an echo target (e.g. --echo-server behind a mapping) and report throughput and latency, -t/-u limit it to one protocol");
+    println!("    --bench-streams        <number>       concurrent TCP request/response streams, default: 1");
+    println!("    --bench-rate           <number>       UDP packets per second, default: 1000");
+    println!("    --bench-size           <bytes>        bytes per TCP request and per UDP packet, default: 1024");
+    println!("    --bench-duration       <number>       seconds to run, default: 10");
+    println!("    --rendezvous-server    <ip>:<port>    run a UDP rendezvous server that tells two --punch peers with the same id each other's public endpoint");
+    println!("    --punch                <host>:<port>  punch a direct UDP path to the peer registered with the same --punch-id on this rendezvous server,");
+    println!("                                          then forward UDP clients of -l to the peer, or channels from the peer to -r");
+    println!("    --punch-id             <name>         name shared by the two --punch peers");
+    println!("    --run-test                            run unit tests and a loopback TCP/UDP forwarding self-test");
+    println!("    --version [--json]                    print version, build info and runtime capabilities (JSON)");
     println!("    -h,--help                             print this help message");
     println!();
 }
 
+/// 生成 `--version --json` 输出 (版本、构建信息和运行时能力)
+fn version_json() -> String {
+    use tinyportmapper::build::{BUILD_DATE, BUILD_TARGET, BUILD_TIME, GIT_VERSION, STATIC_LINK};
+    format!(
+        "{{\"name\":\"tinyPortMapper\",\"version\":\"{}\",\"git_version\":\"{}\",\"build_date\":\"{} {}\",\"target\":\"{}\",\"static\":{},\"capabilities\":{}}}",
+        env!("CARGO_PKG_VERSION"),
+        GIT_VERSION,
+        BUILD_DATE,
+        BUILD_TIME,
+        BUILD_TARGET,
+        STATIC_LINK,
+        tinyportmapper::capabilities::Capabilities::global().to_json()
+    )
+}
+
+/// 检查 stdout 是否为终端
+#[cfg(feature = "color")]
+fn stdout_is_tty() -> bool {
+    atty::is(atty::Stream::Stdout)
+}
+
+/// 检查 stdout 是否为终端 (精简构建不输出颜色，无需检测)
+#[cfg(not(feature = "color"))]
+fn stdout_is_tty() -> bool {
+    false
+}
+
 /// 解析日志级别，支持数字 (0-6) 或字符串
 fn parse_log_level(s: &str) -> Result<LogLevel, String> {
     // 先尝试解析为数字
@@ -185,62 +323,67 @@ fn validate_buffer_size(s: &str) -> Result<usize, String> {
     Ok(value)
 }
 
-/// 设置 socket 绑定到指定网络接口 (SO_BINDTODEVICE)
-#[cfg(target_os = "linux")]
-fn set_bind_to_device(fd: libc::c_int, interface: &str) -> Result<(), String> {
-    let ifreq = {
-        let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
-        let interface_bytes = interface.as_bytes();
-        let ifr_name_len = std::mem::size_of::<libc::c_char>() * libc::IFNAMSIZ;
-        let len = std::cmp::min(interface_bytes.len(), ifr_name_len - 1);
-        unsafe {
-            // ifreq.ifr_name 是 *mut i8，需要正确转换
-            let dest_ptr = ifreq.ifr_name.as_mut_ptr() as *mut libc::c_char;
-            std::ptr::copy_nonoverlapping(
-                interface_bytes.as_ptr() as *const libc::c_char,
-                dest_ptr,
-                len,
-            );
-        }
-        ifreq
-    };
+/// 解析十进制或 0x 开头的十六进制整数
+fn parse_int_auto(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
 
-    let ret = unsafe {
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_BINDTODEVICE,
-            &ifreq as *const _ as *const libc::c_void,
-            std::mem::size_of::<libc::ifreq>() as libc::socklen_t,
-        )
-    };
+/// 解析 TOS 字节 (0-255)
+fn parse_tos(s: &str) -> Result<u8, String> {
+    parse_int_auto(s)
+        .and_then(|v| u8::try_from(v).ok())
+        .ok_or_else(|| format!("invalid tos '{}', must be 0-255 or 0x00-0xff", s))
+}
 
-    if ret < 0 {
-        Err(format!(
-            "failed to bind to interface {}: {}",
-            interface,
-            get_sock_error()
-        ))
-    } else {
-        Ok(())
-    }
+/// 解析 SO_MARK
+fn parse_fwmark(s: &str) -> Result<u32, String> {
+    parse_int_auto(s).ok_or_else(|| format!("invalid fwmark '{}', must be a 32-bit number", s))
 }
 
-/// 设置 socket 绑定到指定网络接口 (非 Linux 平台)
-#[cfg(not(target_os = "linux"))]
-fn set_bind_to_device(_fd: libc::c_int, _interface: &str) -> Result<(), String> {
-    Err("SO_BINDTODEVICE is not supported on this platform".to_string())
+fn parse_tenant(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.len() <= 64
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(format!(
+            "invalid tenant name '{}', must be 1-64 characters of [A-Za-z0-9._-]",
+            s
+        ));
+    }
+    Ok(s.to_string())
 }
 
 #[derive(Parser, Debug)]
 #[command(name = "tinyportmapper")]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
+    #[arg(short, long, default_value = "")]
     listen: String,
 
-    #[arg(short, long)]
-    remote: String,
+    #[arg(long)]
+    dual_stack: bool,
+
+    #[arg(long, conflicts_with = "no_v6only")]
+    v6only: bool,
+
+    #[arg(long, conflicts_with = "dual_stack")]
+    no_v6only: bool,
+
+    #[arg(long)]
+    inherit_stdin: bool,
+
+    #[arg(long)]
+    multicast_reply: bool,
+
+    #[arg(long)]
+    broadcast: bool,
+
+    #[arg(short, long, value_delimiter = ',')]
+    remote: Vec<String>,
 
     #[arg(short)]
     tcp: bool,
@@ -251,6 +394,9 @@ struct Args {
     #[arg(long = "sock-buf", default_value = "1024", value_parser = validate_buffer_size, alias = "buffer")]
     buffer: usize,
 
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    sock_buf_autotune: Option<u64>,
+
     #[arg(long, default_value = "info", value_parser = parse_log_level)]
     log_level: LogLevel,
 
@@ -264,30 +410,144 @@ struct Args {
     #[arg(long)]
     log_position: bool,
 
+    #[arg(long)]
+    log_anonymize_ips: bool,
+
     #[arg(long)]
     log_file: Option<String>,
 
+    #[arg(long = "log-on-error", default_value = "stderr")]
+    log_on_error: LogErrorPolicy,
+
     #[arg(short = '4')]
     mode_4to6: bool,
 
     #[arg(short = '6')]
     mode_6to4: bool,
 
+    #[arg(long)]
+    nat64_prefix: Option<Nat64Prefix>,
+
     #[arg(short = 'e')]
     bind_interface: Option<String>,
 
+    #[arg(long, value_parser = parse_tos)]
+    tos: Option<u8>,
+
+    #[arg(long, value_parser = parse_fwmark)]
+    fwmark: Option<u32>,
+
+    #[arg(long)]
+    mark_inbound: bool,
+
+    #[arg(long)]
+    transparent: bool,
+
+    #[arg(long, conflicts_with = "transparent")]
+    bind_source: Vec<IpAddr>,
+
+    #[arg(long, conflicts_with = "transparent")]
+    source_ports: Option<PortRange>,
+
+    #[arg(long)]
+    upstream: Option<Socks5Upstream>,
+
+    #[arg(long)]
+    sni_routes: Option<String>,
+
+    #[arg(long, default_value = "none")]
+    expect_protocol: ExpectProtocol,
+
+    #[arg(long, conflicts_with_all = ["listen", "remote", "inherit_stdin"])]
+    socks5_listen: Option<String>,
+
+    #[arg(long, requires = "socks5_listen", value_parser = tinyportmapper::socks5::parse_auth)]
+    socks5_auth: Option<(String, String)>,
+
     #[arg(short = 'd')]
     udp_fragment: bool,
 
+    #[arg(long)]
+    mtu: Option<usize>,
+
+    #[arg(long)]
+    udp_preserve_tos_ttl: bool,
+
     #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_MAX_CONNECTIONS)]
     max_connections: usize,
 
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_LISTEN_BACKLOG, value_parser = clap::value_parser!(u32).range(1..))]
+    backlog: u32,
+
+    #[arg(long)]
+    no_reuseport: bool,
+
+    #[arg(long, default_value = "edge")]
+    poll_mode: PollMode,
+
     #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_TCP_TIMEOUT_MS / 1000)]
     tcp_timeout: u64,
 
     #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_UDP_TIMEOUT_MS / 1000)]
     udp_timeout: u64,
 
+    #[arg(long)]
+    udp_timeout_map: Option<UdpTimeoutMap>,
+
+    #[arg(long)]
+    udp_alg: Option<UdpAlgMap>,
+
+    #[arg(long)]
+    idle_timeout_c2s: Option<u64>,
+
+    #[arg(long)]
+    idle_timeout_s2c: Option<u64>,
+
+    #[arg(long)]
+    tcp_keepalive: Option<TcpKeepalive>,
+
+    #[arg(long)]
+    udp_keepalive: Option<UdpKeepalive>,
+
+    #[arg(long)]
+    udp_recverr: bool,
+
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    tcp_nodelay: bool,
+
+    #[arg(long)]
+    tcp_quickack: bool,
+
+    #[arg(long)]
+    congestion: Option<String>,
+
+    #[arg(long)]
+    tcp_user_timeout: Option<u64>,
+
+    #[arg(long)]
+    zerocopy: Option<usize>,
+
+    #[arg(long)]
+    sockmap: bool,
+
+    #[arg(long)]
+    linger: Option<Linger>,
+
+    #[arg(long)]
+    abort_on_timeout: bool,
+
+    #[arg(long, default_value_t = 0)]
+    connect_retries: u32,
+
+    #[arg(long)]
+    circuit_breaker: Option<CircuitBreaker>,
+
+    #[arg(long)]
+    auto_ban: Option<AutoBan>,
+
     #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_CONN_CLEAR_RATIO)]
     conn_clear_ratio: u32,
 
@@ -296,6 +556,162 @@ struct Args {
 
     #[arg(long)]
     disable_conn_clear: bool,
+
+    #[arg(long, value_parser = parse_rate)]
+    rate_limit: Option<u64>,
+
+    #[arg(long, value_parser = parse_rate)]
+    rate_limit_per_conn: Option<u64>,
+
+    #[arg(long, value_parser = Chaos::from_str)]
+    chaos: Option<Chaos>,
+
+    #[arg(long, value_parser = Address::from_str)]
+    mirror: Option<Address>,
+
+    #[arg(long)]
+    encrypt_out: Option<String>,
+
+    #[arg(long)]
+    decrypt_in: Option<String>,
+
+    #[arg(long, default_value = "xor", value_parser = Cipher::from_str)]
+    cipher: Cipher,
+
+    #[arg(long)]
+    compress_out: bool,
+
+    #[arg(long)]
+    decompress_in: bool,
+
+    #[arg(long)]
+    ftp_alg: bool,
+
+    #[arg(long, value_parser = parse_tenant)]
+    tenant: Option<String>,
+
+    #[arg(long, requires = "tenant")]
+    tenant_max_connections: Option<usize>,
+
+    #[arg(long, value_parser = parse_rate, requires = "tenant")]
+    tenant_rate_limit: Option<u64>,
+
+    #[arg(long, value_delimiter = ',', requires = "tenant")]
+    tenant_allow: Vec<String>,
+
+    #[arg(long, value_delimiter = ',', requires = "tenant")]
+    tenant_deny: Vec<String>,
+
+    #[arg(long, default_value = "0")]
+    drain_timeout: u64,
+
+    #[arg(long, default_value = "0")]
+    health_check_interval: u64,
+
+    #[arg(long)]
+    upnp: bool,
+
+    #[arg(long)]
+    stun: Option<String>,
+
+    #[arg(long)]
+    webhook: Option<String>,
+
+    #[arg(long, conflicts_with = "sandbox")]
+    on_connect_exec: Option<String>,
+
+    #[arg(long, conflicts_with = "sandbox")]
+    on_close_exec: Option<String>,
+
+    #[arg(long, default_value_t = DEFAULT_EXEC_RATE, value_parser = clap::value_parser!(u32).range(1..))]
+    exec_rate: u32,
+
+    #[arg(long)]
+    geoip_db: Option<String>,
+
+    #[arg(long)]
+    geoip_asn_db: Option<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    geo_allow: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    geo_deny: Vec<String>,
+
+    #[arg(long, default_value = "round-robin")]
+    lb_policy: LbPolicy,
+
+    #[arg(long)]
+    udp_sticky: bool,
+
+    #[arg(long)]
+    udp_quic: bool,
+
+    #[arg(long, default_value_t = tinyportmapper::config::DEFAULT_STATS_INTERVAL_SECS)]
+    stats_interval: u64,
+
+    #[arg(long)]
+    stats_file: Option<String>,
+
+    #[arg(long)]
+    reset_stats: bool,
+
+    #[arg(long)]
+    upgrade: Option<String>,
+
+    #[arg(long)]
+    top: bool,
+
+    #[arg(long)]
+    sandbox: bool,
+
+    #[arg(long)]
+    echo_server: Option<String>,
+
+    #[arg(long, requires = "echo_server")]
+    sink: bool,
+
+    #[arg(long, conflicts_with = "echo_server")]
+    bench: Option<String>,
+
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), requires = "bench")]
+    bench_streams: u64,
+
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..), requires = "bench")]
+    bench_rate: u64,
+
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..=65507), requires = "bench")]
+    bench_size: u64,
+
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..), requires = "bench")]
+    bench_duration: u64,
+
+    #[arg(long, conflicts_with_all = ["echo_server", "bench"])]
+    rendezvous_server: Option<String>,
+
+    #[arg(long, requires = "punch_id", conflicts_with_all = ["echo_server", "bench", "rendezvous_server"])]
+    punch: Option<String>,
+
+    #[arg(long, requires = "punch")]
+    punch_id: Option<String>,
+}
+
+/// 把指向 socket 的 stdout/stderr 重定向到 /dev/null，日志改用 --log-file
+#[cfg(unix)]
+fn detach_stdio() {
+    use std::os::fd::AsRawFd;
+    let null = match std::fs::OpenOptions::new().write(true).open("/dev/null") {
+        Ok(null) => null,
+        Err(_) => return,
+    };
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } == 0
+            && stat.st_mode & libc::S_IFMT == libc::S_IFSOCK
+        {
+            unsafe { libc::dup2(null.as_raw_fd(), fd) };
+        }
+    }
 }
 
 fn main() {
@@ -315,6 +731,10 @@ fn main() {
     // 检查 --version 和 --help 参数（C++ 风格的早期检查）
     for arg in &raw_args {
         if arg == "--version" {
+            if raw_args.iter().any(|a| a == "--json") {
+                println!("{}", version_json());
+                myexit(0);
+            }
             println!("tinyPortMapper");
             println!(
                 "git version: {}    build date: {} {}",
@@ -322,6 +742,15 @@ fn main() {
                 tinyportmapper::build::BUILD_DATE,
                 tinyportmapper::build::BUILD_TIME
             );
+            println!(
+                "build target: {}{}",
+                tinyportmapper::build::BUILD_TARGET,
+                if tinyportmapper::build::STATIC_LINK {
+                    " (static)"
+                } else {
+                    ""
+                }
+            );
             println!("repository: https://github.com/x1t/tinyPortMapper-rust");
             myexit(0);
         }
@@ -332,6 +761,14 @@ fn main() {
         // 处理单元测试请求（与 C++ 版本 unit_test() 对应）- 提前检查
         if arg == "--run-test" {
             tinyportmapper::unit_test();
+            tinyportmapper::log::Logger::global().set_level(LogLevel::Warn);
+            match tinyportmapper::selftest::run() {
+                Ok(()) => println!("loopback forwarding: ok"),
+                Err(e) => {
+                    println!("loopback forwarding: FAILED: {}", e);
+                    myexit(1);
+                }
+            }
             myexit(0);
         }
     }
@@ -339,6 +776,12 @@ fn main() {
     // 解析命令行参数
     let args = Args::parse();
 
+    // inetd 把客户端连接同时作为 stdin/stdout/stderr 传入，输出必须离开 socket
+    #[cfg(unix)]
+    if args.inherit_stdin {
+        detach_stdio();
+    }
+
     // 与 C++ 版本保持一致的参数处理逻辑：
     // 先遍历所有参数，检查 --enable-color 和 --disable-color
     // 后出现的参数覆盖先出现的
@@ -366,15 +809,26 @@ fn main() {
         false
     } else {
         // 默认：终端支持颜色时启用
-        atty::is(atty::Stream::Stdout)
+        stdout_is_tty()
     };
     logger.set_color(enable_color);
     logger.set_position(args.log_position);
+    logger.set_anonymize_ips(args.log_anonymize_ips);
+    // --top 时终端用于显示连接表，日志只写入日志文件
+    logger.set_console(!args.top);
 
     // 打开日志文件
+    logger.set_error_policy(args.log_on_error);
     if let Some(ref log_file) = args.log_file {
         if let Err(e) = logger.open_log_file(log_file) {
-            eprintln!("Warning: failed to open log file '{}': {}", log_file, e);
+            if args.log_on_error == LogErrorPolicy::Exit {
+                eprintln!("Error: failed to open log file '{}': {}", log_file, e);
+                myexit(1);
+            }
+            eprintln!(
+                "Warning: failed to open log file '{}': {}, will retry",
+                log_file, e
+            );
         } else {
             info!("Log file opened: {}", log_file);
         }
@@ -394,13 +848,28 @@ fn main() {
     println!("==============================");
     println!();
 
-    if args.listen.is_empty() || args.remote.is_empty() {
+    if let Some(ref addr) = args.echo_server {
+        run_echo_server(addr, &args);
+    }
+    if let Some(ref addr) = args.bench {
+        run_bench(addr, &args);
+    }
+    if let Some(ref addr) = args.rendezvous_server {
+        run_rendezvous_server(addr);
+    }
+    if let Some(ref server) = args.punch {
+        run_punch(server, &args);
+    }
+
+    // SOCKS5 服务端的目标由客户端指定，总是转发 TCP，-u 启用 UDP ASSOCIATE
+    let socks5 = args.socks5_listen.is_some();
+    if !socks5 && ((args.listen.is_empty() && !args.inherit_stdin) || args.remote.is_empty()) {
         eprintln!("Error: -l (listen) and -r (remote) are required");
         print_help();
         myexit(1);
     }
 
-    if !args.tcp && !args.udp {
+    if !socks5 && !args.tcp && !args.udp {
         eprintln!("Error: must specify -t (TCP) or -u (UDP) or both");
         print_help();
         myexit(1);
@@ -412,42 +881,302 @@ fn main() {
     log_bare!("{}", args_vec.join(" "));
     log_bare!("\n");
 
-    let listen_addr: Address = match Address::from_str(&args.listen) {
+    let listen = args.socks5_listen.as_deref().unwrap_or(&args.listen);
+    let (listen, multicast_interface) = multicast::split_interface(listen);
+    let listen_addr: Address = match Address::from_str(listen) {
+        Err(_) if listen.is_empty() => Address::from_ipv4(Ipv4Addr::UNSPECIFIED, 0),
         Ok(addr) => addr,
         Err(e) => {
-            eprintln!("Error: invalid listen address '{}': {}", args.listen, e);
+            eprintln!("Error: invalid listen address '{}': {}", listen, e);
             myexit(1);
         }
     };
 
-    let remote_addr: Address = match Address::from_str(&args.remote) {
-        Ok(addr) => addr,
-        Err(e) => {
-            eprintln!("Error: invalid remote address '{}': {}", args.remote, e);
-            myexit(1);
+    let mut remote_addrs = Vec::with_capacity(args.remote.len());
+    let mut remote_weights = Vec::with_capacity(args.remote.len());
+    let mut remote_fallbacks = Vec::with_capacity(args.remote.len());
+    for remote in &args.remote {
+        match resolve_weighted_remote(remote) {
+            Ok((addr, fallback, weight)) => {
+                remote_addrs.push(addr);
+                remote_fallbacks.push(fallback);
+                remote_weights.push(weight);
+            }
+            Err(e) => {
+                eprintln!("Error: invalid remote address '{}': {}", remote, e);
+                myexit(1);
+            }
         }
+    }
+
+    let sni_routes = args
+        .sni_routes
+        .as_ref()
+        .map(|path| match SniRoutes::load(Path::new(path)) {
+            Ok(routes) => routes,
+            Err(e) => {
+                eprintln!("Error: failed to load SNI routes '{}': {}", path, e);
+                myexit(1);
+            }
+        });
+
+    let psk = |option: &str, secret: &Option<String>| {
+        secret
+            .as_deref()
+            .map(|secret| match Psk::new(args.cipher, secret) {
+                Ok(psk) => psk,
+                Err(e) => {
+                    eprintln!("Error: invalid --{}: {}", option, e);
+                    myexit(1);
+                }
+            })
     };
+    let decrypt_in = psk("decrypt-in", &args.decrypt_in);
+    let encrypt_out = psk("encrypt-out", &args.encrypt_out);
 
     info!("Starting tinyPortMapper...");
-    info!("Listen: {}", listen_addr);
-    info!("Remote: {}", remote_addr);
-    info!("TCP: {}, UDP: {}", args.tcp, args.udp);
+    if args.inherit_stdin {
+        info!("Listen: inherited connection on stdin");
+    } else if socks5 {
+        info!("SOCKS5 server: {}", listen_addr);
+        if args.socks5_auth.is_some() {
+            info!("SOCKS5 auth: username/password");
+        }
+    } else {
+        info!("Listen: {}", listen_addr);
+    }
+    if let Some(interface) = multicast_interface {
+        info!("Multicast interface: {}", interface);
+    }
+    if args.multicast_reply {
+        info!("Multicast: remote responses are sent to the group");
+    }
+    if args.broadcast {
+        info!("Broadcast relay: SO_BROADCAST, unconnected session sockets");
+    }
+    if args.v6only {
+        info!("IPv6 listen sockets: IPv6 clients only (IPV6_V6ONLY)");
+    } else if args.no_v6only {
+        info!("IPv6 listen sockets: also accepting IPv4-mapped clients");
+    }
+    for (remote_addr, weight) in remote_addrs.iter().zip(&remote_weights) {
+        if args.lb_policy == LbPolicy::Weighted {
+            info!("Remote: {} (weight {})", remote_addr, weight);
+        } else {
+            info!("Remote: {}", remote_addr);
+        }
+    }
+    for (remote_addr, fallback) in remote_addrs.iter().zip(&remote_fallbacks) {
+        if let Some(fallback) = fallback {
+            info!("Happy Eyeballs: {} falls back to {}", remote_addr, fallback);
+        }
+    }
+    if remote_addrs.len() > 1 {
+        info!("LB policy: {}", args.lb_policy);
+    }
+    info!("TCP: {}, UDP: {}", args.tcp || socks5, args.udp);
     info!("Buffer: {} KB", args.buffer);
+    if let Some(budget) = args.sock_buf_autotune {
+        info!("Socket buffer autotune: up to {} MB extra", budget);
+    }
     info!("Max connections: {}", args.max_connections);
+    if let Some(bytes) = args.max_memory {
+        info!("Max memory: {} MB", bytes / (1024 * 1024));
+    }
+    info!("Listen backlog: {}", args.backlog);
+    if args.no_reuseport {
+        info!("SO_REUSEPORT: disabled");
+    }
+    if args.poll_mode == PollMode::Level {
+        info!("Poll mode: {}", args.poll_mode);
+    }
     info!(
         "TCP timeout: {}s, UDP timeout: {}s",
         args.tcp_timeout, args.udp_timeout
     );
-
-    // Determine address family for socket creation
-    let addr_family = match listen_addr.get_type() {
-        4 => libc::AF_INET,
-        6 => libc::AF_INET6,
-        _ => {
-            eprintln!("Error: unsupported address type");
-            myexit(1);
+    if let Some(ref map) = args.udp_timeout_map {
+        info!("UDP timeout by destination port: {}", map);
+    }
+    if let Some(ref algs) = args.udp_alg {
+        info!("UDP application-layer gateway: {}", algs);
+    }
+    if args.idle_timeout_c2s.is_some() || args.idle_timeout_s2c.is_some() {
+        info!(
+            "Idle timeout: client->remote {}s, remote->client {}s",
+            args.idle_timeout_c2s.unwrap_or(0),
+            args.idle_timeout_s2c.unwrap_or(0)
+        );
+    }
+    if let Some(ref congestion) = args.congestion {
+        info!("TCP congestion control: {}", congestion);
+    }
+    if let Some(keepalive) = args.tcp_keepalive {
+        info!(
+            "TCP keepalive: idle {}s, interval {}s, count {}",
+            keepalive.idle.as_secs(),
+            keepalive.interval.as_secs(),
+            keepalive.count
+        );
+    }
+    if let Some(ref keepalive) = args.udp_keepalive {
+        info!(
+            "UDP keepalive: every {}s, {} bytes",
+            keepalive.interval.as_secs(),
+            keepalive.payload.len()
+        );
+    }
+    if let Some(mtu) = args.mtu {
+        info!("UDP MTU: {}", mtu);
+    }
+    if args.udp_preserve_tos_ttl {
+        info!("UDP datagrams keep their TOS/ECN and TTL");
+    }
+    if args.udp_recverr {
+        info!("UDP sessions close on ICMP errors (IP_RECVERR)");
+    }
+    if let Some(timeout) = args.tcp_user_timeout {
+        info!("TCP user timeout: {}ms", timeout);
+    }
+    if let Some(threshold) = args.zerocopy {
+        info!("TCP zerocopy: sends of {} bytes or more", threshold);
+    }
+    if args.sockmap {
+        info!("TCP sockmap: established connections are relayed in the kernel");
+    }
+    if let Some(linger) = args.linger {
+        info!("TCP linger: {}", linger);
+    }
+    if args.connect_retries > 0 {
+        info!("Connect retries: {}", args.connect_retries);
+    }
+    if let Some(breaker) = args.circuit_breaker {
+        info!(
+            "Circuit breaker: open after {} connect failures for {}s",
+            breaker.threshold,
+            breaker.cooldown.as_secs()
+        );
+    }
+    if let Some(auto_ban) = args.auto_ban {
+        info!(
+            "Auto ban: {} rejects within {}s bans a client for {}s",
+            auto_ban.threshold,
+            auto_ban.window.as_secs(),
+            auto_ban.duration.as_secs()
+        );
+    }
+    if let Some(tos) = args.tos {
+        info!("TOS: {:#04x} (DSCP {})", tos, tos >> 2);
+    }
+    if let Some(fwmark) = args.fwmark {
+        info!("Fwmark: {:#x}", fwmark);
+    }
+    if args.mark_inbound && (args.tos.is_some() || args.fwmark.is_some()) {
+        info!("Marking listen sockets as well");
+    }
+    if let Some(rate) = args.rate_limit {
+        info!("Rate limit: {}/s", format_bytes(rate));
+    }
+    if let Some(rate) = args.rate_limit_per_conn {
+        info!("Rate limit per connection: {}/s", format_bytes(rate));
+    }
+    if let Some(chaos) = args.chaos {
+        warn!("Chaos: {} (testing only)", chaos);
+    }
+    if let Some(ref mirror) = args.mirror {
+        info!("Mirror: {}", mirror);
+    }
+    if decrypt_in.is_some() {
+        info!("Decrypt incoming TCP streams: {}", args.cipher);
+    }
+    if encrypt_out.is_some() {
+        info!("Encrypt outgoing TCP streams: {}", args.cipher);
+    }
+    if args.decompress_in {
+        info!("Decompress incoming TCP streams: lz4");
+    }
+    if args.compress_out {
+        info!("Compress outgoing TCP streams: lz4");
+    }
+    if args.ftp_alg {
+        info!("FTP application-layer gateway: enabled");
+    }
+    if args.upnp {
+        info!("Router port mapping: PCP/NAT-PMP/UPnP");
+    }
+    if let Some(ref server) = args.stun {
+        info!("STUN server: {}", server);
+    }
+    if let Some(ref url) = args.webhook {
+        info!("Webhook: {}", url);
+    }
+    if let Some(ref command) = args.on_connect_exec {
+        info!("On connect exec: {}", command);
+    }
+    if let Some(ref command) = args.on_close_exec {
+        info!("On close exec: {}", command);
+    }
+    if args.on_connect_exec.is_some() || args.on_close_exec.is_some() {
+        info!("Exec rate: {}/s", args.exec_rate);
+    }
+    if let Some(ref path) = args.geoip_db {
+        info!("GeoIP database: {}", path);
+    }
+    if let Some(ref path) = args.geoip_asn_db {
+        info!("GeoIP ASN database: {}", path);
+    }
+    if !args.geo_allow.is_empty() {
+        info!("Geo allow: {}", args.geo_allow.join(","));
+    }
+    if !args.geo_deny.is_empty() {
+        info!("Geo deny: {}", args.geo_deny.join(","));
+    }
+    if let Some(ref tenant) = args.tenant {
+        info!("Tenant: {}", tenant);
+    }
+    if let Some(max) = args.tenant_max_connections {
+        info!("Tenant max connections: {}", max);
+    }
+    if let Some(rate) = args.tenant_rate_limit {
+        info!("Tenant rate limit: {}/s", format_bytes(rate));
+    }
+    if !args.tenant_allow.is_empty() {
+        info!("Tenant allow: {}", args.tenant_allow.join(","));
+    }
+    if !args.tenant_deny.is_empty() {
+        info!("Tenant deny: {}", args.tenant_deny.join(","));
+    }
+    if args.transparent {
+        info!("Transparent proxy: enabled");
+    }
+    for ip in &args.bind_source {
+        info!("Bind source: {}", ip);
+    }
+    if let Some(ports) = args.source_ports {
+        info!("Source ports: {}", ports);
+    }
+    if let Some(ref upstream) = args.upstream {
+        info!("Upstream proxy: {}", upstream);
+    }
+    if let Some(ref path) = args.upgrade {
+        info!("Upgrade socket: {}", path);
+    }
+    if args.expect_protocol != ExpectProtocol::None {
+        info!("Expect protocol: {}", args.expect_protocol);
+    }
+    if let Some(ref routes) = sni_routes {
+        for route in routes.routes() {
+            let remotes: Vec<String> = route
+                .remotes
+                .iter()
+                .map(|(addr, _)| addr.to_string())
+                .collect();
+            info!("SNI route: {} -> {}", route.pattern, remotes.join(", "));
         }
-    };
+    }
+    info!(
+        "Capabilities: {}",
+        tinyportmapper::capabilities::Capabilities::global().summary()
+    );
 
     // 确定转发类型
     let fwd_type = if args.mode_4to6 {
@@ -457,237 +1186,339 @@ fn main() {
     } else {
         FwdType::Normal
     };
+    if let Some(ref prefix) = args.nat64_prefix {
+        info!("NAT64 prefix: {}", prefix);
+    }
 
     let config = Arc::new(Config {
         listen_addr: listen_addr.clone(),
-        remote_addr: remote_addr.clone(),
-        enable_tcp: args.tcp,
+        dual_stack: args.dual_stack,
+        v6only: match (args.v6only, args.no_v6only) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        inherit_stdin: args.inherit_stdin,
+        multicast_interface: multicast_interface.map(str::to_string),
+        multicast_reply: args.multicast_reply,
+        udp_broadcast: args.broadcast,
+        remote_addrs,
+        remote_weights,
+        remote_fallbacks,
+        lb_policy: args.lb_policy,
+        udp_sticky: args.udp_sticky,
+        udp_quic: args.udp_quic,
+        enable_tcp: args.tcp || socks5,
         enable_udp: args.udp,
         socket_buf_size: args.buffer * 1024,
+        tcp_buf_autotune: args
+            .sock_buf_autotune
+            .map(|mb| (mb as usize).saturating_mul(1024 * 1024)),
         listen_fd_buf_size: LISTEN_FD_BUF_SIZE,
         log_level: args.log_level,
         log_position: args.log_position,
+        log_anonymize_ips: args.log_anonymize_ips,
         disable_color: args.disable_color,
         max_connections: args.max_connections,
+        max_memory: args.max_memory,
+        listen_backlog: args.backlog,
+        reuseport: !args.no_reuseport,
+        poll_mode: args.poll_mode,
         tcp_timeout: Duration::from_secs(args.tcp_timeout),
         udp_timeout: Duration::from_secs(args.udp_timeout),
+        udp_timeout_map: args.udp_timeout_map.clone().unwrap_or_default(),
+        udp_algs: args.udp_alg.clone().unwrap_or_default(),
+        idle_timeout_c2s: args
+            .idle_timeout_c2s
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        idle_timeout_s2c: args
+            .idle_timeout_s2c
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
         conn_clear_ratio: args.conn_clear_ratio,
         conn_clear_min: args.conn_clear_min,
         disable_conn_clear: args.disable_conn_clear,
         timer_interval: TIMER_INTERVAL_MS,
         fwd_type,
+        nat64_prefix: args.nat64_prefix.unwrap_or_default(),
         bind_interface: args.bind_interface.clone(),
+        transparent: args.transparent,
+        bind_source: args.bind_source.clone(),
+        source_ports: args.source_ports,
+        upstream: args.upstream.clone(),
+        sni_routes,
+        expect_protocol: args.expect_protocol,
+        socks5_listen: socks5,
+        socks5_auth: args.socks5_auth.clone(),
+        tcp_keepalive: args.tcp_keepalive,
+        udp_keepalive: args.udp_keepalive.clone(),
+        udp_recverr: args.udp_recverr,
+        tcp_nodelay: args.tcp_nodelay,
+        tcp_quickack: args.tcp_quickack,
+        tcp_congestion: args.congestion.clone(),
+        tcp_user_timeout: args.tcp_user_timeout.map(Duration::from_millis),
+        tcp_zerocopy: args.zerocopy,
+        tcp_sockmap: args.sockmap,
+        tcp_linger: args.linger,
+        abort_on_timeout: args.abort_on_timeout,
+        connect_retries: args.connect_retries,
+        circuit_breaker: args.circuit_breaker,
+        auto_ban: args.auto_ban,
+        socket_mark: SocketMark {
+            tos: args.tos,
+            fwmark: args.fwmark,
+        },
+        mark_inbound: args.mark_inbound,
         log_file: args.log_file.clone(),
+        log_on_error: args.log_on_error,
         enable_udp_fragment: args.udp_fragment,
+        udp_mtu: args.mtu,
+        udp_preserve_tos_ttl: args.udp_preserve_tos_ttl,
+        rate_limit: args.rate_limit,
+        rate_limit_per_conn: args.rate_limit_per_conn,
+        chaos: args.chaos,
+        mirror: args.mirror.clone(),
+        decrypt_in,
+        encrypt_out,
+        decompress_in: args.decompress_in,
+        compress_out: args.compress_out,
+        ftp_alg: args.ftp_alg,
+        middlewares: Vec::new(),
+        clock: None,
+        tenant: args.tenant.clone(),
+        tenant_max_connections: args.tenant_max_connections,
+        tenant_rate_limit: args.tenant_rate_limit,
+        tenant_allow: args.tenant_allow,
+        tenant_deny: args.tenant_deny,
+        drain_timeout: Duration::from_secs(args.drain_timeout),
+        health_check_interval: Duration::from_secs(args.health_check_interval),
+        upnp: args.upnp,
+        stun_server: args.stun.clone(),
+        webhook: args.webhook.clone(),
+        on_connect_exec: args.on_connect_exec.clone(),
+        on_close_exec: args.on_close_exec.clone(),
+        exec_rate: args.exec_rate,
+        geoip_db: args.geoip_db,
+        geoip_asn_db: args.geoip_asn_db,
+        geo_allow: args.geo_allow,
+        geo_deny: args.geo_deny,
+        stats_interval: Duration::from_secs(args.stats_interval),
+        stats_file: args.stats_file.clone(),
+        reset_stats: args.reset_stats,
+        upgrade_socket: args.upgrade.clone(),
+        top: args.top,
+        handle_signals: true,
     });
 
-    let fd_manager: Arc<FdManager> = FdManager::new();
-    let tcp_manager: Arc<TcpConnectionManager> = Arc::new(TcpConnectionManager::new(
-        config.tcp_timeout,
-        config.conn_clear_ratio,
-        config.conn_clear_min,
-        config.disable_conn_clear,
-    ));
-    let udp_manager: Arc<UdpSessionManager> = Arc::new(UdpSessionManager::new(
-        config.udp_timeout, // 修复：使用正确的 udp_timeout 而非 tcp_timeout
-        config.conn_clear_ratio,
-        config.conn_clear_min,
-        config.disable_conn_clear,
-    ));
-
-    let mut event_loop: EventLoop = match EventLoop::new(
-        config.clone(),
-        Arc::clone(&fd_manager),
-        Arc::clone(&tcp_manager),
-        Arc::clone(&udp_manager),
-    ) {
-        Ok(el) => el,
+    let mut mapper = match PortMapper::new(config) {
+        Ok(mapper) => mapper,
         Err(e) => {
-            eprintln!("Error: failed to create event loop: {}", e);
+            eprintln!("Error: {}", e);
             myexit(1);
         }
     };
 
-    let mut tcp_listener: Option<TcpListener> = None;
-    let mut udp_socket: Option<UdpSocket> = None;
-
-    if args.tcp {
-        let sockaddr = listen_addr.to_sockaddr_storage();
-        let sockaddr_len = listen_addr.get_len() as libc::socklen_t;
-        let listener = unsafe {
-            let fd = libc::socket(addr_family, libc::SOCK_STREAM, 0);
-            if fd < 0 {
-                eprintln!("Error: failed to create TCP socket");
-                myexit(1);
-            }
-
-            let opt: libc::c_int = 1;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEADDR,
-                &opt as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-            // SO_REUSEPORT 支持多进程绑定同一端口
-            #[cfg(target_os = "linux")]
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEPORT,
-                &opt as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-
-            let bufsize = (args.buffer * 1024) as libc::socklen_t;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_SNDBUF,
-                &bufsize as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::socklen_t>() as libc::socklen_t,
-            );
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_RCVBUF,
-                &bufsize as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::socklen_t>() as libc::socklen_t,
-            );
-
-            // 绑定到指定网络接口
-            if let Some(ref interface) = args.bind_interface {
-                if let Err(e) = set_bind_to_device(fd, interface) {
-                    eprintln!("Warning: {}", e);
-                }
-            }
-
-            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
-
-            if libc::bind(
-                fd,
-                &sockaddr as *const _ as *const libc::sockaddr,
-                sockaddr_len,
-            ) < 0
-            {
-                eprintln!("Error: failed to bind TCP socket");
-                myexit(1);
-            }
-
-            if libc::listen(fd, 512) < 0 {
-                eprintln!("Error: failed to listen");
-                myexit(1);
-            }
-
-            fd
+    if args.sandbox {
+        let allow = sandbox::Allow {
+            bpf: args.sockmap,
+            log_file: args.log_file.is_some(),
+            stats_file: args.stats_file.is_some(),
+            unlink: listen_addr.unix_path().is_some() || args.upgrade.is_some(),
+            terminal: args.top,
+            ftp_alg: args.ftp_alg,
+            read_files: args.webhook.is_some() || args.upnp,
         };
-
-        tcp_listener = Some(unsafe { TcpListener::from_raw_fd(listener) });
-        info!("TCP listening on {}", listen_addr);
+        if let Err(e) = sandbox::install(allow) {
+            eprintln!("Error: failed to install sandbox: {}", e);
+            myexit(1);
+        }
+        info!("[sandbox] seccomp filter installed");
     }
 
-    if args.udp {
-        let sockaddr = listen_addr.to_sockaddr_storage();
-        let sockaddr_len = listen_addr.get_len() as libc::socklen_t;
-        let socket = unsafe {
-            let fd = libc::socket(addr_family, libc::SOCK_DGRAM, libc::IPPROTO_UDP);
-            if fd < 0 {
-                eprintln!("Error: failed to create UDP socket");
-                myexit(1);
-            }
-
-            let opt: libc::c_int = 1;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEADDR,
-                &opt as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-            // SO_REUSEPORT 支持多进程绑定同一端口
-            #[cfg(target_os = "linux")]
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEPORT,
-                &opt as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-
-            let bufsize = (args.buffer * 1024) as libc::socklen_t;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_SNDBUF,
-                &bufsize as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::socklen_t>() as libc::socklen_t,
-            );
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_RCVBUF,
-                &bufsize as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::socklen_t>() as libc::socklen_t,
-            );
+    info!("tinyPortMapper started successfully");
+    info!("Press Ctrl+C to stop");
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("[systemd] ready notify failed: {}", e);
+    }
 
-            // 绑定到指定网络接口
-            if let Some(ref interface) = args.bind_interface {
-                if let Err(e) = set_bind_to_device(fd, interface) {
-                    eprintln!("Warning: {}", e);
-                }
-            }
+    let result = mapper.run();
+    let _ = systemd::notify("STOPPING=1");
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        myexit(1);
+    }
 
-            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+    info!("tinyPortMapper stopped");
+}
 
-            if libc::bind(
-                fd,
-                &sockaddr as *const _ as *const libc::sockaddr,
-                sockaddr_len,
-            ) < 0
-            {
-                eprintln!("Error: failed to bind UDP socket");
-                myexit(1);
-            }
+/// 解析测试模式 (--echo-server/--bench) 的地址，失败时退出
+fn parse_test_addr(addr: &str) -> std::net::SocketAddr {
+    match Address::from_str(addr) {
+        Ok(addr) if addr.is_ip() => addr.to_sockaddr(),
+        Ok(_) => {
+            eprintln!("Error: address must be <ip>:<port>: {}", addr);
+            myexit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: invalid address '{}': {}", addr, e);
+            myexit(1);
+        }
+    }
+}
 
-            fd
-        };
+/// 测试模式启用的协议，未指定 -t/-u 时同时启用
+fn test_protocols(args: &Args) -> (bool, bool) {
+    if args.tcp || args.udp {
+        (args.tcp, args.udp)
+    } else {
+        (true, true)
+    }
+}
 
-        udp_socket = Some(unsafe { UdpSocket::from_raw_fd(socket) });
-        info!("UDP listening on {}", listen_addr);
+/// 以压测客户端模式运行 (--bench)，输出结果后退出
+fn run_bench(addr: &str, args: &Args) -> ! {
+    let (tcp, udp) = test_protocols(args);
+    let config = BenchConfig {
+        target: parse_test_addr(addr),
+        tcp_streams: if tcp { args.bench_streams as usize } else { 0 },
+        udp_rate: if udp { args.bench_rate } else { 0 },
+        size: args.bench_size as usize,
+        duration: Duration::from_secs(args.bench_duration),
+    };
+    info!(
+        "[bench] {} for {}s: TCP streams: {}, UDP rate: {} pps, size: {} bytes",
+        config.target, args.bench_duration, config.tcp_streams, config.udp_rate, config.size
+    );
+    match bench::run(&config) {
+        Ok(report) => {
+            print!("{}", report);
+            let failed = report
+                .tcp
+                .as_ref()
+                .is_some_and(|tcp| tcp.failed_streams > 0);
+            myexit(i32::from(failed));
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            myexit(1);
+        }
     }
+}
 
-    if let Err(e) = event_loop.register_listen_socket(tcp_listener, udp_socket) {
-        eprintln!("Error: failed to register listen socket: {}", e);
+/// 以回显/黑洞服务器模式运行 (--echo-server)，不返回
+fn run_echo_server(addr: &str, args: &Args) -> ! {
+    let addr = parse_test_addr(addr);
+    let (tcp, udp) = test_protocols(args);
+    let mode = if args.sink {
+        EchoMode::Sink
+    } else {
+        EchoMode::Echo
+    };
+    let mut server = match EchoServer::bind(addr, tcp, udp, mode) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Error: failed to start echo server on {}: {}", addr, e);
+            myexit(1);
+        }
+    };
+    info!(
+        "[echo] {} server listening on {} (TCP: {}, UDP: {})",
+        mode,
+        server.local_addr().unwrap_or(addr),
+        tcp,
+        udp
+    );
+    if let Err(e) = server.run() {
+        eprintln!("Error: {}", e);
         myexit(1);
     }
+    myexit(0);
+}
 
-    let tcp_handler = event_loop.tcp_handler();
-    {
-        let mut handler = tcp_handler.write().expect("RwLock poisoned");
-        handler.set_remote_addr(remote_addr.clone());
-        handler.set_buf_size(args.buffer * 1024);
-        handler.set_fwd_type(fwd_type);
-        handler.set_bind_interface(args.bind_interface.clone());
+/// 以会合服务器模式运行 (--rendezvous-server)，不返回
+fn run_rendezvous_server(addr: &str) -> ! {
+    let addr = parse_test_addr(addr);
+    let mut server = match RendezvousServer::bind(addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!(
+                "Error: failed to start rendezvous server on {}: {}",
+                addr, e
+            );
+            myexit(1);
+        }
+    };
+    info!(
+        "[rendezvous] listening on {}",
+        server.local_addr().unwrap_or(addr)
+    );
+    if let Err(e) = server.run() {
+        eprintln!("Error: {}", e);
+        myexit(1);
     }
+    myexit(0);
+}
 
-    let udp_handler = event_loop.udp_handler();
-    {
-        let mut handler = udp_handler.write().expect("RwLock poisoned");
-        handler.set_remote_addr(remote_addr.clone());
-        handler.set_buf_size(args.buffer * 1024);
-        handler.set_fwd_type(fwd_type);
-        handler.set_bind_interface(args.bind_interface.clone());
+/// 以打洞隧道对端模式运行 (--punch)，不返回
+fn run_punch(server: &str, args: &Args) -> ! {
+    if args.tcp {
+        eprintln!("Error: --punch forwards UDP only, -t is not supported");
+        myexit(1);
     }
-
-    info!("tinyPortMapper started successfully");
-    info!("Press Ctrl+C to stop");
-
-    if let Err(e) = event_loop.run() {
-        eprintln!("Error: event loop failed: {}", e);
+    let role = match (args.listen.is_empty(), args.remote.as_slice()) {
+        (false, []) => PunchRole::Connect(parse_test_addr(&args.listen)),
+        (true, [remote]) => PunchRole::Serve(parse_test_addr(remote)),
+        _ => {
+            eprintln!("Error: --punch needs either -l <ip>:<port> (connect side) or a single -r <ip>:<port> (serve side)");
+            myexit(1);
+        }
+    };
+    let server_addr = match server.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            eprintln!("Error: rendezvous server '{}' has no address", server);
+            myexit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: invalid rendezvous server '{}': {}", server, e);
+            myexit(1);
+        }
+    };
+    let config = PunchConfig {
+        server: server_addr,
+        name: args.punch_id.clone().unwrap_or_default(),
+        role,
+        timeout: Duration::from_secs(args.udp_timeout),
+    };
+    let mut peer = match PunchPeer::bind(config) {
+        Ok(peer) => peer,
+        Err(e) => {
+            eprintln!("Error: failed to start punch peer: {}", e);
+            myexit(1);
+        }
+    };
+    match role {
+        PunchRole::Connect(_) => info!(
+            "[punch] forwarding UDP clients of {} to peer '{}' via {}",
+            peer.local_addr()
+                .and_then(Result::ok)
+                .map_or_else(|| args.listen.clone(), |addr| addr.to_string()),
+            args.punch_id.as_deref().unwrap_or_default(),
+            server_addr
+        ),
+        PunchRole::Serve(target) => info!(
+            "[punch] forwarding channels from peer '{}' to {} via {}",
+            args.punch_id.as_deref().unwrap_or_default(),
+            target,
+            server_addr
+        ),
+    }
+    if let Err(e) = peer.run() {
+        eprintln!("Error: {}", e);
         myexit(1);
     }
-
-    info!("tinyPortMapper stopped");
+    myexit(0);
 }
 
 /// 单元测试 - 地址解析测试（类似C++版本的unit_test）
@@ -742,4 +1573,15 @@ mod tests {
         assert!(validate_buffer_size("10241").is_err());
         assert!(validate_buffer_size("abc").is_err());
     }
+
+    #[test]
+    fn test_tenant_validation() {
+        assert_eq!(
+            parse_tenant("team-a.prod_1"),
+            Ok("team-a.prod_1".to_string())
+        );
+        assert!(parse_tenant("").is_err());
+        assert!(parse_tenant("team a").is_err());
+        assert!(parse_tenant(&"x".repeat(65)).is_err());
+    }
 }
@@ -2,17 +2,68 @@
 //!
 //! TCP 连接和 UDP 会话的生命周期管理
 
+use crate::config::UdpTimeoutMap;
 use crate::connection::{TcpConnection, UdpSession};
 use crate::debug;
+use crate::event::observer::CloseReason;
 use crate::fd_manager::Fd64;
 use crate::info;
 use crate::lru::LruCollector;
+use crate::memory::{MemoryBudget, TCP_CONN_MEMORY, UDP_SESSION_MEMORY};
+use crate::quic::{self, MAX_CID_LEN};
+use crate::stats::TrafficStats;
+use crate::sync::{Recover, RwLock};
 use crate::types::Address;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+/// 按方向的空闲超时，未设置的方向不单独检查
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectionalTimeouts {
+    /// 客户端 -> 远程方向没有数据的超时
+    pub c2s: Option<Duration>,
+    /// 远程 -> 客户端方向没有数据的超时
+    pub s2c: Option<Duration>,
+}
+
+impl DirectionalTimeouts {
+    /// 结合总超时计算最早的超时时间点 (毫秒) 及对应的关闭原因
+    fn deadline(
+        &self,
+        timeout: Duration,
+        last_active: u64,
+        last_up: u64,
+        last_down: u64,
+    ) -> (u64, CloseReason) {
+        let mut deadline = (
+            last_active.saturating_add(timeout.as_millis() as u64),
+            CloseReason::Timeout,
+        );
+        for (limit, last, reason) in [
+            (self.c2s, last_up, CloseReason::ClientIdle),
+            (self.s2c, last_down, CloseReason::RemoteIdle),
+        ] {
+            if let Some(limit) = limit {
+                let at = last.saturating_add(limit.as_millis() as u64);
+                if at < deadline.0 {
+                    deadline = (at, reason);
+                }
+            }
+        }
+        deadline
+    }
+}
+
+/// 超时清理日志中的连接描述：总超时保持与 C++ 版本一致，按方向超时时注明方向
+fn sweep_detail(id: u64, reason: CloseReason, traffic: &str) -> String {
+    match reason {
+        CloseReason::Timeout => format!(", #{}, {}", id, traffic),
+        _ => format!(", #{}, {}, {}", id, reason, traffic),
+    }
+}
+
 /// TCP 连接管理器
 #[derive(Debug)]
 pub struct TcpConnectionManager {
@@ -22,14 +73,28 @@ pub struct TcpConnectionManager {
     lru: Arc<RwLock<LruCollector<Fd64, Fd64>>>,
     /// 最后清理时间
     last_clear_time: AtomicU64,
+    /// 活动计数 (新建/更新/删除时递增)
+    activity: AtomicU64,
+    /// 上次完整扫描时的活动计数
+    swept_activity: AtomicU64,
+    /// 上次读取以来的最大并发数
+    peak: AtomicUsize,
+    /// 最早可能超时的时间点 (毫秒)，没有连接时为 u64::MAX
+    next_expiry: AtomicU64,
     /// 超时时间
     timeout: Duration,
+    /// 按方向的空闲超时
+    directional: DirectionalTimeouts,
     /// 连接清除比例
     conn_clear_ratio: u32,
     /// 连接清除最小数量
     conn_clear_min: u32,
     /// 是否禁用连接清除
     disable_conn_clear: bool,
+    /// 内存预算，连接状态计入其中
+    budget: Option<Arc<MemoryBudget>>,
+    /// 当前连接数，供其他线程读取 (PortMapperHandle)
+    shared_len: Arc<AtomicUsize>,
 }
 
 impl TcpConnectionManager {
@@ -44,16 +109,37 @@ impl TcpConnectionManager {
             connections: Arc::new(RwLock::new(HashMap::new())),
             lru: Arc::new(RwLock::new(LruCollector::<Fd64, Fd64>::new())),
             last_clear_time: AtomicU64::new(0),
+            activity: AtomicU64::new(0),
+            swept_activity: AtomicU64::new(0),
+            peak: AtomicUsize::new(0),
+            next_expiry: AtomicU64::new(u64::MAX),
             timeout,
+            directional: DirectionalTimeouts::default(),
             conn_clear_ratio,
             conn_clear_min,
             disable_conn_clear,
+            budget: None,
+            shared_len: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 设置内存预算
+    pub fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) {
+        self.budget = Some(budget);
+    }
+
+    /// 归还被移除连接计入的内存
+    fn release_memory(&self, count: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(count * TCP_CONN_MEMORY);
         }
     }
 
     /// 创建新连接
+    #[allow(clippy::too_many_arguments)]
     pub fn new_connection(
         &self,
+        id: u64,
         local_fd: Fd64,
         remote_fd: Fd64,
         addr_s: String,
@@ -62,6 +148,7 @@ impl TcpConnectionManager {
         remote_connecting: bool,
     ) -> Arc<RwLock<TcpConnection>> {
         let connection = Arc::new(RwLock::new(TcpConnection::new(
+            id,
             local_fd,
             remote_fd,
             addr_s,
@@ -71,35 +158,41 @@ impl TcpConnectionManager {
         )));
 
         let fd64 = local_fd;
-        let mut connections = self.connections.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut connections = self.connections.write().recover();
+        let mut lru = self.lru.write().recover();
 
-        connections.insert(fd64, Arc::clone(&connection));
+        if connections.insert(fd64, Arc::clone(&connection)).is_none() {
+            if let Some(budget) = &self.budget {
+                budget.charge(TCP_CONN_MEMORY);
+            }
+        }
         lru.new_key(fd64, fd64, create_time);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        self.peak.fetch_max(connections.len(), Ordering::Relaxed);
+        self.shared_len.store(connections.len(), Ordering::Relaxed);
 
         connection
     }
 
     /// 获取连接
     pub fn get_connection(&self, fd64: &Fd64) -> Option<Arc<RwLock<TcpConnection>>> {
-        self.connections
-            .read()
-            .expect("RwLock poisoned")
-            .get(fd64)
-            .cloned()
+        self.connections.read().recover().get(fd64).cloned()
     }
 
     /// 通过任意 fd64（local 或 remote）获取连接
     pub fn get_connection_by_any_fd(&self, fd64: &Fd64) -> Option<Arc<RwLock<TcpConnection>>> {
         // 首先尝试直接查找
-        if let Some(conn) = self.connections.read().expect("RwLock poisoned").get(fd64) {
+        if let Some(conn) = self.connections.read().recover().get(fd64) {
             return Some(Arc::clone(conn));
         }
-        // 如果没找到，遍历查找 remote fd
-        let connections = self.connections.read().expect("RwLock poisoned");
+        // 如果没找到，遍历查找 remote fd、正在连接的备用地址 fd 和镜像 fd
+        let connections = self.connections.read().recover();
         for conn in connections.values() {
-            let conn_guard = conn.read().expect("RwLock poisoned");
-            if conn_guard.remote.fd64 == *fd64 {
+            let conn_guard = conn.read().recover();
+            if conn_guard.remote.fd64 == *fd64
+                || conn_guard.fallback_fd64() == Some(*fd64)
+                || conn_guard.mirror_fd64() == Some(*fd64)
+            {
                 return Some(Arc::clone(conn));
             }
         }
@@ -108,87 +201,189 @@ impl TcpConnectionManager {
 
     /// 清理连接
     pub fn erase(&self, fd64: &Fd64) {
-        let mut connections = self.connections.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut connections = self.connections.write().recover();
+        let mut lru = self.lru.write().recover();
+
+        if connections.remove(fd64).is_some() {
+            self.release_memory(1);
+            self.shared_len.store(connections.len(), Ordering::Relaxed);
+        }
+        lru.erase(fd64);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 最久未活动的连接及其最后活动时间
+    pub fn oldest(&self) -> Option<(Fd64, u64)> {
+        let mut lru = self.lru.write().recover();
+        let (fd64, _) = lru.peek_back()?;
+        let ts = lru.ts_of(&fd64)?;
+        Some((fd64, ts))
+    }
+
+    /// 移除连接并返回，用于超出内存预算时关闭
+    pub fn evict(&self, fd64: &Fd64) -> Option<Arc<RwLock<TcpConnection>>> {
+        let mut connections = self.connections.write().recover();
+        let mut lru = self.lru.write().recover();
 
-        connections.remove(fd64);
         lru.erase(fd64);
+        let conn = connections.remove(fd64)?;
+        self.release_memory(1);
+        self.shared_len.store(connections.len(), Ordering::Relaxed);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        Some(conn)
+    }
+
+    /// 设置按方向的空闲超时
+    pub fn set_directional_timeouts(&mut self, directional: DirectionalTimeouts) {
+        self.directional = directional;
     }
 
-    /// 清理非活跃连接
-    pub fn clear_inactive(&self) {
-        let now = crate::log::get_current_time();
+    /// 清理非活跃连接，返回被清理的连接及关闭原因
+    pub fn clear_inactive(&self) -> Vec<(Arc<RwLock<TcpConnection>>, CloseReason)> {
+        let now = crate::log::get_monotonic_time();
 
         // 避免过于频繁清理
         if now - self.last_clear_time.load(Ordering::Relaxed) < 1000 {
-            return;
+            return Vec::new();
         }
 
         self.last_clear_time.store(now, Ordering::Relaxed);
 
         if self.disable_conn_clear {
-            return;
+            return Vec::new();
+        }
+
+        // 自上次扫描以来没有任何活动，且最早的超时时间尚未到达，跳过整轮扫描
+        let activity = self.activity.load(Ordering::Relaxed);
+        if activity == self.swept_activity.load(Ordering::Relaxed)
+            && now < self.next_expiry.load(Ordering::Relaxed)
+        {
+            return Vec::new();
         }
 
-        let mut connections = self.connections.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut connections = self.connections.write().recover();
+        let mut lru = self.lru.write().recover();
 
         let size = connections.len();
         let num_to_clean = size / self.conn_clear_ratio as usize + self.conn_clear_min as usize;
         let num_to_clean = std::cmp::min(num_to_clean, size);
+        let mut next_deadline = u64::MAX;
 
-        // 获取所有超时的连接，按时间排序
-        let mut timed_out: Vec<(Fd64, u64, String)> = connections
+        // 获取所有超时的连接，按超时时间点排序
+        let mut timed_out: Vec<(Fd64, u64, CloseReason)> = connections
             .iter()
             .filter_map(|(fd, conn)| {
-                let conn_guard = conn.read().expect("RwLock poisoned");
-                let last_active = conn_guard.last_active_time.load(Ordering::Relaxed);
-                if now - last_active > self.timeout.as_millis() as u64 {
-                    Some((*fd, last_active, conn_guard.addr_s.clone()))
+                let conn_guard = conn.read().recover();
+                let (deadline, reason) = self.directional.deadline(
+                    self.timeout,
+                    conn_guard.last_active_time.load(Ordering::Relaxed),
+                    conn_guard.last_up_time,
+                    conn_guard.last_down_time,
+                );
+                if now > deadline {
+                    Some((*fd, deadline, reason))
                 } else {
+                    next_deadline = next_deadline.min(deadline);
                     None
                 }
             })
             .collect();
+        let timed_out_remaining = timed_out.len() > num_to_clean;
 
-        // 按最后活跃时间排序（最旧的在前）
-        timed_out.sort_by_key(|(_, ts, _)| *ts);
+        // 最早超时的在前
+        timed_out.sort_by_key(|(_, deadline, _)| *deadline);
 
         // 只清理 num_to_clean 个连接
-        let to_remove: Vec<(Fd64, String)> = timed_out
-            .into_iter()
-            .take(num_to_clean)
-            .map(|(fd, _, addr)| (fd, addr))
-            .collect();
-
-        for (fd, addr) in &to_remove {
-            // 与 C++ 版本保持一致：使用 info 级别打印 inactive connection 日志
-            info!(
-                "[tcp]inactive connection {} cleared, tcp connections={}",
-                addr,
-                connections.len().saturating_sub(1)
-            );
-            debug!("[tcp] lru.size()={}", lru.len().saturating_sub(1));
-            connections.remove(fd);
-            lru.erase(fd);
+        let mut removed = Vec::with_capacity(num_to_clean);
+        for (fd, _, reason) in timed_out.into_iter().take(num_to_clean) {
+            let Some(conn) = connections.remove(&fd) else {
+                continue;
+            };
+            self.release_memory(1);
+            lru.erase(&fd);
+            {
+                let guard = conn.read().recover();
+                // 与 C++ 版本保持一致：使用 info 级别打印 inactive connection 日志
+                info!(
+                    "[tcp]inactive connection {} cleared{}, tcp connections={}",
+                    guard.addr_s,
+                    sweep_detail(guard.id, reason, &guard.summary(reason).traffic()),
+                    connections.len()
+                );
+            }
+            debug!("[tcp] lru.size()={}", lru.len());
+            removed.push((conn, reason));
         }
+        self.shared_len.store(connections.len(), Ordering::Relaxed);
+
+        self.finish_sweep(activity, now, next_deadline, timed_out_remaining);
+        removed
+    }
+
+    /// 记录本轮扫描结果，供下次判断是否可以跳过
+    fn finish_sweep(&self, activity: u64, now: u64, next_deadline: u64, timed_out_remaining: bool) {
+        let next_expiry = if timed_out_remaining {
+            now
+        } else {
+            next_deadline
+        };
+        self.next_expiry.store(next_expiry, Ordering::Relaxed);
+        self.swept_activity.store(activity, Ordering::Relaxed);
+    }
+
+    /// 获取活动计数
+    pub fn activity(&self) -> u64 {
+        self.activity.load(Ordering::Relaxed)
+    }
+
+    /// 上次调用以来的最大并发连接数，并从当前连接数重新开始记录
+    pub fn take_peak(&self) -> usize {
+        let len = self.len();
+        self.peak.swap(len, Ordering::Relaxed).max(len)
+    }
+
+    /// 当前连接数的共享计数，可在其他线程中读取
+    pub fn shared_len(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.shared_len)
     }
 
     /// 获取连接数量
     pub fn len(&self) -> usize {
-        self.connections.read().expect("RwLock poisoned").len()
+        self.connections.read().recover().len()
     }
 
     /// 检查是否为空
     pub fn is_empty(&self) -> bool {
-        self.connections.read().expect("RwLock poisoned").is_empty()
+        self.connections.read().recover().is_empty()
     }
 
     /// 更新 LRU
     pub fn update_lru(&self, fd64: &Fd64) {
-        let now = crate::log::get_current_time();
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let now = crate::log::get_monotonic_time();
+        let mut lru = self.lru.write().recover();
         lru.update(fd64, now);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 每个会话最多登记的 QUIC 连接 ID 数量
+pub const MAX_QUIC_CIDS_PER_SESSION: usize = 8;
+
+/// QUIC 连接 ID 到客户端地址的索引
+#[derive(Debug, Default)]
+struct QuicCidIndex {
+    by_cid: HashMap<Vec<u8>, Address>,
+    /// 各长度连接 ID 的数量，短包头不含长度，按已登记的长度逐一尝试
+    len_count: [usize; MAX_CID_LEN + 1],
+}
+
+impl QuicCidIndex {
+    fn remove_all(&mut self, cids: &[Vec<u8>]) {
+        for cid in cids {
+            if self.by_cid.remove(cid).is_some() {
+                self.len_count[cid.len()] -= 1;
+            }
+        }
     }
 }
 
@@ -199,18 +394,38 @@ pub struct UdpSessionManager {
     pub(crate) sessions: Arc<RwLock<HashMap<Address, Arc<RwLock<UdpSession>>>>>,
     /// fd64 到 Address 的映射，用于快速查找
     fd64_to_addr: Arc<RwLock<HashMap<Fd64, Address>>>,
+    /// QUIC 连接 ID 索引，客户端地址变化后据此找回原会话
+    quic_cids: RwLock<QuicCidIndex>,
     /// LRU 清理器
     lru: Arc<RwLock<LruCollector<Address, Address>>>,
     /// 最后清理时间
     last_clear_time: AtomicU64,
+    /// 活动计数 (新建/更新/删除时递增)
+    activity: AtomicU64,
+    /// 上次完整扫描时的活动计数
+    swept_activity: AtomicU64,
+    /// 上次读取以来的最大并发数
+    peak: AtomicUsize,
+    /// 最早可能超时的时间点 (毫秒)，没有连接时为 u64::MAX
+    next_expiry: AtomicU64,
     /// 超时时间
     timeout: Duration,
+    /// 按方向的空闲超时
+    directional: DirectionalTimeouts,
+    /// 按目标端口覆盖的超时
+    port_timeouts: UdpTimeoutMap,
     /// 连接清除比例
     conn_clear_ratio: u32,
     /// 连接清除最小数量
     conn_clear_min: u32,
     /// 是否禁用连接清除
     disable_conn_clear: bool,
+    /// 会话计数所在的统计
+    stats: &'static TrafficStats,
+    /// 内存预算，会话状态计入其中
+    budget: Option<Arc<MemoryBudget>>,
+    /// 当前会话数，供其他线程读取 (PortMapperHandle)
+    shared_len: Arc<AtomicUsize>,
 }
 
 impl UdpSessionManager {
@@ -224,15 +439,42 @@ impl UdpSessionManager {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             fd64_to_addr: Arc::new(RwLock::new(HashMap::new())),
+            quic_cids: RwLock::new(QuicCidIndex::default()),
             lru: Arc::new(RwLock::new(LruCollector::new())),
             last_clear_time: AtomicU64::new(0),
+            activity: AtomicU64::new(0),
+            swept_activity: AtomicU64::new(0),
+            peak: AtomicUsize::new(0),
+            next_expiry: AtomicU64::new(u64::MAX),
             timeout,
+            directional: DirectionalTimeouts::default(),
+            port_timeouts: UdpTimeoutMap::default(),
             conn_clear_ratio,
             conn_clear_min,
             disable_conn_clear,
+            stats: TrafficStats::global(),
+            budget: None,
+            shared_len: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 设置内存预算
+    pub fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) {
+        self.budget = Some(budget);
+    }
+
+    /// 归还被移除会话计入的内存
+    fn release_memory(&self, count: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(count * UDP_SESSION_MEMORY);
         }
     }
 
+    /// 设置会话计数所在的统计 (默认为全局统计)
+    pub fn set_stats(&mut self, stats: &'static TrafficStats) {
+        self.stats = stats;
+    }
+
     /// 创建新会话
     pub fn new_session(
         &self,
@@ -253,35 +495,37 @@ impl UdpSessionManager {
             create_time,
         )));
 
-        let mut sessions = self.sessions.write().expect("RwLock poisoned");
-        let mut fd64_to_addr = self.fd64_to_addr.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut sessions = self.sessions.write().recover();
+        let mut fd64_to_addr = self.fd64_to_addr.write().recover();
+        let mut lru = self.lru.write().recover();
 
-        sessions.insert(address_saved.clone(), Arc::clone(&session));
+        if sessions
+            .insert(address_saved.clone(), Arc::clone(&session))
+            .is_none()
+        {
+            if let Some(budget) = &self.budget {
+                budget.charge(UDP_SESSION_MEMORY);
+            }
+        }
         fd64_to_addr.insert(fd64, address_saved.clone());
         lru.new_key(address_lru.clone(), address_lru, create_time);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        self.peak.fetch_max(sessions.len(), Ordering::Relaxed);
+        self.shared_len.store(sessions.len(), Ordering::Relaxed);
 
         session
     }
 
     /// 获取会话
     pub fn get_session(&self, address: &Address) -> Option<Arc<RwLock<UdpSession>>> {
-        self.sessions
-            .read()
-            .expect("RwLock poisoned")
-            .get(address)
-            .cloned()
+        self.sessions.read().recover().get(address).cloned()
     }
 
     /// 通过 fd64 获取会话 (O(1) 查找)
     pub fn get_session_by_fd64(&self, fd64: &Fd64) -> Option<Arc<RwLock<UdpSession>>> {
-        let fd64_to_addr = self.fd64_to_addr.read().expect("RwLock poisoned");
+        let fd64_to_addr = self.fd64_to_addr.read().recover();
         if let Some(addr) = fd64_to_addr.get(fd64) {
-            self.sessions
-                .read()
-                .expect("RwLock poisoned")
-                .get(addr)
-                .cloned()
+            self.sessions.read().recover().get(addr).cloned()
         } else {
             None
         }
@@ -289,11 +533,9 @@ impl UdpSessionManager {
 
     /// 清理会话
     pub fn erase(&self, address: &Address) {
-        use crate::stats::TrafficStats;
-
-        let mut sessions = self.sessions.write().expect("RwLock poisoned");
-        let mut fd64_to_addr = self.fd64_to_addr.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut sessions = self.sessions.write().recover();
+        let mut fd64_to_addr = self.fd64_to_addr.write().recover();
+        let mut lru = self.lru.write().recover();
 
         // 先查找 fd64 再移除
         let fd64_to_remove: Vec<Fd64> = fd64_to_addr
@@ -306,97 +548,307 @@ impl UdpSessionManager {
             fd64_to_addr.remove(fd);
         }
 
-        let addr_s = {
-            // 获取地址字符串用于日志
+        let (addr_s, traffic) = {
+            // 获取地址字符串和流量用于日志
             if let Some(session) = sessions.get(address) {
-                let guard = session.read().expect("RwLock poisoned");
-                guard.addr_s.clone()
+                let guard = session.read().recover();
+                let traffic = sweep_detail(
+                    guard.id,
+                    CloseReason::Timeout,
+                    &guard.summary(CloseReason::Timeout).traffic(),
+                );
+                (guard.addr_s.clone(), traffic)
             } else {
-                address.to_string()
+                (address.to_string(), String::new())
             }
         };
 
         // 与 C++ 版本保持一致：打印 inactive connection 日志
         info!(
-            "[udp]inactive connection {} cleared, udp connections={}",
+            "[udp]inactive connection {} cleared{}, udp connections={}",
             addr_s,
+            traffic,
             sessions.len().saturating_sub(1)
         );
         debug!("[udp] lru.size()={}", lru.len().saturating_sub(1));
 
-        sessions.remove(address);
+        let removed = sessions.remove(address);
+        self.shared_len.store(sessions.len(), Ordering::Relaxed);
         lru.erase(address);
+        self.activity.fetch_add(1, Ordering::Relaxed);
 
         // 更新统计
-        TrafficStats::global().dec_udp_sessions();
+        self.stats.dec_udp_sessions();
+        if let Some(session) = removed {
+            self.release_memory(1);
+            let session = session.read().recover();
+            self.remove_quic_cids(&session);
+            if let Some(ref backend) = session.backend {
+                backend.stats.dec_udp_sessions();
+            }
+        }
     }
 
-    /// 清理非活跃会话
-    pub fn clear_inactive(&self) {
-        let now = crate::log::get_current_time();
+    /// 最久未活动的会话及其最后活动时间
+    pub fn oldest(&self) -> Option<(Address, u64)> {
+        let mut lru = self.lru.write().recover();
+        let (address, _) = lru.peek_back()?;
+        let ts = lru.ts_of(&address)?;
+        Some((address, ts))
+    }
 
-        if now - self.last_clear_time.load(Ordering::Relaxed) < 1000 {
+    /// 移除会话并返回，用于超出内存预算时关闭
+    pub fn evict(&self, address: &Address) -> Option<Arc<RwLock<UdpSession>>> {
+        let mut sessions = self.sessions.write().recover();
+        let mut fd64_to_addr = self.fd64_to_addr.write().recover();
+        let mut lru = self.lru.write().recover();
+
+        lru.erase(address);
+        let session = sessions.remove(address)?;
+        self.release_memory(1);
+        self.shared_len.store(sessions.len(), Ordering::Relaxed);
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        {
+            let guard = session.read().recover();
+            fd64_to_addr.remove(&guard.fd64);
+            self.remove_quic_cids(&guard);
+            self.stats.dec_udp_sessions();
+            if let Some(ref backend) = guard.backend {
+                backend.stats.dec_udp_sessions();
+            }
+        }
+        Some(session)
+    }
+
+    /// 为会话登记 QUIC 连接 ID，已被其他会话登记的连接 ID 不会被覆盖
+    pub fn add_quic_cid(&self, address: &Address, cid: &[u8]) {
+        if cid.is_empty() || cid.len() > MAX_CID_LEN {
+            return;
+        }
+        let session = match self.get_session(address) {
+            Some(session) => session,
+            None => return,
+        };
+        let mut session = session.write().recover();
+        if session.quic_cids.len() >= MAX_QUIC_CIDS_PER_SESSION
+            || session.quic_cids.iter().any(|known| known == cid)
+        {
+            return;
+        }
+        let mut index = self.quic_cids.write().recover();
+        if index.by_cid.contains_key(cid) {
+            return;
+        }
+        index.by_cid.insert(cid.to_vec(), address.clone());
+        index.len_count[cid.len()] += 1;
+        session.quic_cids.push(cid.to_vec());
+    }
+
+    /// 按 QUIC 包头中的目标连接 ID 查找会话的客户端地址
+    pub fn find_quic(&self, packet: &[u8]) -> Option<Address> {
+        let index = self.quic_cids.read().recover();
+        if index.by_cid.is_empty() {
+            return None;
+        }
+        if let Some(header) = quic::parse_long_header(packet) {
+            return index.by_cid.get(header.dcid).cloned();
+        }
+        (1..=MAX_CID_LEN)
+            .filter(|&len| index.len_count[len] > 0)
+            .filter_map(|len| quic::short_header_dcid(packet, len))
+            .find_map(|dcid| index.by_cid.get(dcid).cloned())
+    }
+
+    /// 将会话迁移到新的客户端地址，新地址已有会话时返回 None
+    pub fn migrate(
+        &self,
+        from: &Address,
+        to: Address,
+        addr_s: String,
+    ) -> Option<Arc<RwLock<UdpSession>>> {
+        let mut sessions = self.sessions.write().recover();
+        let mut fd64_to_addr = self.fd64_to_addr.write().recover();
+        let mut lru = self.lru.write().recover();
+
+        if sessions.contains_key(&to) {
+            return None;
+        }
+        let session_arc = sessions.remove(from)?;
+        {
+            let mut session = session_arc.write().recover();
+            session.address = to.clone();
+            session.addr_s = addr_s;
+            fd64_to_addr.insert(session.fd64, to.clone());
+            let mut index = self.quic_cids.write().recover();
+            for cid in &session.quic_cids {
+                if let Some(addr) = index.by_cid.get_mut(cid) {
+                    *addr = to.clone();
+                }
+            }
+        }
+        lru.erase(from);
+        lru.new_key(to.clone(), to.clone(), crate::log::get_monotonic_time());
+        sessions.insert(to, Arc::clone(&session_arc));
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        Some(session_arc)
+    }
+
+    /// 从索引中移除会话的 QUIC 连接 ID
+    fn remove_quic_cids(&self, session: &UdpSession) {
+        if session.quic_cids.is_empty() {
             return;
         }
+        self.quic_cids
+            .write()
+            .recover()
+            .remove_all(&session.quic_cids);
+    }
+
+    /// 设置按方向的空闲超时
+    pub fn set_directional_timeouts(&mut self, directional: DirectionalTimeouts) {
+        self.directional = directional;
+    }
+
+    /// 设置按目标端口覆盖的超时
+    pub fn set_port_timeouts(&mut self, port_timeouts: UdpTimeoutMap) {
+        self.port_timeouts = port_timeouts;
+    }
+
+    /// 清理非活跃会话，返回被清理的会话及关闭原因
+    pub fn clear_inactive(&self) -> Vec<(Arc<RwLock<UdpSession>>, CloseReason)> {
+        let now = crate::log::get_monotonic_time();
+
+        if now - self.last_clear_time.load(Ordering::Relaxed) < 1000 {
+            return Vec::new();
+        }
 
         self.last_clear_time.store(now, Ordering::Relaxed);
 
         if self.disable_conn_clear {
-            return;
+            return Vec::new();
+        }
+
+        // 自上次扫描以来没有任何活动，且最早的超时时间尚未到达，跳过整轮扫描
+        let activity = self.activity.load(Ordering::Relaxed);
+        if activity == self.swept_activity.load(Ordering::Relaxed)
+            && now < self.next_expiry.load(Ordering::Relaxed)
+        {
+            return Vec::new();
         }
 
-        let mut sessions = self.sessions.write().expect("RwLock poisoned");
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let mut sessions = self.sessions.write().recover();
+        let mut lru = self.lru.write().recover();
 
         let size = sessions.len();
         let num_to_clean = size / self.conn_clear_ratio as usize + self.conn_clear_min as usize;
         let num_to_clean = std::cmp::min(num_to_clean, size);
+        let mut next_deadline = u64::MAX;
 
-        // 获取所有超时的会话，按时间排序
-        let mut timed_out: Vec<(Address, u64)> = sessions
+        // 获取所有超时的会话，按超时时间点排序
+        let mut timed_out: Vec<(Address, u64, CloseReason)> = sessions
             .iter()
             .filter_map(|(addr, session)| {
-                let session_guard = session.read().expect("RwLock poisoned");
-                let last_active = session_guard.last_active_time.load(Ordering::Relaxed);
-                if now - last_active > self.timeout.as_millis() as u64 {
-                    Some((addr.clone(), last_active))
+                let session_guard = session.read().recover();
+                let timeout = session_guard
+                    .backend
+                    .as_ref()
+                    .and_then(|backend| self.port_timeouts.get(backend.addr.port()))
+                    .unwrap_or(self.timeout);
+                let (deadline, reason) = self.directional.deadline(
+                    timeout,
+                    session_guard.last_active_time.load(Ordering::Relaxed),
+                    session_guard.last_up_time,
+                    session_guard.last_down_time,
+                );
+                if now > deadline {
+                    Some((addr.clone(), deadline, reason))
                 } else {
+                    next_deadline = next_deadline.min(deadline);
                     None
                 }
             })
             .collect();
+        let timed_out_remaining = timed_out.len() > num_to_clean;
 
-        // 按最后活跃时间排序（最旧的在前）
-        timed_out.sort_by_key(|(_, ts)| *ts);
+        // 最早超时的在前
+        timed_out.sort_by_key(|(_, deadline, _)| *deadline);
 
         // 只清理 num_to_clean 个会话
-        let to_remove: Vec<Address> = timed_out
-            .into_iter()
-            .take(num_to_clean)
-            .map(|(addr, _)| addr)
-            .collect();
-
-        for addr in &to_remove {
-            sessions.remove(addr);
-            lru.erase(addr);
+        let mut fd64_to_addr = self.fd64_to_addr.write().recover();
+        let mut removed = Vec::with_capacity(num_to_clean);
+        for (addr, _, reason) in timed_out.into_iter().take(num_to_clean) {
+            let Some(session) = sessions.remove(&addr) else {
+                continue;
+            };
+            self.release_memory(1);
+            lru.erase(&addr);
+            {
+                let guard = session.read().recover();
+                fd64_to_addr.remove(&guard.fd64);
+                self.remove_quic_cids(&guard);
+                self.stats.dec_udp_sessions();
+                if let Some(ref backend) = guard.backend {
+                    backend.stats.dec_udp_sessions();
+                }
+                // 与 C++ 版本保持一致：打印 inactive connection 日志
+                info!(
+                    "[udp]inactive connection {} cleared{}, udp connections={}",
+                    guard.addr_s,
+                    sweep_detail(guard.id, reason, &guard.summary(reason).traffic()),
+                    sessions.len()
+                );
+            }
+            removed.push((session, reason));
         }
+        self.shared_len.store(sessions.len(), Ordering::Relaxed);
+
+        self.finish_sweep(activity, now, next_deadline, timed_out_remaining);
+        removed
+    }
+
+    /// 记录本轮扫描结果，供下次判断是否可以跳过
+    fn finish_sweep(&self, activity: u64, now: u64, next_deadline: u64, timed_out_remaining: bool) {
+        let next_expiry = if timed_out_remaining {
+            now
+        } else {
+            next_deadline
+        };
+        self.next_expiry.store(next_expiry, Ordering::Relaxed);
+        self.swept_activity.store(activity, Ordering::Relaxed);
+    }
+
+    /// 获取活动计数
+    pub fn activity(&self) -> u64 {
+        self.activity.load(Ordering::Relaxed)
+    }
+
+    /// 上次调用以来的最大并发会话数，并从当前会话数重新开始记录
+    pub fn take_peak(&self) -> usize {
+        let len = self.len();
+        self.peak.swap(len, Ordering::Relaxed).max(len)
+    }
+
+    /// 当前会话数的共享计数，可在其他线程中读取
+    pub fn shared_len(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.shared_len)
     }
 
     /// 获取会话数量
     pub fn len(&self) -> usize {
-        self.sessions.read().expect("RwLock poisoned").len()
+        self.sessions.read().recover().len()
     }
 
     /// 检查是否为空
     pub fn is_empty(&self) -> bool {
-        self.sessions.read().expect("RwLock poisoned").is_empty()
+        self.sessions.read().recover().is_empty()
     }
 
     /// 更新 LRU
     pub fn update_lru(&self, address: &Address) {
-        let now = crate::log::get_current_time();
-        let mut lru = self.lru.write().expect("RwLock poisoned");
+        let now = crate::log::get_monotonic_time();
+        let mut lru = self.lru.write().recover();
         lru.update(address, now);
+        self.activity.fetch_add(1, Ordering::Relaxed);
     }
 }
 
@@ -410,6 +862,7 @@ mod tests {
         let manager = TcpConnectionManager::new(Duration::from_secs(60), 30, 1, false);
 
         let _conn = manager.new_connection(
+            1,
             Fd64(1),
             Fd64(2),
             "127.0.0.1:12345".to_string(),
@@ -423,6 +876,7 @@ mod tests {
 
         manager.erase(&Fd64(1));
         assert!(manager.is_empty());
+        assert_eq!(manager.shared_len().load(Ordering::Relaxed), 0);
     }
 
     #[test]
@@ -440,4 +894,228 @@ mod tests {
         manager.erase(&addr_clone);
         assert!(manager.is_empty());
     }
+
+    #[test]
+    fn test_udp_quic_migration() {
+        let manager = UdpSessionManager::new(Duration::from_secs(30), 30, 1, false);
+        let old = Address::from_str("127.0.0.1:1000").expect("address");
+        let new = Address::from_str("127.0.0.2:2000").expect("address");
+        manager.new_session(old.clone(), Fd64(1), Fd64(2), "old".to_string(), 1000);
+
+        let cid = [7u8, 7, 7, 7, 7, 7, 7, 7];
+        manager.add_quic_cid(&old, &cid);
+        let mut short = vec![0x40];
+        short.extend_from_slice(&cid);
+        short.extend_from_slice(b"payload");
+        assert_eq!(manager.find_quic(&short), Some(old.clone()));
+        assert_eq!(manager.find_quic(b"\x40unknown-cid"), None);
+
+        let session = manager
+            .migrate(&old, new.clone(), "new".to_string())
+            .expect("migrated");
+        assert_eq!(session.read().expect("session").address, new);
+        assert!(manager.get_session(&old).is_none());
+        assert!(manager.get_session_by_fd64(&Fd64(1)).is_some());
+        assert_eq!(manager.find_quic(&short), Some(new.clone()));
+
+        manager.erase(&new);
+        assert_eq!(manager.find_quic(&short), None);
+    }
+
+    #[test]
+    fn test_memory_budget_and_evict() {
+        let budget = MemoryBudget::new(usize::MAX);
+        let mut manager = TcpConnectionManager::new(Duration::from_secs(60), 30, 1, false);
+        manager.set_memory_budget(Arc::clone(&budget));
+        manager.new_connection(1, Fd64(1), Fd64(2), "a".to_string(), 1000, 16384, false);
+        manager.new_connection(2, Fd64(3), Fd64(4), "b".to_string(), 2000, 16384, false);
+        assert_eq!(budget.used(), 2 * TCP_CONN_MEMORY);
+
+        assert_eq!(manager.oldest(), Some((Fd64(1), 1000)));
+        let conn = manager.evict(&Fd64(1)).expect("evicted");
+        assert_eq!(conn.read().expect("conn").id, 1);
+        assert!(manager.evict(&Fd64(1)).is_none());
+        assert_eq!(manager.oldest(), Some((Fd64(3), 2000)));
+        manager.erase(&Fd64(3));
+        assert_eq!(budget.used(), 0);
+        assert_eq!(manager.oldest(), None);
+
+        let mut sessions = UdpSessionManager::new(Duration::from_secs(30), 30, 1, false);
+        sessions.set_memory_budget(Arc::clone(&budget));
+        let addr = Address::from_str("127.0.0.1:12345").expect("address");
+        sessions.new_session(addr.clone(), Fd64(5), Fd64(6), "c".to_string(), 3000);
+        assert_eq!(budget.used(), UDP_SESSION_MEMORY);
+        assert_eq!(sessions.oldest(), Some((addr.clone(), 3000)));
+        sessions.evict(&addr).expect("evicted");
+        assert!(sessions.get_session_by_fd64(&Fd64(5)).is_none());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_activity_counter() {
+        let manager = TcpConnectionManager::new(Duration::from_secs(60), 30, 1, false);
+        assert_eq!(manager.activity(), 0);
+
+        manager.new_connection(1, Fd64(1), Fd64(2), "a".to_string(), 1000, 16384, false);
+        manager.update_lru(&Fd64(1));
+        manager.erase(&Fd64(1));
+        assert_eq!(manager.activity(), 3);
+    }
+
+    #[test]
+    fn test_take_peak() {
+        let manager = TcpConnectionManager::new(Duration::from_secs(60), 30, 1, false);
+        manager.new_connection(1, Fd64(1), Fd64(2), "a".to_string(), 1000, 16384, false);
+        manager.new_connection(2, Fd64(3), Fd64(4), "b".to_string(), 1000, 16384, false);
+        manager.erase(&Fd64(1));
+        assert_eq!(manager.take_peak(), 2);
+        // 重新从当前连接数开始记录
+        assert_eq!(manager.take_peak(), 1);
+        manager.erase(&Fd64(3));
+        assert_eq!(manager.take_peak(), 1);
+        assert_eq!(manager.take_peak(), 0);
+    }
+
+    #[test]
+    fn test_clear_inactive_sweeps_expired() {
+        let now = crate::log::get_monotonic_time();
+        let manager = TcpConnectionManager::new(Duration::from_secs(1), 30, 1, false);
+        manager.new_connection(
+            1,
+            Fd64(1),
+            Fd64(2),
+            "a".to_string(),
+            now - 5000,
+            16384,
+            false,
+        );
+        manager.new_connection(2, Fd64(3), Fd64(4), "b".to_string(), now, 16384, false);
+
+        manager.clear_inactive();
+        assert_eq!(manager.len(), 1);
+        assert!(manager.get_connection(&Fd64(3)).is_some());
+        // 剩余连接的最早超时时间点已记录
+        assert_eq!(manager.next_expiry.load(Ordering::Relaxed), now + 1000);
+    }
+
+    #[test]
+    fn test_clear_inactive_directional() {
+        let now = crate::log::get_monotonic_time();
+        let mut manager = TcpConnectionManager::new(Duration::from_secs(60), 30, 1, false);
+        manager.set_directional_timeouts(DirectionalTimeouts {
+            c2s: Some(Duration::from_secs(1)),
+            s2c: None,
+        });
+        // 只有远程在发送数据，客户端方向已静默 5 秒
+        let silent = manager.new_connection(
+            1,
+            Fd64(1),
+            Fd64(2),
+            "a".to_string(),
+            now - 5000,
+            16384,
+            false,
+        );
+        {
+            let mut conn = silent.write().expect("conn");
+            conn.last_active_time.store(now, Ordering::Relaxed);
+            conn.last_down_time = now;
+        }
+        manager.new_connection(2, Fd64(3), Fd64(4), "b".to_string(), now, 16384, false);
+
+        let removed = manager.clear_inactive();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].1, CloseReason::ClientIdle);
+        assert_eq!(removed[0].0.read().expect("conn").id, 1);
+        // 剩余连接按客户端方向超时计算下一次清理时间
+        assert_eq!(manager.next_expiry.load(Ordering::Relaxed), now + 1000);
+    }
+
+    #[test]
+    fn test_lru_with_mock_clock() {
+        use crate::clock::{self, MockClock};
+
+        let clock = MockClock::new();
+        let _guard = clock::install(clock.clone());
+        let start = crate::log::get_monotonic_time();
+        // 每轮最多清理 1 个会话
+        let manager = UdpSessionManager::new(Duration::from_secs(30), 1000, 1, false);
+        let addrs: Vec<Address> = (1..=3)
+            .map(|i| Address::from_str(&format!("127.0.0.1:{}", i)).expect("address"))
+            .collect();
+        for (i, addr) in addrs.iter().enumerate() {
+            let now = crate::log::get_monotonic_time();
+            manager.new_session(
+                addr.clone(),
+                Fd64(i as u64 * 2),
+                Fd64(i as u64 * 2 + 1),
+                addr.to_string(),
+                now,
+            );
+            clock.advance(Duration::from_secs(2));
+        }
+        assert_eq!(manager.oldest(), Some((addrs[0].clone(), start)));
+        // 最近访问的会话移到 LRU 头部
+        manager.update_lru(&addrs[0]);
+        assert_eq!(manager.oldest(), Some((addrs[1].clone(), start + 2000)));
+
+        // 第 1、2 个会话已超时，按超时先后每轮清理一个
+        clock.advance(Duration::from_secs(27));
+        let removed = manager.clear_inactive();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0.read().expect("session").address, addrs[0]);
+        // 两次清理至少间隔 1 秒
+        assert!(manager.clear_inactive().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let removed = manager.clear_inactive();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0.read().expect("session").address, addrs[1]);
+        assert_eq!(manager.len(), 1);
+        assert_eq!(manager.next_expiry.load(Ordering::Relaxed), start + 34_000);
+    }
+
+    #[test]
+    fn test_clear_inactive_skips_idle_sweep() {
+        let now = crate::log::get_monotonic_time();
+        let manager = UdpSessionManager::new(Duration::from_secs(60), 30, 1, false);
+        let addr = Address::from_str("127.0.0.1:12345").expect("Address parsing failed");
+        manager.new_session(addr, Fd64(1), Fd64(2), "a".to_string(), now);
+
+        manager.clear_inactive();
+        assert_eq!(
+            manager.swept_activity.load(Ordering::Relaxed),
+            manager.activity()
+        );
+        assert_eq!(manager.next_expiry.load(Ordering::Relaxed), now + 60_000);
+    }
+
+    #[test]
+    fn test_clear_inactive_port_timeouts() {
+        let now = crate::log::get_monotonic_time();
+        let mut manager = UdpSessionManager::new(Duration::from_secs(60), 30, 2, false);
+        manager.set_port_timeouts("53=1,27015=300".parse().expect("timeout map"));
+        let remotes = ["127.0.0.1:53", "127.0.0.1:27015", "127.0.0.1:5000"];
+        for (i, remote) in remotes.iter().enumerate() {
+            let addr = Address::from_str(&format!("127.0.0.1:{}", 1000 + i)).expect("address");
+            let session = manager.new_session(
+                addr,
+                Fd64(i as u64 * 2),
+                Fd64(i as u64 * 2 + 1),
+                remote.to_string(),
+                now - 5000,
+            );
+            session.write().expect("session").backend =
+                Some(Arc::new(crate::backend::Backend::direct(
+                    Address::from_str(remote).expect("address"),
+                    Arc::default(),
+                )));
+        }
+
+        // 只有 DNS 会话按 1 秒超时，其余按各自的超时保留
+        let removed = manager.clear_inactive();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0.read().expect("session").addr_s, remotes[0]);
+        assert_eq!(manager.len(), 2);
+        assert_eq!(manager.next_expiry.load(Ordering::Relaxed), now + 55_000);
+    }
 }
@@ -0,0 +1,3158 @@
+//! 嵌入式 API
+//!
+//! 供其他程序以库的形式嵌入端口转发，无需启动独立进程：
+//!
+//! ```no_run
+//! use tinyportmapper::PortMapper;
+//!
+//! let mut mapper = PortMapper::builder()
+//!     .listen("0.0.0.0:1234")
+//!     .remote("10.0.0.1:443")
+//!     .tcp(true)
+//!     .build()?;
+//! let handle = mapper.handle();
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_secs(60));
+//!     println!("{:?}", handle.stats());
+//!     handle.stop();
+//! });
+//! mapper.run()?;
+//! # Ok::<(), tinyportmapper::Error>(())
+//! ```
+
+use crate::alg::{UdpAlg, UdpAlgMap};
+use crate::autotune::BufAutotune;
+use crate::backend::{resolve_weighted_remote, BackendPool, LbPolicy};
+use crate::ban;
+use crate::chaos::Chaos;
+use crate::clock::Clock;
+use crate::config::{
+    AutoBan, CircuitBreaker, Config, FwdType, Linger, PollMode, PortRange, SocketMark,
+    TcpKeepalive, UdpKeepalive, UdpTimeoutMap, DEFAULT_CONN_CLEAR_MIN, DEFAULT_CONN_CLEAR_RATIO,
+    DEFAULT_LISTEN_BACKLOG, DEFAULT_MAX_CONNECTIONS, DEFAULT_SOCKET_BUF_SIZE,
+    DEFAULT_STATS_INTERVAL_SECS, DEFAULT_TCP_TIMEOUT_MS, DEFAULT_UDP_TIMEOUT_MS,
+    LISTEN_FD_BUF_SIZE, TIMER_INTERVAL_MS,
+};
+use crate::error::Error;
+use crate::event::drain::DrainReport;
+use crate::event::observer::ConnectionObserver;
+use crate::event::{EventLoop, StopHandle};
+use crate::fd_manager::FdManager;
+use crate::fragment;
+use crate::ftp::Ftp;
+use crate::geo::{GeoFilter, GeoPolicy};
+use crate::health::{HealthChecker, ProbeKind};
+use crate::hook::{ExecHook, DEFAULT_EXEC_RATE};
+#[cfg(target_os = "linux")]
+use crate::ipheader;
+use crate::log::{get_monotonic_time, LogErrorPolicy};
+use crate::manager::{DirectionalTimeouts, TcpConnectionManager, UdpSessionManager};
+use crate::memory::MemoryBudget;
+use crate::middleware::Middleware;
+use crate::mirror::UdpMirror;
+use crate::multicast;
+#[cfg(windows)]
+use crate::npipe::{PipeBridge, PipeBridgeHandle};
+use crate::obfs::{Cipher, Psk, OBFS_MIN_BUF};
+use crate::sni::{SniRouter, SniRoutes};
+use crate::sniff::ExpectProtocol;
+use crate::sockets::{TcpListenerBuilder, UdpSocketBuilder};
+#[cfg(target_os = "linux")]
+use crate::sockmap::Sockmap;
+use crate::socks5::{Socks5Server, Socks5Upstream};
+use crate::stats::{StatsSnapshot, TrafficStats};
+use crate::stun::{self, StunClient};
+use crate::sync::Recover;
+use crate::tenant::{Tenant, TenantLimits};
+use crate::types::{Address, Nat64Prefix};
+use crate::upgrade::{Handover, SocketKind};
+use crate::upnp::{self, PortForwarder};
+use crate::webhook::Webhook;
+use crate::{info, warn};
+
+#[cfg(windows)]
+use crate::winsock::{FromRawFd, IntoRawFd};
+use mio::net::{TcpListener, UdpSocket};
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// PortMapper 构建器
+#[derive(Debug, Clone)]
+pub struct PortMapperBuilder {
+    listen: Option<String>,
+    dual_stack: bool,
+    v6only: Option<bool>,
+    inherit_stdin: bool,
+    multicast_reply: bool,
+    udp_broadcast: bool,
+    remotes: Vec<String>,
+    tcp: bool,
+    udp: bool,
+    socket_buf_size: usize,
+    tcp_buf_autotune: Option<usize>,
+    max_connections: usize,
+    max_memory: Option<usize>,
+    listen_backlog: u32,
+    reuseport: bool,
+    poll_mode: PollMode,
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
+    udp_timeout_map: UdpTimeoutMap,
+    udp_algs: UdpAlgMap,
+    idle_timeout_c2s: Option<Duration>,
+    idle_timeout_s2c: Option<Duration>,
+    conn_clear_ratio: u32,
+    conn_clear_min: u32,
+    disable_conn_clear: bool,
+    fwd_type: FwdType,
+    nat64_prefix: Nat64Prefix,
+    bind_interface: Option<String>,
+    transparent: bool,
+    bind_source: Vec<IpAddr>,
+    source_ports: Option<PortRange>,
+    upstream: Option<String>,
+    sni_routes: Option<String>,
+    expect_protocol: ExpectProtocol,
+    socks5_listen: bool,
+    socks5_auth: Option<String>,
+    tcp_keepalive: Option<TcpKeepalive>,
+    udp_keepalive: Option<UdpKeepalive>,
+    udp_recverr: bool,
+    tcp_nodelay: bool,
+    tcp_quickack: bool,
+    tcp_congestion: Option<String>,
+    tcp_user_timeout: Option<Duration>,
+    tcp_zerocopy: Option<usize>,
+    tcp_sockmap: bool,
+    tcp_linger: Option<Linger>,
+    abort_on_timeout: bool,
+    connect_retries: u32,
+    circuit_breaker: Option<CircuitBreaker>,
+    auto_ban: Option<AutoBan>,
+    socket_mark: SocketMark,
+    mark_inbound: bool,
+    udp_fragment: bool,
+    udp_mtu: Option<usize>,
+    udp_preserve_tos_ttl: bool,
+    rate_limit: Option<u64>,
+    rate_limit_per_conn: Option<u64>,
+    chaos: Option<Chaos>,
+    mirror: Option<String>,
+    cipher: Cipher,
+    decrypt_in: Option<String>,
+    encrypt_out: Option<String>,
+    decompress_in: bool,
+    compress_out: bool,
+    ftp_alg: bool,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    clock: Option<Arc<dyn Clock>>,
+    tenant: Option<String>,
+    tenant_max_connections: Option<usize>,
+    tenant_rate_limit: Option<u64>,
+    tenant_allow: Vec<String>,
+    tenant_deny: Vec<String>,
+    drain_timeout: Duration,
+    health_check_interval: Duration,
+    upnp: bool,
+    stun_server: Option<String>,
+    webhook: Option<String>,
+    on_connect_exec: Option<String>,
+    on_close_exec: Option<String>,
+    exec_rate: u32,
+    geoip_db: Option<String>,
+    geoip_asn_db: Option<String>,
+    geo_allow: Vec<String>,
+    geo_deny: Vec<String>,
+    lb_policy: LbPolicy,
+    udp_sticky: bool,
+    udp_quic: bool,
+    stats_interval: Duration,
+    stats_file: Option<String>,
+    reset_stats: bool,
+    upgrade_socket: Option<String>,
+    top: bool,
+    handle_signals: bool,
+}
+
+impl Default for PortMapperBuilder {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            dual_stack: false,
+            v6only: None,
+            inherit_stdin: false,
+            multicast_reply: false,
+            udp_broadcast: false,
+            remotes: Vec::new(),
+            tcp: false,
+            udp: false,
+            socket_buf_size: DEFAULT_SOCKET_BUF_SIZE,
+            tcp_buf_autotune: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_memory: None,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            reuseport: true,
+            poll_mode: PollMode::Edge,
+            tcp_timeout: Duration::from_millis(DEFAULT_TCP_TIMEOUT_MS),
+            udp_timeout: Duration::from_millis(DEFAULT_UDP_TIMEOUT_MS),
+            udp_timeout_map: UdpTimeoutMap::default(),
+            udp_algs: UdpAlgMap::default(),
+            idle_timeout_c2s: None,
+            idle_timeout_s2c: None,
+            conn_clear_ratio: DEFAULT_CONN_CLEAR_RATIO,
+            conn_clear_min: DEFAULT_CONN_CLEAR_MIN,
+            disable_conn_clear: false,
+            fwd_type: FwdType::Normal,
+            nat64_prefix: Nat64Prefix::default(),
+            bind_interface: None,
+            transparent: false,
+            bind_source: Vec::new(),
+            source_ports: None,
+            upstream: None,
+            sni_routes: None,
+            expect_protocol: ExpectProtocol::None,
+            socks5_listen: false,
+            socks5_auth: None,
+            tcp_keepalive: None,
+            udp_keepalive: None,
+            udp_recverr: false,
+            tcp_nodelay: true,
+            tcp_quickack: false,
+            tcp_congestion: None,
+            tcp_user_timeout: None,
+            tcp_zerocopy: None,
+            tcp_sockmap: false,
+            tcp_linger: None,
+            abort_on_timeout: false,
+            connect_retries: 0,
+            circuit_breaker: None,
+            auto_ban: None,
+            socket_mark: SocketMark::default(),
+            mark_inbound: false,
+            udp_fragment: false,
+            udp_mtu: None,
+            udp_preserve_tos_ttl: false,
+            rate_limit: None,
+            rate_limit_per_conn: None,
+            chaos: None,
+            mirror: None,
+            cipher: Cipher::Xor,
+            decrypt_in: None,
+            encrypt_out: None,
+            decompress_in: false,
+            compress_out: false,
+            ftp_alg: false,
+            middlewares: Vec::new(),
+            clock: None,
+            tenant: None,
+            tenant_max_connections: None,
+            tenant_rate_limit: None,
+            tenant_allow: Vec::new(),
+            tenant_deny: Vec::new(),
+            drain_timeout: Duration::ZERO,
+            health_check_interval: Duration::ZERO,
+            upnp: false,
+            stun_server: None,
+            webhook: None,
+            on_connect_exec: None,
+            on_close_exec: None,
+            exec_rate: DEFAULT_EXEC_RATE,
+            geoip_db: None,
+            geoip_asn_db: None,
+            geo_allow: Vec::new(),
+            geo_deny: Vec::new(),
+            lb_policy: LbPolicy::RoundRobin,
+            udp_sticky: false,
+            udp_quic: false,
+            stats_interval: Duration::from_secs(DEFAULT_STATS_INTERVAL_SECS),
+            stats_file: None,
+            reset_stats: false,
+            upgrade_socket: None,
+            top: false,
+            handle_signals: false,
+        }
+    }
+}
+
+impl PortMapperBuilder {
+    /// 监听地址，例如 `0.0.0.0:1234` 或 `[::]:1234`；组播地址可以用 `239.1.1.1:5000@eth0` 指定加入组的接口
+    pub fn listen(mut self, addr: &str) -> Self {
+        self.listen = Some(addr.to_string());
+        self
+    }
+
+    /// 监听 `0.0.0.0` 或 `[::]` 时分别创建 IPv4 和 IPv6 监听 socket，不依赖系统的 IPv4 映射地址设置
+    pub fn dual_stack(mut self, enable: bool) -> Self {
+        self.dual_stack = enable;
+        self
+    }
+
+    /// 设置 IPv6 监听 socket 的 IPV6_V6ONLY：true 时只接受 IPv6 客户端，
+    /// false 时 `[::]` 同时接受 IPv4 客户端 (显示为 `::ffff:` 映射地址)。默认使用系统设置
+    pub fn v6only(mut self, enable: bool) -> Self {
+        self.v6only = Some(enable);
+        self
+    }
+
+    /// 不监听，转发 fd 0 上由 inetd 传入的已连接 socket (只支持 TCP)，此时可以不设置监听地址
+    pub fn inherit_stdin(mut self, enable: bool) -> Self {
+        self.inherit_stdin = enable;
+        self
+    }
+
+    /// 监听组播地址时，远程的回包发往组播组而不是发回客户端
+    pub fn multicast_reply(mut self, enable: bool) -> Self {
+        self.multicast_reply = enable;
+        self
+    }
+
+    /// 广播中继：接收局域网内的广播数据包转发给远程，远程可以是另一个子网的广播地址，
+    /// 来自任意应答主机的回包都转发回客户端 (只支持 IPv4)
+    pub fn udp_broadcast(mut self, enable: bool) -> Self {
+        self.udp_broadcast = enable;
+        self
+    }
+
+    /// 远程地址
+    ///
+    /// 可多次调用添加多个远程地址，新连接按负载均衡策略分配；
+    /// weighted 策略下可用 `地址@权重` 指定权重
+    pub fn remote(mut self, addr: &str) -> Self {
+        self.remotes.push(addr.to_string());
+        self
+    }
+
+    /// 启用 TCP 转发
+    pub fn tcp(mut self, enable: bool) -> Self {
+        self.tcp = enable;
+        self
+    }
+
+    /// 启用 UDP 转发
+    pub fn udp(mut self, enable: bool) -> Self {
+        self.udp = enable;
+        self
+    }
+
+    /// Socket 缓冲区大小 (字节)
+    pub fn socket_buf_size(mut self, size: usize) -> Self {
+        self.socket_buf_size = size;
+        self
+    }
+
+    /// 按吞吐量和积压自动调整 TCP 连接的 socket 缓冲区：从 `socket_buf_size` 开始，
+    /// 所有连接超出初始大小的部分合计不超过 `budget` 字节
+    pub fn tcp_buf_autotune(mut self, budget: usize) -> Self {
+        self.tcp_buf_autotune = Some(budget);
+        self
+    }
+
+    /// 缓冲区和连接状态的总内存预算 (字节)：新连接会超出时拒绝，
+    /// 已超出时从最久未活动的连接开始关闭
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// 最大连接数
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// TCP 监听队列长度 (默认为 512)，突发大量连接时调大，同时需要调大 net.core.somaxconn
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_backlog = backlog;
+        self
+    }
+
+    /// 监听 socket 是否设置 SO_REUSEPORT (仅 Linux，默认为 true)，
+    /// 关闭后其他进程无法绑定同一端口分担连接
+    pub fn reuseport(mut self, enable: bool) -> Self {
+        self.reuseport = enable;
+        self
+    }
+
+    /// socket 就绪通知方式 (默认为边沿触发)
+    pub fn poll_mode(mut self, mode: PollMode) -> Self {
+        self.poll_mode = mode;
+        self
+    }
+
+    /// TCP 连接超时
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_timeout = timeout;
+        self
+    }
+
+    /// UDP 会话超时
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// 按目标端口覆盖 UDP 会话超时，例如 DNS 会话较短、游戏会话较长
+    pub fn udp_timeout_map(mut self, map: UdpTimeoutMap) -> Self {
+        self.udp_timeout_map = map;
+        self
+    }
+
+    /// 在远程端口上挂载 UDP 应用层网关，例如 `(alg::TFTP_PORT, Arc::new(alg::Tftp))`，
+    /// 远程从新端口回包时会话跟随到新端口 (可多次调用)
+    pub fn udp_alg(mut self, port: u16, alg: Arc<dyn UdpAlg>) -> Self {
+        self.udp_algs.insert(port, alg);
+        self
+    }
+
+    /// 客户端 -> 远程方向超过该时间没有数据即关闭连接 (默认不检查)
+    pub fn idle_timeout_c2s(mut self, timeout: Duration) -> Self {
+        self.idle_timeout_c2s = Some(timeout);
+        self
+    }
+
+    /// 远程 -> 客户端方向超过该时间没有数据即关闭连接 (默认不检查)
+    pub fn idle_timeout_s2c(mut self, timeout: Duration) -> Self {
+        self.idle_timeout_s2c = Some(timeout);
+        self
+    }
+
+    /// 连接清除比例
+    pub fn conn_clear_ratio(mut self, ratio: u32) -> Self {
+        self.conn_clear_ratio = ratio;
+        self
+    }
+
+    /// 每次最少清除的连接数
+    pub fn conn_clear_min(mut self, min: u32) -> Self {
+        self.conn_clear_min = min;
+        self
+    }
+
+    /// 禁用自动连接清除
+    pub fn disable_conn_clear(mut self, disable: bool) -> Self {
+        self.disable_conn_clear = disable;
+        self
+    }
+
+    /// 地址翻译模式
+    pub fn fwd_type(mut self, fwd_type: FwdType) -> Self {
+        self.fwd_type = fwd_type;
+        self
+    }
+
+    /// 4to6/6to4 翻译使用的 NAT64 前缀，例如 `Nat64Prefix::WELL_KNOWN` (64:ff9b::/96)
+    pub fn nat64_prefix(mut self, prefix: Nat64Prefix) -> Self {
+        self.nat64_prefix = prefix;
+        self
+    }
+
+    /// 绑定到指定网络接口 (Linux)
+    pub fn bind_interface(mut self, interface: &str) -> Self {
+        self.bind_interface = Some(interface.to_string());
+        self
+    }
+
+    /// 透明代理模式，后端看到客户端的真实 IP (仅 Linux，需要 CAP_NET_ADMIN)
+    pub fn transparent(mut self, enable: bool) -> Self {
+        self.transparent = enable;
+        self
+    }
+
+    /// 连接后端 (或上游代理) 前把外连 socket 绑定到本机地址 `ip`，用于多出口的主机。
+    /// IPv4 和 IPv6 各可设置一个，socket 按地址族选用，不能与透明代理同时使用
+    pub fn bind_source(mut self, ip: IpAddr) -> Self {
+        self.bind_source.push(ip);
+        self
+    }
+
+    /// 外连 socket 从 `ports` 中选择源端口 (被占用时换下一个)，用于只放行特定端口的防火墙或运营商 NAT。
+    /// 不能与透明代理同时使用
+    pub fn source_ports(mut self, ports: PortRange) -> Self {
+        self.source_ports = Some(ports);
+        self
+    }
+
+    /// 经上游 SOCKS5 代理连接后端，例如 `socks5://10.0.0.2:1080` 或 `socks5://host:1080:user:pass`
+    pub fn upstream(mut self, url: &str) -> Self {
+        self.upstream = Some(url.to_string());
+        self
+    }
+
+    /// 按 TLS SNI 选择 TCP 后端的路由文件，未匹配的连接使用 `remote` 指定的后端
+    pub fn sni_routes(mut self, path: &str) -> Self {
+        self.sni_routes = Some(path.to_string());
+        self
+    }
+
+    /// 只转发开头数据符合 `protocol` 的 TCP 连接，其余在连接后端前关闭
+    pub fn expect_protocol(mut self, protocol: ExpectProtocol) -> Self {
+        self.expect_protocol = protocol;
+        self
+    }
+
+    /// 作为 SOCKS5 服务端监听 `addr`，TCP 连接转发到客户端 CONNECT 的目标，不需要后端地址；
+    /// 总是启用 TCP，同时启用 UDP 时监听 socket 作为 UDP ASSOCIATE 中继
+    pub fn socks5_listen(mut self, addr: &str) -> Self {
+        self.listen = Some(addr.to_string());
+        self.socks5_listen = true;
+        self
+    }
+
+    /// SOCKS5 服务端要求客户端提供的用户名和密码，格式为 `user:pass`
+    pub fn socks5_auth(mut self, auth: &str) -> Self {
+        self.socks5_auth = Some(auth.to_string());
+        self
+    }
+
+    /// 在客户端和远程 TCP 连接上启用 keepalive
+    pub fn tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// 客户端方向空闲的 UDP 会话定期向远程发送保活数据包，避免沿途防火墙的映射过期
+    pub fn udp_keepalive(mut self, keepalive: UdpKeepalive) -> Self {
+        self.udp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// UDP 会话 socket 启用 IP_RECVERR，远程回复 ICMP 端口不可达等错误时立即关闭会话，不必等待超时 (仅 Linux)
+    pub fn udp_recverr(mut self, enable: bool) -> Self {
+        self.udp_recverr = enable;
+        self
+    }
+
+    /// TCP_NODELAY (默认启用)
+    pub fn tcp_nodelay(mut self, enable: bool) -> Self {
+        self.tcp_nodelay = enable;
+        self
+    }
+
+    /// TCP_QUICKACK，立即确认收到的数据 (仅 Linux)
+    pub fn tcp_quickack(mut self, enable: bool) -> Self {
+        self.tcp_quickack = enable;
+        self
+    }
+
+    /// TCP 拥塞控制算法，例如 `bbr` (仅 Linux)
+    pub fn tcp_congestion(mut self, algo: &str) -> Self {
+        self.tcp_congestion = Some(algo.to_string());
+        self
+    }
+
+    /// TCP_USER_TIMEOUT，已发送数据超过该时间未被确认即断开，更快发现失联的对端 (仅 Linux)
+    pub fn tcp_user_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_user_timeout = Some(timeout);
+        self
+    }
+
+    /// 单次发送达到 `threshold` 字节时使用 MSG_ZEROCOPY，省去复制到内核的开销 (仅 Linux 4.14+)
+    pub fn tcp_zerocopy(mut self, threshold: usize) -> Self {
+        self.tcp_zerocopy = Some(threshold);
+        self
+    }
+
+    /// 连接建立后交给 eBPF sockmap 在内核中转发，用户态只处理连接关闭和统计
+    /// (仅 Linux 5.7+，需要 CAP_BPF 和 CAP_NET_ADMIN)
+    pub fn tcp_sockmap(mut self, enable: bool) -> Self {
+        self.tcp_sockmap = enable;
+        self
+    }
+
+    /// 在客户端和远程 TCP 连接上设置 SO_LINGER，`Linger::Secs(0)` 时关闭连接发送 RST
+    pub fn tcp_linger(mut self, linger: Linger) -> Self {
+        self.tcp_linger = Some(linger);
+        self
+    }
+
+    /// 超时清理的 TCP 连接以 RST 而不是 FIN 关闭 (默认为 false)
+    pub fn abort_on_timeout(mut self, enable: bool) -> Self {
+        self.abort_on_timeout = enable;
+        self
+    }
+
+    /// 连接后端被拒绝或超时后重试的次数，重试间隔从 100ms 开始翻倍 (默认为 0，不重试)
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// 后端连续 `threshold` 次连接失败后熔断 `cooldown`，期间到该后端的新连接直接关闭，
+    /// 有其他可用后端时改为分配到其他后端
+    pub fn circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker {
+            threshold,
+            cooldown,
+        });
+        self
+    }
+
+    /// 客户端 IP 在 `window` 内被拒绝 `threshold` 次后封禁 `duration`，期间直接关闭它的新连接、
+    /// 丢弃它建立新会话的数据包
+    pub fn auto_ban(mut self, threshold: u32, window: Duration, duration: Duration) -> Self {
+        self.auto_ban = Some(AutoBan {
+            threshold,
+            window,
+            duration,
+        });
+        self
+    }
+
+    /// 外连 socket 的 IP_TOS/IPV6_TCLASS，例如 DSCP EF 为 `46 << 2` (0xb8)
+    pub fn tos(mut self, tos: u8) -> Self {
+        self.socket_mark.tos = Some(tos);
+        self
+    }
+
+    /// 外连 socket 的 SO_MARK，供策略路由 (`ip rule fwmark`) 和 tc 匹配 (仅 Linux，需要 CAP_NET_ADMIN)
+    pub fn fwmark(mut self, mark: u32) -> Self {
+        self.socket_mark.fwmark = Some(mark);
+        self
+    }
+
+    /// 监听 socket 也设置 TOS/SO_MARK，标记发给客户端的流量 (默认只标记外连)
+    pub fn mark_inbound(mut self, enable: bool) -> Self {
+        self.mark_inbound = enable;
+        self
+    }
+
+    /// 启用 UDP 分片转发：分片到达的数据包允许分片发送，完整到达的带 DF 发送 (仅 Linux)
+    pub fn udp_fragment(mut self, enable: bool) -> Self {
+        self.udp_fragment = enable;
+        self
+    }
+
+    /// 客户端 -> 远程 UDP 数据包的 IP 包长度上限，超出时完整到达的丢弃，分片到达的由内核分片发送
+    pub fn udp_mtu(mut self, mtu: usize) -> Self {
+        self.udp_mtu = Some(mtu);
+        self
+    }
+
+    /// 转发 UDP 数据包时逐包沿用收到的 TOS/ECN 和 TTL (TTL 减一，耗尽时丢弃)，
+    /// 使 ECN 和 traceroute 经过转发后仍然有效；配置了 `--tos` 时外连方向只沿用 ECN 位 (仅 Linux)
+    pub fn udp_preserve_tos_ttl(mut self, enable: bool) -> Self {
+        self.udp_preserve_tos_ttl = enable;
+        self
+    }
+
+    /// 全局限速 (字节/秒)
+    pub fn rate_limit(mut self, rate: u64) -> Self {
+        self.rate_limit = Some(rate);
+        self
+    }
+
+    /// 单连接限速 (字节/秒)
+    pub fn rate_limit_per_conn(mut self, rate: u64) -> Self {
+        self.rate_limit_per_conn = Some(rate);
+        self
+    }
+
+    /// 故障注入：延迟、抖动和 UDP 丢包
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// 把客户端 -> 后端方向的数据复制一份发到 `addr` (TCP 为独立连接，UDP 为数据包副本)
+    pub fn mirror(mut self, addr: &str) -> Self {
+        self.mirror = Some(addr.to_string());
+        self
+    }
+
+    /// `decrypt_in`/`encrypt_out` 使用的算法 (默认为 XOR 混淆)
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// 用预共享密钥解密客户端发来的 TCP 流，对端为使用相同密钥 `encrypt_out` 的中继
+    pub fn decrypt_in(mut self, psk: &str) -> Self {
+        self.decrypt_in = Some(psk.to_string());
+        self
+    }
+
+    /// 用预共享密钥加密发往后端的 TCP 流，后端为使用相同密钥 `decrypt_in` 的中继
+    pub fn encrypt_out(mut self, psk: &str) -> Self {
+        self.encrypt_out = Some(psk.to_string());
+        self
+    }
+
+    /// 解压客户端发来的 LZ4 压缩流 (对端为 `compress_out` 的中继)，普通客户端的流原样转发
+    pub fn decompress_in(mut self, enable: bool) -> Self {
+        self.decompress_in = enable;
+        self
+    }
+
+    /// LZ4 压缩发往后端的 TCP 流，后端为 `decompress_in` 的中继
+    pub fn compress_out(mut self, enable: bool) -> Self {
+        self.compress_out = enable;
+        self
+    }
+
+    /// FTP 应用层网关：解析控制连接中的 PORT/EPRT 和 PASV/EPSV 应答，为数据通道打开临时监听
+    pub fn ftp_alg(mut self, enable: bool) -> Self {
+        self.ftp_alg = enable;
+        self
+    }
+
+    /// 挂载中间件，按挂载顺序处理新连接和会话的数据 (可多次调用)
+    pub fn middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// 事件循环使用的时钟，测试中传入 `MockClock` 后超时和定时器只随它推进
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// 租户名，同一租户的多个实例共享统计汇总
+    pub fn tenant(mut self, name: &str) -> Self {
+        self.tenant = Some(name.to_string());
+        self
+    }
+
+    /// 同一租户所有实例的连接总数上限 (TCP 连接和 UDP 会话合计)
+    pub fn tenant_max_connections(mut self, max: usize) -> Self {
+        self.tenant_max_connections = Some(max);
+        self
+    }
+
+    /// 同一租户所有实例共享的带宽 (字节/秒)
+    pub fn tenant_rate_limit(mut self, rate: u64) -> Self {
+        self.tenant_rate_limit = Some(rate);
+        self
+    }
+
+    /// 租户只接受这些网段的客户端，例如 `["10.0.0.0/8"]`
+    pub fn tenant_allow(mut self, nets: &[&str]) -> Self {
+        self.tenant_allow = nets.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// 租户拒绝这些网段的客户端，先于 `tenant_allow` 检查
+    pub fn tenant_deny(mut self, nets: &[&str]) -> Self {
+        self.tenant_deny = nets.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// 停止时等待已有连接关闭的最长时间 (默认为 0，立即关闭)
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// 后端健康检查间隔 (默认为 0，不检查)
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// 请求路由器把相同的外部端口映射到监听端口 (依次尝试 PCP、NAT-PMP 和 UPnP IGD)，并定期续期
+    pub fn upnp(mut self, enable: bool) -> Self {
+        self.upnp = enable;
+        self
+    }
+
+    /// 通过 STUN 服务端 `host[:port]` (默认端口 3478) 发现 UDP 监听端口的公网地址
+    pub fn stun(mut self, server: &str) -> Self {
+        self.stun_server = Some(server.to_string());
+        self
+    }
+
+    /// 把连接建立/关闭、拒绝新连接和后端状态变化事件以 JSON POST 到 `http://` 地址
+    pub fn webhook(mut self, url: &str) -> Self {
+        self.webhook = Some(url.to_string());
+        self
+    }
+
+    /// TCP 连接建立或新建 UDP 会话时通过 shell 执行命令，环境变量 `TPM_*` 描述客户端和映射
+    pub fn on_connect_exec(mut self, command: &str) -> Self {
+        self.on_connect_exec = Some(command.to_string());
+        self
+    }
+
+    /// 连接/会话关闭时通过 shell 执行命令，额外提供关闭原因和流量
+    pub fn on_close_exec(mut self, command: &str) -> Self {
+        self.on_close_exec = Some(command.to_string());
+        self
+    }
+
+    /// 每秒最多执行的命令数，超出的事件不执行 (默认为 10)
+    pub fn exec_rate(mut self, rate: u32) -> Self {
+        self.exec_rate = rate;
+        self
+    }
+
+    /// MaxMind GeoLite2 Country/City 数据库，新连接日志标注客户端国家 (需要 `geoip` feature)
+    pub fn geoip_db(mut self, path: &str) -> Self {
+        self.geoip_db = Some(path.to_string());
+        self
+    }
+
+    /// MaxMind GeoLite2 ASN 数据库，新连接日志同时标注 ASN
+    pub fn geoip_asn_db(mut self, path: &str) -> Self {
+        self.geoip_asn_db = Some(path.to_string());
+        self
+    }
+
+    /// 只接受这些国家的客户端，例如 `["CN", "US"]`；查不到国家的地址 (内网等) 为 `ZZ`
+    pub fn geo_allow(mut self, countries: &[&str]) -> Self {
+        self.geo_allow = countries.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// 拒绝这些国家的客户端，先于 `geo_allow` 检查
+    pub fn geo_deny(mut self, countries: &[&str]) -> Self {
+        self.geo_deny = countries.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// 统计输出间隔 (默认为 10 秒，为 0 时不输出)
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// 负载均衡策略 (默认为轮询)
+    pub fn lb_policy(mut self, policy: LbPolicy) -> Self {
+        self.lb_policy = policy;
+        self
+    }
+
+    /// UDP 按客户端地址固定后端，会话过期后同一客户端仍分配到同一后端
+    pub fn udp_sticky(mut self, sticky: bool) -> Self {
+        self.udp_sticky = sticky;
+        self
+    }
+
+    /// 跟踪 QUIC 连接 ID，客户端地址变化后 UDP 数据包仍转发到原会话
+    pub fn udp_quic(mut self, enable: bool) -> Self {
+        self.udp_quic = enable;
+        self
+    }
+
+    /// 累计统计状态文件，启动时加载、退出时保存
+    pub fn stats_file(mut self, path: &str) -> Self {
+        self.stats_file = Some(path.to_string());
+        self
+    }
+
+    /// 启动时清零累计统计，不加载状态文件
+    pub fn reset_stats(mut self, reset: bool) -> Self {
+        self.reset_stats = reset;
+        self
+    }
+
+    /// 平滑升级控制 socket：从该路径上运行的旧进程接管监听 socket，旧进程随后排空退出
+    pub fn upgrade_socket(mut self, path: &str) -> Self {
+        self.upgrade_socket = Some(path.to_string());
+        self
+    }
+
+    /// 在终端实时刷新连接表 (默认为 false)，需同时关闭控制台日志 (`Logger::set_console`)
+    pub fn top(mut self, enable: bool) -> Self {
+        self.top = enable;
+        self
+    }
+
+    /// 处理终止、输出连接表和切换日志级别的信号 (默认为 false)
+    ///
+    /// 会在调用 `build()` 的线程中屏蔽这些信号并启动信号线程，影响整个进程，
+    /// 只应在独立运行的程序中开启；同一进程中只能有一个实例开启
+    pub fn handle_signals(mut self, enable: bool) -> Self {
+        self.handle_signals = enable;
+        self
+    }
+
+    /// 校验参数并生成配置
+    pub fn config(&self) -> Result<Config, Error> {
+        let (listen, multicast_interface) = match self.listen.as_deref() {
+            Some(listen) => {
+                let (listen, interface) = multicast::split_interface(listen);
+                (Some(listen), interface.map(str::to_string))
+            }
+            None => (None, None),
+        };
+        let listen_addr = match listen {
+            None if self.inherit_stdin => Address::from_ipv4(Ipv4Addr::UNSPECIFIED, 0),
+            _ => parse_address("listen", listen)?,
+        };
+        if self.socks5_listen && !self.remotes.is_empty() {
+            return Err(Error::config(
+                "socks5-listen takes its targets from clients, do not set remote addresses",
+            ));
+        }
+        if self.remotes.is_empty() && !self.socks5_listen {
+            return Err(Error::config("remote address is required"));
+        }
+        if self.socks5_auth.is_some() && !self.socks5_listen {
+            return Err(Error::config("socks5-auth requires socks5-listen"));
+        }
+        let socks5_auth = self
+            .socks5_auth
+            .as_deref()
+            .map(crate::socks5::parse_auth)
+            .transpose()
+            .map_err(Error::Config)?;
+        let mut remote_addrs = Vec::with_capacity(self.remotes.len());
+        let mut remote_fallbacks = Vec::with_capacity(self.remotes.len());
+        let mut remote_weights = Vec::with_capacity(self.remotes.len());
+        for addr in &self.remotes {
+            let (remote, fallback, weight) =
+                resolve_weighted_remote(addr).map_err(|reason| Error::Address {
+                    kind: "remote",
+                    addr: addr.clone(),
+                    reason,
+                })?;
+            remote_addrs.push(remote);
+            remote_fallbacks.push(fallback);
+            remote_weights.push(weight);
+        }
+        let upstream = self
+            .upstream
+            .as_deref()
+            .map(|url| url.parse::<Socks5Upstream>())
+            .transpose()
+            .map_err(Error::Config)?;
+        let mirror = self
+            .mirror
+            .as_deref()
+            .map(|addr| parse_address("mirror", Some(addr)))
+            .transpose()?;
+        let psk = |secret: &Option<String>| {
+            secret
+                .as_deref()
+                .map(|secret| Psk::new(self.cipher, secret))
+                .transpose()
+                .map_err(Error::Config)
+        };
+        let decrypt_in = psk(&self.decrypt_in)?;
+        let encrypt_out = psk(&self.encrypt_out)?;
+        let sni_routes = match self.sni_routes {
+            Some(ref path) => Some(
+                SniRoutes::load(Path::new(path))
+                    .map_err(|e| Error::io(format!("failed to load SNI routes '{}'", path), e))?,
+            ),
+            None => None,
+        };
+        if !self.tcp && !self.udp && !self.socks5_listen {
+            return Err(Error::config("at least one of tcp or udp must be enabled"));
+        }
+
+        let logger = crate::log::Logger::global();
+        Ok(Config {
+            listen_addr,
+            dual_stack: self.dual_stack,
+            v6only: self.v6only,
+            inherit_stdin: self.inherit_stdin,
+            multicast_interface,
+            multicast_reply: self.multicast_reply,
+            udp_broadcast: self.udp_broadcast,
+            remote_addrs,
+            remote_weights,
+            remote_fallbacks,
+            lb_policy: self.lb_policy,
+            udp_sticky: self.udp_sticky,
+            udp_quic: self.udp_quic,
+            enable_tcp: self.tcp || self.socks5_listen,
+            enable_udp: self.udp,
+            socket_buf_size: self.socket_buf_size,
+            tcp_buf_autotune: self.tcp_buf_autotune,
+            listen_fd_buf_size: LISTEN_FD_BUF_SIZE,
+            log_level: logger.get_level(),
+            log_position: logger.is_position_enabled(),
+            log_anonymize_ips: logger.is_anonymize_ips_enabled(),
+            disable_color: !logger.is_color_enabled(),
+            max_connections: self.max_connections,
+            max_memory: self.max_memory,
+            listen_backlog: self.listen_backlog,
+            reuseport: self.reuseport,
+            poll_mode: self.poll_mode,
+            tcp_timeout: self.tcp_timeout,
+            udp_timeout: self.udp_timeout,
+            udp_timeout_map: self.udp_timeout_map.clone(),
+            udp_algs: self.udp_algs.clone(),
+            idle_timeout_c2s: self.idle_timeout_c2s,
+            idle_timeout_s2c: self.idle_timeout_s2c,
+            conn_clear_ratio: self.conn_clear_ratio,
+            conn_clear_min: self.conn_clear_min,
+            disable_conn_clear: self.disable_conn_clear,
+            timer_interval: TIMER_INTERVAL_MS,
+            fwd_type: self.fwd_type,
+            nat64_prefix: self.nat64_prefix,
+            bind_interface: self.bind_interface.clone(),
+            transparent: self.transparent,
+            bind_source: self.bind_source.clone(),
+            source_ports: self.source_ports,
+            upstream,
+            sni_routes,
+            expect_protocol: self.expect_protocol,
+            socks5_listen: self.socks5_listen,
+            socks5_auth,
+            tcp_keepalive: self.tcp_keepalive,
+            udp_keepalive: self.udp_keepalive.clone(),
+            udp_recverr: self.udp_recverr,
+            tcp_nodelay: self.tcp_nodelay,
+            tcp_quickack: self.tcp_quickack,
+            tcp_congestion: self.tcp_congestion.clone(),
+            tcp_user_timeout: self.tcp_user_timeout,
+            tcp_zerocopy: self.tcp_zerocopy,
+            tcp_sockmap: self.tcp_sockmap,
+            tcp_linger: self.tcp_linger,
+            abort_on_timeout: self.abort_on_timeout,
+            connect_retries: self.connect_retries,
+            circuit_breaker: self.circuit_breaker,
+            auto_ban: self.auto_ban,
+            socket_mark: self.socket_mark,
+            mark_inbound: self.mark_inbound,
+            log_file: None,
+            log_on_error: LogErrorPolicy::Stderr,
+            enable_udp_fragment: self.udp_fragment,
+            udp_mtu: self.udp_mtu,
+            udp_preserve_tos_ttl: self.udp_preserve_tos_ttl,
+            rate_limit: self.rate_limit,
+            rate_limit_per_conn: self.rate_limit_per_conn,
+            chaos: self.chaos,
+            mirror,
+            decrypt_in,
+            encrypt_out,
+            decompress_in: self.decompress_in,
+            compress_out: self.compress_out,
+            ftp_alg: self.ftp_alg,
+            middlewares: self.middlewares.clone(),
+            clock: self.clock.clone(),
+            tenant: self.tenant.clone(),
+            tenant_max_connections: self.tenant_max_connections,
+            tenant_rate_limit: self.tenant_rate_limit,
+            tenant_allow: self.tenant_allow.clone(),
+            tenant_deny: self.tenant_deny.clone(),
+            drain_timeout: self.drain_timeout,
+            health_check_interval: self.health_check_interval,
+            upnp: self.upnp,
+            stun_server: self.stun_server.clone(),
+            webhook: self.webhook.clone(),
+            on_connect_exec: self.on_connect_exec.clone(),
+            on_close_exec: self.on_close_exec.clone(),
+            exec_rate: self.exec_rate,
+            geoip_db: self.geoip_db.clone(),
+            geoip_asn_db: self.geoip_asn_db.clone(),
+            geo_allow: self.geo_allow.clone(),
+            geo_deny: self.geo_deny.clone(),
+            stats_interval: self.stats_interval,
+            stats_file: self.stats_file.clone(),
+            reset_stats: self.reset_stats,
+            upgrade_socket: self.upgrade_socket.clone(),
+            top: self.top,
+            handle_signals: self.handle_signals,
+        })
+    }
+
+    /// 创建监听 socket 和事件循环
+    pub fn build(self) -> Result<PortMapper, Error> {
+        PortMapper::new(Arc::new(self.config()?))
+    }
+}
+
+/// 解析构建器中的地址参数
+fn parse_address(kind: &'static str, addr: Option<&str>) -> Result<Address, Error> {
+    let addr = addr.ok_or_else(|| Error::config(format!("{} address is required", kind)))?;
+    Address::from_str(addr).map_err(|e| Error::Address {
+        kind,
+        addr: addr.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// 端口转发实例
+pub struct PortMapper {
+    config: Arc<Config>,
+    event_loop: EventLoop,
+    tcp_manager: Arc<TcpConnectionManager>,
+    udp_manager: Arc<UdpSessionManager>,
+    /// 监听地址或后端为命名管道时的转发器，`run` 时启动
+    #[cfg(windows)]
+    pipe_bridge: Option<PipeBridge>,
+}
+
+impl PortMapper {
+    /// 创建构建器
+    pub fn builder() -> PortMapperBuilder {
+        PortMapperBuilder::default()
+    }
+
+    /// 按配置创建监听 socket 和事件循环
+    pub fn new(config: Arc<Config>) -> Result<Self, Error> {
+        let congestion = match config.tcp_congestion {
+            Some(ref algo) => Some(congestion_algo(algo)?),
+            None => None,
+        };
+        if !config.socket_mark.is_empty() {
+            check_socket_mark(&config.socket_mark)?;
+        }
+        if config.tcp_zerocopy == Some(0) {
+            return Err(Error::config("zerocopy threshold must be greater than 0"));
+        }
+        if config.tcp_buf_autotune == Some(0) {
+            return Err(Error::config("autotune budget must be greater than 0"));
+        }
+        if config
+            .udp_mtu
+            .is_some_and(|mtu| !(fragment::MIN_MTU..=65535).contains(&mtu))
+        {
+            return Err(Error::config(format!(
+                "mtu must be between {} and 65535",
+                fragment::MIN_MTU
+            )));
+        }
+        if config.max_memory == Some(0) {
+            return Err(Error::config("memory budget must be greater than 0"));
+        }
+        if let Some(auto_ban) = config.auto_ban {
+            if auto_ban.threshold == 0 || auto_ban.window.is_zero() || auto_ban.duration.is_zero() {
+                return Err(Error::config(
+                    "auto-ban failures, window and ban time must be greater than 0",
+                ));
+            }
+        }
+        if config.geoip_db.is_some() && !cfg!(feature = "geoip") {
+            return Err(Error::Unsupported(
+                "geoip-db requires the geoip feature".to_string(),
+            ));
+        }
+        if config.geoip_db.is_none()
+            && (config.geoip_asn_db.is_some()
+                || !config.geo_allow.is_empty()
+                || !config.geo_deny.is_empty())
+        {
+            return Err(Error::config(
+                "geoip-asn-db, geo-allow and geo-deny require geoip-db",
+            ));
+        }
+        if multicast::is_multicast(&config.listen_addr) {
+            if config.enable_tcp {
+                return Err(Error::config(
+                    "TCP cannot listen on a multicast address, forward UDP only",
+                ));
+            }
+        } else if config.multicast_reply {
+            return Err(Error::config(
+                "multicast-reply requires a multicast listen address",
+            ));
+        }
+        #[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+        if multicast::is_multicast(&config.listen_addr) {
+            return Err(Error::Unsupported(
+                "multicast listen is only supported on Linux and macOS".to_string(),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.tcp_zerocopy.is_some() {
+            return Err(Error::Unsupported(
+                "zerocopy is only supported on Linux".to_string(),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.udp_recverr {
+            return Err(Error::Unsupported(
+                "udp-recverr is only supported on Linux".to_string(),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.udp_preserve_tos_ttl {
+            return Err(Error::Unsupported(
+                "udp-preserve-tos-ttl is only supported on Linux".to_string(),
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.tcp_sockmap {
+            return Err(Error::Unsupported(
+                "sockmap is only supported on Linux".to_string(),
+            ));
+        }
+        if config.tcp_sockmap
+            && (config.rate_limit.is_some() || config.rate_limit_per_conn.is_some())
+        {
+            return Err(Error::config(
+                "sockmap bypasses the rate limiter, do not combine them",
+            ));
+        }
+        if config.tcp_sockmap && config.chaos.is_some_and(|chaos| chaos.delays()) {
+            return Err(Error::config(
+                "sockmap bypasses chaos delays, do not combine them",
+            ));
+        }
+        if config.tcp_sockmap && config.mirror.is_some() {
+            return Err(Error::config(
+                "sockmap bypasses traffic mirroring, do not combine them",
+            ));
+        }
+        let obfs = config.decrypt_in.is_some() || config.encrypt_out.is_some();
+        if config.tcp_sockmap && obfs {
+            return Err(Error::config(
+                "sockmap bypasses stream obfuscation, do not combine them",
+            ));
+        }
+        let compress = config.decompress_in || config.compress_out;
+        if compress && !cfg!(feature = "lz4") {
+            return Err(Error::config(
+                "compress-out and decompress-in require the lz4 feature",
+            ));
+        }
+        if config.tcp_sockmap && compress {
+            return Err(Error::config(
+                "sockmap bypasses stream compression, do not combine them",
+            ));
+        }
+        if (obfs || compress) && config.socket_buf_size < OBFS_MIN_BUF {
+            return Err(Error::config(format!(
+                "stream obfuscation and compression need a buffer of at least {} bytes",
+                OBFS_MIN_BUF
+            )));
+        }
+        if config.decrypt_in.is_some()
+            && (config.sni_routes.is_some() || config.expect_protocol != ExpectProtocol::None)
+        {
+            return Err(Error::config(
+                "sni-routes and expect-protocol see encrypted data with decrypt-in",
+            ));
+        }
+        if config.decompress_in
+            && (config.sni_routes.is_some() || config.expect_protocol != ExpectProtocol::None)
+        {
+            return Err(Error::config(
+                "sni-routes and expect-protocol see compressed data with decompress-in",
+            ));
+        }
+        if config.mirror.as_ref().is_some_and(|addr| !addr.is_ip()) {
+            return Err(Error::config("mirror address must be <ip>:<port>"));
+        }
+        #[cfg(target_os = "linux")]
+        let sockmap = if config.tcp_sockmap {
+            let sockmap = Sockmap::new(config.max_connections)
+                .map_err(|e| Error::socket("failed to set up sockmap", e))?;
+            Some(Arc::new(sockmap))
+        } else {
+            None
+        };
+        check_non_ip_addrs(&config)?;
+        check_named_pipes(&config)?;
+        check_socks5_server(&config)?;
+        check_bind_source(&config)?;
+        check_broadcast(&config)?;
+        check_udp_algs(&config)?;
+        check_ftp_alg(&config)?;
+        check_middlewares(&config)?;
+        check_upnp(&config)?;
+        let stun = match config.stun_server {
+            Some(ref server) => Some(Arc::new(stun_client(&config, server)?)),
+            None => None,
+        };
+        if !config.nat64_prefix.is_ipv4_mapped() && config.fwd_type == FwdType::Normal {
+            return Err(Error::config("nat64-prefix requires -4 or -6"));
+        }
+        if config.inherit_stdin && config.upgrade_socket.is_some() {
+            return Err(Error::config(
+                "inherit-stdin has no listening sockets to upgrade",
+            ));
+        }
+        if config.inherit_stdin && (!config.enable_tcp || config.enable_udp) {
+            return Err(Error::config("inherit-stdin only supports TCP forwarding"));
+        }
+        #[cfg(windows)]
+        if config.inherit_stdin {
+            return Err(Error::Unsupported(
+                "inherit-stdin is only supported on Unix".to_string(),
+            ));
+        }
+        let upstream = config.upstream.clone().map(Arc::new);
+        let tenant_limits = TenantLimits::new(
+            config.tenant_max_connections,
+            config.tenant_rate_limit,
+            &config.tenant_allow,
+            &config.tenant_deny,
+        )
+        .map_err(Error::Config)?;
+        if config.tenant.is_none() && !tenant_limits.is_empty() {
+            return Err(Error::config(
+                "tenant-max-connections, tenant-rate-limit, tenant-allow and tenant-deny require tenant",
+            ));
+        }
+        if config.tenant_max_connections == Some(0) {
+            return Err(Error::config(
+                "tenant-max-connections must be greater than 0",
+            ));
+        }
+
+        let fd_manager = FdManager::new();
+        let memory = config.max_memory.map(MemoryBudget::new);
+        let directional = DirectionalTimeouts {
+            c2s: config.idle_timeout_c2s,
+            s2c: config.idle_timeout_s2c,
+        };
+        let mut tcp_manager = TcpConnectionManager::new(
+            config.tcp_timeout,
+            config.conn_clear_ratio,
+            config.conn_clear_min,
+            config.disable_conn_clear,
+        );
+        tcp_manager.set_directional_timeouts(directional);
+        if let Some(ref memory) = memory {
+            tcp_manager.set_memory_budget(Arc::clone(memory));
+        }
+        let tcp_manager = Arc::new(tcp_manager);
+        let mut udp_manager = UdpSessionManager::new(
+            config.udp_timeout,
+            config.conn_clear_ratio,
+            config.conn_clear_min,
+            config.disable_conn_clear,
+        );
+        udp_manager.set_directional_timeouts(directional);
+        udp_manager.set_port_timeouts(config.udp_timeout_map.clone());
+        udp_manager.set_stats(TrafficStats::scope(config.tenant.as_deref()));
+        if let Some(ref memory) = memory {
+            udp_manager.set_memory_budget(Arc::clone(memory));
+        }
+        let udp_manager = Arc::new(udp_manager);
+
+        let mut event_loop = EventLoop::new(
+            Arc::clone(&config),
+            fd_manager,
+            Arc::clone(&tcp_manager),
+            Arc::clone(&udp_manager),
+        )
+        .map_err(Error::EventLoop)?;
+        event_loop.set_memory_budget(memory);
+        if let Some(ref path) = config.geoip_db {
+            let policy =
+                GeoPolicy::new(&config.geo_allow, &config.geo_deny).map_err(Error::Config)?;
+            let geo = GeoFilter::open(path, config.geoip_asn_db.as_deref(), policy)
+                .map_err(Error::Config)?;
+            event_loop.set_geo_filter(Some(Arc::new(geo)));
+        }
+        if let Some(ref name) = config.tenant {
+            let tenant = Tenant::register(name, tenant_limits).map_err(Error::Config)?;
+            event_loop.set_tenant(Some(tenant));
+        }
+
+        // 命名管道不是 socket，监听由 PipeBridge 负责
+        let listen_addrs = if config.inherit_stdin || uses_named_pipe(&config) {
+            Vec::new()
+        } else {
+            listen_addrs(&config)?
+        };
+        // 平滑升级：有旧进程在运行时接管它的监听 socket，不再重新绑定
+        let mut handover = match config.upgrade_socket {
+            Some(ref path) => {
+                Handover::connect(Path::new(path)).map_err(|source| Error::Connect {
+                    addr: path.clone(),
+                    source,
+                })?
+            }
+            None => None,
+        };
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        let mut multicast_fd = None;
+        for listen_addr in listen_addrs {
+            let tcp_listener = if config.enable_tcp {
+                Some(
+                    match take_over(&mut handover, &listen_addr, SocketKind::Tcp)? {
+                        Some(fd) => unsafe { TcpListener::from_raw_fd(fd) },
+                        None => listen_tcp(&config, &listen_addr)?,
+                    },
+                )
+            } else {
+                None
+            };
+
+            let udp_socket = if config.enable_udp {
+                Some(
+                    match take_over(&mut handover, &listen_addr, SocketKind::Udp)? {
+                        Some(fd) => unsafe { UdpSocket::from_raw_fd(fd) },
+                        None => listen_udp(&config, &listen_addr)?,
+                    },
+                )
+            } else {
+                None
+            };
+
+            #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+            if multicast::is_multicast(&listen_addr) {
+                multicast_fd = udp_socket
+                    .as_ref()
+                    .map(std::os::unix::io::AsRawFd::as_raw_fd);
+            }
+
+            event_loop
+                .register_listen_socket(tcp_listener, udp_socket)
+                .map_err(Error::EventLoop)?;
+        }
+        // 接口删除重建后内核不会恢复成员关系，定期重新加入组播组
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        if let Some(fd) = multicast_fd {
+            let group = config.listen_addr.ip().ip();
+            let interface = config.multicast_interface.clone();
+            event_loop.register_timer(multicast::REJOIN_INTERVAL, move || {
+                match multicast::join(fd, group, interface.as_deref()) {
+                    Ok(true) => info!("[udp] rejoined multicast group {}", group),
+                    Ok(false) => {}
+                    Err(e) => warn!("[udp] failed to rejoin multicast group {}: {}", group, e),
+                }
+            });
+        }
+
+        // TCP 和 UDP 共享后端 (统计和健康状态)，各自轮询
+        let stats = TrafficStats::scope(config.tenant.as_deref());
+        let mut backends = BackendPool::with_policy(
+            &config.remote_addrs,
+            &config.remote_weights,
+            config.lb_policy,
+            stats,
+        )
+        .with_fallbacks(&config.remote_fallbacks);
+        if let Some(ref path) = config.stats_file {
+            if config.reset_stats {
+                info!("[stats] counters reset, not loading {}", path);
+            } else if let Err(e) = stats.load(Path::new(path)) {
+                warn!("[stats] failed to load {}: {}", path, e);
+            }
+        }
+        // systemd 看门狗：事件循环卡住时定时器不再运行，心跳停止后由 systemd 重启服务
+        if let Some(timeout) = crate::systemd::watchdog_timeout() {
+            info!(
+                "[systemd] watchdog enabled, timeout {}ms",
+                timeout.as_millis()
+            );
+            event_loop.register_timer(timeout / 2, || {
+                if let Err(e) = crate::systemd::notify("WATCHDOG=1") {
+                    warn!("[systemd] watchdog notify failed: {}", e);
+                }
+            });
+        }
+        #[cfg(windows)]
+        let pipe_bridge = if uses_named_pipe(&config) {
+            Some(open_pipe_bridge(&config, backends.fork(), stats)?)
+        } else {
+            None
+        };
+        let socks_server = config.socks5_listen.then(|| {
+            Arc::new(Socks5Server::new(
+                config.socks5_auth.clone(),
+                stats.backend("socks5"),
+            ))
+        });
+        let sni_router = config
+            .sni_routes
+            .clone()
+            .map(|routes| Arc::new(SniRouter::new(routes, config.lb_policy, stats)));
+        if let Some(ref url) = config.webhook {
+            let webhook = Webhook::start(url, config.tenant.clone()).map_err(Error::Config)?;
+            event_loop.add_observer(Arc::new(webhook));
+        }
+        if config.on_connect_exec.is_some() || config.on_close_exec.is_some() {
+            let hook = ExecHook::start(
+                config.on_connect_exec.clone(),
+                config.on_close_exec.clone(),
+                config.exec_rate,
+                config.listen_addr.to_string(),
+                config.tenant.clone(),
+            )
+            .map_err(Error::Config)?;
+            event_loop.add_observer(Arc::new(hook));
+        }
+        if let Some(bans) = event_loop.ban_list() {
+            event_loop.register_timer(ban::EXPIRE_INTERVAL, move || {
+                for ip in bans.expire(get_monotonic_time()) {
+                    info!("[ban] {} unbanned", ban::shown_ip(ip));
+                }
+            });
+        }
+        if !config.health_check_interval.is_zero() {
+            let kind = if config.enable_tcp {
+                ProbeKind::Tcp
+            } else {
+                ProbeKind::Udp
+            };
+            let probed = backends
+                .iter()
+                .chain(
+                    sni_router
+                        .iter()
+                        .flat_map(|router| router.pools())
+                        .flat_map(|pool| pool.iter()),
+                )
+                .cloned()
+                .collect();
+            let checker = HealthChecker::new(probed, kind, config.fwd_type, config.nat64_prefix)
+                .with_observers(Arc::clone(&event_loop.observers));
+            event_loop.register_timer(config.health_check_interval, move || checker.run());
+        }
+        if config.upnp {
+            let addr = config.listen_addr.ip();
+            let local = match addr.ip() {
+                IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+                _ => None,
+            };
+            let protocols = [
+                (config.enable_tcp, upnp::Protocol::Tcp),
+                (config.enable_udp, upnp::Protocol::Udp),
+            ]
+            .into_iter()
+            .filter_map(|(enabled, protocol)| enabled.then_some(protocol))
+            .collect();
+            let forwarder = PortForwarder::new(local, addr.port(), protocols);
+            forwarder.run();
+            event_loop.register_timer(upnp::RENEW_INTERVAL, move || forwarder.run());
+        }
+        {
+            let tcp_handler = event_loop.tcp_handler();
+            let mut handler = tcp_handler.write().recover();
+            handler.set_backends(Arc::new(backends.fork()));
+            handler.set_buf_size(config.socket_buf_size);
+            handler.set_fwd_type(config.fwd_type);
+            handler.set_nat64_prefix(config.nat64_prefix);
+            handler.set_bind_interface(config.bind_interface.clone());
+            handler.set_transparent(config.transparent);
+            handler.set_bind_source(config.bind_source.clone());
+            handler.set_source_ports(config.source_ports);
+            handler.set_upstream(upstream.clone());
+            handler.set_sni_router(sni_router);
+            handler.set_expect_protocol(config.expect_protocol);
+            handler.set_socks_server(socks_server.clone());
+            handler.set_keepalive(config.tcp_keepalive);
+            handler.set_nodelay(config.tcp_nodelay);
+            handler.set_quickack(config.tcp_quickack);
+            handler.set_congestion(congestion);
+            handler.set_user_timeout(config.tcp_user_timeout);
+            handler.set_zerocopy(config.tcp_zerocopy);
+            handler.set_buf_autotune(
+                config
+                    .tcp_buf_autotune
+                    .map(|budget| BufAutotune::new(config.socket_buf_size, budget)),
+            );
+            #[cfg(target_os = "linux")]
+            handler.set_sockmap(sockmap);
+            handler.set_linger(config.tcp_linger);
+            handler.set_connect_retries(config.connect_retries);
+            handler.set_circuit_breaker(config.circuit_breaker);
+            handler.set_socket_mark(config.socket_mark);
+            handler.set_mirror(config.mirror.clone());
+            handler.set_obfs(config.decrypt_in.clone(), config.encrypt_out.clone());
+            handler.set_compress(config.decompress_in, config.compress_out);
+            handler.set_middlewares(middlewares(&config));
+        }
+        {
+            let udp_handler = event_loop.udp_handler();
+            let mut handler = udp_handler.write().recover();
+            backends.set_sticky(config.udp_sticky);
+            handler.set_backends(Arc::new(backends));
+            handler.set_buf_size(config.socket_buf_size);
+            handler.set_fwd_type(config.fwd_type);
+            handler.set_nat64_prefix(config.nat64_prefix);
+            handler.set_bind_interface(config.bind_interface.clone());
+            handler.set_transparent(config.transparent);
+            handler.set_bind_source(config.bind_source.clone());
+            handler.set_source_ports(config.source_ports);
+            handler.set_upstream(upstream);
+            handler.set_socks_server(socks_server);
+            handler.set_quic(config.udp_quic);
+            handler.set_socket_mark(config.socket_mark);
+            handler.set_keepalive(config.udp_keepalive.clone());
+            handler.set_recverr(config.udp_recverr);
+            handler.set_preserve_tos_ttl(config.udp_preserve_tos_ttl);
+            handler.set_broadcast(config.udp_broadcast);
+            handler.set_middlewares(middlewares(&config));
+            handler.set_stun(stun);
+            handler.set_multicast_reply(config.multicast_reply.then(|| config.listen_addr.clone()));
+            if let Some(ref addr) = config.mirror {
+                let mirror = UdpMirror::connect(addr.to_sockaddr())
+                    .map_err(|e| Error::socket("failed to create UDP mirror socket", e))?;
+                handler.set_mirror(Some(mirror));
+            }
+        }
+        if let Some(handover) = handover {
+            handover
+                .finish()
+                .map_err(|e| Error::io("failed to confirm takeover", e))?;
+            info!("[upgrade] took over listening sockets, the old instance is draining");
+        }
+        #[cfg(unix)]
+        if let Some(ref path) = config.upgrade_socket {
+            let listener =
+                crate::upgrade::listen(Path::new(path)).map_err(|source| Error::Listen {
+                    addr: path.clone(),
+                    source,
+                })?;
+            event_loop
+                .register_upgrade_listener(listener)
+                .map_err(Error::EventLoop)?;
+        }
+        #[cfg(unix)]
+        if config.inherit_stdin {
+            event_loop
+                .adopt_connection(libc::STDIN_FILENO)
+                .map_err(|e| Error::io("failed to adopt inherited connection on stdin", e))?;
+        }
+
+        Ok(Self {
+            config,
+            event_loop,
+            tcp_manager,
+            udp_manager,
+            #[cfg(windows)]
+            pipe_bridge,
+        })
+    }
+
+    /// 获取配置
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 注册连接事件观察者
+    pub fn add_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.event_loop.add_observer(observer);
+    }
+
+    /// 获取控制句柄，可在其他线程中停止转发和读取统计
+    pub fn handle(&self) -> PortMapperHandle {
+        PortMapperHandle {
+            stop: self.event_loop.stop_handle(),
+            tcp_connections: self.tcp_manager.shared_len(),
+            udp_sessions: self.udp_manager.shared_len(),
+            stats: TrafficStats::scope(self.config.tenant.as_deref()),
+            drain_report: self.event_loop.drain_report(),
+            stun: self.event_loop.udp_handler().read().recover().stun(),
+        }
+    }
+
+    /// 运行事件循环，直到调用 `PortMapperHandle::stop()` 或收到终止信号 (开启 `handle_signals` 时)
+    ///
+    /// 配置了统计状态文件时，退出前保存累计统计
+    pub fn run(&mut self) -> Result<(), Error> {
+        #[cfg(windows)]
+        let pipe_bridge: Option<PipeBridgeHandle> = self
+            .pipe_bridge
+            .take()
+            .map(PipeBridge::spawn)
+            .transpose()
+            .map_err(|e| Error::io("failed to start named pipe bridge", e))?;
+        let result = self.event_loop.run().map_err(Error::EventLoop);
+        #[cfg(windows)]
+        if let Some(bridge) = pipe_bridge {
+            bridge.stop();
+        }
+        // 监听 socket 已交给新进程时，socket 文件由新进程继续使用
+        if !self.event_loop.handed_over() {
+            if let Some(path) = self.config.listen_addr.unix_path() {
+                let _ = std::fs::remove_file(path);
+            }
+            if let Some(ref path) = self.config.upgrade_socket {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        if let Some(ref path) = self.config.stats_file {
+            let stats = TrafficStats::scope(self.config.tenant.as_deref());
+            match stats.save(Path::new(path)) {
+                Ok(()) => info!("[stats] saved to {}", path),
+                Err(e) => warn!("[stats] failed to save {}: {}", path, e),
+            }
+        }
+        result
+    }
+}
+
+/// PortMapper 控制句柄
+#[derive(Debug, Clone)]
+pub struct PortMapperHandle {
+    stop: StopHandle,
+    /// 本实例的连接/会话数 (管理器只在事件循环线程中访问)
+    tcp_connections: Arc<AtomicUsize>,
+    udp_sessions: Arc<AtomicUsize>,
+    stats: &'static TrafficStats,
+    drain_report: Arc<Mutex<Option<DrainReport>>>,
+    stun: Option<Arc<StunClient>>,
+}
+
+impl PortMapperHandle {
+    /// 停止转发
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+
+    /// 读取统计信息
+    ///
+    /// 流量字节数为租户级 (未设置租户时为进程级) 统计，连接/会话数来自本实例
+    pub fn stats(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            tcp_connections: self.tcp_connections.load(Ordering::Relaxed) as u64,
+            udp_sessions: self.udp_sessions.load(Ordering::Relaxed) as u64,
+            ..self.stats.snapshot()
+        }
+    }
+
+    /// 清零累计统计 (租户级或进程级，当前连接/会话数保留)
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// 最近一次排空报告 (尚未开始排空时为 None)
+    pub fn drain_report(&self) -> Option<DrainReport> {
+        self.drain_report.lock().recover().clone()
+    }
+
+    /// STUN 发现的 UDP 监听端口的公网地址 (未启用 `stun` 或尚未得到应答时为 None)
+    pub fn public_endpoint(&self) -> Option<SocketAddr> {
+        self.stun.as_ref().and_then(|stun| stun.endpoint())
+    }
+}
+
+/// 在临时 socket 上设置一次标记，提前发现权限不足 (SO_MARK 需要 CAP_NET_ADMIN) 或平台不支持
+fn check_socket_mark(mark: &SocketMark) -> Result<(), Error> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")
+        .map_err(|e| Error::socket("failed to create socket", e))?;
+    #[cfg(unix)]
+    let fd = std::os::unix::io::AsRawFd::as_raw_fd(&socket);
+    #[cfg(windows)]
+    let fd = std::os::windows::io::AsRawSocket::as_raw_socket(&socket);
+    crate::set_socket_mark(fd, mark).map_err(|e| Error::socket("failed to set socket mark", e))
+}
+
+/// 校验拥塞控制算法是否可用
+#[cfg(target_os = "linux")]
+fn congestion_algo(algo: &str) -> Result<CString, Error> {
+    let name = CString::new(algo).map_err(|e| Error::config(e.to_string()))?;
+    crate::event::tcp::check_congestion(&name)
+        .map_err(|e| Error::socket(format!("congestion control '{}' is not available", algo), e))?;
+    Ok(name)
+}
+
+/// 校验拥塞控制算法是否可用 (非 Linux 平台不支持)
+#[cfg(not(target_os = "linux"))]
+fn congestion_algo(algo: &str) -> Result<CString, Error> {
+    Err(Error::Unsupported(format!(
+        "congestion control '{}' is only supported on Linux",
+        algo
+    )))
+}
+
+/// 校验 Unix 域 socket 和 vsock 地址的使用范围：只支持 TCP，不能与透明代理、双栈监听同时使用，
+/// 后端不是 IP 地址时不能经过上游代理
+fn check_non_ip_addrs(config: &Config) -> Result<(), Error> {
+    let remote_non_ip = config
+        .remote_addrs
+        .iter()
+        .chain(
+            config
+                .sni_routes
+                .iter()
+                .flat_map(|routes| routes.routes())
+                .flat_map(|route| route.remotes.iter().map(|(addr, _)| addr)),
+        )
+        .any(|addr| !addr.is_ip());
+    if config.listen_addr.is_ip() && !remote_non_ip {
+        return Ok(());
+    }
+    let conflict = if config.enable_udp {
+        "UDP"
+    } else if config.transparent {
+        "transparent proxy"
+    } else if config.dual_stack {
+        "dual-stack"
+    } else if remote_non_ip && config.upstream.is_some() {
+        "upstream proxy"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "Unix socket, vsock and named pipe addresses are not supported with {}",
+        conflict
+    )))
+}
+
+/// SOCKS5 服务端自己读取客户端开头的握手数据，不能再窥探或解密；UDP ASSOCIATE 的会话不经上游代理
+fn check_socks5_server(config: &Config) -> Result<(), Error> {
+    if !config.socks5_listen {
+        return Ok(());
+    }
+    let conflict = if !config.listen_addr.is_ip() {
+        "a non-IP listen address"
+    } else if !config.enable_tcp {
+        "UDP only"
+    } else if config.sni_routes.is_some() {
+        "sni-routes"
+    } else if config.expect_protocol != ExpectProtocol::None {
+        "expect-protocol"
+    } else if config.decrypt_in.is_some() {
+        "decrypt-in"
+    } else if config.decompress_in {
+        "decompress-in"
+    } else if config.enable_udp && config.upstream.is_some() {
+        "UDP through an upstream proxy"
+    } else if config.enable_udp && config.udp_quic {
+        "udp-quic"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "socks5-listen is not supported with {}",
+        conflict
+    )))
+}
+
+/// 监听地址或任一后端是否为 Windows 命名管道
+fn uses_named_pipe(config: &Config) -> bool {
+    config.listen_addr.named_pipe().is_some()
+        || config
+            .remote_addrs
+            .iter()
+            .any(|addr| addr.named_pipe().is_some())
+}
+
+/// 命名管道由 `PipeBridge` 直接转发，不经过 `TcpHandler`，不支持 SNI 路由、协议嗅探、流混淆和上游代理
+fn check_named_pipes(config: &Config) -> Result<(), Error> {
+    if !uses_named_pipe(config) {
+        return Ok(());
+    }
+    let conflict = if config.sni_routes.is_some() {
+        "sni-routes"
+    } else if config.expect_protocol != ExpectProtocol::None {
+        "expect-protocol"
+    } else if config.decrypt_in.is_some() || config.encrypt_out.is_some() {
+        "stream obfuscation"
+    } else if config.decompress_in || config.compress_out {
+        "stream compression"
+    } else if config.upstream.is_some() {
+        "upstream proxy"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "named pipe addresses are not supported with {}",
+        conflict
+    )))
+}
+
+/// 创建命名管道转发器：监听地址为命名管道时创建第一个管道实例，否则创建 TCP 监听 socket
+#[cfg(windows)]
+fn open_pipe_bridge(
+    config: &Config,
+    backends: BackendPool,
+    stats: &'static TrafficStats,
+) -> Result<PipeBridge, Error> {
+    let (buf_size, max_connections) = (config.socket_buf_size, config.max_connections);
+    let bridge = match config.listen_addr.named_pipe() {
+        Some(name) => PipeBridge::listen_pipe(name, backends, stats, buf_size, max_connections),
+        None => {
+            let listener = listen_tcp(config, &config.listen_addr)?;
+            PipeBridge::listen_tcp(listener, backends, stats, buf_size, max_connections)
+        }
+    };
+    bridge.map_err(|source| Error::Listen {
+        addr: config.listen_addr.to_string(),
+        source,
+    })
+}
+
+/// 广播只有 IPv4，会话 socket 不连接也不经过上游代理
+fn check_broadcast(config: &Config) -> Result<(), Error> {
+    if !config.udp_broadcast {
+        return Ok(());
+    }
+    if !matches!(config.listen_addr.ip(), std::net::SocketAddr::V4(_)) {
+        return Err(Error::config("broadcast requires an IPv4 listen address"));
+    }
+    let conflict = if config.socks5_listen {
+        "socks5-listen"
+    } else if config.upstream.is_some() {
+        "upstream"
+    } else if config.transparent {
+        "transparent proxy"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "broadcast cannot be combined with {}",
+        conflict
+    )))
+}
+
+/// 应用层网关的会话直接与远程往来，SOCKS5 中继和广播中继的会话 socket 由各自的模式管理
+fn check_udp_algs(config: &Config) -> Result<(), Error> {
+    if config.udp_algs.is_empty() {
+        return Ok(());
+    }
+    let conflict = if config.socks5_listen {
+        "socks5-listen"
+    } else if config.upstream.is_some() {
+        "upstream"
+    } else if config.udp_broadcast {
+        "broadcast"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "udp-alg cannot be combined with {}",
+        conflict
+    )))
+}
+
+/// FTP 网关改写明文控制连接，数据通道按 IP 地址监听和连接，直连服务端
+fn check_ftp_alg(config: &Config) -> Result<(), Error> {
+    if !config.ftp_alg {
+        return Ok(());
+    }
+    let conflict = if !config.enable_tcp {
+        "UDP only"
+    } else if !config.listen_addr.is_ip() || !config.remote_addrs.iter().all(Address::is_ip) {
+        "non-IP addresses"
+    } else if config.decrypt_in.is_some() || config.encrypt_out.is_some() {
+        "stream obfuscation"
+    } else if config.decompress_in || config.compress_out {
+        "stream compression"
+    } else if config.tcp_sockmap {
+        "sockmap"
+    } else if config.socks5_listen {
+        "socks5-listen"
+    } else if config.upstream.is_some() {
+        "upstream"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "ftp-alg cannot be combined with {}",
+        conflict
+    )))
+}
+
+/// 库用户的中间件可能处理 TCP 和 UDP，不能与改写数据或接管 socket 的模式同时使用
+fn check_middlewares(config: &Config) -> Result<(), Error> {
+    if config.middlewares.is_empty() {
+        return Ok(());
+    }
+    let conflict = if config.decrypt_in.is_some() || config.encrypt_out.is_some() {
+        "stream obfuscation"
+    } else if config.decompress_in || config.compress_out {
+        "stream compression"
+    } else if config.tcp_sockmap {
+        "sockmap"
+    } else if config.socks5_listen {
+        "socks5-listen"
+    } else if config.upstream.is_some() {
+        "upstream"
+    } else if config.udp_broadcast {
+        "broadcast"
+    } else {
+        return Ok(());
+    };
+    Err(Error::config(format!(
+        "middlewares cannot be combined with {}",
+        conflict
+    )))
+}
+
+/// 路由器端口映射只有 IPv4，外部端口与监听端口相同
+fn check_upnp(config: &Config) -> Result<(), Error> {
+    if !config.upnp {
+        return Ok(());
+    }
+    match config.listen_addr.ip() {
+        std::net::SocketAddr::V4(addr) if config.listen_addr.is_ip() && addr.port() != 0 => Ok(()),
+        _ => Err(Error::config(
+            "upnp requires an IPv4 listen address with a fixed port",
+        )),
+    }
+}
+
+/// STUN 请求从 UDP 监听 socket 发出，双栈监听时使用 IPv4 服务端
+fn stun_client(config: &Config, server: &str) -> Result<StunClient, Error> {
+    if !config.enable_udp || !config.listen_addr.is_ip() {
+        return Err(Error::config("stun requires a UDP listen address"));
+    }
+    let ipv6 = config.listen_addr.ip().is_ipv6() && !config.dual_stack;
+    let server = stun::resolve_server(server, ipv6).map_err(Error::Config)?;
+    Ok(StunClient::new(server))
+}
+
+/// 映射使用的中间件：库用户挂载的在前，之后是命令行启用的应用层网关
+fn middlewares(config: &Config) -> Vec<Arc<dyn Middleware>> {
+    let mut middlewares = config.middlewares.clone();
+    if config.ftp_alg {
+        middlewares.push(Arc::new(Ftp));
+    }
+    if !config.udp_algs.is_empty() {
+        middlewares.push(Arc::new(config.udp_algs.clone()));
+    }
+    middlewares
+}
+
+/// 检查外连源地址和端口：每个地址族最多一个地址，且透明代理已经使用客户端 IP 作为源地址
+fn check_bind_source(config: &Config) -> Result<(), Error> {
+    if config.transparent {
+        let conflict = if !config.bind_source.is_empty() {
+            "bind-source"
+        } else if config.source_ports.is_some() {
+            "source-ports"
+        } else {
+            return Ok(());
+        };
+        return Err(Error::config(format!(
+            "{} cannot be combined with transparent proxy",
+            conflict
+        )));
+    }
+    let v6 = config.bind_source.iter().filter(|ip| ip.is_ipv6()).count();
+    if v6 > 1 || config.bind_source.len() - v6 > 1 {
+        return Err(Error::config(
+            "bind-source accepts at most one IPv4 and one IPv6 address",
+        ));
+    }
+    Ok(())
+}
+
+/// 删除上次运行遗留的 Unix 域 socket 文件
+///
+/// 仍有进程在该路径上监听，或路径不是 socket 时返回错误
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::io::{self, ErrorKind};
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 需要监听的地址，启用双栈时未指定地址拆分为 IPv4 和 IPv6 两个
+fn listen_addrs(config: &Config) -> Result<Vec<Address>, Error> {
+    if !config.dual_stack {
+        return Ok(vec![config.listen_addr.clone()]);
+    }
+    if config.v6only == Some(false) {
+        return Err(Error::config(
+            "dual-stack cannot be combined with v6only disabled",
+        ));
+    }
+    let addr = config.listen_addr.ip();
+    if !addr.ip().is_unspecified() {
+        return Err(Error::config(
+            "dual-stack requires listening on 0.0.0.0 or [::]",
+        ));
+    }
+    Ok(vec![
+        Address::from_ipv4(Ipv4Addr::UNSPECIFIED, addr.port()),
+        Address::from_ipv6(Ipv6Addr::UNSPECIFIED, addr.port()),
+    ])
+}
+
+/// 从旧进程接管监听 socket，没有进行升级时返回 None
+fn take_over(
+    handover: &mut Option<Handover>,
+    listen_addr: &Address,
+    kind: SocketKind,
+) -> Result<Option<crate::PlatformRawFd>, Error> {
+    let Some(handover) = handover else {
+        return Ok(None);
+    };
+    let proto_name = match kind {
+        SocketKind::Tcp => "TCP",
+        SocketKind::Udp => "UDP",
+    };
+    let fd = handover.take(kind, listen_addr).ok_or_else(|| {
+        Error::config(format!(
+            "running instance has no {} socket listening on {}",
+            proto_name, listen_addr
+        ))
+    })?;
+    info!("{} listening on {} (taken over)", proto_name, listen_addr);
+    Ok(Some(fd.into_raw_fd()))
+}
+
+/// 监听 socket 的 IPV6_V6ONLY：双栈时 IPv6 socket 只接受 IPv6 客户端，IPv4 客户端由单独的 IPv4 socket 接受；
+/// 否则按 --v6only/--no-v6only 设置，都未指定时保持系统默认
+fn listen_v6only(config: &Config) -> Option<bool> {
+    if config.dual_stack {
+        Some(true)
+    } else {
+        config.v6only
+    }
+}
+
+/// 按配置创建 TCP 监听 socket
+fn listen_tcp(config: &Config, listen_addr: &Address) -> Result<TcpListener, Error> {
+    #[cfg(unix)]
+    if let Some(path) = listen_addr.unix_path() {
+        remove_stale_socket(path)
+            .map_err(|e| Error::io("failed to remove stale Unix socket", e))?;
+    }
+    let listener = TcpListenerBuilder::new(listen_addr)
+        .reuse_port(config.reuseport)
+        .buf_size(config.socket_buf_size)
+        .v6only(listen_v6only(config))
+        .interface(config.bind_interface.as_deref())
+        .mark(config.mark_inbound.then_some(&config.socket_mark))
+        .transparent(config.transparent)
+        .backlog(config.listen_backlog)
+        .build()?;
+    info!("TCP listening on {}", listen_addr);
+    Ok(listener)
+}
+
+/// 按配置创建 UDP 监听 socket
+fn listen_udp(config: &Config, listen_addr: &Address) -> Result<UdpSocket, Error> {
+    let socket = UdpSocketBuilder::new()
+        .reuse_port(config.reuseport)
+        .buf_size(config.socket_buf_size)
+        .v6only(listen_v6only(config))
+        .interface(config.bind_interface.as_deref())
+        .mark(config.mark_inbound.then_some(&config.socket_mark))
+        .transparent(config.transparent)
+        .bind(listen_addr)?;
+    // -d: 识别分片到达的数据包
+    #[cfg(target_os = "linux")]
+    if config.enable_udp_fragment {
+        if let Err(e) =
+            fragment::enable_recv_fragsize(std::os::unix::io::AsRawFd::as_raw_fd(&socket))
+        {
+            warn!(
+                "[udp] failed to enable IP_RECVFRAGSIZE on {}: {}",
+                listen_addr, e
+            );
+        }
+    }
+    if config.udp_broadcast {
+        #[cfg(unix)]
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&socket);
+        #[cfg(windows)]
+        let fd = std::os::windows::io::AsRawSocket::as_raw_socket(&socket);
+        crate::set_broadcast(fd).map_err(|e| Error::socket("failed to set SO_BROADCAST", e))?;
+    }
+    // 组播地址：加入组，--multicast-reply 时回包发往组地址
+    #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+    if multicast::is_multicast(listen_addr) {
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&socket);
+        let group = listen_addr.ip().ip();
+        let interface = config.multicast_interface.as_deref();
+        multicast::join(fd, group, interface)
+            .map_err(|e| Error::socket("failed to join multicast group", e))?;
+        if config.multicast_reply {
+            multicast::set_reply(fd, group, interface)
+                .map_err(|e| Error::socket("failed to set multicast reply interface", e))?;
+        }
+        info!(
+            "[udp] joined multicast group {} on {}",
+            group,
+            interface.unwrap_or("the default interface")
+        );
+    }
+    // --udp-preserve-tos-ttl: 读取客户端数据包的 TOS 和 TTL
+    #[cfg(target_os = "linux")]
+    if config.udp_preserve_tos_ttl {
+        if let Err(e) =
+            ipheader::enable_recv_tos_ttl(std::os::unix::io::AsRawFd::as_raw_fd(&socket))
+        {
+            warn!(
+                "[udp] failed to enable IP_RECVTOS on {}: {}",
+                listen_addr, e
+            );
+        }
+    }
+    info!("UDP listening on {}", listen_addr);
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    /// 在新线程中创建并运行 PortMapper (single-thread 特性下 PortMapper 不能移到其他线程)
+    fn spawn_mapper(builder: PortMapperBuilder) -> (PortMapperHandle, std::thread::JoinHandle<()>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let runner = std::thread::spawn(move || {
+            let mut mapper = builder.build().expect("build mapper");
+            tx.send(mapper.handle()).expect("send handle");
+            mapper.run().expect("run mapper");
+        });
+        (rx.recv().expect("mapper handle"), runner)
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let err = PortMapper::builder()
+            .remote("127.0.0.1:80")
+            .tcp(true)
+            .config();
+        assert_eq!(err.map(|_| ()).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let err = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("not-an-address")
+            .tcp(true)
+            .config();
+        assert!(matches!(err, Err(Error::Address { kind: "remote", .. })));
+
+        let err = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:80")
+            .config();
+        assert!(err.is_err());
+
+        let config = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:80")
+            .udp(true)
+            .max_connections(10)
+            .config()
+            .expect("valid config");
+        assert!(!config.enable_tcp);
+        assert!(config.enable_udp);
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.socket_buf_size, DEFAULT_SOCKET_BUF_SIZE);
+
+        let config = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:80")
+            .remote("127.0.0.1:81")
+            .tcp(true)
+            .config()
+            .expect("valid config");
+        assert_eq!(config.remote_addrs.len(), 2);
+
+        // SOCKS5 服务端不需要后端地址，总是转发 TCP
+        let config = PortMapper::builder()
+            .socks5_listen("127.0.0.1:0")
+            .socks5_auth("user:pass")
+            .config()
+            .expect("valid config");
+        assert!(config.enable_tcp && config.socks5_listen);
+        assert_eq!(
+            config.socks5_auth,
+            Some(("user".to_string(), "pass".to_string()))
+        );
+        assert!(PortMapper::builder()
+            .socks5_listen("127.0.0.1:0")
+            .remote("127.0.0.1:80")
+            .config()
+            .is_err());
+        assert!(PortMapper::builder()
+            .socks5_listen("127.0.0.1:0")
+            .socks5_auth("user")
+            .config()
+            .is_err());
+    }
+
+    /// 绑定后立即释放，得到一个当前空闲的本地地址
+    fn free_addr() -> std::net::SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("probe addr")
+    }
+
+    #[test]
+    fn test_tcp_forwards_all_data_and_eof() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        const LEN: usize = 1024 * 1024;
+
+        // 回显后端：回显 LEN 字节后关闭连接
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().expect("accept");
+            let mut buf = [0u8; 16384];
+            let mut left = LEN;
+            while left > 0 {
+                match stream.read(&mut buf[..left.min(16384)]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        stream.write_all(&buf[..n]).expect("echo");
+                        left -= n;
+                    }
+                }
+            }
+        });
+
+        let listen_addr = free_addr();
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true),
+        );
+
+        let client = TcpStream::connect(listen_addr).expect("connect");
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .expect("read timeout");
+        let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let writer = {
+            let mut client = client.try_clone().expect("clone");
+            let data = data.clone();
+            std::thread::spawn(move || client.write_all(&data).expect("write"))
+        };
+
+        // 数据完整回显，且后端关闭后客户端读到 EOF
+        let mut echoed = Vec::new();
+        (&client).read_to_end(&mut echoed).expect("read until eof");
+        writer.join().expect("join writer");
+        assert_eq!(echoed.len(), data.len());
+        assert!(echoed == data);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_swept_tcp_connection_uncounted() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+
+        // 后端接受连接后保持空闲
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().expect("accept");
+            let _ = stream.read(&mut [0u8; 1]);
+        });
+
+        let listen_addr = free_addr();
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .tcp_timeout(Duration::from_millis(200))
+                .tenant("mapper-tcp-sweep"),
+        );
+
+        let stats = TrafficStats::tenant("mapper-tcp-sweep");
+        let wait_for = |expected: u64| {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while stats.snapshot().tcp_connections != expected
+                && std::time::Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            stats.snapshot().tcp_connections
+        };
+
+        // 空闲连接被超时清理后，连接计数回到 0
+        let _client = TcpStream::connect(listen_addr).expect("connect");
+        assert_eq!(wait_for(1), 1);
+        assert_eq!(wait_for(0), 0);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_sweep_keeps_busy_and_closes_idle() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        // 回显后端，每个连接一个线程
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        let _ = stream.write_all(&buf[..n]);
+                    }
+                });
+            }
+        });
+
+        let listen_addr = free_addr();
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .tcp_timeout(Duration::from_millis(300)),
+        );
+
+        let connect = || {
+            let stream = TcpStream::connect(listen_addr).expect("connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("read timeout");
+            stream
+        };
+        let mut busy = connect();
+        let mut idle = connect();
+
+        // 持续转发数据的连接超过超时时间后仍然可用
+        for _ in 0..25 {
+            let mut buf = [0u8; 4];
+            busy.write_all(b"ping").expect("write busy");
+            busy.read_exact(&mut buf).expect("busy connection swept");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        // 空闲连接被清理时关闭 socket，客户端读到 EOF
+        assert_eq!(idle.read(&mut [0u8; 1]).expect("read idle"), 0);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_swept_udp_session_uncounted() {
+        use std::net::UdpSocket;
+
+        let backend = UdpSocket::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+
+        let listen_addr = free_addr();
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .udp(true)
+                .udp_timeout(Duration::from_millis(200))
+                .tenant("mapper-udp-sweep"),
+        );
+
+        let stats = TrafficStats::tenant("mapper-udp-sweep");
+        let wait_for = |expected: u64| {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while stats.snapshot().udp_sessions != expected && std::time::Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            stats.snapshot().udp_sessions
+        };
+
+        // 空闲会话被超时清理后，会话计数回到 0
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client.send_to(b"ping", listen_addr).expect("send");
+        assert_eq!(wait_for(1), 1);
+        assert_eq!(wait_for(0), 0);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_dual_stack_listen_addrs() {
+        let builder = PortMapper::builder()
+            .listen("[::]:1234")
+            .remote("127.0.0.1:80")
+            .tcp(true);
+        let config = builder.clone().config().expect("valid config");
+        assert_eq!(listen_addrs(&config).expect("addrs").len(), 1);
+
+        let config = builder
+            .clone()
+            .dual_stack(true)
+            .config()
+            .expect("valid config");
+        let addrs: Vec<String> = listen_addrs(&config)
+            .expect("addrs")
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(addrs, vec!["0.0.0.0:1234", "[::]:1234"]);
+
+        let config = PortMapper::builder()
+            .listen("127.0.0.1:1234")
+            .remote("127.0.0.1:80")
+            .tcp(true)
+            .dual_stack(true)
+            .config()
+            .expect("valid config");
+        assert!(listen_addrs(&config).is_err());
+
+        let builder = builder.dual_stack(true);
+        let config = builder.clone().v6only(true).config().expect("valid config");
+        assert_eq!(listen_addrs(&config).expect("addrs").len(), 2);
+        let config = builder.v6only(false).config().expect("valid config");
+        assert!(listen_addrs(&config).is_err());
+    }
+
+    #[test]
+    fn test_upnp_validation() {
+        let config = |listen: &str| {
+            PortMapper::builder()
+                .listen(listen)
+                .remote("127.0.0.1:80")
+                .tcp(true)
+                .upnp(true)
+                .config()
+                .expect("valid config")
+        };
+        assert!(check_upnp(&config("0.0.0.0:1234")).is_ok());
+        assert!(check_upnp(&config("192.168.1.2:1234")).is_ok());
+        assert!(check_upnp(&config("[::]:1234")).is_err());
+        assert!(check_upnp(&config("0.0.0.0:0")).is_err());
+    }
+
+    #[test]
+    fn test_bind_source_validation() {
+        let builder = PortMapper::builder()
+            .listen("127.0.0.1:1234")
+            .remote("127.0.0.1:80")
+            .tcp(true)
+            .bind_source("10.0.0.2".parse().expect("ip"))
+            .bind_source("2001:db8::2".parse().expect("ip"));
+        let config = builder.clone().config().expect("valid config");
+        assert!(check_bind_source(&config).is_ok());
+
+        let config = builder
+            .clone()
+            .bind_source("10.0.0.3".parse().expect("ip"))
+            .config()
+            .expect("valid config");
+        assert!(check_bind_source(&config).is_err());
+        let config = builder.transparent(true).config().expect("valid config");
+        assert!(check_bind_source(&config).is_err());
+    }
+
+    #[test]
+    fn test_inherit_stdin_config() {
+        let builder = PortMapper::builder()
+            .remote("127.0.0.1:80")
+            .tcp(true)
+            .inherit_stdin(true);
+        let config = builder.clone().config().expect("valid config");
+        assert!(config.inherit_stdin);
+        assert_eq!(config.listen_addr.to_string(), "0.0.0.0:0");
+
+        let err = builder.udp(true).build().map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_listen_socket_reuseport() {
+        use std::os::unix::io::AsRawFd;
+
+        let reuseport = |fd: libc::c_int| {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_REUSEPORT,
+                    &mut value as *mut _ as *mut libc::c_void,
+                    &mut len,
+                );
+            }
+            value
+        };
+        let builder = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:80")
+            .tcp(true)
+            .listen_backlog(16);
+        let config = builder.clone().config().expect("valid config");
+        assert_eq!(config.listen_backlog, 16);
+        let listener = listen_tcp(&config, &config.listen_addr).expect("listen socket");
+        assert_eq!(reuseport(listener.as_raw_fd()), 1);
+
+        let config = builder.reuseport(false).config().expect("valid config");
+        let listener = listen_tcp(&config, &config.listen_addr).expect("listen socket");
+        assert_eq!(reuseport(listener.as_raw_fd()), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listen_socket_v6only() {
+        use std::os::unix::io::AsRawFd;
+
+        let v6only = |fd: libc::c_int| {
+            let mut value: libc::c_int = -1;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_V6ONLY,
+                    &mut value as *mut _ as *mut libc::c_void,
+                    &mut len,
+                );
+            }
+            value
+        };
+        let builder = PortMapper::builder()
+            .listen("[::]:0")
+            .remote("127.0.0.1:80")
+            .tcp(true);
+        // Linux 绑定具体的 IPv6 地址时总会设置 IPV6_V6ONLY，只能用通配地址验证
+        for enable in [true, false] {
+            let config = builder
+                .clone()
+                .v6only(enable)
+                .config()
+                .expect("valid config");
+            let Ok(listener) = listen_tcp(&config, &config.listen_addr) else {
+                // 没有 IPv6 的环境
+                return;
+            };
+            assert_eq!(v6only(listener.as_raw_fd()), libc::c_int::from(enable));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_ip_addrs() {
+        let builder = PortMapper::builder()
+            .listen("unix:/run/app.sock")
+            .remote("127.0.0.1:80")
+            .tcp(true);
+        let config = builder.clone().config().expect("valid config");
+        assert!(check_non_ip_addrs(&config).is_ok());
+
+        let config = builder.clone().udp(true).config().expect("valid config");
+        let err = check_non_ip_addrs(&config).unwrap_err();
+        assert!(err.to_string().contains("UDP"));
+
+        let config = builder.dual_stack(true).config().expect("valid config");
+        assert!(check_non_ip_addrs(&config).is_err());
+
+        let builder = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("unix:/var/run/backend.sock")
+            .tcp(true);
+        let config = builder.clone().config().expect("valid config");
+        assert!(check_non_ip_addrs(&config).is_ok());
+        let config = builder
+            .upstream("socks5://127.0.0.1:1080")
+            .config()
+            .expect("valid config");
+        let err = check_non_ip_addrs(&config).unwrap_err();
+        assert!(err.to_string().contains("upstream"));
+
+        #[cfg(target_os = "linux")]
+        {
+            let builder = PortMapper::builder()
+                .listen("vsock://any:5000")
+                .remote("127.0.0.1:80")
+                .tcp(true);
+            let config = builder.clone().config().expect("valid config");
+            assert!(check_non_ip_addrs(&config).is_ok());
+            let config = builder.transparent(true).config().expect("valid config");
+            assert!(check_non_ip_addrs(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_run_and_stop() {
+        let mut mapper = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:9")
+            .tcp(true)
+            .udp(true)
+            .build()
+            .expect("build mapper");
+        let handle = mapper.handle();
+        assert_eq!(handle.stats().tcp_connections, 0);
+
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            handle.stop();
+        });
+        mapper.run().expect("run mapper");
+        stopper.join().expect("join stopper");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signals_not_handled_by_default() {
+        let blocked = |handle_signals| {
+            let mapper = PortMapper::builder()
+                .listen("127.0.0.1:0")
+                .remote("127.0.0.1:9")
+                .tcp(true)
+                .handle_signals(handle_signals)
+                .build()
+                .expect("build mapper");
+            let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+            unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) };
+            drop(mapper);
+            unsafe { libc::sigismember(&mask, libc::SIGTERM) == 1 }
+        };
+        // 嵌入时不修改调用线程的信号屏蔽字
+        let thread = std::thread::spawn(move || blocked(false));
+        assert!(!thread.join().expect("join"));
+        let thread = std::thread::spawn(move || blocked(true));
+        assert!(thread.join().expect("join"));
+    }
+
+    #[test]
+    fn test_tenant_limits_validation() {
+        let builder = || {
+            PortMapper::builder()
+                .listen("127.0.0.1:0")
+                .remote("127.0.0.1:9")
+                .tcp(true)
+        };
+        let invalid = |result: Result<PortMapper, Error>| matches!(result, Err(Error::Config(_)));
+        assert!(invalid(builder().tenant_max_connections(10).build()));
+        assert!(invalid(
+            builder()
+                .tenant("mapper-tenant-validation")
+                .tenant_allow(&["10.0.0.1/8"])
+                .build()
+        ));
+        assert!(invalid(
+            builder()
+                .tenant("mapper-tenant-validation")
+                .tenant_max_connections(0)
+                .build()
+        ));
+
+        // 同一租户的实例不能设置不同的限制
+        let _first = builder()
+            .tenant("mapper-tenant-validation")
+            .tenant_max_connections(10)
+            .build()
+            .expect("first");
+        assert!(invalid(
+            builder()
+                .tenant("mapper-tenant-validation")
+                .tenant_max_connections(20)
+                .build()
+        ));
+        builder()
+            .tenant("mapper-tenant-validation")
+            .build()
+            .expect("join without limits");
+    }
+
+    #[test]
+    fn test_tenant_limits_enforced() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream, UdpSocket};
+
+        // 回显后端，每个连接一个线程
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr").to_string();
+        std::thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        let _ = stream.write_all(&buf[..n]);
+                    }
+                });
+            }
+        });
+        let start = |builder: PortMapperBuilder| {
+            let listen_addr = free_addr();
+            let (handle, _) = spawn_mapper(
+                builder
+                    .listen(&listen_addr.to_string())
+                    .remote(&backend_addr),
+            );
+            (listen_addr, handle)
+        };
+        let ping = |addr| {
+            let mut stream = TcpStream::connect(addr).expect("connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("read timeout");
+            let mut buf = [0u8; 4];
+            let ok = stream.write_all(b"ping").is_ok() && stream.read_exact(&mut buf).is_ok();
+            (stream, ok)
+        };
+
+        // 两个实例属于同一租户，共享连接数上限
+        let tenant = || {
+            PortMapper::builder()
+                .tcp(true)
+                .tenant("mapper-tenant-enforced")
+                .tenant_max_connections(1)
+        };
+        let (first, first_handle) = start(tenant());
+        let (second, second_handle) = start(tenant());
+        let (open, ok) = ping(first);
+        assert!(ok);
+        assert!(!ping(second).1);
+
+        // 释放名额后另一个实例可以建立连接
+        drop(open);
+        let stats = TrafficStats::tenant("mapper-tenant-enforced");
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while stats.snapshot().tcp_connections > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(ping(second).1);
+
+        // 租户 ACL 拒绝回环地址的客户端
+        let (denied, denied_handle) = start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .tenant("mapper-tenant-acl")
+                .tenant_deny(&["127.0.0.0/8"]),
+        );
+        assert!(!ping(denied).1);
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .expect("read timeout");
+        client.send_to(b"ping", denied).expect("send");
+        assert!(client.recv(&mut [0u8; 4]).is_err());
+        assert_eq!(denied_handle.stats().tcp_connections, 0);
+        assert_eq!(denied_handle.stats().udp_sessions, 0);
+
+        for handle in [first_handle, second_handle, denied_handle] {
+            handle.stop();
+        }
+    }
+
+    #[test]
+    fn test_forward_tcp_and_udp() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream, UdpSocket};
+
+        // 后端：TCP 和 UDP 回显，使用同一端口
+        let tcp_backend = TcpListener::bind("127.0.0.1:0").expect("bind tcp backend");
+        let backend_addr = tcp_backend.local_addr().expect("backend addr");
+        let udp_backend = UdpSocket::bind(backend_addr).expect("bind udp backend");
+        std::thread::spawn(move || {
+            let (mut stream, _) = tcp_backend.accept().expect("accept");
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).expect("read");
+            stream.write_all(&buf[..n]).expect("write");
+        });
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let (n, peer) = udp_backend.recv_from(&mut buf).expect("recv");
+            udp_backend.send_to(&buf[..n], peer).expect("send");
+        });
+
+        // 找一个 TCP 和 UDP 都空闲的端口
+        let listen_addr = loop {
+            let probe = TcpListener::bind("127.0.0.1:0").expect("bind probe");
+            let addr = probe.local_addr().expect("probe addr");
+            if UdpSocket::bind(addr).is_ok() {
+                break addr;
+            }
+        };
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .udp(true),
+        );
+
+        let timeout = Some(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(listen_addr).expect("connect");
+        stream.set_read_timeout(timeout).expect("set timeout");
+        stream.write_all(b"tcp ping").expect("write");
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).expect("read");
+        assert_eq!(&buf[..n], b"tcp ping");
+
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client.set_read_timeout(timeout).expect("set timeout");
+        client.send_to(b"udp ping", listen_addr).expect("send");
+        let (n, from) = client.recv_from(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], b"udp ping");
+        assert_eq!(from, listen_addr);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[test]
+    fn test_forward_level_triggered() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        // 后端晚读取，数据积压在代理的接收队列中，客户端写完后不再有新数据到达，
+        // 剩余数据只能靠重新注册后的事件读完
+        const LEN: usize = 8 << 20;
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().expect("accept");
+            std::thread::sleep(Duration::from_millis(300));
+            let mut data = vec![0u8; LEN];
+            stream.read_exact(&mut data).expect("read");
+            let sum: u64 = data.iter().map(|&b| b as u64).sum();
+            stream.write_all(&sum.to_be_bytes()).expect("write");
+        });
+
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|probe| probe.local_addr())
+            .expect("probe addr");
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .socket_buf_size(64 * 1024)
+                .poll_mode(PollMode::Level),
+        );
+
+        let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+        let mut stream = TcpStream::connect(listen_addr).expect("connect");
+        let timeout = Some(Duration::from_secs(5));
+        stream.set_read_timeout(timeout).expect("set timeout");
+        stream.set_write_timeout(timeout).expect("set timeout");
+        stream.write_all(&data).expect("write");
+        let mut sum = [0u8; 8];
+        stream.read_exact(&mut sum).expect("read");
+        let expected: u64 = data.iter().map(|&b| b as u64).sum();
+        assert_eq!(u64::from_be_bytes(sum), expected);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_upgrade_drains_without_timeout() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            for stream in backend.incoming() {
+                let Ok(mut stream) = stream else { break };
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 64];
+                    while let Ok(n) = stream.read(&mut buf) {
+                        if n == 0 || stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|probe| probe.local_addr())
+            .expect("probe addr");
+        let dir = tempfile::tempdir().expect("tempdir");
+        let control = dir.path().join("upgrade.sock");
+        let builder = || {
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .upgrade_socket(control.to_str().unwrap())
+        };
+        let echo = |stream: &mut TcpStream, data: &[u8]| {
+            stream.write_all(data).expect("write");
+            let mut buf = vec![0u8; data.len()];
+            stream.read_exact(&mut buf).expect("read");
+            assert_eq!(buf, data);
+        };
+
+        let (_old, old_runner) = spawn_mapper(builder());
+        let mut stream = TcpStream::connect(listen_addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        echo(&mut stream, b"before");
+
+        // 未设置 --drain-timeout：旧进程交接后继续转发已有连接，直到连接关闭才退出
+        let (new, new_runner) = spawn_mapper(builder());
+        std::thread::sleep(Duration::from_millis(200));
+        echo(&mut stream, b"after handover");
+        assert!(!old_runner.is_finished());
+        let mut fresh = TcpStream::connect(listen_addr).expect("connect new");
+        fresh
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        echo(&mut fresh, b"new instance");
+
+        drop(stream);
+        old_runner.join().expect("join old");
+        new.stop();
+        new_runner.join().expect("join new");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_upgrade_serves_until_ack() {
+        use crate::upgrade::Handover;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            for stream in backend.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut buf = [0u8; 64];
+                let n = stream.read(&mut buf).expect("read");
+                stream.write_all(&buf[..n]).expect("write");
+            }
+        });
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|probe| probe.local_addr())
+            .expect("probe addr");
+        let dir = tempfile::tempdir().expect("tempdir");
+        let control = dir.path().join("upgrade.sock");
+        let (_old, old_runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote(&backend_addr.to_string())
+                .tcp(true)
+                .upgrade_socket(control.to_str().unwrap()),
+        );
+
+        // 新进程收到监听 socket 后还在启动，尚未确认：旧进程继续接受和转发连接
+        let handover = Handover::connect(&control)
+            .expect("connect control socket")
+            .expect("old instance");
+        let mut stream = TcpStream::connect(listen_addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .expect("set timeout");
+        stream.write_all(b"during handover").expect("write");
+        let mut buf = [0u8; 15];
+        stream.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf, b"during handover");
+        drop(stream);
+
+        // 确认后旧进程停止接受连接，没有连接需要排空时退出
+        handover.finish().expect("ack");
+        old_runner.join().expect("join old");
+    }
+
+    #[test]
+    fn test_geoip_validation() {
+        let builder = || {
+            PortMapper::builder()
+                .listen("127.0.0.1:0")
+                .remote("127.0.0.1:9")
+                .tcp(true)
+        };
+        assert!(matches!(
+            builder().geo_allow(&["CN"]).build(),
+            Err(Error::Config(_))
+        ));
+        let err = builder()
+            .geoip_db("/nonexistent/GeoLite2-Country.mmdb")
+            .geo_deny(&["RU"])
+            .build();
+        if cfg!(feature = "geoip") {
+            assert!(matches!(err, Err(Error::Config(_))));
+        } else {
+            assert!(matches!(err, Err(Error::Unsupported(_))));
+        }
+        assert!(matches!(
+            builder()
+                .geoip_db("/nonexistent/GeoLite2-Country.mmdb")
+                .geo_allow(&["USA"])
+                .build(),
+            Err(Error::Config(_)) | Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_memory_refuses() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+
+        let err = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:9")
+            .tcp(true)
+            .max_memory(0)
+            .build();
+        assert!(err.is_err());
+
+        // 预算不够一个连接：接受后立即关闭
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|probe| probe.local_addr())
+            .expect("probe addr");
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&listen_addr.to_string())
+                .remote("127.0.0.1:9")
+                .tcp(true)
+                .max_memory(1),
+        );
+
+        let mut stream = TcpStream::connect(listen_addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        let mut buf = [0u8; 8];
+        assert_eq!(stream.read(&mut buf).expect("read"), 0);
+        assert_eq!(handle.stats().tcp_connections, 0);
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_forward_named_pipe() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let backend = TcpListener::bind("127.0.0.1:0").expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().expect("accept");
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).expect("read");
+            stream.write_all(&buf[..n]).expect("write");
+        });
+
+        let name = format!(r"\\.\pipe\tinyportmapper-test-{}", std::process::id());
+        let err = PortMapper::builder()
+            .listen(&format!("npipe:{}", name))
+            .remote(&backend_addr.to_string())
+            .udp(true)
+            .build()
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let (handle, runner) = spawn_mapper(
+            PortMapper::builder()
+                .listen(&format!("npipe:{}", name))
+                .remote(&backend_addr.to_string())
+                .tcp(true),
+        );
+
+        let mut pipe = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&name)
+            .expect("open pipe");
+        pipe.write_all(b"pipe ping").expect("write");
+        let mut buf = [0u8; 64];
+        let n = pipe.read(&mut buf).expect("read");
+        assert_eq!(&buf[..n], b"pipe ping");
+
+        handle.stop();
+        runner.join().expect("join runner");
+    }
+}
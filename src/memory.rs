@@ -0,0 +1,130 @@
+//! 全局内存预算 (--max-memory)
+//!
+//! 统计缓冲区池分配的缓冲区和各连接/会话的状态占用的内存。新连接或会话会超出预算时拒绝，
+//! 已经超出时由事件循环按 LRU 从最久未活动的连接开始关闭，避免受到攻击时被 OOM killer 杀死
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// 每个 TCP 连接计入的状态内存 (不含缓冲区)
+pub const TCP_CONN_MEMORY: usize = std::mem::size_of::<crate::connection::TcpConnection>();
+
+/// 每个 UDP 会话计入的状态内存
+pub const UDP_SESSION_MEMORY: usize = std::mem::size_of::<crate::connection::UdpSession>();
+
+/// 全局内存预算
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    /// 因预算不足拒绝的连接和会话数
+    refused: AtomicU64,
+    /// 因超出预算关闭的连接和会话数
+    evicted: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+            refused: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        })
+    }
+
+    /// 预算上限 (字节)
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// 当前计入的字节数
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// 再占用 `extra` 字节后是否仍在预算内
+    pub fn has_room(&self, extra: usize) -> bool {
+        self.used().saturating_add(extra) <= self.limit
+    }
+
+    /// 是否已超出预算
+    pub fn is_exceeded(&self) -> bool {
+        self.used() > self.limit
+    }
+
+    pub fn charge(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    pub fn record_evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+}
+
+/// 解析内存大小
+///
+/// 单位为字节，支持 K/M/G 后缀 (1024 进制)，例如 `512M`、`2G`
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (num, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let value: usize = num
+        .parse()
+        .map_err(|_| format!("invalid size: {}, expected e.g. 512M, 2G", s))?;
+    if value == 0 {
+        return Err("size must be greater than 0".to_string());
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let budget = MemoryBudget::new(100);
+        assert!(budget.has_room(100));
+        budget.charge(60);
+        assert!(!budget.has_room(50));
+        assert!(!budget.is_exceeded());
+        budget.charge(60);
+        assert!(budget.is_exceeded());
+        budget.release(60);
+        assert_eq!(budget.used(), 60);
+        assert!(!budget.is_exceeded());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512k"), Ok(512 * 1024));
+        assert_eq!(parse_size("64M"), Ok(64 * 1024 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("lots").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! 中间件：检查和改写转发的数据，按需打开辅助映射
+//!
+//! 协议修正 (FTP、TFTP 等应用层网关) 都实现为 `Middleware`，映射按顺序询问每个中间件：新 TCP 连接
+//! 建立时创建 `TcpFilter`，新 UDP 会话建立时创建 `UdpFilter`，返回 None 的中间件不参与该连接。
+//!
+//! TCP 过滤器依次处理每次读到的数据块 (在流混淆之后)，可以留住不完整的部分等待后续数据；需要另开连接的
+//! 协议通过 `TcpContext::open_tcp` 请求辅助映射：在本机临时端口上监听，只接受指定对端 IP 的一个连接，
+//! 转发到目标地址，超过 `AUX_TIMEOUT` 没有连接时关闭。辅助映射的连接不再经过中间件。
+//!
+//! UDP 过滤器检查或改写每个数据报，也可以让会话 socket 先不连接，跟随远程从新端口发来的回包。
+//! 作为库使用时用构建器的 `middleware()` 挂载自己的实现
+
+use crate::types::Address;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// 辅助映射的临时监听等待连接的时间
+pub const AUX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 每个连接同时打开的辅助映射数上限，超出时 `open_tcp` 返回 None
+pub const MAX_AUX_MAPPINGS: usize = 4;
+
+/// 中间件，每个映射共享一个实例
+pub trait Middleware: Send + Sync + fmt::Debug {
+    /// 名称，用于日志
+    fn name(&self) -> &str;
+
+    /// 新 TCP 连接转发到 `remote` 时创建过滤器，不处理该连接时返回 None
+    fn tcp_filter(&self, _remote: &Address) -> Option<Box<dyn TcpFilter>> {
+        None
+    }
+
+    /// 新 UDP 会话转发到 `remote` 时创建过滤器，不处理该会话时返回 None
+    fn udp_filter(&self, _remote: &Address) -> Option<Box<dyn UdpFilter>> {
+        None
+    }
+}
+
+/// TCP 连接的过滤器，保存该连接的解析状态
+pub trait TcpFilter: Send + Sync + fmt::Debug {
+    /// 输出不超过 `room` 字节时一次最多输入的字节数 (改写会使数据变长时要留出余量)
+    fn max_input(&self, room: usize) -> usize {
+        room
+    }
+
+    /// 处理 `to_remote` 方向收到的数据，结果追加到 `out`，可以留住不完整的部分
+    fn process(
+        &mut self,
+        to_remote: bool,
+        input: &[u8],
+        out: &mut Vec<u8>,
+        ctx: &mut dyn TcpContext,
+    );
+}
+
+/// TCP 连接两端的地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    /// 客户端地址
+    pub client: SocketAddr,
+    /// 客户端所连接的本机地址
+    pub client_local: SocketAddr,
+    /// 远程地址
+    pub remote: SocketAddr,
+    /// 连接远程所用的本机地址
+    pub remote_local: SocketAddr,
+}
+
+/// 辅助映射：在本机 `listen` IP 的临时端口上监听，接受来自 `peer` 的一个连接并转发到 `target`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuxMapping {
+    pub listen: IpAddr,
+    pub peer: IpAddr,
+    pub target: SocketAddr,
+}
+
+/// 过滤器处理数据时可用的连接信息和操作
+pub trait TcpContext {
+    /// 连接两端的地址，socket 已关闭或不是 IP 地址时返回 None
+    fn stream(&self) -> Option<StreamInfo>;
+
+    /// 打开辅助映射，返回临时监听地址，失败或达到上限时返回 None
+    fn open_tcp(&mut self, mapping: AuxMapping) -> Option<SocketAddr>;
+}
+
+/// UDP 会话的过滤器
+pub trait UdpFilter: Send + Sync + fmt::Debug {
+    /// 名称，用于日志
+    fn name(&self) -> &str;
+
+    /// 检查或改写 `to_remote` 方向的数据报，返回 false 时丢弃
+    fn datagram(&mut self, _to_remote: bool, _data: &mut Vec<u8>) -> bool {
+        true
+    }
+
+    /// 会话 socket 是否先不连接，等待远程从其他地址回包 (见 `follow`)
+    fn unconnected(&self) -> bool {
+        false
+    }
+
+    /// 未连接的会话发往 `remote`，收到来自 `from` 的 `payload` 时，会话是否改为与 `from` 通信
+    fn follow(&mut self, _remote: &Address, _from: &Address, _payload: &[u8]) -> bool {
+        false
+    }
+}
@@ -0,0 +1,134 @@
+//! 流量镜像 (--mirror)
+//!
+//! 把客户端 -> 后端方向的数据复制一份发到镜像目标，用于给 IDS 提供流量或对新后端做影子测试。
+//! TCP 每个连接另开一条到镜像目标的连接，镜像目标的回应读取后丢弃；UDP 数据包从一个共享 socket 发出。
+//! 镜像是尽力而为的，不会让转发等待：TCP 积压超过 `MIRROR_MAX_PENDING` 时停止镜像该连接，UDP 发送失败直接丢弃
+
+use crate::fd_manager::Fd64;
+use crate::sim::SocketIo;
+use crate::PlatformRawFd;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// 单个 TCP 镜像流最多积压的字节数
+pub const MIRROR_MAX_PENDING: usize = 1024 * 1024;
+
+/// 单个 TCP 连接的镜像流
+#[derive(Debug, Clone)]
+pub struct MirrorStream {
+    pub fd64: Fd64,
+    /// 连接完成前和发送受阻时积压的数据
+    pending: VecDeque<u8>,
+    /// 非阻塞连接已完成
+    pub connected: bool,
+    /// 已发给镜像目标的字节数
+    pub sent: u64,
+}
+
+impl MirrorStream {
+    pub fn new(fd64: Fd64) -> Self {
+        Self {
+            fd64,
+            pending: VecDeque::new(),
+            connected: false,
+            sent: 0,
+        }
+    }
+
+    /// 追加待镜像的数据，积压超过上限时返回 false
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if self.pending.len() + data.len() > MIRROR_MAX_PENDING {
+            return false;
+        }
+        self.pending.extend(data);
+        true
+    }
+
+    /// 积压的字节数
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// 尽量发出积压的数据，发送出错 (镜像连接已不可用) 时返回错误
+    pub fn flush(&mut self, io: &dyn SocketIo, fd: PlatformRawFd) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let (head, tail) = self.pending.as_slices();
+            match io.send(fd, &[head, tail]) {
+                Ok(0) => break,
+                Ok(sent) => {
+                    self.pending.drain(..sent);
+                    self.sent += sent as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// UDP 镜像：所有会话共用一个已连接到镜像目标的 socket
+#[derive(Debug)]
+pub struct UdpMirror {
+    socket: UdpSocket,
+}
+
+impl UdpMirror {
+    pub fn connect(target: SocketAddr) -> io::Result<Self> {
+        let bind_addr: SocketAddr = if target.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// 发送一个数据包的副本，失败 (缓冲区满、ICMP 错误等) 时丢弃
+    pub fn send(&self, payload: &[u8]) {
+        let _ = self.socket.send(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimNet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mirror_stream() {
+        let net = SimNet::new();
+        let fd = net.socket(4);
+        let mut mirror = MirrorStream::new(Fd64(1));
+        assert!(mirror.push(b"hello"));
+        mirror.flush(net.as_ref(), fd).expect("flush");
+        // 镜像目标只收下 4 字节，其余继续积压
+        assert_eq!(mirror.pending(), 1);
+        assert_eq!(net.take(fd, 16), b"hell");
+        mirror.flush(net.as_ref(), fd).expect("flush");
+        assert_eq!(mirror.pending(), 0);
+        assert_eq!(mirror.sent, 5);
+
+        assert!(mirror.push(&vec![0u8; MIRROR_MAX_PENDING]));
+        assert!(!mirror.push(b"x"));
+        net.reset(fd);
+        assert!(mirror.flush(net.as_ref(), fd).is_err());
+    }
+
+    #[test]
+    fn test_udp_mirror() {
+        let target = UdpSocket::bind("127.0.0.1:0").expect("bind target");
+        target
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout");
+        let mirror = UdpMirror::connect(target.local_addr().expect("addr")).expect("connect");
+        mirror.send(b"copy");
+        let mut buf = [0u8; 16];
+        let n = target.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], b"copy");
+    }
+}
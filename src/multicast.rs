@@ -0,0 +1,176 @@
+//! 组播监听 (-l 239.1.1.1:5000@eth0)
+//!
+//! 监听地址为组播地址时 UDP 监听 socket 绑定到组地址并加入该组 (IGMP/MLD 成员报告由内核发送)，
+//! 收到的数据包和单播一样按来源地址建立会话转发给单播远程，回包默认发回客户端的单播地址，
+//! `--multicast-reply` 时发往组地址。
+//!
+//! 接口被删除重建 (例如 VPN 的 tun 设备) 时内核丢弃成员关系，映射定期重新加入组；已是成员时内核返回
+//! EADDRINUSE，不重复报告。只支持 Linux 和 macOS
+
+use crate::types::Address;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use std::io;
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use std::net::IpAddr;
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use std::os::unix::io::RawFd;
+
+/// 定期重新加入组播组的间隔
+pub const REJOIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 是否为组播 IP 地址
+pub fn is_multicast(addr: &Address) -> bool {
+    addr.is_ip() && addr.ip().ip().is_multicast()
+}
+
+/// 拆分监听地址 `组地址:端口@接口` 中的接口名，不是组播地址时原样返回
+pub fn split_interface(s: &str) -> (&str, Option<&str>) {
+    match s.rsplit_once('@') {
+        Some((addr, interface))
+            if !interface.is_empty()
+                && Address::from_str(addr).is_ok_and(|addr| is_multicast(&addr)) =>
+        {
+            (addr, Some(interface))
+        }
+        _ => (s, None),
+    }
+}
+
+/// 接口名对应的接口索引，None 时为 0 (由内核按路由选择接口)
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+fn interface_index(interface: Option<&str>) -> io::Result<u32> {
+    let Some(interface) = interface else {
+        return Ok(0);
+    };
+    let name = std::ffi::CString::new(interface)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("interface {} not found", interface),
+        )),
+        index => Ok(index),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, val: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            val as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 在 `interface` 上加入组播组 `group`，返回是否新加入 (已是成员时返回 false)
+///
+/// Linux 上同时关闭 IP_MULTICAST_ALL，只接收本 socket 加入的组
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+pub fn join(fd: RawFd, group: IpAddr, interface: Option<&str>) -> io::Result<bool> {
+    let index = interface_index(interface)?;
+    let joined = match group {
+        IpAddr::V4(group) => {
+            #[cfg(target_os = "linux")]
+            setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_MULTICAST_ALL,
+                &(0 as libc::c_int),
+            )?;
+            let mreq = libc::ip_mreqn {
+                imr_multiaddr: libc::in_addr {
+                    s_addr: u32::from(group).to_be(),
+                },
+                imr_address: libc::in_addr { s_addr: 0 },
+                imr_ifindex: index as _,
+            };
+            setsockopt(fd, libc::IPPROTO_IP, libc::IP_ADD_MEMBERSHIP, &mreq)
+        }
+        IpAddr::V6(group) => {
+            let mreq = libc::ipv6_mreq {
+                ipv6mr_multiaddr: libc::in6_addr {
+                    s6_addr: group.octets(),
+                },
+                ipv6mr_interface: index as _,
+            };
+            #[cfg(target_os = "linux")]
+            let name = libc::IPV6_ADD_MEMBERSHIP;
+            #[cfg(target_vendor = "apple")]
+            let name = libc::IPV6_JOIN_GROUP;
+            setsockopt(fd, libc::IPPROTO_IPV6, name, &mreq)
+        }
+    };
+    match joined {
+        Ok(()) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EADDRINUSE) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// `--multicast-reply`：回包从 `interface` 发往组地址，并关闭组播回环，避免映射收到自己发出的回包
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+pub fn set_reply(fd: RawFd, group: IpAddr, interface: Option<&str>) -> io::Result<()> {
+    let index = interface_index(interface)?;
+    match group {
+        IpAddr::V4(_) => {
+            setsockopt(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_LOOP, &0u8)?;
+            let mreq = libc::ip_mreqn {
+                imr_multiaddr: libc::in_addr { s_addr: 0 },
+                imr_address: libc::in_addr { s_addr: 0 },
+                imr_ifindex: index as _,
+            };
+            setsockopt(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &mreq)
+        }
+        IpAddr::V6(_) => {
+            setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MULTICAST_LOOP,
+                &(0 as libc::c_uint),
+            )?;
+            setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MULTICAST_IF,
+                &(index as libc::c_uint),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_interface() {
+        assert_eq!(
+            split_interface("239.1.1.1:5000@eth0"),
+            ("239.1.1.1:5000", Some("eth0"))
+        );
+        assert_eq!(
+            split_interface("[ff02::1]:5000@eth0"),
+            ("[ff02::1]:5000", Some("eth0"))
+        );
+        assert_eq!(split_interface("239.1.1.1:5000"), ("239.1.1.1:5000", None));
+        // 单播地址不接受接口名，交给地址解析报错
+        assert_eq!(
+            split_interface("10.0.0.1:5000@eth0"),
+            ("10.0.0.1:5000@eth0", None)
+        );
+        assert!(is_multicast(&"[ff02::1]:5000".parse().unwrap()));
+        assert!(!is_multicast(&"0.0.0.0:5000".parse().unwrap()));
+    }
+}
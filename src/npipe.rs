@@ -0,0 +1,591 @@
+//! Windows 命名管道转发
+//!
+//! 命名管道不是 socket，不能交给 `TcpHandler` 收发。监听地址或后端为 `npipe:` 时，
+//! `PortMapper` 不注册监听 socket，改由 `PipeBridge` 在独立线程中用自己的 mio Poll
+//! 接受连接，并在命名管道和 TCP 之间转发。mio 的 `NamedPipe` 用 IOCP 重叠 I/O 模拟就绪事件，
+//! 读写都经过它的内部缓冲区。
+//! 每个管道服务端实例只能连接一个客户端，客户端连上后立即创建下一个实例等待后续客户端
+
+use crate::backend::{Backend, BackendPool};
+use crate::stats::{Direction, TrafficStats};
+use crate::types::Address;
+use crate::{debug, info, warn};
+use mio::net::{TcpListener, TcpStream};
+use mio::windows::NamedPipe;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{FromRawHandle, IntoRawHandle, RawHandle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use winapi::shared::winerror::ERROR_PIPE_BUSY;
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::namedpipeapi::{CreateNamedPipeW, WaitNamedPipeW};
+use winapi::um::winbase::{
+    FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX, PIPE_TYPE_BYTE,
+    PIPE_UNLIMITED_INSTANCES,
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+/// 第 n 个连接的客户端和后端分别使用 `FIRST_STREAM_TOKEN + 2n` 和 `FIRST_STREAM_TOKEN + 2n + 1`
+const FIRST_STREAM_TOKEN: usize = 2;
+/// 后端管道的所有实例都已连接时，等待空闲实例的时间
+const PIPE_BUSY_WAIT_MS: u32 = 1000;
+
+/// 接受客户端连接的一端
+enum Listener {
+    /// 等待客户端连接的管道服务端实例
+    Pipe {
+        name: String,
+        pending: NamedPipe,
+    },
+    Tcp(TcpListener),
+}
+
+/// 转发的一端
+enum Stream {
+    Pipe(NamedPipe),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    /// 注册接受的客户端
+    fn register_accepted(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        match self {
+            // 服务端实例在等待客户端时已注册到 LISTENER，这里只改 token
+            Stream::Pipe(pipe) => registry.reregister(pipe, token, interest),
+            Stream::Tcp(stream) => registry.register(stream, token, interest),
+        }
+    }
+
+    /// 注册新连接的后端
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        match self {
+            Stream::Pipe(pipe) => registry.register(pipe, token, interest),
+            Stream::Tcp(stream) => registry.register(stream, token, interest),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        let _ = match self {
+            Stream::Pipe(pipe) => registry.deregister(pipe),
+            Stream::Tcp(stream) => registry.deregister(stream),
+        };
+    }
+
+    /// 是否已可以写入，TCP 后端的非阻塞 connect 完成前为 false
+    fn is_connected(&self) -> io::Result<bool> {
+        match self {
+            Stream::Pipe(_) => Ok(true),
+            Stream::Tcp(stream) => {
+                if let Some(e) = stream.take_error()? {
+                    return Err(e);
+                }
+                match stream.peer_addr() {
+                    Ok(_) => Ok(true),
+                    Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /// 对端的数据已经读完，关闭写方向 (管道不能半关闭，由 `Pair::relay` 关闭整个连接)
+    fn shutdown_write(&self) {
+        if let Stream::Tcp(stream) = self {
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Pipe(pipe) => pipe.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Pipe(pipe) => pipe.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Pipe(pipe) => pipe.flush(),
+            Stream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// 一个方向上读到但尚未写出的数据
+#[derive(Default)]
+struct Pending {
+    data: Vec<u8>,
+    pos: usize,
+    /// 源端已读到 EOF
+    eof: bool,
+    /// EOF 之前的数据已全部写出，写方向已关闭
+    done: bool,
+}
+
+/// 客户端和后端组成的一个转发连接
+struct Pair {
+    client: Stream,
+    server: Stream,
+    server_connected: bool,
+    c2s: Pending,
+    s2c: Pending,
+    label: String,
+    backend: Arc<Backend>,
+}
+
+/// 命名管道转发器
+pub struct PipeBridge {
+    poll: Poll,
+    listener: Listener,
+    backends: BackendPool,
+    stats: &'static TrafficStats,
+    buf_size: usize,
+    max_connections: usize,
+    pairs: HashMap<usize, Pair>,
+    next_id: usize,
+    stop: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+/// 运行中的转发线程
+pub struct PipeBridgeHandle {
+    stop: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    thread: JoinHandle<()>,
+}
+
+impl PipeBridgeHandle {
+    /// 停止转发线程并关闭所有连接
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.waker.wake();
+        let _ = self.thread.join();
+    }
+}
+
+impl PipeBridge {
+    /// 在命名管道 `name` 上等待客户端，创建第一个实例 (名称已被占用时返回错误)
+    pub fn listen_pipe(
+        name: &str,
+        backends: BackendPool,
+        stats: &'static TrafficStats,
+        buf_size: usize,
+        max_connections: usize,
+    ) -> io::Result<Self> {
+        let pending = create_instance(name, true, buf_size)?;
+        let listener = Listener::Pipe {
+            name: name.to_string(),
+            pending,
+        };
+        Self::new(listener, backends, stats, buf_size, max_connections)
+    }
+
+    /// 在 TCP 监听 socket 上接受客户端，转发到命名管道后端
+    pub fn listen_tcp(
+        listener: TcpListener,
+        backends: BackendPool,
+        stats: &'static TrafficStats,
+        buf_size: usize,
+        max_connections: usize,
+    ) -> io::Result<Self> {
+        Self::new(
+            Listener::Tcp(listener),
+            backends,
+            stats,
+            buf_size,
+            max_connections,
+        )
+    }
+
+    fn new(
+        mut listener: Listener,
+        backends: BackendPool,
+        stats: &'static TrafficStats,
+        buf_size: usize,
+        max_connections: usize,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        match listener {
+            Listener::Pipe {
+                ref mut pending, ..
+            } => poll
+                .registry()
+                .register(pending, LISTENER, Interest::WRITABLE)?,
+            Listener::Tcp(ref mut tcp) => {
+                poll.registry()
+                    .register(tcp, LISTENER, Interest::READABLE)?
+            }
+        }
+        Ok(Self {
+            poll,
+            listener,
+            backends,
+            stats,
+            buf_size,
+            max_connections,
+            pairs: HashMap::new(),
+            next_id: 0,
+            stop: Arc::new(AtomicBool::new(false)),
+            waker,
+        })
+    }
+
+    /// 在新线程中运行转发
+    pub fn spawn(self) -> io::Result<PipeBridgeHandle> {
+        let stop = Arc::clone(&self.stop);
+        let waker = Arc::clone(&self.waker);
+        let thread = std::thread::Builder::new()
+            .name("npipe-bridge".to_string())
+            .spawn(move || self.run())?;
+        Ok(PipeBridgeHandle {
+            stop,
+            waker,
+            thread,
+        })
+    }
+
+    fn run(mut self) {
+        let mut events = Events::with_capacity(256);
+        let mut buf = vec![0u8; self.buf_size];
+        // 管道实例可能在注册前已有客户端连入，先尝试一次
+        self.on_listener();
+        while !self.stop.load(Ordering::Relaxed) {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!("[npipe] poll failed: {}", e);
+                break;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {}
+                    LISTENER => self.on_listener(),
+                    Token(token) => self.relay((token - FIRST_STREAM_TOKEN) / 2, &mut buf),
+                }
+            }
+        }
+        let registry = self.poll.registry();
+        for (_, mut pair) in self.pairs.drain() {
+            pair.client.deregister(registry);
+            pair.server.deregister(registry);
+            self.stats.dec_tcp_connections();
+            pair.backend.stats.dec_tcp_connections();
+        }
+    }
+
+    /// 接受所有已到达的客户端
+    fn on_listener(&mut self) {
+        loop {
+            let accepted = match self.listener {
+                Listener::Tcp(ref listener) => match listener.accept() {
+                    Ok((stream, addr)) => (Stream::Tcp(stream), addr.to_string()),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => {
+                        warn!("[npipe] accept failed: {}", e);
+                        return;
+                    }
+                },
+                Listener::Pipe {
+                    ref name,
+                    ref mut pending,
+                } => {
+                    let connected = match pending.take_error() {
+                        Ok(Some(e)) | Err(e) => Err(e),
+                        Ok(None) => pending.connect(),
+                    };
+                    let connected = match connected {
+                        Ok(()) => true,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                        Err(e) => {
+                            warn!("[npipe] connect on {} failed: {}", name, e);
+                            false
+                        }
+                    };
+                    // 先准备好下一个实例，再处理已连接 (或失败) 的实例
+                    let next = match create_instance(name, false, self.buf_size) {
+                        Ok(mut next) => {
+                            if let Err(e) = self.poll.registry().register(
+                                &mut next,
+                                LISTENER,
+                                Interest::WRITABLE,
+                            ) {
+                                warn!("[npipe] register pipe instance failed: {}", e);
+                                return;
+                            }
+                            next
+                        }
+                        Err(e) => {
+                            warn!("[npipe] create pipe instance on {} failed: {}", name, e);
+                            return;
+                        }
+                    };
+                    let pipe = std::mem::replace(pending, next);
+                    if !connected {
+                        continue;
+                    }
+                    (Stream::Pipe(pipe), name.clone())
+                }
+            };
+            self.start(accepted.0, accepted.1);
+        }
+    }
+
+    /// 为新客户端连接后端并开始转发
+    fn start(&mut self, mut client: Stream, label: String) {
+        if self.pairs.len() >= self.max_connections {
+            warn!(
+                "[npipe] {} rejected, too many connections ({})",
+                label,
+                self.pairs.len()
+            );
+            client.deregister(self.poll.registry());
+            return;
+        }
+        let Some(backend) = self.backends.pick() else {
+            warn!("[npipe] {} rejected, no backend available", label);
+            client.deregister(self.poll.registry());
+            return;
+        };
+        let mut server = match connect(&backend.addr) {
+            Ok(server) => server,
+            Err(e) => {
+                warn!(
+                    "[npipe] {} connect to {} failed: {}",
+                    label, backend.addr, e
+                );
+                client.deregister(self.poll.registry());
+                return;
+            }
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let client_token = Token(FIRST_STREAM_TOKEN + 2 * id);
+        let registry = self.poll.registry();
+        let registered = client
+            .register_accepted(registry, client_token)
+            .and_then(|_| server.register(registry, Token(client_token.0 + 1)));
+        if let Err(e) = registered {
+            warn!("[npipe] {} register failed: {}", label, e);
+            client.deregister(registry);
+            server.deregister(registry);
+            return;
+        }
+
+        info!("[npipe] {} connected to {}", label, backend.addr);
+        self.stats.inc_tcp_connections();
+        backend.stats.inc_tcp_connections();
+        self.pairs.insert(
+            id,
+            Pair {
+                client,
+                server,
+                server_connected: false,
+                c2s: Pending::default(),
+                s2c: Pending::default(),
+                label,
+                backend,
+            },
+        );
+    }
+
+    /// 连接的任一端有事件时两个方向都尝试转发，出错或两个方向都结束后关闭
+    fn relay(&mut self, id: usize, buf: &mut [u8]) {
+        let Some(pair) = self.pairs.get_mut(&id) else {
+            return;
+        };
+        let result = pair.relay(buf, self.stats);
+        let finished = match result {
+            Ok(finished) => finished,
+            Err(ref e) => {
+                debug!("[npipe] {} relay error: {}", pair.label, e);
+                true
+            }
+        };
+        if !finished {
+            return;
+        }
+        let Some(mut pair) = self.pairs.remove(&id) else {
+            return;
+        };
+        let registry = self.poll.registry();
+        pair.client.deregister(registry);
+        pair.server.deregister(registry);
+        self.stats.dec_tcp_connections();
+        pair.backend.stats.dec_tcp_connections();
+        info!("[npipe] {} closed", pair.label);
+    }
+}
+
+impl Pair {
+    /// 两个方向都转发到阻塞为止，连接可以关闭时返回 true
+    fn relay(&mut self, buf: &mut [u8], stats: &TrafficStats) -> io::Result<bool> {
+        if !self.server_connected {
+            if !self.server.is_connected()? {
+                return Ok(false);
+            }
+            self.server_connected = true;
+        }
+        let backend_stats = &self.backend.stats;
+        pump(
+            &mut self.client,
+            &mut self.server,
+            &mut self.c2s,
+            buf,
+            |n| {
+                stats.add_tcp_sent(Direction::ClientToServer, n);
+                backend_stats.add_bytes_up(n);
+            },
+            stats,
+        )?;
+        pump(
+            &mut self.server,
+            &mut self.client,
+            &mut self.s2c,
+            buf,
+            |n| {
+                stats.add_tcp_sent(Direction::ServerToClient, n);
+                backend_stats.add_bytes_down(n);
+            },
+            stats,
+        )?;
+        // 管道不能半关闭：任一方向写到管道的数据结束时关闭整个连接
+        let pipe_closed = (self.c2s.done && matches!(self.server, Stream::Pipe(_)))
+            || (self.s2c.done && matches!(self.client, Stream::Pipe(_)));
+        Ok((self.c2s.done && self.s2c.done) || pipe_closed)
+    }
+}
+
+/// 从 `src` 读取并写入 `dst`，直到其中一端阻塞或 `src` 的数据全部写出
+fn pump(
+    src: &mut Stream,
+    dst: &mut Stream,
+    pending: &mut Pending,
+    buf: &mut [u8],
+    mut on_sent: impl FnMut(usize),
+    stats: &TrafficStats,
+) -> io::Result<()> {
+    while !pending.done {
+        if pending.pos < pending.data.len() {
+            match dst.write(&pending.data[pending.pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    pending.pos += n;
+                    on_sent(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+        if pending.eof {
+            dst.shutdown_write();
+            pending.done = true;
+            break;
+        }
+        match src.read(buf) {
+            Ok(0) => pending.eof = true,
+            Ok(n) => {
+                stats.add_tcp_received(n);
+                pending.data.clear();
+                pending.data.extend_from_slice(&buf[..n]);
+                pending.pos = 0;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// 连接后端 (TCP 为非阻塞连接，管道所有实例都忙时最多等待 `PIPE_BUSY_WAIT_MS`)
+fn connect(addr: &Address) -> io::Result<Stream> {
+    match addr.named_pipe() {
+        Some(name) => open_pipe(name).map(Stream::Pipe),
+        None => TcpStream::connect(addr.to_sockaddr()).map(Stream::Tcp),
+    }
+}
+
+/// 以重叠 I/O 方式打开管道客户端
+fn open_pipe(name: &str) -> io::Result<NamedPipe> {
+    let open = || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(name)
+    };
+    let file = match open() {
+        Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+            let wide = to_wide(name);
+            unsafe { WaitNamedPipeW(wide.as_ptr(), PIPE_BUSY_WAIT_MS) };
+            open()?
+        }
+        result => result?,
+    };
+    Ok(unsafe { NamedPipe::from_raw_handle(file.into_raw_handle()) })
+}
+
+/// 探测管道后端：能打开客户端连接即视为健康
+pub fn probe(name: &str) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(name)
+        .map(|_| ())
+}
+
+/// 创建管道服务端实例，`first` 为 true 时名称已存在则失败 (防止与其他进程共用同一个名称)
+fn create_instance(name: &str, first: bool, buf_size: usize) -> io::Result<NamedPipe> {
+    let wide = to_wide(name);
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let buf_size = buf_size.min(u32::MAX as usize) as u32;
+    let handle = unsafe {
+        CreateNamedPipeW(
+            wide.as_ptr(),
+            open_mode,
+            PIPE_TYPE_BYTE,
+            PIPE_UNLIMITED_INSTANCES,
+            buf_size,
+            buf_size,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { NamedPipe::from_raw_handle(handle as RawHandle) })
+}
+
+fn to_wide(name: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(name)
+        .encode_wide()
+        .chain(Some(0))
+        .collect()
+}
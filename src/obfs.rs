@@ -0,0 +1,679 @@
+//! 中继之间的预共享密钥流混淆 (--encrypt-out / --decrypt-in)
+//!
+//! 两个映射实例串联时，前一个用 `--encrypt-out` 加密发往后端的 TCP 流，后一个用 `--decrypt-in` 解密
+//! 客户端发来的流，中间链路上的数据不再是可识别的明文。每个方向先发送 16 字节随机盐，之后：
+//! - `xor`：与由密钥和盐生成的伪随机密钥流异或，只做混淆，不防篡改
+//! - `chacha20-poly1305` (需要 `aead` 特性)：按帧加密，帧为加密的 2 字节长度和加密的数据，各带 16 字节
+//!   认证标签；nonce 为盐加帧计数，认证失败时关闭连接
+//!
+//! 密钥由 HKDF-SHA256 派生：`Psk::new` 从密钥字符串提取 (extract) 出主密钥，每个方向每条流再以方向
+//! 标签 (`c2s`/`s2c`) 和该方向的盐为 info 扩展 (expand) 出各自的密钥，两个方向不会共用密钥和 nonce。
+//! HKDF 不做密钥拉伸，密钥字符串应是足够长的随机值而不是口令。
+//!
+//! 不防重放：盐由发送端选择，录下的整条流 (或一条流的开头部分) 原样重放给解密端时会被正常解密，
+//! 重复的数据会再次转发到后端。需要防重放时应在上层协议 (如 TLS) 中处理。
+//!
+//! 只处理 TCP，UDP 按原样转发。LZ4 压缩 (`compress.rs`) 也作为处理步骤串在同一条流水线中
+
+use crate::compress::{Compressor, Decompressor};
+use crate::stats::Direction;
+#[cfg(feature = "aead")]
+use chacha20poly1305::{AeadInPlace, KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// 每个方向开头的随机盐长度
+pub const SALT_LEN: usize = 16;
+
+/// HKDF 提取主密钥时使用的固定盐，区分本协议和其他用途的同一密钥字符串
+const KDF_SALT: &[u8] = b"tinyPortMapper obfs v1";
+
+/// 加密帧的最大数据长度
+pub const MAX_FRAME_PAYLOAD: usize = 4096;
+
+#[cfg(feature = "aead")]
+const TAG_LEN: usize = 16;
+
+/// 每帧的额外字节：加密的长度字段和两个认证标签
+#[cfg(feature = "aead")]
+const FRAME_OVERHEAD: usize = 2 + 2 * TAG_LEN;
+
+/// 启用混淆时转发缓冲区的最小长度，保证解密积压的半帧后仍能读取新数据
+pub const OBFS_MIN_BUF: usize = 2 * (SALT_LEN + MAX_FRAME_PAYLOAD + 2 + 2 * 16);
+
+/// 流混淆算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    /// 异或伪随机密钥流
+    #[default]
+    Xor,
+    /// XChaCha20-Poly1305 认证加密
+    ChaCha20Poly1305,
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xor" => Ok(Cipher::Xor),
+            #[cfg(feature = "aead")]
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            #[cfg(not(feature = "aead"))]
+            "chacha20-poly1305" => Err("chacha20-poly1305 requires the aead feature".to_string()),
+            _ => Err(format!(
+                "invalid cipher '{}', must be xor or chacha20-poly1305",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::Xor => write!(f, "xor"),
+            Cipher::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+/// 预共享密钥
+#[derive(Clone, PartialEq, Eq)]
+pub struct Psk {
+    cipher: Cipher,
+    /// HKDF 提取出的主密钥 (PRK)
+    prk: [u8; 32],
+}
+
+impl Psk {
+    /// 由密钥字符串提取主密钥，两端的算法和字符串必须相同
+    ///
+    /// HKDF 不增加口令的强度，应使用足够长的随机字符串
+    pub fn new(cipher: Cipher, secret: &str) -> Result<Self, String> {
+        if secret.is_empty() {
+            return Err("pre-shared key must not be empty".to_string());
+        }
+        #[cfg(not(feature = "aead"))]
+        if cipher == Cipher::ChaCha20Poly1305 {
+            return Err("chacha20-poly1305 requires the aead feature".to_string());
+        }
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(KDF_SALT), secret.as_bytes());
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&prk);
+        Ok(Self { cipher, prk: key })
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// 派生 `direction` 方向上以 `salt` 开头的一条流的密钥
+    fn stream_key(&self, direction: Direction, salt: &[u8; SALT_LEN]) -> [u8; 32] {
+        let label: &[u8] = match direction {
+            Direction::ClientToServer => b"c2s",
+            Direction::ServerToClient => b"s2c",
+        };
+        let hkdf = Hkdf::<Sha256>::from_prk(&self.prk).expect("PRK is one SHA-256 block");
+        let mut key = [0u8; 32];
+        hkdf.expand_multi_info(&[b"tinyPortMapper obfs ", label, salt], &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    /// 新建 `direction` 方向的加密端，盐在第一次输出时发送
+    pub fn encoder(&self, direction: Direction) -> Encoder {
+        let mut salt = [0u8; SALT_LEN];
+        fill_random(&mut salt);
+        Encoder {
+            stream: Keystream::new(self, direction, salt),
+            salt: Some(salt),
+        }
+    }
+
+    /// 新建 `direction` 方向的解密端
+    pub fn decoder(&self, direction: Direction) -> Decoder {
+        Decoder {
+            psk: self.clone(),
+            direction,
+            stream: None,
+            pending: Vec::new(),
+            #[cfg(feature = "aead")]
+            frame_len: None,
+        }
+    }
+}
+
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Psk({}, <hidden>)", self.cipher)
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// 随机盐，Linux 上取自 getrandom，其他平台或失败时退回伪随机数
+fn fill_random(buf: &mut [u8]) {
+    #[cfg(target_os = "linux")]
+    {
+        let ret = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if ret == buf.len() as isize {
+            return;
+        }
+    }
+    for chunk in buf.chunks_mut(8) {
+        let value = crate::get_fake_random_number_64().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
+
+/// 由流密钥生成的伪随机异或密钥流
+#[derive(Clone)]
+struct XorStream {
+    state: u64,
+    block: [u8; 8],
+    used: usize,
+}
+
+impl XorStream {
+    /// 异或密钥流 (异或是对称的，加密和解密相同)
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            if self.used == self.block.len() {
+                self.state = self.state.wrapping_add(1);
+                self.block = splitmix64(self.state).to_le_bytes();
+                self.used = 0;
+            }
+            *b ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// 一个方向的密钥流状态
+#[derive(Clone)]
+enum Keystream {
+    Xor(XorStream),
+    #[cfg(feature = "aead")]
+    Aead {
+        key: [u8; 32],
+        salt: [u8; SALT_LEN],
+        counter: u64,
+    },
+}
+
+impl Keystream {
+    fn new(psk: &Psk, direction: Direction, salt: [u8; SALT_LEN]) -> Self {
+        let key = psk.stream_key(direction, &salt);
+        match psk.cipher {
+            Cipher::Xor => {
+                let mut state = [0u8; 8];
+                state.copy_from_slice(&key[..8]);
+                Keystream::Xor(XorStream {
+                    state: u64::from_le_bytes(state),
+                    block: [0; 8],
+                    used: 8,
+                })
+            }
+            #[cfg(feature = "aead")]
+            Cipher::ChaCha20Poly1305 => Keystream::Aead {
+                key,
+                salt,
+                counter: 0,
+            },
+            #[cfg(not(feature = "aead"))]
+            Cipher::ChaCha20Poly1305 => unreachable!("chacha20-poly1305 requires the aead feature"),
+        }
+    }
+
+    /// 下一个 nonce：盐加帧计数
+    #[cfg(feature = "aead")]
+    fn next_nonce(salt: &[u8; SALT_LEN], counter: &mut u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..SALT_LEN].copy_from_slice(salt);
+        nonce[SALT_LEN..].copy_from_slice(&counter.to_le_bytes());
+        *counter += 1;
+        nonce
+    }
+}
+
+impl fmt::Debug for Keystream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Keystream::Xor(_) => write!(f, "Keystream::Xor"),
+            #[cfg(feature = "aead")]
+            Keystream::Aead { counter, .. } => write!(f, "Keystream::Aead({})", counter),
+        }
+    }
+}
+
+/// 一个方向的加密端
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    stream: Keystream,
+    /// 尚未发送的盐
+    salt: Option<[u8; SALT_LEN]>,
+}
+
+impl Encoder {
+    /// 加密 `input` 追加到 `out`
+    pub fn encode(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        if let Some(salt) = self.salt.take() {
+            out.extend_from_slice(&salt);
+        }
+        match self.stream {
+            Keystream::Xor(ref mut xor) => {
+                let start = out.len();
+                out.extend_from_slice(input);
+                xor.apply(&mut out[start..]);
+            }
+            #[cfg(feature = "aead")]
+            Keystream::Aead {
+                ref key,
+                ref salt,
+                ref mut counter,
+            } => {
+                let cipher = XChaCha20Poly1305::new(key.into());
+                for chunk in input.chunks(MAX_FRAME_PAYLOAD) {
+                    let mut seal = |data: &[u8]| {
+                        let start = out.len();
+                        out.extend_from_slice(data);
+                        let nonce = Keystream::next_nonce(salt, counter);
+                        let tag = cipher
+                            .encrypt_in_place_detached(&nonce, b"", &mut out[start..])
+                            .expect("frame within AEAD size limit");
+                        out.extend_from_slice(&tag);
+                    };
+                    seal(&(chunk.len() as u16).to_be_bytes());
+                    seal(chunk);
+                }
+            }
+        }
+    }
+
+    /// 输出不超过 `room` 字节时最多能输入的字节数
+    pub fn max_input(&self, room: usize) -> usize {
+        let room = room.saturating_sub(self.salt.map_or(0, |salt| salt.len()));
+        match self.stream {
+            Keystream::Xor(_) => room,
+            #[cfg(feature = "aead")]
+            Keystream::Aead { .. } => {
+                let frames = room.div_ceil(MAX_FRAME_PAYLOAD + FRAME_OVERHEAD);
+                room.saturating_sub(frames * FRAME_OVERHEAD)
+            }
+        }
+    }
+}
+
+/// 一个方向的解密端，积压不完整的盐和帧
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    psk: Psk,
+    direction: Direction,
+    /// 收到盐之前为 None
+    stream: Option<Keystream>,
+    pending: Vec<u8>,
+    /// 已解密长度字段、等待数据的帧长度
+    #[cfg(feature = "aead")]
+    frame_len: Option<usize>,
+}
+
+impl Decoder {
+    /// 解密 `input` 中所有完整的数据追加到 `out`，认证失败或帧长度非法时返回错误
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let mut input = input;
+        if self.stream.is_none() {
+            let need = SALT_LEN - self.pending.len();
+            let take = need.min(input.len());
+            self.pending.extend_from_slice(&input[..take]);
+            input = &input[take..];
+            if self.pending.len() < SALT_LEN {
+                return Ok(());
+            }
+            let mut salt = [0u8; SALT_LEN];
+            salt.copy_from_slice(&self.pending);
+            self.pending.clear();
+            self.stream = Some(Keystream::new(&self.psk, self.direction, salt));
+        }
+        match self.stream {
+            Some(Keystream::Xor(ref mut xor)) => {
+                let start = out.len();
+                out.extend_from_slice(input);
+                xor.apply(&mut out[start..]);
+                Ok(())
+            }
+            #[cfg(feature = "aead")]
+            Some(Keystream::Aead {
+                ref key,
+                ref salt,
+                ref mut counter,
+            }) => {
+                self.pending.extend_from_slice(input);
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let mut open = |data: &mut [u8]| -> io::Result<()> {
+                    let (data, tag) = data.split_at_mut(data.len() - TAG_LEN);
+                    let nonce = Keystream::next_nonce(salt, counter);
+                    cipher
+                        .decrypt_in_place_detached(&nonce, b"", data, Tag::from_slice(tag))
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad frame tag"))
+                };
+                let mut offset = 0;
+                loop {
+                    let rest = &mut self.pending[offset..];
+                    let len = match self.frame_len {
+                        Some(len) => len,
+                        None if rest.len() >= 2 + TAG_LEN => {
+                            open(&mut rest[..2 + TAG_LEN])?;
+                            let len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+                            if len == 0 || len > MAX_FRAME_PAYLOAD {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "bad frame length",
+                                ));
+                            }
+                            offset += 2 + TAG_LEN;
+                            self.frame_len = Some(len);
+                            continue;
+                        }
+                        None => break,
+                    };
+                    if rest.len() < len + TAG_LEN {
+                        break;
+                    }
+                    open(&mut rest[..len + TAG_LEN])?;
+                    out.extend_from_slice(&rest[..len]);
+                    offset += len + TAG_LEN;
+                    self.frame_len = None;
+                }
+                self.pending.drain(..offset);
+                Ok(())
+            }
+            None => unreachable!(),
+        }
+    }
+
+    /// 输出不超过 `room` 字节时最多能输入的字节数 (输出不会超过积压加输入)
+    pub fn max_input(&self, room: usize) -> usize {
+        room.saturating_sub(self.pending.len())
+    }
+}
+
+/// 一个方向上的处理步骤
+#[derive(Debug, Clone)]
+pub enum Stage {
+    Encode(Encoder),
+    Decode(Decoder),
+    Compress(Compressor),
+    Decompress(Decompressor),
+}
+
+impl Stage {
+    fn max_input(&self, room: usize) -> usize {
+        match self {
+            Stage::Encode(encoder) => encoder.max_input(room),
+            Stage::Decode(decoder) => decoder.max_input(room),
+            Stage::Compress(compressor) => compressor.max_input(room),
+            Stage::Decompress(decompressor) => decompressor.max_input(room),
+        }
+    }
+}
+
+/// 单个 TCP 连接两个方向的混淆和压缩处理
+///
+/// 客户端 -> 后端：按 `--decrypt-in` 解密、`--decompress-in` 解压、`--compress-out` 压缩、
+/// `--encrypt-out` 加密；后端 -> 客户端按相反的顺序做相反的处理
+#[derive(Debug, Clone)]
+pub struct ObfsStreams {
+    up: Vec<Stage>,
+    down: Vec<Stage>,
+}
+
+impl ObfsStreams {
+    /// 所有选项都没有设置时返回 None
+    pub fn new(
+        decrypt_in: Option<&Psk>,
+        encrypt_out: Option<&Psk>,
+        decompress_in: bool,
+        compress_out: bool,
+    ) -> Option<Self> {
+        if decrypt_in.is_none() && encrypt_out.is_none() && !decompress_in && !compress_out {
+            return None;
+        }
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        if let Some(psk) = decrypt_in {
+            up.push(Stage::Decode(psk.decoder(Direction::ClientToServer)));
+        }
+        if decompress_in {
+            up.push(Stage::Decompress(Decompressor::new()));
+        }
+        if compress_out {
+            up.push(Stage::Compress(Compressor::new()));
+        }
+        if let Some(psk) = encrypt_out {
+            up.push(Stage::Encode(psk.encoder(Direction::ClientToServer)));
+            down.push(Stage::Decode(psk.decoder(Direction::ServerToClient)));
+        }
+        if compress_out {
+            down.push(Stage::Decompress(Decompressor::new()));
+        }
+        if decompress_in {
+            down.push(Stage::Compress(Compressor::follow()));
+        }
+        if let Some(psk) = decrypt_in {
+            down.push(Stage::Encode(psk.encoder(Direction::ServerToClient)));
+        }
+        Some(Self { up, down })
+    }
+
+    /// 处理后不超过 `room` 字节时，`to_remote` 方向一次最多读取的字节数
+    pub fn max_input(&self, to_remote: bool, room: usize) -> usize {
+        let stages = if to_remote { &self.up } else { &self.down };
+        stages
+            .iter()
+            .rev()
+            .fold(room, |room, stage| stage.max_input(room))
+    }
+
+    /// `to_remote` 方向积压了已完整收到、因缓冲区放不下而没有输出的数据，不读取新数据也应调用 `apply`
+    pub fn backlogged(&self, to_remote: bool) -> bool {
+        let stages = if to_remote { &self.up } else { &self.down };
+        stages
+            .iter()
+            .any(|stage| matches!(stage, Stage::Decompress(d) if d.ready()))
+    }
+
+    /// 原地处理 `buf[..len]`，返回处理后的长度 (可能为 0，数据积压在解密端或解压端)
+    pub fn apply(&mut self, to_remote: bool, buf: &mut [u8], len: usize) -> io::Result<usize> {
+        // 客户端发来的是压缩流时回包才压缩
+        let peer_compresses = self
+            .up
+            .iter()
+            .any(|stage| matches!(stage, Stage::Decompress(d) if d.compressed() == Some(true)));
+        let stages = if to_remote {
+            &mut self.up
+        } else {
+            &mut self.down
+        };
+        let mut data = buf[..len].to_vec();
+        for i in 0..stages.len() {
+            // 解压后的数据经过后续步骤仍要放进缓冲区
+            let cap = stages[i + 1..]
+                .iter()
+                .rev()
+                .fold(buf.len(), |room, stage| stage.max_input(room));
+            let mut out = Vec::with_capacity(data.len() + SALT_LEN);
+            match &mut stages[i] {
+                Stage::Encode(encoder) => encoder.encode(&data, &mut out),
+                Stage::Decode(decoder) => decoder.decode(&data, &mut out)?,
+                Stage::Compress(compressor) => {
+                    compressor.compress(&data, &mut out, peer_compresses)
+                }
+                Stage::Decompress(decompressor) => decompressor.decompress(&data, &mut out, cap)?,
+            }
+            data = out;
+        }
+        if data.len() > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "obfuscated data exceeds buffer",
+            ));
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(cipher: Cipher) {
+        let psk = Psk::new(cipher, "relay secret").unwrap();
+        let mut encoder = psk.encoder(Direction::ClientToServer);
+        let mut decoder = psk.decoder(Direction::ClientToServer);
+        let data = crate::selftest::pattern(3 * MAX_FRAME_PAYLOAD + 100, 1);
+
+        let mut wire = Vec::new();
+        encoder.encode(&data[..1000], &mut wire);
+        encoder.encode(&data[1000..], &mut wire);
+        assert!(wire.len() > data.len());
+        assert!(!wire.windows(64).any(|w| w == &data[..64]));
+
+        // 按任意边界分段到达
+        let mut plain = Vec::new();
+        for piece in wire.chunks(7) {
+            decoder.decode(piece, &mut plain).expect("decode");
+        }
+        assert!(plain == data);
+
+        // 同一密钥的另一条流使用不同的盐
+        let mut other = Vec::new();
+        psk.encoder(Direction::ClientToServer)
+            .encode(&data[..1000], &mut other);
+        assert_ne!(other[..1016], wire[..1016]);
+
+        // 两个方向的密钥不同，反方向的解密端得不到原文
+        let mut plain = Vec::new();
+        let reverse = psk
+            .decoder(Direction::ServerToClient)
+            .decode(&wire, &mut plain);
+        assert!(reverse.is_err() || plain[..1000] != data[..1000]);
+    }
+
+    #[test]
+    fn test_stream_keys() {
+        let psk = Psk::new(Cipher::Xor, "relay secret").unwrap();
+        let salt = [7u8; SALT_LEN];
+        let c2s = psk.stream_key(Direction::ClientToServer, &salt);
+        assert_eq!(c2s, psk.stream_key(Direction::ClientToServer, &salt));
+        assert_ne!(c2s, psk.stream_key(Direction::ServerToClient, &salt));
+        assert_ne!(
+            c2s,
+            psk.stream_key(Direction::ClientToServer, &[8u8; SALT_LEN])
+        );
+
+        // 两端由同一字符串得到同一主密钥，算法不影响派生
+        assert!(psk == Psk::new(Cipher::Xor, "relay secret").unwrap());
+        let other = Psk::new(Cipher::Xor, "relay secret!").unwrap();
+        assert_ne!(c2s, other.stream_key(Direction::ClientToServer, &salt));
+    }
+
+    #[test]
+    fn test_xor_roundtrip() {
+        roundtrip(Cipher::Xor);
+        assert_eq!("xor".parse::<Cipher>(), Ok(Cipher::Xor));
+        assert!("rot13".parse::<Cipher>().is_err());
+        assert!(Psk::new(Cipher::Xor, "").is_err());
+        assert!(!format!("{:?}", Psk::new(Cipher::Xor, "k").unwrap()).contains("107"));
+    }
+
+    #[cfg(feature = "aead")]
+    #[test]
+    fn test_aead_roundtrip() {
+        roundtrip(Cipher::ChaCha20Poly1305);
+
+        let psk = Psk::new(Cipher::ChaCha20Poly1305, "relay secret").unwrap();
+        let mut wire = Vec::new();
+        psk.encoder(Direction::ClientToServer)
+            .encode(b"hello", &mut wire);
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        let mut plain = Vec::new();
+        assert!(psk
+            .decoder(Direction::ClientToServer)
+            .decode(&wire, &mut plain)
+            .is_err());
+
+        let wrong = Psk::new(Cipher::ChaCha20Poly1305, "other secret").unwrap();
+        wire[last] ^= 1;
+        assert!(wrong
+            .decoder(Direction::ClientToServer)
+            .decode(&wire, &mut plain)
+            .is_err());
+    }
+
+    #[test]
+    fn test_obfs_streams() {
+        let psk = Psk::new(Cipher::default(), "k").unwrap();
+        // 出口实例加密，入口实例解密，数据原样到达
+        let mut out_relay = ObfsStreams::new(None, Some(&psk), false, false).unwrap();
+        let mut in_relay = ObfsStreams::new(Some(&psk), None, false, false).unwrap();
+        assert!(ObfsStreams::new(None, None, false, false).is_none());
+
+        let mut buf = vec![0u8; 64];
+        buf[..5].copy_from_slice(b"hello");
+        assert_eq!(out_relay.max_input(true, 64), 64 - SALT_LEN);
+        let len = out_relay.apply(true, &mut buf, 5).unwrap();
+        assert_eq!(len, SALT_LEN + 5);
+        let len = in_relay.apply(true, &mut buf, len).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // 解密端收到半个盐时没有输出
+        let len = in_relay.apply(false, &mut buf, 3).unwrap();
+        let len = out_relay.apply(false, &mut buf, len.min(8)).unwrap();
+        assert_eq!(len, 0);
+    }
+
+    #[test]
+    fn test_compressed_streams() {
+        let psk = Psk::new(Cipher::default(), "k").unwrap();
+        let mut out_relay = ObfsStreams::new(None, Some(&psk), false, true).unwrap();
+        let mut in_relay = ObfsStreams::new(Some(&psk), None, true, false).unwrap();
+        let text = b"{\"status\":\"ok\",\"items\":[]}\n".repeat(200);
+
+        // 压缩得很小的数据在入口解压时超出缓冲区，剩余部分积压到下次调用
+        let mut buf = vec![0u8; OBFS_MIN_BUF];
+        let mut received = Vec::new();
+        for chunk in text.chunks(out_relay.max_input(true, buf.len())) {
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let len = out_relay.apply(true, &mut buf, chunk.len()).unwrap();
+            let mut len = in_relay.apply(true, &mut buf, len).unwrap();
+            loop {
+                received.extend_from_slice(&buf[..len]);
+                if !in_relay.backlogged(true) {
+                    break;
+                }
+                len = in_relay.apply(true, &mut buf, 0).unwrap();
+            }
+        }
+        assert_eq!(received, text);
+
+        // 客户端发来了压缩流，回包同样压缩
+        buf[..text.len().min(1000)].copy_from_slice(&text[..1000]);
+        let len = in_relay.apply(false, &mut buf, 1000).unwrap();
+        if cfg!(feature = "lz4") {
+            assert!(len < 1000);
+        }
+        let len = out_relay.apply(false, &mut buf, len).unwrap();
+        assert_eq!(&buf[..len], &text[..1000]);
+    }
+}
@@ -0,0 +1,797 @@
+//! UDP 打洞隧道 (--rendezvous-server / --punch)
+//!
+//! 两个位于 NAT 后的实例用同一个名字向会合服务器注册，服务器把各自观察到的公网地址发给对方，
+//! 双方随即同时向对方地址发送打洞报文，在两端 NAT 上建立直连的 UDP 路径。
+//! 打通后连接端 (`-l`) 把本地客户端的数据报按通道封装发给服务端，服务端 (`-r`) 为每个通道
+//! 创建一个到目标的 UDP 会话。只转发 UDP；对称型 NAT 对不同目标分配不同端口，无法打通。
+//!
+//! 所有报文以 `TPMP` 和 1 字节类型开头，数据报文后跟 2 字节通道号 (大端) 和载荷
+
+use crate::log::get_monotonic_time;
+use crate::{debug, info, warn};
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const WAKER_TOKEN: Token = Token(0);
+const TUNNEL_TOKEN: Token = Token(1);
+const LOCAL_TOKEN: Token = Token(2);
+const FIRST_CHANNEL_TOKEN: usize = 3;
+
+const MAGIC: [u8; 4] = *b"TPMP";
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+const TYPE_REGISTER: u8 = 1;
+const TYPE_PEER: u8 = 2;
+const TYPE_PUNCH: u8 = 3;
+const TYPE_KEEPALIVE: u8 = 4;
+const TYPE_DATA: u8 = 5;
+
+/// 名字的最大长度
+pub const MAX_NAME_LEN: usize = 64;
+
+/// 定时检查的间隔，未打通时每次都向对端发送打洞报文
+const TICK: Duration = Duration::from_millis(500);
+
+/// 未打通时向会合服务器注册的间隔 (ms)
+const REGISTER_INTERVAL: u64 = 2000;
+
+/// 打通后没有其他流量时发送保活报文的间隔 (ms)，低于常见 NAT 的 UDP 映射超时
+const KEEPALIVE_INTERVAL: u64 = 15_000;
+
+/// 这么久没有收到对端的报文就认为直连路径已断开，重新注册 (ms)
+const PEER_TIMEOUT: u64 = 60_000;
+
+/// 会合服务器上注册的有效期 (ms)
+const REGISTRATION_TIMEOUT: u64 = 30_000;
+
+/// 读缓冲区大小，足够容纳最大的 UDP 数据报
+const BUF_SIZE: usize = 65536;
+
+/// 隧道报文
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// 对端 -> 会合服务器：注册名字
+    Register(&'a str),
+    /// 会合服务器 -> 对端：同名对端的公网地址
+    Peer(SocketAddr),
+    /// 对端之间的打洞报文，带上名字，不接受其他名字的对端
+    Punch(&'a str),
+    /// 保活，也用于应答打洞报文
+    Keepalive,
+    /// 通道数据
+    Data { channel: u16, payload: &'a [u8] },
+}
+
+impl<'a> Frame<'a> {
+    /// 编码到 `buf` (先清空)
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.extend_from_slice(&MAGIC);
+        match *self {
+            Frame::Register(name) => {
+                buf.push(TYPE_REGISTER);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            Frame::Peer(addr) => {
+                buf.push(TYPE_PEER);
+                buf.extend_from_slice(addr.to_string().as_bytes());
+            }
+            Frame::Punch(name) => {
+                buf.push(TYPE_PUNCH);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            Frame::Keepalive => buf.push(TYPE_KEEPALIVE),
+            Frame::Data { channel, payload } => {
+                buf.push(TYPE_DATA);
+                buf.extend_from_slice(&channel.to_be_bytes());
+                buf.extend_from_slice(payload);
+            }
+        }
+    }
+
+    /// 解码，不是隧道报文或格式错误时返回 None
+    pub fn decode(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let body = &data[HEADER_LEN..];
+        match data[MAGIC.len()] {
+            TYPE_REGISTER => parse_name(body).map(Frame::Register),
+            TYPE_PEER => std::str::from_utf8(body)
+                .ok()?
+                .parse()
+                .ok()
+                .map(Frame::Peer),
+            TYPE_PUNCH => parse_name(body).map(Frame::Punch),
+            TYPE_KEEPALIVE => Some(Frame::Keepalive),
+            TYPE_DATA if body.len() >= 2 => Some(Frame::Data {
+                channel: u16::from_be_bytes([body[0], body[1]]),
+                payload: &body[2..],
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn parse_name(data: &[u8]) -> Option<&str> {
+    std::str::from_utf8(data)
+        .ok()
+        .filter(|name| is_valid_name(name))
+}
+
+/// 名字非空、不超过 `MAX_NAME_LEN` 字节且不含控制字符
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && !name.chars().any(char::is_control)
+}
+
+fn send_frame(socket: &UdpSocket, out: &mut Vec<u8>, frame: &Frame<'_>, to: SocketAddr) {
+    frame.encode(out);
+    if let Err(e) = socket.send_to(out, to) {
+        debug!("[punch] send to {} failed: {}", to, e);
+    }
+}
+
+/// 与 `addr` 同族的通配地址
+fn unspecified(addr: &SocketAddr) -> SocketAddr {
+    let ip = if addr.is_ipv6() {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    SocketAddr::new(ip, 0)
+}
+
+/// 停止会合服务器或隧道对端的句柄，可跨线程使用
+#[derive(Debug, Clone)]
+pub struct PunchHandle {
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl PunchHandle {
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.waker.wake();
+    }
+}
+
+#[derive(Debug)]
+struct Registration {
+    addr: SocketAddr,
+    last_seen: u64,
+}
+
+/// 会合服务器，只交换地址，不转发数据
+#[derive(Debug)]
+pub struct RendezvousServer {
+    poll: Poll,
+    socket: UdpSocket,
+    /// 名字 -> 最近注册的 (至多两个) 对端
+    names: HashMap<String, Vec<Registration>>,
+    last_purge: u64,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl RendezvousServer {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let mut socket = UdpSocket::bind(addr)?;
+        poll.registry()
+            .register(&mut socket, TUNNEL_TOKEN, Interest::READABLE)?;
+        Ok(Self {
+            poll,
+            socket,
+            names: HashMap::new(),
+            last_purge: get_monotonic_time(),
+            buf: vec![0u8; BUF_SIZE],
+            out: Vec::new(),
+            running: Arc::new(AtomicBool::new(true)),
+            waker,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn handle(&self) -> PunchHandle {
+        PunchHandle {
+            running: Arc::clone(&self.running),
+            waker: Arc::clone(&self.waker),
+        }
+    }
+
+    /// 运行直到 `PunchHandle::stop`
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(64);
+        while self.running.load(Ordering::Relaxed) {
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            let now = get_monotonic_time();
+            if now.saturating_sub(self.last_purge) >= REGISTRATION_TIMEOUT {
+                self.names.retain(|_, peers| {
+                    peers.retain(|peer| now.saturating_sub(peer.last_seen) < REGISTRATION_TIMEOUT);
+                    !peers.is_empty()
+                });
+                self.last_purge = now;
+            }
+            for event in events.iter() {
+                if event.token() == TUNNEL_TOKEN {
+                    self.on_datagrams(now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_datagrams(&mut self, now: u64) {
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("[rendezvous] recv failed: {}", e);
+                    continue;
+                }
+            };
+            let Some(Frame::Register(name)) = Frame::decode(&self.buf[..n]) else {
+                debug!("[rendezvous] ignored {} bytes from {}", n, from);
+                continue;
+            };
+            let peers = self.names.entry(name.to_string()).or_default();
+            peers.retain(|peer| now.saturating_sub(peer.last_seen) < REGISTRATION_TIMEOUT);
+            match peers.iter_mut().find(|peer| peer.addr == from) {
+                Some(peer) => peer.last_seen = now,
+                None => {
+                    info!("[rendezvous] {} registered as '{}'", from, name);
+                    // 同名的第三个对端顶替最早注册的
+                    if peers.len() == 2 {
+                        peers.remove(0);
+                    }
+                    peers.push(Registration {
+                        addr: from,
+                        last_seen: now,
+                    });
+                }
+            }
+            // 每次注册都重发，丢包时由对端的下一次注册补上
+            if let Some(other) = peers.iter().map(|peer| peer.addr).find(|&a| a != from) {
+                send_frame(&self.socket, &mut self.out, &Frame::Peer(other), from);
+                send_frame(&self.socket, &mut self.out, &Frame::Peer(from), other);
+            }
+        }
+    }
+}
+
+/// 隧道对端的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PunchRole {
+    /// 在本地地址上接收 UDP 客户端，经隧道转发给对端
+    Connect(SocketAddr),
+    /// 把对端发来的通道转发到目标地址
+    Serve(SocketAddr),
+}
+
+/// 隧道对端配置
+#[derive(Debug, Clone)]
+pub struct PunchConfig {
+    /// 会合服务器地址
+    pub server: SocketAddr,
+    /// 两端共用的名字
+    pub name: String,
+    pub role: PunchRole,
+    /// 通道空闲超时
+    pub timeout: Duration,
+}
+
+/// 通道的本地一端
+#[derive(Debug)]
+enum ChannelEnd {
+    /// 连接端：本地客户端地址
+    Client(SocketAddr),
+    /// 服务端：连接到目标的 socket
+    Remote(UdpSocket),
+}
+
+#[derive(Debug)]
+struct Channel {
+    end: ChannelEnd,
+    last_active: u64,
+}
+
+/// 隧道对端
+#[derive(Debug)]
+pub struct PunchPeer {
+    poll: Poll,
+    config: PunchConfig,
+    tunnel: UdpSocket,
+    /// 连接端的本地监听 socket
+    local: Option<UdpSocket>,
+    /// 会合服务器告知 (或打洞报文来源) 的对端地址
+    peer: Option<SocketAddr>,
+    connected: bool,
+    last_peer_recv: u64,
+    last_sent: u64,
+    last_register: Option<u64>,
+    channels: HashMap<u16, Channel>,
+    /// 连接端：客户端地址 -> 通道号
+    clients: HashMap<SocketAddr, u16>,
+    next_channel: u16,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl PunchPeer {
+    /// 绑定隧道 socket (与会合服务器同族、随机端口) 和连接端的本地监听 socket
+    pub fn bind(config: PunchConfig) -> io::Result<Self> {
+        if !is_valid_name(&config.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "punch id must be 1-{} bytes without control characters",
+                    MAX_NAME_LEN
+                ),
+            ));
+        }
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let mut tunnel = UdpSocket::bind(unspecified(&config.server))?;
+        poll.registry()
+            .register(&mut tunnel, TUNNEL_TOKEN, Interest::READABLE)?;
+        let local = match config.role {
+            PunchRole::Connect(addr) => {
+                let mut local = UdpSocket::bind(addr)?;
+                poll.registry()
+                    .register(&mut local, LOCAL_TOKEN, Interest::READABLE)?;
+                Some(local)
+            }
+            PunchRole::Serve(_) => None,
+        };
+        Ok(Self {
+            poll,
+            config,
+            tunnel,
+            local,
+            peer: None,
+            connected: false,
+            last_peer_recv: 0,
+            last_sent: 0,
+            last_register: None,
+            channels: HashMap::new(),
+            clients: HashMap::new(),
+            next_channel: 0,
+            buf: vec![0u8; BUF_SIZE],
+            out: Vec::new(),
+            running: Arc::new(AtomicBool::new(true)),
+            waker,
+        })
+    }
+
+    /// 连接端的本地监听地址
+    pub fn local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.local.as_ref().map(UdpSocket::local_addr)
+    }
+
+    pub fn handle(&self) -> PunchHandle {
+        PunchHandle {
+            running: Arc::clone(&self.running),
+            waker: Arc::clone(&self.waker),
+        }
+    }
+
+    /// 运行直到 `PunchHandle::stop`
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(256);
+        while self.running.load(Ordering::Relaxed) {
+            self.tick(get_monotonic_time());
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            let now = get_monotonic_time();
+            for event in events.iter() {
+                match event.token() {
+                    WAKER_TOKEN => {}
+                    TUNNEL_TOKEN => self.on_tunnel(now),
+                    LOCAL_TOKEN => self.on_local(now),
+                    Token(token) => self.on_remote((token - FIRST_CHANNEL_TOKEN) as u16, now),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 注册、打洞、保活和清理空闲通道
+    fn tick(&mut self, now: u64) {
+        if self.connected && now.saturating_sub(self.last_peer_recv) >= PEER_TIMEOUT {
+            warn!(
+                "[punch] no packets from peer {} for {}s, registering again",
+                self.peer.map_or_else(String::new, |peer| peer.to_string()),
+                PEER_TIMEOUT / 1000
+            );
+            self.connected = false;
+            self.peer = None;
+            self.last_register = None;
+            self.close_channels();
+        }
+        if !self.connected {
+            if self
+                .last_register
+                .is_none_or(|last| now.saturating_sub(last) >= REGISTER_INTERVAL)
+            {
+                let frame = Frame::Register(&self.config.name);
+                send_frame(&self.tunnel, &mut self.out, &frame, self.config.server);
+                self.last_register = Some(now);
+            }
+            if let Some(peer) = self.peer {
+                let frame = Frame::Punch(&self.config.name);
+                send_frame(&self.tunnel, &mut self.out, &frame, peer);
+            }
+        } else if now.saturating_sub(self.last_sent) >= KEEPALIVE_INTERVAL {
+            self.send_to_peer(&Frame::Keepalive, now);
+        }
+
+        let timeout = self.config.timeout.as_millis() as u64;
+        let expired: Vec<u16> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| now.saturating_sub(channel.last_active) >= timeout)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            debug!("[punch] channel {} idle, closed", id);
+            self.close_channel(id);
+        }
+    }
+
+    fn send_to_peer(&mut self, frame: &Frame<'_>, now: u64) {
+        if let Some(peer) = self.peer {
+            send_frame(&self.tunnel, &mut self.out, frame, peer);
+            self.last_sent = now;
+        }
+    }
+
+    /// 收到对端的有效报文
+    fn peer_alive(&mut self, from: SocketAddr, now: u64) {
+        self.last_peer_recv = now;
+        if !self.connected {
+            self.connected = true;
+            info!("[punch] direct path to peer {} established", from);
+        }
+    }
+
+    fn on_tunnel(&mut self, now: u64) {
+        loop {
+            let (n, from) = match self.tunnel.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // 打洞初期对端 NAT 尚未放行时常见 ICMP 不可达
+                    debug!("[punch] tunnel recv failed: {}", e);
+                    continue;
+                }
+            };
+            match Frame::decode(&self.buf[..n]) {
+                Some(Frame::Peer(addr)) if from == self.config.server => {
+                    if !self.connected && self.peer != Some(addr) {
+                        info!("[punch] peer endpoint {} from rendezvous server", addr);
+                        self.peer = Some(addr);
+                        let frame = Frame::Punch(&self.config.name);
+                        send_frame(&self.tunnel, &mut self.out, &frame, addr);
+                    }
+                }
+                // NAT 可能给对端换了端口，以打洞报文的实际来源为准
+                Some(Frame::Punch(name)) if name == self.config.name => {
+                    if self.peer != Some(from) {
+                        if self.connected {
+                            self.close_channels();
+                        }
+                        self.peer = Some(from);
+                        self.connected = false;
+                    }
+                    self.peer_alive(from, now);
+                    self.send_to_peer(&Frame::Keepalive, now);
+                }
+                Some(Frame::Keepalive) if self.peer == Some(from) => self.peer_alive(from, now),
+                Some(Frame::Data { channel, payload }) if self.peer == Some(from) => {
+                    let start = n - payload.len();
+                    self.peer_alive(from, now);
+                    self.deliver(channel, start, n, now);
+                }
+                _ => debug!("[punch] ignored {} bytes from {}", n, from),
+            }
+        }
+    }
+
+    /// 把隧道发来的 `buf[start..end]` 交给通道的本地一端
+    fn deliver(&mut self, id: u16, start: usize, end: usize, now: u64) {
+        if !self.channels.contains_key(&id) {
+            let PunchRole::Serve(target) = self.config.role else {
+                debug!("[punch] data for unknown channel {}", id);
+                return;
+            };
+            match self.open_remote(id, target) {
+                Ok(socket) => {
+                    debug!("[punch] channel {} opened to {}", id, target);
+                    self.channels.insert(
+                        id,
+                        Channel {
+                            end: ChannelEnd::Remote(socket),
+                            last_active: now,
+                        },
+                    );
+                }
+                Err(e) => {
+                    warn!("[punch] failed to open channel {} to {}: {}", id, target, e);
+                    return;
+                }
+            }
+        }
+        let Some(channel) = self.channels.get_mut(&id) else {
+            return;
+        };
+        channel.last_active = now;
+        let payload = &self.buf[start..end];
+        let result = match &channel.end {
+            ChannelEnd::Client(client) => match &self.local {
+                Some(local) => local.send_to(payload, *client),
+                None => return,
+            },
+            ChannelEnd::Remote(socket) => socket.send(payload),
+        };
+        if let Err(e) = result {
+            debug!("[punch] channel {} send failed: {}", id, e);
+        }
+    }
+
+    fn open_remote(&mut self, id: u16, target: SocketAddr) -> io::Result<UdpSocket> {
+        let mut socket = UdpSocket::bind(unspecified(&target))?;
+        socket.connect(target)?;
+        self.poll.registry().register(
+            &mut socket,
+            Token(FIRST_CHANNEL_TOKEN + usize::from(id)),
+            Interest::READABLE,
+        )?;
+        Ok(socket)
+    }
+
+    /// 连接端：本地客户端的数据报
+    fn on_local(&mut self, now: u64) {
+        loop {
+            let Some(local) = &self.local else {
+                return;
+            };
+            let (n, client) = match local.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("[punch] local recv failed: {}", e);
+                    continue;
+                }
+            };
+            if !self.connected {
+                debug!(
+                    "[punch] no direct path yet, dropped {} bytes from {}",
+                    n, client
+                );
+                continue;
+            }
+            let Some(id) = self.client_channel(client, now) else {
+                warn!("[punch] no free channel for {}", client);
+                continue;
+            };
+            let Some(peer) = self.peer else {
+                continue;
+            };
+            let frame = Frame::Data {
+                channel: id,
+                payload: &self.buf[..n],
+            };
+            send_frame(&self.tunnel, &mut self.out, &frame, peer);
+            self.last_sent = now;
+        }
+    }
+
+    /// 连接端：客户端对应的通道，没有时分配一个
+    fn client_channel(&mut self, client: SocketAddr, now: u64) -> Option<u16> {
+        if let Some(&id) = self.clients.get(&client) {
+            if let Some(channel) = self.channels.get_mut(&id) {
+                channel.last_active = now;
+            }
+            return Some(id);
+        }
+        let id = (0..=u16::MAX)
+            .map(|i| self.next_channel.wrapping_add(i))
+            .find(|id| !self.channels.contains_key(id))?;
+        self.next_channel = id.wrapping_add(1);
+        debug!("[punch] channel {} opened for {}", id, client);
+        self.clients.insert(client, id);
+        self.channels.insert(
+            id,
+            Channel {
+                end: ChannelEnd::Client(client),
+                last_active: now,
+            },
+        );
+        Some(id)
+    }
+
+    /// 服务端：目标的回复
+    fn on_remote(&mut self, id: u16, now: u64) {
+        loop {
+            let Some(channel) = self.channels.get_mut(&id) else {
+                return;
+            };
+            let ChannelEnd::Remote(socket) = &channel.end else {
+                return;
+            };
+            let n = match socket.recv(&mut self.buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("[punch] channel {} recv failed: {}", id, e);
+                    continue;
+                }
+            };
+            channel.last_active = now;
+            let Some(peer) = self.peer else {
+                continue;
+            };
+            let frame = Frame::Data {
+                channel: id,
+                payload: &self.buf[..n],
+            };
+            send_frame(&self.tunnel, &mut self.out, &frame, peer);
+            self.last_sent = now;
+        }
+    }
+
+    fn close_channel(&mut self, id: u16) {
+        let Some(channel) = self.channels.remove(&id) else {
+            return;
+        };
+        match channel.end {
+            ChannelEnd::Client(client) => {
+                self.clients.remove(&client);
+            }
+            ChannelEnd::Remote(mut socket) => {
+                let _ = self.poll.registry().deregister(&mut socket);
+            }
+        }
+    }
+
+    fn close_channels(&mut self) {
+        let ids: Vec<u16> = self.channels.keys().copied().collect();
+        for id in ids {
+            self.close_channel(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::{EchoMode, EchoServer};
+
+    #[test]
+    fn test_frame() {
+        let mut buf = Vec::new();
+        let frames = [
+            Frame::Register("home"),
+            Frame::Peer("203.0.113.7:40000".parse().unwrap()),
+            Frame::Peer("[2001:db8::1]:5000".parse().unwrap()),
+            Frame::Punch("home"),
+            Frame::Keepalive,
+            Frame::Data {
+                channel: 0x1234,
+                payload: b"hello",
+            },
+            Frame::Data {
+                channel: 0,
+                payload: b"",
+            },
+        ];
+        for frame in frames {
+            frame.encode(&mut buf);
+            assert_eq!(Frame::decode(&buf), Some(frame));
+        }
+        Frame::Data {
+            channel: 0x1234,
+            payload: b"x",
+        }
+        .encode(&mut buf);
+        assert_eq!(buf, b"TPMP\x05\x12\x34x");
+
+        assert_eq!(Frame::decode(b"TPMP"), None);
+        assert_eq!(Frame::decode(b"XPMP\x04"), None);
+        assert_eq!(Frame::decode(b"TPMP\x05\x00"), None);
+        assert_eq!(Frame::decode(b"TPMP\x01"), None);
+        assert_eq!(Frame::decode(b"TPMP\x02not-an-addr"), None);
+        assert_eq!(Frame::decode(b"TPMP\x09"), None);
+        assert!(!is_valid_name(&"x".repeat(MAX_NAME_LEN + 1)));
+        assert!(!is_valid_name("a\nb"));
+    }
+
+    #[test]
+    fn test_punch_loopback() {
+        let mut echo =
+            EchoServer::bind("127.0.0.1:0".parse().unwrap(), false, true, EchoMode::Echo)
+                .expect("bind echo server");
+        let echo_addr = echo.local_addr().expect("echo addr");
+        let echo_handle = echo.handle();
+        let echo_runner = std::thread::spawn(move || echo.run().expect("run echo server"));
+
+        let mut server =
+            RendezvousServer::bind("127.0.0.1:0".parse().unwrap()).expect("bind rendezvous server");
+        let server_addr = server.local_addr().expect("server addr");
+        let server_handle = server.handle();
+        let server_runner = std::thread::spawn(move || server.run().expect("run rendezvous"));
+
+        let config = |role| PunchConfig {
+            server: server_addr,
+            name: "loopback".to_string(),
+            role,
+            timeout: Duration::from_secs(30),
+        };
+        let mut serve = PunchPeer::bind(config(PunchRole::Serve(echo_addr))).expect("bind serve");
+        let mut connect =
+            PunchPeer::bind(config(PunchRole::Connect("127.0.0.1:0".parse().unwrap())))
+                .expect("bind connect");
+        assert!(serve.local_addr().is_none());
+        let local = connect.local_addr().unwrap().expect("local addr");
+        let handles = [serve.handle(), connect.handle()];
+        let runners = [
+            std::thread::spawn(move || serve.run().expect("run serve peer")),
+            std::thread::spawn(move || connect.run().expect("run connect peer")),
+        ];
+
+        // 打通之前的数据报被丢弃，重发直到收到回显
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .expect("set timeout");
+        let mut buf = [0u8; 64];
+        let mut echoed = None;
+        for _ in 0..50 {
+            client.send_to(b"through the hole", local).expect("send");
+            if let Ok((n, from)) = client.recv_from(&mut buf) {
+                echoed = Some((buf[..n].to_vec(), from));
+                break;
+            }
+        }
+        assert_eq!(echoed, Some((b"through the hole".to_vec(), local)));
+
+        for handle in &handles {
+            handle.stop();
+        }
+        for runner in runners {
+            runner.join().expect("join peer");
+        }
+        server_handle.stop();
+        server_runner.join().expect("join rendezvous");
+        echo_handle.stop();
+        echo_runner.join().expect("join echo");
+    }
+}
@@ -0,0 +1,74 @@
+//! QUIC 包头解析
+//!
+//! 只读取未加密的包头字段 (RFC 9000 17.2/17.3)：长包头中的目标/源连接 ID 和短包头开头的目标连接 ID。
+//! 握手后由 NEW_CONNECTION_ID 帧下发的连接 ID 经过加密，无法跟踪
+
+/// 连接 ID 最大长度
+pub const MAX_CID_LEN: usize = 20;
+
+/// 长包头 (Initial/0-RTT/Handshake/Retry) 中的连接 ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongHeader<'a> {
+    /// 目标连接 ID
+    pub dcid: &'a [u8],
+    /// 源连接 ID
+    pub scid: &'a [u8],
+}
+
+/// 解析长包头，不是长包头或格式不对时返回 None
+pub fn parse_long_header(packet: &[u8]) -> Option<LongHeader<'_>> {
+    // 首字节最高两位为 header form 和 fixed bit，之后是 4 字节版本号
+    if packet.len() < 7 || packet[0] & 0xc0 != 0xc0 {
+        return None;
+    }
+    let dcid_len = usize::from(packet[5]);
+    if dcid_len > MAX_CID_LEN {
+        return None;
+    }
+    let dcid = packet.get(6..6 + dcid_len)?;
+    let scid_len = usize::from(*packet.get(6 + dcid_len)?);
+    if scid_len > MAX_CID_LEN {
+        return None;
+    }
+    let scid = packet.get(7 + dcid_len..7 + dcid_len + scid_len)?;
+    Some(LongHeader { dcid, scid })
+}
+
+/// 短包头中长度为 `len` 的目标连接 ID (短包头不含连接 ID 长度，需要由调用方给出)
+pub fn short_header_dcid(packet: &[u8], len: usize) -> Option<&[u8]> {
+    if packet.first()? & 0xc0 != 0x40 || len == 0 || len > MAX_CID_LEN {
+        return None;
+    }
+    packet.get(1..1 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        // Initial: 版本 1，DCID 8 字节，SCID 4 字节，之后是 token 长度等
+        let mut initial = vec![0xc3, 0x00, 0x00, 0x00, 0x01, 8];
+        initial.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        initial.push(4);
+        initial.extend_from_slice(&[9, 9, 9, 9]);
+        initial.extend_from_slice(&[0x00, 0x41, 0x00]);
+        let header = parse_long_header(&initial).expect("long header");
+        assert_eq!(header.dcid, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(header.scid, &[9, 9, 9, 9]);
+        assert!(short_header_dcid(&initial, 8).is_none());
+
+        assert!(parse_long_header(&initial[..10]).is_none());
+        let mut bad = initial.clone();
+        bad[5] = 21;
+        assert!(parse_long_header(&bad).is_none());
+
+        let short = [0x41, 9, 9, 9, 9, 0xaa, 0xbb];
+        assert!(parse_long_header(&short).is_none());
+        assert_eq!(short_header_dcid(&short, 4), Some(&[9, 9, 9, 9][..]));
+        assert!(short_header_dcid(&short, 7).is_none());
+        assert!(short_header_dcid(&short, 0).is_none());
+        assert!(short_header_dcid(b"\x00abc", 2).is_none());
+    }
+}
@@ -0,0 +1,273 @@
+//! 限速模块
+//!
+//! 基于令牌桶的全局/租户/单连接带宽限制
+
+use crate::sync::{Mutex, Recover};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 令牌桶最小容量 (保证单个最大 UDP 包可以通过)
+pub const MIN_BUCKET_CAPACITY: u64 = 65536;
+
+/// 令牌桶
+///
+/// 令牌单位为字节，按 `rate` 字节/秒 匀速补充，最多累积 `capacity` 个
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// 补充速率 (字节/秒)
+    rate: u64,
+    /// 桶容量 (字节)
+    capacity: u64,
+    /// 当前令牌数
+    tokens: f64,
+    /// 上次补充时间
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// 创建新的令牌桶 (初始为满)
+    pub fn new(rate: u64) -> Self {
+        Self::new_at(rate, crate::clock::now())
+    }
+
+    /// 以指定时间点创建令牌桶
+    pub fn new_at(rate: u64, now: Instant) -> Self {
+        let capacity = rate.max(MIN_BUCKET_CAPACITY);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    /// 获取补充速率
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// 按经过的时间补充令牌
+    pub fn refill_at(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.capacity as f64);
+        self.last_refill = now;
+    }
+
+    /// 当前可用令牌数
+    pub fn available_at(&mut self, now: Instant) -> u64 {
+        self.refill_at(now);
+        self.tokens as u64
+    }
+
+    /// 消耗令牌 (允许透支，透支部分由后续补充抵扣)
+    pub fn consume_at(&mut self, bytes: usize, now: Instant) {
+        self.refill_at(now);
+        self.tokens -= bytes as f64;
+    }
+
+    /// 距离可用令牌达到 `bytes` 还需等待的时间
+    pub fn wait_time_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill_at(now);
+        let need = (bytes as u64).min(self.capacity) as f64 - self.tokens;
+        if need <= 0.0 || self.rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(need / self.rate as f64)
+    }
+}
+
+/// 限速器
+///
+/// 同时维护一个全局令牌桶和单连接速率，单连接令牌桶保存在各自的连接/会话中
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// 全局令牌桶
+    global: Option<Mutex<TokenBucket>>,
+    /// 租户令牌桶，由同一租户的所有映射共享 (可能在不同线程中)
+    tenant: Option<Arc<std::sync::Mutex<TokenBucket>>>,
+    /// 单连接速率 (字节/秒)
+    per_conn_rate: Option<u64>,
+}
+
+impl RateLimiter {
+    /// 创建限速器，没有任何限速时返回 None
+    pub fn new(
+        global_rate: Option<u64>,
+        per_conn_rate: Option<u64>,
+        tenant: Option<Arc<std::sync::Mutex<TokenBucket>>>,
+    ) -> Option<Self> {
+        if global_rate.is_none() && per_conn_rate.is_none() && tenant.is_none() {
+            return None;
+        }
+        Some(Self {
+            global: global_rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            tenant,
+            per_conn_rate,
+        })
+    }
+
+    /// 为新连接/会话创建单连接令牌桶
+    pub fn new_conn_bucket(&self) -> Option<TokenBucket> {
+        self.per_conn_rate.map(TokenBucket::new)
+    }
+
+    /// 计算本次最多可传输的字节数 (不超过 `want`)
+    pub fn allowance(&self, conn: Option<&mut TokenBucket>, want: usize) -> usize {
+        let now = crate::clock::now();
+        let mut allowance = want as u64;
+        if let Some(ref global) = self.global {
+            let mut bucket = global.lock().recover();
+            allowance = allowance.min(bucket.available_at(now));
+        }
+        if let Some(ref tenant) = self.tenant {
+            let mut bucket = tenant.lock().recover();
+            allowance = allowance.min(bucket.available_at(now));
+        }
+        if let Some(bucket) = conn {
+            allowance = allowance.min(bucket.available_at(now));
+        }
+        allowance as usize
+    }
+
+    /// 检查 `bytes` 字节能否立即通过 (用于 UDP 整包判断)
+    pub fn check(&self, conn: Option<&mut TokenBucket>, bytes: usize) -> bool {
+        self.allowance(conn, bytes) >= bytes
+    }
+
+    /// 单连接令牌桶是否不足 `bytes` 字节，用于区分客户端自身超速和全局限速
+    pub fn conn_limited(&self, conn: Option<&mut TokenBucket>, bytes: usize) -> bool {
+        conn.is_some_and(|bucket| bucket.available_at(crate::clock::now()) < bytes as u64)
+    }
+
+    /// 记录已传输的字节数
+    pub fn consume(&self, conn: Option<&mut TokenBucket>, bytes: usize) {
+        let now = crate::clock::now();
+        if let Some(ref global) = self.global {
+            global.lock().recover().consume_at(bytes, now);
+        }
+        if let Some(ref tenant) = self.tenant {
+            tenant.lock().recover().consume_at(bytes, now);
+        }
+        if let Some(bucket) = conn {
+            bucket.consume_at(bytes, now);
+        }
+    }
+
+    /// 令牌不足时需要等待的时间 (取全局、租户和单连接的最大值)
+    pub fn wait_time(&self, conn: Option<&mut TokenBucket>, bytes: usize) -> Duration {
+        let now = crate::clock::now();
+        let mut wait = Duration::ZERO;
+        if let Some(ref global) = self.global {
+            let mut bucket = global.lock().recover();
+            wait = wait.max(bucket.wait_time_at(bytes, now));
+        }
+        if let Some(ref tenant) = self.tenant {
+            let mut bucket = tenant.lock().recover();
+            wait = wait.max(bucket.wait_time_at(bytes, now));
+        }
+        if let Some(bucket) = conn {
+            wait = wait.max(bucket.wait_time_at(bytes, now));
+        }
+        wait
+    }
+}
+
+/// 解析速率参数
+///
+/// 单位为 字节/秒，支持 K/M/G 后缀 (1024 进制)，例如 `512K`、`10M`
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let value: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid rate: {}, expected e.g. 512K, 10M", s))?;
+    if value == 0 {
+        return Err("rate must be greater than 0".to_string());
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("rate too large: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert_eq!(parse_rate("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_rate("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("abc").is_err());
+        assert!(parse_rate("").is_err());
+    }
+
+    #[test]
+    fn test_bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(100_000, start);
+        assert_eq!(bucket.available_at(start), 100_000);
+
+        bucket.consume_at(100_000, start);
+        assert_eq!(bucket.available_at(start), 0);
+
+        // 半秒后补充一半
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.available_at(later), 50_000);
+
+        // 不超过容量
+        let much_later = start + Duration::from_secs(10);
+        assert_eq!(bucket.available_at(much_later), 100_000);
+    }
+
+    #[test]
+    fn test_bucket_wait_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(100_000, start);
+        assert_eq!(bucket.wait_time_at(1000, start), Duration::ZERO);
+
+        bucket.consume_at(100_000, start);
+        let wait = bucket.wait_time_at(10_000, start);
+        assert_eq!(wait, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_min_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(1024, start);
+        assert_eq!(bucket.available_at(start), MIN_BUCKET_CAPACITY);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::new(None, None, None).is_none());
+
+        let limiter = RateLimiter::new(None, Some(100_000), None).expect("limiter");
+        let mut conn = limiter.new_conn_bucket();
+        assert!(conn.is_some());
+        assert_eq!(limiter.allowance(conn.as_mut(), 4096), 4096);
+        limiter.consume(conn.as_mut(), 100_000);
+        assert!(!limiter.check(conn.as_mut(), 4096));
+        assert!(limiter.wait_time(conn.as_mut(), 4096) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_tenant_bucket() {
+        // 两个映射共享同一个租户令牌桶
+        let tenant = Arc::new(std::sync::Mutex::new(TokenBucket::new(100_000)));
+        let a = RateLimiter::new(None, None, Some(Arc::clone(&tenant))).expect("limiter");
+        let b = RateLimiter::new(None, None, Some(tenant)).expect("limiter");
+        assert_eq!(b.allowance(None, 4096), 4096);
+        a.consume(None, 100_000);
+        assert!(!b.check(None, 4096));
+        assert!(b.wait_time(None, 4096) > Duration::ZERO);
+    }
+}
@@ -0,0 +1,572 @@
+//! seccomp 沙箱 (仅 Linux)
+//!
+//! 初始化完成后安装 seccomp-bpf 过滤器，只允许事件循环转发数据所需的系统调用，
+//! 其他系统调用 (execve、ptrace、mount 等) 返回 EPERM。过滤器同步到进程内所有线程，
+//! 之后创建的线程 (健康检查等) 自动继承
+//!
+//! socket 只能创建 IPv4/IPv6/Unix/vsock 的流或数据报 socket，ioctl 只允许用到的请求；
+//! connect 的目标地址在用户内存中，seccomp 无法检查。监听以及打开、重命名和删除文件的
+//! 系统调用只在 [`Allow`] 中对应的选项开启时允许
+
+/// 按启用的选项额外允许的系统调用
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Allow {
+    /// --sockmap：运行时更新 sockmap (bpf)，查询 socket 队列长度 (ioctl FIONREAD/TIOCOUTQ)
+    pub bpf: bool,
+    /// --log-file：重新打开日志文件 (openat)
+    pub log_file: bool,
+    /// --stats-file：保存统计状态文件 (openat、renameat)
+    pub stats_file: bool,
+    /// 监听 Unix 域 socket 或 --upgrade：退出时删除 socket 文件 (unlinkat)
+    pub unlink: bool,
+    /// --top：查询终端大小 (ioctl TIOCGWINSZ)
+    pub terminal: bool,
+    /// --ftp-alg：为数据通道创建临时监听 (listen)
+    pub ftp_alg: bool,
+    /// --webhook、--upnp：运行时解析域名 (读取 /etc/resolv.conf、/etc/hosts 等，uname)
+    /// 和读取 /proc/net/route，只允许只读打开 (openat)
+    pub read_files: bool,
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use imp::install;
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) use imp::run_isolated;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod imp {
+    use super::Allow;
+    use std::io;
+
+    /// 安装 seccomp 过滤器，`allow` 中开启的选项额外允许对应的系统调用
+    pub fn install(allow: Allow) -> io::Result<()> {
+        let mut program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+        let prog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_mut_ptr(),
+        };
+
+        // 非特权进程安装过滤器必须先设置 no_new_privs
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            // TSYNC 失败时返回无法同步的线程 ID
+            tid if tid > 0 => Err(io::Error::other(format!(
+                "failed to synchronize seccomp filter to thread {}",
+                tid
+            ))),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// x32 ABI 的系统调用号标志位，x86_64 上需要拒绝以免绕过过滤器
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// 允许的系统调用
+    const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        // 内存
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // 线程、同步和信号
+        libc::SYS_futex,
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_gettid,
+        libc::SYS_getpid,
+        libc::SYS_tgkill,
+        libc::SYS_prctl,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_sigaltstack,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // 时间
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_getrandom,
+        // 事件循环
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_pwait2,
+        libc::SYS_epoll_ctl,
+        libc::SYS_ppoll,
+        // socket (socket 按参数过滤，见 arg_rules)
+        libc::SYS_connect,
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_shutdown,
+        libc::SYS_recvfrom,
+        libc::SYS_sendto,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_sendmmsg,
+        // 读写和 fd
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_fcntl,
+        libc::SYS_splice,
+        libc::SYS_pipe2,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_fsync,
+        // x86_64 上的旧系统调用 (aarch64 上不存在)
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_accept,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_arch_prctl,
+    ];
+
+    /// 按 `allow` 组合允许的系统调用
+    fn allowed_syscalls(allow: Allow) -> Vec<libc::c_long> {
+        let mut allowed = ALLOWED_SYSCALLS.to_vec();
+        if allow.bpf {
+            allowed.push(libc::SYS_bpf);
+        }
+        if allow.log_file || allow.stats_file {
+            allowed.push(libc::SYS_openat);
+            #[cfg(target_arch = "x86_64")]
+            allowed.push(libc::SYS_open);
+        }
+        if allow.stats_file {
+            allowed.extend([libc::SYS_renameat, libc::SYS_renameat2]);
+            #[cfg(target_arch = "x86_64")]
+            allowed.push(libc::SYS_rename);
+        }
+        if allow.unlink {
+            allowed.push(libc::SYS_unlinkat);
+            #[cfg(target_arch = "x86_64")]
+            allowed.push(libc::SYS_unlink);
+        }
+        if allow.ftp_alg {
+            allowed.push(libc::SYS_listen);
+        }
+        if allow.read_files {
+            allowed.push(libc::SYS_uname);
+        }
+        allowed
+    }
+
+    /// 参数检查：第 `arg` 个参数的低 32 位与 `mask` 按位与后须等于 `values` 之一
+    struct ArgCheck {
+        arg: u32,
+        mask: u32,
+        values: Vec<u32>,
+    }
+
+    /// 按参数过滤的系统调用，所有检查都通过时允许
+    struct ArgRule {
+        nr: libc::c_long,
+        checks: Vec<ArgCheck>,
+    }
+
+    /// socket 只允许 IP/Unix/vsock 的流和数据报 socket，ioctl 只允许用到的请求，
+    /// `read_files` 开启时允许只读打开文件 (openat 已整体允许时规则不起作用)
+    ///
+    /// 这些参数在内核中都是 32 位的，只检查低 32 位即可
+    fn arg_rules(allow: Allow) -> Vec<ArgRule> {
+        let mut ioctls = vec![libc::FIONBIO as u32];
+        if allow.bpf {
+            ioctls.extend([libc::FIONREAD as u32, libc::TIOCOUTQ as u32]);
+        }
+        if allow.terminal {
+            ioctls.push(libc::TIOCGWINSZ as u32);
+        }
+        let mut rules = vec![
+            ArgRule {
+                nr: libc::SYS_socket,
+                checks: vec![
+                    ArgCheck {
+                        arg: 0,
+                        mask: u32::MAX,
+                        values: [libc::AF_INET, libc::AF_INET6, libc::AF_UNIX, libc::AF_VSOCK]
+                            .map(|family| family as u32)
+                            .to_vec(),
+                    },
+                    ArgCheck {
+                        arg: 1,
+                        mask: !((libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as u32),
+                        values: vec![libc::SOCK_STREAM as u32, libc::SOCK_DGRAM as u32],
+                    },
+                ],
+            },
+            ArgRule {
+                nr: libc::SYS_ioctl,
+                checks: vec![ArgCheck {
+                    arg: 1,
+                    mask: u32::MAX,
+                    values: ioctls,
+                }],
+            },
+        ];
+        if allow.read_files {
+            // 访问模式须为只读，且不能创建或截断文件
+            let read_only = |arg| ArgCheck {
+                arg,
+                mask: (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u32,
+                values: vec![libc::O_RDONLY as u32],
+            };
+            rules.push(ArgRule {
+                nr: libc::SYS_openat,
+                checks: vec![read_only(2)],
+            });
+            #[cfg(target_arch = "x86_64")]
+            rules.push(ArgRule {
+                nr: libc::SYS_open,
+                checks: vec![read_only(1)],
+            });
+        }
+        rules
+    }
+
+    /// 在子进程中单独重新运行测试 `test`，子进程执行 `body`，由它在合适的时机安装沙箱
+    ///
+    /// 过滤器对整个进程生效且无法卸载，不能安装在运行其他测试的进程中
+    #[cfg(test)]
+    pub(crate) fn run_isolated(test: &str, body: impl FnOnce()) {
+        const CHILD_ENV: &str = "TPM_SANDBOX_TEST";
+        if std::env::var_os(CHILD_ENV).is_some_and(|name| name == test) {
+            body();
+            return;
+        }
+        let exe = std::env::current_exe().expect("test binary");
+        let output = std::process::Command::new(exe)
+            .args([test, "--exact", "--test-threads=1", "--nocapture"])
+            .env(CHILD_ENV, test)
+            .output()
+            .expect("run test in child process");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success() && stdout.contains("1 passed"),
+            "{} failed in sandbox ({}):\n{}{}",
+            test,
+            output.status,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// seccomp_data 中系统调用号和架构字段的偏移
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// seccomp_data 中第 `arg` 个参数低 32 位的偏移 (小端)
+    fn arg_offset(arg: u32) -> u32 {
+        16 + 8 * arg
+    }
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// 生成 BPF 程序：检查架构，允许列表中的系统调用和参数检查通过的系统调用，其余返回 EPERM
+    fn build_filter(allowed: &[libc::c_long], rules: &[ArgRule]) -> Vec<libc::sock_filter> {
+        use libc::{
+            BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+        };
+
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut program = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET | BPF_K, deny),
+        ]);
+        for &nr in allowed {
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+            program.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+        }
+        for rule in rules {
+            // 每个检查：加载参数、可选的按位与、逐个比较 (命中时跳过余下比较和拒绝)、拒绝
+            let mut body = Vec::new();
+            for check in &rule.checks {
+                body.push(stmt(BPF_LD | BPF_W | BPF_ABS, arg_offset(check.arg)));
+                if check.mask != u32::MAX {
+                    body.push(stmt(BPF_ALU | BPF_AND | BPF_K, check.mask));
+                }
+                let n = check.values.len();
+                for (i, &value) in check.values.iter().enumerate() {
+                    body.push(jump(BPF_JMP | BPF_JEQ | BPF_K, value, (n - i) as u8, 0));
+                }
+                body.push(stmt(BPF_RET | BPF_K, deny));
+            }
+            body.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+            // 系统调用号不匹配时跳过整条规则，累加器中仍是系统调用号
+            program.push(jump(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                rule.nr as u32,
+                0,
+                body.len() as u8,
+            ));
+            program.extend(body);
+        }
+        program.push(stmt(BPF_RET | BPF_K, deny));
+        program
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// 按 seccomp 的语义解释执行过滤器，返回动作
+        fn run(program: &[libc::sock_filter], nr: libc::c_long, args: [u64; 6]) -> u32 {
+            let mut data = [0u8; 64];
+            data[0..4].copy_from_slice(&(nr as u32).to_le_bytes());
+            data[4..8].copy_from_slice(&AUDIT_ARCH.to_le_bytes());
+            for (i, arg) in args.iter().enumerate() {
+                data[16 + 8 * i..24 + 8 * i].copy_from_slice(&arg.to_le_bytes());
+            }
+            let mut acc = 0u32;
+            let mut pc = 0;
+            loop {
+                let insn = program[pc];
+                let code = u32::from(insn.code);
+                pc += 1;
+                match code {
+                    c if c == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                        let k = insn.k as usize;
+                        acc = u32::from_le_bytes(data[k..k + 4].try_into().expect("word"));
+                    }
+                    c if c == libc::BPF_ALU | libc::BPF_AND | libc::BPF_K => acc &= insn.k,
+                    c if c == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                        pc += usize::from(if acc == insn.k { insn.jt } else { insn.jf });
+                    }
+                    c if c == libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K => {
+                        pc += usize::from(if acc >= insn.k { insn.jt } else { insn.jf });
+                    }
+                    c if c == libc::BPF_RET | libc::BPF_K => return insn.k,
+                    _ => panic!("unexpected instruction {:#x}", code),
+                }
+            }
+        }
+
+        const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        #[test]
+        fn test_build_filter() {
+            let allow = Allow::default();
+            let program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+            // BPF 程序长度上限为 4096 条指令
+            assert!(program.len() < 4096);
+            assert_eq!(program[0].k, ARCH_OFFSET);
+            assert_eq!(program[1].k, AUDIT_ARCH);
+            let last = program.last().expect("instruction");
+            assert_eq!(last.k, DENY);
+
+            let allowed = |nr| run(&program, nr, [0; 6]) == libc::SECCOMP_RET_ALLOW;
+            assert!(allowed(libc::SYS_read));
+            assert!(allowed(libc::SYS_epoll_pwait));
+            assert!(!allowed(libc::SYS_execve));
+            // 默认不允许文件操作
+            assert!(!allowed(libc::SYS_openat));
+            assert!(!allowed(libc::SYS_renameat));
+            assert!(!allowed(libc::SYS_unlinkat));
+            assert!(!allowed(libc::SYS_bpf));
+            assert!(!allowed(libc::SYS_listen));
+        }
+
+        #[test]
+        fn test_allow_files() {
+            let allow = Allow {
+                log_file: true,
+                ..Allow::default()
+            };
+            let allowed = allowed_syscalls(allow);
+            assert!(allowed.contains(&libc::SYS_openat));
+            assert!(!allowed.contains(&libc::SYS_renameat));
+            assert!(!allowed.contains(&libc::SYS_unlinkat));
+
+            let allow = Allow {
+                stats_file: true,
+                unlink: true,
+                bpf: true,
+                ..Allow::default()
+            };
+            let allowed = allowed_syscalls(allow);
+            for nr in [
+                libc::SYS_openat,
+                libc::SYS_renameat,
+                libc::SYS_renameat2,
+                libc::SYS_unlinkat,
+                libc::SYS_bpf,
+            ] {
+                assert!(allowed.contains(&nr));
+            }
+            assert!(!allowed.contains(&libc::SYS_listen));
+
+            let allow = Allow {
+                ftp_alg: true,
+                ..Allow::default()
+            };
+            assert!(allowed_syscalls(allow).contains(&libc::SYS_listen));
+        }
+
+        // ioctl 请求常量的类型随 libc 变化 (glibc 为 c_ulong，musl 为 c_int)
+        #[allow(clippy::unnecessary_cast)]
+        #[test]
+        fn test_arg_rules() {
+            let allow = Allow::default();
+            let program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+            let socket = |family: libc::c_int, ty: libc::c_int| {
+                run(
+                    &program,
+                    libc::SYS_socket,
+                    [family as u64, ty as u64, 0, 0, 0, 0],
+                )
+            };
+            let flags = libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+            assert_eq!(
+                socket(libc::AF_INET, libc::SOCK_STREAM),
+                libc::SECCOMP_RET_ALLOW
+            );
+            assert_eq!(
+                socket(libc::AF_INET6, libc::SOCK_DGRAM | flags),
+                libc::SECCOMP_RET_ALLOW
+            );
+            assert_eq!(
+                socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC),
+                libc::SECCOMP_RET_ALLOW
+            );
+            assert_eq!(
+                socket(libc::AF_VSOCK, libc::SOCK_STREAM),
+                libc::SECCOMP_RET_ALLOW
+            );
+            assert_eq!(socket(libc::AF_PACKET, libc::SOCK_DGRAM), DENY);
+            assert_eq!(socket(libc::AF_NETLINK, libc::SOCK_DGRAM), DENY);
+            assert_eq!(socket(libc::AF_INET, libc::SOCK_RAW), DENY);
+
+            let ioctl = |program: &[libc::sock_filter], request: u64| {
+                run(program, libc::SYS_ioctl, [0, request, 0, 0, 0, 0])
+            };
+            assert_eq!(
+                ioctl(&program, libc::FIONBIO as u64),
+                libc::SECCOMP_RET_ALLOW
+            );
+            assert_eq!(ioctl(&program, libc::FIONREAD as u64), DENY);
+            assert_eq!(ioctl(&program, libc::TIOCSTI as u64), DENY);
+            assert_eq!(ioctl(&program, libc::TIOCGWINSZ as u64), DENY);
+
+            let allow = Allow {
+                bpf: true,
+                terminal: true,
+                ..Allow::default()
+            };
+            let program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+            for request in [libc::FIONREAD, libc::TIOCOUTQ, libc::TIOCGWINSZ] {
+                assert_eq!(ioctl(&program, request as u64), libc::SECCOMP_RET_ALLOW);
+            }
+            assert_eq!(ioctl(&program, libc::TIOCSTI as u64), DENY);
+            // 其他系统调用仍被拒绝
+            assert_eq!(run(&program, libc::SYS_execve, [0; 6]), DENY);
+
+            let openat = |program: &[libc::sock_filter], flags: libc::c_int| {
+                run(program, libc::SYS_openat, [0, 0, flags as u64, 0, 0, 0])
+            };
+            let read_only = libc::O_RDONLY | libc::O_CLOEXEC;
+            assert_eq!(openat(&program, read_only), DENY);
+            let allow = Allow {
+                read_files: true,
+                ..Allow::default()
+            };
+            let program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+            assert_eq!(openat(&program, read_only), libc::SECCOMP_RET_ALLOW);
+            for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_CREAT] {
+                assert_eq!(openat(&program, flags | libc::O_CLOEXEC), DENY);
+            }
+            // 日志文件需要写入，整体允许 openat
+            let allow = Allow {
+                read_files: true,
+                log_file: true,
+                ..Allow::default()
+            };
+            let program = build_filter(&allowed_syscalls(allow), &arg_rules(allow));
+            assert_eq!(
+                openat(&program, libc::O_WRONLY | libc::O_APPEND),
+                libc::SECCOMP_RET_ALLOW
+            );
+        }
+    }
+}
+
+/// 安装 seccomp 过滤器 (当前平台不支持)
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn install(_allow: Allow) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "seccomp sandbox is only supported on Linux x86_64/aarch64",
+    ))
+}
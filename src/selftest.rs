@@ -0,0 +1,1384 @@
+//! 端到端回环自测
+//!
+//! `Harness` 在进程内启动回显后端 (`EchoServer`) 和一个 `PortMapper`，经 127.0.0.1 上的真实 socket
+//! 转发 TCP 和 UDP；测试用例和 `--run-test` 共用，后者在目标设备上快速确认转发可用
+
+use crate::echo::{EchoHandle, EchoMode, EchoServer};
+use crate::error::Error;
+use crate::mapper::{PortMapperBuilder, PortMapperHandle};
+use crate::stats::StatsSnapshot;
+use crate::PortMapper;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// 自测中单次读写的超时时间
+pub const SELFTEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 回环转发测试环境，drop 时停止映射和回显后端
+#[derive(Debug)]
+pub struct Harness {
+    listen_addr: SocketAddr,
+    backend_addr: SocketAddr,
+    handle: PortMapperHandle,
+    runner: Option<JoinHandle<Result<(), Error>>>,
+    echo: Option<(EchoHandle, JoinHandle<io::Result<()>>)>,
+}
+
+impl Harness {
+    /// 启动回显后端，并把 `builder` 的监听地址和远程地址指向回显后端后启动映射
+    pub fn start(builder: PortMapperBuilder) -> Result<Self, Error> {
+        Self::with_echo(|backend_addr| Self::with_backend(builder, backend_addr))
+    }
+
+    /// 启动回显后端和 SOCKS5 服务端模式的映射，客户端请求连接 `backend_addr()` 即可收到回显
+    pub fn socks5(builder: PortMapperBuilder) -> Result<Self, Error> {
+        Self::with_echo(|backend_addr| {
+            let listen_addr =
+                free_addr().map_err(|e| Error::socket("failed to find a free port", e))?;
+            let builder = builder.socks5_listen(&listen_addr.to_string());
+            Self::spawn(builder, listen_addr, backend_addr)
+        })
+    }
+
+    /// 启动回显后端，再由 `start` 启动转发到它的映射
+    fn with_echo(start: impl FnOnce(SocketAddr) -> Result<Self, Error>) -> Result<Self, Error> {
+        let mut echo = EchoServer::bind(loopback(0), true, true, EchoMode::Echo)
+            .map_err(|e| Error::socket("failed to start echo backend", e))?;
+        let backend_addr = echo
+            .local_addr()
+            .map_err(|e| Error::socket("failed to get echo backend address", e))?;
+        let echo_handle = echo.handle();
+        let echo_runner = thread::spawn(move || echo.run());
+        let mut harness = start(backend_addr);
+        match &mut harness {
+            Ok(harness) => harness.echo = Some((echo_handle, echo_runner)),
+            Err(_) => {
+                echo_handle.stop();
+                let _ = echo_runner.join();
+            }
+        }
+        harness
+    }
+
+    /// 启动映射，转发到调用方提供的后端
+    pub fn with_backend(
+        builder: PortMapperBuilder,
+        backend_addr: SocketAddr,
+    ) -> Result<Self, Error> {
+        let listen_addr =
+            free_addr().map_err(|e| Error::socket("failed to find a free port", e))?;
+        let builder = builder
+            .listen(&listen_addr.to_string())
+            .remote(&backend_addr.to_string());
+        Self::spawn(builder, listen_addr, backend_addr)
+    }
+
+    /// 在新线程中创建并运行映射
+    fn spawn(
+        builder: PortMapperBuilder,
+        listen_addr: SocketAddr,
+        backend_addr: SocketAddr,
+    ) -> Result<Self, Error> {
+        // PortMapper 在 single-thread 特性下不是 Send，在运行它的线程中创建
+        let (tx, rx) = mpsc::channel();
+        let runner = thread::spawn(move || {
+            let mut mapper = match builder.build() {
+                Ok(mapper) => mapper,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return Ok(());
+                }
+            };
+            let _ = tx.send(Ok(mapper.handle()));
+            mapper.run()
+        });
+        let handle = match rx.recv() {
+            Ok(handle) => handle?,
+            Err(_) => {
+                return Err(match runner.join() {
+                    Ok(Err(e)) => e,
+                    _ => Error::config("port mapper thread exited"),
+                })
+            }
+        };
+        Ok(Self {
+            listen_addr,
+            backend_addr,
+            handle,
+            runner: Some(runner),
+            echo: None,
+        })
+    }
+
+    /// 映射的监听地址 (TCP 和 UDP 相同)
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// 后端地址
+    pub fn backend_addr(&self) -> SocketAddr {
+        self.backend_addr
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.handle.stats()
+    }
+
+    /// 映射的控制句柄
+    pub fn handle(&self) -> &PortMapperHandle {
+        &self.handle
+    }
+
+    /// 等待统计满足条件，超时返回 false
+    pub fn wait_until(&self, timeout: Duration, cond: impl Fn(&StatsSnapshot) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if cond(&self.stats()) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// 停止映射和回显后端，返回映射运行的结果
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.handle.stop();
+        let result = match self.runner.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(Error::config("port mapper thread panicked")),
+            None => Ok(()),
+        };
+        if let Some((handle, runner)) = self.echo.take() {
+            handle.stop();
+            let _ = runner.join();
+        }
+        result
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+fn loopback(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// 找一个 TCP 和 UDP 都空闲的回环端口
+fn free_addr() -> io::Result<SocketAddr> {
+    for _ in 0..64 {
+        let addr = TcpListener::bind(loopback(0))?.local_addr()?;
+        if UdpSocket::bind(addr).is_ok() {
+            return Ok(addr);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free loopback port for both TCP and UDP",
+    ))
+}
+
+/// 可校验的测试数据，`seed` 不同的流内容不同
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i % 251) as u8 ^ seed.wrapping_mul(31))
+        .collect()
+}
+
+/// 经 `addr` 发送 `len` 字节并校验回显，读写并行以免双方缓冲区写满
+pub fn check_tcp_echo(addr: SocketAddr, len: usize, seed: u8) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, SELFTEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(SELFTEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(SELFTEST_TIMEOUT))?;
+    let data = pattern(len, seed);
+    let mut reader = stream.try_clone()?;
+    let expected = data.clone();
+    let reader = thread::spawn(move || -> io::Result<()> {
+        let mut echoed = vec![0u8; expected.len()];
+        reader.read_exact(&mut echoed)?;
+        if echoed != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tcp echo does not match",
+            ));
+        }
+        Ok(())
+    });
+    stream.write_all(&data)?;
+    reader
+        .join()
+        .map_err(|_| io::Error::other("tcp echo reader panicked"))?
+}
+
+/// 经 `addr` 发送一组大小的数据报并校验回显
+pub fn check_udp_echo(addr: SocketAddr, sizes: &[usize]) -> io::Result<()> {
+    let socket = UdpSocket::bind(loopback(0))?;
+    socket.set_read_timeout(Some(SELFTEST_TIMEOUT))?;
+    let mut buf = vec![0u8; 65536];
+    for (i, &size) in sizes.iter().enumerate() {
+        let data = pattern(size, i as u8);
+        socket.send_to(&data, addr)?;
+        let (n, from) = socket.recv_from(&mut buf)?;
+        if from != addr || buf[..n] != data[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("udp echo of {} bytes does not match", size),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `--run-test` 的回环转发自测：TCP 和 UDP 各转发一次并校验数据
+pub fn run() -> Result<(), String> {
+    let harness = Harness::start(PortMapper::builder().tcp(true).udp(true))
+        .map_err(|e| format!("failed to start: {}", e))?;
+    let addr = harness.listen_addr();
+    check_tcp_echo(addr, 1 << 20, 1).map_err(|e| format!("tcp forwarding: {}", e))?;
+    check_udp_echo(addr, &[1, 1400, 9000]).map_err(|e| format!("udp forwarding: {}", e))?;
+    harness.stop().map_err(|e| format!("failed to stop: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).expect("connect");
+        stream
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        stream
+    }
+
+    #[test]
+    fn test_data_integrity() {
+        // 流量统计按租户全局共享，单独的租户避免与并行的测试互相影响
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .tenant("selftest-integrity"),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        // 并发的多条流，每条都超过套接字缓冲区
+        let streams: Vec<_> = (0..4)
+            .map(|seed| thread::spawn(move || check_tcp_echo(addr, 4 << 20, seed)))
+            .collect();
+        for stream in streams {
+            stream.join().expect("join stream").expect("tcp echo");
+        }
+        check_udp_echo(addr, &[1, 512, 1472, 9000, 65507]).expect("udp echo");
+
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| {
+            s.tcp_bytes_c2s == 4 * (4 << 20) && s.tcp_bytes_s2c == 4 * (4 << 20)
+        }));
+        assert_eq!(harness.stats().udp_sessions, 1);
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_eof_flushes_pending_data() {
+        // 后端写完数据立即关闭：客户端应收到全部数据后再读到 EOF
+        const LEN: usize = 8 << 20;
+        let backend = TcpListener::bind(loopback(0)).expect("bind backend");
+        let backend_addr = backend.local_addr().expect("backend addr");
+        let writer = thread::spawn(move || {
+            let (mut stream, _) = backend.accept().expect("accept");
+            stream.write_all(&pattern(LEN, 7)).expect("write");
+        });
+        let harness = Harness::with_backend(
+            PortMapper::builder().tcp(true).socket_buf_size(64 * 1024),
+            backend_addr,
+        )
+        .expect("start");
+
+        let mut stream = connect(harness.listen_addr());
+        let mut received = Vec::with_capacity(LEN);
+        stream.read_to_end(&mut received).expect("read to EOF");
+        writer.join().expect("join writer");
+        assert!(received == pattern(LEN, 7));
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| s.tcp_connections == 0));
+
+        // 客户端关闭时连接同样被回收
+        let harness = Harness::start(PortMapper::builder().tcp(true)).expect("start");
+        let mut stream = connect(harness.listen_addr());
+        stream.write_all(b"ping").expect("write");
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).expect("read");
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| s.tcp_connections == 1));
+        drop(stream);
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| s.tcp_connections == 0));
+    }
+
+    #[test]
+    fn test_timeout_reaping() {
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .tcp_timeout(Duration::from_secs(1))
+                .udp_timeout(Duration::from_secs(1)),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        let mut stream = connect(addr);
+        stream.write_all(b"ping").expect("write");
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).expect("read");
+        check_udp_echo(addr, &[64]).expect("udp echo");
+        let stats = harness.stats();
+        assert_eq!((stats.tcp_connections, stats.udp_sessions), (1, 1));
+
+        // 空闲超时后两者都被清理，客户端读到 EOF
+        assert!(harness.wait_until(Duration::from_secs(5), |s| {
+            s.tcp_connections == 0 && s.udp_sessions == 0
+        }));
+        assert_eq!(stream.read(&mut buf).expect("read EOF"), 0);
+    }
+
+    #[test]
+    fn test_mock_clock_timeout() {
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .tcp_timeout(Duration::from_secs(1))
+                .udp_timeout(Duration::from_secs(1))
+                .clock(clock.clone()),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        let mut stream = connect(addr);
+        stream.write_all(b"ping").expect("write");
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).expect("read");
+        check_udp_echo(addr, &[64]).expect("udp echo");
+
+        // 真实时间超过超时也不清理，只随模拟时钟推进
+        assert!(!harness.wait_until(Duration::from_millis(1500), |s| {
+            s.tcp_connections == 0 || s.udp_sessions == 0
+        }));
+        clock.advance(Duration::from_secs(5));
+        assert!(harness.wait_until(Duration::from_secs(5), |s| {
+            s.tcp_connections == 0 && s.udp_sessions == 0
+        }));
+        assert_eq!(stream.read(&mut buf).expect("read EOF"), 0);
+    }
+
+    #[test]
+    fn test_udp_keepalive() {
+        use crate::clock::MockClock;
+
+        let backend = UdpSocket::bind(loopback(0)).expect("bind backend");
+        backend
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let clock = MockClock::new();
+        let harness = Harness::with_backend(
+            PortMapper::builder()
+                .udp(true)
+                .udp_keepalive("1,ff".parse().unwrap())
+                .clock(clock.clone()),
+            backend.local_addr().expect("backend addr"),
+        )
+        .expect("start");
+
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client.send_to(b"hi", harness.listen_addr()).expect("send");
+        let mut buf = [0u8; 16];
+        let (len, session) = backend.recv_from(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"hi");
+
+        // 客户端空闲超过间隔后，从同一个会话 socket 发出保活数据包
+        // 后端收到数据包时映射可能还没记录发送时间，稍等后再推进模拟时钟
+        thread::sleep(Duration::from_millis(100));
+        clock.advance(Duration::from_secs(2));
+        let (len, from) = backend.recv_from(&mut buf).expect("recv keepalive");
+        assert_eq!((&buf[..len], from), (&[0xff][..], session));
+        assert_eq!(harness.stats().udp_sessions, 1);
+        harness.stop().expect("stop");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_udp_recverr() {
+        // 没有进程监听的端口，内核回复 ICMP 端口不可达
+        let closed = free_addr().expect("closed addr");
+        let harness = Harness::with_backend(
+            PortMapper::builder()
+                .udp(true)
+                .udp_recverr(true)
+                .tenant("selftest-recverr"),
+            closed,
+        )
+        .expect("start");
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client.send_to(b"hi", harness.listen_addr()).expect("send");
+
+        // 不必等待 UDP 超时，会话在收到 ICMP 错误后立即关闭
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| {
+            s.udp_bytes_c2s == 2 && s.udp_sessions == 0
+        }));
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_udp_mtu() {
+        let builder = PortMapper::builder()
+            .listen("127.0.0.1:0")
+            .remote("127.0.0.1:9");
+        assert!(builder.udp(true).udp_mtu(20).build().is_err());
+
+        // 100 字节的 IPv4 包最多带 72 字节负载，完整到达的超长数据包即使启用 -d 也丢弃
+        let harness = Harness::start(
+            PortMapper::builder()
+                .udp(true)
+                .udp_fragment(true)
+                .udp_mtu(100),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+        check_udp_echo(addr, &[72]).expect("udp echo");
+        let socket = UdpSocket::bind(loopback(0)).expect("bind");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .expect("set timeout");
+        socket.send_to(&[0u8; 73], addr).expect("send");
+        assert!(socket.recv_from(&mut [0u8; 128]).is_err());
+        harness.stop().expect("stop");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_udp_preserve_tos_ttl() {
+        use crate::ipheader::{self, IpHeader};
+        use crate::types::Address;
+        use std::os::unix::io::AsRawFd;
+
+        let backend = UdpSocket::bind(loopback(0)).expect("bind backend");
+        backend
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .expect("set timeout");
+        ipheader::enable_recv_tos_ttl(backend.as_raw_fd()).expect("IP_RECVTOS");
+        let harness = Harness::with_backend(
+            PortMapper::builder().udp(true).udp_preserve_tos_ttl(true),
+            backend.local_addr().expect("backend addr"),
+        )
+        .expect("start");
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        ipheader::enable_recv_tos_ttl(client.as_raw_fd()).expect("IP_RECVTOS");
+        let mapper = Address::from_sockaddr(harness.listen_addr());
+        let mut buf = [0u8; 16];
+
+        // 两个方向都沿用 TOS，TTL 减一
+        let marks = |tos, ttl| IpHeader {
+            fragmented: false,
+            tos: Some(tos),
+            ttl: Some(ttl),
+        };
+        ipheader::send_to(
+            client.as_raw_fd(),
+            b"up",
+            Some(&mapper),
+            &marks(0x2a, 9),
+            false,
+        );
+        let (len, session, header) =
+            ipheader::recv_from(backend.as_raw_fd(), &mut buf).expect("recv");
+        assert_eq!((&buf[..len], header), (&b"up"[..], marks(0x2a, 8)));
+
+        let session = Address::from_sockaddr(session);
+        ipheader::send_to(
+            backend.as_raw_fd(),
+            b"down",
+            Some(&session),
+            &marks(0x01, 5),
+            false,
+        );
+        let (len, _, header) = ipheader::recv_from(client.as_raw_fd(), &mut buf).expect("recv");
+        assert_eq!((&buf[..len], header), (&b"down"[..], marks(0x01, 4)));
+
+        // TTL 耗尽的数据包不转发
+        ipheader::send_to(client.as_raw_fd(), b"x", Some(&mapper), &marks(0, 1), false);
+        assert!(backend.recv_from(&mut buf).is_err());
+        harness.stop().expect("stop");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multicast_listen() {
+        use std::net::Ipv4Addr;
+        use std::os::unix::io::AsRawFd;
+
+        let builder = PortMapper::builder().remote("127.0.0.1:9");
+        assert!(builder
+            .clone()
+            .listen("239.255.42.1:5000")
+            .tcp(true)
+            .build()
+            .is_err());
+        assert!(builder
+            .clone()
+            .listen("127.0.0.1:5000@lo")
+            .udp(true)
+            .build()
+            .is_err());
+        assert!(builder
+            .listen("127.0.0.1:0")
+            .udp(true)
+            .multicast_reply(true)
+            .build()
+            .is_err());
+
+        // 在环回接口上加入组，发往组地址的数据包转发给后端，回包发回客户端
+        let port = free_addr().expect("free port").port();
+        let group = SocketAddr::from(([239, 255, 42, 1], port));
+        let harness = Harness::with_echo(|backend_addr| {
+            let builder = PortMapper::builder()
+                .udp(true)
+                .listen(&format!("{}@lo", group))
+                .remote(&backend_addr.to_string());
+            Harness::spawn(builder, group, backend_addr)
+        })
+        .expect("start");
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let interface = libc::in_addr {
+            s_addr: u32::from(Ipv4Addr::LOCALHOST).to_be(),
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                client.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MULTICAST_IF,
+                &interface as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+        client.send_to(b"hello group", group).expect("send");
+        let mut buf = [0u8; 32];
+        let (len, _) = client.recv_from(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"hello group");
+        assert_eq!(harness.stats().udp_sessions, 1);
+        harness.stop().expect("stop");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_udp_broadcast() {
+        let builder = PortMapper::builder().udp(true).udp_broadcast(true);
+        assert!(builder
+            .clone()
+            .listen("[::1]:0")
+            .remote("127.0.0.1:9")
+            .build()
+            .is_err());
+
+        // 广播发往环回子网的广播地址，回包来自后端的单播地址
+        let backend = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).expect("bind backend");
+        backend
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let backend_port = backend.local_addr().expect("backend addr").port();
+        let port = free_addr().expect("free port").port();
+        let harness = Harness::spawn(
+            builder
+                .listen(&format!("0.0.0.0:{}", port))
+                .remote(&format!("127.255.255.255:{}", backend_port)),
+            loopback(port),
+            loopback(backend_port),
+        )
+        .expect("start");
+
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client.set_broadcast(true).expect("SO_BROADCAST");
+        client
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        client
+            .send_to(b"discover", SocketAddr::from(([127, 255, 255, 255], port)))
+            .expect("send");
+        let mut buf = [0u8; 16];
+        let (len, session) = backend.recv_from(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"discover");
+        backend
+            .send_to(b"offer", loopback(session.port()))
+            .expect("reply");
+        let (len, _) = client.recv_from(&mut buf).expect("recv reply");
+        assert_eq!(&buf[..len], b"offer");
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_udp_tftp_alg() {
+        use crate::alg::Tftp;
+        use std::sync::Arc;
+
+        let server = UdpSocket::bind(loopback(0)).expect("bind server");
+        server
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let server_addr = server.local_addr().expect("server addr");
+        let builder = PortMapper::builder()
+            .udp(true)
+            .udp_alg(server_addr.port(), Arc::new(Tftp));
+        assert!(builder
+            .clone()
+            .udp_broadcast(true)
+            .listen("0.0.0.0:0")
+            .remote("127.0.0.1:9")
+            .build()
+            .is_err());
+        let harness = Harness::with_backend(builder, server_addr).expect("start");
+
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        client
+            .send_to(b"\x00\x01file\x00octet\x00", harness.listen_addr())
+            .expect("send RRQ");
+        let mut buf = [0u8; 64];
+        let (len, session) = server.recv_from(&mut buf).expect("recv RRQ");
+        assert_eq!(&buf[..len], b"\x00\x01file\x00octet\x00");
+
+        // 服务端从新端口发送 DATA，之后的 ACK 发往新端口
+        let transfer = UdpSocket::bind(loopback(0)).expect("bind transfer");
+        transfer
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        transfer
+            .send_to(b"\x00\x03\x00\x01hello", session)
+            .expect("send DATA");
+        let (len, _) = client.recv_from(&mut buf).expect("recv DATA");
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01hello");
+        client
+            .send_to(b"\x00\x04\x00\x01", harness.listen_addr())
+            .expect("send ACK");
+        let (len, from) = transfer.recv_from(&mut buf).expect("recv ACK");
+        assert_eq!(&buf[..len], b"\x00\x04\x00\x01");
+        assert_eq!(from, session);
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_ftp_alg() {
+        check_ftp_alg(|| ());
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_ftp_alg_sandboxed() {
+        crate::sandbox::run_isolated("selftest::tests::test_ftp_alg_sandboxed", || {
+            check_ftp_alg(|| {
+                let allow = crate::sandbox::Allow {
+                    ftp_alg: true,
+                    ..Default::default()
+                };
+                crate::sandbox::install(allow).expect("install sandbox");
+            })
+        });
+    }
+
+    /// FTP ALG 改写被动和主动模式的数据通道地址，`started` 在映射启动后调用
+    fn check_ftp_alg(started: impl FnOnce()) {
+        use std::io::{BufRead, BufReader};
+
+        // PORT 参数和 227 应答括号中的 h1,h2,h3,h4,p1,p2
+        fn data_addr(line: &str) -> SocketAddr {
+            let args = line.trim_end().rsplit([' ', '(']).next().unwrap();
+            let n: Vec<u8> = args
+                .trim_end_matches(['.', ')'])
+                .split(',')
+                .map(|v| v.parse().unwrap())
+                .collect();
+            SocketAddr::from(([n[0], n[1], n[2], n[3]], u16::from_be_bytes([n[4], n[5]])))
+        }
+        fn read_line(reader: &mut impl BufRead) -> String {
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("read line");
+            line
+        }
+
+        let server = TcpListener::bind(loopback(0)).expect("bind server");
+        let server_addr = server.local_addr().expect("server addr");
+        let passive = TcpListener::bind(loopback(0)).expect("bind passive");
+        let active = TcpListener::bind(loopback(0)).expect("bind active");
+        let harness =
+            Harness::with_backend(PortMapper::builder().tcp(true).ftp_alg(true), server_addr)
+                .expect("start");
+        started();
+        let mut client = connect(harness.listen_addr());
+        let mut client_reader = BufReader::new(client.try_clone().expect("clone"));
+        let (mut control, _) = server.accept().expect("accept control");
+        control
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let mut server_reader = BufReader::new(control.try_clone().expect("clone"));
+
+        // 被动模式：应答中的地址改写为映射上的临时监听
+        client.write_all(b"PASV\r\n").expect("send PASV");
+        assert_eq!(read_line(&mut server_reader), "PASV\r\n");
+        let passive_addr = passive.local_addr().expect("passive addr");
+        let [p1, p2] = passive_addr.port().to_be_bytes();
+        write!(
+            control,
+            "227 Entering Passive Mode (10,0,0,1,{},{}).\r\n",
+            p1, p2
+        )
+        .expect("send 227");
+        let reply = read_line(&mut client_reader);
+        let advertised = data_addr(&reply);
+        assert_eq!(advertised.ip(), passive_addr.ip());
+        assert_ne!(advertised.port(), passive_addr.port());
+        let mut data = connect(advertised);
+        let (mut accepted, _) = passive.accept().expect("accept passive data");
+        accepted.write_all(b"passive").expect("send data");
+        drop(accepted);
+        let mut received = Vec::new();
+        data.read_to_end(&mut received).expect("read data");
+        assert_eq!(received, b"passive");
+
+        // 主动模式：服务端连接改写后的地址，转发到客户端的监听
+        let active_addr = active.local_addr().expect("active addr");
+        let [p1, p2] = active_addr.port().to_be_bytes();
+        write!(client, "PORT 127,0,0,1,{},{}\r\n", p1, p2).expect("send PORT");
+        let command = read_line(&mut server_reader);
+        assert!(command.starts_with("PORT ") && command.ends_with("\r\n"));
+        let advertised = data_addr(&command);
+        assert_ne!(advertised.port(), active_addr.port());
+        let mut data = TcpStream::connect(advertised).expect("connect active data");
+        let (mut accepted, _) = active.accept().expect("accept active data");
+        accepted
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        data.write_all(b"active").expect("send data");
+        drop(data);
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).expect("read data");
+        assert_eq!(received, b"active");
+
+        // 其他命令原样转发
+        client.write_all(b"QUIT\r\n").expect("send QUIT");
+        assert_eq!(read_line(&mut server_reader), "QUIT\r\n");
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_stun() {
+        // 假 STUN 服务端：以请求的来源地址应答 XOR-MAPPED-ADDRESS
+        let server = UdpSocket::bind(loopback(0)).expect("bind stun server");
+        let server_addr = server.local_addr().expect("server addr");
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).expect("recv binding request");
+            assert_eq!(len, 20);
+            let SocketAddr::V4(from_v4) = from else {
+                panic!("expected IPv4");
+            };
+            let mut response = buf[..20].to_vec();
+            response[..4].copy_from_slice(&[0x01, 0x01, 0, 12]);
+            response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+            let ip = u32::from(*from_v4.ip()) ^ 0x2112_a442;
+            response.extend_from_slice(&ip.to_be_bytes());
+            server
+                .send_to(&response, from)
+                .expect("send binding response");
+            from
+        });
+        let harness = Harness::start(
+            PortMapper::builder()
+                .udp(true)
+                .stun(&server_addr.to_string()),
+        )
+        .expect("start");
+        let from = responder.join().expect("stun server");
+        assert_eq!(from, harness.listen_addr());
+        let deadline = Instant::now() + SELFTEST_TIMEOUT;
+        while harness.handle().public_endpoint().is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(harness.handle().public_endpoint(), Some(from));
+        // 应答没有建立会话，普通数据包照常转发
+        assert_eq!(harness.stats().udp_sessions, 0);
+        check_udp_echo(harness.listen_addr(), &[1, 1400]).expect("udp echo");
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_webhook() {
+        check_webhook("127.0.0.1", || ());
+        assert!(Harness::start(PortMapper::builder().tcp(true).webhook("ftp://x/")).is_err());
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_webhook_sandboxed() {
+        crate::sandbox::run_isolated("selftest::tests::test_webhook_sandboxed", || {
+            // 域名在每次发送时解析
+            check_webhook("localhost", || {
+                let allow = crate::sandbox::Allow {
+                    read_files: true,
+                    ..Default::default()
+                };
+                crate::sandbox::install(allow).expect("install sandbox");
+            })
+        });
+    }
+
+    /// 连接建立和关闭时向 `http://host:port/events` 发送事件，`started` 在映射启动后调用
+    fn check_webhook(host: &str, started: impl FnOnce()) {
+        use std::net::ToSocketAddrs;
+
+        // 假 HTTP 接收端：取出每个请求的正文，应答 204，监听在域名解析出的首个地址上
+        let addr = (host, 0)
+            .to_socket_addrs()
+            .expect("resolve")
+            .next()
+            .expect("address");
+        let listener = TcpListener::bind(addr).expect("bind webhook receiver");
+        let port = listener.local_addr().expect("addr").port();
+        let url = format!("http://{}:{}/events", host, port);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+                stream
+                    .set_read_timeout(Some(SELFTEST_TIMEOUT))
+                    .expect("set timeout");
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"}") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n");
+                let request = String::from_utf8_lossy(&request).to_string();
+                let body = request
+                    .split_once("\r\n\r\n")
+                    .map(|(_, body)| body.to_string());
+                if tx.send(body.unwrap_or_default()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .tenant("selftest-webhook")
+                .webhook(&url),
+        )
+        .expect("start");
+        started();
+        check_tcp_echo(harness.listen_addr(), 1000, 3).expect("tcp echo");
+        let connect = rx.recv_timeout(SELFTEST_TIMEOUT).expect("connect event");
+        assert!(
+            connect.starts_with(r#"{"event":"connect","time":"#),
+            "{}",
+            connect
+        );
+        assert!(connect.contains(r#""tenant":"selftest-webhook","protocol":"tcp""#));
+        let close = rx.recv_timeout(SELFTEST_TIMEOUT).expect("close event");
+        assert!(
+            close.starts_with(r#"{"event":"close","time":"#),
+            "{}",
+            close
+        );
+        assert!(
+            close.contains(r#""bytes_up":1000,"bytes_down":1000"#),
+            "{}",
+            close
+        );
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_middleware() {
+        use crate::middleware::{Middleware, TcpContext, TcpFilter, UdpFilter};
+        use crate::types::Address;
+        use std::sync::Arc;
+
+        /// 发往远程的 TCP 数据转为大写，丢弃以 `drop` 开头的 UDP 数据报
+        #[derive(Debug)]
+        struct Upper;
+
+        impl Middleware for Upper {
+            fn name(&self) -> &str {
+                "upper"
+            }
+
+            fn tcp_filter(&self, _remote: &Address) -> Option<Box<dyn TcpFilter>> {
+                Some(Box::new(Upper))
+            }
+
+            fn udp_filter(&self, _remote: &Address) -> Option<Box<dyn UdpFilter>> {
+                Some(Box::new(Upper))
+            }
+        }
+
+        impl TcpFilter for Upper {
+            fn process(
+                &mut self,
+                to_remote: bool,
+                input: &[u8],
+                out: &mut Vec<u8>,
+                _ctx: &mut dyn TcpContext,
+            ) {
+                match to_remote {
+                    true => out.extend(input.iter().map(u8::to_ascii_uppercase)),
+                    false => out.extend_from_slice(input),
+                }
+            }
+        }
+
+        impl UdpFilter for Upper {
+            fn name(&self) -> &str {
+                "upper"
+            }
+
+            fn datagram(&mut self, _to_remote: bool, data: &mut Vec<u8>) -> bool {
+                !data.starts_with(b"drop")
+            }
+        }
+
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .middleware(Arc::new(Upper)),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        let mut stream = connect(addr);
+        stream.write_all(b"hello").expect("send");
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).expect("recv echo");
+        assert_eq!(&buf, b"HELLO");
+
+        let client = UdpSocket::bind(loopback(0)).expect("bind client");
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .expect("set timeout");
+        client.send_to(b"drop me", addr).expect("send");
+        client.send_to(b"keep me", addr).expect("send");
+        let mut buf = [0u8; 64];
+        let len = client.recv(&mut buf).expect("recv echo");
+        assert_eq!(&buf[..len], b"keep me");
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let harness =
+            Harness::start(PortMapper::builder().tcp(true).max_connections(2)).expect("start");
+        let addr = harness.listen_addr();
+        let mut buf = [0u8; 4];
+
+        let mut open: Vec<_> = (0..2)
+            .map(|_| {
+                let mut stream = connect(addr);
+                stream.write_all(b"ping").expect("write");
+                stream.read_exact(&mut buf).expect("read");
+                stream
+            })
+            .collect();
+        assert_eq!(harness.stats().tcp_connections, 2);
+
+        // 超过上限的连接被接受后立即关闭
+        let mut refused = connect(addr);
+        let _ = refused.write_all(b"ping");
+        assert!(matches!(refused.read(&mut buf), Ok(0) | Err(_)));
+        assert_eq!(harness.stats().tcp_connections, 2);
+
+        // 释放一个名额后可以再建立连接
+        open.pop();
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| s.tcp_connections == 1));
+        check_tcp_echo(addr, 4096, 0).expect("tcp echo after release");
+    }
+
+    #[test]
+    fn test_chaos() {
+        use crate::chaos::Chaos;
+
+        // 每个方向延迟 100ms，往返至少 200ms
+        let chaos: Chaos = "delay=100ms".parse().unwrap();
+        let harness =
+            Harness::start(PortMapper::builder().tcp(true).udp(true).chaos(chaos)).expect("start");
+        let addr = harness.listen_addr();
+        let start = Instant::now();
+        check_tcp_echo(addr, 64 << 10, 3).expect("tcp echo");
+        check_udp_echo(addr, &[100]).expect("udp echo");
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let chaos: Chaos = "loss=100%".parse().unwrap();
+        let harness = Harness::start(PortMapper::builder().udp(true).chaos(chaos)).expect("start");
+        let socket = UdpSocket::bind(loopback(0)).expect("bind");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .expect("set timeout");
+        socket
+            .send_to(b"lost", harness.listen_addr())
+            .expect("send");
+        assert!(socket.recv_from(&mut [0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_mirror() {
+        // 镜像目标在同一端口上接收 TCP 镜像流和 UDP 副本
+        let mirror_addr = free_addr().expect("mirror addr");
+        let listener = TcpListener::bind(mirror_addr).expect("bind mirror");
+        let collector = UdpSocket::bind(mirror_addr).expect("bind mirror");
+        collector
+            .set_read_timeout(Some(SELFTEST_TIMEOUT))
+            .expect("set timeout");
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .mirror(&mirror_addr.to_string()),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        let reader = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            stream
+                .set_read_timeout(Some(SELFTEST_TIMEOUT))
+                .expect("set timeout");
+            let mut mirrored = Vec::new();
+            stream.read_to_end(&mut mirrored).expect("read mirror");
+            mirrored
+        });
+        // 客户端照常收到回显，连接关闭后镜像流也随之关闭
+        check_tcp_echo(addr, 256 << 10, 5).expect("tcp echo");
+        assert!(reader.join().expect("join reader") == pattern(256 << 10, 5));
+
+        check_udp_echo(addr, &[100]).expect("udp echo");
+        let mut buf = [0u8; 256];
+        let len = collector.recv(&mut buf).expect("recv mirror copy");
+        assert_eq!(len, 100);
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_expect_protocol() {
+        use crate::sniff::ExpectProtocol;
+
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .expect_protocol(ExpectProtocol::Http)
+                .tenant("selftest-expect-protocol"),
+        )
+        .expect("start");
+        let request = b"GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        let mut stream = connect(harness.listen_addr());
+        stream.write_all(request).expect("write");
+        let mut echoed = vec![0u8; request.len()];
+        stream.read_exact(&mut echoed).expect("read");
+        assert_eq!(echoed, request);
+
+        // 不是 HTTP 的连接在连接后端前被关闭
+        let mut junk = connect(harness.listen_addr());
+        junk.write_all(b"\x16\x03\x01\x00\x05hello").expect("write");
+        let mut received = Vec::new();
+        let _ = junk.read_to_end(&mut received);
+        assert!(received.is_empty());
+        assert_eq!(harness.stats().tcp_connections, 1);
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_auto_ban() {
+        use crate::sniff::ExpectProtocol;
+
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .expect_protocol(ExpectProtocol::Http)
+                .auto_ban(2, Duration::from_secs(60), Duration::from_secs(60)),
+        )
+        .expect("start");
+        let request = b"GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        for _ in 0..2 {
+            let mut junk = connect(harness.listen_addr());
+            junk.write_all(b"\x16\x03\x01\x00\x05hello").expect("write");
+            let mut received = Vec::new();
+            let _ = junk.read_to_end(&mut received);
+        }
+
+        // 两次协议不符后被封禁，正常请求也不再转发
+        let mut stream = connect(harness.listen_addr());
+        let _ = stream.write_all(request);
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        assert!(received.is_empty());
+        assert_eq!(harness.stats().tcp_connections, 0);
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_auto_ban_ignores_full_server() {
+        let harness = Harness::start(PortMapper::builder().tcp(true).max_connections(1).auto_ban(
+            1,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        ))
+        .expect("start");
+        let addr = harness.listen_addr();
+        let mut buf = [0u8; 4];
+        let mut open = connect(addr);
+        open.write_all(b"ping").expect("write");
+        open.read_exact(&mut buf).expect("read");
+
+        // 连接数已满的拒绝不是客户端的问题，不计入封禁
+        let mut refused = connect(addr);
+        let _ = refused.write_all(b"ping");
+        assert!(matches!(refused.read(&mut buf), Ok(0) | Err(_)));
+        drop(open);
+        assert!(harness.wait_until(SELFTEST_TIMEOUT, |s| s.tcp_connections == 0));
+        check_tcp_echo(addr, 4096, 0).expect("tcp echo after max connections reject");
+        harness.stop().expect("stop");
+    }
+
+    #[test]
+    fn test_auto_ban_rate_limit() {
+        let harness = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .rate_limit_per_conn(64 * 1024)
+                .auto_ban(1, Duration::from_secs(60), Duration::from_secs(60)),
+        )
+        .expect("start");
+        let addr = harness.listen_addr();
+
+        // 持续超出单连接速率，读取被暂停并报告拒绝
+        let mut stream = connect(addr);
+        stream
+            .set_write_timeout(Some(Duration::from_millis(200)))
+            .expect("set timeout");
+        let data = pattern(1 << 20, 3);
+        let _ = stream.write_all(&data);
+
+        let deadline = Instant::now() + SELFTEST_TIMEOUT;
+        let banned = loop {
+            let mut probe = connect(addr);
+            let _ = probe.write_all(b"ping");
+            probe
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .expect("set timeout");
+            let mut buf = [0u8; 4];
+            match probe.read(&mut buf) {
+                Ok(0) => break true,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break true,
+                _ => {}
+            }
+            if Instant::now() > deadline {
+                break false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(banned, "rate limited client was not banned");
+        drop(stream);
+        harness.stop().expect("stop");
+    }
+
+    /// 客户端 -> 加密中继 -> 解密中继 -> 回显后端
+    fn check_obfs_chain(cipher: crate::obfs::Cipher) {
+        let inner = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .cipher(cipher)
+                .decrypt_in("relay secret"),
+        )
+        .expect("start inner");
+        let outer = Harness::with_backend(
+            PortMapper::builder()
+                .tcp(true)
+                .cipher(cipher)
+                .encrypt_out("relay secret"),
+            inner.listen_addr(),
+        )
+        .expect("start outer");
+        check_tcp_echo(outer.listen_addr(), 1 << 20, 9).expect("tcp echo through relays");
+
+        // 中继之间的数据不是明文
+        let capture = TcpListener::bind(loopback(0)).expect("bind capture");
+        let capture_addr = capture.local_addr().expect("capture addr");
+        let sniffer = thread::spawn(move || {
+            let (mut stream, _) = capture.accept().expect("accept");
+            let mut wire = vec![0u8; 4096];
+            stream.read_exact(&mut wire).expect("read");
+            wire
+        });
+        let outer = Harness::with_backend(
+            PortMapper::builder()
+                .tcp(true)
+                .cipher(cipher)
+                .encrypt_out("relay secret"),
+            capture_addr,
+        )
+        .expect("start outer");
+        let data = pattern(4096, 2);
+        let mut stream = connect(outer.listen_addr());
+        stream.write_all(&data).expect("write");
+        let wire = sniffer.join().expect("join sniffer");
+        assert!(!wire.windows(32).any(|w| data.windows(32).any(|d| d == w)));
+    }
+
+    #[test]
+    fn test_obfs_xor() {
+        check_obfs_chain(crate::obfs::Cipher::Xor);
+    }
+
+    #[cfg(feature = "aead")]
+    #[test]
+    fn test_obfs_aead() {
+        check_obfs_chain(crate::obfs::Cipher::ChaCha20Poly1305);
+
+        // 没有加密的客户端直连解密中继时连接被关闭，后端收不到数据
+        let inner = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .cipher(crate::obfs::Cipher::ChaCha20Poly1305)
+                .decrypt_in("relay secret"),
+        )
+        .expect("start inner");
+        let mut stream = connect(inner.listen_addr());
+        stream.write_all(&pattern(1024, 4)).expect("write");
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        assert!(received.is_empty());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compress() {
+        // 客户端 -> 压缩并加密的中继 -> 解密并解压的中继 -> 回显后端
+        let inner = Harness::start(
+            PortMapper::builder()
+                .tcp(true)
+                .decrypt_in("relay secret")
+                .decompress_in(true),
+        )
+        .expect("start inner");
+        let outer = Harness::with_backend(
+            PortMapper::builder()
+                .tcp(true)
+                .encrypt_out("relay secret")
+                .compress_out(true),
+            inner.listen_addr(),
+        )
+        .expect("start outer");
+        check_tcp_echo(outer.listen_addr(), 1 << 20, 5).expect("tcp echo through relays");
+
+        // 可压缩的数据解压后超出缓冲区，回包同样压缩
+        let mut stream = connect(outer.listen_addr());
+        let text = b"INFO request served in 3ms\n".repeat(40000);
+        let writer = {
+            let mut stream = stream.try_clone().expect("clone");
+            let text = text.clone();
+            thread::spawn(move || stream.write_all(&text))
+        };
+        let mut echoed = vec![0u8; text.len()];
+        stream.read_exact(&mut echoed).expect("read echo");
+        writer.join().expect("join writer").expect("write");
+        assert!(echoed == text);
+
+        // 普通客户端直连解压中继时原样转发
+        let inner = Harness::start(PortMapper::builder().tcp(true).decompress_in(true))
+            .expect("start inner");
+        check_tcp_echo(inner.listen_addr(), 64 << 10, 6).expect("plain tcp echo");
+    }
+
+    #[test]
+    fn test_socks5_server() {
+        let server = Harness::socks5(
+            PortMapper::builder()
+                .udp(true)
+                .socks5_auth("user:secret")
+                .tenant("selftest-socks5"),
+        )
+        .expect("start socks5 server");
+        // 另一个实例以上游代理模式经 SOCKS5 服务端转发 TCP 和 UDP 到回显后端
+        let client = Harness::with_backend(
+            PortMapper::builder()
+                .tcp(true)
+                .udp(true)
+                .upstream(&format!("socks5://{}:user:secret", server.listen_addr())),
+            server.backend_addr(),
+        )
+        .expect("start client");
+        check_tcp_echo(client.listen_addr(), 256 << 10, 11).expect("tcp echo through socks5");
+        check_udp_echo(client.listen_addr(), &[1, 1400]).expect("udp echo through socks5");
+
+        // 不等应答连续发送问候、认证和请求，目标拒绝连接时应答 connection refused
+        let closed = TcpListener::bind(loopback(0))
+            .and_then(|listener| listener.local_addr())
+            .expect("closed port");
+        let mut stream = connect(server.listen_addr());
+        let mut request = vec![5, 1, 2, 1, 4];
+        request.extend_from_slice(b"user");
+        request.push(6);
+        request.extend_from_slice(b"secret");
+        request.extend_from_slice(&[5, 1, 0, 1, 127, 0, 0, 1]);
+        request.extend_from_slice(&closed.port().to_be_bytes());
+        stream.write_all(&request).expect("write");
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply);
+        assert_eq!(reply, [5, 2, 1, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // 密码错误时认证失败
+        let mut stream = connect(server.listen_addr());
+        stream
+            .write_all(&[5, 1, 2, 1, 4, b'u', b's', b'e', b'r', 1, b'x'])
+            .expect("write");
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply);
+        assert_eq!(reply, [5, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_run() {
+        run().expect("selftest");
+    }
+}
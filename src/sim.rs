@@ -0,0 +1,230 @@
+//! 模拟网络
+//!
+//! `SocketIo` 抽象 TCP 转发路径上的收发：`SysIo` 直接调用系统调用，`SimNet` 在内存中模拟 socket。
+//! 模拟 socket 的发送方向有容量上限，写满后返回 WouldBlock，配合 `crate::clock::MockClock`
+//! 可以不依赖内核缓冲区大小和真实等待，复现部分发送、背压和超时
+
+use crate::sync::{LoopShared, Mutex, Recover};
+use crate::PlatformRawFd;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(windows)]
+use crate::winsock as libc;
+
+/// 模拟 socket 编号的起点，远大于进程实际打开的 fd，误传给系统调用时只会得到 EBADF
+const SIM_FD_BASE: PlatformRawFd = 1 << 30;
+
+/// 转发路径上的 socket 收发
+pub trait SocketIo: LoopShared + fmt::Debug {
+    /// 接收数据，返回 0 表示对端已关闭
+    fn recv(&self, fd: PlatformRawFd, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// 依次发送多段数据，返回实际发出的字节数
+    fn send(&self, fd: PlatformRawFd, segments: &[&[u8]]) -> io::Result<usize>;
+}
+
+/// 系统调用收发
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SysIo;
+
+impl SocketIo for SysIo {
+    fn recv(&self, fd: PlatformRawFd, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    fn send(&self, fd: PlatformRawFd, segments: &[&[u8]]) -> io::Result<usize> {
+        let sent = crate::event::tcp::send_segments(fd, segments);
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+#[derive(Debug, Default)]
+struct SimSocket {
+    /// 对端发来、等待 recv 读取的数据
+    inbound: VecDeque<u8>,
+    /// 已发出、等待对端取走的数据
+    outbound: VecDeque<u8>,
+    /// outbound 最多缓存的字节数
+    capacity: usize,
+    /// 对端已关闭写方向，inbound 读完后 recv 返回 0
+    peer_closed: bool,
+    /// 连接已被重置，收发都返回 ConnectionReset
+    reset: bool,
+}
+
+#[derive(Debug, Default)]
+struct SimState {
+    sockets: HashMap<PlatformRawFd, SimSocket>,
+    next_fd: PlatformRawFd,
+}
+
+/// 内存中的模拟网络
+///
+/// 每个模拟 socket 代表转发器持有的一端，测试代码扮演对端：`deliver` 写入数据，`take` 取走转发器发出的数据
+#[derive(Debug, Default)]
+pub struct SimNet {
+    state: Mutex<SimState>,
+}
+
+impl SimNet {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// 创建模拟 socket，发送方向最多缓存 `capacity` 字节
+    pub fn socket(&self, capacity: usize) -> PlatformRawFd {
+        let mut state = self.state.lock().recover();
+        let fd = SIM_FD_BASE + state.next_fd;
+        state.next_fd += 1;
+        state.sockets.insert(
+            fd,
+            SimSocket {
+                capacity,
+                ..SimSocket::default()
+            },
+        );
+        fd
+    }
+
+    /// 对端向 `fd` 写入数据
+    pub fn deliver(&self, fd: PlatformRawFd, data: &[u8]) {
+        if let Some(socket) = self.state.lock().recover().sockets.get_mut(&fd) {
+            socket.inbound.extend(data);
+        }
+    }
+
+    /// 对端取走 `fd` 上最多 `max` 字节的已发送数据
+    pub fn take(&self, fd: PlatformRawFd, max: usize) -> Vec<u8> {
+        let mut state = self.state.lock().recover();
+        match state.sockets.get_mut(&fd) {
+            Some(socket) => {
+                let len = max.min(socket.outbound.len());
+                socket.outbound.drain(..len).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// `fd` 上等待对端取走的字节数
+    pub fn queued(&self, fd: PlatformRawFd) -> usize {
+        let state = self.state.lock().recover();
+        state
+            .sockets
+            .get(&fd)
+            .map_or(0, |socket| socket.outbound.len())
+    }
+
+    /// 对端关闭写方向
+    pub fn shutdown(&self, fd: PlatformRawFd) {
+        if let Some(socket) = self.state.lock().recover().sockets.get_mut(&fd) {
+            socket.peer_closed = true;
+        }
+    }
+
+    /// 对端重置连接
+    pub fn reset(&self, fd: PlatformRawFd) {
+        if let Some(socket) = self.state.lock().recover().sockets.get_mut(&fd) {
+            socket.reset = true;
+        }
+    }
+
+    fn with_socket<R>(
+        &self,
+        fd: PlatformRawFd,
+        f: impl FnOnce(&mut SimSocket) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut state = self.state.lock().recover();
+        let socket = state
+            .sockets
+            .get_mut(&fd)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown simulated socket"))?;
+        if socket.reset {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        f(socket)
+    }
+}
+
+impl SocketIo for SimNet {
+    fn recv(&self, fd: PlatformRawFd, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_socket(fd, |socket| {
+            if socket.inbound.is_empty() {
+                if socket.peer_closed {
+                    return Ok(0);
+                }
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(socket.inbound.len());
+            for (dst, src) in buf.iter_mut().zip(socket.inbound.drain(..len)) {
+                *dst = src;
+            }
+            Ok(len)
+        })
+    }
+
+    fn send(&self, fd: PlatformRawFd, segments: &[&[u8]]) -> io::Result<usize> {
+        self.with_socket(fd, |socket| {
+            let total: usize = segments.iter().map(|s| s.len()).sum();
+            let room = socket.capacity.saturating_sub(socket.outbound.len());
+            if total > 0 && room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let mut sent = 0;
+            for segment in segments {
+                let len = segment.len().min(room - sent);
+                socket.outbound.extend(&segment[..len]);
+                sent += len;
+            }
+            Ok(sent)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sim_socket() {
+        let net = SimNet::new();
+        let fd = net.socket(8);
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            net.recv(fd, &mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        net.deliver(fd, b"hello");
+        assert_eq!(net.recv(fd, &mut buf[..3]).unwrap(), 3);
+        assert_eq!(net.recv(fd, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+
+        // 容量 8 字节，写满后 WouldBlock，对端取走后可以继续发送
+        assert_eq!(net.send(fd, &[b"abc", b"defgh", b"ij"]).unwrap(), 8);
+        assert_eq!(
+            net.send(fd, &[b"k"]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(net.take(fd, 5), b"abcde");
+        assert_eq!(net.send(fd, &[b"ijk"]).unwrap(), 3);
+        assert_eq!(net.queued(fd), 6);
+
+        net.shutdown(fd);
+        assert_eq!(net.recv(fd, &mut buf).unwrap(), 0);
+        net.reset(fd);
+        assert_eq!(
+            net.send(fd, &[b"x"]).unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+        assert!(net.recv(fd + 1, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! 带代数标记的 slab 分配器
+//!
+//! 为 Token 和 Fd64 分配键：释放的槽位被复用，键中带有槽位的代数 (每次释放加一)，
+//! 槽位被复用后旧键不再能查到新条目。键的低半部分为槽位序号加一，因此键不会是 0
+
+/// 键中槽位序号占的位数，其余高位为代数
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: usize = usize::MAX >> INDEX_BITS;
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: usize,
+    value: Option<T>,
+}
+
+/// 带代数标记的 slab
+#[derive(Debug)]
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    /// 空闲槽位序号，后释放的先复用
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// 预分配容量
+    pub fn reserve(&mut self, capacity: usize) {
+        self.slots.reserve(capacity.saturating_sub(self.free.len()));
+    }
+
+    /// 插入条目，返回键
+    pub fn insert(&mut self, value: T) -> usize {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                assert!(self.slots.len() < INDEX_MASK, "slab is full");
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.value = Some(value);
+        self.len += 1;
+        (slot.generation << INDEX_BITS) | (index + 1)
+    }
+
+    /// 键对应的槽位，代数不符 (槽位已被释放或复用) 时返回 None
+    fn slot(&self, key: usize) -> Option<&Slot<T>> {
+        let index = (key & INDEX_MASK).checked_sub(1)?;
+        let slot = self.slots.get(index)?;
+        (slot.generation == key >> INDEX_BITS).then_some(slot)
+    }
+
+    fn slot_mut(&mut self, key: usize) -> Option<&mut Slot<T>> {
+        let index = (key & INDEX_MASK).checked_sub(1)?;
+        let slot = self.slots.get_mut(index)?;
+        (slot.generation == key >> INDEX_BITS).then_some(slot)
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        self.slot(key)?.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        self.slot_mut(key)?.value.as_mut()
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// 移除条目，槽位代数加一后放回空闲列表
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        let slot = self.slot_mut(key)?;
+        let value = slot.value.take()?;
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+        self.free.push((key & INDEX_MASK) - 1);
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// 条目数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 遍历所有条目
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|slot| slot.value.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_with_generation() {
+        let mut slab = Slab::new();
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        assert_ne!(a, 0);
+        assert_ne!(a, b);
+        assert_eq!(slab.get(a), Some(&"a"));
+        assert_eq!(slab.len(), 2);
+
+        assert_eq!(slab.remove(a), Some("a"));
+        assert_eq!(slab.remove(a), None);
+        assert!(!slab.contains(a));
+
+        // 复用 a 的槽位，旧键查不到新条目
+        let c = slab.insert("c");
+        assert_eq!(c & INDEX_MASK, a & INDEX_MASK);
+        assert_ne!(c, a);
+        assert_eq!(slab.get(a), None);
+        assert_eq!(slab.get(c), Some(&"c"));
+        assert_eq!(slab.slots.len(), 2);
+
+        *slab.get_mut(b).expect("b") = "B";
+        let mut values: Vec<_> = slab.values_mut().map(|v| *v).collect();
+        values.sort();
+        assert_eq!(values, ["B", "c"]);
+        assert_eq!(slab.get(0), None);
+        assert_eq!(slab.get(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_generation_wraps() {
+        let mut slab = Slab::new();
+        let first = slab.insert(0);
+        slab.slots[0].generation = GENERATION_MASK;
+        slab.slots[0].value = None;
+        slab.free.push(0);
+        slab.len -= 1;
+
+        let key = slab.insert(1);
+        assert_eq!(key >> INDEX_BITS, GENERATION_MASK);
+        slab.remove(key);
+        assert_eq!(slab.insert(2), first);
+    }
+}
@@ -0,0 +1,385 @@
+//! TLS SNI 路由
+//!
+//! 从客户端发来的 TLS ClientHello 中读取 SNI (不终止 TLS)，按路由文件选择后端。
+//! 路由文件每行一条规则 `主机名 远程地址...`，`*.example.com` 匹配其所有子域名，
+//! `#` 之后为注释；未匹配、没有 SNI 或不是 TLS 的连接使用 `-r` 指定的后端
+
+use crate::backend::{parse_weighted_remote, BackendPool, LbPolicy};
+use crate::stats::TrafficStats;
+use crate::types::Address;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 等待 ClientHello 的最长时间，超时后按未匹配处理
+pub const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 窥探 ClientHello 的最大字节数，超过时按没有 SNI 处理
+pub const MAX_CLIENT_HELLO_LEN: usize = 16384;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// TLS 记录长度上限 (2^14 加上压缩/加密扩展)
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+/// ClientHello 解析结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniResult {
+    /// 数据不完整，需要等待更多数据
+    Incomplete,
+    /// 不是 TLS，或 ClientHello 中没有 SNI
+    NotFound,
+    /// SNI 主机名 (小写，去掉末尾的点)
+    Found(String),
+}
+
+/// 从连接开头的数据中解析 ClientHello 的 SNI，ClientHello 可能跨多个 TLS 记录
+pub fn parse_client_hello(buf: &[u8]) -> SniResult {
+    let mut message = Vec::new();
+    let mut rest = buf;
+    loop {
+        match rest.first() {
+            Some(&CONTENT_TYPE_HANDSHAKE) => {}
+            Some(_) => return SniResult::NotFound,
+            None => return SniResult::Incomplete,
+        }
+        if rest.len() >= 2 && rest[1] != 3 {
+            return SniResult::NotFound;
+        }
+        if rest.len() < 5 {
+            return SniResult::Incomplete;
+        }
+        let len = usize::from(u16::from_be_bytes([rest[3], rest[4]]));
+        if len == 0 || len > MAX_RECORD_LEN {
+            return SniResult::NotFound;
+        }
+        if rest.len() < 5 + len {
+            return SniResult::Incomplete;
+        }
+        message.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return SniResult::NotFound;
+            }
+            let body_len = usize::from(message[1]) << 16
+                | usize::from(message[2]) << 8
+                | usize::from(message[3]);
+            if message.len() >= 4 + body_len {
+                return parse_hello_body(&message[4..4 + body_len])
+                    .map_or(SniResult::NotFound, SniResult::Found);
+            }
+        }
+    }
+}
+
+/// 按字节读取握手消息的游标
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// 读取以 u8 长度为前缀的字段
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.bytes(usize::from(len))
+    }
+
+    /// 读取以 u16 长度为前缀的字段
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(usize::from(len))
+    }
+}
+
+/// 解析 ClientHello 消息体，返回 server_name 扩展中的主机名
+fn parse_hello_body(body: &[u8]) -> Option<String> {
+    let mut reader = Reader { buf: body };
+    // legacy_version + random
+    reader.bytes(2 + 32)?;
+    reader.vec8()?; // session id
+    reader.vec16()?; // cipher suites
+    reader.vec8()?; // compression methods
+    let mut extensions = Reader {
+        buf: reader.vec16()?,
+    };
+    while !extensions.buf.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader {
+            buf: Reader { buf: data }.vec16()?,
+        };
+        while !list.buf.is_empty() {
+            let name_type = list.u8()?;
+            let name = list.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return normalize_host(std::str::from_utf8(name).ok()?);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// 主机名转为小写并去掉末尾的点，包含非法字符时返回 None
+fn normalize_host(host: &str) -> Option<String> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+/// 一条 SNI 路由规则
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniRoute {
+    /// 主机名，`*.` 开头时匹配其子域名
+    pub pattern: String,
+    /// 远程地址和权重
+    pub remotes: Vec<(Address, u32)>,
+}
+
+impl SniRoute {
+    /// 主机名是否匹配该规则
+    fn matches(&self, host: &str) -> bool {
+        match self.pattern.strip_prefix('*') {
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => self.pattern == host,
+        }
+    }
+}
+
+/// SNI 路由表
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SniRoutes {
+    routes: Vec<SniRoute>,
+}
+
+impl SniRoutes {
+    /// 从路由文件加载
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        content
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// 所有规则 (按文件中的顺序)
+    pub fn routes(&self) -> &[SniRoute] {
+        &self.routes
+    }
+
+    /// 查找主机名匹配的规则：精确匹配优先，其次是后缀最长的通配规则
+    pub fn lookup(&self, host: &str) -> Option<usize> {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(|(_, route)| route.matches(host))
+            .max_by_key(|(i, route)| {
+                let exact = !route.pattern.starts_with('*');
+                (exact, route.pattern.len(), std::cmp::Reverse(*i))
+            })
+            .map(|(i, _)| i)
+    }
+}
+
+impl FromStr for SniRoutes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut routes: Vec<SniRoute> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: String| format!("line {}: {}", i + 1, msg);
+            let mut fields = line.split_whitespace();
+            let host = fields.next().unwrap_or_default();
+            let pattern = match host.strip_prefix("*.") {
+                Some(domain) => normalize_host(domain).map(|domain| format!("*.{}", domain)),
+                None => normalize_host(host),
+            }
+            .ok_or_else(|| error(format!("invalid host name '{}'", host)))?;
+            if routes.iter().any(|route| route.pattern == pattern) {
+                return Err(error(format!("duplicate route for '{}'", pattern)));
+            }
+            let remotes = fields
+                .map(|remote| {
+                    parse_weighted_remote(remote)
+                        .map_err(|e| error(format!("invalid remote address '{}': {}", remote, e)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if remotes.is_empty() {
+                return Err(error(format!("no remote address for '{}'", pattern)));
+            }
+            routes.push(SniRoute { pattern, remotes });
+        }
+        Ok(Self { routes })
+    }
+}
+
+/// 运行时的 SNI 路由：每条规则对应一个后端地址池
+#[derive(Debug, Default)]
+pub struct SniRouter {
+    routes: SniRoutes,
+    pools: Vec<BackendPool>,
+}
+
+impl SniRouter {
+    /// 为每条规则按负载均衡策略创建后端地址池，后端统计记录在 `stats` 中
+    pub fn new(routes: SniRoutes, policy: LbPolicy, stats: &TrafficStats) -> Self {
+        let pools = routes
+            .routes
+            .iter()
+            .map(|route| {
+                let (addrs, weights): (Vec<Address>, Vec<u32>) =
+                    route.remotes.iter().cloned().unzip();
+                BackendPool::with_policy(&addrs, &weights, policy, stats)
+            })
+            .collect();
+        Self { routes, pools }
+    }
+
+    /// 主机名对应的后端地址池，没有匹配的规则时返回 None
+    pub fn route(&self, host: &str) -> Option<&BackendPool> {
+        self.routes.lookup(host).map(|i| &self.pools[i])
+    }
+
+    /// 遍历所有规则的后端地址池
+    pub fn pools(&self) -> impl Iterator<Item = &BackendPool> {
+        self.pools.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造带 SNI 扩展的 ClientHello 记录
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // 无关的扩展 (supported_groups)
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        if let Some(host) = host {
+            let name = host.as_bytes();
+            let list_len = 3 + name.len();
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+            extensions.extend_from_slice(&(list_len as u16).to_be_bytes());
+            extensions.push(NAME_TYPE_HOST_NAME);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        record.extend_from_slice(&message);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let record = client_hello(Some("WWW.Example.com."));
+        assert_eq!(
+            parse_client_hello(&record),
+            SniResult::Found("www.example.com".to_string())
+        );
+        for len in [0, 1, 4, 5, record.len() - 1] {
+            assert_eq!(parse_client_hello(&record[..len]), SniResult::Incomplete);
+        }
+        assert_eq!(parse_client_hello(&client_hello(None)), SniResult::NotFound);
+        assert_eq!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            SniResult::NotFound
+        );
+        assert_eq!(parse_client_hello(b"\x16\x01"), SniResult::NotFound);
+
+        // 握手消息拆分到两个记录中
+        let message = &record[5..];
+        let (first, second) = message.split_at(20);
+        let mut split = Vec::new();
+        for part in [first, second] {
+            split.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(
+            parse_client_hello(&split[..split.len() - 1]),
+            SniResult::Incomplete
+        );
+        assert_eq!(
+            parse_client_hello(&split),
+            SniResult::Found("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let routes: SniRoutes = "
+            # 注释
+            example.com      10.0.0.1:443
+            *.example.com    10.0.0.2:443 10.0.0.3:443@3  # 行尾注释
+            *.api.example.com [::1]:8443
+        "
+        .parse()
+        .expect("routes");
+        assert_eq!(routes.routes().len(), 3);
+        assert_eq!(routes.routes()[1].remotes.len(), 2);
+        assert_eq!(routes.routes()[1].remotes[1].1, 3);
+
+        assert_eq!(routes.lookup("example.com"), Some(0));
+        assert_eq!(routes.lookup("www.example.com"), Some(1));
+        assert_eq!(routes.lookup("a.b.example.com"), Some(1));
+        assert_eq!(routes.lookup("v1.api.example.com"), Some(2));
+        assert_eq!(routes.lookup("api.example.com"), Some(1));
+        assert_eq!(routes.lookup("example.org"), None);
+        assert_eq!(routes.lookup("badexample.com"), None);
+
+        assert!("example.com".parse::<SniRoutes>().is_err());
+        assert!("example.com 10.0.0.1".parse::<SniRoutes>().is_err());
+        assert!("bad/host 10.0.0.1:443".parse::<SniRoutes>().is_err());
+        let err = "a.com 10.0.0.1:443\nA.com. 10.0.0.2:443"
+            .parse::<SniRoutes>()
+            .expect_err("duplicate");
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+}
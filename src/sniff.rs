@@ -0,0 +1,154 @@
+//! 协议嗅探 (--expect-protocol)
+//!
+//! 连接后端之前窥探客户端连接开头的数据，不符合预期协议的连接直接关闭 (例如打到 TLS 端口的扫描流量)，
+//! 减少打到后端的垃圾连接。只适用于客户端先发数据的协议
+
+use std::fmt;
+use std::str::FromStr;
+
+/// TLS 握手记录的内容类型
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// HTTP/1.x 请求方法，`PRI` 为 HTTP/2 明文连接前言 (`PRI * HTTP/2.0`)
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI ",
+];
+
+/// 客户端连接应使用的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpectProtocol {
+    /// 不检查
+    #[default]
+    None,
+    /// 以 TLS ClientHello 开头
+    Tls,
+    /// 以 HTTP 请求行开头
+    Http,
+}
+
+/// 嗅探结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sniff {
+    /// 数据不足以判断，需要等待更多数据
+    Incomplete,
+    Match,
+    Mismatch,
+}
+
+impl ExpectProtocol {
+    /// 按连接开头的数据判断是否为预期协议
+    pub fn sniff(self, buf: &[u8]) -> Sniff {
+        match self {
+            ExpectProtocol::None => Sniff::Match,
+            ExpectProtocol::Tls => sniff_tls(buf),
+            ExpectProtocol::Http => sniff_http(buf),
+        }
+    }
+}
+
+/// TLS 记录头 (握手类型、3.x 版本、非零长度) 后紧跟 ClientHello 消息类型
+fn sniff_tls(buf: &[u8]) -> Sniff {
+    // 数据不足时先检查已收到的字节，明显不是 TLS 的连接不必等满
+    let header_ok = buf.first().is_none_or(|&b| b == CONTENT_TYPE_HANDSHAKE)
+        && buf.get(1).is_none_or(|&b| b == 3)
+        && buf.get(2).is_none_or(|&b| b <= 4)
+        && buf.get(5).is_none_or(|&b| b == HANDSHAKE_CLIENT_HELLO);
+    if !header_ok {
+        return Sniff::Mismatch;
+    }
+    if buf.len() < 6 {
+        return Sniff::Incomplete;
+    }
+    if u16::from_be_bytes([buf[3], buf[4]]) == 0 {
+        return Sniff::Mismatch;
+    }
+    Sniff::Match
+}
+
+/// 请求行以已知方法加空格开头
+fn sniff_http(buf: &[u8]) -> Sniff {
+    let mut incomplete = false;
+    for method in HTTP_METHODS {
+        if buf.starts_with(method) {
+            return Sniff::Match;
+        }
+        incomplete |= method.starts_with(buf);
+    }
+    if incomplete {
+        Sniff::Incomplete
+    } else {
+        Sniff::Mismatch
+    }
+}
+
+impl FromStr for ExpectProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ExpectProtocol::None),
+            "tls" => Ok(ExpectProtocol::Tls),
+            "http" => Ok(ExpectProtocol::Http),
+            _ => Err(format!(
+                "invalid protocol '{}', must be tls, http or none",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExpectProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectProtocol::None => write!(f, "none"),
+            ExpectProtocol::Tls => write!(f, "tls"),
+            ExpectProtocol::Http => write!(f, "http"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let tls = ExpectProtocol::Tls;
+        let hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+        assert_eq!(tls.sniff(&hello), Sniff::Match);
+        assert_eq!(tls.sniff(&hello[..4]), Sniff::Incomplete);
+        assert_eq!(tls.sniff(&[]), Sniff::Incomplete);
+        assert_eq!(tls.sniff(b"GET / HTTP/1.1\r\n"), Sniff::Mismatch);
+        assert_eq!(
+            tls.sniff(&[0x16, 0x03, 0x01, 0x00, 0x00, 0x01]),
+            Sniff::Mismatch
+        );
+        // 握手记录但不是 ClientHello
+        assert_eq!(
+            tls.sniff(&[0x16, 0x03, 0x03, 0x00, 0x30, 0x02]),
+            Sniff::Mismatch
+        );
+
+        let http = ExpectProtocol::Http;
+        assert_eq!(http.sniff(b"GET / HTTP/1.1\r\n"), Sniff::Match);
+        assert_eq!(http.sniff(b"PRI * HTTP/2.0\r\n"), Sniff::Match);
+        assert_eq!(http.sniff(b"OPTI"), Sniff::Incomplete);
+        assert_eq!(http.sniff(b"GETX"), Sniff::Mismatch);
+        assert_eq!(http.sniff(&hello), Sniff::Mismatch);
+
+        assert_eq!(ExpectProtocol::None.sniff(b"\x00"), Sniff::Match);
+        assert_eq!("tls".parse::<ExpectProtocol>(), Ok(ExpectProtocol::Tls));
+        assert!("ssh".parse::<ExpectProtocol>().is_err());
+        assert_eq!(ExpectProtocol::Http.to_string(), "http");
+    }
+}
@@ -0,0 +1,432 @@
+//! socket 创建
+//!
+//! 监听 socket 和 UDP 会话 socket 的创建、选项设置、绑定集中在两个构建器中，返回 mio 类型。
+//! 中途出错时已创建的 socket 由 `OwnedSocket` 关闭，调用方不需要手动 close
+
+use crate::config::{PortRange, SocketMark};
+use crate::error::Error;
+use crate::types::Address;
+use crate::{warn, PlatformRawFd};
+use mio::net::{TcpListener, UdpSocket};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+#[cfg(windows)]
+use crate::winsock::{self as libc, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+
+/// 拥有所有权的原始 socket，drop 时关闭
+struct OwnedSocket(PlatformRawFd);
+
+impl OwnedSocket {
+    fn new(family: libc::c_int, ty: libc::c_int, protocol: libc::c_int) -> io::Result<Self> {
+        crate::new_socket(family, ty, protocol).map(Self)
+    }
+
+    fn fd(&self) -> PlatformRawFd {
+        self.0
+    }
+
+    fn setsockopt_int(&self, level: libc::c_int, name: libc::c_int, value: libc::c_int) {
+        unsafe {
+            libc::setsockopt(
+                self.0,
+                level,
+                name,
+                &value as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+    }
+
+    fn bind(&self, addr: &Address) -> io::Result<()> {
+        let sockaddr = addr.to_sockaddr_storage();
+        let ret = unsafe {
+            libc::bind(
+                self.0,
+                &sockaddr as *const _ as *const libc::sockaddr,
+                addr.get_len() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn into_raw(self) -> PlatformRawFd {
+        let fd = self.0;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for OwnedSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// 监听 socket 的公共选项
+#[derive(Debug, Clone, Default)]
+struct ListenOptions<'a> {
+    reuse_port: bool,
+    buf_size: Option<usize>,
+    v6only: Option<bool>,
+    interface: Option<&'a str>,
+    mark: Option<&'a SocketMark>,
+    transparent: bool,
+}
+
+impl ListenOptions<'_> {
+    /// 创建 socket，设置选项并绑定到 `addr`
+    ///
+    /// 绑定网卡和标记失败只记录警告，其余步骤失败时返回错误
+    fn bind(&self, addr: &Address, ty: libc::c_int) -> Result<OwnedSocket, Error> {
+        let (proto_name, protocol) = if ty == libc::SOCK_STREAM {
+            ("TCP", 0)
+        } else {
+            ("UDP", libc::IPPROTO_UDP)
+        };
+        let family = addr.get_addr_family();
+        let socket = OwnedSocket::new(family, ty, protocol)
+            .map_err(|e| Error::socket(format!("failed to create {} socket", proto_name), e))?;
+
+        // Windows 上 SO_REUSEADDR 允许绑定其他进程正在监听的端口，不设置
+        #[cfg(unix)]
+        if addr.is_ip() {
+            socket.setsockopt_int(libc::SOL_SOCKET, libc::SO_REUSEADDR, 1);
+            // SO_REUSEPORT 支持多进程绑定同一端口
+            #[cfg(target_os = "linux")]
+            if self.reuse_port {
+                socket.setsockopt_int(libc::SOL_SOCKET, libc::SO_REUSEPORT, 1);
+            }
+        }
+        if let Some(size) = self.buf_size {
+            socket.setsockopt_int(libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int);
+            socket.setsockopt_int(libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int);
+        }
+        if family == libc::AF_INET6 {
+            if let Some(v6only) = self.v6only {
+                socket.setsockopt_int(
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_V6ONLY,
+                    libc::c_int::from(v6only),
+                );
+            }
+        }
+
+        // 绑定到指定网络接口
+        if let Some(interface) = self.interface {
+            if let Err(e) = crate::set_bind_to_device(socket.fd(), interface) {
+                warn!("failed to bind to interface {}: {}", interface, e);
+            }
+        }
+
+        // 标记发给客户端的流量，TCP 接受的连接继承监听 socket 的标记
+        if let Some(mark) = self.mark.filter(|_| addr.is_ip()) {
+            if let Err(e) = crate::set_socket_mark(socket.fd(), mark) {
+                warn!("failed to mark {} listen socket: {}", proto_name, e);
+            }
+        }
+
+        // 透明代理：接受目标地址不属于本机的连接 (TPROXY)
+        if self.transparent {
+            crate::set_transparent(socket.fd(), family).map_err(|e| {
+                Error::socket(
+                    format!("failed to set IP_TRANSPARENT on {} socket", proto_name),
+                    e,
+                )
+            })?;
+        }
+
+        // Windows 上 UDP socket 收到 ICMP 端口不可达后，下一次 recvfrom 会报 WSAECONNRESET，
+        // 一个客户端离开就会打断所有会话的收包
+        #[cfg(windows)]
+        if ty == libc::SOCK_DGRAM {
+            if let Err(e) = crate::winsock::disable_udp_connreset(socket.fd()) {
+                warn!("failed to disable SIO_UDP_CONNRESET: {}", e);
+            }
+        }
+
+        crate::set_nonblocking(socket.fd()).map_err(|e| {
+            Error::socket(
+                format!("failed to set {} socket non-blocking", proto_name),
+                e,
+            )
+        })?;
+        socket.bind(addr).map_err(|source| Error::Bind {
+            addr: format!("{} {}", proto_name, addr),
+            source,
+        })?;
+        Ok(socket)
+    }
+}
+
+/// TCP 监听 socket 构建器
+///
+/// ```no_run
+/// use tinyportmapper::sockets::TcpListenerBuilder;
+///
+/// let addr = "127.0.0.1:1234".parse()?;
+/// let listener = TcpListenerBuilder::new(&addr).backlog(128).build()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct TcpListenerBuilder<'a> {
+    addr: &'a Address,
+    options: ListenOptions<'a>,
+    backlog: u32,
+}
+
+impl<'a> TcpListenerBuilder<'a> {
+    /// 监听 `addr` (IP、Unix 域 socket 或 vsock 地址)
+    pub fn new(addr: &'a Address) -> Self {
+        Self {
+            addr,
+            options: ListenOptions::default(),
+            backlog: crate::config::DEFAULT_LISTEN_BACKLOG,
+        }
+    }
+
+    /// 设置 SO_REUSEPORT (仅 Linux)
+    pub fn reuse_port(mut self, enable: bool) -> Self {
+        self.options.reuse_port = enable;
+        self
+    }
+
+    /// 设置收发缓冲区大小，接受的连接继承该值
+    pub fn buf_size(mut self, size: usize) -> Self {
+        self.options.buf_size = Some(size);
+        self
+    }
+
+    /// 设置 IPV6_V6ONLY，None 时保持系统默认
+    pub fn v6only(mut self, v6only: Option<bool>) -> Self {
+        self.options.v6only = v6only;
+        self
+    }
+
+    /// 绑定到网络接口，失败时只记录警告
+    pub fn interface(mut self, interface: Option<&'a str>) -> Self {
+        self.options.interface = interface;
+        self
+    }
+
+    /// 标记发给客户端的流量，失败时只记录警告
+    pub fn mark(mut self, mark: Option<&'a SocketMark>) -> Self {
+        self.options.mark = mark;
+        self
+    }
+
+    /// 设置 IP_TRANSPARENT (仅 Linux)
+    pub fn transparent(mut self, enable: bool) -> Self {
+        self.options.transparent = enable;
+        self
+    }
+
+    /// listen 队列长度
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// 创建、绑定并开始监听
+    pub fn build(&self) -> Result<TcpListener, Error> {
+        let socket = self.options.bind(self.addr, libc::SOCK_STREAM)?;
+        let backlog = self.backlog.min(libc::c_int::MAX as u32) as libc::c_int;
+        if unsafe { libc::listen(socket.fd(), backlog) } < 0 {
+            return Err(Error::Listen {
+                addr: self.addr.to_string(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw()) })
+    }
+}
+
+/// UDP socket 构建器
+///
+/// `bind` 创建监听 socket，`connect` 创建连接到后端的会话 socket，`unconnected` 创建只绑定源地址的会话 socket
+#[derive(Debug, Clone, Default)]
+pub struct UdpSocketBuilder<'a> {
+    options: ListenOptions<'a>,
+    sources: &'a [IpAddr],
+    ports: Option<PortRange>,
+    transparent_source: Option<SocketAddr>,
+}
+
+impl<'a> UdpSocketBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置 SO_REUSEPORT (仅 Linux，仅 `bind`)
+    pub fn reuse_port(mut self, enable: bool) -> Self {
+        self.options.reuse_port = enable;
+        self
+    }
+
+    /// 设置收发缓冲区大小
+    pub fn buf_size(mut self, size: usize) -> Self {
+        self.options.buf_size = Some(size);
+        self
+    }
+
+    /// 设置 IPV6_V6ONLY，None 时保持系统默认 (仅 `bind`)
+    pub fn v6only(mut self, v6only: Option<bool>) -> Self {
+        self.options.v6only = v6only;
+        self
+    }
+
+    /// 绑定到网络接口，失败时只记录警告 (仅 `bind`)
+    pub fn interface(mut self, interface: Option<&'a str>) -> Self {
+        self.options.interface = interface;
+        self
+    }
+
+    /// 标记发给客户端的流量，失败时只记录警告 (仅 `bind`)
+    pub fn mark(mut self, mark: Option<&'a SocketMark>) -> Self {
+        self.options.mark = mark;
+        self
+    }
+
+    /// 设置 IP_TRANSPARENT (仅 Linux，仅 `bind`)
+    pub fn transparent(mut self, enable: bool) -> Self {
+        self.options.transparent = enable;
+        self
+    }
+
+    /// 连接前绑定的源地址 (取与后端相同地址族的一个) 和源端口范围 (仅 `connect`)
+    pub fn source(mut self, sources: &'a [IpAddr], ports: Option<PortRange>) -> Self {
+        self.sources = sources;
+        self.ports = ports;
+        self
+    }
+
+    /// 以客户端地址作为源地址连接 (透明代理，仅 `connect`)
+    pub fn transparent_source(mut self, client: Option<SocketAddr>) -> Self {
+        self.transparent_source = client;
+        self
+    }
+
+    /// 创建并绑定监听 socket
+    pub fn bind(&self, addr: &Address) -> Result<UdpSocket, Error> {
+        let socket = self.options.bind(addr, libc::SOCK_DGRAM)?;
+        Ok(unsafe { UdpSocket::from_raw_fd(socket.into_raw()) })
+    }
+
+    /// 创建连接到 `remote` 的非阻塞 socket，IPv4-mapped IPv6 地址使用 IPv4 socket
+    pub fn connect(&self, remote: &Address) -> Result<UdpSocket, Error> {
+        let (socket, remote) = self.open(remote)?;
+        let sockaddr = remote.to_sockaddr_storage();
+        let ret = unsafe {
+            libc::connect(
+                socket.fd(),
+                &sockaddr as *const _ as *const libc::sockaddr,
+                remote.get_len() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Connect {
+                addr: remote.to_string(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(unsafe { UdpSocket::from_raw_fd(socket.into_raw()) })
+    }
+
+    /// 创建发往 `remote` 的非阻塞 socket，只绑定源地址不连接，可以收到来自任意地址的数据包
+    /// (UDP 应用层网关等待远程从新端口回包)
+    pub fn unconnected(&self, remote: &Address) -> Result<UdpSocket, Error> {
+        let (socket, _) = self.open(remote)?;
+        Ok(unsafe { UdpSocket::from_raw_fd(socket.into_raw()) })
+    }
+
+    /// 按 `remote` 的地址族创建 socket 并绑定源地址，返回 socket 和实际使用的远程地址
+    fn open(&self, remote: &Address) -> Result<(OwnedSocket, Address), Error> {
+        let remote = remote
+            .from_ipv4_mapped_ipv6()
+            .unwrap_or_else(|| remote.clone());
+        let family = remote.get_addr_family();
+        let socket = OwnedSocket::new(family, libc::SOCK_DGRAM, libc::IPPROTO_UDP)
+            .map_err(|e| Error::socket("failed to create UDP socket", e))?;
+        crate::set_nonblocking(socket.fd())
+            .map_err(|e| Error::socket("failed to set UDP socket non-blocking", e))?;
+        if let Some(size) = self.options.buf_size {
+            crate::set_buf_size(socket.fd(), size)
+                .map_err(|e| Error::socket("failed to set UDP socket buffer size", e))?;
+        }
+
+        let bound = match self.transparent_source {
+            Some(client) => crate::bind_transparent(socket.fd(), family, client),
+            None => match crate::pick_source(self.sources, family) {
+                None if self.ports.is_none() => Ok(()),
+                ip => crate::bind_source(socket.fd(), family, ip, self.ports),
+            },
+        };
+        bound.map_err(|e| Error::socket("failed to bind UDP source address", e))?;
+        Ok((socket, remote))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_tcp_listener() {
+        let addr = Address::from_ipv4(Ipv4Addr::LOCALHOST, 0);
+        let listener = TcpListenerBuilder::new(&addr)
+            .buf_size(64 * 1024)
+            .backlog(16)
+            .build()
+            .expect("listener");
+        let local = listener.local_addr().expect("local addr");
+        std::net::TcpStream::connect(local).expect("connect");
+
+        // 端口被占用 (未设置 SO_REUSEPORT) 时返回 Bind 错误
+        let taken = Address::from_sockaddr(local);
+        let err = TcpListenerBuilder::new(&taken).build().unwrap_err();
+        assert!(matches!(err, Error::Bind { .. }));
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn test_udp_bind_and_connect() {
+        let addr = Address::from_ipv4(Ipv4Addr::LOCALHOST, 0);
+        let server = UdpSocketBuilder::new()
+            .buf_size(64 * 1024)
+            .bind(&addr)
+            .expect("bind");
+        let server_addr = server.local_addr().expect("local addr");
+
+        let localhost = [IpAddr::from(Ipv4Addr::LOCALHOST)];
+        let client = UdpSocketBuilder::new()
+            .source(&localhost, None)
+            .connect(&Address::from_sockaddr(server_addr))
+            .expect("connect");
+        assert_eq!(client.peer_addr().expect("peer addr"), server_addr);
+        assert_eq!(client.local_addr().expect("local addr").ip(), localhost[0]);
+
+        client.send(b"ping").expect("send");
+        let mut buf = [0u8; 16];
+        let mut received = None;
+        for _ in 0..100 {
+            match server.recv_from(&mut buf) {
+                Ok(result) => {
+                    received = Some(result);
+                    break;
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        let (n, from) = received.expect("datagram");
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, client.local_addr().expect("local addr"));
+    }
+}
@@ -0,0 +1,474 @@
+//! eBPF sockmap 加速 (仅 Linux)
+//!
+//! 连接建立后把客户端和远程两个 socket 加入 SOCKHASH，挂在上面的 sk_skb 流判决程序把
+//! 一端收到的数据直接重定向到另一端的发送队列，数据不再经过用户态。用户态只负责连接生命周期
+//! (EOF、超时、关闭) 和统计：程序按 socket 累计收到的字节数，事件循环定期读取。
+//!
+//! 程序按 socket cookie 查找对端：`pairs` 哈希表以 cookie 为键，值为对端 cookie 和字节计数，
+//! `sockets` SOCKHASH 以 cookie 为键保存 socket。程序很短，直接以指令形式写在这里，
+//! 不需要 clang 或 libbpf。需要 CAP_BPF 和 CAP_NET_ADMIN (或 root)，内核 5.7+
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// SO_COOKIE (include/uapi/asm-generic/socket.h)，libc 未导出
+const SO_COOKIE: libc::c_int = 57;
+
+// bpf(2) 命令
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_ATTACH: libc::c_int = 8;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_SOCKHASH: u32 = 18;
+const BPF_PROG_TYPE_SK_SKB: u32 = 14;
+const BPF_SK_SKB_STREAM_VERDICT: u32 = 5;
+/// ld_imm64 的 src_reg，表示立即数是 map fd
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+// 辅助函数编号
+const FN_MAP_LOOKUP_ELEM: i32 = 1;
+const FN_GET_SOCKET_COOKIE: i32 = 46;
+const FN_SK_REDIRECT_HASH: i32 = 72;
+
+/// 程序的许可证，部分辅助函数要求 GPL 兼容
+const LICENSE: &[u8] = b"Dual MIT/GPL\0";
+
+/// `pairs` 的值：对端 cookie 和本端累计收到的字节数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct PairValue {
+    peer: u64,
+    bytes: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Insn {
+    code: u8,
+    /// 低 4 位 dst_reg，高 4 位 src_reg
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+/// 生成流判决程序
+///
+/// ```text
+/// r6 = skb; r7 = skb->len
+/// key = bpf_get_socket_cookie(skb)
+/// v = pairs[key]; if !v return SK_PASS
+/// peer = v->peer; v->bytes += r7 (原子加)
+/// return bpf_sk_redirect_hash(skb, sockets, &peer, 0)
+/// ```
+fn program(pairs_fd: RawFd, sockets_fd: RawFd) -> Vec<Insn> {
+    const MOV64_X: u8 = 0xbf;
+    const MOV64_K: u8 = 0xb7;
+    const ADD64_K: u8 = 0x07;
+    const LDX_W: u8 = 0x61;
+    const LDX_DW: u8 = 0x79;
+    const STX_DW: u8 = 0x7b;
+    const ATOMIC_DW: u8 = 0xdb;
+    const LD_IMM64: u8 = 0x18;
+    const JEQ_K: u8 = 0x15;
+    const CALL: u8 = 0x85;
+    const EXIT: u8 = 0x95;
+    const SK_PASS: i32 = 1;
+
+    vec![
+        insn(MOV64_X, 6, 1, 0, 0),
+        insn(LDX_W, 7, 6, 0, 0),
+        insn(CALL, 0, 0, 0, FN_GET_SOCKET_COOKIE),
+        insn(STX_DW, 10, 0, -8, 0),
+        insn(LD_IMM64, 1, BPF_PSEUDO_MAP_FD, 0, pairs_fd),
+        insn(0, 0, 0, 0, 0),
+        insn(MOV64_X, 2, 10, 0, 0),
+        insn(ADD64_K, 2, 0, 0, -8),
+        insn(CALL, 0, 0, 0, FN_MAP_LOOKUP_ELEM),
+        insn(JEQ_K, 0, 0, 11, 0),
+        insn(LDX_DW, 1, 0, 0, 0),
+        insn(STX_DW, 10, 1, -16, 0),
+        insn(ATOMIC_DW, 0, 7, 8, 0),
+        insn(MOV64_X, 1, 6, 0, 0),
+        insn(LD_IMM64, 2, BPF_PSEUDO_MAP_FD, 0, sockets_fd),
+        insn(0, 0, 0, 0, 0),
+        insn(MOV64_X, 3, 10, 0, 0),
+        insn(ADD64_K, 3, 0, 0, -16),
+        insn(MOV64_K, 4, 0, 0, 0),
+        insn(CALL, 0, 0, 0, FN_SK_REDIRECT_HASH),
+        insn(EXIT, 0, 0, 0, 0),
+        insn(MOV64_K, 0, 0, 0, SK_PASS),
+        insn(EXIT, 0, 0, 0, 0),
+    ]
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn map_create(map_type: u32, value_size: u32, max_entries: u32) -> io::Result<RawFd> {
+    let mut attr = MapCreateAttr {
+        map_type,
+        key_size: 8,
+        value_size,
+        max_entries,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_CREATE, &mut attr).map(|fd| fd as RawFd)
+}
+
+fn map_elem<V>(
+    cmd: libc::c_int,
+    map_fd: RawFd,
+    key: &u64,
+    value: Option<&mut V>,
+) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: map_fd as u32,
+        key: key as *const u64 as u64,
+        value: value.map_or(0, |v| v as *mut V as u64),
+        ..Default::default()
+    };
+    bpf(cmd, &mut attr).map(|_| ())
+}
+
+/// socket 的 cookie (内核内唯一的 64 位标识)
+fn socket_cookie(fd: RawFd) -> io::Result<u64> {
+    let mut cookie: u64 = 0;
+    let mut len = std::mem::size_of::<u64>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_COOKIE,
+            &mut cookie as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cookie)
+}
+
+/// 接收队列中尚未读取的字节数
+fn queued_bytes(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut n: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut n) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n)
+}
+
+/// 已写入 socket 的累计字节数：已确认的加上发送队列中的
+fn written_bytes(fd: RawFd) -> io::Result<u64> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut outq: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut outq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info.tcpi_bytes_acked + outq.max(0) as u64)
+}
+
+/// 加载好的 sockmap 和流判决程序
+#[derive(Debug)]
+pub struct Sockmap {
+    sockets: RawFd,
+    pairs: RawFd,
+    prog: RawFd,
+}
+
+impl Sockmap {
+    /// 创建 map、加载程序并挂到 SOCKHASH 上，最多容纳 `max_pairs` 对连接
+    pub fn new(max_pairs: usize) -> io::Result<Self> {
+        let entries = max_pairs.saturating_mul(2).clamp(2, u32::MAX as usize) as u32;
+        let sockets = map_create(BPF_MAP_TYPE_SOCKHASH, 4, entries)?;
+        let mut sockmap = Sockmap {
+            sockets,
+            pairs: -1,
+            prog: -1,
+        };
+        sockmap.pairs = map_create(
+            BPF_MAP_TYPE_HASH,
+            std::mem::size_of::<PairValue>() as u32,
+            entries,
+        )?;
+        sockmap.prog = load_program(&program(sockmap.pairs, sockmap.sockets))?;
+
+        let mut attr = ProgAttachAttr {
+            target_fd: sockmap.sockets as u32,
+            attach_bpf_fd: sockmap.prog as u32,
+            attach_type: BPF_SK_SKB_STREAM_VERDICT,
+            ..Default::default()
+        };
+        bpf(BPF_PROG_ATTACH, &mut attr)?;
+        Ok(sockmap)
+    }
+
+    /// 把已建立的一对 TCP 连接交给内核转发
+    ///
+    /// 加入后任一端的接收队列中还有数据时撤销并返回 WouldBlock：这些数据到达时程序尚未生效，
+    /// 由用户态读取转发，之后可以再次尝试
+    pub fn attach(self: &Arc<Self>, local: RawFd, remote: RawFd) -> io::Result<SockmapPair> {
+        let cookies = [socket_cookie(local)?, socket_cookie(remote)?];
+        let pair = SockmapPair {
+            map: Arc::clone(self),
+            cookies,
+            fds: [local, remote],
+            base: [written_bytes(remote)?, written_bytes(local)?],
+            seen: [AtomicU64::new(0), AtomicU64::new(0)],
+        };
+        // 先写入对端关系再加入 SOCKHASH，加入后到达的数据都能找到对端；失败时 drop 清理
+        for (cookie, peer) in [(cookies[0], cookies[1]), (cookies[1], cookies[0])] {
+            let mut value = PairValue { peer, bytes: 0 };
+            map_elem(BPF_MAP_UPDATE_ELEM, self.pairs, &cookie, Some(&mut value))?;
+        }
+        for (cookie, fd) in [(cookies[0], local), (cookies[1], remote)] {
+            let mut fd = fd as u32;
+            map_elem(BPF_MAP_UPDATE_ELEM, self.sockets, &cookie, Some(&mut fd))?;
+        }
+        if queued_bytes(local)? > 0 || queued_bytes(remote)? > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "data arrived before the sockmap took over",
+            ));
+        }
+        Ok(pair)
+    }
+
+    /// socket 累计被重定向的字节数
+    fn bytes(&self, cookie: u64) -> Option<u64> {
+        let mut value = PairValue::default();
+        map_elem(BPF_MAP_LOOKUP_ELEM, self.pairs, &cookie, Some(&mut value)).ok()?;
+        Some(value.bytes)
+    }
+
+    fn remove(&self, cookie: u64) {
+        let _ = map_elem::<()>(BPF_MAP_DELETE_ELEM, self.sockets, &cookie, None);
+        let _ = map_elem::<()>(BPF_MAP_DELETE_ELEM, self.pairs, &cookie, None);
+    }
+}
+
+impl Drop for Sockmap {
+    fn drop(&mut self) {
+        for fd in [self.prog, self.pairs, self.sockets] {
+            if fd >= 0 {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+}
+
+/// 加载程序，失败时带上校验器日志的最后一行
+fn load_program(insns: &[Insn]) -> io::Result<RawFd> {
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_SK_SKB,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: LICENSE.as_ptr() as u64,
+        ..Default::default()
+    };
+    match bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(fd as RawFd),
+        Err(e) => {
+            let mut log = vec![0u8; 64 * 1024];
+            attr.log_level = 1;
+            attr.log_size = log.len() as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            let _ = bpf(BPF_PROG_LOAD, &mut attr);
+            let log = String::from_utf8_lossy(&log);
+            let last = log.trim_end_matches('\0').lines().last().unwrap_or("");
+            Err(io::Error::new(
+                e.kind(),
+                format!("failed to load sockmap program: {} {}", e, last),
+            ))
+        }
+    }
+}
+
+/// 已交给内核转发的一对连接，drop 时从 map 中移除
+#[derive(Debug)]
+pub struct SockmapPair {
+    map: Arc<Sockmap>,
+    /// 客户端和远程 socket 的 cookie
+    cookies: [u64; 2],
+    /// 客户端和远程 socket
+    fds: [RawFd; 2],
+    /// 加入时已写入远程和客户端 socket 的字节数
+    base: [u64; 2],
+    /// 上次读取时的累计字节数
+    seen: [AtomicU64; 2],
+}
+
+impl SockmapPair {
+    /// 取出上次以来内核转发的字节数：(客户端 -> 远程, 远程 -> 客户端)
+    pub fn take_bytes(&self) -> (u64, u64) {
+        let mut delta = [0u64; 2];
+        for (i, cookie) in self.cookies.iter().enumerate() {
+            if let Some(total) = self.map.bytes(*cookie) {
+                let seen = self.seen[i].swap(total, Ordering::Relaxed);
+                delta[i] = total.saturating_sub(seen);
+            }
+        }
+        (delta[0], delta[1])
+    }
+
+    /// 一个方向重定向的数据是否都已进入目标 socket 的发送队列
+    ///
+    /// 内核先把重定向的数据挂在目标 socket 的积压队列中，socket 关闭或移出 map 时积压的数据
+    /// 会被丢弃。读到 EOF 后要等这个方向转发完再关闭连接
+    pub fn drained(&self, to_remote: bool) -> bool {
+        let (src, dst) = if to_remote { (0, 1) } else { (1, 0) };
+        let redirected = match self.map.bytes(self.cookies[src]) {
+            Some(bytes) => bytes,
+            None => return true,
+        };
+        match written_bytes(self.fds[dst]) {
+            Ok(written) => written >= self.base[src] + redirected,
+            Err(_) => true,
+        }
+    }
+}
+
+impl Drop for SockmapPair {
+    fn drop(&mut self) {
+        for cookie in self.cookies {
+            self.map.remove(cookie);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_program_layout() {
+        let insns = program(3, 4);
+        // ld_imm64 占两条指令，第二条全零
+        assert_eq!(insns[4].imm, 3);
+        assert_eq!(insns[4].regs, (BPF_PSEUDO_MAP_FD << 4) | 1);
+        assert_eq!(insns[5].code, 0);
+        // 查找失败时跳到 SK_PASS
+        let target = 9 + 1 + insns[9].off as usize;
+        assert_eq!(insns[target].imm, 1);
+        assert_eq!(insns.last().expect("insn").code, 0x95);
+    }
+
+    /// 需要 CAP_BPF/CAP_NET_ADMIN，没有权限时跳过
+    #[test]
+    fn test_redirect() {
+        let sockmap = match Sockmap::new(4) {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                eprintln!("skip: {}", e);
+                return;
+            }
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let mut client = TcpStream::connect(addr).expect("connect");
+        let (proxy_local, _) = listener.accept().expect("accept");
+        let proxy_remote = TcpStream::connect(addr).expect("connect");
+        let (mut server, _) = listener.accept().expect("accept");
+
+        let pair = sockmap
+            .attach(proxy_local.as_raw_fd(), proxy_remote.as_raw_fd())
+            .expect("attach");
+        client.write_all(b"ping").expect("write");
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf, b"ping");
+        server.write_all(b"pong!").expect("write");
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf, b"pong!");
+        assert!(pair.drained(true) && pair.drained(false));
+        assert_eq!(pair.take_bytes(), (4, 5));
+        assert_eq!(pair.take_bytes(), (0, 0));
+    }
+}
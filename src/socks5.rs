@@ -0,0 +1,997 @@
+//! SOCKS5 上游代理和服务端 (RFC 1928/1929)
+//!
+//! 配置 `--upstream socks5://host:port[:user:pass]` 后，TCP 外连先连接代理，在事件循环中
+//! 完成 CONNECT 握手后再转发数据；UDP 会话通过 UDP ASSOCIATE 建立的中继转发，
+//! 关联在后台线程中建立，完成前到达的数据包暂存，关联建立后发出
+//!
+//! 配置 `--socks5-listen` 后作为 SOCKS5 服务端：客户端握手由 `Socks5Accept` 在事件循环中处理，
+//! CONNECT 的目标作为该连接的后端；UDP ASSOCIATE 使用 UDP 监听 socket 作为中继
+
+use crate::backend::Backend;
+use crate::config::PortRange;
+use crate::stats::BackendStats;
+use crate::sync::Recover;
+use crate::types::Address;
+#[cfg(windows)]
+use crate::winsock as libc;
+use crate::{debug, warn};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// UDP ASSOCIATE 握手超时时间
+pub const SOCKS5_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+const METHOD_NONE: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// 服务端应答码
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// 客户端握手消息的最大长度 (用户名/密码认证各 255 字节)
+const MAX_CLIENT_MESSAGE: usize = 513;
+
+/// 上游 SOCKS5 代理
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Upstream {
+    /// 代理地址
+    pub proxy: Address,
+    /// 用户名和密码
+    pub auth: Option<(String, String)>,
+}
+
+impl FromStr for Socks5Upstream {
+    type Err = String;
+
+    /// 解析 `socks5://host:port[:user:pass]`，IPv6 地址写作 `[addr]:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            format!(
+                "invalid upstream '{}': {}, expected socks5://host:port[:user:pass]",
+                s, reason
+            )
+        };
+        let rest = s
+            .strip_prefix("socks5://")
+            .ok_or_else(|| invalid("unsupported scheme"))?;
+        let (host, rest) = match rest.strip_prefix('[') {
+            Some(v6) => {
+                let end = v6.find(']').ok_or_else(|| invalid("missing ']'"))?;
+                let rest = v6[end + 1..]
+                    .strip_prefix(':')
+                    .ok_or_else(|| invalid("missing port"))?;
+                (&v6[..end], rest)
+            }
+            None => rest
+                .split_once(':')
+                .ok_or_else(|| invalid("missing port"))?,
+        };
+        let mut fields = rest.splitn(3, ':');
+        let port = fields
+            .next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .filter(|&p| p > 0)
+            .ok_or_else(|| invalid("bad port"))?;
+        let auth = match (fields.next(), fields.next()) {
+            (None, _) => None,
+            (Some(user), Some(pass))
+                if !user.is_empty() && user.len() <= 255 && pass.len() <= 255 =>
+            {
+                Some((user.to_string(), pass.to_string()))
+            }
+            _ => return Err(invalid("bad user:pass")),
+        };
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| invalid(&e.to_string()))?
+            .next()
+            .ok_or_else(|| invalid("host did not resolve"))?;
+        Ok(Self {
+            proxy: Address::from_sockaddr(addr),
+            auth,
+        })
+    }
+}
+
+impl std::fmt::Display for Socks5Upstream {
+    /// 输出时隐去密码
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.auth {
+            Some((ref user, _)) => write!(f, "socks5://{}:{}:***", self.proxy, user),
+            None => write!(f, "socks5://{}", self.proxy),
+        }
+    }
+}
+
+impl Socks5Upstream {
+    /// 创建到 target 的 CONNECT 握手
+    pub fn connect(&self, target: Address) -> Socks5Handshake {
+        Socks5Handshake::new(self.auth.clone(), CMD_CONNECT, target)
+    }
+
+    /// 阻塞建立 UDP ASSOCIATE，返回控制连接和中继地址
+    ///
+    /// 控制连接关闭时代理会释放中继，需要在会话存活期间保持打开
+    pub fn associate(&self, timeout: Duration) -> io::Result<(TcpStream, SocketAddr)> {
+        let proxy = self.proxy.to_sockaddr();
+        let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let unspecified = match proxy {
+            SocketAddr::V4(_) => Address::from_ipv4(Ipv4Addr::UNSPECIFIED, 0),
+            SocketAddr::V6(_) => Address::from_ipv6(Ipv6Addr::UNSPECIFIED, 0),
+        };
+        let mut handshake = Socks5Handshake::new(self.auth.clone(), CMD_UDP_ASSOCIATE, unspecified);
+        stream.write_all(&handshake.start())?;
+        let mut buf = [0u8; 512];
+        loop {
+            let len = stream.read(&mut buf)?;
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proxy closed the connection during handshake",
+                ));
+            }
+            match handshake.on_data(&buf[..len])? {
+                Step::Send(data) => stream.write_all(&data)?,
+                Step::Wait => {}
+                Step::Done(bound) => {
+                    let bound = bound.ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "proxy returned a domain name as UDP relay address",
+                        )
+                    })?;
+                    // 代理返回未指定地址时，中继与代理在同一地址上
+                    let relay = if bound.ip().is_unspecified() {
+                        SocketAddr::new(proxy.ip(), bound.port())
+                    } else {
+                        bound
+                    };
+                    stream.set_read_timeout(None)?;
+                    stream.set_write_timeout(None)?;
+                    return Ok((stream, relay));
+                }
+            }
+        }
+    }
+}
+
+/// 握手阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// 尚未发送问候
+    Idle,
+    /// 等待认证方法选择
+    Greeting,
+    /// 等待用户名/密码认证结果
+    Auth,
+    /// 等待 CONNECT/UDP ASSOCIATE 应答
+    Request,
+    /// 握手完成
+    Done,
+}
+
+/// 握手推进结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// 需要发送给代理的数据
+    Send(Vec<u8>),
+    /// 应答不完整，等待更多数据
+    Wait,
+    /// 握手完成，附带代理返回的绑定地址 (域名时为 None)
+    Done(Option<SocketAddr>),
+}
+
+/// 非阻塞 SOCKS5 握手状态机
+#[derive(Debug, Clone)]
+pub struct Socks5Handshake {
+    auth: Option<(String, String)>,
+    cmd: u8,
+    target: Address,
+    stage: Stage,
+    buf: Vec<u8>,
+}
+
+impl Socks5Handshake {
+    fn new(auth: Option<(String, String)>, cmd: u8, target: Address) -> Self {
+        Self {
+            auth,
+            cmd,
+            target,
+            stage: Stage::Idle,
+            buf: Vec::new(),
+        }
+    }
+
+    /// 是否已发送问候
+    pub fn is_started(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// 开始握手，返回问候消息
+    pub fn start(&mut self) -> Vec<u8> {
+        self.stage = Stage::Greeting;
+        let method = if self.auth.is_some() {
+            METHOD_USER_PASS
+        } else {
+            METHOD_NONE
+        };
+        vec![VERSION, 1, method]
+    }
+
+    /// 处理代理发来的数据
+    pub fn on_data(&mut self, data: &[u8]) -> io::Result<Step> {
+        self.buf.extend_from_slice(data);
+        match self.stage {
+            Stage::Idle | Stage::Done => Err(protocol_error("unexpected data from proxy")),
+            Stage::Greeting => {
+                let Some(reply) = self.take(2) else {
+                    return Ok(Step::Wait);
+                };
+                if reply[0] != VERSION {
+                    return Err(protocol_error("proxy is not a SOCKS5 server"));
+                }
+                match (reply[1], &self.auth) {
+                    (METHOD_NONE, _) => self.request(),
+                    (METHOD_USER_PASS, Some((user, pass))) => {
+                        let mut msg = vec![AUTH_VERSION, user.len() as u8];
+                        msg.extend_from_slice(user.as_bytes());
+                        msg.push(pass.len() as u8);
+                        msg.extend_from_slice(pass.as_bytes());
+                        self.stage = Stage::Auth;
+                        Ok(Step::Send(msg))
+                    }
+                    (METHOD_UNACCEPTABLE, _) => {
+                        Err(protocol_error("proxy rejected all authentication methods"))
+                    }
+                    _ => Err(protocol_error("proxy selected an unsupported method")),
+                }
+            }
+            Stage::Auth => {
+                let Some(reply) = self.take(2) else {
+                    return Ok(Step::Wait);
+                };
+                if reply[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "proxy authentication failed",
+                    ));
+                }
+                self.request()
+            }
+            Stage::Request => {
+                if self.buf.len() < 4 {
+                    return Ok(Step::Wait);
+                }
+                if self.buf[0] != VERSION {
+                    return Err(protocol_error("proxy is not a SOCKS5 server"));
+                }
+                if self.buf[1] != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("proxy request failed: {}", reply_message(self.buf[1])),
+                    ));
+                }
+                let Some((bound, len)) = decode_addr(&self.buf[3..])? else {
+                    return Ok(Step::Wait);
+                };
+                self.buf.drain(..3 + len);
+                self.stage = Stage::Done;
+                Ok(Step::Done(bound))
+            }
+        }
+    }
+
+    /// 握手完成后取出应答之后已收到的数据 (后端先发送的数据可能与应答一起到达)
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// 取出 len 字节的应答，数据不足时返回 None
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if self.buf.len() < len {
+            return None;
+        }
+        Some(self.buf.drain(..len).collect())
+    }
+
+    fn request(&mut self) -> io::Result<Step> {
+        self.stage = Stage::Request;
+        let mut msg = vec![VERSION, self.cmd, 0];
+        encode_addr(&mut msg, &self.target.to_sockaddr());
+        Ok(Step::Send(msg))
+    }
+}
+
+/// 解析 `user:pass` 形式的认证信息 (密码中可以包含 `:`)
+pub fn parse_auth(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((user, pass))
+            if !user.is_empty() && user.len() <= 255 && !pass.is_empty() && pass.len() <= 255 =>
+        {
+            Ok((user.to_string(), pass.to_string()))
+        }
+        _ => Err(format!(
+            "invalid socks5 auth '{}', expected user:pass (1-255 bytes each)",
+            s
+        )),
+    }
+}
+
+/// SOCKS5 服务端 (`--socks5-listen`)
+#[derive(Debug)]
+pub struct Socks5Server {
+    /// 客户端必须提供的用户名和密码，None 时不认证
+    auth: Option<(String, String)>,
+    /// 有 UDP ASSOCIATE 控制连接的客户端 IP 和连接数，只接受这些 IP 发往中继的数据包
+    associations: Mutex<HashMap<IpAddr, usize>>,
+    /// 所有目标共用的后端统计 (目标地址不固定，不按地址分别统计)
+    stats: Arc<BackendStats>,
+}
+
+impl Socks5Server {
+    pub fn new(auth: Option<(String, String)>, stats: Arc<BackendStats>) -> Self {
+        Self {
+            auth,
+            associations: Mutex::new(HashMap::new()),
+            stats,
+        }
+    }
+
+    /// 客户端请求的目标作为后端
+    pub fn backend(&self, target: SocketAddr) -> Arc<Backend> {
+        Arc::new(Backend::direct(
+            Address::from_sockaddr(target),
+            Arc::clone(&self.stats),
+        ))
+    }
+
+    /// 新客户端连接的握手状态
+    pub fn accept(&self) -> Socks5Accept {
+        Socks5Accept {
+            auth: self.auth.clone(),
+            stage: AcceptStage::Greeting,
+            buf: Vec::new(),
+        }
+    }
+
+    /// 登记 UDP ASSOCIATE 控制连接
+    pub fn associate(&self, ip: IpAddr) {
+        *self.associations.lock().recover().entry(ip).or_insert(0) += 1;
+    }
+
+    /// 控制连接关闭
+    pub fn release(&self, ip: IpAddr) {
+        let mut associations = self.associations.lock().recover();
+        if let Some(count) = associations.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                associations.remove(&ip);
+            }
+        }
+    }
+
+    /// 该 IP 是否有 UDP ASSOCIATE 控制连接
+    pub fn is_associated(&self, ip: IpAddr) -> bool {
+        self.associations.lock().recover().contains_key(&ip)
+    }
+}
+
+/// 服务端握手阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptStage {
+    /// 等待客户端问候 (认证方法列表)
+    Greeting,
+    /// 等待用户名/密码
+    Auth,
+    /// 等待 CONNECT/UDP ASSOCIATE 请求
+    Request,
+    /// 请求已处理
+    Done,
+}
+
+/// 服务端握手推进结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Accept {
+    /// 消息不完整，等待更多数据
+    Wait,
+    /// 需要发给客户端的应答，之后继续握手
+    Reply(Vec<u8>),
+    /// CONNECT 请求的目标和请求之后已收到的数据 (连接后端后再应答)
+    Connect(SocketAddr, Vec<u8>),
+    /// UDP ASSOCIATE 请求 (由调用方应答中继地址)
+    Associate,
+    /// 拒绝：发送应答后关闭连接
+    Reject(Vec<u8>, &'static str),
+}
+
+/// 非阻塞 SOCKS5 服务端握手状态机
+#[derive(Debug, Clone)]
+pub struct Socks5Accept {
+    auth: Option<(String, String)>,
+    stage: AcceptStage,
+    buf: Vec<u8>,
+}
+
+impl Socks5Accept {
+    /// 处理客户端发来的数据，返回 `Reply` 后应以空数据再次调用 (客户端可能不等应答就发送后续消息)
+    ///
+    /// 请求处理之后收到的数据 (UDP ASSOCIATE 控制连接上的数据) 丢弃
+    pub fn on_data(&mut self, data: &[u8]) -> Accept {
+        if self.stage == AcceptStage::Done {
+            return Accept::Wait;
+        }
+        self.buf.extend_from_slice(data);
+        match self.step() {
+            // 积压的是不完整的消息，超过最大长度时不会再完整
+            Accept::Wait if self.buf.len() > MAX_CLIENT_MESSAGE => {
+                Accept::Reject(Vec::new(), "handshake message too long")
+            }
+            accept => accept,
+        }
+    }
+
+    /// 处理积压中的下一条消息
+    fn step(&mut self) -> Accept {
+        match self.stage {
+            AcceptStage::Greeting => {
+                let Some(&[version, count]) = self.buf.get(..2) else {
+                    return Accept::Wait;
+                };
+                if version != VERSION {
+                    return Accept::Reject(Vec::new(), "not a SOCKS5 client");
+                }
+                let Some(methods) = self.take(2 + count as usize) else {
+                    return Accept::Wait;
+                };
+                let wanted = if self.auth.is_some() {
+                    METHOD_USER_PASS
+                } else {
+                    METHOD_NONE
+                };
+                if !methods[2..].contains(&wanted) {
+                    return Accept::Reject(
+                        vec![VERSION, METHOD_UNACCEPTABLE],
+                        "no acceptable authentication method",
+                    );
+                }
+                self.stage = if self.auth.is_some() {
+                    AcceptStage::Auth
+                } else {
+                    AcceptStage::Request
+                };
+                Accept::Reply(vec![VERSION, wanted])
+            }
+            AcceptStage::Auth => {
+                // VER ULEN UNAME PLEN PASSWD
+                let Some(&ulen) = self.buf.get(1) else {
+                    return Accept::Wait;
+                };
+                let ulen = ulen as usize;
+                let Some(&plen) = self.buf.get(2 + ulen) else {
+                    return Accept::Wait;
+                };
+                let Some(msg) = self.take(3 + ulen + plen as usize) else {
+                    return Accept::Wait;
+                };
+                let user = &msg[2..2 + ulen];
+                let pass = &msg[3 + ulen..];
+                let ok = msg[0] == AUTH_VERSION
+                    && self
+                        .auth
+                        .as_ref()
+                        .is_some_and(|(u, p)| u.as_bytes() == user && p.as_bytes() == pass);
+                if !ok {
+                    return Accept::Reject(vec![AUTH_VERSION, 1], "authentication failed");
+                }
+                self.stage = AcceptStage::Request;
+                Accept::Reply(vec![AUTH_VERSION, 0])
+            }
+            AcceptStage::Request => {
+                if self.buf.len() < 4 {
+                    return Accept::Wait;
+                }
+                if self.buf[0] != VERSION {
+                    return Accept::Reject(Vec::new(), "not a SOCKS5 client");
+                }
+                let cmd = self.buf[1];
+                let (target, len) = match decode_addr(&self.buf[3..]) {
+                    Ok(Some(decoded)) => decoded,
+                    Ok(None) => return Accept::Wait,
+                    Err(_) => {
+                        return Accept::Reject(
+                            reply(REP_ADDRESS_NOT_SUPPORTED, None),
+                            "unknown address type",
+                        )
+                    }
+                };
+                self.buf.drain(..3 + len);
+                self.stage = AcceptStage::Done;
+                match (cmd, target) {
+                    (CMD_CONNECT, Some(target)) => {
+                        Accept::Connect(target, std::mem::take(&mut self.buf))
+                    }
+                    // 事件循环中不能阻塞解析域名，客户端需自行解析
+                    (CMD_CONNECT, None) => Accept::Reject(
+                        reply(REP_ADDRESS_NOT_SUPPORTED, None),
+                        "domain name targets are not supported",
+                    ),
+                    (CMD_UDP_ASSOCIATE, _) => Accept::Associate,
+                    _ => Accept::Reject(
+                        reply(REP_COMMAND_NOT_SUPPORTED, None),
+                        "command not supported",
+                    ),
+                }
+            }
+            AcceptStage::Done => Accept::Wait,
+        }
+    }
+
+    /// 取出 len 字节的消息，数据不足时返回 None
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if self.buf.len() < len {
+            return None;
+        }
+        Some(self.buf.drain(..len).collect())
+    }
+}
+
+/// 服务端对 CONNECT/UDP ASSOCIATE 的应答，`bound` 为 None 时绑定地址填 0.0.0.0:0
+pub fn reply(code: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let mut msg = vec![VERSION, code, 0];
+    encode_addr(
+        &mut msg,
+        &bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+    );
+    msg
+}
+
+/// 关联建立前每个会话最多暂存的数据包数量
+pub const MAX_PENDING_DATAGRAMS: usize = 16;
+
+/// UDP ASSOCIATE 会话状态
+///
+/// 关联在后台线程中建立，期间事件循环发送的数据包暂存在这里，
+/// 关联完成后由后台线程连接会话 socket 并发出
+#[derive(Debug)]
+pub struct Socks5Association {
+    /// 数据包最终目标
+    pub target: Address,
+    state: Mutex<AssociationState>,
+    connected: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct AssociationState {
+    /// 控制连接，关闭时代理释放中继
+    control: Option<TcpStream>,
+    /// 关联建立前暂存的数据包 (已封装中继头)
+    pending: Vec<Vec<u8>>,
+}
+
+impl Socks5Association {
+    pub fn new(target: Address) -> Self {
+        Self {
+            target,
+            state: Mutex::new(AssociationState::default()),
+            connected: AtomicBool::new(false),
+        }
+    }
+
+    /// 会话 socket 是否已连接到中继
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// 中继尚未就绪时暂存数据包 (超过上限时丢弃)，已就绪时原样返回由调用方直接发送
+    pub fn queue(&self, packet: Vec<u8>) -> Option<Vec<u8>> {
+        if self.is_connected() {
+            return Some(packet);
+        }
+        let mut state = self.state.lock().recover();
+        // 加锁后再检查一次，避免与 establish 竞争丢包
+        if self.is_connected() {
+            return Some(packet);
+        }
+        if state.pending.len() < MAX_PENDING_DATAGRAMS {
+            state.pending.push(packet);
+        }
+        None
+    }
+
+    /// 关联建立完成：连接会话 socket 到中继，发出暂存的数据包并保存控制连接
+    pub fn establish(
+        &self,
+        control: TcpStream,
+        relay: SocketAddr,
+        socket: &UdpSocket,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().recover();
+        socket.connect(relay)?;
+        for packet in state.pending.drain(..) {
+            let _ = socket.send(&packet);
+        }
+        state.control = Some(control);
+        self.connected.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// 在后台线程中建立 UDP ASSOCIATE
+///
+/// 线程持有会话 socket 的副本 (dup)，会话先被关闭也不会误操作复用的 fd
+pub fn spawn_associate(
+    upstream: Arc<Socks5Upstream>,
+    association: Arc<Socks5Association>,
+    fd: crate::PlatformRawFd,
+    addr_s: String,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    let dup = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    #[cfg(windows)]
+    let dup =
+        unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(fd) }.try_clone_to_owned()?;
+    let socket = UdpSocket::from(dup);
+    std::thread::Builder::new()
+        .name("socks5-associate".to_string())
+        .spawn(move || {
+            let result =
+                upstream
+                    .associate(SOCKS5_HANDSHAKE_TIMEOUT)
+                    .and_then(|(control, relay)| {
+                        association.establish(control, relay, &socket)?;
+                        Ok(relay)
+                    });
+            match result {
+                Ok(relay) => debug!("[udp] upstream relay for {} is {}", addr_s, relay),
+                Err(e) => warn!(
+                    "[udp] upstream proxy associate for {} failed: {}",
+                    addr_s, e
+                ),
+            }
+        })?;
+    Ok(())
+}
+
+/// 封装 UDP 中继数据包 (RSV FRAG ATYP DST.ADDR DST.PORT DATA)
+pub fn encode_udp(target: &Address, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 22);
+    packet.extend_from_slice(&[0, 0, 0]);
+    encode_addr(&mut packet, &target.to_sockaddr());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// 解析 UDP 中继数据包，返回负载的起始位置 (不支持分片，分片包返回 None)
+pub fn decode_udp(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+    match decode_addr(&packet[3..]) {
+        Ok(Some((_, len))) => Some(3 + len),
+        _ => None,
+    }
+}
+
+/// 解析客户端发往中继的数据包，返回目标地址和负载的起始位置 (域名目标和分片包返回 None)
+pub fn decode_udp_target(packet: &[u8]) -> Option<(SocketAddr, usize)> {
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+    match decode_addr(&packet[3..]) {
+        Ok(Some((Some(target), len))) => Some((target, 3 + len)),
+        _ => None,
+    }
+}
+
+fn encode_addr(out: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// 解析 ATYP 开头的地址，返回地址 (域名时为 None) 和占用的字节数，数据不足时返回 Ok(None)
+fn decode_addr(data: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    let Some(&atyp) = data.first() else {
+        return Ok(None);
+    };
+    let addr_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => match data.get(1) {
+            Some(&len) => 1 + len as usize,
+            None => return Ok(None),
+        },
+        _ => return Err(protocol_error("unknown address type in proxy reply")),
+    };
+    let total = 1 + addr_len + 2;
+    if data.len() < total {
+        return Ok(None);
+    }
+    let port = u16::from_be_bytes([data[total - 2], data[total - 1]]);
+    let ip = match atyp {
+        ATYP_IPV4 => Some(IpAddr::from(
+            <[u8; 4]>::try_from(&data[1..5]).expect("length"),
+        )),
+        ATYP_IPV6 => Some(IpAddr::from(
+            <[u8; 16]>::try_from(&data[1..17]).expect("length"),
+        )),
+        _ => None,
+    };
+    Ok(Some((ip.map(|ip| SocketAddr::new(ip, port)), total)))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// 创建未连接的非阻塞 UDP socket，关联建立后再连接到中继 (作为服务端时用 sendto 发往各个目标)；
+/// 绑定 `sources` 中与 `proxy` 地址族相同的地址 (没有时为通配地址) 和 `ports` 中的端口
+pub fn new_relay_udp_fd(
+    proxy: &Address,
+    buf_size: usize,
+    sources: &[IpAddr],
+    ports: Option<PortRange>,
+) -> io::Result<crate::PlatformRawFd> {
+    let family = match proxy.to_sockaddr() {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = crate::new_socket(family, libc::SOCK_DGRAM, 0)?;
+    let result = crate::set_nonblocking(fd)
+        .and_then(|_| crate::set_buf_size(fd, buf_size))
+        .and_then(|_| crate::bind_source(fd, family, crate::pick_source(sources, family), ports));
+    if let Err(e) = result {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_upstream() {
+        let upstream: Socks5Upstream = "socks5://127.0.0.1:1080".parse().expect("parse");
+        assert_eq!(upstream.proxy.to_string(), "127.0.0.1:1080");
+        assert_eq!(upstream.auth, None);
+
+        let upstream: Socks5Upstream = "socks5://[::1]:1080:user:p:ss".parse().expect("parse");
+        assert_eq!(
+            upstream.proxy.to_sockaddr(),
+            "[::1]:1080".parse().expect("addr")
+        );
+        assert_eq!(
+            upstream.auth,
+            Some(("user".to_string(), "p:ss".to_string()))
+        );
+        assert_eq!(upstream.to_string(), "socks5://[::1]:1080:user:***");
+
+        assert!("http://127.0.0.1:1080".parse::<Socks5Upstream>().is_err());
+        assert!("socks5://127.0.0.1".parse::<Socks5Upstream>().is_err());
+        assert!("socks5://127.0.0.1:0".parse::<Socks5Upstream>().is_err());
+        assert!("socks5://127.0.0.1:1080:user"
+            .parse::<Socks5Upstream>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_connect_handshake() {
+        let upstream: Socks5Upstream = "socks5://127.0.0.1:1080:u:pw".parse().expect("parse");
+        let target: Address = "10.0.0.1:443".parse().expect("addr");
+        let mut handshake = upstream.connect(target);
+        assert!(!handshake.is_started());
+        assert_eq!(handshake.start(), vec![5, 1, METHOD_USER_PASS]);
+
+        // 分段到达的应答
+        assert_eq!(handshake.on_data(&[5]).expect("greeting"), Step::Wait);
+        assert_eq!(
+            handshake.on_data(&[METHOD_USER_PASS]).expect("greeting"),
+            Step::Send(vec![1, 1, b'u', 2, b'p', b'w'])
+        );
+        assert_eq!(
+            handshake.on_data(&[1, 0]).expect("auth"),
+            Step::Send(vec![5, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 1, 187])
+        );
+        assert_eq!(
+            handshake
+                .on_data(&[5, 0, 0, ATYP_IPV4, 1, 2])
+                .expect("reply"),
+            Step::Wait
+        );
+        assert_eq!(
+            handshake
+                .on_data(&[3, 4, 0, 80, b'S', b'S'])
+                .expect("reply"),
+            Step::Done(Some("1.2.3.4:80".parse().expect("addr")))
+        );
+        assert_eq!(handshake.take_remaining(), b"SS");
+
+        let mut refused = upstream.connect("10.0.0.1:443".parse().expect("addr"));
+        refused.start();
+        assert!(refused.on_data(&[5, METHOD_NONE]).is_ok());
+        let err = refused
+            .on_data(&[5, 5, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+            .expect_err("refused");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_udp_packet() {
+        let target: Address = "[2001:db8::1]:53".parse().expect("addr");
+        let packet = encode_udp(&target, b"hello");
+        assert_eq!(&packet[..4], &[0, 0, 0, ATYP_IPV6]);
+        let offset = decode_udp(&packet).expect("decode");
+        assert_eq!(&packet[offset..], b"hello");
+
+        // 分片包和截断的包被丢弃
+        let mut fragment = packet.clone();
+        fragment[2] = 1;
+        assert_eq!(decode_udp(&fragment), None);
+        assert_eq!(decode_udp(&packet[..10]), None);
+    }
+
+    #[test]
+    fn test_accept() {
+        let server = Socks5Server::new(Some(parse_auth("user:p:w").expect("auth")), Arc::default());
+        let mut accept = server.accept();
+        // 分段到达的问候，认证和请求一起到达
+        assert_eq!(accept.on_data(&[5, 2]), Accept::Wait);
+        assert_eq!(
+            accept.on_data(&[METHOD_NONE, METHOD_USER_PASS]),
+            Accept::Reply(vec![5, METHOD_USER_PASS])
+        );
+        let mut data = vec![1, 4, b'u', b's', b'e', b'r', 3, b'p', b':', b'w'];
+        data.extend_from_slice(&[5, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80, b'G']);
+        assert_eq!(accept.on_data(&data), Accept::Reply(vec![1, 0]));
+        assert_eq!(
+            accept.on_data(&[]),
+            Accept::Connect("10.0.0.1:80".parse().expect("addr"), b"G".to_vec())
+        );
+
+        let mut accept = server.accept();
+        assert!(matches!(
+            accept.on_data(&[5, 1, METHOD_NONE]),
+            Accept::Reject(reply, _) if reply == [5, METHOD_UNACCEPTABLE]
+        ));
+        let mut accept = server.accept();
+        accept.on_data(&[5, 1, METHOD_USER_PASS]);
+        assert!(matches!(
+            accept.on_data(&[1, 4, b'u', b's', b'e', b'r', 1, b'x']),
+            Accept::Reject(reply, _) if reply == [1, 1]
+        ));
+
+        // 不认证时：域名目标和 BIND 被拒绝，UDP ASSOCIATE 交给调用方
+        let server = Socks5Server::new(None, Arc::default());
+        let request = |cmd: u8, target: &[u8]| {
+            let mut accept = server.accept();
+            assert_eq!(
+                accept.on_data(&[5, 1, METHOD_NONE]),
+                Accept::Reply(vec![5, 0])
+            );
+            let mut data = vec![5, cmd, 0];
+            data.extend_from_slice(target);
+            accept.on_data(&data)
+        };
+        assert!(matches!(
+            request(CMD_CONNECT, &[ATYP_DOMAIN, 3, b'a', b'.', b'b', 0, 80]),
+            Accept::Reject(reply, _) if reply[1] == REP_ADDRESS_NOT_SUPPORTED
+        ));
+        assert!(matches!(
+            request(2, &[ATYP_IPV4, 10, 0, 0, 1, 0, 80]),
+            Accept::Reject(reply, _) if reply[1] == REP_COMMAND_NOT_SUPPORTED
+        ));
+        assert_eq!(
+            request(CMD_UDP_ASSOCIATE, &[ATYP_IPV4, 0, 0, 0, 0, 0, 0]),
+            Accept::Associate
+        );
+
+        let ip = "10.0.0.2".parse().expect("ip");
+        server.associate(ip);
+        server.associate(ip);
+        server.release(ip);
+        assert!(server.is_associated(ip));
+        server.release(ip);
+        assert!(!server.is_associated(ip));
+
+        assert!(parse_auth("user").is_err());
+        assert!(parse_auth(":pass").is_err());
+    }
+
+    #[test]
+    fn test_udp_target() {
+        let target: Address = "192.0.2.1:53".parse().expect("addr");
+        let packet = encode_udp(&target, b"query");
+        let (addr, offset) = decode_udp_target(&packet).expect("decode");
+        assert_eq!(addr, target.to_sockaddr());
+        assert_eq!(&packet[offset..], b"query");
+        assert_eq!(
+            decode_udp_target(&[0, 0, 0, ATYP_DOMAIN, 1, b'a', 0, 53]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_associate() {
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let relay_port = relay_socket.local_addr().expect("local addr").port();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let proxy = listener.local_addr().expect("local addr");
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut buf = [0u8; 64];
+            stream.read_exact(&mut buf[..3]).expect("greeting");
+            stream.write_all(&[5, METHOD_NONE]).expect("method");
+            stream.read_exact(&mut buf[..10]).expect("request");
+            assert_eq!(buf[1], CMD_UDP_ASSOCIATE);
+            // 返回未指定地址，中继地址取代理的 IP
+            let mut reply = vec![5, 0, 0, ATYP_IPV4, 0, 0, 0, 0];
+            reply.extend_from_slice(&relay_port.to_be_bytes());
+            stream.write_all(&reply).expect("reply");
+            stream
+        });
+
+        let upstream: Socks5Upstream = format!("socks5://{}", proxy).parse().expect("parse");
+        let (control, relay) = upstream
+            .associate(SOCKS5_HANDSHAKE_TIMEOUT)
+            .expect("associate");
+        assert_eq!(relay, SocketAddr::from(([127, 0, 0, 1], relay_port)));
+        server.join().expect("server");
+
+        // 关联建立前的数据包暂存，建立后发出
+        let association = Socks5Association::new("10.0.0.1:53".parse().expect("addr"));
+        assert_eq!(association.queue(b"early".to_vec()), None);
+        let session = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        association
+            .establish(control, relay, &session)
+            .expect("establish");
+        assert!(association.is_connected());
+        let mut buf = [0u8; 16];
+        let len = relay_socket.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"early");
+        assert_eq!(association.queue(b"late".to_vec()), Some(b"late".to_vec()));
+    }
+}
@@ -1,99 +1,639 @@
 //! 数据统计模块
 //!
 //! 跟踪流量统计信息
+//!
+//! 热路径计数器按线程分片，每个线程只写自己的缓存行，读取时再汇总
+
+use crate::sync::Recover;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
-use std::sync::atomic::{AtomicU64, Ordering};
+/// 计数器分片数 (线程数超过时多个线程共用一个分片)
+pub const COUNTER_SHARDS: usize = 16;
 
-/// 全局流量统计
+/// 独占一个缓存行的计数分片
 #[derive(Debug, Default)]
-pub struct TrafficStats {
+#[repr(align(64))]
+struct Shard(AtomicU64);
+
+/// 当前线程使用的分片编号 (首次使用时轮流分配)
+fn shard_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed) % COUNTER_SHARDS;
+    }
+    INDEX.with(|index| *index)
+}
+
+/// 按线程分片的计数器
+///
+/// 增减只修改当前线程的分片，读取时汇总所有分片。
+/// 增减可能落在不同分片上，分片按回绕算术累加，汇总结果仍然准确
+#[derive(Debug, Default)]
+pub struct ShardedCounter {
+    shards: [Shard; COUNTER_SHARDS],
+}
+
+impl ShardedCounter {
+    /// 增加计数
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.shards[shard_index()].0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// 减少计数
+    #[inline]
+    pub fn sub(&self, n: u64) {
+        self.shards[shard_index()].0.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// 汇总所有分片
+    pub fn get(&self) -> u64 {
+        self.shards.iter().fold(0u64, |sum, shard| {
+            sum.wrapping_add(shard.0.load(Ordering::Relaxed))
+        })
+    }
+
+    /// 清零
+    pub fn reset(&self) {
+        for shard in &self.shards {
+            shard.0.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 延迟直方图桶数：第 i 个桶记录 [2^(i-1), 2^i) 微秒，最后一个桶不设上限
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// 按 2 的幂分桶的延迟直方图，百分位取所在桶的上界
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+/// 延迟百分位快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// 样本数
+    pub count: u64,
+    /// 中位数
+    pub p50: Duration,
+    /// 90 百分位
+    pub p90: Duration,
+    /// 99 百分位
+    pub p99: Duration,
+}
+
+impl LatencyHistogram {
+    /// 记录一个样本
+    pub fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let index = ((u64::BITS - micros.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 百分位 (0-100)，没有样本时返回 None
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * p / 100.0).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        let index = counts
+            .iter()
+            .position(|&count| {
+                seen += count;
+                seen >= rank
+            })
+            .unwrap_or(LATENCY_BUCKETS - 1);
+        Some(Duration::from_micros(1 << index))
+    }
+
+    /// 读取样本数和常用百分位
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            count: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum(),
+            p50: self.percentile(50.0).unwrap_or_default(),
+            p90: self.percentile(90.0).unwrap_or_default(),
+            p99: self.percentile(99.0).unwrap_or_default(),
+        }
+    }
+
+    /// 清空
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl std::fmt::Display for LatencySnapshot {
+    /// 输出 p50/p90/p99，没有样本时输出 `-`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            return f.write_str("-");
+        }
+        write!(
+            f,
+            "{}/{}/{}",
+            format_latency(self.p50),
+            format_latency(self.p90),
+            format_latency(self.p99)
+        )
+    }
+}
+
+/// 转发方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 客户端 -> 服务端
+    ClientToServer,
+    /// 服务端 -> 客户端
+    ServerToClient,
+}
+
+/// 流量统计快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
     /// TCP 接收字节数
-    pub tcp_bytes_received: AtomicU64,
+    pub tcp_bytes_received: u64,
     /// TCP 发送字节数
-    pub tcp_bytes_sent: AtomicU64,
+    pub tcp_bytes_sent: u64,
+    /// TCP 客户端 -> 服务端 转发字节数
+    pub tcp_bytes_c2s: u64,
+    /// TCP 服务端 -> 客户端 转发字节数
+    pub tcp_bytes_s2c: u64,
     /// UDP 接收字节数
-    pub udp_bytes_received: AtomicU64,
+    pub udp_bytes_received: u64,
     /// UDP 发送字节数
-    pub udp_bytes_sent: AtomicU64,
+    pub udp_bytes_sent: u64,
+    /// UDP 客户端 -> 服务端 转发字节数
+    pub udp_bytes_c2s: u64,
+    /// UDP 服务端 -> 客户端 转发字节数
+    pub udp_bytes_s2c: u64,
     /// TCP 连接数
-    pub tcp_connections: AtomicU64,
+    pub tcp_connections: u64,
     /// UDP 会话数
+    pub udp_sessions: u64,
+    /// TCP 从接受连接到远程连接建立的延迟
+    pub connect_latency: LatencySnapshot,
+    /// TCP 从接受连接到转发远程第一个字节的延迟
+    pub first_byte_latency: LatencySnapshot,
+}
+
+/// 单个后端的统计
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    /// 累计分配的连接/会话数
+    pub assigned: AtomicU64,
+    /// 当前 TCP 连接数
+    pub tcp_connections: AtomicU64,
+    /// 当前 UDP 会话数
     pub udp_sessions: AtomicU64,
+    /// 客户端 -> 后端 字节数
+    pub bytes_up: ShardedCounter,
+    /// 后端 -> 客户端 字节数
+    pub bytes_down: ShardedCounter,
+    /// TCP 连接建立延迟
+    pub connect_latency: LatencyHistogram,
+    /// TCP 首字节延迟
+    pub first_byte_latency: LatencyHistogram,
+    /// 熔断器打开次数
+    pub breaker_trips: AtomicU64,
+    /// 熔断期间直接关闭的连接数
+    pub breaker_rejected: AtomicU64,
+}
+
+/// 后端统计快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackendSnapshot {
+    /// 累计分配的连接/会话数
+    pub assigned: u64,
+    /// 当前 TCP 连接数
+    pub tcp_connections: u64,
+    /// 当前 UDP 会话数
+    pub udp_sessions: u64,
+    /// 客户端 -> 后端 字节数
+    pub bytes_up: u64,
+    /// 后端 -> 客户端 字节数
+    pub bytes_down: u64,
+    /// TCP 连接建立延迟
+    pub connect_latency: LatencySnapshot,
+    /// TCP 首字节延迟
+    pub first_byte_latency: LatencySnapshot,
+    /// 熔断器打开次数
+    pub breaker_trips: u64,
+    /// 熔断期间直接关闭的连接数
+    pub breaker_rejected: u64,
+}
+
+impl BackendStats {
+    /// 分配了新的 TCP 连接
+    #[inline]
+    pub fn inc_tcp_connections(&self) {
+        self.assigned.fetch_add(1, Ordering::Relaxed);
+        self.tcp_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// TCP 连接关闭
+    #[inline]
+    pub fn dec_tcp_connections(&self) {
+        self.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 分配了新的 UDP 会话
+    #[inline]
+    pub fn inc_udp_sessions(&self) {
+        self.assigned.fetch_add(1, Ordering::Relaxed);
+        self.udp_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// UDP 会话关闭
+    #[inline]
+    pub fn dec_udp_sessions(&self) {
+        self.udp_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 增加 客户端 -> 后端 字节数
+    #[inline]
+    pub fn add_bytes_up(&self, bytes: usize) {
+        self.bytes_up.add(bytes as u64);
+    }
+
+    /// 增加 后端 -> 客户端 字节数
+    #[inline]
+    pub fn add_bytes_down(&self, bytes: usize) {
+        self.bytes_down.add(bytes as u64);
+    }
+
+    /// 清零累计计数 (当前连接/会话数保留)
+    pub fn reset(&self) {
+        self.assigned.store(0, Ordering::Relaxed);
+        self.bytes_up.reset();
+        self.bytes_down.reset();
+        self.connect_latency.reset();
+        self.first_byte_latency.reset();
+        self.breaker_trips.store(0, Ordering::Relaxed);
+        self.breaker_rejected.store(0, Ordering::Relaxed);
+    }
+
+    /// 读取当前统计快照
+    pub fn snapshot(&self) -> BackendSnapshot {
+        BackendSnapshot {
+            assigned: self.assigned.load(Ordering::Relaxed),
+            tcp_connections: self.tcp_connections.load(Ordering::Relaxed),
+            udp_sessions: self.udp_sessions.load(Ordering::Relaxed),
+            bytes_up: self.bytes_up.get(),
+            bytes_down: self.bytes_down.get(),
+            connect_latency: self.connect_latency.snapshot(),
+            first_byte_latency: self.first_byte_latency.snapshot(),
+            breaker_trips: self.breaker_trips.load(Ordering::Relaxed),
+            breaker_rejected: self.breaker_rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 统计状态文件首行
+const STATS_FILE_HEADER: &str = "# tinyPortMapper stats v1";
+
+/// 租户统计注册表 (租户名 -> 统计)，租户统计在进程生命周期内保留
+fn tenant_registry() -> &'static Mutex<BTreeMap<String, &'static TrafficStats>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<String, &'static TrafficStats>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// 流量统计 (全局或租户级)
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    /// TCP 接收字节数
+    pub tcp_bytes_received: ShardedCounter,
+    /// TCP 发送字节数
+    pub tcp_bytes_sent: ShardedCounter,
+    /// TCP 客户端 -> 服务端 转发字节数
+    pub tcp_bytes_c2s: ShardedCounter,
+    /// TCP 服务端 -> 客户端 转发字节数
+    pub tcp_bytes_s2c: ShardedCounter,
+    /// UDP 接收字节数
+    pub udp_bytes_received: ShardedCounter,
+    /// UDP 发送字节数
+    pub udp_bytes_sent: ShardedCounter,
+    /// UDP 客户端 -> 服务端 转发字节数
+    pub udp_bytes_c2s: ShardedCounter,
+    /// UDP 服务端 -> 客户端 转发字节数
+    pub udp_bytes_s2c: ShardedCounter,
+    /// TCP 连接数
+    pub tcp_connections: ShardedCounter,
+    /// UDP 会话数
+    pub udp_sessions: ShardedCounter,
+    /// TCP 从接受连接到远程连接建立的延迟
+    pub connect_latency: LatencyHistogram,
+    /// TCP 从接受连接到转发远程第一个字节的延迟
+    pub first_byte_latency: LatencyHistogram,
+    /// 上级统计 (租户统计同时累加到全局统计)
+    parent: Option<&'static TrafficStats>,
+    /// 各后端统计 (后端地址 -> 统计)
+    backends: Mutex<BTreeMap<String, Arc<BackendStats>>>,
 }
 
 impl TrafficStats {
     /// 获取单例实例
     pub fn global() -> &'static Self {
-        use std::sync::OnceLock;
         static INSTANCE: OnceLock<TrafficStats> = OnceLock::new();
         INSTANCE.get_or_init(TrafficStats::default)
     }
 
-    /// 增加 TCP 接收字节数
+    /// 获取租户统计 (不存在时创建)，计数同时累加到全局统计
+    pub fn tenant(name: &str) -> &'static Self {
+        let mut registry = tenant_registry().lock().recover();
+        registry.entry(name.to_string()).or_insert_with(|| {
+            Box::leak(Box::new(TrafficStats {
+                parent: Some(Self::global()),
+                ..Default::default()
+            }))
+        })
+    }
+
+    /// 按租户选择统计：未指定租户时使用全局统计
+    pub fn scope(tenant: Option<&str>) -> &'static Self {
+        tenant.map_or_else(Self::global, Self::tenant)
+    }
+
+    /// 所有租户的统计快照 (按租户名排序)
+    pub fn tenants() -> Vec<(String, StatsSnapshot)> {
+        tenant_registry()
+            .lock()
+            .recover()
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.snapshot()))
+            .collect()
+    }
+
+    /// 获取后端统计 (不存在时创建)
+    pub fn backend(&self, addr: &str) -> Arc<BackendStats> {
+        let mut backends = self.backends.lock().recover();
+        Arc::clone(backends.entry(addr.to_string()).or_default())
+    }
+
+    /// 所有后端的统计快照 (按后端地址排序)
+    pub fn backends(&self) -> Vec<(String, BackendSnapshot)> {
+        self.backends
+            .lock()
+            .recover()
+            .iter()
+            .map(|(addr, stats)| (addr.clone(), stats.snapshot()))
+            .collect()
+    }
+
+    /// 累加指定计数器，同时累加到上级统计
+    #[inline]
+    fn add_to(&self, counter: fn(&TrafficStats) -> &ShardedCounter, bytes: usize) {
+        counter(self).add(bytes as u64);
+        if let Some(parent) = self.parent {
+            parent.add_to(counter, bytes);
+        }
+    }
+
+    /// 增加从 socket 读到的 TCP 字节数
     #[inline]
     pub fn add_tcp_received(&self, bytes: usize) {
-        self.tcp_bytes_received
-            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.add_to(|s| &s.tcp_bytes_received, bytes);
     }
 
-    /// 增加 TCP 发送字节数
+    /// 增加成功发出的 TCP 字节数 (同时计入对应方向)
     #[inline]
-    pub fn add_tcp_sent(&self, bytes: usize) {
-        self.tcp_bytes_sent
-            .fetch_add(bytes as u64, Ordering::Relaxed);
+    pub fn add_tcp_sent(&self, direction: Direction, bytes: usize) {
+        self.add_to(|s| &s.tcp_bytes_sent, bytes);
+        match direction {
+            Direction::ClientToServer => self.add_to(|s| &s.tcp_bytes_c2s, bytes),
+            Direction::ServerToClient => self.add_to(|s| &s.tcp_bytes_s2c, bytes),
+        }
     }
 
-    /// 增加 UDP 接收字节数
+    /// 增加从 socket 读到的 UDP 字节数
     #[inline]
     pub fn add_udp_received(&self, bytes: usize) {
-        self.udp_bytes_received
-            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.add_to(|s| &s.udp_bytes_received, bytes);
     }
 
-    /// 增加 UDP 发送字节数
+    /// 增加成功发出的 UDP 载荷字节数 (同时计入对应方向)
     #[inline]
-    pub fn add_udp_sent(&self, bytes: usize) {
-        self.udp_bytes_sent
-            .fetch_add(bytes as u64, Ordering::Relaxed);
+    pub fn add_udp_sent(&self, direction: Direction, bytes: usize) {
+        self.add_to(|s| &s.udp_bytes_sent, bytes);
+        match direction {
+            Direction::ClientToServer => self.add_to(|s| &s.udp_bytes_c2s, bytes),
+            Direction::ServerToClient => self.add_to(|s| &s.udp_bytes_s2c, bytes),
+        }
+    }
+
+    /// 记录 TCP 连接建立延迟
+    pub fn record_connect_latency(&self, latency: Duration) {
+        self.connect_latency.record(latency);
+        if let Some(parent) = self.parent {
+            parent.record_connect_latency(latency);
+        }
+    }
+
+    /// 记录 TCP 首字节延迟
+    pub fn record_first_byte_latency(&self, latency: Duration) {
+        self.first_byte_latency.record(latency);
+        if let Some(parent) = self.parent {
+            parent.record_first_byte_latency(latency);
+        }
     }
 
     /// 增加 TCP 连接数
     #[inline]
     pub fn inc_tcp_connections(&self) {
-        self.tcp_connections.fetch_add(1, Ordering::Relaxed);
+        self.tcp_connections.add(1);
+        if let Some(parent) = self.parent {
+            parent.inc_tcp_connections();
+        }
     }
 
     /// 减少 TCP 连接数
     #[inline]
     pub fn dec_tcp_connections(&self) {
-        self.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+        self.tcp_connections.sub(1);
+        if let Some(parent) = self.parent {
+            parent.dec_tcp_connections();
+        }
     }
 
     /// 增加 UDP 会话数
     #[inline]
     pub fn inc_udp_sessions(&self) {
-        self.udp_sessions.fetch_add(1, Ordering::Relaxed);
+        self.udp_sessions.add(1);
+        if let Some(parent) = self.parent {
+            parent.inc_udp_sessions();
+        }
     }
 
     /// 减少 UDP 会话数
     #[inline]
     pub fn dec_udp_sessions(&self) {
-        self.udp_sessions.fetch_sub(1, Ordering::Relaxed);
+        self.udp_sessions.sub(1);
+        if let Some(parent) = self.parent {
+            parent.dec_udp_sessions();
+        }
+    }
+
+    /// 读取当前统计快照
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            tcp_bytes_received: self.tcp_bytes_received.get(),
+            tcp_bytes_sent: self.tcp_bytes_sent.get(),
+            tcp_bytes_c2s: self.tcp_bytes_c2s.get(),
+            tcp_bytes_s2c: self.tcp_bytes_s2c.get(),
+            udp_bytes_received: self.udp_bytes_received.get(),
+            udp_bytes_sent: self.udp_bytes_sent.get(),
+            udp_bytes_c2s: self.udp_bytes_c2s.get(),
+            udp_bytes_s2c: self.udp_bytes_s2c.get(),
+            tcp_connections: self.tcp_connections.get(),
+            udp_sessions: self.udp_sessions.get(),
+            connect_latency: self.connect_latency.snapshot(),
+            first_byte_latency: self.first_byte_latency.snapshot(),
+        }
+    }
+
+    /// 清零累计字节数、延迟统计和各后端累计计数 (当前连接/会话数保留，不影响上级统计)
+    pub fn reset(&self) {
+        self.tcp_bytes_received.reset();
+        self.tcp_bytes_sent.reset();
+        self.tcp_bytes_c2s.reset();
+        self.tcp_bytes_s2c.reset();
+        self.udp_bytes_received.reset();
+        self.udp_bytes_sent.reset();
+        self.udp_bytes_c2s.reset();
+        self.udp_bytes_s2c.reset();
+        self.connect_latency.reset();
+        self.first_byte_latency.reset();
+        for stats in self.backends.lock().recover().values() {
+            stats.reset();
+        }
+    }
+
+    /// 将累计字节数和各后端累计计数写入状态文件
+    ///
+    /// 先写临时文件再重命名，避免中途退出留下不完整的文件
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let snapshot = self.snapshot();
+        let mut content = format!("{}\n", STATS_FILE_HEADER);
+        for (name, value) in [
+            ("tcp_bytes_received", snapshot.tcp_bytes_received),
+            ("tcp_bytes_sent", snapshot.tcp_bytes_sent),
+            ("tcp_bytes_c2s", snapshot.tcp_bytes_c2s),
+            ("tcp_bytes_s2c", snapshot.tcp_bytes_s2c),
+            ("udp_bytes_received", snapshot.udp_bytes_received),
+            ("udp_bytes_sent", snapshot.udp_bytes_sent),
+            ("udp_bytes_c2s", snapshot.udp_bytes_c2s),
+            ("udp_bytes_s2c", snapshot.udp_bytes_s2c),
+        ] {
+            content.push_str(&format!("{} {}\n", name, value));
+        }
+        for (addr, backend) in self.backends() {
+            content.push_str(&format!(
+                "backend {} {} {} {}\n",
+                addr, backend.assigned, backend.bytes_up, backend.bytes_down
+            ));
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// 从状态文件累加之前保存的计数，文件不存在时不做任何事
+    ///
+    /// 租户统计加载的字节数同时计入全局统计
+    pub fn load(&self, path: &Path) -> io::Result<()> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid stats file line: '{}'", line),
+            )
+        };
+
+        let mut lines = content.lines();
+        if lines.next() != Some(STATS_FILE_HEADER) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unrecognized stats file header",
+            ));
+        }
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let num = |i: usize| -> io::Result<u64> {
+                fields
+                    .get(i)
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| invalid(line))
+            };
+            match fields[0] {
+                "tcp_bytes_received" => self.add_to(|s| &s.tcp_bytes_received, num(1)? as usize),
+                "tcp_bytes_sent" => self.add_to(|s| &s.tcp_bytes_sent, num(1)? as usize),
+                "tcp_bytes_c2s" => self.add_to(|s| &s.tcp_bytes_c2s, num(1)? as usize),
+                "tcp_bytes_s2c" => self.add_to(|s| &s.tcp_bytes_s2c, num(1)? as usize),
+                "udp_bytes_received" => self.add_to(|s| &s.udp_bytes_received, num(1)? as usize),
+                "udp_bytes_sent" => self.add_to(|s| &s.udp_bytes_sent, num(1)? as usize),
+                "udp_bytes_c2s" => self.add_to(|s| &s.udp_bytes_c2s, num(1)? as usize),
+                "udp_bytes_s2c" => self.add_to(|s| &s.udp_bytes_s2c, num(1)? as usize),
+                "backend" => {
+                    let addr = fields.get(1).ok_or_else(|| invalid(line))?;
+                    let (assigned, up, down) = (num(2)?, num(3)?, num(4)?);
+                    let backend = self.backend(addr);
+                    backend.assigned.fetch_add(assigned, Ordering::Relaxed);
+                    backend.bytes_up.add(up);
+                    backend.bytes_down.add(down);
+                }
+                // 忽略未知字段，便于以后扩展
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
     /// 获取格式化的统计信息
     pub fn get_stats_string(&self) -> String {
         format!(
             "TCP: {}/{}, UDP: {}/{}",
-            format_bytes(self.tcp_bytes_received.load(Ordering::Relaxed)),
-            format_bytes(self.tcp_bytes_sent.load(Ordering::Relaxed)),
-            format_bytes(self.udp_bytes_received.load(Ordering::Relaxed)),
-            format_bytes(self.udp_bytes_sent.load(Ordering::Relaxed))
+            format_bytes(self.tcp_bytes_received.get()),
+            format_bytes(self.tcp_bytes_sent.get()),
+            format_bytes(self.udp_bytes_received.get()),
+            format_bytes(self.udp_bytes_sent.get())
         )
     }
 }
 
+/// 格式化延迟
+pub fn format_latency(latency: Duration) -> String {
+    if latency < Duration::from_millis(1) {
+        format!("{}us", latency.as_micros())
+    } else if latency < Duration::from_secs(1) {
+        format!("{}ms", latency.as_millis())
+    } else {
+        format!("{:.1}s", latency.as_secs_f64())
+    }
+}
+
 /// 格式化字节数
+#[cfg(feature = "stats-format")]
 pub fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = 1024 * KB;
@@ -109,3 +649,147 @@ pub fn format_bytes(bytes: u64) -> String {
         format!("{} B", bytes)
     }
 }
+
+/// 格式化字节数 (精简构建，直接输出字节数)
+#[cfg(not(feature = "stats-format"))]
+pub fn format_bytes(bytes: u64) -> String {
+    format!("{} B", bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_counter() {
+        let counter = Arc::new(ShardedCounter::default());
+        let threads: Vec<_> = (0..COUNTER_SHARDS + 4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counter.add(3);
+                    }
+                    counter.sub(1);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("join");
+        }
+        assert_eq!(counter.get(), (COUNTER_SHARDS as u64 + 4) * 2999);
+
+        // 加减落在不同分片时汇总结果仍然准确
+        counter.reset();
+        counter.add(2);
+        let other = Arc::clone(&counter);
+        std::thread::spawn(move || other.sub(2))
+            .join()
+            .expect("join");
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+
+        // 90 个约 1ms 的样本，10 个约 100ms 的样本
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(1000));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(100));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 100);
+        assert_eq!(snapshot.p50, Duration::from_micros(1024));
+        assert_eq!(snapshot.p90, Duration::from_micros(1024));
+        assert_eq!(snapshot.p99, Duration::from_micros(131072));
+
+        histogram.record(Duration::ZERO);
+        histogram.record(Duration::MAX);
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_micros(1)));
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_micros(1 << (LATENCY_BUCKETS - 1)))
+        );
+        histogram.reset();
+        assert_eq!(histogram.snapshot().count, 0);
+
+        assert_eq!(format_latency(Duration::from_micros(512)), "512us");
+        assert_eq!(format_latency(Duration::from_micros(1024)), "1ms");
+        assert_eq!(format_latency(Duration::from_millis(1500)), "1.5s");
+        assert_eq!(snapshot.to_string(), "1ms/1ms/131ms");
+        assert_eq!(LatencySnapshot::default().to_string(), "-");
+    }
+
+    #[test]
+    fn test_tenant_rollup() {
+        let a = TrafficStats::tenant("test-rollup-a");
+        let b = TrafficStats::tenant("test-rollup-b");
+        assert!(std::ptr::eq(a, TrafficStats::tenant("test-rollup-a")));
+        assert!(std::ptr::eq(
+            TrafficStats::scope(None),
+            TrafficStats::global()
+        ));
+
+        let global_before = TrafficStats::global().snapshot().tcp_bytes_sent;
+        a.add_tcp_sent(Direction::ClientToServer, 100);
+        b.add_tcp_sent(Direction::ServerToClient, 20);
+        b.inc_udp_sessions();
+
+        assert_eq!(a.snapshot().tcp_bytes_sent, 100);
+        assert_eq!(b.snapshot().tcp_bytes_sent, 20);
+        assert_eq!(a.snapshot().tcp_bytes_c2s, 100);
+        assert_eq!(b.snapshot().tcp_bytes_s2c, 20);
+        assert_eq!(b.snapshot().tcp_bytes_c2s, 0);
+        assert_eq!(b.snapshot().udp_sessions, 1);
+        // 全局统计可能被其他测试同时修改，只检查下限
+        assert!(TrafficStats::global().snapshot().tcp_bytes_sent >= global_before + 120);
+
+        let tenants = TrafficStats::tenants();
+        let names: Vec<&str> = tenants.iter().map(|(name, _)| name.as_str()).collect();
+        assert!(names.contains(&"test-rollup-a"));
+        assert!(names.contains(&"test-rollup-b"));
+    }
+
+    #[test]
+    fn test_save_load_reset() {
+        let path =
+            std::env::temp_dir().join(format!("tpm-stats-test-{}.state", std::process::id()));
+        let stats = TrafficStats::default();
+        stats.add_tcp_sent(Direction::ClientToServer, 100);
+        stats.add_udp_received(7);
+        stats.add_udp_sent(Direction::ServerToClient, 5);
+        stats.backend("127.0.0.1:9000").inc_tcp_connections();
+        stats.backend("127.0.0.1:9000").add_bytes_up(40);
+        stats.save(&path).expect("save");
+
+        // 加载到已有计数上累加
+        let restored = TrafficStats::default();
+        restored.add_tcp_sent(Direction::ClientToServer, 1);
+        restored.load(&path).expect("load");
+        assert_eq!(restored.snapshot().tcp_bytes_sent, 101);
+        assert_eq!(restored.snapshot().tcp_bytes_c2s, 101);
+        assert_eq!(restored.snapshot().udp_bytes_received, 7);
+        assert_eq!(restored.snapshot().udp_bytes_s2c, 5);
+        let backends = restored.backends();
+        assert_eq!(backends[0].0, "127.0.0.1:9000");
+        assert_eq!(backends[0].1.assigned, 1);
+        assert_eq!(backends[0].1.bytes_up, 40);
+        // 当前连接数不持久化
+        assert_eq!(backends[0].1.tcp_connections, 0);
+
+        restored.reset();
+        assert_eq!(restored.snapshot(), StatsSnapshot::default());
+        assert_eq!(restored.backends()[0].1, BackendSnapshot::default());
+
+        std::fs::write(&path, "garbage\n").expect("write");
+        assert!(restored.load(&path).is_err());
+        std::fs::remove_file(&path).expect("remove");
+        // 文件不存在时视为没有历史数据
+        assert!(restored.load(&path).is_ok());
+    }
+}
@@ -0,0 +1,304 @@
+//! STUN 公网地址发现 (--stun)
+//!
+//! 从 UDP 监听 socket 向 STUN 服务端发送绑定请求 (RFC 5389)，应答中的 XOR-MAPPED-ADDRESS 即 NAT 为监听
+//! 端口分配的公网地址和端口，客户端应连接这个地址。请求和应答都经过监听 socket：`UdpHandler` 在建立会话之前
+//! 截住来自服务端、事务 ID 匹配的应答，其他数据包照常转发。
+//!
+//! 没有结果时每 `RETRY_INTERVAL` 重发，之后每 `REFRESH_INTERVAL` 刷新一次，同时保持 NAT 映射不过期；
+//! 公网地址变化时输出日志，结果由统计输出和 `PortMapperHandle::public_endpoint` 提供
+
+use crate::sync::Recover;
+use crate::{debug, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// STUN 服务端的默认端口
+pub const STUN_PORT: u16 = 3478;
+
+/// 已得到公网地址后的刷新间隔 (短于常见 NAT 的 UDP 映射超时)
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(25);
+
+/// 还没有应答时的重发间隔
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// 绑定请求和成功应答的消息类型
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+/// 固定的 magic cookie
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// 消息头长度
+const HEADER_LEN: usize = 20;
+
+/// 属性类型
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// 解析 `host[:port]`，省略端口时使用 3478，按监听地址族选择服务端地址
+pub fn resolve_server(server: &str, ipv6: bool) -> Result<SocketAddr, String> {
+    let resolved: Vec<SocketAddr> = if let Ok(ip) = server.parse::<IpAddr>() {
+        vec![SocketAddr::new(ip, STUN_PORT)]
+    } else if server.contains(':') {
+        server
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to resolve STUN server '{}': {}", server, e))?
+            .collect()
+    } else {
+        (server, STUN_PORT)
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to resolve STUN server '{}': {}", server, e))?
+            .collect()
+    };
+    resolved
+        .iter()
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .copied()
+        .ok_or_else(|| {
+            format!(
+                "STUN server '{}' has no {} address",
+                server,
+                if ipv6 { "IPv6" } else { "IPv4" }
+            )
+        })
+}
+
+/// 绑定请求的发送状态和结果
+#[derive(Debug)]
+pub struct StunClient {
+    server: SocketAddr,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// 最近一次请求的事务 ID
+    transaction: [u8; 12],
+    /// 下次发送请求的单调时间 (毫秒)
+    next_request: u64,
+    /// 发现的公网地址
+    endpoint: Option<SocketAddr>,
+}
+
+impl StunClient {
+    pub fn new(server: SocketAddr) -> Self {
+        Self {
+            server,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// STUN 服务端地址
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// 最近一次发现的公网地址
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.state.lock().recover().endpoint
+    }
+
+    /// 到了发送时间时生成新的绑定请求，`now` 为单调时间 (毫秒)
+    pub fn poll_request(&self, now: u64) -> Option<[u8; HEADER_LEN]> {
+        let mut state = self.state.lock().recover();
+        if now < state.next_request {
+            return None;
+        }
+        let interval = if state.endpoint.is_some() {
+            REFRESH_INTERVAL
+        } else {
+            RETRY_INTERVAL
+        };
+        state.next_request = now + interval.as_millis() as u64;
+        for chunk in state.transaction.chunks_mut(8) {
+            let value = crate::get_fake_random_number_64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Some(binding_request(&state.transaction))
+    }
+
+    /// 监听 socket 收到的数据包是否为当前请求的应答，是时记录公网地址
+    pub fn on_datagram(&self, from: SocketAddr, data: &[u8]) -> bool {
+        if from.port() != self.server.port()
+            || from.ip().to_canonical() != self.server.ip().to_canonical()
+        {
+            return false;
+        }
+        let mut state = self.state.lock().recover();
+        let Some(endpoint) = parse_binding_response(data, &state.transaction) else {
+            return false;
+        };
+        // 监听 IPv6 socket 收到 IPv4 映射地址时还原为 IPv4
+        let endpoint = SocketAddr::new(endpoint.ip().to_canonical(), endpoint.port());
+        if state.endpoint == Some(endpoint) {
+            debug!("[stun] public endpoint {} unchanged", endpoint);
+        } else {
+            info!("[stun] public endpoint {} (via {})", endpoint, self.server);
+            state.endpoint = Some(endpoint);
+            // 地址已知，改为按刷新间隔发送
+            state.next_request =
+                crate::log::get_monotonic_time() + REFRESH_INTERVAL.as_millis() as u64;
+        }
+        true
+    }
+}
+
+/// 不带属性的绑定请求
+pub fn binding_request(transaction: &[u8; 12]) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+    buf[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    buf[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buf[8..20].copy_from_slice(transaction);
+    buf
+}
+
+/// 解析事务 ID 为 `transaction` 的绑定成功应答，返回映射地址 (优先 XOR-MAPPED-ADDRESS)
+pub fn parse_binding_response(data: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if data.len() < HEADER_LEN
+        || u16::from_be_bytes([data[0], data[1]]) != BINDING_SUCCESS
+        || data[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || data[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+    let mut attrs = data.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = usize::from(u16::from_be_bytes([attrs[2], attrs[3]]));
+        let value = attrs.get(4..4 + len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&data[4..20])),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // 属性值按 4 字节对齐
+        attrs = attrs.get(4 + len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+/// 解析地址属性，`xor` 为 magic cookie 和事务 ID (XOR-MAPPED-ADDRESS)
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let [_, family, p0, p1, addr @ ..] = value else {
+        return None;
+    };
+    let mask = |i: usize| xor.map_or(0, |xor| xor[i]);
+    let port = u16::from_be_bytes([p0 ^ mask(0), p1 ^ mask(1)]);
+    let mut addr = addr.to_vec();
+    for (i, b) in addr.iter_mut().enumerate().take(16) {
+        *b ^= mask(i);
+    }
+    let ip = match (family, addr.as_slice()) {
+        (0x01, &[a, b, c, d]) => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        (0x02, addr) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(addr).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 5769 2.2 的 IPv4 绑定应答 (去掉 SOFTWARE 之外的完整性属性)
+    fn response(transaction: &[u8; 12], attrs: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(transaction);
+        buf.extend_from_slice(attrs);
+        buf
+    }
+
+    const TRANSACTION: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    #[test]
+    fn test_parse_response() {
+        // SOFTWARE 属性 (长度 3，需要补齐) 之后是 XOR-MAPPED-ADDRESS 192.0.2.1:32853
+        let attrs = [
+            0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0x00, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01,
+            0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        ];
+        let data = response(&TRANSACTION, &attrs);
+        assert_eq!(
+            parse_binding_response(&data, &TRANSACTION),
+            Some("192.0.2.1:32853".parse().unwrap())
+        );
+        // 事务 ID 不符或不是成功应答时忽略
+        assert_eq!(parse_binding_response(&data, &[0; 12]), None);
+        assert_eq!(
+            parse_binding_response(&binding_request(&TRANSACTION), &TRANSACTION),
+            None
+        );
+        assert_eq!(parse_binding_response(&data[..30], &TRANSACTION), None);
+
+        // RFC 5769 2.3：XOR-MAPPED-ADDRESS [2001:db8:1234:5678:11:2233:4455:6677]:32853
+        let attrs = [
+            0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3,
+            0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ];
+        assert_eq!(
+            parse_binding_response(&response(&TRANSACTION, &attrs), &TRANSACTION),
+            Some(
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                    .parse()
+                    .unwrap()
+            )
+        );
+
+        // 只有 MAPPED-ADDRESS 的旧服务端
+        let attrs = [
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x1f, 0x90, 203, 0, 113, 7,
+        ];
+        assert_eq!(
+            parse_binding_response(&response(&TRANSACTION, &attrs), &TRANSACTION),
+            Some("203.0.113.7:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_client() {
+        let client = StunClient::new("198.51.100.1:3478".parse().unwrap());
+        let request = client.poll_request(1000).expect("first request");
+        assert_eq!(&request[..8], &[0, 1, 0, 0, 0x21, 0x12, 0xa4, 0x42]);
+        assert!(client.poll_request(1001).is_none());
+
+        let mut transaction = [0u8; 12];
+        transaction.copy_from_slice(&request[8..]);
+        let attrs = [
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x1f, 0x90, 203, 0, 113, 7,
+        ];
+        let data = response(&transaction, &attrs);
+        // 来自其他地址的数据包照常转发
+        assert!(!client.on_datagram("198.51.100.2:3478".parse().unwrap(), &data));
+        assert!(client.on_datagram("[::ffff:198.51.100.1]:3478".parse().unwrap(), &data));
+        assert_eq!(client.endpoint(), Some("203.0.113.7:8080".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_resolve_server() {
+        assert_eq!(
+            resolve_server("127.0.0.1", false),
+            Ok("127.0.0.1:3478".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_server("127.0.0.1:19302", false),
+            Ok("127.0.0.1:19302".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_server("::1", true),
+            Ok("[::1]:3478".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_server("[::1]:19302", true),
+            Ok("[::1]:19302".parse().unwrap())
+        );
+        assert!(resolve_server("127.0.0.1", true).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! 锁中毒恢复
+//!
+//! 事件处理中的 panic 由事件循环截获，只关闭出错的连接。持有写锁时发生 panic 会使锁中毒，
+//! 之后的 `lock()/read()/write()` 都返回 `Err`。这里统一取出锁内的数据继续使用，
+//! 避免一次 panic 让所有访问同一把锁的代码连锁 panic。
+//!
+//! 事件循环内部的共享数据 (连接/会话管理器、Token/Fd64 分配、处理器状态、缓冲区池、定时器)
+//! 使用这里导出的 `RwLock`/`Mutex`：默认即标准库的锁；启用 `single-thread` 特性时换成
+//! 基于 `RefCell` 的单线程版本，接口相同，每次访问只做借用计数，不再有原子操作。
+//! 此时这些数据不再是 `Send`/`Sync`，`PortMapper` 只能在创建它的线程中运行，
+//! `PortMapperHandle` 仍可跨线程使用
+
+use std::sync::{LockResult, PoisonError};
+
+#[cfg(not(feature = "single-thread"))]
+pub use std::sync::{Mutex, RwLock};
+
+#[cfg(feature = "single-thread")]
+pub use local::{Mutex, RwLock};
+
+/// 事件循环中注册的回调 (定时任务等) 需要满足的约束：默认为 `Send + Sync`，
+/// `single-thread` 特性下没有约束，回调可以持有管理器
+#[cfg(not(feature = "single-thread"))]
+pub trait LoopShared: Send + Sync {}
+#[cfg(not(feature = "single-thread"))]
+impl<T: Send + Sync + ?Sized> LoopShared for T {}
+
+#[cfg(feature = "single-thread")]
+pub trait LoopShared {}
+#[cfg(feature = "single-thread")]
+impl<T: ?Sized> LoopShared for T {}
+
+/// 单线程版本的锁：借用冲突 (同一线程重复加写锁) 时 panic，标准库的锁此时会死锁
+#[cfg(feature = "single-thread")]
+mod local {
+    use std::cell::{Ref, RefCell, RefMut};
+    use std::sync::LockResult;
+
+    /// 单线程读写锁
+    #[derive(Debug, Default)]
+    pub struct RwLock<T: ?Sized>(RefCell<T>);
+
+    impl<T> RwLock<T> {
+        pub const fn new(value: T) -> Self {
+            Self(RefCell::new(value))
+        }
+    }
+
+    impl<T: ?Sized> RwLock<T> {
+        pub fn read(&self) -> LockResult<Ref<'_, T>> {
+            Ok(self.0.borrow())
+        }
+
+        pub fn write(&self) -> LockResult<RefMut<'_, T>> {
+            Ok(self.0.borrow_mut())
+        }
+
+        pub fn get_mut(&mut self) -> LockResult<&mut T> {
+            Ok(self.0.get_mut())
+        }
+    }
+
+    /// 单线程互斥锁
+    #[derive(Debug, Default)]
+    pub struct Mutex<T: ?Sized>(RefCell<T>);
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T) -> Self {
+            Self(RefCell::new(value))
+        }
+    }
+
+    impl<T: ?Sized> Mutex<T> {
+        pub fn lock(&self) -> LockResult<RefMut<'_, T>> {
+            Ok(self.0.borrow_mut())
+        }
+
+        pub fn get_mut(&mut self) -> LockResult<&mut T> {
+            Ok(self.0.get_mut())
+        }
+    }
+}
+
+/// 从中毒的锁中恢复
+pub trait Recover<G> {
+    /// 返回锁守卫，锁已中毒时同样返回守卫
+    fn recover(self) -> G;
+}
+
+impl<G> Recover<G> for LockResult<G> {
+    fn recover(self) -> G {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    #[test]
+    fn test_recover_poisoned() {
+        let lock = Arc::new(RwLock::new(1));
+        let mutex = Arc::new(Mutex::new(1));
+        let (l, m) = (Arc::clone(&lock), Arc::clone(&mutex));
+        let _ = std::thread::spawn(move || {
+            let _w = l.write().recover();
+            let _g = m.lock().recover();
+            panic!("poison");
+        })
+        .join();
+
+        assert!(lock.is_poisoned());
+        assert!(mutex.is_poisoned());
+        *lock.write().recover() += 1;
+        *mutex.lock().recover() += 1;
+        assert_eq!(*lock.read().recover(), 2);
+        assert_eq!(*mutex.lock().recover(), 2);
+    }
+
+    #[cfg(feature = "single-thread")]
+    #[test]
+    fn test_local_locks() {
+        let lock = local::RwLock::new(1);
+        {
+            let a = lock.read().recover();
+            let b = lock.read().recover();
+            assert_eq!(*a + *b, 2);
+        }
+        *lock.write().recover() += 1;
+        assert_eq!(*lock.read().recover(), 2);
+
+        let mut mutex = local::Mutex::new(vec![1]);
+        mutex.lock().recover().push(2);
+        mutex.get_mut().recover().push(3);
+        assert_eq!(*mutex.lock().recover(), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,82 @@
+//! systemd 通知
+//!
+//! 通过 `NOTIFY_SOCKET` 向 systemd 报告启动完成 (`READY=1`)、停止 (`STOPPING=1`)
+//! 和看门狗心跳 (`WATCHDOG=1`)，未由 systemd 启动时所有操作都不做任何事
+
+use std::env;
+use std::io;
+use std::time::Duration;
+
+/// 向 systemd 发送状态，返回是否实际发送 (未设置 `NOTIFY_SOCKET` 时为 false)
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => notify_to(&path, state).map(|()| true),
+        _ => Ok(false),
+    }
+}
+
+/// 向指定的通知 socket 发送状态，`@` 开头的路径为 Linux 抽象命名空间
+#[cfg(unix)]
+pub fn notify_to(path: &str, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// 向指定的通知 socket 发送状态 (非 Unix 平台不支持)
+#[cfg(not(unix))]
+pub fn notify_to(_path: &str, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sd_notify is only supported on Unix",
+    ))
+}
+
+/// systemd 看门狗超时 (`WATCHDOG_USEC`)，未启用看门狗或不是发给本进程时为 None
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_notify_to() {
+        let path = env::temp_dir().join(format!("tpm-notify-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).expect("bind");
+
+        notify_to(path.to_str().expect("path"), "READY=1").expect("notify");
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_file(&path).expect("remove");
+        assert!(notify_to(path.to_str().expect("path"), "READY=1").is_err());
+    }
+}
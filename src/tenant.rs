@@ -0,0 +1,191 @@
+//! 租户级别的限制 (--tenant-max-connections / --tenant-rate-limit / --tenant-allow / --tenant-deny)
+//!
+//! 同一进程中同名租户的所有映射共享一个连接数上限、一个带宽令牌桶和一组客户端地址 ACL，
+//! 各映射自己的 `--max-connections`/`--rate-limit` 仍然生效。租户在第一个映射创建时注册，
+//! 之后同名的映射要么不设置限制 (沿用已注册的)，要么设置完全相同的限制。
+//! 连接数取租户统计中的当前 TCP 连接数和 UDP 会话数，所以跨线程运行的映射也能共享
+
+use crate::ratelimit::TokenBucket;
+use crate::stats::TrafficStats;
+use crate::sync::Recover;
+use crate::types::IpNet;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// 租户限制，字段均为空时不做任何限制
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TenantLimits {
+    /// 租户所有映射的 TCP 连接和 UDP 会话总数上限
+    pub max_connections: Option<usize>,
+    /// 租户所有映射共享的带宽 (字节/秒)
+    pub rate_limit: Option<u64>,
+    /// 非空时只接受这些网段的客户端
+    pub allow: Vec<IpNet>,
+    /// 拒绝这些网段的客户端，先于 `allow` 检查
+    pub deny: Vec<IpNet>,
+}
+
+impl TenantLimits {
+    /// 解析网段列表并组成限制
+    pub fn new(
+        max_connections: Option<usize>,
+        rate_limit: Option<u64>,
+        allow: &[String],
+        deny: &[String],
+    ) -> Result<Self, String> {
+        let parse = |nets: &[String]| {
+            nets.iter()
+                .map(|net| net.parse::<IpNet>())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            max_connections,
+            rate_limit,
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 已注册的租户
+#[derive(Debug)]
+pub struct Tenant {
+    name: String,
+    limits: TenantLimits,
+    stats: &'static TrafficStats,
+    /// 租户共享的令牌桶，可能被不同线程中的事件循环使用
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+/// 租户注册表 (租户名 -> 租户)，与租户统计一样在进程生命周期内保留
+fn registry() -> &'static Mutex<BTreeMap<String, Arc<Tenant>>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<String, Arc<Tenant>>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+impl Tenant {
+    /// 注册租户或加入已注册的同名租户
+    ///
+    /// 已注册的租户限制不同且 `limits` 非空时返回错误
+    pub fn register(name: &str, limits: TenantLimits) -> Result<Arc<Self>, String> {
+        let mut registry = registry().lock().recover();
+        if let Some(tenant) = registry.get(name) {
+            if limits.is_empty() || limits == tenant.limits {
+                return Ok(Arc::clone(tenant));
+            }
+            return Err(format!(
+                "tenant '{}' is already registered with different limits",
+                name
+            ));
+        }
+        let tenant = Arc::new(Self {
+            name: name.to_string(),
+            bucket: limits
+                .rate_limit
+                .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+            limits,
+            stats: TrafficStats::tenant(name),
+        });
+        registry.insert(name.to_string(), Arc::clone(&tenant));
+        Ok(tenant)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limits(&self) -> &TenantLimits {
+        &self.limits
+    }
+
+    /// 租户共享的令牌桶，未设置 `rate_limit` 时为 None
+    pub fn bucket(&self) -> Option<Arc<Mutex<TokenBucket>>> {
+        self.bucket.clone()
+    }
+
+    /// 按 ACL 检查客户端地址：先看拒绝列表，允许列表非空时只放行其中的网段
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.limits.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.limits.allow.is_empty() || self.limits.allow.iter().any(|net| net.contains(ip))
+    }
+
+    /// 租户的连接数是否已达上限，`pending` 为调用方尚未计入统计的连接数
+    pub fn is_full(&self, pending: usize) -> bool {
+        self.limits.max_connections.is_some_and(|max| {
+            let current = self.stats.tcp_connections.get() + self.stats.udp_sessions.get();
+            current.saturating_add(pending as u64) >= max as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(s: &str) -> Vec<String> {
+        s.split(',').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_register() {
+        let limits = TenantLimits::new(Some(10), Some(1024), &[], &[]).unwrap();
+        let a = Tenant::register("tenant-test-register", limits.clone()).unwrap();
+        assert_eq!(a.name(), "tenant-test-register");
+        assert!(a.bucket().is_some());
+
+        // 相同限制或不设置限制时加入已注册的租户
+        let b = Tenant::register("tenant-test-register", limits).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        let c = Tenant::register("tenant-test-register", TenantLimits::default()).unwrap();
+        assert!(Arc::ptr_eq(&a, &c));
+
+        let other = TenantLimits::new(Some(20), None, &[], &[]).unwrap();
+        assert!(Tenant::register("tenant-test-register", other).is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let limits = TenantLimits::new(
+            None,
+            None,
+            &nets("10.0.0.0/8,2001:db8::/32"),
+            &nets("10.0.0.1"),
+        )
+        .unwrap();
+        let tenant = Tenant::register("tenant-test-acl", limits).unwrap();
+        assert!(tenant.allows("10.1.2.3".parse().unwrap()));
+        assert!(tenant.allows("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(tenant.allows("2001:db8::5".parse().unwrap()));
+        assert!(!tenant.allows("10.0.0.1".parse().unwrap()));
+        assert!(!tenant.allows("192.0.2.1".parse().unwrap()));
+
+        assert!(TenantLimits::new(None, None, &nets("10.0.0.1/8"), &[]).is_err());
+        assert!(TenantLimits::default().is_empty());
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let limits = TenantLimits::new(Some(2), None, &[], &[]).unwrap();
+        let tenant = Tenant::register("tenant-test-max", limits).unwrap();
+        let stats = TrafficStats::tenant("tenant-test-max");
+        assert!(!tenant.is_full(0));
+        assert!(tenant.is_full(2));
+        stats.inc_tcp_connections();
+        assert!(!tenant.is_full(0));
+        stats.inc_udp_sessions();
+        assert!(tenant.is_full(0));
+        stats.dec_tcp_connections();
+        stats.dec_udp_sessions();
+        assert!(!tenant.is_full(1));
+
+        let unlimited = Tenant::register("tenant-test-unlimited", TenantLimits::default()).unwrap();
+        assert!(!unlimited.is_full(usize::MAX));
+    }
+}
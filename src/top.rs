@@ -0,0 +1,246 @@
+//! 实时连接表 (--top)
+//!
+//! 定期在终端重绘当前的 TCP 连接和 UDP 会话，按速率从高到低排列，类似 iftop。
+//! 速率为相邻两次刷新之间的流量增量，按连接 ID 对应
+
+use crate::connection::{TcpConnection, UdpSession};
+use crate::manager::{TcpConnectionManager, UdpSessionManager};
+use crate::stats::format_bytes;
+use crate::sync::Recover;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// 刷新间隔
+pub const TOP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 无法获取终端大小时的行数
+pub const DEFAULT_ROWS: usize = 24;
+
+/// 表头占用的行数 (汇总行、空行、列名)
+const HEADER_ROWS: usize = 3;
+
+/// 连接表中的一行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopRow {
+    /// 连接 ID
+    pub id: u64,
+    /// "tcp" 或 "udp"
+    pub proto: &'static str,
+    /// 客户端地址
+    pub client: String,
+    /// 后端地址，未分配时为 "-"
+    pub backend: String,
+    /// 上行累计字节数
+    pub bytes_up: u64,
+    /// 下行累计字节数
+    pub bytes_down: u64,
+    /// 空闲时间
+    pub idle: Duration,
+}
+
+impl TopRow {
+    /// TCP 连接对应的行
+    pub fn from_conn(conn: &TcpConnection) -> Self {
+        Self {
+            id: conn.id,
+            proto: "tcp",
+            client: conn.addr_s.clone(),
+            backend: conn
+                .backend
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |b| b.addr.to_string()),
+            bytes_up: conn.bytes_up,
+            bytes_down: conn.bytes_down,
+            idle: conn.idle_duration(),
+        }
+    }
+
+    /// UDP 会话对应的行
+    pub fn from_session(session: &UdpSession) -> Self {
+        Self {
+            id: session.id,
+            proto: "udp",
+            client: session.addr_s.clone(),
+            backend: session
+                .backend
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |b| b.addr.to_string()),
+            bytes_up: session.bytes_up,
+            bytes_down: session.bytes_down,
+            idle: session.idle_duration(),
+        }
+    }
+}
+
+/// 读取所有 TCP 连接和 UDP 会话
+pub fn collect(tcp_manager: &TcpConnectionManager, udp_manager: &UdpSessionManager) -> Vec<TopRow> {
+    let mut rows: Vec<TopRow> = tcp_manager
+        .connections
+        .read()
+        .recover()
+        .values()
+        .map(|conn| TopRow::from_conn(&conn.read().recover()))
+        .collect();
+    rows.extend(
+        udp_manager
+            .sessions
+            .read()
+            .recover()
+            .values()
+            .map(|session| TopRow::from_session(&session.read().recover())),
+    );
+    rows
+}
+
+/// 终端行数，stdout 不是终端时返回 `DEFAULT_ROWS`
+#[cfg(unix)]
+pub fn terminal_rows() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_row > 0 {
+        usize::from(size.ws_row)
+    } else {
+        DEFAULT_ROWS
+    }
+}
+
+/// 终端行数
+#[cfg(windows)]
+pub fn terminal_rows() -> usize {
+    DEFAULT_ROWS
+}
+
+/// 连接表渲染器，保存上一次刷新时各连接的累计流量用于计算速率
+#[derive(Debug, Default)]
+pub struct Top {
+    last: HashMap<u64, (u64, u64)>,
+    last_time: Option<Instant>,
+}
+
+impl Top {
+    /// 渲染一帧，最多 `max_rows` 行 (含表头)，放不下的连接在末行汇总
+    pub fn render(&mut self, rows: Vec<TopRow>, now: Instant, max_rows: usize) -> String {
+        let secs = self
+            .last_time
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+        let rate = |current: u64, last: u64| {
+            if secs > 0.0 {
+                (current.saturating_sub(last) as f64 / secs) as u64
+            } else {
+                0
+            }
+        };
+
+        // 首次出现的连接从 0 开始计算速率
+        let mut rated: Vec<(TopRow, u64, u64)> = rows
+            .into_iter()
+            .map(|row| {
+                let (up, down) = self.last.get(&row.id).copied().unwrap_or((0, 0));
+                let (up, down) = (rate(row.bytes_up, up), rate(row.bytes_down, down));
+                (row, up, down)
+            })
+            .collect();
+        self.last = rated
+            .iter()
+            .map(|(row, _, _)| (row.id, (row.bytes_up, row.bytes_down)))
+            .collect();
+        self.last_time = Some(now);
+        rated.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.id.cmp(&b.0.id)));
+
+        let tcp = rated
+            .iter()
+            .filter(|(row, _, _)| row.proto == "tcp")
+            .count();
+        let total_up: u64 = rated.iter().map(|r| r.1).sum();
+        let total_down: u64 = rated.iter().map(|r| r.2).sum();
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "tinymapper - TCP: {}, UDP: {}, up: {}/s, down: {}/s",
+            tcp,
+            rated.len() - tcp,
+            format_bytes(total_up),
+            format_bytes(total_down)
+        );
+        let _ = writeln!(out);
+        let _ = writeln!(
+            out,
+            "{:>8} {:<5} {:<40} {:<40} {:>12} {:>12} {:>12} {:>12} {:>6}",
+            "ID", "PROTO", "CLIENT", "BACKEND", "UP/s", "DOWN/s", "UP", "DOWN", "IDLE"
+        );
+
+        let available = max_rows.saturating_sub(HEADER_ROWS).max(1);
+        let shown = if rated.len() > available {
+            available - 1
+        } else {
+            rated.len()
+        };
+        for (row, up, down) in &rated[..shown] {
+            let _ = writeln!(
+                out,
+                "{:>8} {:<5} {:<40} {:<40} {:>12} {:>12} {:>12} {:>12} {:>5}s",
+                format!("#{}", row.id),
+                row.proto,
+                row.client,
+                row.backend,
+                format_bytes(*up),
+                format_bytes(*down),
+                format_bytes(row.bytes_up),
+                format_bytes(row.bytes_down),
+                row.idle.as_secs()
+            );
+        }
+        if shown < rated.len() {
+            let _ = writeln!(out, "... {} more", rated.len() - shown);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: u64, proto: &'static str, bytes_up: u64) -> TopRow {
+        TopRow {
+            id,
+            proto,
+            client: format!("10.0.0.{}:1000", id),
+            backend: "10.0.0.1:443".to_string(),
+            bytes_up,
+            bytes_down: 0,
+            idle: Duration::from_secs(id),
+        }
+    }
+
+    #[test]
+    fn test_render_rates_and_truncation() {
+        let mut top = Top::default();
+        let start = Instant::now();
+        let frame = top.render(vec![row(1, "tcp", 100), row(2, "udp", 0)], start, 24);
+        assert!(frame.starts_with("tinymapper - TCP: 1, UDP: 1, up: 0 B/s"));
+
+        // 速率按增量计算，新连接从 0 开始，按速率从高到低排列
+        let later = start + Duration::from_secs(2);
+        let rows = vec![row(1, "tcp", 300), row(2, "udp", 2000), row(3, "tcp", 0)];
+        let frame = top.render(rows, later, 24);
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(lines.len(), HEADER_ROWS + 3);
+        assert!(lines[3].trim_start().starts_with("#2 "));
+        assert!(lines[3].contains("1000 B"));
+        assert!(lines[4].trim_start().starts_with("#1 "));
+        assert!(lines[4].contains("100 B"));
+        assert!(lines[5].trim_start().starts_with("#3 "));
+
+        let frame = top.render(
+            vec![row(1, "tcp", 300), row(2, "udp", 2000), row(3, "tcp", 0)],
+            later + Duration::from_secs(1),
+            HEADER_ROWS + 2,
+        );
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(lines.len(), HEADER_ROWS + 2);
+        assert_eq!(lines[4], "... 2 more");
+    }
+}
@@ -1,16 +1,43 @@
 //! 地址结构体实现
 //!
-//! 提供 IPv4/IPv6 地址的存储和转换功能
+//! 提供 IPv4/IPv6、Unix 域 socket、vsock 和 Windows 命名管道地址的存储和转换功能
 
+use crate::config::PortRange;
+use crate::types::Nat64Prefix;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::str::FromStr;
 
+#[cfg(windows)]
+use crate::winsock as libc;
+
 /// IPv4 地址类型标识
 pub const ADDR_TYPE_IPV4: u8 = 4;
 /// IPv6 地址类型标识
 pub const ADDR_TYPE_IPV6: u8 = 6;
+/// Unix 域 socket 地址类型标识
+pub const ADDR_TYPE_UNIX: u8 = 1;
+
+/// vsock 地址类型标识
+pub const ADDR_TYPE_VSOCK: u8 = 2;
+
+/// Windows 命名管道地址类型标识
+pub const ADDR_TYPE_NPIPE: u8 = 3;
+
+/// Unix 域 socket 地址前缀
+pub const UNIX_ADDR_PREFIX: &str = "unix:";
+/// vsock 地址前缀
+pub const VSOCK_ADDR_PREFIX: &str = "vsock://";
+/// Windows 命名管道地址前缀
+pub const NPIPE_ADDR_PREFIX: &str = "npipe:";
+
+/// 命名管道名称的最大长度 (含 `\\.\pipe\` 前缀)
+#[cfg(windows)]
+const NPIPE_NAME_MAX: usize = 256;
 
 /// 地址类型枚举
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,40 +46,136 @@ pub enum AddressType {
     Ipv4,
     /// IPv6 地址
     Ipv6,
+    /// Unix 域 socket 路径
+    Unix,
+    /// vsock (虚拟机与宿主机通信)
+    Vsock,
+    /// Windows 命名管道
+    NamedPipe,
+}
+
+/// 内部地址存储
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Inner {
+    /// IPv4/IPv6 地址
+    Inet(SocketAddr),
+    /// Unix 域 socket 路径 (仅 TCP 转发使用)
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// vsock 上下文 ID 和端口 (仅 Linux，仅 TCP 转发使用)
+    #[cfg(target_os = "linux")]
+    Vsock { cid: u32, port: u32 },
+    /// 命名管道名称，如 `\\.\pipe\foo` (仅 Windows，仅 TCP 转发使用)
+    #[cfg(windows)]
+    NamedPipe(String),
 }
 
 /// 地址结构体
 ///
-/// 支持 IPv4 和 IPv6 地址的存储，内部使用标准库的 `SocketAddr`
+/// 支持 IPv4、IPv6、Unix 域 socket、vsock 和命名管道地址的存储，IP 地址内部使用标准库的 `SocketAddr`
 #[derive(Debug, Clone)]
 pub struct Address {
     /// 内部地址存储
-    addr: SocketAddr,
+    addr: Inner,
 }
 
 impl Address {
     /// 从 IPv4 地址创建
     pub fn from_ipv4(ip: Ipv4Addr, port: u16) -> Self {
         Self {
-            addr: SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            addr: Inner::Inet(SocketAddr::V4(SocketAddrV4::new(ip, port))),
         }
     }
 
     /// 从 IPv6 地址创建
     pub fn from_ipv6(ip: Ipv6Addr, port: u16) -> Self {
         Self {
-            addr: SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+            addr: Inner::Inet(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
         }
     }
 
     /// 从 `SocketAddr` 转换
     pub fn from_sockaddr(sock_addr: SocketAddr) -> Self {
-        Self { addr: sock_addr }
+        Self {
+            addr: Inner::Inet(sock_addr),
+        }
+    }
+
+    /// 从 Unix 域 socket 路径创建
+    ///
+    /// 路径为空或超过 sockaddr_un 容量时返回错误
+    #[cfg(unix)]
+    pub fn from_unix_path(path: impl Into<PathBuf>) -> Result<Self, AddressParseError> {
+        let path = path.into();
+        let len = path.as_os_str().len();
+        if len == 0 || len >= unix_path_capacity() {
+            return Err(AddressParseError::InvalidPath);
+        }
+        Ok(Self {
+            addr: Inner::Unix(path),
+        })
+    }
+
+    /// 从 vsock 上下文 ID 和端口创建
+    #[cfg(target_os = "linux")]
+    pub fn from_vsock(cid: u32, port: u32) -> Self {
+        Self {
+            addr: Inner::Vsock { cid, port },
+        }
+    }
+
+    /// 从命名管道名称创建
+    ///
+    /// 名称必须为 `\\<server>\pipe\<name>` 形式，为空或过长时返回错误
+    #[cfg(windows)]
+    pub fn from_named_pipe(name: &str) -> Result<Self, AddressParseError> {
+        let pipe = name
+            .strip_prefix(r"\\")
+            .and_then(|rest| rest.split_once('\\'))
+            .filter(|(server, _)| !server.is_empty())
+            .and_then(|(_, rest)| rest.split_once('\\'))
+            .filter(|(kind, _)| kind.eq_ignore_ascii_case("pipe"))
+            .map(|(_, pipe)| pipe);
+        let valid = pipe.is_some_and(|pipe| !pipe.is_empty());
+        if !valid || name.encode_utf16().count() > NPIPE_NAME_MAX {
+            return Err(AddressParseError::InvalidPath);
+        }
+        Ok(Self {
+            addr: Inner::NamedPipe(name.to_string()),
+        })
+    }
+
+    /// 是否为 IPv4/IPv6 地址
+    pub fn is_ip(&self) -> bool {
+        matches!(self.addr, Inner::Inet(_))
+    }
+
+    /// 是否为 Unix 域 socket 地址
+    pub fn is_unix(&self) -> bool {
+        self.unix_path().is_some()
+    }
+
+    /// Unix 域 socket 路径，其他地址返回 None
+    pub fn unix_path(&self) -> Option<&Path> {
+        match self.addr {
+            #[cfg(unix)]
+            Inner::Unix(ref path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// 命名管道名称，其他地址返回 None
+    pub fn named_pipe(&self) -> Option<&str> {
+        match self.addr {
+            #[cfg(windows)]
+            Inner::NamedPipe(ref name) => Some(name),
+            _ => None,
+        }
     }
 
     /// 从原生 sockaddr 创建地址（类似C++版本的 from_sockaddr）
     ///
-    /// 支持 IPv4 (sockaddr_in) 和 IPv6 (sockaddr_in6)
+    /// 支持 IPv4 (sockaddr_in)、IPv6 (sockaddr_in6)、命名的 Unix 域 socket (sockaddr_un) 和 vsock (sockaddr_vm)
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn from_raw_sockaddr(
         sockaddr: *const libc::sockaddr,
@@ -79,6 +202,33 @@ impl Address {
                     let scope_id = addr_in6.sin6_scope_id;
                     Ok(Self::from_ipv6_with_scope_id(ip, port, scope_id))
                 }
+                #[cfg(unix)]
+                libc::AF_UNIX => {
+                    use std::os::unix::ffi::OsStrExt;
+                    let offset = std::mem::offset_of!(libc::sockaddr_un, sun_path);
+                    let addr_un = &*(sockaddr as *const libc::sockaddr_un);
+                    let len = (socklen as usize)
+                        .saturating_sub(offset)
+                        .min(addr_un.sun_path.len());
+                    let path: Vec<u8> = addr_un.sun_path[..len]
+                        .iter()
+                        .map(|&c| c as u8)
+                        .take_while(|&c| c != 0)
+                        .collect();
+                    // 未命名和抽象命名空间 socket 没有路径
+                    if path.is_empty() {
+                        return Err(AddressParseError::InvalidPath);
+                    }
+                    Self::from_unix_path(std::ffi::OsStr::from_bytes(&path))
+                }
+                #[cfg(target_os = "linux")]
+                libc::AF_VSOCK => {
+                    if socklen < std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t {
+                        return Err(AddressParseError::InvalidFormat);
+                    }
+                    let addr_vm = &*(sockaddr as *const libc::sockaddr_vm);
+                    Ok(Self::from_vsock(addr_vm.svm_cid, addr_vm.svm_port))
+                }
                 _ => Err(AddressParseError::InvalidFormat),
             }
         }
@@ -87,53 +237,79 @@ impl Address {
     /// 从 IPv6 地址创建，带 scope_id
     fn from_ipv6_with_scope_id(ip: Ipv6Addr, port: u16, scope_id: u32) -> Self {
         Self {
-            addr: SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)),
+            addr: Inner::Inet(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))),
         }
     }
 
     /// 转换为 `SocketAddr`
+    ///
+    /// Unix 域 socket 和 vsock 地址没有对应的 `SocketAddr`，返回 `0.0.0.0:0`
     pub fn to_sockaddr(&self) -> SocketAddr {
-        self.addr
+        match self.addr {
+            Inner::Inet(addr) => addr,
+            #[allow(unreachable_patterns)]
+            _ => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        }
     }
 
     /// 获取地址类型
     ///
-    /// 返回 `ADDR_TYPE_IPV4` 或 `ADDR_TYPE_IPV6`
+    /// 返回 `ADDR_TYPE_IPV4`、`ADDR_TYPE_IPV6`、`ADDR_TYPE_UNIX` 或 `ADDR_TYPE_VSOCK`
     pub fn get_type(&self) -> u8 {
         match self.addr {
-            SocketAddr::V4(_) => ADDR_TYPE_IPV4,
-            SocketAddr::V6(_) => ADDR_TYPE_IPV6,
+            Inner::Inet(SocketAddr::V4(_)) => ADDR_TYPE_IPV4,
+            Inner::Inet(SocketAddr::V6(_)) => ADDR_TYPE_IPV6,
+            #[cfg(unix)]
+            Inner::Unix(_) => ADDR_TYPE_UNIX,
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { .. } => ADDR_TYPE_VSOCK,
+            #[cfg(windows)]
+            Inner::NamedPipe(_) => ADDR_TYPE_NPIPE,
         }
     }
 
     /// 获取地址族（用于 socket 创建）
     ///
-    /// 返回 libc::AF_INET 或 libc::AF_INET6
+    /// 返回 libc::AF_INET、libc::AF_INET6、libc::AF_UNIX 或 libc::AF_VSOCK，命名管道不是 socket，返回 AF_UNSPEC
     pub fn get_addr_family(&self) -> libc::c_int {
         match self.addr {
-            SocketAddr::V4(_) => libc::AF_INET,
-            SocketAddr::V6(_) => libc::AF_INET6,
+            Inner::Inet(SocketAddr::V4(_)) => libc::AF_INET,
+            Inner::Inet(SocketAddr::V6(_)) => libc::AF_INET6,
+            #[cfg(unix)]
+            Inner::Unix(_) => libc::AF_UNIX,
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { .. } => libc::AF_VSOCK,
+            #[cfg(windows)]
+            Inner::NamedPipe(_) => libc::AF_UNSPEC,
         }
     }
 
     /// 获取 sockaddr 长度
     ///
-    /// IPv4 返回 16，IPv6 返回 28
+    /// IPv4 返回 16，IPv6 返回 28，Unix 域 socket 为路径长度加上 sun_path 偏移和结尾的 NUL
     pub fn get_len(&self) -> usize {
         match self.addr {
-            SocketAddr::V4(_) => std::mem::size_of::<libc::sockaddr_in>(),
-            SocketAddr::V6(_) => std::mem::size_of::<libc::sockaddr_in6>(),
+            Inner::Inet(SocketAddr::V4(_)) => std::mem::size_of::<libc::sockaddr_in>(),
+            Inner::Inet(SocketAddr::V6(_)) => std::mem::size_of::<libc::sockaddr_in6>(),
+            #[cfg(unix)]
+            Inner::Unix(ref path) => {
+                std::mem::offset_of!(libc::sockaddr_un, sun_path) + path.as_os_str().len() + 1
+            }
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { .. } => std::mem::size_of::<libc::sockaddr_vm>(),
+            #[cfg(windows)]
+            Inner::NamedPipe(_) => 0,
         }
     }
 
-    /// 获取端口号
+    /// 获取端口号 (Unix 域 socket 和 vsock 地址返回 0)
     pub fn port(&self) -> u16 {
-        self.addr.port()
+        self.to_sockaddr().port()
     }
 
-    /// 获取 IP 地址
+    /// 获取 IP 地址 (Unix 域 socket 和 vsock 地址返回 `0.0.0.0:0`)
     pub fn ip(&self) -> SocketAddr {
-        self.addr
+        self.to_sockaddr()
     }
 
     /// 转换为 libc::sockaddr_storage
@@ -141,14 +317,14 @@ impl Address {
     /// 用于 libc 系统调用
     pub fn to_sockaddr_storage(&self) -> libc::sockaddr_storage {
         match self.addr {
-            SocketAddr::V4(v4) => {
+            Inner::Inet(SocketAddr::V4(v4)) => {
                 let sockaddr = libc::sockaddr_in {
                     sin_family: libc::AF_INET as libc::sa_family_t,
                     sin_port: v4.port().to_be(),
                     sin_addr: libc::in_addr {
                         s_addr: u32::from_ne_bytes(v4.ip().octets()),
                     },
-                    #[cfg(target_os = "linux")]
+                    #[cfg(any(target_os = "linux", windows))]
                     sin_zero: [0; 8],
                 };
                 let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
@@ -161,7 +337,7 @@ impl Address {
                 }
                 storage
             }
-            SocketAddr::V6(v6) => {
+            Inner::Inet(SocketAddr::V6(v6)) => {
                 let sockaddr = libc::sockaddr_in6 {
                     sin6_family: libc::AF_INET6 as libc::sa_family_t,
                     sin6_port: v6.port().to_be(),
@@ -181,19 +357,61 @@ impl Address {
                 }
                 storage
             }
+            #[cfg(unix)]
+            Inner::Unix(ref path) => {
+                use std::os::unix::ffi::OsStrExt;
+                let mut sockaddr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+                sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+                // 长度已在构造时检查，保留结尾的 NUL
+                for (dst, &src) in sockaddr
+                    .sun_path
+                    .iter_mut()
+                    .zip(path.as_os_str().as_bytes())
+                {
+                    *dst = src as libc::c_char;
+                }
+                let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+                unsafe {
+                    std::ptr::copy(
+                        &sockaddr as *const _ as *const u8,
+                        &mut storage as *mut _ as *mut u8,
+                        std::mem::size_of::<libc::sockaddr_un>(),
+                    );
+                }
+                storage
+            }
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { cid, port } => {
+                let mut sockaddr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+                sockaddr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+                sockaddr.svm_cid = cid;
+                sockaddr.svm_port = port;
+                let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+                unsafe {
+                    std::ptr::copy(
+                        &sockaddr as *const _ as *const u8,
+                        &mut storage as *mut _ as *mut u8,
+                        std::mem::size_of::<libc::sockaddr_vm>(),
+                    );
+                }
+                storage
+            }
+            // 命名管道没有 sockaddr
+            #[cfg(windows)]
+            Inner::NamedPipe(_) => unsafe { std::mem::zeroed() },
         }
     }
 
     /// 转换为原始字节（用于哈希）
     pub fn to_bytes(&self) -> Vec<u8> {
         match self.addr {
-            SocketAddr::V4(v4) => {
+            Inner::Inet(SocketAddr::V4(v4)) => {
                 let mut bytes = Vec::with_capacity(8);
                 bytes.extend_from_slice(&v4.ip().octets());
                 bytes.extend_from_slice(&v4.port().to_be_bytes());
                 bytes
             }
-            SocketAddr::V6(v6) => {
+            Inner::Inet(SocketAddr::V6(v6)) => {
                 let mut bytes = Vec::with_capacity(24);
                 bytes.extend_from_slice(&v6.ip().octets());
                 bytes.extend_from_slice(&v6.port().to_be_bytes());
@@ -201,90 +419,63 @@ impl Address {
                 bytes.extend_from_slice(&v6.scope_id().to_be_bytes());
                 bytes
             }
+            #[cfg(unix)]
+            Inner::Unix(ref path) => {
+                use std::os::unix::ffi::OsStrExt;
+                path.as_os_str().as_bytes().to_vec()
+            }
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { cid, port } => {
+                let mut bytes = Vec::with_capacity(8);
+                bytes.extend_from_slice(&cid.to_be_bytes());
+                bytes.extend_from_slice(&port.to_be_bytes());
+                bytes
+            }
+            #[cfg(windows)]
+            Inner::NamedPipe(ref name) => name.as_bytes().to_vec(),
         }
     }
 
     /// 创建已连接的 UDP socket（类似 C++ 版本的 new_connected_udp_fd）
     ///
-    /// 创建一个 UDP socket 并连接到当前地址
-    /// 返回 raw fd，失败返回 -1
-    /// 对于 IPv4-mapped IPv6 地址，自动使用 IPv4 socket 连接
-    #[cfg(unix)]
+    /// 创建一个非阻塞 UDP socket 并连接到当前地址，返回 raw fd (见 `sockets::UdpSocketBuilder::connect`)
+    /// 对于 IPv4-mapped IPv6 地址，自动使用 IPv4 socket 连接。
+    /// `sources` 中有与 socket 地址族相同的地址或指定了 `ports` 时先绑定源地址和端口
     pub fn new_connected_udp_fd(
         &self,
         buf_size: usize,
-    ) -> Result<std::os::unix::io::RawFd, std::io::Error> {
-        // 检查是否是 IPv4-mapped IPv6 地址，如果是则使用 IPv4 socket
-        let (addr_family, sockaddr, len) = if let Some(ipv4_addr) = self.from_ipv4_mapped_ipv6() {
-            let storage = ipv4_addr.to_sockaddr_storage();
-            let addr_len = ipv4_addr.get_len() as libc::socklen_t;
-            (libc::AF_INET, storage, addr_len)
-        } else {
-            let storage = self.to_sockaddr_storage();
-            let addr_len = self.get_len() as libc::socklen_t;
-            (self.get_addr_family(), storage, addr_len)
-        };
-
-        let fd = unsafe { libc::socket(addr_family, libc::SOCK_DGRAM, libc::IPPROTO_UDP) };
-        if fd < 0 {
-            return Err(std::io::Error::last_os_error());
-        }
-
-        // 设置非阻塞
-        crate::set_nonblocking(fd)?;
-
-        // 设置缓冲区大小
-        crate::set_buf_size(fd, buf_size)?;
-
-        // 连接到远程地址
-        unsafe {
-            if libc::connect(fd, &sockaddr as *const _ as *const libc::sockaddr, len) != 0 {
-                libc::close(fd);
-                return Err(std::io::Error::last_os_error());
-            }
-        }
-
-        Ok(fd)
+        sources: &[IpAddr],
+        ports: Option<PortRange>,
+    ) -> Result<crate::PlatformRawFd, std::io::Error> {
+        self.connected_udp_fd(buf_size, sources, ports, None)
     }
 
-    /// 创建已连接的 UDP socket（Windows 版本）
-    /// 对于 IPv4-mapped IPv6 地址，自动使用 IPv4 socket 连接
-    #[cfg(windows)]
-    pub fn new_connected_udp_fd(
+    /// 创建已连接的 UDP socket，并以客户端 IP 作为源地址 (透明代理)
+    pub fn new_transparent_udp_fd(
         &self,
         buf_size: usize,
-    ) -> Result<std::os::windows::io::RawSocket, std::io::Error> {
-        // 检查是否是 IPv4-mapped IPv6 地址，如果是则使用 IPv4 socket
-        let (addr_family, sockaddr, len) = if let Some(ipv4_addr) = self.from_ipv4_mapped_ipv6() {
-            let storage = ipv4_addr.to_sockaddr_storage();
-            let addr_len = ipv4_addr.get_len() as libc::socklen_t;
-            (libc::AF_INET, storage, addr_len)
-        } else {
-            let storage = self.to_sockaddr_storage();
-            let addr_len = self.get_len() as libc::socklen_t;
-            (self.get_addr_family(), storage, addr_len)
-        };
-
-        let fd = unsafe { libc::socket(addr_family, libc::SOCK_DGRAM, libc::IPPROTO_UDP) };
-        if fd < 0 {
-            return Err(std::io::Error::last_os_error());
-        }
-
-        // 设置非阻塞
-        crate::set_nonblocking(fd)?;
-
-        // 设置缓冲区大小
-        crate::set_buf_size(fd, buf_size)?;
-
-        // 连接到远程地址
-        unsafe {
-            if libc::connect(fd, &sockaddr as *const _ as *const libc::sockaddr, len) != 0 {
-                libc::closesocket(fd);
-                return Err(std::io::Error::last_os_error());
-            }
-        }
+        client: SocketAddr,
+    ) -> Result<crate::PlatformRawFd, std::io::Error> {
+        self.connected_udp_fd(buf_size, &[], None, Some(client))
+    }
 
-        Ok(fd)
+    fn connected_udp_fd(
+        &self,
+        buf_size: usize,
+        sources: &[IpAddr],
+        ports: Option<PortRange>,
+        transparent_source: Option<SocketAddr>,
+    ) -> Result<crate::PlatformRawFd, std::io::Error> {
+        let socket = crate::sockets::UdpSocketBuilder::new()
+            .buf_size(buf_size)
+            .source(sources, ports)
+            .transparent_source(transparent_source)
+            .connect(self)?;
+        #[cfg(windows)]
+        use crate::winsock::IntoRawFd;
+        #[cfg(unix)]
+        use std::os::unix::io::IntoRawFd;
+        Ok(socket.into_raw_fd())
     }
 
     /// 转换为 IPv4 映射的 IPv6 地址 (::ffff:x.x.x.x)
@@ -292,7 +483,7 @@ impl Address {
     /// 用于 4to6 翻译模式
     pub fn to_ipv4_mapped_ipv6(&self) -> Option<Self> {
         match self.addr {
-            SocketAddr::V4(v4) => {
+            Inner::Inet(SocketAddr::V4(v4)) => {
                 // 将 IPv4 地址转换为 IPv4 映射的 IPv6 地址
                 let ipv6_addr = Ipv6Addr::new(
                     0x0000,
@@ -306,7 +497,7 @@ impl Address {
                 );
                 Some(Self::from_ipv6(ipv6_addr, v4.port()))
             }
-            SocketAddr::V6(_) => None,
+            _ => None,
         }
     }
 
@@ -315,7 +506,7 @@ impl Address {
     /// 用于 6to4 翻译模式
     pub fn from_ipv4_mapped_ipv6(&self) -> Option<Self> {
         match self.addr {
-            SocketAddr::V6(v6) => {
+            Inner::Inet(SocketAddr::V6(v6)) => {
                 // 检查是否是 IPv4 映射的 IPv6 地址 (::ffff:x.x.x.x)
                 // Ipv6Addr::new使用16位段，所以格式为：
                 // segments = [0, 0, 0, 0, 0, 0xffff, ipv4_high, ipv4_low]
@@ -341,7 +532,27 @@ impl Address {
                     None
                 }
             }
-            SocketAddr::V4(_) => None,
+            _ => None,
+        }
+    }
+
+    /// 按 NAT64 前缀把 IPv4 地址转换为 IPv6 地址，用于 4to6 翻译模式
+    pub fn to_nat64(&self, prefix: &Nat64Prefix) -> Option<Self> {
+        match self.addr {
+            Inner::Inet(SocketAddr::V4(v4)) => {
+                Some(Self::from_ipv6(prefix.embed(*v4.ip()), v4.port()))
+            }
+            _ => None,
+        }
+    }
+
+    /// 从带 NAT64 前缀的 IPv6 地址取出 IPv4 地址，用于 6to4 翻译模式
+    pub fn from_nat64(&self, prefix: &Nat64Prefix) -> Option<Self> {
+        match self.addr {
+            Inner::Inet(SocketAddr::V6(v6)) => {
+                Some(Self::from_ipv4(prefix.extract(*v6.ip())?, v6.port()))
+            }
+            _ => None,
         }
     }
 
@@ -387,8 +598,40 @@ impl FromStr for Address {
     ///
     /// 支持两种格式：
     /// - IPv4: `"1.2.3.4:443"`
-    /// - IPv6: `"[2001:db8::1]:443"`
+    /// - IPv6: `"[2001:db8::1]:443"`，链路本地地址可带接口名或索引 `"[fe80::1%eth0]:443"`
+    /// - Unix 域 socket: `"unix:/run/app.sock"` (仅 Unix 平台)
+    /// - vsock: `"vsock://3:5000"`，`any` 表示任意 CID (仅 Linux)
+    /// - 命名管道: `"npipe:\\\\.\\pipe\\foo"` (仅 Windows)
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_ADDR_PREFIX) {
+            #[cfg(unix)]
+            return Self::from_unix_path(path);
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                return Err(AddressParseError::InvalidPath);
+            }
+        }
+        if let Some(rest) = s.strip_prefix(VSOCK_ADDR_PREFIX) {
+            #[cfg(target_os = "linux")]
+            return parse_vsock(rest);
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = rest;
+                return Err(AddressParseError::InvalidFormat);
+            }
+        }
+
+        if let Some(name) = s.strip_prefix(NPIPE_ADDR_PREFIX) {
+            #[cfg(windows)]
+            return Self::from_named_pipe(name);
+            #[cfg(not(windows))]
+            {
+                let _ = name;
+                return Err(AddressParseError::InvalidPath);
+            }
+        }
+
         // 处理 IPv6 方括号格式: [::1]:8080
         if s.starts_with('[') {
             let closing = match s.find(']') {
@@ -406,8 +649,12 @@ impl FromStr for Address {
                 .parse()
                 .map_err(|_| AddressParseError::InvalidPort)?;
 
+            let (ip_part, scope_id) = match ip_part.split_once('%') {
+                Some((ip, zone)) => (ip, parse_scope_id(zone)?),
+                None => (ip_part, 0),
+            };
             let ip: Ipv6Addr = ip_part.parse().map_err(|_| AddressParseError::InvalidIp)?;
-            return Ok(Self::from_ipv6(ip, port));
+            return Ok(Self::from_ipv6_with_scope_id(ip, port, scope_id));
         }
 
         // 处理 IPv4 格式: 1.2.3.4:443
@@ -434,34 +681,84 @@ impl FromStr for Address {
 impl fmt::Display for Address {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.addr {
-            SocketAddr::V4(v4) => write!(f, "{}:{}", v4.ip(), v4.port()),
-            SocketAddr::V6(v6) => write!(f, "[{}]:{}", v6.ip(), v6.port()),
+            Inner::Inet(SocketAddr::V4(v4)) => write!(f, "{}:{}", v4.ip(), v4.port()),
+            Inner::Inet(SocketAddr::V6(v6)) if v6.scope_id() != 0 => {
+                write!(f, "[{}%{}]:{}", v6.ip(), v6.scope_id(), v6.port())
+            }
+            Inner::Inet(SocketAddr::V6(v6)) => write!(f, "[{}]:{}", v6.ip(), v6.port()),
+            #[cfg(unix)]
+            Inner::Unix(ref path) => write!(f, "{}{}", UNIX_ADDR_PREFIX, path.display()),
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { cid, port } if cid == libc::VMADDR_CID_ANY => {
+                write!(f, "{}any:{}", VSOCK_ADDR_PREFIX, port)
+            }
+            #[cfg(target_os = "linux")]
+            Inner::Vsock { cid, port } => write!(f, "{}{}:{}", VSOCK_ADDR_PREFIX, cid, port),
+            #[cfg(windows)]
+            Inner::NamedPipe(ref name) => write!(f, "{}{}", NPIPE_ADDR_PREFIX, name),
         }
     }
 }
 
 /// 地址解析错误
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, thiserror::Error)]
 pub enum AddressParseError {
     /// 格式错误
+    #[error("invalid address format")]
     InvalidFormat,
     /// 无效的 IP 地址
+    #[error("invalid IP address")]
     InvalidIp,
     /// 无效的端口号
+    #[error("invalid port number")]
     InvalidPort,
+    /// 无效的 IPv6 区域标识 (接口不存在或索引为 0)
+    #[error("invalid IPv6 zone identifier")]
+    InvalidScope,
+    /// 无效的 Unix 域 socket 路径或命名管道名称 (为空、过长或当前平台不支持)
+    #[error("invalid Unix socket path or pipe name")]
+    InvalidPath,
 }
 
-impl fmt::Display for AddressParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            AddressParseError::InvalidFormat => write!(f, "invalid address format"),
-            AddressParseError::InvalidIp => write!(f, "invalid IP address"),
-            AddressParseError::InvalidPort => write!(f, "invalid port number"),
+/// 解析 IPv6 地址 `%` 之后的区域标识：数字为接口索引，否则按接口名查找索引 (仅 Unix 平台)
+fn parse_scope_id(zone: &str) -> Result<u32, AddressParseError> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return if index == 0 {
+            Err(AddressParseError::InvalidScope)
+        } else {
+            Ok(index)
+        };
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(zone).map_err(|_| AddressParseError::InvalidScope)?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(AddressParseError::InvalidScope),
+            index => Ok(index),
         }
     }
+    #[cfg(not(unix))]
+    Err(AddressParseError::InvalidScope)
 }
 
-impl std::error::Error for AddressParseError {}
+/// 解析 vsock 地址中 `cid:port` 部分
+#[cfg(target_os = "linux")]
+fn parse_vsock(s: &str) -> Result<Address, AddressParseError> {
+    let (cid, port) = s.split_once(':').ok_or(AddressParseError::InvalidFormat)?;
+    let cid = match cid {
+        "any" => libc::VMADDR_CID_ANY,
+        _ => cid.parse().map_err(|_| AddressParseError::InvalidIp)?,
+    };
+    let port = port.parse().map_err(|_| AddressParseError::InvalidPort)?;
+    Ok(Address::from_vsock(cid, port))
+}
+
+/// sockaddr_un 中 sun_path 的容量 (含结尾的 NUL)
+#[cfg(unix)]
+fn unix_path_capacity() -> usize {
+    let sockaddr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    sockaddr.sun_path.len()
+}
 
 #[cfg(test)]
 mod tests {
@@ -487,6 +784,37 @@ mod tests {
         assert_eq!(addr.to_string(), "[::1]:8080");
     }
 
+    #[test]
+    fn test_ipv6_scope_id() {
+        let addr: Address = "[fe80::1%3]:443".parse().unwrap();
+        match addr.to_sockaddr() {
+            SocketAddr::V6(v6) => assert_eq!(v6.scope_id(), 3),
+            SocketAddr::V4(_) => panic!("expected IPv6"),
+        }
+        let storage = addr.to_sockaddr_storage();
+        let sin6 = unsafe { &*(&storage as *const _ as *const libc::sockaddr_in6) };
+        assert_eq!(sin6.sin6_scope_id, 3);
+        assert_eq!(addr.to_string(), "[fe80::1%3]:443");
+        assert_ne!(addr, "[fe80::1]:443".parse().unwrap());
+
+        #[cfg(target_os = "linux")]
+        {
+            let lo: Address = "[fe80::1%lo]:443".parse().unwrap();
+            assert_ne!(
+                lo.to_sockaddr(),
+                "[fe80::1]:443".parse::<Address>().unwrap().to_sockaddr()
+            );
+        }
+        assert_eq!(
+            "[fe80::1%0]:443".parse::<Address>(),
+            Err(AddressParseError::InvalidScope)
+        );
+        assert_eq!(
+            "[fe80::1%no-such-if0]:443".parse::<Address>(),
+            Err(AddressParseError::InvalidScope)
+        );
+    }
+
     #[test]
     fn test_ipv6_any() {
         let addr = "[::]:443".parse::<Address>().expect("Option unwrap failed");
@@ -622,6 +950,104 @@ mod tests {
         assert_eq!(addr.port(), 3000);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_parse() {
+        let addr: Address = "unix:/run/app.sock"
+            .parse()
+            .expect("Address parsing failed");
+        assert!(addr.is_unix());
+        assert_eq!(addr.get_type(), ADDR_TYPE_UNIX);
+        assert_eq!(addr.get_addr_family(), libc::AF_UNIX);
+        assert_eq!(addr.unix_path(), Some(Path::new("/run/app.sock")));
+        assert_eq!(addr.to_string(), "unix:/run/app.sock");
+        assert_eq!(addr.port(), 0);
+        assert!(addr.to_ipv4_mapped_ipv6().is_none());
+        assert_eq!(
+            addr.get_len(),
+            std::mem::offset_of!(libc::sockaddr_un, sun_path) + "/run/app.sock".len() + 1
+        );
+        let storage = addr.to_sockaddr_storage();
+        assert_eq!(storage.ss_family as libc::c_int, libc::AF_UNIX);
+
+        let other: Address = "unix:/run/other.sock"
+            .parse()
+            .expect("Address parsing failed");
+        assert_ne!(addr, other);
+        let ip: Address = "127.0.0.1:8080".parse().expect("Address parsing failed");
+        assert!(!ip.is_unix());
+        assert_ne!(addr, ip);
+
+        assert_eq!(
+            "unix:".parse::<Address>(),
+            Err(AddressParseError::InvalidPath)
+        );
+        let long = format!("unix:/{}", "a".repeat(200));
+        assert_eq!(long.parse::<Address>(), Err(AddressParseError::InvalidPath));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_vsock_parse() {
+        let addr: Address = "vsock://3:5000".parse().expect("Address parsing failed");
+        assert!(!addr.is_ip());
+        assert!(!addr.is_unix());
+        assert_eq!(addr.get_type(), ADDR_TYPE_VSOCK);
+        assert_eq!(addr.get_addr_family(), libc::AF_VSOCK);
+        assert_eq!(addr.to_string(), "vsock://3:5000");
+        assert_eq!(addr.get_len(), std::mem::size_of::<libc::sockaddr_vm>());
+        let storage = addr.to_sockaddr_storage();
+        let vm = unsafe { &*(&storage as *const _ as *const libc::sockaddr_vm) };
+        assert_eq!((vm.svm_cid, vm.svm_port), (3, 5000));
+
+        let any: Address = "vsock://any:5000".parse().expect("Address parsing failed");
+        assert_eq!(any, Address::from_vsock(libc::VMADDR_CID_ANY, 5000));
+        assert_eq!(any.to_string(), "vsock://any:5000");
+        assert_ne!(addr, any);
+
+        assert_eq!(
+            "vsock://3".parse::<Address>(),
+            Err(AddressParseError::InvalidFormat)
+        );
+        assert_eq!(
+            "vsock://host:5000".parse::<Address>(),
+            Err(AddressParseError::InvalidIp)
+        );
+        assert_eq!(
+            "vsock://3:x".parse::<Address>(),
+            Err(AddressParseError::InvalidPort)
+        );
+    }
+
+    #[test]
+    fn test_npipe_parse() {
+        #[cfg(windows)]
+        {
+            let addr: Address = r"npipe:\\.\pipe\foo"
+                .parse()
+                .expect("Address parsing failed");
+            assert!(!addr.is_ip());
+            assert_eq!(addr.get_type(), ADDR_TYPE_NPIPE);
+            assert_eq!(addr.named_pipe(), Some(r"\\.\pipe\foo"));
+            assert_eq!(addr.to_string(), r"npipe:\\.\pipe\foo");
+            assert_eq!(addr.port(), 0);
+            let remote: Address = r"npipe:\\host\PIPE\foo"
+                .parse()
+                .expect("Address parsing failed");
+            assert_ne!(addr, remote);
+        }
+        for bad in [
+            r"npipe:",
+            r"npipe:foo",
+            r"npipe:\\.\pipe\",
+            r"npipe:\\\pipe\foo",
+        ] {
+            assert_eq!(bad.parse::<Address>(), Err(AddressParseError::InvalidPath));
+        }
+        let ip: Address = "127.0.0.1:8080".parse().expect("Address parsing failed");
+        assert_eq!(ip.named_pipe(), None);
+    }
+
     #[test]
     fn test_unspecified_addresses() {
         let ipv4_any: Address = "0.0.0.0:0".parse().expect("Address parsing failed");
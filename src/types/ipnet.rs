@@ -0,0 +1,146 @@
+//! IP 网段 (CIDR)
+//!
+//! 用于按客户端地址放行或拒绝，例如 `10.0.0.0/8`、`2001:db8::/32`；不带长度时只匹配该地址。
+//! IPv4 网段同样匹配 IPv4 映射的 IPv6 客户端地址 (双栈监听时的 `::ffff:a.b.c.d`)
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IP 网段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpNet {
+    /// 创建网段，长度超出地址位数或前缀之后的位不为 0 时返回 None
+    pub fn new(addr: IpAddr, len: u8) -> Option<Self> {
+        let net = Self { addr, len };
+        if len > net.max_len() || net.masked(addr) != addr_bits(addr) {
+            return None;
+        }
+        Some(net)
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// 地址是否在网段内
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V6(v6)) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => return false,
+            },
+            (_, ip) => ip,
+        };
+        self.addr.is_ipv4() == ip.is_ipv4() && self.masked(ip) == addr_bits(self.addr)
+    }
+
+    fn max_len(&self) -> u8 {
+        if self.addr.is_ipv4() {
+            32
+        } else {
+            128
+        }
+    }
+
+    /// 按前缀长度截断后的地址位 (与网段同一地址族)
+    fn masked(&self, ip: IpAddr) -> u128 {
+        let host_bits = u32::from(self.max_len() - self.len.min(self.max_len()));
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        addr_bits(ip) & mask
+    }
+}
+
+/// 地址的数值形式，IPv4 占低 32 位
+fn addr_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(u32::from(v4)),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("invalid network '{}', expected e.g. 10.0.0.0/8", s);
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let len = match len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, len).ok_or_else(|| {
+            format!(
+                "invalid network '{}', prefix length too long or host bits set",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(net.addr(), ip("10.0.0.0"));
+        assert_eq!(net.prefix_len(), 8);
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert_eq!("192.0.2.1".parse::<IpNet>().unwrap().prefix_len(), 32);
+        assert_eq!("2001:db8::1".parse::<IpNet>().unwrap().prefix_len(), 128);
+        assert_eq!("0.0.0.0/0".parse::<IpNet>().unwrap().prefix_len(), 0);
+
+        assert!("10.0.0.1/8".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("2001:db8::/129".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNet>().is_err());
+        assert!("example.com/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.1.2.3")));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert!("::/0".parse::<IpNet>().unwrap().contains(ip("2001:db8::1")));
+    }
+}
@@ -1,4 +1,11 @@
 //! 类型模块
 
 pub mod address;
-pub use address::{Address, AddressParseError, AddressType, ADDR_TYPE_IPV4, ADDR_TYPE_IPV6};
+pub mod ipnet;
+pub mod nat64;
+pub use address::{
+    Address, AddressParseError, AddressType, ADDR_TYPE_IPV4, ADDR_TYPE_IPV6, ADDR_TYPE_NPIPE,
+    ADDR_TYPE_UNIX, ADDR_TYPE_VSOCK, NPIPE_ADDR_PREFIX, UNIX_ADDR_PREFIX, VSOCK_ADDR_PREFIX,
+};
+pub use ipnet::IpNet;
+pub use nat64::Nat64Prefix;
@@ -0,0 +1,172 @@
+//! NAT64 前缀 (RFC 6052)
+//!
+//! 4to6 模式把 IPv4 远程地址嵌入前缀得到 IPv6 地址，6to4 模式从带前缀的 IPv6 远程地址中取出 IPv4 地址。
+//! 默认前缀为 `::ffff:0:0/96` (IPv4 映射地址)，与原来的行为相同；经 NAT64 网关转发时使用
+//! 知名前缀 `64:ff9b::/96` 或运营商分配的前缀
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// RFC 6052 允许的前缀长度
+const PREFIX_LENS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+/// 地址中第 64-71 位 (u 字节)，必须为 0，嵌入的 IPv4 地址跳过这个字节
+const U_OCTET: usize = 8;
+
+/// NAT64 前缀
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nat64Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// IPv4 映射地址前缀 `::ffff:0:0/96`
+    pub const IPV4_MAPPED: Self = Self {
+        prefix: Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0),
+        len: 96,
+    };
+
+    /// 知名前缀 `64:ff9b::/96` (RFC 6052)
+    pub const WELL_KNOWN: Self = Self {
+        prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    /// 创建前缀，长度不是 32/40/48/56/64/96、前缀之后的位或 u 字节不为 0 时返回 None
+    pub fn new(prefix: Ipv6Addr, len: u8) -> Option<Self> {
+        if !PREFIX_LENS.contains(&len) {
+            return None;
+        }
+        let octets = prefix.octets();
+        let start = usize::from(len / 8);
+        if octets[start..].iter().any(|&b| b != 0) || octets[U_OCTET] != 0 {
+            return None;
+        }
+        Some(Self { prefix, len })
+    }
+
+    pub fn prefix(&self) -> Ipv6Addr {
+        self.prefix
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// 是否为默认的 IPv4 映射地址前缀
+    pub fn is_ipv4_mapped(&self) -> bool {
+        *self == Self::IPV4_MAPPED
+    }
+
+    /// 嵌入的 IPv4 地址在 IPv6 地址中的字节位置
+    fn positions(&self) -> impl Iterator<Item = usize> {
+        (usize::from(self.len / 8)..16)
+            .filter(|&i| i != U_OCTET)
+            .take(4)
+    }
+
+    /// 把 IPv4 地址嵌入前缀
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        for (i, b) in self.positions().zip(ip.octets()) {
+            octets[i] = b;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// 取出嵌入的 IPv4 地址，地址不在前缀内时返回 None
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = ip.octets();
+        let start = usize::from(self.len / 8);
+        if octets[..start] != self.prefix.octets()[..start] {
+            return None;
+        }
+        let mut v4 = [0u8; 4];
+        for (b, i) in v4.iter_mut().zip(self.positions()) {
+            *b = octets[i];
+        }
+        Some(Ipv4Addr::from(v4))
+    }
+}
+
+impl Default for Nat64Prefix {
+    fn default() -> Self {
+        Self::IPV4_MAPPED
+    }
+}
+
+impl FromStr for Nat64Prefix {
+    type Err = String;
+
+    /// 解析 `64:ff9b::/96`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("invalid NAT64 prefix '{}', expected addr/len", s))?;
+        let prefix: Ipv6Addr = prefix
+            .parse()
+            .map_err(|_| format!("invalid IPv6 address in NAT64 prefix '{}'", s))?;
+        let len: u8 = len
+            .parse()
+            .map_err(|_| format!("invalid length in NAT64 prefix '{}'", s))?;
+        Self::new(prefix, len).ok_or_else(|| {
+            format!(
+                "invalid NAT64 prefix '{}', length must be 32/40/48/56/64/96 with host and u-octet bits zero",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embed() {
+        // RFC 6052 2.4 的示例：192.0.2.33 嵌入 2001:db8:122:344::/32 等前缀
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        let cases = [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::c000:221"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ];
+        for (prefix, expected) in cases {
+            let prefix: Nat64Prefix = prefix.parse().unwrap();
+            let expected: Ipv6Addr = expected.parse().unwrap();
+            assert_eq!(prefix.embed(ip), expected, "{}", prefix);
+            assert_eq!(prefix.extract(expected), Some(ip), "{}", prefix);
+        }
+        assert_eq!(Nat64Prefix::default().embed(ip), ip.to_ipv6_mapped(),);
+        assert_eq!(
+            Nat64Prefix::WELL_KNOWN.extract("2001:db8::c000:221".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "64:ff9b::/96".parse::<Nat64Prefix>(),
+            Ok(Nat64Prefix::WELL_KNOWN)
+        );
+        assert_eq!(Nat64Prefix::WELL_KNOWN.to_string(), "64:ff9b::/96");
+        assert!("64:ff9b::".parse::<Nat64Prefix>().is_err());
+        assert!("64:ff9b::/80".parse::<Nat64Prefix>().is_err());
+        // 前缀之后的位和 u 字节必须为 0
+        assert!("64:ff9b::1/96".parse::<Nat64Prefix>().is_err());
+        assert!("2001:db8:0:0:ff00::/64".parse::<Nat64Prefix>().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! 平滑升级 (仅 Unix)
+//!
+//! 旧进程在 Unix 域控制 socket 上等待新进程连接，用 SCM_RIGHTS 发送所有监听 socket；
+//! 新进程核对地址并注册后回复确认，旧进程随即停止读取监听 socket 并开始排空。
+//! 监听 socket 始终至少由一个进程持有，升级期间不会拒绝新连接。
+//! Windows 上只保留 `SocketKind` 和不可构造的 `Handover`，`Handover::connect` 返回 Unsupported
+
+use crate::types::Address;
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(windows)]
+use std::os::windows::io::OwnedSocket;
+use std::path::Path;
+use std::time::Duration;
+
+/// 交接过程中单次读写的超时时间
+pub const HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 单次交接的监听 socket 数量上限
+pub const MAX_HANDOVER_FDS: usize = 16;
+
+/// 新进程接管完成的确认字节
+#[cfg(unix)]
+const ACK: u8 = b'K';
+
+/// 监听 socket 类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    /// TCP 监听 socket
+    Tcp,
+    /// UDP 监听 socket
+    Udp,
+}
+
+#[cfg(unix)]
+impl SocketKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            SocketKind::Tcp => b'T',
+            SocketKind::Udp => b'U',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'T' => Some(SocketKind::Tcp),
+            b'U' => Some(SocketKind::Udp),
+            _ => None,
+        }
+    }
+}
+
+/// 通过 Unix 域 socket 发送一组 fd，每个 fd 对应正文中的一个类型字节
+#[cfg(unix)]
+pub fn send_fds(fd: RawFd, sockets: &[(SocketKind, RawFd)]) -> io::Result<()> {
+    if sockets.is_empty() || sockets.len() > MAX_HANDOVER_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot hand over {} sockets", sockets.len()),
+        ));
+    }
+    let kinds: Vec<u8> = sockets.iter().map(|(kind, _)| kind.to_byte()).collect();
+    let fds: Vec<RawFd> = sockets.iter().map(|&(_, fd)| fd).collect();
+    let fds_len = std::mem::size_of_val(fds.as_slice()) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: kinds.as_ptr() as *mut libc::c_void,
+        iov_len: kinds.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr() as *const u8,
+            libc::CMSG_DATA(cmsg),
+            fds_len as usize,
+        );
+    }
+    if unsafe { libc::sendmsg(fd, &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 接收 `send_fds` 发送的一组 fd
+#[cfg(unix)]
+pub fn recv_fds(fd: RawFd) -> io::Result<Vec<(SocketKind, OwnedFd)>> {
+    let mut kinds = [0u8; MAX_HANDOVER_FDS];
+    let fds_len = (MAX_HANDOVER_FDS * std::mem::size_of::<RawFd>()) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: kinds.as_mut_ptr() as *mut libc::c_void,
+        iov_len: kinds.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let n = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // 先接管收到的所有 fd，出错返回时一并关闭
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..data_len / std::mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "control socket closed",
+        ));
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 || fds.len() != n as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "socket list and passed descriptors do not match",
+        ));
+    }
+    kinds[..n as usize]
+        .iter()
+        .zip(fds)
+        .map(|(&byte, fd)| {
+            SocketKind::from_byte(byte)
+                .map(|kind| (kind, fd))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown socket kind"))
+        })
+        .collect()
+}
+
+/// 监听 socket 绑定的本地地址
+#[cfg(unix)]
+fn local_address(fd: RawFd) -> Option<Address> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret != 0 {
+        return None;
+    }
+    Address::from_raw_sockaddr(&storage as *const _ as *const libc::sockaddr, len).ok()
+}
+
+/// 从旧进程接管的监听 socket
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Handover {
+    stream: UnixStream,
+    sockets: Vec<(SocketKind, OwnedFd)>,
+}
+
+#[cfg(unix)]
+impl Handover {
+    /// 连接旧进程的控制 socket 并接收监听 socket，没有旧进程在运行时返回 None
+    pub fn connect(path: &Path) -> io::Result<Option<Self>> {
+        let stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDOVER_TIMEOUT))?;
+        let sockets = recv_fds(stream.as_raw_fd())?;
+        Ok(Some(Self { stream, sockets }))
+    }
+
+    /// 取出类型和本地地址都匹配的监听 socket
+    pub fn take(&mut self, kind: SocketKind, addr: &Address) -> Option<OwnedFd> {
+        let index = self.sockets.iter().position(|(k, fd)| {
+            *k == kind && local_address(fd.as_raw_fd()).as_ref() == Some(addr)
+        })?;
+        Some(self.sockets.swap_remove(index).1)
+    }
+
+    /// 通知旧进程接管完成，未取出的 socket 随之关闭
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stream.write_all(&[ACK])
+    }
+}
+
+/// Windows 不支持平滑升级，不会有接管的监听 socket
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum Handover {}
+
+#[cfg(windows)]
+impl Handover {
+    /// 总是返回 Unsupported
+    pub fn connect(_path: &Path) -> io::Result<Option<Self>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "graceful upgrade is only supported on Unix",
+        ))
+    }
+
+    pub fn take(&mut self, _kind: SocketKind, _addr: &Address) -> Option<OwnedSocket> {
+        match *self {}
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {}
+    }
+}
+
+/// 在旧进程中处理一次升级请求：发送监听 socket 并等待新进程确认，返回是否已完成交接
+#[cfg(unix)]
+pub fn serve(mut stream: UnixStream, sockets: &[(SocketKind, RawFd)]) -> io::Result<bool> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDOVER_TIMEOUT))?;
+    send_fds(stream.as_raw_fd(), sockets)?;
+    let mut ack = [0u8; 1];
+    match stream.read(&mut ack) {
+        Ok(1) => Ok(ack[0] == ACK),
+        Ok(_) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// 创建控制 socket，替换路径上已有的 socket 文件
+#[cfg(unix)]
+pub fn listen(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_recv_fds() {
+        let (left, right) = UnixStream::pair().expect("socketpair");
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").expect("bind tcp");
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind udp");
+        let tcp_addr = Address::from_sockaddr(tcp.local_addr().expect("addr"));
+        let udp_addr = Address::from_sockaddr(udp.local_addr().expect("addr"));
+        send_fds(
+            left.as_raw_fd(),
+            &[
+                (SocketKind::Tcp, tcp.as_raw_fd()),
+                (SocketKind::Udp, udp.as_raw_fd()),
+            ],
+        )
+        .expect("send");
+        let sockets = recv_fds(right.as_raw_fd()).expect("recv");
+        assert_eq!(sockets.len(), 2);
+
+        let mut handover = Handover {
+            stream: right,
+            sockets,
+        };
+        assert!(handover.take(SocketKind::Udp, &tcp_addr).is_none());
+        let fd = handover
+            .take(SocketKind::Tcp, &tcp_addr)
+            .expect("tcp socket");
+        assert_eq!(local_address(fd.as_raw_fd()), Some(tcp_addr));
+        assert!(handover.take(SocketKind::Udp, &udp_addr).is_some());
+        handover.finish().expect("ack");
+        let mut ack = [0u8; 1];
+        (&left).read_exact(&mut ack).expect("read ack");
+        assert_eq!(ack[0], ACK);
+
+        assert!(send_fds(left.as_raw_fd(), &[]).is_err());
+    }
+}
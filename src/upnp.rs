@@ -0,0 +1,972 @@
+//! 路由器端口映射 (--upnp)
+//!
+//! 启动时请求家用路由器把相同的外部端口映射到监听端口，依次尝试 PCP (RFC 6887)、NAT-PMP (RFC 6886)
+//! 和 UPnP IGD：PCP 和 NAT-PMP 发往默认网关的 5351 端口 (网关地址从 /proc/net/route 读取，仅 Linux)，
+//! 网关只支持 NAT-PMP 时以版本错误应答 PCP 请求，改用 NAT-PMP；都没有应答时用 SSDP 组播发现 IGD，
+//! 以 SOAP 调用 AddPortMapping。
+//!
+//! 请求在单独的线程中进行，不阻塞事件循环；映射租期为 `LEASE`，由定时器每 `RENEW_INTERVAL` 续期，
+//! 外部地址变化时输出日志。退出时不删除映射，由路由器在租期结束后回收。只支持 IPv4
+
+use crate::sync::Recover;
+use crate::{debug, info, warn};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 请求的映射租期
+pub const LEASE: Duration = Duration::from_secs(3600);
+
+/// 续期间隔 (租期的一半)
+pub const RENEW_INTERVAL: Duration = Duration::from_secs(1800);
+
+/// 网关上 PCP 和 NAT-PMP 的服务端口
+pub const PMP_PORT: u16 = 5351;
+
+/// PCP/NAT-PMP 首次等待应答的时间，每次重发加倍 (RFC 6886 3.1)
+const PMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// PCP/NAT-PMP 请求的发送次数
+const PMP_ATTEMPTS: u32 = 4;
+
+/// SSDP 组播地址
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// SSDP 发现和 HTTP 请求的超时时间
+const UPNP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// HTTP 应答的长度上限
+const MAX_HTTP_RESPONSE: u64 = 64 << 10;
+
+/// 提供端口映射的 IGD 服务，按优先顺序
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// 映射在路由器上显示的描述
+const DESCRIPTION: &str = "tinyPortMapper";
+
+/// 映射的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// PCP 请求中的 IP 协议号
+    fn ip_proto(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+
+    /// NAT-PMP 映射请求的操作码
+    fn pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        })
+    }
+}
+
+/// 建立映射使用的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::Pcp => "PCP",
+            Method::NatPmp => "NAT-PMP",
+            Method::Upnp => "UPnP",
+        })
+    }
+}
+
+/// 路由器上建立的映射
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub method: Method,
+    pub protocol: Protocol,
+    /// 本机端口
+    pub internal: u16,
+    /// 外部地址和端口
+    pub external: SocketAddrV4,
+    /// 路由器给出的租期，为 0 时为永久映射
+    pub lease: Duration,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} port {} mapped to {} via {}",
+            self.protocol, self.internal, self.external, self.method
+        )?;
+        if self.lease.is_zero() {
+            f.write_str(", permanent")
+        } else {
+            write!(f, ", lease {}s", self.lease.as_secs())
+        }
+    }
+}
+
+/// 路由器端口映射，由定时器调用 `run` 建立和续期
+#[derive(Debug)]
+pub struct PortForwarder {
+    request: Arc<Request>,
+    running: Arc<AtomicBool>,
+}
+
+impl PortForwarder {
+    /// 为 `port` 的各协议请求映射，`local` 为监听的本机 IP，None 时使用通往路由器的本机地址
+    pub fn new(local: Option<Ipv4Addr>, port: u16, protocols: Vec<Protocol>) -> Self {
+        let mut nonce = [0u8; 12];
+        for chunk in nonce.chunks_mut(8) {
+            let value = crate::get_fake_random_number_64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Self {
+            request: Arc::new(Request {
+                local,
+                port,
+                protocols,
+                nonce,
+                gateway: None,
+                state: Mutex::new(State::default()),
+            }),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 在后台线程中建立或续期映射 (上一次请求尚未结束时跳过)
+    pub fn run(&self) {
+        if self.running.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let request = Arc::clone(&self.request);
+        let running = Arc::clone(&self.running);
+        let spawned = std::thread::Builder::new()
+            .name("upnp".to_string())
+            .spawn(move || {
+                request.map_all();
+                running.store(false, Ordering::Relaxed);
+            });
+        if let Err(e) = spawned {
+            warn!("[upnp] failed to spawn mapping thread: {}", e);
+            self.running.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 映射请求和上次的结果
+#[derive(Debug)]
+struct Request {
+    local: Option<Ipv4Addr>,
+    port: u16,
+    protocols: Vec<Protocol>,
+    /// PCP 映射的标识，续期时必须相同
+    nonce: [u8; 12],
+    /// PCP/NAT-PMP 网关，None 时使用默认网关 (测试中指定)
+    gateway: Option<SocketAddrV4>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// 上次成功使用的方式，续期时先尝试
+    method: Option<Method>,
+    /// 已发现的 IGD
+    igd: Option<Igd>,
+    /// 各协议上次的映射，外部地址变化时输出日志
+    mappings: Vec<Mapping>,
+}
+
+impl Request {
+    /// 为每个协议建立映射并输出结果
+    fn map_all(&self) {
+        for &protocol in &self.protocols {
+            match self.map(protocol) {
+                Ok(mapping) => {
+                    let mut state = self.state.lock().recover();
+                    state.method = Some(mapping.method);
+                    let known = state
+                        .mappings
+                        .iter()
+                        .any(|m| m.protocol == protocol && m.external == mapping.external);
+                    if known {
+                        debug!("[upnp] renewed {}", mapping);
+                    } else {
+                        info!("[upnp] {}", mapping);
+                        state.mappings.retain(|m| m.protocol != protocol);
+                        state.mappings.push(mapping);
+                    }
+                }
+                Err(e) => {
+                    // 下次重新尝试所有方式
+                    self.state.lock().recover().method = None;
+                    warn!(
+                        "[upnp] failed to map {} port {}: {}",
+                        protocol, self.port, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// 建立一个映射：上次使用 UPnP 时直接续期，否则先尝试 PCP/NAT-PMP
+    fn map(&self, protocol: Protocol) -> io::Result<Mapping> {
+        let method = self.state.lock().recover().method;
+        if method != Some(Method::Upnp) {
+            let gateway = match self.gateway {
+                Some(gateway) => Ok(gateway),
+                None => default_gateway().map(|ip| SocketAddrV4::new(ip, PMP_PORT)),
+            };
+            match gateway.and_then(|gateway| self.map_pmp(gateway, protocol)) {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => debug!("[upnp] PCP/NAT-PMP unavailable: {}", e),
+            }
+        }
+        self.map_upnp(protocol)
+    }
+
+    /// 向网关请求 PCP 映射，网关只支持 NAT-PMP 时改用 NAT-PMP
+    fn map_pmp(&self, gateway: SocketAddrV4, protocol: Protocol) -> io::Result<Mapping> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(
+            self.local.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            0,
+        ))?;
+        socket.connect(gateway)?;
+        let client = match socket.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => return Err(io::Error::other("gateway is not IPv4")),
+        };
+
+        let request = pcp_request(&self.nonce, client, protocol, self.port, LEASE);
+        let reply = exchange(&socket, &request)?;
+        if let Some((external, lease)) = parse_pcp(&reply, &self.nonce, protocol, self.port)? {
+            return Ok(Mapping {
+                method: Method::Pcp,
+                protocol,
+                internal: self.port,
+                external,
+                lease,
+            });
+        }
+
+        let reply = exchange(&socket, &natpmp_request(protocol, self.port, LEASE))?;
+        let (port, lease) = parse_natpmp(&reply, protocol, self.port)?;
+        let reply = exchange(&socket, &[0, 0])?;
+        let ip = parse_natpmp_address(&reply)?;
+        Ok(Mapping {
+            method: Method::NatPmp,
+            protocol,
+            internal: self.port,
+            external: SocketAddrV4::new(ip, port),
+            lease,
+        })
+    }
+
+    /// 通过 IGD 建立映射，IGD 出错时下次重新发现
+    fn map_upnp(&self, protocol: Protocol) -> io::Result<Mapping> {
+        let cached = self.state.lock().recover().igd.clone();
+        let igd = match cached {
+            Some(igd) => igd,
+            None => {
+                let igd = Igd::discover(self.local)?;
+                debug!("[upnp] found gateway {}", igd.control);
+                igd
+            }
+        };
+        let result = igd.add_port_mapping(self.local, protocol, self.port);
+        self.state.lock().recover().igd = result.is_ok().then_some(igd);
+        result
+    }
+}
+
+/// PCP MAP 请求 (RFC 6887 7.1、11.1)，建议的外部端口与本机端口相同
+fn pcp_request(
+    nonce: &[u8; 12],
+    client: Ipv4Addr,
+    protocol: Protocol,
+    port: u16,
+    lease: Duration,
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(60);
+    buf.extend_from_slice(&[2, 1, 0, 0]);
+    buf.extend_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+    buf.extend_from_slice(&client.to_ipv6_mapped().octets());
+    buf.extend_from_slice(nonce);
+    buf.extend_from_slice(&[protocol.ip_proto(), 0, 0, 0]);
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    buf
+}
+
+/// 解析 PCP MAP 应答，返回外部地址和租期；网关只支持 NAT-PMP 时返回 None
+fn parse_pcp(
+    reply: &[u8],
+    nonce: &[u8; 12],
+    protocol: Protocol,
+    port: u16,
+) -> io::Result<Option<(SocketAddrV4, Duration)>> {
+    // NAT-PMP 网关以版本 0、结果码 1 (不支持的版本) 应答
+    if let [0, _, 0, 1, ..] = reply {
+        return Ok(None);
+    }
+    if reply.len() < 60 || reply[0] != 2 || reply[1] != 0x81 {
+        return Err(invalid_reply("PCP"));
+    }
+    if reply[3] != 0 {
+        return Err(io::Error::other(format!("PCP error {}", reply[3])));
+    }
+    if reply[24..36] != nonce[..]
+        || reply[36] != protocol.ip_proto()
+        || reply[40..42] != port.to_be_bytes()
+    {
+        return Err(invalid_reply("PCP"));
+    }
+    let lease = u32::from_be_bytes([reply[4], reply[5], reply[6], reply[7]]);
+    let external_port = u16::from_be_bytes([reply[42], reply[43]]);
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&reply[44..60]);
+    let ip = Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .ok_or_else(|| invalid_reply("PCP"))?;
+    Ok(Some((
+        SocketAddrV4::new(ip, external_port),
+        Duration::from_secs(lease.into()),
+    )))
+}
+
+/// NAT-PMP 映射请求 (RFC 6886 3.3)
+fn natpmp_request(protocol: Protocol, port: u16, lease: Duration) -> [u8; 12] {
+    let mut buf = [0u8; 12];
+    buf[1] = protocol.pmp_opcode();
+    buf[4..6].copy_from_slice(&port.to_be_bytes());
+    buf[6..8].copy_from_slice(&port.to_be_bytes());
+    buf[8..12].copy_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+    buf
+}
+
+/// 检查 NAT-PMP 应答的版本、操作码和结果码
+fn check_natpmp(reply: &[u8], opcode: u8, len: usize) -> io::Result<()> {
+    if reply.len() < len || reply[0] != 0 || reply[1] != 128 + opcode {
+        return Err(invalid_reply("NAT-PMP"));
+    }
+    match u16::from_be_bytes([reply[2], reply[3]]) {
+        0 => Ok(()),
+        code => Err(io::Error::other(format!("NAT-PMP error {}", code))),
+    }
+}
+
+/// 解析 NAT-PMP 映射应答，返回外部端口和租期
+fn parse_natpmp(reply: &[u8], protocol: Protocol, port: u16) -> io::Result<(u16, Duration)> {
+    check_natpmp(reply, protocol.pmp_opcode(), 16)?;
+    if reply[8..10] != port.to_be_bytes() {
+        return Err(invalid_reply("NAT-PMP"));
+    }
+    let external = u16::from_be_bytes([reply[10], reply[11]]);
+    let lease = u32::from_be_bytes([reply[12], reply[13], reply[14], reply[15]]);
+    Ok((external, Duration::from_secs(lease.into())))
+}
+
+/// 解析 NAT-PMP 外部地址应答
+fn parse_natpmp_address(reply: &[u8]) -> io::Result<Ipv4Addr> {
+    check_natpmp(reply, 0, 12)?;
+    Ok(Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]))
+}
+
+fn invalid_reply(protocol: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {} reply", protocol),
+    )
+}
+
+/// 发送请求并等待应答，超时后加倍等待时间重发
+fn exchange(socket: &UdpSocket, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; 1100];
+    let mut timeout = PMP_INITIAL_TIMEOUT;
+    for _ in 0..PMP_ATTEMPTS {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            Ok(n) => return Ok(buf[..n].to_vec()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                timeout *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no reply from gateway",
+    ))
+}
+
+/// 默认网关地址
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    #[cfg(target_os = "linux")]
+    {
+        let routes = std::fs::read_to_string("/proc/net/route")?;
+        parse_route(&routes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 default gateway"))
+    }
+    #[cfg(not(target_os = "linux"))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "default gateway lookup is only supported on Linux",
+    ))
+}
+
+/// 从 /proc/net/route 中找出默认路由的网关 (目的地址和掩码为 0，带 RTF_GATEWAY 标志)
+#[cfg(any(target_os = "linux", test))]
+fn parse_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let hex = |i: usize| fields.get(i).and_then(|v| u32::from_str_radix(v, 16).ok());
+        let (dest, gateway, flags, mask) = (hex(1)?, hex(2)?, hex(3)?, hex(7)?);
+        // 地址字段为网络字节序按本机字节序打印
+        (dest == 0 && mask == 0 && flags & 0x2 != 0 && gateway != 0)
+            .then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// `http://host:port/path` 形式的地址，`--webhook` 也使用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl HttpUrl {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let rest = s.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') => (host, port.parse().ok()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// 解析相对于本地址的引用
+    fn join(&self, reference: &str) -> Option<Self> {
+        if reference.starts_with("http://") {
+            return Self::parse(reference);
+        }
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            let dir = &self.path[..=self.path.rfind('/').unwrap_or(0)];
+            format!("{}{}", dir, reference)
+        };
+        Some(Self {
+            path,
+            ..self.clone()
+        })
+    }
+
+    fn socket_addr(&self) -> io::Result<SocketAddr> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for gateway"))
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// 发送 HTTP/1.0 请求，返回状态码和正文
+pub(crate) fn http_request(
+    url: &HttpUrl,
+    method: &str,
+    headers: &str,
+    body: &str,
+) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect_timeout(&url.socket_addr()?, UPNP_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPNP_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPNP_TIMEOUT))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+        method,
+        url.path,
+        url.host,
+        url.port,
+        headers,
+        body.len(),
+        body
+    )?;
+    let mut response = Vec::new();
+    stream.take(MAX_HTTP_RESPONSE).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid_reply("HTTP"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid_reply("HTTP"))?;
+    Ok((status, body.to_string()))
+}
+
+/// 取出 HTTP 头部中的字段值 (不区分大小写)
+pub(crate) fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// 取出第一个 `<name>` 元素的文本
+fn xml_text<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/// 设备描述中提供端口映射的服务及其控制地址
+fn find_service(description: &str) -> Option<(&'static str, &str)> {
+    WAN_SERVICES.iter().find_map(|&service| {
+        let start = description.find(&format!("<serviceType>{}</serviceType>", service))?;
+        let rest = &description[start..];
+        let end = rest.find("</service>").unwrap_or(rest.len());
+        Some((service, xml_text(&rest[..end], "controlURL")?))
+    })
+}
+
+/// UPnP 互联网网关设备
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Igd {
+    service: &'static str,
+    control: HttpUrl,
+}
+
+impl Igd {
+    /// 用 SSDP 组播查找网关，取第一个应答的设备
+    fn discover(local: Option<Ipv4Addr>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(local.unwrap_or(Ipv4Addr::UNSPECIFIED), 0))?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+            SSDP_ADDR
+        );
+        socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+        let deadline = Instant::now() + UPNP_TIMEOUT;
+        let mut buf = [0u8; 2048];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no UPnP gateway found",
+                ));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let response = String::from_utf8_lossy(&buf[..n]);
+            if let Some(location) = header(&response, "location") {
+                match Self::from_location(location) {
+                    Ok(igd) => return Ok(igd),
+                    Err(e) => debug!("[upnp] ignoring device at {}: {}", location, e),
+                }
+            }
+        }
+    }
+
+    /// 读取设备描述，找出端口映射服务
+    fn from_location(location: &str) -> io::Result<Self> {
+        let url = HttpUrl::parse(location).ok_or_else(|| invalid_reply("SSDP"))?;
+        let (status, description) = http_request(&url, "GET", "", "")?;
+        if status != 200 {
+            return Err(io::Error::other(format!(
+                "device description returned HTTP {}",
+                status
+            )));
+        }
+        let (service, control) = find_service(&description).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "device has no WAN connection service",
+            )
+        })?;
+        let control = url.join(control).ok_or_else(|| invalid_reply("UPnP"))?;
+        Ok(Self { service, control })
+    }
+
+    /// 调用服务的 `action`，返回应答正文
+    fn call(&self, action: &str, args: &[(&str, String)]) -> io::Result<String> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+            action, self.service, args
+        );
+        let headers = format!(
+            "Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n",
+            self.service, action
+        );
+        let (status, response) = http_request(&self.control, "POST", &headers, &body)?;
+        if status == 200 {
+            return Ok(response);
+        }
+        Err(match xml_text(&response, "errorCode") {
+            Some(code) => io::Error::other(format!(
+                "{} failed: UPnP error {} {}",
+                action,
+                code,
+                xml_text(&response, "errorDescription").unwrap_or("")
+            )),
+            None => io::Error::other(format!("{} failed: HTTP {}", action, status)),
+        })
+    }
+
+    /// 请求相同外部端口的映射，路由器只支持永久映射 (错误 725) 时改为不带租期
+    fn add_port_mapping(
+        &self,
+        local: Option<Ipv4Addr>,
+        protocol: Protocol,
+        port: u16,
+    ) -> io::Result<Mapping> {
+        let client = match local {
+            Some(ip) => ip,
+            None => local_ip_towards(self.control.socket_addr()?)?,
+        };
+        let mut lease = LEASE;
+        loop {
+            let args = [
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", protocol.to_string()),
+                ("NewInternalPort", port.to_string()),
+                ("NewInternalClient", client.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", DESCRIPTION.to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ];
+            match self.call("AddPortMapping", &args) {
+                Ok(_) => break,
+                Err(e) if !lease.is_zero() && e.to_string().contains("UPnP error 725") => {
+                    lease = Duration::ZERO;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let response = self.call("GetExternalIPAddress", &[])?;
+        let ip = xml_text(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| invalid_reply("GetExternalIPAddress"))?;
+        Ok(Mapping {
+            method: Method::Upnp,
+            protocol,
+            internal: port,
+            external: SocketAddrV4::new(ip, port),
+            lease,
+        })
+    }
+}
+
+/// 发往 `peer` 时使用的本机 IPv4 地址 (连接 UDP socket，不发送数据)
+fn local_ip_towards(peer: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(peer)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => Err(io::Error::other("gateway is not IPv4")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const NONCE: [u8; 12] = [7; 12];
+
+    fn request(gateway: SocketAddrV4) -> Request {
+        Request {
+            local: Some(Ipv4Addr::LOCALHOST),
+            port: 8443,
+            protocols: vec![Protocol::Tcp, Protocol::Udp],
+            nonce: NONCE,
+            gateway: Some(gateway),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let routes =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+            eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n";
+        let expected = if cfg!(target_endian = "little") {
+            Ipv4Addr::new(192, 168, 2, 1)
+        } else {
+            Ipv4Addr::new(1, 2, 168, 192)
+        };
+        assert_eq!(parse_route(routes), Some(expected));
+        assert_eq!(
+            parse_route(
+                routes
+                    .lines()
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+                    .as_str()
+            ),
+            None
+        );
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_default_gateway_sandboxed() {
+        // 每次续期都重新读取路由表，沙箱中须能读取
+        crate::sandbox::run_isolated("upnp::tests::test_default_gateway_sandboxed", || {
+            let allow = crate::sandbox::Allow {
+                read_files: true,
+                ..Default::default()
+            };
+            crate::sandbox::install(allow).expect("install sandbox");
+            // 环境中可能没有默认路由，只要求不被沙箱拒绝
+            if let Err(e) = default_gateway() {
+                assert_ne!(e.kind(), io::ErrorKind::PermissionDenied, "{}", e);
+            }
+        });
+    }
+
+    #[test]
+    fn test_pcp_packets() {
+        let request = pcp_request(
+            &NONCE,
+            Ipv4Addr::new(192, 168, 1, 2),
+            Protocol::Udp,
+            8443,
+            LEASE,
+        );
+        assert_eq!(request.len(), 60);
+        assert_eq!(&request[..8], &[2, 1, 0, 0, 0, 0, 0x0e, 0x10]);
+        assert_eq!(request[36], 17);
+
+        let mut reply = request.clone();
+        reply[1] = 0x81;
+        reply[44..60].copy_from_slice(&Ipv4Addr::new(203, 0, 113, 5).to_ipv6_mapped().octets());
+        reply[42..44].copy_from_slice(&9000u16.to_be_bytes());
+        let (external, lease) = parse_pcp(&reply, &NONCE, Protocol::Udp, 8443)
+            .unwrap()
+            .unwrap();
+        assert_eq!(external, "203.0.113.5:9000".parse().unwrap());
+        assert_eq!(lease, LEASE);
+        // 应答的标识或协议不符时拒绝，错误码原样报告
+        assert!(parse_pcp(&reply, &[0; 12], Protocol::Udp, 8443).is_err());
+        assert!(parse_pcp(&reply, &NONCE, Protocol::Tcp, 8443).is_err());
+        reply[3] = 8;
+        assert!(parse_pcp(&reply, &NONCE, Protocol::Udp, 8443)
+            .unwrap_err()
+            .to_string()
+            .contains("PCP error 8"));
+        // NAT-PMP 网关的版本错误应答
+        assert_eq!(
+            parse_pcp(&[0, 0x81, 0, 1, 0, 0, 0, 0], &NONCE, Protocol::Udp, 8443).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_natpmp_packets() {
+        assert_eq!(
+            natpmp_request(Protocol::Tcp, 8443, LEASE),
+            [0, 2, 0, 0, 0x20, 0xfb, 0x20, 0xfb, 0, 0, 0x0e, 0x10]
+        );
+        let reply = [
+            0, 130, 0, 0, 0, 0, 0, 1, 0x20, 0xfb, 0x23, 0x28, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            parse_natpmp(&reply, Protocol::Tcp, 8443).unwrap(),
+            (9000, LEASE)
+        );
+        assert!(parse_natpmp(&reply, Protocol::Udp, 8443).is_err());
+        let mut refused = reply;
+        refused[3] = 2;
+        assert!(parse_natpmp(&refused, Protocol::Tcp, 8443).is_err());
+        assert_eq!(
+            parse_natpmp_address(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 5]).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 5)
+        );
+    }
+
+    #[test]
+    fn test_natpmp_fallback() {
+        // 假网关：以版本错误应答 PCP，之后按 NAT-PMP 应答
+        let gateway = UdpSocket::bind("127.0.0.1:0").expect("bind gateway");
+        let addr = match gateway.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            for _ in 0..3 {
+                let (n, from) = gateway.recv_from(&mut buf).unwrap();
+                let reply: Vec<u8> = match &buf[..n] {
+                    [2, ..] => vec![0, 0x81, 0, 1, 0, 0, 0, 1],
+                    [0, 0] => vec![0, 128, 0, 0, 0, 0, 0, 1, 198, 51, 100, 7],
+                    [0, op, _, _, a, b, ..] => {
+                        let mut reply = vec![0, 128 + op, 0, 0, 0, 0, 0, 1, *a, *b, *a, *b];
+                        reply.extend_from_slice(&600u32.to_be_bytes());
+                        reply
+                    }
+                    _ => panic!("unexpected request"),
+                };
+                gateway.send_to(&reply, from).unwrap();
+            }
+        });
+        let request = request(addr);
+        let mapping = request.map_pmp(addr, Protocol::Tcp).expect("map");
+        server.join().unwrap();
+        assert_eq!(mapping.method, Method::NatPmp);
+        assert_eq!(mapping.external, "198.51.100.7:8443".parse().unwrap());
+        assert_eq!(mapping.lease, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_url() {
+        let url = HttpUrl::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(url.port, 5000);
+        assert_eq!(
+            url.join("/ctl/IPConn").unwrap().to_string(),
+            "http://192.168.1.1:5000/ctl/IPConn"
+        );
+        assert_eq!(url.join("ctl").unwrap().path, "/ctl");
+        assert_eq!(
+            url.join("http://192.168.1.1:80/x").unwrap(),
+            HttpUrl::parse("http://192.168.1.1/x").unwrap()
+        );
+        assert!(HttpUrl::parse("https://192.168.1.1/").is_none());
+        assert_eq!(
+            header(
+                "HTTP/1.1 200 OK\r\nLOCATION: http://10.0.0.1/d.xml\r\n\r\n",
+                "location"
+            ),
+            Some("http://10.0.0.1/d.xml")
+        );
+    }
+
+    #[test]
+    fn test_upnp_mapping() {
+        // 假 IGD：提供设备描述，应答 AddPortMapping (先拒绝带租期的请求) 和 GetExternalIPAddress
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind igd");
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut actions = Vec::new();
+            for _ in 0..4 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = vec![0u8; 4096];
+                let mut len = 0;
+                loop {
+                    len += stream.read(&mut buf[len..]).unwrap();
+                    let text = String::from_utf8_lossy(&buf[..len]);
+                    if (text.starts_with("GET") && text.contains("\r\n\r\n"))
+                        || text.contains("</s:Envelope>")
+                    {
+                        break;
+                    }
+                }
+                let text = String::from_utf8_lossy(&buf[..len]).to_string();
+                let (status, body) = if text.starts_with("GET /rootDesc.xml") {
+                    (200, "<root><service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service></root>".to_string())
+                } else if text.contains("#GetExternalIPAddress") {
+                    (
+                        200,
+                        "<NewExternalIPAddress>203.0.113.9</NewExternalIPAddress>".to_string(),
+                    )
+                } else if text.contains("<NewLeaseDuration>3600<") {
+                    (500, "<errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription>".to_string())
+                } else {
+                    (200, String::new())
+                };
+                actions.push(text);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            actions
+        });
+        let igd = Igd::from_location(&location).expect("igd");
+        assert_eq!(igd.service, WAN_SERVICES[1]);
+        let mapping = igd
+            .add_port_mapping(Some(Ipv4Addr::LOCALHOST), Protocol::Udp, 8443)
+            .expect("map");
+        let actions = server.join().unwrap();
+        assert_eq!(mapping.external, "203.0.113.9:8443".parse().unwrap());
+        assert!(mapping.lease.is_zero());
+        assert!(actions[1].starts_with("POST /ctl/IPConn"));
+        assert!(actions[2].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(actions[2].contains("<NewProtocol>UDP</NewProtocol>"));
+    }
+}
@@ -0,0 +1,310 @@
+//! 事件 Webhook (--webhook)
+//!
+//! 作为连接观察者把连接建立/关闭、拒绝新连接和后端状态变化编码为 JSON，由后台线程逐个以
+//! HTTP POST 发给指定地址，事件循环不等待网络请求。接收端过慢或不可达、队列已满时丢弃新事件。
+//! 只支持 `http://`
+
+use crate::event::observer::{ConnectionObserver, ConnectionSummary, Protocol, RejectReason};
+use crate::log::get_current_time;
+use crate::types::Address;
+use crate::upnp::{http_request, HttpUrl};
+use crate::{debug, info, warn};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/// 等待发送的事件数上限
+pub const QUEUE_LEN: usize = 1024;
+
+/// 把事件 POST 到 HTTP 地址的观察者
+#[derive(Debug)]
+pub struct Webhook {
+    tx: SyncSender<String>,
+    tenant: Option<String>,
+    dropped: AtomicU64,
+}
+
+impl Webhook {
+    /// 解析地址并启动发送线程，`tenant` 非空时每个事件带上 `tenant` 字段
+    pub fn start(url: &str, tenant: Option<String>) -> Result<Self, String> {
+        let parsed = HttpUrl::parse(url).ok_or_else(|| {
+            format!(
+                "invalid webhook URL '{}', expected http://host[:port][/path]",
+                url
+            )
+        })?;
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("webhook".to_string())
+            .spawn(move || deliver(&parsed, rx))
+            .map_err(|e| format!("failed to spawn webhook thread: {}", e))?;
+        Ok(Self {
+            tx,
+            tenant,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn event(&self, name: &str) -> Event {
+        Event::new(name, self.tenant.as_deref())
+    }
+
+    fn send(&self, event: Event) {
+        match self.tx.try_send(event.finish()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!("[webhook] queue full, {} events dropped so far", dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl ConnectionObserver for Webhook {
+    fn on_connect_established(&self, peer: &str, remote: &Address) {
+        self.send(
+            self.event("connect")
+                .str("protocol", &Protocol::Tcp.to_string())
+                .str("client", peer)
+                .str("remote", &remote.to_string()),
+        );
+    }
+
+    fn on_udp_session(&self, peer: &str, remote: &Address) {
+        self.send(
+            self.event("connect")
+                .str("protocol", &Protocol::Udp.to_string())
+                .str("client", peer)
+                .str("remote", &remote.to_string()),
+        );
+    }
+
+    fn on_close(&self, summary: &ConnectionSummary<'_>) {
+        self.send(
+            self.event("close")
+                .str("protocol", &summary.protocol.to_string())
+                .str("client", summary.peer)
+                .str("reason", &summary.reason.to_string())
+                .num("bytes_up", summary.bytes_up)
+                .num("bytes_down", summary.bytes_down)
+                .num("packets_up", summary.packets_up)
+                .num("packets_down", summary.packets_down)
+                .num("duration_ms", summary.duration_ms),
+        );
+    }
+
+    fn on_reject(&self, protocol: Protocol, peer: &str, reason: RejectReason) {
+        self.send(
+            self.event("reject")
+                .str("protocol", &protocol.to_string())
+                .str("client", peer)
+                .str("reason", &reason.to_string()),
+        );
+    }
+
+    fn on_backend_down(&self, backend: &Address, reason: &str) {
+        self.send(
+            self.event("backend-down")
+                .str("backend", &backend.to_string())
+                .str("reason", reason),
+        );
+    }
+
+    fn on_backend_up(&self, backend: &Address) {
+        self.send(
+            self.event("backend-up")
+                .str("backend", &backend.to_string()),
+        );
+    }
+}
+
+/// 逐个字段拼接的 JSON 对象，开头是 `event` 和 `time` (Unix 毫秒)
+struct Event(String);
+
+impl Event {
+    fn new(name: &str, tenant: Option<&str>) -> Self {
+        let event = Self(String::from("{"))
+            .str("event", name)
+            .num("time", get_current_time());
+        match tenant {
+            Some(tenant) => event.str("tenant", tenant),
+            None => event,
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        let _ = write!(self.0, "\"{}\":", key);
+    }
+
+    fn str(mut self, key: &str, value: &str) -> Self {
+        self.key(key);
+        push_json_string(&mut self.0, value);
+        self
+    }
+
+    fn num(mut self, key: &str, value: u64) -> Self {
+        self.key(key);
+        let _ = write!(self.0, "{}", value);
+        self
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+/// 追加带引号和转义的 JSON 字符串
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// 发送线程：逐个 POST，失败时只在状态变化时输出警告，Webhook 释放后退出
+fn deliver(url: &HttpUrl, rx: Receiver<String>) {
+    let mut failing = false;
+    for body in rx {
+        let result = http_request(url, "POST", "Content-Type: application/json\r\n", &body);
+        let error = match result {
+            Ok((status, _)) if (200..300).contains(&status) => {
+                if failing {
+                    info!("[webhook] {} is accepting events again", url);
+                    failing = false;
+                }
+                continue;
+            }
+            Ok((status, _)) => format!("HTTP {}", status),
+            Err(e) => e.to_string(),
+        };
+        if failing {
+            debug!("[webhook] POST to {} failed: {}", url, error);
+        } else {
+            warn!("[webhook] POST to {} failed: {}", url, error);
+            failing = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::observer::CloseReason;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::new("close", Some("a\"b"))
+            .str("client", "[::1]:80\n")
+            .num("bytes_up", 7)
+            .finish();
+        let time = event
+            .split("\"time\":")
+            .nth(1)
+            .and_then(|rest| rest.split(',').next())
+            .unwrap();
+        assert!(time.parse::<u64>().unwrap() > 0);
+        assert_eq!(
+            event.replace(time, "T"),
+            r#"{"event":"close","time":T,"tenant":"a\"b","client":"[::1]:80\n","bytes_up":7}"#
+        );
+        let mut s = String::new();
+        push_json_string(&mut s, "\\\u{1}");
+        assert_eq!(s, r#""\\\u0001""#);
+        assert!(Webhook::start("https://example.com/", None).is_err());
+        assert!(Webhook::start("example.com", None).is_err());
+    }
+
+    #[test]
+    fn test_post_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let webhook = Webhook::start(&url, None).expect("start webhook");
+
+        let remote = Address::from_str("127.0.0.1:9000").unwrap();
+        webhook.on_connect_established("127.0.0.1:1000", &remote);
+        webhook.on_close(&ConnectionSummary {
+            protocol: Protocol::Udp,
+            peer: "127.0.0.1:1001",
+            bytes_up: 10,
+            bytes_down: 20,
+            packets_up: 1,
+            packets_down: 2,
+            duration_ms: 5,
+            reason: CloseReason::Timeout,
+        });
+        webhook.on_reject(
+            Protocol::Tcp,
+            "127.0.0.1:1002",
+            RejectReason::MaxConnections,
+        );
+        webhook.on_backend_down(&remote, "circuit breaker opened");
+
+        let expected = [
+            r#""event":"connect""#,
+            r#""protocol":"tcp","client":"127.0.0.1:1000","remote":"127.0.0.1:9000"}"#,
+            r#""event":"close""#,
+            r#""protocol":"udp","client":"127.0.0.1:1001","reason":"timeout","bytes_up":10,"bytes_down":20,"packets_up":1,"packets_down":2,"duration_ms":5}"#,
+            r#""event":"reject""#,
+            r#""protocol":"tcp","client":"127.0.0.1:1002","reason":"max connections"}"#,
+            r#""event":"backend-down""#,
+            r#""backend":"127.0.0.1:9000","reason":"circuit breaker opened"}"#,
+        ];
+        for pair in expected.chunks(2) {
+            let (mut stream, _) = listener.accept().expect("accept");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("set timeout");
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            // 请求头之后按 Content-Length 读完正文
+            let body = loop {
+                let n = stream.read(&mut buf).expect("read request");
+                assert!(n > 0, "request truncated");
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let len: usize = crate::upnp::header(head, "content-length")
+                    .and_then(|len| len.parse().ok())
+                    .expect("content length");
+                if body.len() >= len {
+                    assert!(head.starts_with("POST /hook HTTP/1.0\r\n"));
+                    assert!(head.contains("Content-Type: application/json\r\n"));
+                    break body.to_string();
+                }
+            };
+            assert!(
+                body.starts_with(&format!("{{{},\"time\":", pair[0])),
+                "{}",
+                body
+            );
+            assert!(body.ends_with(pair[1]), "{}", body);
+            stream
+                .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                .expect("write response");
+        }
+    }
+}
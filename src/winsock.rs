@@ -0,0 +1,304 @@
+//! Windows 上与 libc 同名的 Winsock 绑定
+//!
+//! libc crate 在 Windows 上不提供 socket API。这里按 libc 的名称和签名包装 Winsock，
+//! socket 相关模块在 Windows 上 `use crate::winsock as libc` 后与 Unix 共用同一份代码：
+//! socket 统一用 `RawSocket` 表示，`close` 调用 closesocket，`EINPROGRESS` 等错误码换成对应的 WSA 错误码。
+//! Winsock 的错误码由 WSAGetLastError 给出，它与 GetLastError 读取同一个值，`io::Error::last_os_error()` 可以直接使用
+
+#![allow(non_camel_case_types)]
+
+pub use libc::{c_char, c_int, c_long, c_ushort, c_void};
+use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket, RawSocket};
+use winapi::shared::{ws2def, ws2ipdef};
+use winapi::um::winsock2;
+
+pub type socklen_t = c_int;
+pub type sa_family_t = u16;
+
+pub use winapi::shared::ws2def::{
+    AF_INET, AF_INET6, AF_UNSPEC, SOCK_DGRAM, SOCK_STREAM, SOL_SOCKET, SO_BROADCAST, SO_ERROR,
+    SO_KEEPALIVE, SO_LINGER, SO_RCVBUF, SO_SNDBUF, TCP_NODELAY,
+};
+pub use winapi::shared::ws2ipdef::IPV6_V6ONLY;
+pub use winapi::um::winsock2::{FIONBIO, MSG_PEEK};
+
+// winapi 中 IPPROTO_* 为枚举类型 (u32)，setsockopt 的 level 参数是 c_int
+pub const IPPROTO_TCP: c_int = 6;
+pub const IPPROTO_UDP: c_int = 17;
+pub const IPPROTO_IPV6: c_int = 41;
+
+/// TCP keepalive 参数 (Windows 10 1709 起支持)，winapi 0.3 未定义
+pub const TCP_KEEPIDLE: c_int = 3;
+pub const TCP_KEEPCNT: c_int = 16;
+pub const TCP_KEEPINTVL: c_int = 17;
+
+/// 非阻塞 connect 返回 WSAEWOULDBLOCK 而不是 EINPROGRESS
+pub const EINPROGRESS: c_int = winapi::shared::winerror::WSAEWOULDBLOCK as c_int;
+pub const EMFILE: c_int = winapi::shared::winerror::WSAEMFILE as c_int;
+/// Winsock 没有 ENFILE，系统资源耗尽时返回 WSAENOBUFS
+pub const ENFILE: c_int = winapi::shared::winerror::WSAENOBUFS as c_int;
+pub const ECONNREFUSED: c_int = winapi::shared::winerror::WSAECONNREFUSED as c_int;
+pub const EIO: c_int = winapi::shared::winerror::ERROR_IO_DEVICE as c_int;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sockaddr {
+    pub sa_family: sa_family_t,
+    pub sa_data: [c_char; 14],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct in_addr {
+    pub s_addr: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sockaddr_in {
+    pub sin_family: sa_family_t,
+    pub sin_port: u16,
+    pub sin_addr: in_addr,
+    pub sin_zero: [c_char; 8],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct in6_addr {
+    pub s6_addr: [u8; 16],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sockaddr_in6 {
+    pub sin6_family: sa_family_t,
+    pub sin6_port: u16,
+    pub sin6_flowinfo: u32,
+    pub sin6_addr: in6_addr,
+    pub sin6_scope_id: u32,
+}
+
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+pub struct sockaddr_storage {
+    pub ss_family: sa_family_t,
+    __ss_pad: [u8; 126],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct linger {
+    pub l_onoff: c_ushort,
+    pub l_linger: c_ushort,
+}
+
+// 与 Winsock 结构体布局一致，指针可以直接互相转换
+const _: () = {
+    assert!(std::mem::size_of::<sockaddr>() == std::mem::size_of::<ws2def::SOCKADDR>());
+    assert!(std::mem::size_of::<sockaddr_in>() == std::mem::size_of::<ws2def::SOCKADDR_IN>());
+    assert!(
+        std::mem::size_of::<sockaddr_in6>() == std::mem::size_of::<ws2ipdef::SOCKADDR_IN6_LH>()
+    );
+    assert!(
+        std::mem::size_of::<sockaddr_storage>()
+            == std::mem::size_of::<ws2def::SOCKADDR_STORAGE_LH>()
+    );
+};
+
+/// socket 句柄，对应 Unix 的 `RawFd`
+pub type RawFd = RawSocket;
+
+/// 对应 Unix 的 `AsRawFd`，由实现了 `AsRawSocket` 的类型自动实现
+pub trait AsRawFd {
+    fn as_raw_fd(&self) -> RawFd;
+}
+
+impl<T: AsRawSocket> AsRawFd for T {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_raw_socket()
+    }
+}
+
+/// 对应 Unix 的 `FromRawFd`，由实现了 `FromRawSocket` 的类型自动实现
+pub trait FromRawFd {
+    /// # Safety
+    /// `fd` 必须是调用方独占的有效 socket
+    unsafe fn from_raw_fd(fd: RawFd) -> Self;
+}
+
+impl<T: FromRawSocket> FromRawFd for T {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        T::from_raw_socket(fd)
+    }
+}
+
+/// 对应 Unix 的 `IntoRawFd`，由实现了 `IntoRawSocket` 的类型自动实现
+pub trait IntoRawFd {
+    fn into_raw_fd(self) -> RawFd;
+}
+
+impl<T: IntoRawSocket> IntoRawFd for T {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_raw_socket()
+    }
+}
+
+fn handle(fd: RawSocket) -> winsock2::SOCKET {
+    fd as winsock2::SOCKET
+}
+
+/// 单次 send/recv 的长度以 c_int 表示，超出部分留给下一次调用
+fn io_len(len: usize) -> c_int {
+    len.min(c_int::MAX as usize) as c_int
+}
+
+pub unsafe fn close(fd: RawSocket) -> c_int {
+    winsock2::closesocket(handle(fd))
+}
+
+pub unsafe fn bind(fd: RawSocket, addr: *const sockaddr, len: socklen_t) -> c_int {
+    winsock2::bind(handle(fd), addr as *const _, len)
+}
+
+pub unsafe fn connect(fd: RawSocket, addr: *const sockaddr, len: socklen_t) -> c_int {
+    winsock2::connect(handle(fd), addr as *const _, len)
+}
+
+pub unsafe fn listen(fd: RawSocket, backlog: c_int) -> c_int {
+    winsock2::listen(handle(fd), backlog)
+}
+
+pub unsafe fn getpeername(fd: RawSocket, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    winsock2::getpeername(handle(fd), addr as *mut _, len)
+}
+
+pub unsafe fn getsockname(fd: RawSocket, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    winsock2::getsockname(handle(fd), addr as *mut _, len)
+}
+
+pub unsafe fn setsockopt(
+    fd: RawSocket,
+    level: c_int,
+    name: c_int,
+    value: *const c_void,
+    len: socklen_t,
+) -> c_int {
+    winsock2::setsockopt(handle(fd), level, name, value as *const _, len)
+}
+
+pub unsafe fn getsockopt(
+    fd: RawSocket,
+    level: c_int,
+    name: c_int,
+    value: *mut c_void,
+    len: *mut socklen_t,
+) -> c_int {
+    winsock2::getsockopt(handle(fd), level, name, value as *mut _, len)
+}
+
+pub unsafe fn send(fd: RawSocket, buf: *const c_void, len: usize, flags: c_int) -> isize {
+    winsock2::send(handle(fd), buf as *const _, io_len(len), flags) as isize
+}
+
+/// 与 libc 同名的 iovec，`writev` 内部转换成 WSABUF
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct iovec {
+    pub iov_base: *mut c_void,
+    pub iov_len: usize,
+}
+
+/// 用 WSASend 一次发送多段数据，非阻塞 socket 上立即返回已发送字节数
+pub unsafe fn writev(fd: RawSocket, iov: *const iovec, iovcnt: c_int) -> isize {
+    let iov = std::slice::from_raw_parts(iov, iovcnt.max(0) as usize);
+    let mut bufs: Vec<ws2def::WSABUF> = iov
+        .iter()
+        .map(|v| ws2def::WSABUF {
+            len: io_len(v.iov_len) as u32,
+            buf: v.iov_base as *mut _,
+        })
+        .collect();
+    let mut sent: u32 = 0;
+    let ret = winsock2::WSASend(
+        handle(fd),
+        bufs.as_mut_ptr(),
+        bufs.len() as u32,
+        &mut sent,
+        0,
+        std::ptr::null_mut(),
+        None,
+    );
+    if ret != 0 {
+        return -1;
+    }
+    sent as isize
+}
+
+pub unsafe fn recv(fd: RawSocket, buf: *mut c_void, len: usize, flags: c_int) -> isize {
+    winsock2::recv(handle(fd), buf as *mut _, io_len(len), flags) as isize
+}
+
+pub unsafe fn recvfrom(
+    fd: RawSocket,
+    buf: *mut c_void,
+    len: usize,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addr_len: *mut socklen_t,
+) -> isize {
+    winsock2::recvfrom(
+        handle(fd),
+        buf as *mut _,
+        io_len(len),
+        flags,
+        addr as *mut _,
+        addr_len,
+    ) as isize
+}
+
+pub unsafe fn sendto(
+    fd: RawSocket,
+    buf: *const c_void,
+    len: usize,
+    flags: c_int,
+    addr: *const sockaddr,
+    addr_len: socklen_t,
+) -> isize {
+    winsock2::sendto(
+        handle(fd),
+        buf as *const _,
+        io_len(len),
+        flags,
+        addr as *const _,
+        addr_len,
+    ) as isize
+}
+
+pub unsafe fn ioctlsocket(fd: RawSocket, cmd: c_long, arg: *mut u32) -> c_int {
+    winsock2::ioctlsocket(handle(fd), cmd, arg)
+}
+
+/// 关闭 UDP socket 的 SIO_UDP_CONNRESET
+///
+/// 默认情况下对端回复 ICMP 端口不可达后，该 socket 的下一次 recvfrom 返回 WSAECONNRESET
+pub fn disable_udp_connreset(fd: RawSocket) -> std::io::Result<()> {
+    let mut enable: u32 = 0;
+    let mut returned: u32 = 0;
+    let ret = unsafe {
+        winsock2::WSAIoctl(
+            handle(fd),
+            winapi::um::mswsock::SIO_UDP_CONNRESET,
+            &mut enable as *mut _ as *mut _,
+            std::mem::size_of::<u32>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            None,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}